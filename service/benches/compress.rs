@@ -0,0 +1,93 @@
+//! Compression throughput at the extremes of the input space
+//! `scalar` is the byte-at-a-time run detection the word based scan replaced
+//! `encode` always runs the encoder, without the store mode shortcut
+//! `zeroize` is the cost of `ServerConfig::zeroize_buffers` on top of a
+//! request, wiping the request it read and the whole of its response buffer
+//! `validate` is the scan of a valid payload against the default `CharPolicy`,
+//! the hot path of every Compress
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use service::message::MAX_MESSAGE_PADDED;
+use service::{compress_message, CharPolicy, CompressOptions, Compressor, MAX_PAYLOAD};
+use zeroize::Zeroize;
+
+const LEN: usize = MAX_PAYLOAD as usize;
+
+fn scalar(rx: &[u8], tx: &mut [u8]) -> usize {
+    let mut compress = 0;
+    let mut rest = rx;
+    while let Some(&first) = rest.first() {
+        let run = rest.iter().take_while(|&&x| x == first).count();
+        if run >= 3 {
+            for c in run.to_string().bytes() {
+                tx[compress] = c;
+                compress += 1;
+            }
+            tx[compress] = first;
+            compress += 1;
+        } else {
+            tx[compress..compress + run].fill(first);
+            compress += run;
+        }
+        rest = &rest[run..];
+    }
+    compress
+}
+
+fn bench_inputs(c: &mut Criterion) {
+    let single_run = vec![b'a'; LEN];
+    let no_runs: Vec<u8> = (0..LEN).map(|i| b'a' + (i % 26) as u8).collect();
+    let mut tx = vec![0u8; LEN];
+
+    for (name, rx) in &[("single_long_run", &single_run), ("no_runs", &no_runs)] {
+        let mut group = c.benchmark_group(*name);
+        group.throughput(Throughput::Bytes(LEN as u64));
+        group.bench_function("compress_message", |b| {
+            b.iter(|| compress_message(black_box(rx), black_box(&mut tx)))
+        });
+        group.bench_function("encode", |b| {
+            b.iter(|| {
+                Compressor::new(black_box(rx), &CompressOptions::default()).fill(black_box(&mut tx))
+            })
+        });
+        group.bench_function("scalar", |b| {
+            b.iter(|| scalar(black_box(rx), black_box(&mut tx)))
+        });
+        group.finish();
+    }
+}
+
+fn bench_zeroize(c: &mut Criterion) {
+    let mut rx = vec![b'a'; MAX_MESSAGE_PADDED];
+    let mut tx = vec![0u8; MAX_MESSAGE_PADDED];
+    let mut group = c.benchmark_group("zeroize");
+    for len in [16, LEN] {
+        group.bench_function(format!("compress_{}", len), |b| {
+            b.iter(|| {
+                rx[..len].fill(b'a');
+                compress_message(black_box(&rx[..len]), black_box(&mut tx))
+            })
+        });
+        group.bench_function(format!("compress_{}_zeroized", len), |b| {
+            b.iter(|| {
+                rx[..len].fill(b'a');
+                let size = compress_message(black_box(&rx[..len]), black_box(&mut tx));
+                rx[..len].zeroize();
+                tx.zeroize();
+                size
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_validate(c: &mut Criterion) {
+    let valid: Vec<u8> = (0..LEN).map(|i| b'a' + (i % 26) as u8).collect();
+    let policy = CharPolicy::default();
+    let mut group = c.benchmark_group("validate");
+    group.throughput(Throughput::Bytes(LEN as u64));
+    group.bench_function("valid", |b| b.iter(|| policy.validate(black_box(&valid))));
+    group.finish();
+}
+
+criterion_group!(benches, bench_inputs, bench_zeroize, bench_validate);
+criterion_main!(benches);
@@ -1,26 +1,453 @@
-use service::Server;
-use std::env;
+use service::{CharPolicy, Enforcement, Server, ServerConfig, ANONYMOUS_TENANT};
+use std::{
+    env, fs,
+    io::{Error, ErrorKind},
+    path::PathBuf,
+    time::Duration,
+};
+use tokio::runtime::Runtime;
 
 /// Run the server of the compression service on the address provided via the
-/// commandline or the default address of 127.0.0.4000
-#[tokio::main]
-async fn main() -> Result<(), std::io::Error> {
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "127.0.0.1:4000".to_string());
-
-    Server::new_with_url(&addr).await?.serve().await
+/// commandline or the default address of 127.0.0.1:4000
+///
+/// Options:
+///   --allow-chars <spec>    characters accepted in compress payloads, e.g. "a-z -"
+///   --fold-case             accept uppercase and fold it to lowercase when compressing
+///   --min-run <n>           shortest run encoded with a count prefix (default 3, minimum 2)
+///   --ratio-policy <policy> how the ratio of GetStats is computed: cumulative over every
+///                           request, ema:ALPHA for a moving average of their ratios or
+///                           last:N over the last N requests (default cumulative)
+///   --idle-timeout <secs>   close connections that stay idle for this long
+///   --frame-timeout <ms>    how long the rest of a message split across reads is waited
+///                           for before it's answered for what it has (default 1000, 0
+///                           waits as long as --idle-timeout)
+///   --requests-per-yield <n> requests a connection handles in a row before
+///                           letting others run (default 1, 0 never yields)
+///   --permissive-flags      ignore unsupported header flags instead of rejecting them
+///   --strict                reject trailing bytes and non-canonical Decompress payloads
+///                           with precise errors, and unsupported flags whatever
+///                           --permissive-flags says
+///   --violation-strikes <n> with --strict, close connections after this many requests
+///                           violating the protocol (default 3, 0 never closes them)
+///   --allow-global-reset    let ResetStats clear the stats of the whole service
+///   --bad-magic-strikes <n> close connections after this many messages with a bad
+///                           magic (default 3, 0 never closes them)
+///   --silent-bad-magic      don't respond to messages with a bad magic
+///   --debug-addr <addr>     also serve the line based debug protocol at this address
+///   --max-connections <n>   connections served at once, others wait to be accepted
+///                           (default 0, unlimited)
+///   --shutdown-timeout <secs> on SIGINT or SIGTERM, how long the connections being
+///                           served get to finish before they're dropped (default 5)
+///   --stats-interval <secs> log a summary of the stats since the previous one this often
+///   --bind-attempts <n>     times to try binding the address while it's in use, e.g. by
+///                           the process being replaced, backing off from 100ms (default 1)
+///   --memory-budget <bytes> requests and responses held in memory at once across the
+///                           connections, over it requests get ServerBusy (default 0,
+///                           unlimited)
+///   --max-requests-per-connection <n> close connections with a Goodbye once they
+///                           have answered this many requests (default 0, unlimited)
+///   --max-connection-lifetime <secs> close connections with a Goodbye once they have
+///                           been open this long
+///   --heavy-lane <n>        Compress and Decompress requests handled at once across the
+///                           connections, Ping and the like never wait on them
+///                           (default 4, 0 unlimited)
+///   --tenant <token>=<name> let clients authenticating with the token account their
+///                           stats to the tenant, may be repeated. GetStats and ResetStats
+///                           are then scoped to the requesting connection's tenant
+///   --max-tenants <n>       tenants whose stats are kept at once (default 64)
+///   --recent-requests <n>   remember the latest n requests for the debug port's RECENT
+///                           (default 0, none)
+///   --capture-payload-prefix also remember the first 32 payload bytes of those requests
+///   --flush-dir <dir>       let authenticated clients dump the stats as JSON to a file of
+///                           this directory with FlushStats, off by default
+///   --zeroize-buffers       wipe the bytes of each request and response from memory once
+///                           answered, off by default
+///   --max-batch <n>         entries a Batch request may have (default 256, 0 turns
+///                           batches off)
+///   --error-details         error responses to malformed requests carry why in ASCII,
+///                           off by default
+///   --describe-unsupported  an UnsupportedRequestType carries the protocol version and
+///                           the request codes served, off by default
+///   --reject-log <spec>     dump the requests rejected within budgets, e.g.
+///                           "dump=64,every=10,connection=4096,global=65536", or off
+///                           (default on in debug builds only)
+///   --hello                 greet each connection with a Hello message of the limits
+///                           GetConfig answers with, off by default
+///   --reset-tokens <n>      ResetStats tokens each connection remembers so that a retried
+///                           reset isn't applied twice (default 16, 0 turns tokens off)
+///   --request-deadline <ms> how long handling a request may take before it's logged and
+///                           counted as slow (default 1000, 0 turns it off)
+///   --max-response <bytes>  longest response payload, a longer one is answered
+///                           ResponseTooLarge (default and at most 8192)
+///   --flush-policy <policy> when the responses of a connection are written: immediate
+///                           as each is built, messages:N up to N in a write, or
+///                           idle:MICROS that long after the first (default immediate)
+///   --max-buffered <bytes>  responses a connection holds back at most under its flush
+///                           policy (default 32800)
+///   --config <file>         also read the options from this file, one per line without
+///                           the leading "--" (e.g. "idle-timeout 30"), they override the
+///                           command line's. On SIGHUP the file is read again and what
+///                           can change live applied, see `ConfigHandle::reload`
+fn main() -> Result<(), std::io::Error> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (addr, config) = load_config(&args)?;
+
+    // for tokio-console, which connects at 127.0.0.1:6669 unless
+    // TOKIO_CONSOLE_BIND says otherwise
+    #[cfg(feature = "console")]
+    console_subscriber::init();
+    // the startup and stats reports are logged at the info level
+    #[cfg(not(feature = "console"))]
+    tracing_subscriber::fmt().init();
+
+    let grace = config.shutdown_timeout;
+    let runtime = Runtime::new()?;
+    let handle = runtime.handle().clone();
+    runtime.block_on(async {
+        let mut server = Server::new_with_config(&addr, config).await?;
+        #[cfg(unix)]
+        service::spawn_named(
+            "config reloader",
+            reload_on_hangup(server.config_handle(), args),
+            &handle,
+        );
+        server.serve_on(&handle, shutdown_signal(), grace).await
+    })?;
+    // the connections are done or dropped by now, only the runtime's own
+    // threads are left
+    runtime.shutdown_timeout(grace);
+    tracing::info!("Compression Service Stopped");
+    Ok(())
+}
+
+/// Completes on SIGINT (Ctrl-C), or on SIGTERM where there are signals
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.expect("Ctrl-C handler");
+}
+
+/// The address and configuration given by the command line `args`, the
+/// options of the config file it names, if any, applied after its own
+fn load_config(args: &[String]) -> Result<(String, ServerConfig), std::io::Error> {
+    let mut addr = "127.0.0.1:4000".to_string();
+    let mut config = ServerConfig::default();
+    let mut config_file = None;
+    parse_args(
+        args.iter().cloned(),
+        &mut addr,
+        &mut config,
+        &mut config_file,
+    )?;
+    if let Some(path) = config_file {
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e)))?;
+        let mut nested = None;
+        parse_args(file_args(&contents), &mut addr, &mut config, &mut nested)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e)))?;
+        if nested.is_some() {
+            let msg = format!("{}: config files can't name another", path);
+            return Err(Error::new(ErrorKind::InvalidInput, msg));
+        }
+    }
+    Ok((addr, config))
 }
 
-// TODO:
-// Handle shutdown timeout by creating custom runtime and passing to `serve`,
-// Here is an illustration,
-// async fn main() -> Result<(), std::io::Error> {
-//     let mut runtime = Runtime::new().unwrap();
-//     let handle = runtime.handle();
-//     runtime.block_on(async move {
-// 	    Server::new_with_url("127.0.0.1:4001").await?.serve_with_runtime(handle).await
-//     })?;
-//     runtime.shutdown_timeout(Duration::from_millis(10));
-//     Ok(())
-// }
+/// The options of a config file as command line arguments, the file has an
+/// option per line without its leading "--", followed by its value if it
+/// takes one, e.g. "idle-timeout 30". Blank lines and lines starting with #
+/// are skipped
+fn file_args(contents: &str) -> impl Iterator<Item = String> + '_ {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .flat_map(|line| match line.split_once(char::is_whitespace) {
+            Some((option, value)) => vec![format!("--{}", option), value.trim().to_string()],
+            None => vec![format!("--{}", line)],
+        })
+}
+
+fn parse_args<I: Iterator<Item = String>>(
+    mut args: I,
+    addr: &mut String,
+    config: &mut ServerConfig,
+    config_file: &mut Option<String>,
+) -> Result<(), std::io::Error> {
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--allow-chars" => {
+                let spec = args.next().unwrap_or_default();
+                config.char_policy = CharPolicy::from_spec(&spec)
+                    .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            }
+            "--fold-case" => config.fold_case = true,
+            "--permissive-flags" => config.strict_flags = false,
+            "--strict" => config.enforcement = Enforcement::Strict,
+            "--allow-global-reset" => config.allow_global_reset = true,
+            "--silent-bad-magic" => config.silent_bad_magic = true,
+            "--capture-payload-prefix" => config.capture_payload_prefix = true,
+            "--zeroize-buffers" => config.zeroize_buffers = true,
+            "--error-details" => config.error_details = true,
+            "--describe-unsupported" => config.describe_unsupported = true,
+            "--hello" => config.hello = true,
+            "--min-run" => {
+                config.min_run = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|n| *n >= 2)
+                    .ok_or_else(|| {
+                        Error::new(ErrorKind::InvalidInput, "--min-run expects a number >= 2")
+                    })?;
+            }
+            "--reject-log" => {
+                let spec = args.next().unwrap_or_default();
+                config.reject_log = match spec.as_str() {
+                    "off" => None,
+                    spec => Some(
+                        spec.parse()
+                            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?,
+                    ),
+                };
+            }
+            "--ratio-policy" => {
+                let policy = args.next().unwrap_or_default();
+                config.ratio_policy = policy
+                    .parse()
+                    .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            }
+            "--idle-timeout" => {
+                let secs = args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, "--idle-timeout expects seconds")
+                })?;
+                config.idle_timeout = Some(Duration::from_secs(secs));
+            }
+            "--frame-timeout" => {
+                let millis = args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        "--frame-timeout expects milliseconds",
+                    )
+                })?;
+                config.frame_timeout = match millis {
+                    0 => None,
+                    millis => Some(Duration::from_millis(millis)),
+                };
+            }
+            "--requests-per-yield" => {
+                config.requests_per_yield =
+                    args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            "--requests-per-yield expects a number",
+                        )
+                    })?;
+            }
+            "--debug-addr" => {
+                config.debug_addr = Some(args.next().ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, "--debug-addr expects an address")
+                })?);
+            }
+            "--bad-magic-strikes" => {
+                config.bad_magic_strikes =
+                    args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            "--bad-magic-strikes expects a number",
+                        )
+                    })?;
+            }
+            "--violation-strikes" => {
+                config.violation_strikes =
+                    args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            "--violation-strikes expects a number",
+                        )
+                    })?;
+            }
+            "--max-connections" => {
+                config.max_connections =
+                    args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            "--max-connections expects a number",
+                        )
+                    })?;
+            }
+            "--shutdown-timeout" => {
+                let secs = args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        "--shutdown-timeout expects seconds",
+                    )
+                })?;
+                config.shutdown_timeout = Duration::from_secs(secs);
+            }
+            "--stats-interval" => {
+                let secs = args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, "--stats-interval expects seconds")
+                })?;
+                config.stats_interval = Some(Duration::from_secs(secs));
+            }
+            "--bind-attempts" => {
+                config.bind_attempts = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|n| *n >= 1)
+                    .ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            "--bind-attempts expects a number >= 1",
+                        )
+                    })?;
+            }
+            "--memory-budget" => {
+                config.memory_budget =
+                    args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            "--memory-budget expects a number of bytes",
+                        )
+                    })?;
+            }
+            "--max-requests-per-connection" => {
+                config.max_requests_per_connection =
+                    args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            "--max-requests-per-connection expects a number",
+                        )
+                    })?;
+            }
+            "--max-connection-lifetime" => {
+                let secs = args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        "--max-connection-lifetime expects seconds",
+                    )
+                })?;
+                config.max_connection_lifetime = Some(Duration::from_secs(secs));
+            }
+            "--heavy-lane" => {
+                config.heavy_lane = args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, "--heavy-lane expects a number")
+                })?;
+            }
+            "--tenant" => {
+                let (token, tenant) = args
+                    .next()
+                    .and_then(|spec| {
+                        let (token, tenant) = spec.split_once('=')?;
+                        Some((token.to_string(), tenant.to_string()))
+                    })
+                    .filter(|(token, tenant)| {
+                        !token.is_empty() && !tenant.is_empty() && tenant != ANONYMOUS_TENANT
+                    })
+                    .ok_or_else(|| {
+                        let msg = "--tenant expects TOKEN=NAME, NAME other than anonymous";
+                        Error::new(ErrorKind::InvalidInput, msg)
+                    })?;
+                config.tenants.insert(token, tenant);
+            }
+            "--max-tenants" => {
+                config.max_tenants = args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, "--max-tenants expects a number")
+                })?;
+            }
+            "--recent-requests" => {
+                config.recent_requests =
+                    args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            "--recent-requests expects a number",
+                        )
+                    })?;
+            }
+            "--max-batch" => {
+                config.max_batch = args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, "--max-batch expects a number")
+                })?;
+            }
+            "--reset-tokens" => {
+                config.reset_tokens =
+                    args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                        Error::new(ErrorKind::InvalidInput, "--reset-tokens expects a number")
+                    })?;
+            }
+            "--request-deadline" => {
+                let millis = args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        "--request-deadline expects milliseconds",
+                    )
+                })?;
+                config.request_deadline = match millis {
+                    0 => None,
+                    millis => Some(Duration::from_millis(millis)),
+                };
+            }
+            "--max-response" => {
+                config.max_response =
+                    args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                        Error::new(ErrorKind::InvalidInput, "--max-response expects bytes")
+                    })?;
+            }
+            "--flush-policy" => {
+                let policy = args.next().unwrap_or_default();
+                config.flush_policy = policy
+                    .parse()
+                    .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            }
+            "--max-buffered" => {
+                config.max_buffered =
+                    args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                        Error::new(ErrorKind::InvalidInput, "--max-buffered expects bytes")
+                    })?;
+            }
+            "--flush-dir" => {
+                config.flush_dir = Some(args.next().map(PathBuf::from).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, "--flush-dir expects a directory")
+                })?);
+            }
+            "--config" => {
+                *config_file = Some(args.next().ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, "--config expects a file")
+                })?);
+            }
+            _ if arg.starts_with("--") => {
+                let msg = format!("unknown option {}", arg);
+                return Err(Error::new(ErrorKind::InvalidInput, msg));
+            }
+            _ => *addr = arg,
+        }
+    }
+    Ok(())
+}
+
+/// Reloads the configuration on every SIGHUP, from `args` and the config file
+/// they name. Only what can change live is applied, a configuration failing
+/// to load or validate leaves the current one in place
+#[cfg(unix)]
+async fn reload_on_hangup(config: service::ConfigHandle, args: Vec<String>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup()).expect("SIGHUP handler");
+    while hangup.recv().await.is_some() {
+        match load_config(&args).and_then(|(_, reloaded)| config.reload(reloaded)) {
+            Ok(ignored) if ignored.is_empty() => tracing::info!("Configuration reloaded"),
+            Ok(ignored) => tracing::info!(
+                "Configuration reloaded, changes to {} need a restart",
+                ignored.join(", ")
+            ),
+            Err(e) => tracing::warn!("Keeping the current configuration: {}", e),
+        }
+    }
+}
@@ -0,0 +1,559 @@
+//! A minimal compression server on `std::net` and threads, for deployments
+//! where an async runtime is overkill
+//!
+//! Requests are handled by the same `handle_request` as the async `Server`'s,
+//! and judged and accounted for by the same `framing`, the two only differ in
+//! how they wait on their streams
+
+use crate::message::{self, GoodbyeReason, Response};
+use crate::server::framing::{self, Conduct};
+use crate::server::{handle_request_scoped, Draining, Goodbye, Limits, ServerConfig, State};
+use std::{
+    cmp,
+    io::{Error, ErrorKind, Read, Write},
+    mem,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use zerocopy::AsBytes;
+use zeroize::Zeroize;
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+/// Worker threads of a `Server` whose config doesn't limit its connections
+pub const DEFAULT_WORKERS: usize = 16;
+
+/// A stream whose reads time out, how the blocking `Server` bounds its waits
+/// without a runtime: for a request, as whoever accepted the stream set it
+/// (`ServerConfig::idle_timeout` for the `Server`'s own), and for the rest
+/// of one cut short under `ServerConfig::frame_timeout`
+pub trait ReadTimeout {
+    fn read_timeout(&self) -> Result<Option<Duration>>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()>;
+}
+
+impl ReadTimeout for TcpStream {
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        TcpStream::read_timeout(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl ReadTimeout for std::os::unix::net::UnixStream {
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        std::os::unix::net::UnixStream::read_timeout(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl<T: ReadTimeout> ReadTimeout for &T {
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        (**self).read_timeout()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+}
+
+/// The blocking compression Server
+///
+/// A fixed pool of worker threads each accept and serve one connection at a
+/// time, `ServerConfig::max_connections` of them (`DEFAULT_WORKERS` if
+/// unlimited). Idle connections are timed out with `set_read_timeout`
+///
+/// # Example
+/// ```no_run
+/// use service::blocking::Server;
+/// use std::thread;
+///
+/// let server = Server::new_with_url("127.0.0.1:4000").unwrap();
+/// let shutdown = server.shutdown_handle().unwrap();
+/// let serving = thread::spawn(move || server.serve());
+/// // ...
+/// shutdown.shutdown();
+/// serving.join().unwrap().unwrap();
+/// ```
+pub struct Server {
+    pub listener: TcpListener,
+    the_state: Arc<Mutex<State>>,
+    config: Arc<ServerConfig>,
+    shutdown: Arc<AtomicBool>,
+}
+
+/// Stops a blocking `Server` from another thread, see `Server::shutdown_handle`
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    flag: Arc<AtomicBool>,
+    draining: Draining,
+    addr: SocketAddr,
+    workers: usize,
+}
+
+impl Shutdown {
+    /// Stops the server from accepting connections, `Server::serve` returns
+    /// once the connections being served are closed. Health requests report
+    /// draining from then on
+    pub fn shutdown(&self) {
+        self.draining.start();
+        self.flag.store(true, Ordering::SeqCst);
+        // every worker blocked in accept is woken by a connection of its own
+        for _ in 0..self.workers {
+            let _ = TcpStream::connect(self.addr);
+        }
+    }
+}
+
+impl Server {
+    pub fn new_with_url(url: &str) -> Result<Server> {
+        Server::new_with_config(url, Default::default())
+    }
+
+    /// Creates a server listening at `url` that behaves according to `config`
+    pub fn new_with_config(url: &str, config: ServerConfig) -> Result<Server> {
+        let listener = TcpListener::bind(url)?;
+        Ok(Server {
+            listener,
+            the_state: Arc::new(Mutex::new(State::new())),
+            config: Arc::new(config),
+            shutdown: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn shutdown_handle(&self) -> Result<Shutdown> {
+        Ok(Shutdown {
+            flag: Arc::clone(&self.shutdown),
+            draining: self.the_state.lock().unwrap().draining(),
+            addr: self.listener.local_addr()?,
+            workers: self.workers(),
+        })
+    }
+
+    fn workers(&self) -> usize {
+        match self.config.max_connections {
+            0 => DEFAULT_WORKERS,
+            n => n,
+        }
+    }
+
+    /// Accept loop of every worker thread, returns once the server is shut
+    /// down and all of them are done
+    pub fn serve(&self) -> Result<()> {
+        tracing::info!(
+            "Starting Compression Service @ {}",
+            self.listener.local_addr()?
+        );
+        let workers = (0..self.workers())
+            .map(|_| {
+                let listener = self.listener.try_clone()?;
+                let state = Arc::clone(&self.the_state);
+                let config = Arc::clone(&self.config);
+                let shutdown = Arc::clone(&self.shutdown);
+                Ok(thread::spawn(move || {
+                    Server::work(listener, &state, &config, &shutdown)
+                }))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for worker in workers {
+            let _ = worker.join();
+        }
+        Ok(())
+    }
+
+    fn work(
+        listener: TcpListener,
+        state: &Mutex<State>,
+        config: &ServerConfig,
+        shutdown: &AtomicBool,
+    ) {
+        let (accepts, peaks, gauge) = {
+            let state = state.lock().unwrap();
+            (state.accepts(), state.peaks(), state.connections())
+        };
+        loop {
+            let accepted = listener.accept();
+            if shutdown.load(Ordering::SeqCst) {
+                return;
+            }
+            match accepted {
+                Ok((stream, peer_addr)) => {
+                    let _counted = (gauge.accept(), peaks.open_connection());
+                    let result = stream
+                        .set_read_timeout(config.idle_timeout)
+                        .and_then(|_| Server::process(stream, state, config));
+                    if let Err(e) = result {
+                        tracing::warn!("{}", e)
+                    }
+
+                    tracing::info!("Client @ {:?} Complete", peer_addr);
+                }
+                Err(e) => {
+                    let class = accepts.update_error(&e);
+                    tracing::error!("accept failed ({}): {:?}", class.name(), e)
+                }
+            }
+        }
+    }
+
+    /// Process communication from a given client connection like the async
+    /// `Server::process` does, a read timing out is taken for an idle
+    /// timeout. The state is only locked while a request is handled, never
+    /// while writing its response
+    pub fn process<S>(mut stream: S, state: &Mutex<State>, config: &ServerConfig) -> Result<()>
+    where
+        S: Read + Write + ReadTimeout,
+    {
+        let mut rx = [0u8; message::MAX_MESSAGE_PADDED];
+        let mut tx = [0u8; message::MAX_MESSAGE_PADDED];
+        // the stats of this connection alone, the scope ResetStats resets by
+        // default
+        let mut session = State::new();
+        let mut requests = 0;
+        // the strikes and violations the peer is closed for
+        let mut conduct = Conduct::new();
+        let in_flight = state.lock().unwrap().in_flight();
+        let opened = Instant::now();
+        // bytes read past the last message answered, the start of the next
+        let mut next = 0;
+        if config.hello {
+            Server::say_hello(&mut stream, state, &config.limits())?;
+        }
+        loop {
+            let continued = mem::take(&mut next);
+            let read = match continued {
+                0 => Server::read_request(&mut stream, &mut rx),
+                next => Ok(next),
+            };
+            let read = read.and_then(|read| {
+                let frame_timeout = config.frame_timeout;
+                Server::read_whole(&mut stream, &mut rx, read, continued > 0, frame_timeout)
+            });
+            let read = match read {
+                Ok(read) => read,
+                Err(e) if e.kind() == ErrorKind::TimedOut => {
+                    let goodbye = Goodbye::new_with(
+                        session.snapshot().stats,
+                        requests,
+                        GoodbyeReason::IdleTimeout,
+                    );
+                    Server::say_goodbye(&mut stream, state, goodbye);
+                    return Err(e);
+                }
+                Err(e) => return Err(e),
+            };
+            if read == 0 {
+                // closed, or half-closed by a client done sending, like the
+                // async server every message read before is answered
+                return stream.flush();
+            }
+
+            // a read of more than one message is answered a message at a
+            // time, like the async server
+            let (bytes_read, skipped) =
+                framing::frame(&rx[..read], config.enforcement).unwrap_or((read, 0));
+
+            // MessageTooLarge so, drain the rest of the message its header
+            // declares before answering, like the async server
+            let drained = match framing::oversize_left(&rx[..bytes_read]) {
+                0 => 0,
+                left => Server::drain(&mut stream, left, config.zeroize_buffers)?,
+            };
+            let discarded = framing::discarded(bytes_read, skipped, drained);
+            let dropped = drained + skipped;
+
+            // the request is held until its response is written, over the
+            // budget it isn't handled
+            let budget = config.memory_budget_of(&rx[..bytes_read]);
+            let mut reservation = in_flight.reserve(bytes_read, budget);
+            // accounted for in a pending state of its own like the async
+            // server does, handled against a view of the shared state and
+            // applied to it once the response is written
+            let mut pending = state.lock().unwrap().pending();
+            let received = dropped + bytes_read;
+            framing::account_read(&mut pending, &mut session, received, discarded, config);
+            let (size, code) = if reservation.is_none() {
+                framing::set_busy(&mut tx, &mut pending)
+            } else {
+                let request = &rx[..bytes_read];
+                let dispatched = Instant::now();
+                let size = {
+                    let mut shared = state.lock().unwrap();
+                    pending = shared.view(pending);
+                    let size =
+                        handle_request_scoped(request, &mut tx, &mut pending, &mut session, config)
+                            .expect("tx holds any response");
+                    shared.admit_from(&pending);
+                    size
+                };
+                let took = dispatched.elapsed();
+                // nothing handled on this thread can be cut short, a
+                // request running long is only told about
+                if let Some(deadline) =
+                    framing::account_handled(&mut pending, &mut session, took, config)
+                {
+                    tracing::warn!(
+                        "Request {:?} of {} bytes took {:?}, past the deadline of {:?}",
+                        message::Request::of(request),
+                        bytes_read,
+                        took,
+                        deadline
+                    );
+                }
+                let code = message::Message::parse(&tx[..size]).unwrap().header.code();
+                (size, code)
+            };
+
+            let verdict = conduct.judge(&rx[..bytes_read], code, discarded, config);
+
+            if verdict.respond {
+                if let Some(reservation) = reservation.as_mut() {
+                    reservation.grow(size);
+                }
+                // a failed write still accounts for the bytes that made it out
+                let (written, result) = Server::write_response(&mut stream, &tx[..size]);
+                if config.zeroize_buffers {
+                    rx[..bytes_read].zeroize();
+                    tx.zeroize();
+                }
+                if let Err(e) = result {
+                    let mut shared = state.lock().unwrap();
+                    framing::account_failed(&mut shared, pending, &session, written, config);
+                    return Err(e);
+                }
+                framing::account_sent(&mut pending, &mut session, written, config);
+                requests += 1;
+            } else if config.zeroize_buffers {
+                rx[..bytes_read].zeroize();
+                tx.zeroize();
+            }
+            next = framing::keep_pending(&mut rx, bytes_read + skipped..read, config);
+            let closed = verdict.closed(&mut pending);
+            state.lock().unwrap().apply(pending);
+
+            if let Some(e) = closed {
+                if let Some(reason) = verdict.goodbye(config) {
+                    let goodbye = Goodbye::new_with(session.snapshot().stats, requests, reason);
+                    Server::say_goodbye(&mut stream, state, goodbye);
+                }
+                return Err(e);
+            }
+
+            // the lifetime is only checked once a request is answered, the
+            // read timeout set for the connection is its idle timeout
+            if config.rotation_due(requests as usize, opened.elapsed()) {
+                state.lock().unwrap().update_rotation();
+                let goodbye =
+                    Goodbye::new_with(session.snapshot().stats, requests, GoodbyeReason::Rotated);
+                Server::say_goodbye(&mut stream, state, goodbye);
+                return Ok(());
+            }
+        }
+    }
+
+    /// Reads the next request into `rx`, a read timeout is reported as
+    /// `TimedOut` whichever error the platform uses for it
+    fn read_request<S: Read>(stream: &mut S, rx: &mut [u8]) -> Result<usize> {
+        loop {
+            match stream.read(rx) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    return Err(Error::new(ErrorKind::TimedOut, "Idle timeout"))
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Reads on after the `read` bytes of `rx` until the message they start
+    /// is whole, like the async server, returning the bytes read in all.
+    /// Stops early at the end of the stream, on a read timing out or
+    /// `frame_timeout` from now, the message is then answered for what it
+    /// has. The stream's own read timeout is restored once done
+    fn read_whole<S: Read + ReadTimeout>(
+        stream: &mut S,
+        rx: &mut [u8],
+        mut read: usize,
+        continued: bool,
+        frame_timeout: Option<Duration>,
+    ) -> Result<usize> {
+        if read == 0 || framing::missing(&rx[..read], continued) == 0 {
+            return Ok(read);
+        }
+        let idle_timeout = stream.read_timeout()?;
+        let started = Instant::now();
+        let joined = loop {
+            if framing::missing(&rx[..read], continued) == 0 {
+                break Ok(read);
+            }
+            let left = frame_timeout.map(|timeout| timeout.saturating_sub(started.elapsed()));
+            let timeout = match (idle_timeout, left) {
+                (_, Some(Duration::ZERO)) => break Ok(read),
+                (Some(idle), Some(left)) => Some(idle.min(left)),
+                (idle, left) => idle.or(left),
+            };
+            if let Err(e) = stream.set_read_timeout(timeout) {
+                break Err(e);
+            }
+            match Server::read_request(stream, &mut rx[read..]) {
+                Ok(0) => break Ok(read),
+                Ok(more) => read += more,
+                Err(e) if e.kind() == ErrorKind::TimedOut => break Ok(read),
+                Err(e) => break Err(e),
+            }
+        };
+        stream.set_read_timeout(idle_timeout)?;
+        joined
+    }
+
+    /// Reads and throws away the next `len` bytes of `stream`, returning how
+    /// many there were. Stops early at the end of the stream or on a read
+    /// timing out, the next read finds out which
+    fn drain<S: Read>(stream: &mut S, len: usize, zeroize: bool) -> Result<usize> {
+        let mut bytes = [0u8; message::MAX_MESSAGE_PADDED];
+        let mut drained = 0;
+        while drained < len {
+            let want = cmp::min(len - drained, bytes.len());
+            let read = match Server::read_request(stream, &mut bytes[..want]) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == ErrorKind::TimedOut => break,
+                Err(e) => return Err(e),
+            };
+            if zeroize {
+                bytes[..read].zeroize();
+            }
+            drained += read;
+        }
+        Ok(drained)
+    }
+
+    /// Sends a Goodbye before the connection is closed, a failure to do so
+    /// changes nothing as the connection is closing anyway
+    fn say_goodbye<S: Write>(stream: &mut S, state: &Mutex<State>, goodbye: Goodbye) {
+        let mut tx = [0u8; message::HEADER_SIZE + mem::size_of::<Goodbye>()];
+        let mut message = message::Message::parse_mut(&mut tx[..]).unwrap();
+        message.set_all(
+            message::MAGIC,
+            goodbye.as_bytes().len() as u16,
+            Response::Goodbye as u16,
+            goodbye.as_bytes(),
+        );
+        let (written, _) = Server::write_response(stream, &tx);
+        state.lock().unwrap().update_sent(written);
+    }
+
+    /// Greets a connection just accepted with a Hello of `limits`, like the
+    /// async server
+    fn say_hello<S: Write>(stream: &mut S, state: &Mutex<State>, limits: &Limits) -> Result<()> {
+        let mut tx = [0u8; message::HEADER_SIZE + mem::size_of::<Limits>()];
+        let mut message = message::Message::parse_mut(&mut tx[..]).unwrap();
+        message.set_all(
+            message::MAGIC,
+            limits.as_bytes().len() as u16,
+            Response::Hello as u16,
+            limits.as_bytes(),
+        );
+        let (written, result) = Server::write_response(stream, &tx);
+        state.lock().unwrap().update_sent(written);
+        result
+    }
+
+    /// Writes all of `buf`, also reporting how much of it was written when
+    /// the write fails
+    fn write_response<S: Write>(stream: &mut S, mut buf: &[u8]) -> (usize, Result<()>) {
+        let mut written = 0;
+        while !buf.is_empty() {
+            match stream.write(buf) {
+                Ok(0) => return (written, Err(ErrorKind::WriteZero.into())),
+                Ok(n) => {
+                    written += n;
+                    buf = &buf[n..];
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return (written, Err(e)),
+            }
+        }
+        (written, Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::HealthStatus;
+    use std::time::Duration;
+
+    #[test]
+    fn test_oversize_drain() {
+        let server = Server::new_with_config("127.0.0.1:0", ServerConfig::default()).unwrap();
+        let addr = server.listener.local_addr().unwrap();
+        let shutdown = server.shutdown_handle().unwrap();
+        let serving = thread::spawn(move || server.serve());
+
+        // the rest of the Compress its header declares is drained, the Ping
+        // written along with it is answered
+        let size = message::MAX_PAYLOAD * 3 + 5;
+        let mut bytes = vec![b'a'; message::total_response_len(size as usize)];
+        let header = message::Header::raw(message::MAGIC, size, message::Request::Compress as u16);
+        bytes[..message::HEADER_SIZE].copy_from_slice(header.as_bytes());
+        let ping = message::Header::request(message::Request::Ping, 0).unwrap();
+        bytes.extend(ping.as_bytes());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&bytes).unwrap();
+        let mut response = [0u8; message::HEADER_SIZE];
+        stream.read_exact(&mut response).unwrap();
+        assert_eq!(response[7], Response::MessageTooLarge as u8);
+        stream.read_exact(&mut response).unwrap();
+        assert_eq!(response, [83, 84, 82, 89, 0, 0, 0, 0]);
+        drop(stream);
+        shutdown.shutdown();
+        serving.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_serve_and_shutdown() {
+        let config = ServerConfig {
+            max_connections: 2,
+            idle_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        let server = Server::new_with_config("127.0.0.1:0", config).unwrap();
+        let addr = server.listener.local_addr().unwrap();
+        let shutdown = server.shutdown_handle().unwrap();
+        let serving = thread::spawn(move || server.serve());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut ping = [0u8; message::HEADER_SIZE];
+        message::Message::parse_mut(&mut ping[..])
+            .unwrap()
+            .set_header_with_default_magic(0, message::Request::Ping as u16);
+        stream.write_all(&ping).unwrap();
+        let mut response = [0u8; message::HEADER_SIZE];
+        stream.read_exact(&mut response).unwrap();
+        assert_eq!(response, [83, 84, 82, 89, 0, 0, 0, 0]);
+
+        // the connection still open is served, as draining
+        shutdown.shutdown();
+        let health = message::Header::request(message::Request::Health, 0).unwrap();
+        stream.write_all(health.as_bytes()).unwrap();
+        let mut response = [0u8; message::HEADER_SIZE + 14];
+        stream.read_exact(&mut response).unwrap();
+        assert_eq!(response[message::HEADER_SIZE], HealthStatus::Draining as u8);
+        assert_eq!(&response[message::HEADER_SIZE + 1..], b"shutting down");
+        drop(stream);
+        serving.join().unwrap().unwrap();
+    }
+}
@@ -8,7 +8,17 @@
 //! The service is also able to respond to several other types of `Request`s
 //!
 //! The unit of communcation is done through a `Message`
+//!
+//! Without the default `server` feature only the wire format is built, i.e.
+//! for clients on embedded targets, the crate is then #![no_std] and never
+//! allocates
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
 pub mod message;
 pub use message::*;
 pub mod server;
 pub use server::*;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
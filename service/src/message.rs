@@ -1,15 +1,74 @@
 use byteorder::NetworkEndian;
-use std::{cmp, error::Error, fmt, mem};
+use core::{cmp, error::Error, fmt, mem};
 use zerocopy::{
     byteorder::{U16, U32},
     AsBytes, ByteSlice, ByteSliceMut, FromBytes, LayoutVerified,
 };
 
+#[cfg(feature = "std")]
+pub use batch::encode_batch;
+pub use batch::{BatchEntries, BatchWriter, TruncatedEntry, BATCH_LENGTH_PREFIX};
+#[cfg(feature = "std")]
+pub use hexdump::{hexdump, HEXDUMP_DEFAULT_ROWS, HEXDUMP_ROW_WIDTH};
+pub use policy::{CharCategory, CharPolicy, InvalidPayload, PolicyError};
+pub use sequence::{sequence, SEQUENCE_LEN};
+#[cfg(feature = "std")]
+pub use sequence::{with_sequence, without_sequence};
+
+mod batch;
+#[cfg(feature = "std")]
+mod hexdump;
+mod policy;
+mod sequence;
+
 pub const MAGIC: u32 = 0x5354_5259_u32;
+/// Reported by GetConfig, bumped on incompatible changes to the protocol
+pub const PROTOCOL_VERSION: u8 = 1;
 pub const HEADER_SIZE: usize = mem::size_of::<Header>();
 pub const MAX_PAYLOAD: u16 = 1 << 13;
 pub const MAX_MESSAGE: usize = HEADER_SIZE + MAX_PAYLOAD as usize;
 pub const MAX_MESSAGE_PADDED: usize = MAX_MESSAGE + 8;
+/// Longest ASCII detail an error response carries when the service sends
+/// them, e.g. "header.size=5 but payload=3 bytes"
+pub const MAX_ERROR_DETAIL: usize = 128;
+/// Bytes of the idempotency token a ResetStats may carry after its scope,
+/// see `Feature::IDEMPOTENT_RESET`
+pub const RESET_TOKEN_LEN: usize = 8;
+
+/// The low byte of a header's code field holds the request / response code
+pub const CODE_MASK: u16 = 0x00FF;
+/// The high byte of a header's code field is a bitmask of `Flag`s
+pub const FLAGS_MASK: u16 = 0xFF00;
+
+/// Named bits of the flags byte, see `Header::flags`
+///
+/// Only `SEQUENCED` is defined on the wire yet, a message without a sequence
+/// number has flags of 0 as every existing client's. The other names reserve
+/// the bits for booleans that would otherwise each need a new request /
+/// response code
+pub struct Flag;
+
+impl Flag {
+    /// The payload is followed by a checksum
+    pub const CHECKSUM: u8 = 1 << 0;
+    /// The payload was case-folded before compressing
+    pub const CASE_FOLDED: u8 = 1 << 1;
+    /// The compressed payload is stored as is, without any encoded run
+    pub const STORED: u8 = 1 << 2;
+    /// The stats payload was truncated
+    pub const TRUNCATED_STATS: u8 = 1 << 3;
+    /// The payload starts with a u16 sequence number of the client's, echoed
+    /// by the response to it, see `sequence`
+    ///
+    /// The number is the first `SEQUENCE_LEN` bytes of the payload, in
+    /// network byte order, and counts towards the size in the header. The
+    /// header has no room left for it, so the response echoes it in the
+    /// same place: the first bytes of its payload, ahead of the response's
+    /// own payload, with this flag set on the response too
+    pub const SEQUENCED: u8 = 1 << 4;
+    /// The flags the service understands in requests
+    pub const SUPPORTED: u8 = Flag::SEQUENCED;
+}
 
 /// The request code found within the header of received messages from the client
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -18,18 +77,207 @@ pub enum Request {
     GetStats = 2,
     ResetStats = 3,
     Compress = 4,
+    Decompress = 5,
+    /// Compress using the binary run-length scheme (`RleBinary`)
+    CompressBinary = 6,
+    /// Decompress the output of a `CompressBinary` request
+    DecompressBinary = 7,
+    /// The limits and features of the service (`Limits`)
+    GetConfig = 8,
+    /// Associates the connection with the tenant of the token in the
+    /// payload, see `ServerConfig::tenants`
+    Authenticate = 9,
+    /// Writes the stats of the whole service as JSON to a file of
+    /// `ServerConfig::flush_dir`, named by the payload if any
+    FlushStats = 10,
+    /// The stats of GetStats prefixed by their layout version, see
+    /// `Stats::parse_versioned`
+    GetStatsV2 = 11,
+    /// Compress and Ping requests handled in order, the payload is their
+    /// messages each prefixed by its length, see `BatchEntries`
+    Batch = 12,
+    /// Compress whose response payload is followed by the stats GetStats
+    /// would report right after the compression, see `STATS_LEN`
+    CompressWithStats = 13,
+    /// Whether the service is ready for more requests, the response payload
+    /// is a `HealthStatus` byte and, unless ready, why in ASCII
+    Health = 14,
 }
 
 impl Request {
+    /// The request of a header's code field, any flags are ignored
     pub fn from_u16(value: u16) -> Option<Request> {
-        match value {
+        match value & CODE_MASK {
             1 => Some(Request::Ping),
             2 => Some(Request::GetStats),
             3 => Some(Request::ResetStats),
             4 => Some(Request::Compress),
+            5 => Some(Request::Decompress),
+            6 => Some(Request::CompressBinary),
+            7 => Some(Request::DecompressBinary),
+            8 => Some(Request::GetConfig),
+            9 => Some(Request::Authenticate),
+            10 => Some(Request::FlushStats),
+            11 => Some(Request::GetStatsV2),
+            12 => Some(Request::Batch),
+            13 => Some(Request::CompressWithStats),
+            14 => Some(Request::Health),
+            _ => None,
+        }
+    }
+
+    /// The payloads the request takes, the one place every request registers
+    /// its policy, see `Header::validate_header`
+    pub fn payload_policy(&self) -> PayloadPolicy {
+        match self {
+            Request::Compress
+            | Request::Decompress
+            | Request::CompressBinary
+            | Request::DecompressBinary
+            | Request::CompressWithStats => PayloadPolicy::MustBeNonZero,
+            Request::Authenticate | Request::FlushStats | Request::Batch => PayloadPolicy::Any,
+            Request::ResetStats => PayloadPolicy::AtMost(1 + RESET_TOKEN_LEN as u16),
+            Request::Ping
+            | Request::GetStats
+            | Request::GetConfig
+            | Request::GetStatsV2
+            | Request::Health => PayloadPolicy::MustBeZero,
+        }
+    }
+
+    /// The request of the message at the start of `bytes`, by its header
+    /// alone
+    pub fn of(bytes: &[u8]) -> Option<Request> {
+        Message::parse(bytes).and_then(|message| Request::from_u16(message.header.code()))
+    }
+}
+
+/// The payload sizes a request takes, within MAX_PAYLOAD for every request
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum PayloadPolicy {
+    /// Header only, a payload is answered RequestKindRequiresZeroLength
+    MustBeZero,
+    /// At least one byte, none is answered
+    /// CompressionRequestRequiresNonZeroLength
+    MustBeNonZero,
+    /// At most this many bytes, more are answered
+    /// RequestKindRequiresZeroLength
+    AtMost(u16),
+    /// Any payload
+    Any,
+}
+
+impl PayloadPolicy {
+    /// The response to a payload of `size` bytes, already known to be
+    /// within MAX_PAYLOAD
+    pub fn validate(&self, size: u16) -> Response {
+        match (self, size) {
+            (PayloadPolicy::MustBeZero, 0) => Response::Ok,
+            (PayloadPolicy::MustBeZero, _) => Response::RequestKindRequiresZeroLength,
+            (PayloadPolicy::MustBeNonZero, 0) => Response::CompressionRequestRequiresNonZeroLength,
+            (PayloadPolicy::MustBeNonZero, _) => Response::Ok,
+            (PayloadPolicy::AtMost(max), size) if size > *max => {
+                Response::RequestKindRequiresZeroLength
+            }
+            (PayloadPolicy::AtMost(_), _) | (PayloadPolicy::Any, _) => Response::Ok,
+        }
+    }
+}
+
+/// The stats cleared by a ResetStats request, given by the first byte of its
+/// optional payload, which may go on with a `RESET_TOKEN_LEN` bytes token
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ResetScope {
+    /// The stats of the requesting connection only
+    Connection = 0,
+    /// The stats of the whole service, see `ServerConfig::allow_global_reset`
+    Global = 1,
+    /// The stats of the tenant of the requesting connection, see
+    /// `Request::Authenticate`
+    Tenant = 2,
+}
+
+impl ResetScope {
+    pub fn from_u8(value: u8) -> Option<ResetScope> {
+        match value {
+            0 => Some(ResetScope::Connection),
+            1 => Some(ResetScope::Global),
+            2 => Some(ResetScope::Tenant),
+            _ => None,
+        }
+    }
+}
+
+/// Why the service closed a connection, the last byte of a `Goodbye`
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum GoodbyeReason {
+    /// No request was received within the idle timeout
+    IdleTimeout = 1,
+    /// The client sent requests faster than the service's rate limit
+    RateLimited = 2,
+    /// The service is shutting down
+    Shutdown = 3,
+    /// The client kept sending messages far larger than MAX_MESSAGE
+    Abuse = 4,
+    /// The client kept sending messages without the protocol's magic
+    BadMagic = 5,
+    /// The connection reached its request cap or lifetime, the client is
+    /// expected to reconnect, possibly to another instance of the service
+    Rotated = 6,
+    /// The client kept violating the protocol under strict enforcement
+    Violations = 7,
+}
+
+impl GoodbyeReason {
+    pub fn from_u8(value: u8) -> Option<GoodbyeReason> {
+        match value {
+            1 => Some(GoodbyeReason::IdleTimeout),
+            2 => Some(GoodbyeReason::RateLimited),
+            3 => Some(GoodbyeReason::Shutdown),
+            4 => Some(GoodbyeReason::Abuse),
+            5 => Some(GoodbyeReason::BadMagic),
+            6 => Some(GoodbyeReason::Rotated),
+            7 => Some(GoodbyeReason::Violations),
+            _ => None,
+        }
+    }
+}
+
+/// The first byte of the payload of a Health response, from the most to the
+/// least severe the service reports the first that applies
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum HealthStatus {
+    Ready = 0,
+    /// The service is shutting down, the connections open are served until
+    /// they close but no more are accepted
+    Draining = 1,
+    /// The requests in flight leave no room within the memory budget for a
+    /// message of MAX_MESSAGE, which would be answered ServerBusy, or the
+    /// connections open reached the limit and more would wait to be accepted
+    Overloaded = 2,
+    /// The service had an internal error, e.g. failed to flush its stats
+    Degraded = 3,
+}
+
+impl HealthStatus {
+    pub fn from_u8(value: u8) -> Option<HealthStatus> {
+        match value {
+            0 => Some(HealthStatus::Ready),
+            1 => Some(HealthStatus::Draining),
+            2 => Some(HealthStatus::Overloaded),
+            3 => Some(HealthStatus::Degraded),
             _ => None,
         }
     }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HealthStatus::Ready => "ready",
+            HealthStatus::Draining => "draining",
+            HealthStatus::Overloaded => "overloaded",
+            HealthStatus::Degraded => "degraded",
+        }
+    }
 }
 
 /// The response code found within the header of sent messages from the server
@@ -52,7 +300,111 @@ pub enum Response {
     /// Compression request requires a header with a non-zero length field
     CompressionRequestRequiresNonZeroLength = 38,
     /// Compression request payload includes non lowercase ascii characters
+    /// Also the catch-all when several kinds of invalid characters are found
     MessagePayloadContainsInvalidCharacters = 39,
+    /// Compression request payload includes uppercase ascii characters
+    MessageContainsUppercaseCharacters = 40,
+    /// Compression request payload includes ascii digits
+    MessageContainsDigits = 41,
+    /// Compression request payload includes non-ascii bytes
+    MessageContainsNonAscii = 42,
+    /// Decompression request payload is not a valid compressed payload
+    MalformedCompressedPayload = 43,
+    /// The response to the request would be larger than MAX_PAYLOAD
+    ResponseTooLarge = 44,
+    /// The request header sets flags the service doesn't support
+    UnsupportedFlags = 45,
+    /// The request is not allowed under the service's configuration, i.e. a
+    /// global ResetStats without `allow_global_reset`
+    Forbidden = 46,
+    /// The ResetStats payload is not a `ResetScope`, alone or followed by a
+    /// token the service accepts
+    UnsupportedResetScope = 47,
+    /// The service is closing the connection, the payload is a `Goodbye`
+    /// summary of the connection, not a response to any request
+    Goodbye = 48,
+    /// The service is short of memory for the request, it may be retried
+    /// once the requests in flight are answered
+    ServerBusy = 49,
+    /// The token of an Authenticate request is not one of the service's
+    Unauthorized = 50,
+    /// The service failed to write the file of a FlushStats
+    IoError = 51,
+    /// The message goes on past the payload its size field declares, only
+    /// told apart from `MessageHeaderSizeMismatch` under strict enforcement
+    TrailingBytes = 52,
+    /// The Decompress payload isn't what Compress would send for its output,
+    /// e.g. "1a2a" or "03a" for "aaa", only rejected under strict enforcement
+    NonCanonicalEncoding = 53,
+    /// The responses to the entries of a Batch request, in order, each
+    /// prefixed by its length like the entries
+    Batch = 54,
+    /// The Batch request has more entries than the service handles in one,
+    /// none of them was handled
+    BatchTooLarge = 55,
+    /// The service greets a connection it just accepted, the payload is the
+    /// `Limits` GetConfig answers with. Not a response to any request, only
+    /// sent with `ServerConfig::hello`
+    Hello = 56,
+}
+
+impl Response {
+    /// The response of a header's code field, any flags are ignored
+    pub fn from_u16(value: u16) -> Option<Response> {
+        let response = match value & CODE_MASK {
+            0 => Response::Ok,
+            1 => Response::UnknownError,
+            2 => Response::MessageTooLarge,
+            3 => Response::UnsupportedRequestType,
+            34 => Response::MessageTooSmall,
+            35 => Response::MessageHeaderHasBadMagic,
+            36 => Response::MessageHeaderSizeMismatch,
+            37 => Response::RequestKindRequiresZeroLength,
+            38 => Response::CompressionRequestRequiresNonZeroLength,
+            39 => Response::MessagePayloadContainsInvalidCharacters,
+            40 => Response::MessageContainsUppercaseCharacters,
+            41 => Response::MessageContainsDigits,
+            42 => Response::MessageContainsNonAscii,
+            43 => Response::MalformedCompressedPayload,
+            44 => Response::ResponseTooLarge,
+            45 => Response::UnsupportedFlags,
+            46 => Response::Forbidden,
+            47 => Response::UnsupportedResetScope,
+            48 => Response::Goodbye,
+            49 => Response::ServerBusy,
+            50 => Response::Unauthorized,
+            51 => Response::IoError,
+            52 => Response::TrailingBytes,
+            53 => Response::NonCanonicalEncoding,
+            54 => Response::Batch,
+            55 => Response::BatchTooLarge,
+            56 => Response::Hello,
+            _ => return None,
+        };
+        Some(response)
+    }
+
+    /// The request was served, Ok or the responses to the entries of a
+    /// Batch, whichever they are
+    pub fn is_success(&self) -> bool {
+        matches!(self, Response::Ok | Response::Batch)
+    }
+
+    /// The request broke the framing or encoding rules of the protocol,
+    /// rather than asking for something the service won't do. Bad magic and
+    /// oversized messages have strikes of their own
+    pub fn is_violation(&self) -> bool {
+        matches!(
+            self,
+            Response::UnsupportedRequestType
+                | Response::MessageTooSmall
+                | Response::MessageHeaderSizeMismatch
+                | Response::RequestKindRequiresZeroLength
+                | Response::UnsupportedFlags
+                | Response::TrailingBytes
+                | Response::NonCanonicalEncoding
+        )
+    }
 }
 
 /// A Message's header field
@@ -69,8 +421,27 @@ pub struct Header {
 }
 
 impl Header {
-    // used only for Client Tests
-    pub fn new_with(sign: u32, size: u16, code: u16) -> Header {
+    /// The header of a `request` with a payload of `size` bytes
+    pub fn request(request: Request, size: u16) -> Result<Header, SizeTooLarge> {
+        Header::checked(size, request as u16)
+    }
+
+    /// The header of a `response` with a payload of `size` bytes
+    pub fn response(response: Response, size: u16) -> Result<Header, SizeTooLarge> {
+        Header::checked(size, response as u16)
+    }
+
+    fn checked(size: u16, code: u16) -> Result<Header, SizeTooLarge> {
+        if size > MAX_PAYLOAD {
+            return Err(SizeTooLarge { size });
+        }
+        Ok(Header::raw(MAGIC, size, code))
+    }
+
+    /// A header of any fields, for the tests that need one the service
+    /// rejects, e.g. without the magic or with a size over MAX_PAYLOAD.
+    /// Otherwise see `Header::request` and `Header::response`
+    pub fn raw(sign: u32, size: u16, code: u16) -> Header {
         Header {
             sign: U32::new(sign),
             size: U16::new(size),
@@ -102,25 +473,44 @@ impl Header {
         self.code.set(code);
     }
 
-    /// Validates the header of a client's request message
+    /// The `Flag` bits, the high byte of the code field
+    pub fn flags(&self) -> u8 {
+        ((self.code.get() & FLAGS_MASK) >> 8) as u8
+    }
+
+    /// Sets the `Flag` bits, keeping the request / response code
+    pub fn set_flags(&mut self, flags: u8) {
+        self.code
+            .set((self.code.get() & CODE_MASK) | (flags as u16) << 8);
+    }
+
+    /// Validates the header of a client's request message, rejecting flags
+    /// the service doesn't support
     /// returns a `Response` relative to the `Request`
     pub fn validate_header(&self) -> Response {
-        let request = Request::from_u16(self.code.get());
+        self.validate_header_with(true)
+    }
+
+    /// Validates the header of a client's request message, flags the service
+    /// doesn't support are rejected if `strict_flags` and ignored otherwise
+    ///
+    /// A size over MAX_PAYLOAD is too large whatever the request, only then
+    /// is it held to the request's `PayloadPolicy`
+    pub fn validate_header_with(&self, strict_flags: bool) -> Response {
         if self.sign.get() != MAGIC {
             return Response::MessageHeaderHasBadMagic;
         }
-        if request.is_none() {
-            return Response::UnsupportedRequestType;
+        if strict_flags && self.flags() & !Flag::SUPPORTED != 0 {
+            return Response::UnsupportedFlags;
         }
-        match (request.unwrap(), self.size.get()) {
-            (Request::Compress, n) => match n {
-                0 => Response::CompressionRequestRequiresNonZeroLength,
-                n if n > MAX_PAYLOAD => Response::MessageTooLarge,
-                _ => Response::Ok,
-            },
-            (_, 0) => Response::Ok,
-            (_, _) => Response::RequestKindRequiresZeroLength,
+        let request = match Request::from_u16(self.code.get()) {
+            Some(request) => request,
+            None => return Response::UnsupportedRequestType,
+        };
+        if self.size.get() > MAX_PAYLOAD {
+            return Response::MessageTooLarge;
         }
+        request.payload_policy().validate(self.size.get())
     }
 }
 
@@ -154,6 +544,31 @@ impl<B: ByteSlice> Message<B> {
         let (header, payload) = LayoutVerified::new_from_prefix(bytes)?;
         Some(Message { header, payload })
     }
+
+    /// The payload as described by the header's size field, clamped to the
+    /// bytes actually available so a lying size field can never index out of
+    /// bounds
+    pub fn payload_slice(&self) -> &[u8] {
+        let len = cmp::min(self.header.size() as usize, self.payload.len());
+        &self.payload[..len]
+    }
+
+    /// Hex dump of the header and (bounds-checked) payload of the message
+    /// limited to `HEXDUMP_DEFAULT_ROWS` rows
+    #[cfg(feature = "std")]
+    pub fn hexdump(&self) -> String {
+        self.hexdump_rows(HEXDUMP_DEFAULT_ROWS)
+    }
+
+    /// Hex dump of the header and (bounds-checked) payload of the message
+    /// limited to `max_rows` rows
+    #[cfg(feature = "std")]
+    pub fn hexdump_rows(&self, max_rows: usize) -> String {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.payload_slice().len());
+        bytes.extend_from_slice(self.header.as_bytes());
+        bytes.extend_from_slice(self.payload_slice());
+        hexdump(&bytes, max_rows)
+    }
 }
 
 impl<B: ByteSliceMut> Message<B> {
@@ -196,9 +611,12 @@ impl<B: ByteSliceMut> Message<B> {
 
     /// Sets the body of the payload from a given byte-slice
     /// returns error if the length of the input slice is larger than the message's payload length
-    pub fn set_payload(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
-        if bytes.len() > self.payload.len() as usize {
-            return Err("length of input exceeds payload size".into());
+    pub fn set_payload(&mut self, bytes: &[u8]) -> Result<(), PayloadTooLong> {
+        if bytes.len() > self.payload.len() {
+            return Err(PayloadTooLong {
+                len: bytes.len(),
+                capacity: self.payload.len(),
+            });
         }
         self.payload[..bytes.len()].clone_from_slice(bytes);
         Ok(())
@@ -210,58 +628,127 @@ impl<B: ByteSliceMut> Message<B> {
     }
 }
 
+/// The payload given to `Message::set_payload` doesn't fit the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadTooLong {
+    pub len: usize,
+    pub capacity: usize,
+}
+
+impl fmt::Display for PayloadTooLong {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "length of input ({}) exceeds payload size ({})",
+            self.len, self.capacity
+        )
+    }
+}
+
+impl Error for PayloadTooLong {}
+
+/// The size given to `Header::request` or `Header::response` is over
+/// MAX_PAYLOAD
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeTooLarge {
+    pub size: u16,
+}
+
+impl fmt::Display for SizeTooLarge {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "size ({}) exceeds the largest payload ({})",
+            self.size, MAX_PAYLOAD
+        )
+    }
+}
+
+impl Error for SizeTooLarge {}
+
 impl<B> Message<B>
 where
     B: ByteSlice,
 {
+    /// Validates the message under the default `CharPolicy` (lowercase ascii)
     pub fn validate(&self, bytes_read: usize) -> Response {
+        self.validate_with(bytes_read, &CharPolicy::default())
+    }
+
+    /// Validates the message, compression payloads are checked against `policy`
+    pub fn validate_with(&self, bytes_read: usize, policy: &CharPolicy) -> Response {
+        self.validate_using(bytes_read, |payload| policy.validate(payload))
+    }
+
+    /// Validates the structure of the message (sizes and header), the payload
+    /// of a compression request is then checked by `validate_payload`
+    pub fn validate_using<F>(&self, bytes_read: usize, validate_payload: F) -> Response
+    where
+        F: FnOnce(&[u8]) -> Response,
+    {
+        self.validate_flags_using(bytes_read, true, validate_payload)
+    }
+
+    /// Like `validate_using`, unsupported flags are only rejected if
+    /// `strict_flags`, see `Header::validate_header_with`
+    pub fn validate_flags_using<F>(
+        &self,
+        bytes_read: usize,
+        strict_flags: bool,
+        validate_payload: F,
+    ) -> Response
+    where
+        F: FnOnce(&[u8]) -> Response,
+    {
         if bytes_read < HEADER_SIZE {
             return Response::MessageTooSmall;
         }
-        if bytes_read > MAX_MESSAGE as usize {
+        if bytes_read > MAX_MESSAGE {
             return Response::MessageTooLarge;
         }
         if self.header.size() != payload_len(bytes_read) as u16 {
             return Response::MessageHeaderSizeMismatch;
         }
 
-        let response = self.header.validate_header();
+        let response = self.header.validate_header_with(strict_flags);
         let request = Request::from_u16(self.header.code());
         match (response, request) {
-            (Response::Ok, Some(Request::Compress)) => self.validate_payload(bytes_read),
+            (Response::Ok, Some(Request::Compress))
+            | (Response::Ok, Some(Request::CompressBinary))
+            | (Response::Ok, Some(Request::CompressWithStats)) => {
+                validate_payload(self.payload_slice())
+            }
             (response_code, _) => response_code,
         }
     }
 
-    pub fn validate_payload(&self, bytes_read: usize) -> Response {
-        if self.is_payload_valid(bytes_read) {
-            Response::Ok
-        } else {
-            Response::MessagePayloadContainsInvalidCharacters
-        }
+    pub fn validate_payload(&self, _bytes_read: usize) -> Response {
+        self.validate_payload_with(&CharPolicy::default())
+    }
+
+    /// Validates the payload against `policy`, see `CharPolicy::validate`
+    pub fn validate_payload_with(&self, policy: &CharPolicy) -> Response {
+        policy.validate(self.payload_slice())
     }
 
-    // could instead filter out valid characters (ascii_lowercase)
-    // then, check the unique character types found and relay a more precise message
-    // i.e.
-    // Resopnse::MessageContainsNumbers,
-    // Resopnse::MessageContainsUppercaseCharacters
     /// Validates the payload part of a message
-    /// Currently, a payload is only valid if it exclusively contains lowercase ascii characters
+    /// Under the default policy, a payload is only valid if it exclusively contains lowercase ascii characters
     pub fn is_payload_valid(&self, _bytes_read: usize) -> bool {
+        self.is_payload_valid_with(&CharPolicy::default())
+    }
+
+    pub fn is_payload_valid_with(&self, policy: &CharPolicy) -> bool {
         // There is a trade-off between validating before vs while compressing
-        self.payload[..self.header.size() as usize]
-            .iter()
-            .all(|x: &u8| (*x as char).is_ascii_lowercase())
+        self.payload_slice().iter().all(|x: &u8| policy.allows(*x))
     }
 }
 
 impl<B: ByteSlice> fmt::Display for Message<B> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        let max_size = cmp::min(self.header.size(), MAX_PAYLOAD) as usize;
+        let max_size = cmp::min(self.payload_slice().len(), MAX_PAYLOAD as usize);
         fmt.debug_struct("Message")
             .field("header", &*self.header)
-            .field("payload", &&self.payload[..max_size])
+            .field("payload", &&self.payload_slice()[..max_size])
             .finish()
     }
 }
@@ -282,17 +769,139 @@ pub fn total_response_len(payload_len: usize) -> usize {
     HEADER_SIZE + payload_len
 }
 
+/// The length of the message whose header `bytes` start with, header
+/// included, as its size field declares. None without a whole header or
+/// with a bad magic, a size can't be trusted from anything but a header
+///
+/// # Example
+/// ```
+/// use service::message::declared_len;
+/// let compress = [83u8, 84, 82, 89, 0x27, 0x10, 0, 4, 97, 97, 97];
+/// assert_eq!(declared_len(&compress), Some(10_008));
+/// assert_eq!(declared_len(&compress[..7]), None);
+/// assert_eq!(declared_len(&[0u8; 11]), None);
+/// ```
+pub fn declared_len(bytes: &[u8]) -> Option<usize> {
+    let message = Message::parse(bytes)?;
+    match message.header.sign() {
+        MAGIC => Some(total_response_len(message.header.size() as usize)),
+        _ => None,
+    }
+}
+
 /// Determine if a slice can be parsed/serialized into a `Message`
+/// Whether `bytes` hold a header, the size field it parses to isn't checked
+/// against anything, see `validate_wire` before indexing by it
 pub fn can_parse(bytes: &[u8]) -> bool {
     bytes.len() >= HEADER_SIZE
 }
 
+/// Validates the structure of the request `bytes` hold, exactly: its length
+/// against the header's size, the magic, the flags, a known request code and
+/// the request's `PayloadPolicy`, as `Message::validate` would with
+/// `bytes.len()` read. The characters of the payload aren't checked. Returns
+/// the request and its payload, or the response the service would send
+///
+/// # Example
+/// ```
+/// use service::message::{validate_wire, Request, Response};
+/// let compress = [83u8, 84, 82, 89, 0, 3, 0, 4, 97, 97, 97];
+/// assert_eq!(validate_wire(&compress), Ok((Request::Compress, &b"aaa"[..])));
+/// assert_eq!(validate_wire(&compress[..10]), Err(Response::MessageHeaderSizeMismatch));
+/// ```
+pub fn validate_wire(bytes: &[u8]) -> Result<(Request, &[u8]), Response> {
+    let message = Message::parse(bytes).ok_or(Response::MessageTooSmall)?;
+    match message.validate_using(bytes.len(), |_| Response::Ok) {
+        Response::Ok => (),
+        response => return Err(response),
+    }
+    // a known code, or it wouldn't have validated
+    let request = Request::from_u16(message.header.code()).ok_or(Response::UnknownError)?;
+    Ok((request, &bytes[HEADER_SIZE..]))
+}
+
 #[cfg(test)]
 mod tests {
     #[allow(unused)]
-    use super::{Message, Request, Response, HEADER_SIZE, MAX_MESSAGE, MAX_PAYLOAD};
+    use super::{
+        Flag, Header, Message, PayloadTooLong, Request, Response, SizeTooLarge, HEADER_SIZE,
+        MAX_MESSAGE, MAX_PAYLOAD,
+    };
+    use crate::testing::wire::raw;
+    use zerocopy::AsBytes;
     const MAGIC: u32 = 0x5354_5259_u32;
 
+    #[test]
+    fn test_typed_headers() {
+        let header = Header::request(Request::Compress, 3).unwrap();
+        assert_eq!(header.as_bytes(), [83, 84, 82, 89, 0, 3, 0, 4]);
+        assert_eq!(header.validate_header(), Response::Ok);
+        let header = Header::response(Response::Ok, MAX_PAYLOAD).unwrap();
+        assert_eq!(
+            (header.sign(), header.size(), header.code()),
+            (MAGIC, MAX_PAYLOAD, 0)
+        );
+
+        let oversize = SizeTooLarge {
+            size: MAX_PAYLOAD + 1,
+        };
+        assert_eq!(
+            Header::request(Request::Compress, MAX_PAYLOAD + 1),
+            Err(oversize)
+        );
+        assert_eq!(
+            Header::response(Response::Ok, u16::MAX),
+            Err(SizeTooLarge { size: u16::MAX })
+        );
+
+        // raw still builds the headers the service rejects
+        let oversize = Header::raw(MAGIC, MAX_PAYLOAD + 1, Request::Compress as u16);
+        assert_eq!(oversize.validate_header(), Response::MessageTooLarge);
+        let bad_magic = Header::raw(0, 0, Request::Ping as u16);
+        assert_eq!(
+            bad_magic.validate_header(),
+            Response::MessageHeaderHasBadMagic
+        );
+        let unknown = Header::raw(MAGIC, 0, 0xFF);
+        assert_eq!(unknown.validate_header(), Response::UnsupportedRequestType);
+    }
+
+    #[test]
+    fn test_validate_header_sizes() {
+        let validate = |request: Request, size: u16| {
+            Header::raw(MAGIC, size, request as u16).validate_header()
+        };
+        // too large whatever the request, rather than a wrong payload kind
+        for request in [Request::GetStats, Request::Ping, Request::Compress] {
+            assert_eq!(
+                validate(request.clone(), u16::MAX),
+                Response::MessageTooLarge
+            );
+            assert_eq!(
+                validate(request, MAX_PAYLOAD + 1),
+                Response::MessageTooLarge
+            );
+        }
+        let zero = Response::RequestKindRequiresZeroLength;
+        let non_zero = Response::CompressionRequestRequiresNonZeroLength;
+        let cases = [
+            (Request::GetStats, 0, Response::Ok),
+            (Request::GetStats, 1, zero),
+            (Request::Ping, MAX_PAYLOAD, zero),
+            (Request::Compress, 0, non_zero),
+            (Request::Compress, MAX_PAYLOAD, Response::Ok),
+            (Request::ResetStats, 0, Response::Ok),
+            (Request::ResetStats, 1, Response::Ok),
+            (Request::ResetStats, 9, Response::Ok),
+            (Request::ResetStats, 10, zero),
+            (Request::Batch, 0, Response::Ok),
+            (Request::Authenticate, MAX_PAYLOAD, Response::Ok),
+        ];
+        for (request, size, expected) in cases {
+            assert_eq!(validate(request.clone(), size), expected, "{:?}", request);
+        }
+    }
+
     #[test]
     fn test_payload() {
         let mut buf = [83u8, 84, 82, 89, 0, 3, 0, 4, 97, 97, 97];
@@ -306,6 +915,22 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_set_payload_too_long() {
+        let mut buf = [0u8; HEADER_SIZE + 2];
+        let mut message = Message::parse_mut(&mut buf[..]).unwrap();
+        assert_eq!(message.set_payload(b"ab"), Ok(()));
+        assert_eq!(
+            message.set_payload(b"abc"),
+            Err(PayloadTooLong {
+                len: 3,
+                capacity: 2
+            })
+        );
+        assert_eq!(message.payload_slice(), b"");
+        assert_eq!(&buf[HEADER_SIZE..], b"ab");
+    }
+
     #[test]
     fn test_message_too_large() {
         let mut rx = [0u8; MAX_MESSAGE + 8];
@@ -378,6 +1003,86 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_validate_wire() {
+        let wire = |size, code, payload: &[u8]| raw(MAGIC, size, code, payload);
+        let compress = Request::Compress as u16;
+        let ping = Request::Ping as u16;
+        let max = [b'a'; MAX_PAYLOAD as usize + 1];
+        let cases = [
+            // the validate() matrix
+            (wire(3, compress, b"aaa"), Ok(Request::Compress)),
+            (
+                wire(0, compress, b""),
+                Err(Response::CompressionRequestRequiresNonZeroLength),
+            ),
+            (
+                wire(1, ping, b"a"),
+                Err(Response::RequestKindRequiresZeroLength),
+            ),
+            (
+                wire(1, Request::GetStats as u16, b"a"),
+                Err(Response::RequestKindRequiresZeroLength),
+            ),
+            (wire(0, ping, b""), Ok(Request::Ping)),
+            (
+                wire(1, Request::ResetStats as u16, &[0]),
+                Ok(Request::ResetStats),
+            ),
+            (wire(0, 99, b""), Err(Response::UnsupportedRequestType)),
+            (wire(0, 0x0100 | ping, b""), Err(Response::UnsupportedFlags)),
+            (
+                Header::raw(0, 0, ping).as_bytes().to_vec(),
+                Err(Response::MessageHeaderHasBadMagic),
+            ),
+            (
+                wire(0, compress, b"")[..7].to_vec(),
+                Err(Response::MessageTooSmall),
+            ),
+            (Vec::new(), Err(Response::MessageTooSmall)),
+            (
+                wire(MAX_PAYLOAD + 1, compress, &max),
+                Err(Response::MessageTooLarge),
+            ),
+            (
+                wire(MAX_PAYLOAD, compress, &max[1..]),
+                Ok(Request::Compress),
+            ),
+            // a size the bytes don't back is never trusted
+            (
+                wire(3, compress, b"aa"),
+                Err(Response::MessageHeaderSizeMismatch),
+            ),
+            (
+                wire(u16::MAX, compress, b"aa"),
+                Err(Response::MessageHeaderSizeMismatch),
+            ),
+            // trailing slack is a mismatch, not ignored
+            (
+                wire(3, compress, b"aaa\0"),
+                Err(Response::MessageHeaderSizeMismatch),
+            ),
+            (
+                wire(0, ping, b"\0\0"),
+                Err(Response::MessageHeaderSizeMismatch),
+            ),
+            // the characters are the caller's to check
+            (wire(3, compress, b"aB3"), Ok(Request::Compress)),
+        ];
+        for (bytes, expected) in cases {
+            let validated = super::validate_wire(&bytes);
+            assert_eq!(
+                validated.clone().map(|(request, _)| request),
+                expected,
+                "{:?}",
+                bytes
+            );
+            if let Ok((_, payload)) = validated {
+                assert_eq!(payload, &bytes[HEADER_SIZE..]);
+            }
+        }
+    }
+
     #[test]
     fn test_compression_request_requires_non_zero() {
         let mut rx = [83u8, 84, 82, 89, 0, 0, 0, 4];
@@ -388,4 +1093,92 @@ mod tests {
             .validate(bytes_read)
             .eq(&Response::CompressionRequestRequiresNonZeroLength));
     }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_hexdump() {
+        let rx = [83u8, 84, 82, 89, 0, 3, 0, 4, 97, 97, 97];
+        let message = Message::parse(&rx[..]).unwrap();
+        assert_eq!(
+            message.hexdump(),
+            "00000000  53 54 52 59 00 03 00 04  61 61 61                 |STRY....aaa|\n"
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_hexdump_size_lie() {
+        // header.size = 65535, payload.len = 2
+        let rx = [83u8, 84, 82, 89, 255, 255, 0, 4, 97, 97];
+        let message = Message::parse(&rx[..]).unwrap();
+        assert_eq!(message.payload_slice(), &[97, 97]);
+        assert_eq!(
+            message.hexdump(),
+            "00000000  53 54 52 59 ff ff 00 04  61 61                    |STRY....aa|\n"
+        );
+        assert!(message.is_payload_valid(rx.len()));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_hexdump_max_payload_is_capped() {
+        let mut rx = [97u8; MAX_MESSAGE];
+        let mut message = Message::parse_mut(&mut rx[..]).unwrap();
+        message.set_header_with_default_magic(MAX_PAYLOAD, Request::Compress as u16);
+        let dump = message.hexdump();
+        assert_eq!(dump.lines().count(), super::HEXDUMP_DEFAULT_ROWS + 1);
+        assert!(dump.ends_with("... 7944 more bytes (8200 total)\n"));
+    }
+
+    #[test]
+    fn test_flags() {
+        let mut rx = [83u8, 84, 82, 89, 0, 3, 0, 4, 97, 97, 97];
+        let mut message = Message::parse_mut(&mut rx[..]).unwrap();
+        assert_eq!(message.header.flags(), 0);
+        message.header.set_flags(Flag::CHECKSUM | Flag::STORED);
+        assert_eq!(message.header.flags(), 0b101);
+        assert_eq!(message.header.code(), 0x0504);
+        assert_eq!(
+            Request::from_u16(message.header.code()),
+            Some(Request::Compress)
+        );
+        message.header.set_flags(0);
+        assert_eq!(message.header.code(), 4);
+        assert_eq!(
+            Response::from_u16(0x0100 | Response::MessageContainsDigits as u16),
+            Some(Response::MessageContainsDigits)
+        );
+        assert_eq!(Response::from_u16(4), None);
+    }
+
+    #[test]
+    fn test_unsupported_flags() {
+        let mut rx = [83u8, 84, 82, 89, 0, 3, 0, 4, 97, 97, 97];
+        // flags of 0, as sent by every existing client
+        let message = Message::parse(&rx[..]).unwrap();
+        assert_eq!(message.validate(rx.len()), Response::Ok);
+        assert_eq!(
+            message.validate_flags_using(rx.len(), false, |_| Response::Ok),
+            Response::Ok
+        );
+
+        Message::parse_mut(&mut rx[..])
+            .unwrap()
+            .header
+            .set_flags(Flag::TRUNCATED_STATS);
+        let message = Message::parse(&rx[..]).unwrap();
+        assert_eq!(message.validate(rx.len()), Response::UnsupportedFlags);
+        // permissive, the flag is ignored
+        assert_eq!(
+            message.validate_flags_using(rx.len(), false, |_| Response::Ok),
+            Response::Ok
+        );
+        // the magic is still checked first
+        rx[0] = 0;
+        let message = Message::parse(&rx[..]).unwrap();
+        assert_eq!(
+            message.validate(rx.len()),
+            Response::MessageHeaderHasBadMagic
+        );
+    }
 }
@@ -0,0 +1,234 @@
+use super::PayloadTooLong;
+use core::{error::Error, fmt};
+
+/// Bytes of the length every entry of a batch is prefixed with
+pub const BATCH_LENGTH_PREFIX: usize = 2;
+
+/// An entry of a batch payload goes on past its end, either its length
+/// prefix or the message it declares
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TruncatedEntry {
+    /// Where the entry starts within the payload, at its length prefix
+    pub offset: usize,
+    /// The length the entry declares, `None` if the prefix itself is cut
+    /// short
+    pub len: Option<usize>,
+}
+
+impl fmt::Display for TruncatedEntry {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.len {
+            Some(len) => write!(
+                fmt,
+                "the batch entry at {} of {} bytes is cut short",
+                self.offset, len
+            ),
+            None => write!(
+                fmt,
+                "the length of the batch entry at {} is cut short",
+                self.offset
+            ),
+        }
+    }
+}
+
+impl Error for TruncatedEntry {}
+
+/// The entries of the payload of a Batch request or response, in order
+///
+/// Each entry is a whole message, header and payload, prefixed by its length
+/// as a u16 in network order. An entry cut short by the end of the payload
+/// is the last one read, as an error, since the entries after it can't be
+/// told apart
+///
+/// # Example
+/// ```
+/// use service::message::{BatchEntries, TruncatedEntry};
+/// let payload = [0u8, 2, 7, 7, 0, 1, 9, 0, 3, 1];
+/// let mut entries = BatchEntries::new(&payload[..]);
+/// assert_eq!(entries.next(), Some(Ok(&[7u8, 7][..])));
+/// assert_eq!(entries.next(), Some(Ok(&[9u8][..])));
+/// let truncated = TruncatedEntry { offset: 7, len: Some(3) };
+/// assert_eq!(entries.next(), Some(Err(truncated)));
+/// assert_eq!(entries.next(), None);
+/// ```
+#[derive(Debug, Clone)]
+pub struct BatchEntries<'a> {
+    payload: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> BatchEntries<'a> {
+    pub fn new(payload: &'a [u8]) -> BatchEntries<'a> {
+        BatchEntries { payload, offset: 0 }
+    }
+}
+
+impl<'a> Iterator for BatchEntries<'a> {
+    type Item = Result<&'a [u8], TruncatedEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+        let rest = &self.payload[offset..];
+        if rest.is_empty() {
+            return None;
+        }
+        // nothing is read past an entry cut short
+        self.offset = self.payload.len();
+        if rest.len() < BATCH_LENGTH_PREFIX {
+            return Some(Err(TruncatedEntry { offset, len: None }));
+        }
+        let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+        match rest.get(BATCH_LENGTH_PREFIX..BATCH_LENGTH_PREFIX + len) {
+            Some(entry) => {
+                self.offset = offset + BATCH_LENGTH_PREFIX + len;
+                Some(Ok(entry))
+            }
+            None => Some(Err(TruncatedEntry {
+                offset,
+                len: Some(len),
+            })),
+        }
+    }
+}
+
+/// Writes the entries of a batch payload into a buffer, each prefixed by
+/// its length, without allocating
+#[derive(Debug)]
+pub struct BatchWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    count: usize,
+}
+
+impl<'a> BatchWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> BatchWriter<'a> {
+        BatchWriter {
+            buf,
+            len: 0,
+            count: 0,
+        }
+    }
+
+    /// Appends `entry`, nothing is written if it doesn't fit what's left of
+    /// the buffer or is longer than a u16 can tell
+    pub fn push(&mut self, entry: &[u8]) -> Result<(), PayloadTooLong> {
+        let end = self.len + BATCH_LENGTH_PREFIX + entry.len();
+        if end > self.buf.len() || entry.len() > u16::MAX as usize {
+            return Err(PayloadTooLong {
+                len: end,
+                capacity: self.buf.len(),
+            });
+        }
+        let start = self.len + BATCH_LENGTH_PREFIX;
+        self.buf[self.len..start].copy_from_slice(&(entry.len() as u16).to_be_bytes());
+        self.buf[start..end].copy_from_slice(entry);
+        self.len = end;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Bytes written, the length of the batch payload
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Entries written
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+/// The batch payload of `entries`
+#[cfg(feature = "std")]
+pub fn encode_batch<'e, I>(entries: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = &'e [u8]>,
+{
+    let mut payload = Vec::new();
+    for entry in entries {
+        payload.extend_from_slice(&(entry.len() as u16).to_be_bytes());
+        payload.extend_from_slice(entry);
+    }
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Header, Request};
+    use crate::testing::wire::ping;
+    use zerocopy::AsBytes;
+
+    #[test]
+    fn test_round_trip() {
+        let compress = [
+            Header::request(Request::Compress, 3).unwrap().as_bytes(),
+            b"aaa",
+        ]
+        .concat();
+        let entries = [&ping()[..], &compress, &[][..], &ping()];
+        let mut buf = [0u8; 64];
+        let mut writer = BatchWriter::new(&mut buf);
+        for entry in &entries {
+            writer.push(entry).unwrap();
+        }
+        assert_eq!((writer.len(), writer.count()), (8 + 11 + 8 + 4 * 2, 4));
+        let len = writer.len();
+        #[cfg(feature = "std")]
+        assert_eq!(&buf[..len], &encode_batch(entries.iter().copied())[..]);
+
+        let read: Vec<_> = BatchEntries::new(&buf[..len]).collect();
+        assert_eq!(read, entries.iter().map(|e| Ok(*e)).collect::<Vec<_>>());
+        assert_eq!(BatchEntries::new(&[]).next(), None);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_truncated() {
+        let short = |offset, len| Err(TruncatedEntry { offset, len });
+        let payload = encode_batch([&ping()[..], &ping()[..]]);
+        // every cut but at the boundaries between entries leaves the last
+        // one short
+        for cut in 1..payload.len() {
+            let entries: Vec<_> = BatchEntries::new(&payload[..cut]).collect();
+            match cut {
+                1 => assert_eq!(entries, [short(0, None)]),
+                2..=9 => assert_eq!(entries, [short(0, Some(8))]),
+                10 => assert_eq!(entries, [Ok(&ping()[..])]),
+                11 => assert_eq!(entries, [Ok(&ping()[..]), short(10, None)]),
+                _ => assert_eq!(entries, [Ok(&ping()[..]), short(10, Some(8))]),
+            }
+        }
+        // a length past the end isn't read past, whatever follows it
+        let lying = [0xFF, 0xFF, 0, 0, 0, 0];
+        let mut entries = BatchEntries::new(&lying);
+        let truncated = TruncatedEntry {
+            offset: 0,
+            len: Some(0xFFFF),
+        };
+        assert_eq!(entries.next(), Some(Err(truncated)));
+        assert_eq!(entries.next(), None);
+    }
+
+    #[test]
+    fn test_writer_full() {
+        let mut buf = [0u8; 12];
+        let mut writer = BatchWriter::new(&mut buf);
+        writer.push(&ping()).unwrap();
+        let full = PayloadTooLong {
+            len: 20,
+            capacity: 12,
+        };
+        assert_eq!(writer.push(&ping()), Err(full));
+        // what was written is left as it was
+        assert_eq!((writer.len(), writer.count()), (10, 1));
+        writer.push(&[]).unwrap();
+        assert_eq!(writer.len(), 12);
+        assert!(!writer.is_empty());
+    }
+}
@@ -0,0 +1,104 @@
+use std::fmt::Write;
+
+/// Number of bytes rendered on each row of a hex dump
+pub const HEXDUMP_ROW_WIDTH: usize = 16;
+
+/// Default number of rows rendered by `Message::hexdump`
+/// 16 rows (256 bytes) is enough to see the header and the start of the
+/// payload without flooding the logs with a MAX_PAYLOAD sized message
+pub const HEXDUMP_DEFAULT_ROWS: usize = 16;
+
+/// Formats a byte-slice as a canonical hex dump. Each row holds an offset
+/// column, 16 bytes in hex (split in two groups of 8) and an ASCII gutter where
+/// non-printable bytes are shown as '.'
+///
+/// At most `max_rows` rows are rendered, the remaining bytes are summarized
+/// by a single elision line so the output length is bounded
+///
+/// # Example
+/// ```
+/// use service::hexdump;
+/// let dump = hexdump(&[83u8, 84, 82, 89, 0, 0, 0, 1], 4);
+/// assert_eq!(
+///     dump,
+///     "00000000  53 54 52 59 00 00 00 01                           |STRY....|\n"
+/// );
+/// ```
+pub fn hexdump(bytes: &[u8], max_rows: usize) -> String {
+    let mut out = String::new();
+    for (row, chunk) in bytes.chunks(HEXDUMP_ROW_WIDTH).take(max_rows).enumerate() {
+        let _ = write!(out, "{:08x} ", row * HEXDUMP_ROW_WIDTH);
+        for i in 0..HEXDUMP_ROW_WIDTH {
+            if i % 8 == 0 {
+                out.push(' ');
+            }
+            match chunk.get(i) {
+                Some(byte) => {
+                    let _ = write!(out, "{:02x} ", byte);
+                }
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str(" |");
+        out.extend(chunk.iter().map(|&x| printable(x)));
+        out.push_str("|\n");
+    }
+    let shown = max_rows.saturating_mul(HEXDUMP_ROW_WIDTH).min(bytes.len());
+    if shown < bytes.len() {
+        let _ = writeln!(
+            out,
+            "... {} more bytes ({} total)",
+            bytes.len() - shown,
+            bytes.len()
+        );
+    }
+    out
+}
+
+fn printable(byte: u8) -> char {
+    if byte.is_ascii_graphic() || byte == b' ' {
+        byte as char
+    } else {
+        '.'
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hexdump;
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(hexdump(&[], 4), "");
+    }
+
+    #[test]
+    fn test_full_row() {
+        let bytes = b"STRY\x00\x08\x00\x04aaabbbcc";
+        assert_eq!(
+            hexdump(bytes, 4),
+            "00000000  53 54 52 59 00 08 00 04  61 61 61 62 62 62 63 63  |STRY....aaabbbcc|\n"
+        );
+    }
+
+    #[test]
+    fn test_partial_rows() {
+        let bytes = b"STRY\x00\x0a\x00\x04abcdefgh\x7f\xff";
+        assert_eq!(
+            hexdump(bytes, 4),
+            "00000000  53 54 52 59 00 0a 00 04  61 62 63 64 65 66 67 68  |STRY....abcdefgh|\n\
+             00000010  7f ff                                             |..|\n"
+        );
+    }
+
+    #[test]
+    fn test_elision() {
+        let bytes = [97u8; 40];
+        assert_eq!(
+            hexdump(&bytes, 1),
+            "00000000  61 61 61 61 61 61 61 61  61 61 61 61 61 61 61 61  |aaaaaaaaaaaaaaaa|\n\
+             ... 24 more bytes (40 total)\n"
+        );
+        assert_eq!(hexdump(&bytes, 0), "... 40 more bytes (40 total)\n");
+    }
+}
@@ -0,0 +1,339 @@
+use super::Response;
+use core::{error::Error, fmt};
+
+/// The set of bytes a compression request payload may contain
+///
+/// Digits can never be allowed as they collide with the run-length counts
+/// emitted by the compressor ("3a" would be ambiguous), this is rejected when
+/// the policy is constructed
+///
+/// # Example
+/// ```
+/// use service::CharPolicy;
+/// let policy = CharPolicy::from_spec("a-z -").unwrap();
+/// assert!(policy.allows(b' '));
+/// assert!(policy.allows(b'-'));
+/// assert!(!policy.allows(b'A'));
+/// assert!(CharPolicy::from_spec("a-z0-9").is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CharPolicy {
+    allowed: [u64; 4],
+}
+
+/// The kinds of bytes a `CharPolicy` rejects, each answered with a response
+/// of its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CharCategory {
+    /// 'A' to 'Z'
+    Uppercase,
+    /// '0' to '9'
+    Digit,
+    /// 0x80 and over
+    NonAscii,
+    /// Any other byte, e.g. a space or punctuation
+    Other,
+}
+
+impl CharCategory {
+    pub fn of(byte: u8) -> CharCategory {
+        match byte {
+            b'A'..=b'Z' => CharCategory::Uppercase,
+            b'0'..=b'9' => CharCategory::Digit,
+            0x80..=0xff => CharCategory::NonAscii,
+            _ => CharCategory::Other,
+        }
+    }
+
+    /// The response to a payload whose rejected bytes are all of this
+    /// category
+    pub fn response(&self) -> Response {
+        match self {
+            CharCategory::Uppercase => Response::MessageContainsUppercaseCharacters,
+            CharCategory::Digit => Response::MessageContainsDigits,
+            CharCategory::NonAscii => Response::MessageContainsNonAscii,
+            CharCategory::Other => Response::MessagePayloadContainsInvalidCharacters,
+        }
+    }
+}
+
+/// The first byte of a payload a `CharPolicy` rejects, see
+/// `CharPolicy::check`
+///
+/// # Example
+/// ```
+/// use service::{CharCategory, CharPolicy, InvalidPayload};
+/// let invalid = CharPolicy::default().check(b"abCd").unwrap_err();
+/// assert_eq!(
+///     invalid,
+///     InvalidPayload { offset: 2, byte: b'C', category: CharCategory::Uppercase, mixed: false }
+/// );
+/// assert_eq!(invalid.to_string(), "invalid byte 0x43 at offset 2");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidPayload {
+    pub offset: usize,
+    pub byte: u8,
+    pub category: CharCategory,
+    /// Whether the payload also holds rejected bytes of another category,
+    /// or any of `CharCategory::Other`
+    pub mixed: bool,
+}
+
+impl InvalidPayload {
+    /// The precise response when every rejected byte is of the same
+    /// category, otherwise the generic `MessagePayloadContainsInvalidCharacters`
+    pub fn response(&self) -> Response {
+        match self.mixed {
+            true => Response::MessagePayloadContainsInvalidCharacters,
+            false => self.category.response(),
+        }
+    }
+}
+
+/// Reasons a character policy specification is rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyError {
+    /// The specification allows no characters at all
+    Empty,
+    /// The specification contains a non-ascii character
+    NonAscii(char),
+    /// A range whose start is greater than its end, e.g. "z-a"
+    InvalidRange(char, char),
+    /// Digits would be ambiguous with the run-length counts
+    DigitsNotAllowed(char),
+}
+
+impl CharPolicy {
+    /// The default policy, only lowercase ascii characters (a-z)
+    pub fn lowercase_ascii() -> CharPolicy {
+        let mut policy = CharPolicy { allowed: [0; 4] };
+        (b'a'..=b'z').for_each(|x| policy.insert(x));
+        policy
+    }
+
+    /// Parses a policy from a specification such as "a-z -"
+    /// Each character is allowed literally and "x-y" allows the inclusive
+    /// range from x to y, a '-' at the start or end of the spec is literal
+    pub fn from_spec(spec: &str) -> Result<CharPolicy, PolicyError> {
+        if let Some(c) = spec.chars().find(|c| !c.is_ascii()) {
+            return Err(PolicyError::NonAscii(c));
+        }
+        let bytes = spec.as_bytes();
+        let mut policy = CharPolicy { allowed: [0; 4] };
+        let mut i = 0;
+        while i < bytes.len() {
+            if i + 2 < bytes.len() && bytes[i + 1] == b'-' {
+                let (start, end) = (bytes[i], bytes[i + 2]);
+                if start > end {
+                    return Err(PolicyError::InvalidRange(start as char, end as char));
+                }
+                (start..=end).for_each(|x| policy.insert(x));
+                i += 3;
+            } else {
+                policy.insert(bytes[i]);
+                i += 1;
+            }
+        }
+        if let Some(digit) = (b'0'..=b'9').find(|x| policy.allows(*x)) {
+            return Err(PolicyError::DigitsNotAllowed(digit as char));
+        }
+        if policy.allowed == [0; 4] {
+            return Err(PolicyError::Empty);
+        }
+        Ok(policy)
+    }
+
+    /// This policy extended with the uppercase form of every allowed
+    /// lowercase ascii character
+    pub fn case_insensitive(&self) -> CharPolicy {
+        let mut policy = *self;
+        (b'a'..=b'z')
+            .filter(|x| self.allows(*x))
+            .for_each(|x| policy.insert(x.to_ascii_uppercase()));
+        policy
+    }
+
+    /// Whether `byte` may appear in a compression request payload
+    pub fn allows(&self, byte: u8) -> bool {
+        self.allowed[(byte / 64) as usize] & (1 << (byte % 64)) != 0
+    }
+
+    /// Relays a precise response when all offending bytes of `payload`
+    /// share a category (uppercase, digits, non-ascii), otherwise falls back
+    /// to the generic `MessagePayloadContainsInvalidCharacters`, see `check`
+    pub fn validate(&self, payload: &[u8]) -> Response {
+        match self.check(payload) {
+            Ok(()) => Response::Ok,
+            Err(invalid) => invalid.response(),
+        }
+    }
+
+    /// Finds the first byte of `payload` not allowed by the policy. Past it
+    /// the payload is only scanned for a rejected byte of another category,
+    /// up to the first one, and not at all once the category is `Other`,
+    /// either makes the response the generic one
+    pub fn check(&self, payload: &[u8]) -> Result<(), InvalidPayload> {
+        for &byte in payload {
+            if !self.allows(byte) {
+                return Err(self.invalid(payload));
+            }
+        }
+        Ok(())
+    }
+
+    /// The first rejected byte of `payload`, which has one. Kept out of the
+    /// scan, whose loop stays as tight for valid payloads as without offsets
+    #[cold]
+    fn invalid(&self, payload: &[u8]) -> InvalidPayload {
+        let offset = payload
+            .iter()
+            .position(|&byte| !self.allows(byte))
+            .expect("a rejected byte");
+        let byte = payload[offset];
+        let category = CharCategory::of(byte);
+        let mixed = category == CharCategory::Other
+            || payload[offset + 1..]
+                .iter()
+                .any(|&byte| !self.allows(byte) && CharCategory::of(byte) != category);
+        InvalidPayload {
+            offset,
+            byte,
+            category,
+            mixed,
+        }
+    }
+
+    fn insert(&mut self, byte: u8) {
+        self.allowed[(byte / 64) as usize] |= 1 << (byte % 64);
+    }
+}
+
+impl Default for CharPolicy {
+    fn default() -> CharPolicy {
+        CharPolicy::lowercase_ascii()
+    }
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PolicyError::Empty => write!(fmt, "character policy allows no characters"),
+            PolicyError::NonAscii(c) => write!(fmt, "non-ascii character {:?} in policy", c),
+            PolicyError::InvalidRange(start, end) => {
+                write!(fmt, "invalid character range {:?}-{:?}", start, end)
+            }
+            PolicyError::DigitsNotAllowed(c) => write!(
+                fmt,
+                "digit {:?} would be ambiguous with run-length counts",
+                c
+            ),
+        }
+    }
+}
+
+impl Error for PolicyError {}
+
+impl fmt::Display for InvalidPayload {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "invalid byte {:#04x} at offset {}",
+            self.byte, self.offset
+        )
+    }
+}
+
+impl Error for InvalidPayload {}
+
+#[cfg(test)]
+mod tests {
+    use super::{CharCategory, CharPolicy, InvalidPayload, PolicyError};
+    use crate::message::Response;
+
+    #[test]
+    fn test_default_is_lowercase() {
+        let policy = CharPolicy::default();
+        assert!((0..=255u8).all(|x| policy.allows(x) == x.is_ascii_lowercase()));
+        assert_eq!(CharPolicy::from_spec("a-z"), Ok(policy));
+    }
+
+    #[test]
+    fn test_from_spec() {
+        let policy = CharPolicy::from_spec("a-z -").unwrap();
+        let allowed: Vec<u8> = (0..=255u8).filter(|x| policy.allows(*x)).collect();
+        let mut expected: Vec<u8> = (b'a'..=b'z').collect();
+        expected.extend_from_slice(b" -");
+        expected.sort_unstable();
+        assert_eq!(allowed, expected);
+
+        let policy = CharPolicy::from_spec("-a-c").unwrap();
+        assert!(policy.allows(b'-') && policy.allows(b'b') && !policy.allows(b'd'));
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let policy = CharPolicy::from_spec("a-c -").unwrap().case_insensitive();
+        assert!(policy.allows(b'A') && policy.allows(b'c') && policy.allows(b'C'));
+        assert!(policy.allows(b' ') && !policy.allows(b'D') && !policy.allows(b'd'));
+    }
+
+    #[test]
+    fn test_from_spec_errors() {
+        assert_eq!(CharPolicy::from_spec(""), Err(PolicyError::Empty));
+        assert_eq!(
+            CharPolicy::from_spec("a-z0-9"),
+            Err(PolicyError::DigitsNotAllowed('0'))
+        );
+        assert_eq!(
+            CharPolicy::from_spec(" -~"),
+            Err(PolicyError::DigitsNotAllowed('0'))
+        );
+        assert_eq!(
+            CharPolicy::from_spec("z-a"),
+            Err(PolicyError::InvalidRange('z', 'a'))
+        );
+        assert_eq!(CharPolicy::from_spec("aé"), Err(PolicyError::NonAscii('é')));
+    }
+
+    #[test]
+    fn test_check_offsets() {
+        let policy = CharPolicy::default();
+        let mut payload = [b'a'; 8192];
+        assert_eq!(policy.check(&payload), Ok(()));
+        for offset in [0, 4096, 8191] {
+            payload[offset] = b'A';
+            assert_eq!(
+                policy.check(&payload),
+                Err(InvalidPayload {
+                    offset,
+                    byte: b'A',
+                    category: CharCategory::Uppercase,
+                    mixed: false,
+                })
+            );
+            payload[offset] = b'a';
+        }
+
+        // the first rejected byte is reported, a later one of another
+        // category makes the response the generic one
+        let invalid = policy.check(b"ab3cD4").unwrap_err();
+        assert_eq!((invalid.offset, invalid.byte), (2, b'3'));
+        assert_eq!(
+            invalid.response(),
+            Response::MessagePayloadContainsInvalidCharacters
+        );
+        let invalid = policy.check(b"ab3c44").unwrap_err();
+        assert_eq!(invalid.response(), Response::MessageContainsDigits);
+        let invalid = policy.check(b"a b").unwrap_err();
+        assert_eq!(
+            (invalid.category, invalid.mixed),
+            (CharCategory::Other, true)
+        );
+        assert_eq!(invalid.to_string(), "invalid byte 0x20 at offset 1");
+        assert_eq!(
+            policy.validate(b"a\xFFb\x80"),
+            Response::MessageContainsNonAscii
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use super::{Flag, Header};
+#[cfg(feature = "std")]
+use zerocopy::AsBytes;
+use zerocopy::LayoutVerified;
+
+/// Bytes of the sequence number the payload of a message of
+/// `Flag::SEQUENCED` starts with
+pub const SEQUENCE_LEN: usize = 2;
+
+/// The sequence number of `message`, `None` if it doesn't set
+/// `Flag::SEQUENCED` or is too short to hold one
+///
+/// # Example
+/// ```
+/// use service::message::sequence;
+/// let ping = [83u8, 84, 82, 89, 0, 2, 0x10, 1, 0, 7];
+/// assert_eq!(sequence(&ping), Some(7));
+/// assert_eq!(sequence(&ping[..8]), None);
+/// ```
+pub fn sequence(message: &[u8]) -> Option<u16> {
+    let (header, payload) = LayoutVerified::<_, Header>::new_from_prefix(message)?;
+    if header.flags() & Flag::SEQUENCED == 0 {
+        return None;
+    }
+    let sequence = payload.get(..SEQUENCE_LEN)?;
+    Some(u16::from_be_bytes([sequence[0], sequence[1]]))
+}
+
+/// `message` numbered `sequence`: `Flag::SEQUENCED` set, the sequence number
+/// put before its payload and its size field grown by as much. A message
+/// shorter than a header is returned as is, it has nowhere to set the flag
+#[cfg(feature = "std")]
+pub fn with_sequence(message: &[u8], sequence: u16) -> Vec<u8> {
+    let (header, payload) = match LayoutVerified::<_, Header>::new_from_prefix(message) {
+        Some(parts) => parts,
+        None => return message.to_vec(),
+    };
+    let size = header.size().wrapping_add(SEQUENCE_LEN as u16);
+    let code = header.code() | (Flag::SEQUENCED as u16) << 8;
+    let mut sequenced = Vec::with_capacity(message.len() + SEQUENCE_LEN);
+    sequenced.extend_from_slice(Header::raw(header.sign(), size, code).as_bytes());
+    sequenced.extend_from_slice(&sequence.to_be_bytes());
+    sequenced.extend_from_slice(payload);
+    sequenced
+}
+
+/// `message` without its sequence number, `Flag::SEQUENCED` cleared and its
+/// size field shrunk by as much, along with the number. `None` if it isn't
+/// numbered
+///
+/// A size field too small to hold the number wraps around rather than
+/// saturating, so that the message it leaves still doesn't match its size
+#[cfg(feature = "std")]
+pub fn without_sequence(message: &[u8]) -> Option<(u16, Vec<u8>)> {
+    let sequence = sequence(message)?;
+    let (header, payload) = LayoutVerified::<_, Header>::new_from_prefix(message)?;
+    let size = header.size().wrapping_sub(SEQUENCE_LEN as u16);
+    let code = header.code() & !((Flag::SEQUENCED as u16) << 8);
+    let mut plain = Vec::with_capacity(message.len() - SEQUENCE_LEN);
+    plain.extend_from_slice(Header::raw(header.sign(), size, code).as_bytes());
+    plain.extend_from_slice(&payload[SEQUENCE_LEN..]);
+    Some((sequence, plain))
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::message::{Request, MAGIC};
+    use crate::testing::wire::raw;
+
+    #[test]
+    fn test_round_trip() {
+        let compress = raw(MAGIC, 3, Request::Compress as u16, b"aaa");
+        let sequenced = with_sequence(&compress, 0x0102);
+        let flagged = (Flag::SEQUENCED as u16) << 8 | Request::Compress as u16;
+        assert_eq!(sequenced, raw(MAGIC, 5, flagged, b"\x01\x02aaa"));
+        assert_eq!(sequence(&sequenced), Some(0x0102));
+        assert_eq!(
+            without_sequence(&sequenced),
+            Some((0x0102, compress.clone()))
+        );
+
+        // unnumbered messages are left alone
+        assert_eq!(sequence(&compress), None);
+        assert_eq!(without_sequence(&compress), None);
+        assert_eq!(with_sequence(b"STRY", 1), b"STRY");
+        // the other flags are kept
+        let checksum = (Flag::CHECKSUM as u16) << 8 | Request::Ping as u16;
+        let ping = raw(MAGIC, 0, checksum, b"");
+        assert_eq!(without_sequence(&with_sequence(&ping, 9)), Some((9, ping)));
+    }
+
+    #[test]
+    fn test_size_mismatch_kept() {
+        // a payload shorter than the number isn't numbered
+        let flagged = (Flag::SEQUENCED as u16) << 8 | Request::Ping as u16;
+        assert_eq!(sequence(&raw(MAGIC, 2, flagged, b"\x00")), None);
+        // a size field short of the number is still mismatched without it
+        let (sequence, plain) = without_sequence(&raw(MAGIC, 1, flagged, b"\x00\x05")).unwrap();
+        assert_eq!(sequence, 5);
+        assert_eq!(plain, raw(MAGIC, 0xFFFF, Request::Ping as u16, b""));
+        // and one over the payload is still over
+        let lying = with_sequence(&raw(MAGIC, 9, Request::Compress as u16, b"ab"), 1);
+        assert_eq!(&lying[4..6], &[0, 11]);
+    }
+}
@@ -1,34 +1,191 @@
-use crate::message;
-pub use compress::compress_message;
-pub use connection::Connection;
-pub use state::State;
-pub use stats::Stats;
+#[cfg(feature = "server")]
+use crate::message::{self, GoodbyeReason, Response};
+#[cfg(feature = "std")]
+pub use accept::{AcceptError, AcceptStats, ListenerAccepts, ACCEPT_ERROR_CLASSES};
+#[cfg(feature = "std")]
+pub use binary::{
+    binary_len, compress_binary, decompress_binary, max_binary_len, BINARY_MARKER, BINARY_RUN_LEN,
+    MIN_BINARY_RUN,
+};
+#[cfg(feature = "async-std")]
+pub use compat::AsyncStdServer;
+#[cfg(feature = "std")]
+pub use compress::{
+    compress, compress_to_writer, compress_to_writer_with, decompress, decompress_capped,
+    decompress_to_writer, validate_compressible, Uncompressible,
+};
+pub use compress::{
+    compress_bound, compress_message, compress_message_folded, compress_message_with,
+    compress_with_stats, decompress_bound, decompress_message, run_length, CompressOptions,
+    CompressOutcome, Compressor, DecompressError, DEFAULT_MIN_RUN, MAX_DECOMPRESS_COUNT_DIGITS,
+};
+#[cfg(feature = "server")]
+pub use compress::{
+    compress_to_async_writer, compress_to_async_writer_with, decompress_to_async_writer,
+};
+#[cfg(feature = "std")]
+pub use config::{
+    Enforcement, FlushPolicy, RejectLogPolicy, ServerConfig, DEFAULT_BAD_MAGIC_STRIKES,
+    DEFAULT_BIND_BACKOFF, DEFAULT_FRAME_TIMEOUT, DEFAULT_HEAVY_LANE, DEFAULT_MAX_BATCH,
+    DEFAULT_MAX_BUFFERED, DEFAULT_MAX_TENANTS, DEFAULT_REJECT_CONNECTION_RATE, DEFAULT_REJECT_DUMP,
+    DEFAULT_REJECT_GLOBAL_RATE, DEFAULT_REQUESTS_PER_YIELD, DEFAULT_REQUEST_DEADLINE,
+    DEFAULT_SHUTDOWN_TIMEOUT, DEFAULT_VIOLATION_STRIKES,
+};
+#[cfg(feature = "std")]
+pub use connection::{
+    handle_request, handle_request_scoped, restate_stats, Connection, HandleError,
+};
+#[cfg(feature = "std")]
+pub use flush::{flush_path, flush_stats, DEFAULT_FLUSH_FILE};
+#[cfg(feature = "std")]
+pub use goodbye::Goodbye;
+#[cfg(feature = "server")]
+pub use group::ServerGroup;
+#[cfg(feature = "server")]
+pub use handle::{ConfigHandle, ResetHandle, ServerHandle, StateHandle};
+#[cfg(feature = "server")]
+pub use lanes::{Lane, Lanes, Turn};
+#[cfg(feature = "std")]
+pub use limits::{Feature, Limits, ServedRequests};
+#[cfg(feature = "std")]
+pub use metrics::Metrics;
+#[cfg(feature = "std")]
+pub use ratio::{RatioPolicy, RatioTracker, MAX_RATIO_WINDOW};
+#[cfg(feature = "std")]
+pub use recent::{Recent, Recorder, RequestSummary, PAYLOAD_PREFIX};
+#[cfg(feature = "server")]
+pub use rejects::{ConnectionRejects, RejectLog, RejectSink, RejectStats};
+#[cfg(feature = "server")]
+pub use report::{report_stats, Report};
+#[cfg(feature = "server")]
+pub use requests::{OwnedRequest, RequestStream, Responder, ServiceError};
+#[cfg(feature = "std")]
+pub use scheme::{CompressError, CompressionScheme, RleBinary, RlePrefix};
+#[cfg(feature = "server")]
+pub use spawn::{spawn_named, spawn_named_in};
+#[cfg(feature = "std")]
+pub use state::{
+    Connected, ConnectionGauge, Draining, InFlight, Observer, Open, Peaks, Reservation, State,
+    StatsDelta, StatsSnapshot, ANONYMOUS_TENANT, MATERIAL_CHANGE, REQUEST_KINDS,
+};
+pub use stats::{
+    HumanBytes, Stats, StatsError, StatsParseError, StatsV2, VersionedStats, STATS_LEN,
+    STATS_VERSION,
+};
 
+// Only the compressor and the layout of `Stats` are part of the wire format,
+// handling requests (`Connection`) needs the `std` feature and serving them
+// over tokio, the `Server` itself, the `server` feature
+#[cfg(feature = "std")]
+mod accept;
+#[cfg(feature = "std")]
+mod binary;
+#[cfg(feature = "server")]
+mod coalesce;
+#[cfg(feature = "async-std")]
+mod compat;
 mod compress;
+#[cfg(feature = "std")]
+mod config;
+#[cfg(feature = "std")]
 mod connection;
+#[cfg(feature = "std")]
+mod flush;
+#[cfg(any(feature = "server", feature = "blocking"))]
+pub(crate) mod framing;
+#[cfg(feature = "std")]
+pub mod goodbye;
+#[cfg(feature = "server")]
+mod group;
+#[cfg(feature = "server")]
+mod handle;
+#[cfg(feature = "server")]
+mod lanes;
+#[cfg(feature = "std")]
+pub mod limits;
+#[cfg(feature = "std")]
+mod metrics;
+#[cfg(feature = "std")]
+mod ratio;
+#[cfg(feature = "std")]
+mod recent;
+#[cfg(feature = "server")]
+mod rejects;
+#[cfg(feature = "server")]
+mod report;
+#[cfg(feature = "server")]
+mod requests;
+#[cfg(feature = "std")]
+mod scheme;
+#[cfg(feature = "server")]
+mod spawn;
+#[cfg(feature = "std")]
 mod state;
 pub mod stats;
+#[cfg(feature = "server")]
+mod text;
 
+#[cfg(feature = "server")]
+use coalesce::Coalesced;
+#[cfg(feature = "server")]
+use framing::Conduct;
+#[cfg(feature = "server")]
+use requests::Framer;
+#[cfg(feature = "server")]
 use std::{
+    cmp,
+    future::{self, Future},
     io::{Error, ErrorKind},
+    mem,
     sync::Arc,
+    time::{Duration, SystemTime},
 };
+#[cfg(feature = "server")]
 use tokio::{
-    net::{TcpListener, TcpStream},
-    prelude::*,
-    sync::Mutex,
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    net::{self, TcpListener, TcpSocket},
+    runtime::Handle,
+    sync::{oneshot, watch, Mutex, Semaphore},
+    task::{self, JoinSet},
+    time,
 };
 
+#[cfg(feature = "server")]
+use zerocopy::AsBytes;
+#[cfg(feature = "server")]
+use zeroize::Zeroize;
+
+#[cfg(feature = "server")]
 type Result<T> = std::result::Result<T, std::io::Error>;
 
 // `State`, `Message`, `Connection` could be generalized
 
+// Time is only ever taken from `tokio::time`, so everything time dependent can
+// be tested deterministically on a paused runtime (`start_paused`)
+
 /// The compression Server
+#[cfg(feature = "server")]
 pub struct Server {
     pub listener: TcpListener,
+    /// The debug port's listener, when `ServerConfig::debug_addr` is set
+    pub debug_listener: Option<TcpListener>,
     the_state: Arc<Mutex<State>>,
+    /// The configuration connections consult before each request, replaced
+    /// through a `ConfigHandle`
+    config: Arc<watch::Sender<Arc<ServerConfig>>>,
+    changes: watch::Receiver<StatsSnapshot>,
+    /// Schedules the requests of every connection, sized at startup
+    lanes: Lanes,
+    /// The latest requests of every connection, sized at startup
+    recent: Recent,
+    /// Dumps the requests the connections reject
+    rejects: RejectLog,
 }
 
+#[cfg(feature = "server")]
 impl Server {
     /// # Examples
     ///
@@ -41,39 +198,309 @@ impl Server {
     /// }
     /// ```
     pub async fn new_with_url(url: &str) -> Result<Server> {
-        let listener = TcpListener::bind(url).await?;
-        let the_state = Arc::new(Mutex::new(State::new()));
+        Server::new_with_config(url, Default::default()).await
+    }
+
+    /// Creates a server listening at `url` that behaves according to `config`
+    pub async fn new_with_config(url: &str, config: ServerConfig) -> Result<Server> {
+        let (publisher, changes) = watch::channel(StatsSnapshot::default());
+        let mut state = State::new();
+        state.observe(Arc::new(move |snapshot: &StatsSnapshot| {
+            publisher.send_replace(snapshot.clone());
+        }));
+        let lanes = Lanes::new_with(config.heavy_lane);
+        Server::new_with_state(url, config, Arc::new(Mutex::new(state)), changes, lanes).await
+    }
+
+    /// Creates a server listening at `url` that accounts for its requests in
+    /// `state`, whose changes are published on `changes`, and schedules them
+    /// in `lanes`, see `ServerGroup`
+    async fn new_with_state(
+        url: &str,
+        config: ServerConfig,
+        state: Arc<Mutex<State>>,
+        changes: watch::Receiver<StatsSnapshot>,
+        lanes: Lanes,
+    ) -> Result<Server> {
+        let (attempts, backoff) = (config.bind_attempts, config.bind_backoff);
+        let listener = Server::bind_with_retry(url, attempts, backoff).await?;
+        let debug_listener = match &config.debug_addr {
+            Some(addr) => Some(Server::bind_with_retry(addr, attempts, backoff).await?),
+            None => None,
+        };
         Ok(Server {
             listener,
-            the_state,
+            debug_listener,
+            the_state: state,
+            lanes,
+            recent: Recent::new_with(config.recent_requests),
+            rejects: RejectLog::default(),
+            config: Arc::new(watch::Sender::new(Arc::new(config))),
+            changes,
         })
     }
 
+    /// Binds a listener at `url`, retrying while the address is in use for
+    /// up to `attempts` tries in all. The first retry waits `backoff`, each
+    /// next one twice as long as the previous. Any other error, e.g. a
+    /// permission denied or an address that doesn't resolve, fails at once
+    ///
+    /// The socket is bound with SO_REUSEADDR (but on Windows, where it would
+    /// let another socket take over the address) so that the connections of
+    /// a previous process left in TIME_WAIT don't keep it from binding
+    pub async fn bind_with_retry(
+        url: &str,
+        attempts: u32,
+        backoff: Duration,
+    ) -> Result<TcpListener> {
+        let mut delay = backoff;
+        let mut attempt = 1;
+        loop {
+            match Server::bind(url).await {
+                Err(e) if e.kind() == ErrorKind::AddrInUse && attempt < attempts => {
+                    tracing::warn!(
+                        "{} is in use, retrying in {:?} ({}/{})",
+                        url,
+                        delay,
+                        attempt,
+                        attempts
+                    );
+                    time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Binds the first of the addresses `url` resolves to that it can
+    async fn bind(url: &str) -> Result<TcpListener> {
+        let mut last_error = None;
+        for addr in net::lookup_host(url).await? {
+            let socket = if addr.is_ipv4() {
+                TcpSocket::new_v4()?
+            } else {
+                TcpSocket::new_v6()?
+            };
+            #[cfg(not(windows))]
+            socket.set_reuseaddr(true)?;
+            match socket.bind(addr) {
+                Ok(()) => return socket.listen(1024),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| Error::new(ErrorKind::InvalidInput, "no address to bind to")))
+    }
+
+    /// A read-only handle on the stats of the service, for an embedding
+    /// application to report them without speaking the protocol
+    pub fn state_handle(&self) -> StateHandle {
+        StateHandle::new_with(
+            Arc::clone(&self.the_state),
+            self.changes.clone(),
+            self.lanes.clone(),
+        )
+    }
+
+    /// A handle that can also reset the stats of the service, like a global
+    /// ResetStats
+    pub fn reset_handle(&self) -> ResetHandle {
+        ResetHandle::new_with(self.state_handle())
+    }
+
+    /// The latest requests the connections handled, see
+    /// `ServerConfig::recent_requests`
+    pub fn recent(&self) -> Recent {
+        self.recent.clone()
+    }
+
+    /// What was dumped of the requests the connections rejected, see
+    /// `ServerConfig::reject_log`
+    pub fn reject_log(&self) -> RejectLog {
+        self.rejects.clone()
+    }
+
+    /// A handle to replace the configuration of the server while it serves,
+    /// e.g. on SIGHUP
+    pub fn config_handle(&self) -> ConfigHandle {
+        ConfigHandle::new_with(Arc::clone(&self.config))
+    }
+
+    /// Serves on a task of its own, returning the handle to stop it with.
+    /// Shutting down gives the connections being served
+    /// `ServerConfig::shutdown_timeout` to finish
+    ///
+    /// # Example
+    /// ```no_run
+    /// use service::Server;
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), std::io::Error> {
+    ///     let mut server = Server::new_with_url("127.0.0.1:0").await?.spawn();
+    ///     println!("Serving @ {}", server.local_addr());
+    ///     // ...
+    ///     server.shutdown().await
+    /// }
+    /// ```
+    pub fn spawn(mut self) -> ServerHandle {
+        let local_addr = self.listener.local_addr().unwrap();
+        let state = self.state_handle();
+        let (stop, stopped) = oneshot::channel();
+        let name = format!("server {}", local_addr);
+        let task = spawn_named(
+            &name,
+            async move {
+                // a dropped `ServerHandle` leaves the server running
+                let shutdown = async {
+                    if stopped.await.is_err() {
+                        future::pending().await
+                    }
+                };
+                let grace = self.config.borrow().shutdown_timeout;
+                self.serve_on(&Handle::current(), shutdown, grace).await
+            },
+            &Handle::current(),
+        );
+        ServerHandle::new_with(local_addr, state, stop, task)
+    }
+
     /// Asynchronous accept loop for a TcpListener listening at a given url
     /// Multiple threads are spawned for processing connections in parallel
     pub async fn serve(&mut self) -> Result<()> {
-        println!(
+        self.serve_on(&Handle::current(), future::pending(), Duration::ZERO)
+            .await
+    }
+
+    /// Accept loop spawning the connections on the runtime of `handle`, until
+    /// `shutdown` completes. Accepting then stops and the connections being
+    /// served are given `grace` to finish, whichever are still open after it,
+    /// e.g. idle ones, are dropped
+    ///
+    /// # Example
+    /// ```no_run
+    /// use service::Server;
+    /// use std::time::Duration;
+    ///
+    /// let runtime = tokio::runtime::Runtime::new().unwrap();
+    /// let handle = runtime.handle().clone();
+    /// runtime
+    ///     .block_on(async {
+    ///         let mut server = Server::new_with_url("127.0.0.1:4000").await?;
+    ///         let shutdown = async { tokio::signal::ctrl_c().await.unwrap() };
+    ///         server.serve_on(&handle, shutdown, Duration::from_secs(5)).await
+    ///     })
+    ///     .unwrap();
+    /// runtime.shutdown_timeout(Duration::from_secs(1));
+    /// ```
+    pub async fn serve_on<F>(&mut self, handle: &Handle, shutdown: F, grace: Duration) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        tracing::info!(
             "Starting Compression Service @ {}",
             self.listener.local_addr().unwrap()
         );
+        // the settings sized or bound at startup, see
+        // `ServerConfig::restart_only_changes`
+        let config = Arc::clone(&self.config.borrow());
+        let debug = self.debug_listener.take().map(|listener| {
+            tracing::info!("Starting Debug Port @ {}", listener.local_addr().unwrap());
+            let state = Arc::clone(&self.the_state);
+            let recent = self.recent.clone();
+            let accept = Server::serve_text(listener, state, self.config.subscribe(), recent);
+            spawn_named("debug port", accept, handle)
+        });
+        let connections = Server::connection_limit(&config);
+        let (accepts, draining, peaks, gauge) = {
+            let state = self.the_state.lock().await;
+            let gauge = state.connections();
+            (state.accepts(), state.draining(), state.peaks(), gauge)
+        };
+        let period = config.stats_interval.filter(|period| !period.is_zero());
+        let reporter = period.map(|period| {
+            let state = Arc::clone(&self.the_state);
+            let gauge = gauge.clone();
+            let report = report_stats(state, gauge, period, |report| tracing::info!("{}", report));
+            spawn_named("stats reporter", report, handle)
+        });
+        // the servers of a group share the state, each listener is counted
+        // by its address
+        let listener_accepts = accepts.listener(&self.listener.local_addr()?.to_string());
+        let mut tasks = JoinSet::new();
+        // numbers the connections in the names of their tasks
+        let mut accepted_count = 0u64;
+        tokio::pin!(shutdown);
         loop {
-            match self.listener.accept().await {
+            let permit = match Arc::clone(&connections).try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    accepts.update_limit_wait();
+                    tokio::select! {
+                        _ = &mut shutdown => break,
+                        permit = Arc::clone(&connections).acquire_owned() => permit.unwrap(),
+                    }
+                }
+            };
+            while tasks.try_join_next().is_some() {}
+            let accepted = tokio::select! {
+                _ = &mut shutdown => break,
+                accepted = self.listener.accept() => accepted,
+            };
+            match accepted {
                 Ok((stream, _)) => {
+                    listener_accepts.update_accepted();
+                    let connected = gauge.accept();
+                    let open = peaks.open_connection();
                     let peer_addr = stream.peer_addr()?;
                     let state = Arc::clone(&self.the_state);
-                    tokio::spawn(async move {
-                        // println!("Client @ {:?}", peer_addr);
+                    let config = self.config.subscribe();
+                    let lanes = self.lanes.clone();
+                    accepted_count += 1;
+                    let recorder = self.recent.connection(accepted_count, Some(peer_addr));
+                    let rejects = self.rejects.connection();
+                    let name = format!("connection {} ({})", accepted_count, peer_addr);
+                    spawn_named_in(
+                        &mut tasks,
+                        &name,
+                        async move {
+                            // the connection is only counted as open and its
+                            // permit held by the task, a task panicking or
+                            // cancelled drops them all the same
+                            let _counted = (connected, open, permit);
+                            let processed = Server::process_watched(
+                                stream, state, config, lanes, recorder, rejects,
+                            );
+                            if let Err(e) = processed.await {
+                                tracing::warn!("{}", e)
+                            }
 
-                        if let Err(e) = Server::process(stream, state).await {
-                            eprintln!("{}", e)
-                        }
-
-                        println!("Client @ {:?} Complete", peer_addr);
-                    });
+                            tracing::info!("Client @ {:?} Complete", peer_addr);
+                        },
+                        handle,
+                    );
+                }
+                Err(e) => {
+                    let class = accepts.update_error(&e);
+                    tracing::error!("accept failed ({}): {:?}", class.name(), e)
                 }
-                Err(e) => eprintln!("{:?}", e),
             }
         }
+
+        // Health reports draining from here on, the listener is only closed
+        // once `self` is dropped
+        draining.start();
+        for task in debug.into_iter().chain(reporter) {
+            task.abort();
+        }
+        let drained =
+            time::timeout(grace, async { while tasks.join_next().await.is_some() {} }).await;
+        if drained.is_err() {
+            tracing::warn!("Dropping {} connections still open", tasks.len());
+        }
+        tasks.shutdown().await;
+        Ok(())
     }
 
     /// Process communication from a given client connection, consumes client
@@ -85,49 +512,1747 @@ impl Server {
     /// with the use of bytes::Bytes and a Framed codec
     /// and wasted stack space
     ///
-    /// TODO:
-    /// Find alternative to dropping the client for flooding the server with
-    /// excessively large messages perhaps, rate limiting or a warning response?
-    pub async fn process(mut stream: TcpStream, state: Arc<Mutex<State>>) -> Result<()> {
+    /// Generic over the stream so connections can be served over anything
+    /// bidirectional, e.g. `tokio::io::duplex` in tests
+    ///
+    /// A client may half-close the connection once it sent its last request:
+    /// every whole message read before the end of the stream is still
+    /// answered, and only once the responses are written is the service's
+    /// side shut down
+    pub async fn process<S>(
+        stream: S,
+        state: Arc<Mutex<State>>,
+        config: Arc<ServerConfig>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (_, config) = watch::channel(config);
+        let (recorder, rejects) = (Recorder::default(), RejectLog::default().connection());
+        Server::process_watched(stream, state, config, Lanes::default(), recorder, rejects).await
+    }
+
+    /// `process` with the latest configuration sent on `configs` applied to
+    /// each request, and to the wait for it, so a reload is seen without
+    /// reconnecting. Each request waits for its turn in `lanes`, shared with
+    /// the other connections, and is recorded by `recorder` once answered.
+    /// Those rejected are dumped by `rejects`
+    pub async fn process_watched<S>(
+        stream: S,
+        state: Arc<Mutex<State>>,
+        mut configs: watch::Receiver<Arc<ServerConfig>>,
+        lanes: Lanes,
+        recorder: Recorder,
+        mut rejects: ConnectionRejects,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // the responses are held back as the flush policy has it
+        let mut stream = Coalesced::new(stream, &configs.borrow());
+        // reads the requests a framed message at a time, like a
+        // `RequestStream` does
+        let mut framer = Framer::new();
+        let mut tx = [0u8; message::MAX_MESSAGE_PADDED];
+        // requests handled since the task last waited for input or yielded
+        let mut handled = 0;
+        // the stats of this connection alone, the scope ResetStats resets by
+        // default
+        let mut session = State::new();
+        let mut requests = 0;
+        // the strikes and violations the peer is closed for
+        let mut conduct = Conduct::new();
+        let (in_flight, peaks) = {
+            let state = state.lock().await;
+            (state.in_flight(), state.peaks())
+        };
+        let opened = time::Instant::now();
+        let hello = configs.borrow().hello.then(|| configs.borrow().limits());
+        if let Some(limits) = hello {
+            Server::say_hello(&mut stream, &state, &limits).await?;
+        }
+        // the pending states of the responses held back, accounted for once
+        // they're written out
+        let mut held = stream.held();
+        let mut write_failed = false;
+        let served: Result<()> = 'serve: loop {
+            let config = Arc::clone(&configs.borrow_and_update());
+            stream.configure(&config);
+            // waiting for a request ends with the connection's lifetime
+            let lifetime_left = config.lifetime_left(opened.elapsed());
+            let timeout = match (config.idle_timeout, lifetime_left) {
+                (Some(idle), Some(left)) => Some(idle.min(left)),
+                (idle, left) => idle.or(left),
+            };
+            // cancelling while waiting for a request leaves nothing to account for
+            let frame_timeout = config.frame_timeout;
+            let read = {
+                let read = framer.read(&mut stream, timeout, frame_timeout);
+                tokio::pin!(read);
+                loop {
+                    tokio::select! {
+                        read = &mut read => break read,
+                        // held back until the read waits, the responses are
+                        // accounted for as soon as they're written out
+                        written_out = held.flushed(), if !held.is_empty() => {
+                            Server::account(&state, written_out).await;
+                        }
+                    }
+                }
+            };
+            let (read, waited) = match read {
+                Ok(read) => read,
+                Err(e)
+                    if e.kind() == ErrorKind::TimedOut
+                        && config.rotation_due(requests as usize, opened.elapsed()) =>
+                {
+                    Server::rotate(&mut stream, &state, &session, requests).await;
+                    break 'serve Ok(());
+                }
+                Err(e) if e.kind() == ErrorKind::TimedOut => {
+                    let goodbye = Goodbye::new_with(
+                        session.snapshot().stats,
+                        requests,
+                        GoodbyeReason::IdleTimeout,
+                    );
+                    Server::say_goodbye(&mut stream, &state, goodbye).await;
+                    break 'serve Err(e);
+                }
+                Err(e) => break 'serve Err(e),
+            };
+            if waited {
+                handled = 0;
+            }
+            if read == 0 {
+                // closed, or half-closed by a client done sending: every
+                // message read before is answered, shutting down our side
+                // flushes the responses. The peer may be gone already, which
+                // is no error of ours
+                let _ = stream.shutdown().await;
+                break 'serve Ok(());
+            }
+            let (at, started) = (SystemTime::now(), time::Instant::now());
+
+            // a read of more than one message is answered a message at a
+            // time, the rest of an oversized one is drained
+            let framed = match framer.frame(&mut stream, read, timeout, &config).await {
+                Ok(framed) => framed,
+                Err(e) => break 'serve Err(e),
+            };
+            let (bytes_read, skipped, drained) = (framed.len, framed.skipped, framed.drained);
+            let rx = framer.message();
+            let discarded = framing::discarded(bytes_read, skipped, drained);
+            // read past the message and never answered
+            let dropped = drained + skipped;
+
+            // the request is held until its response is written, over the
+            // budget it isn't handled
+            let budget = config.memory_budget_of(&rx[..bytes_read]);
+            let mut reservation = in_flight.reserve(bytes_read, budget);
+            let lane = Lane::of(&rx[..bytes_read]);
+            let turn = lanes.enter(lane).await;
+            let heavy = (lane == Lane::Heavy).then(|| peaks.open_heavy());
+
+            // The request is accounted for in a pending state of its own,
+            // handled with no lock held and applied to the shared state once
+            // the response is written, if the task is cancelled none of it is
+            // accounted for. A request of the control lane reads the shared
+            // state, it is handled against a view of it in a critical section
+            // of its own, and so are the stats a CompressWithStats reports
+            // restated. Either is still applied once the response is written
+            let mut pending = state.lock().await.pending();
+            let received = dropped + bytes_read;
+            framing::account_read(&mut pending, &mut session, received, discarded, &config);
+
+            let (size, code) = if reservation.is_none() {
+                framing::set_busy(&mut tx, &mut pending)
+            } else {
+                let request = &rx[..bytes_read];
+                // the stats a request reads cover every response before it,
+                // those held back are written out first
+                let reports = matches!(
+                    message::Request::of(request),
+                    Some(
+                        message::Request::GetStats
+                            | message::Request::GetStatsV2
+                            | message::Request::ResetStats
+                            | message::Request::FlushStats
+                            | message::Request::CompressWithStats
+                    )
+                );
+                if reports && !held.is_empty() {
+                    if let Err(e) = stream.flush().await {
+                        held.hold(pending);
+                        break 'serve Err(e);
+                    }
+                }
+                let dispatched = time::Instant::now();
+                let size = if lane == Lane::Control {
+                    let mut shared = state.lock().await;
+                    for written in held.written_out() {
+                        shared.apply(written);
+                    }
+                    pending = shared.view(pending);
+                    let size = handle_request_scoped(
+                        request,
+                        &mut tx,
+                        &mut pending,
+                        &mut session,
+                        &config,
+                    )
+                    .expect("tx holds any response");
+                    shared.admit_from(&pending);
+                    size
+                } else {
+                    let size = Server::handle_bounded(
+                        request,
+                        lane,
+                        &mut tx,
+                        &mut pending,
+                        &mut session,
+                        &config,
+                    )
+                    .await;
+                    if message::Request::of(request) == Some(message::Request::CompressWithStats) {
+                        let mut shared = state.lock().await;
+                        for written in held.written_out() {
+                            shared.apply(written);
+                        }
+                        pending = shared.view(pending);
+                        restate_stats(request, &mut tx, size, &mut pending, &session, &config);
+                    }
+                    size
+                };
+                let took = dispatched.elapsed();
+                let code = message::Message::parse(&tx[..size]).unwrap().header.code();
+                // the built-in handlers can't be cut short, one running long
+                // is at least told about
+                if let Some(deadline) =
+                    framing::account_handled(&mut pending, &mut session, took, &config)
+                {
+                    let mut summary = String::new();
+                    recorder
+                        .summarize(request, received, code, at, took, false)
+                        .write_json(&mut summary);
+                    tracing::warn!(
+                        "Request took {:?}, past the deadline of {:?}: {}",
+                        took,
+                        deadline,
+                        summary
+                    );
+                }
+                let served = Response::from_u16(code).is_some_and(|response| response.is_success());
+                if let (Some(policy), false) = (&config.reject_log, served) {
+                    rejects.reject(policy, request, code);
+                }
+                (size, code)
+            };
+            handled += 1;
+            pending.update_requests_per_wake(handled);
+
+            let verdict = conduct.judge(&rx[..bytes_read], code, discarded, &config);
+
+            let record = || {
+                let (read, duration) = (dropped + bytes_read, started.elapsed());
+                let capture = config.capture_payload_prefix;
+                recorder.record(&rx[..bytes_read], read, code, at, duration, capture);
+            };
+            // the turn ends once the request is handled, a peer that stops
+            // reading its responses holds no lane while its write waits
+            drop((turn, heavy));
+            let accepted = stream.accepted();
+            let written = if verdict.respond {
+                if let Some(reservation) = reservation.as_mut() {
+                    reservation.grow(size);
+                }
+                Some(Server::write_response(&mut stream, &tx[..size]).await)
+            } else {
+                None
+            };
+            record();
+            // nothing of the request is kept past its response, the whole of
+            // tx as a failed compress may have written past the response
+            if config.zeroize_buffers {
+                framer.zeroize_message();
+                tx.zeroize();
+            }
+            framer.advance(&config);
+            if let Some((written, result)) = written {
+                // a failed write still accounts for the bytes that made it out,
+                // though not for the request it was answering
+                if let Err(e) = result {
+                    let mut shared = state.lock().await;
+                    framing::account_failed(&mut shared, pending, &session, written, &config);
+                    write_failed = true;
+                    break 'serve Err(e);
+                }
+                framing::account_sent(&mut pending, &mut session, written, &config);
+                requests += 1;
+            }
+            let closed = verdict.closed(&mut pending);
+            // a response held back is accounted for once written out, after
+            // those written out before it
+            let answered = if stream.accepted() != accepted {
+                held.hold(pending);
+                None
+            } else {
+                Some(pending)
+            };
+            {
+                let written_out = held.written_out();
+                let mut shared = state.lock().await;
+                for pending in written_out.into_iter().chain(answered) {
+                    shared.apply(pending);
+                }
+                shared.publish();
+            }
+            drop(reservation);
+
+            if let Some(e) = closed {
+                match verdict.goodbye(&config) {
+                    Some(reason) => {
+                        let goodbye = Goodbye::new_with(session.snapshot().stats, requests, reason);
+                        Server::say_goodbye(&mut stream, &state, goodbye).await;
+                    }
+                    None => {
+                        let _ = stream.flush().await;
+                    }
+                }
+                break 'serve Err(e);
+            }
+
+            if config.rotation_due(requests as usize, opened.elapsed()) {
+                Server::rotate(&mut stream, &state, &session, requests).await;
+                break 'serve Ok(());
+            }
+
+            // a connection pipelining requests never waits for input, let the
+            // other connections on this worker run every so often
+            if config.requests_per_yield > 0 && handled % config.requests_per_yield == 0 {
+                task::yield_now().await;
+                handled = 0;
+            }
+        };
+
+        // the responses held back that never made it out answered nothing,
+        // their requests are accounted for as read like any failed write
+        let (written_out, unsent) = (held.written_out(), held.unsent());
+        if !written_out.is_empty() || !unsent.is_empty() {
+            let tenancy = configs.borrow().tenancy();
+            let mut shared = state.lock().await;
+            for pending in written_out {
+                shared.apply(pending);
+            }
+            if !unsent.is_empty() {
+                for pending in unsent {
+                    shared.apply_unanswered(pending);
+                }
+                shared.update_sent(stream.held_written());
+                if tenancy {
+                    shared.update_tenant_sent(session.tenant(), stream.held_written());
+                }
+                if !write_failed {
+                    shared.update_failed_write();
+                }
+            }
+            shared.publish();
+        }
+        served
+    }
+
+    /// Applies the pending states of responses written out to `state`
+    async fn account(state: &Mutex<State>, written_out: Vec<State>) {
+        let mut shared = state.lock().await;
+        for pending in written_out {
+            shared.apply(pending);
+        }
+        shared.publish();
+    }
+
+    /// Handles `request` into `tx` like `handle_request_scoped`, under
+    /// `ServerConfig::request_deadline` when a custom `scheme` handles it:
+    /// the scheme then runs on the blocking pool and past the deadline the
+    /// request is answered UnknownError, none of it accounted for but the
+    /// error. A scheme that hangs keeps its blocking thread, the connection
+    /// goes on all the same
+    async fn handle_bounded(
+        request: &[u8],
+        lane: Lane,
+        tx: &mut [u8],
+        state: &mut State,
+        session: &mut State,
+        config: &Arc<ServerConfig>,
+    ) -> usize {
+        let deadline = match config.request_deadline {
+            Some(deadline) if lane == Lane::Heavy && config.scheme.is_some() => deadline,
+            _ => {
+                return handle_request_scoped(request, tx, state, session, config)
+                    .expect("tx holds any response")
+            }
+        };
+        let (request, mut out) = (request.to_vec(), tx.to_vec());
+        let (mut handled, mut handled_session) = (state.clone(), session.clone());
+        let config = Arc::clone(config);
+        let handling = task::spawn_blocking(move || {
+            let size = handle_request_scoped(
+                &request,
+                &mut out,
+                &mut handled,
+                &mut handled_session,
+                &config,
+            )
+            .expect("tx holds any response");
+            (size, out, handled, handled_session)
+        });
+        match time::timeout(deadline, handling).await {
+            Ok(Ok((size, out, handled, handled_session))) => {
+                tx.copy_from_slice(&out);
+                *state = handled;
+                *session = handled_session;
+                size
+            }
+            // a scheme that panics takes the connection down as it would
+            // have handled inline
+            Ok(Err(e)) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            _ => {
+                message::Message::parse_mut(&mut tx[..message::HEADER_SIZE])
+                    .unwrap()
+                    .set_header_with_default_magic(0, Response::UnknownError as u16);
+                state.update_error();
+                session.update_error();
+                message::HEADER_SIZE
+            }
+        }
+    }
+
+    /// Limits the connections served at once to `config.max_connections`
+    fn connection_limit(config: &ServerConfig) -> Arc<Semaphore> {
+        let permits = match config.max_connections {
+            0 => Semaphore::MAX_PERMITS,
+            n => n,
+        };
+        Arc::new(Semaphore::new(permits))
+    }
+
+    /// Accept loop of the debug port, see `Server::process_text`
+    /// Each connection keeps the configuration it was accepted with
+    async fn serve_text(
+        listener: TcpListener,
+        state: Arc<Mutex<State>>,
+        configs: watch::Receiver<Arc<ServerConfig>>,
+        recent: Recent,
+    ) {
+        let accepts = state.lock().await.accepts();
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer_addr)) => {
+                    let state = Arc::clone(&state);
+                    let config = Arc::clone(&configs.borrow());
+                    let recent = recent.clone();
+                    let name = format!("debug connection ({})", peer_addr);
+                    let serve = async move {
+                        let processed = Server::process_text_with(stream, state, config, recent);
+                        if let Err(e) = processed.await {
+                            tracing::warn!("{}", e)
+                        }
+                        tracing::info!("Debug Client @ {:?} Complete", peer_addr);
+                    };
+                    spawn_named(&name, serve, &Handle::current());
+                }
+                Err(e) => {
+                    let class = accepts.update_error(&e);
+                    tracing::error!("debug port accept failed ({}): {:?}", class.name(), e)
+                }
+            }
+        }
+    }
+
+    /// Process a connection to the debug port, a line based text protocol for
+    /// when only a tool like netcat is at hand, every line is answered with a
+    /// single line, e.g. "COMPRESS aaaaabbb" => "OK 5a3b"
+    ///
+    /// Each line is translated into the binary request it stands for and
+    /// handled by `Connection` like those of `process`, only the text read
+    /// and sent is what's accounted for in the stats. See `text::Command`.
+    /// METRICS is answered by the port itself, with the lines of the
+    /// `Metrics` of the state ending with "# EOF"
+    pub async fn process_text<S>(
+        stream: S,
+        state: Arc<Mutex<State>>,
+        config: Arc<ServerConfig>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        Server::process_text_with(stream, state, config, Recent::default()).await
+    }
+
+    /// `process_text` also answering RECENT, with the requests in `recent`
+    /// as a JSON array on one line, e.g. "OK [{\"at_ms\":...}]". RECENT is
+    /// answered by the port itself, it stands for no binary request
+    pub async fn process_text_with<S>(
+        stream: S,
+        state: Arc<Mutex<State>>,
+        config: Arc<ServerConfig>,
+        recent: Recent,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut stream = BufReader::new(stream);
         let mut rx = [0u8; message::MAX_MESSAGE_PADDED];
         let mut tx = [0u8; message::MAX_MESSAGE_PADDED];
+        let mut session = State::new();
+        let mut line = Vec::new();
         loop {
-            let mut state = state.lock().await;
-            let bytes_read = stream.read(&mut rx).await?;
+            line.clear();
+            let bytes_read = Server::read_line(&mut stream, &mut line).await?;
             if bytes_read == 0 {
                 return Ok(()); // connection closed
             }
+            let command = line.strip_suffix(b"\n").unwrap_or(&line);
+            let command = command.strip_suffix(b"\r").unwrap_or(command);
+
+            // a command reads the shared state, it is handled against it in
+            // a critical section of its own and the bytes sent for it are
+            // accounted for once written, no lock is held meanwhile
+            let mut shared = state.lock().await;
+            shared.update_read(bytes_read);
+            session.update_read(bytes_read);
+            if config.tenancy() {
+                shared.update_tenant_read(session.tenant(), bytes_read);
+            }
+
+            let mut reply = match text::Command::parse(command) {
+                _ if command.eq_ignore_ascii_case(text::RECENT) => {
+                    format!("OK {}", recent.to_json())
+                }
+                _ if command.eq_ignore_ascii_case(text::METRICS) => {
+                    format!("{}# EOF", shared.metrics().to_prometheus())
+                }
+                Ok(command) => {
+                    let len = command.encode(&mut rx);
+                    let size = Connection::new_with(&rx[..len], &mut tx[..], len)
+                        .create_response_scoped(&mut shared, &mut session, &config);
+                    let response = message::Message::parse(&tx[..size]).unwrap();
+                    let code = Response::from_u16(response.header.code());
+                    command.reply(code, &tx[message::HEADER_SIZE..size])
+                }
+                Err(error) => format!("ERR {}", error),
+            };
+            reply.push('\n');
+            drop(shared);
+
+            let (written, result) = Server::write_response(&mut stream, reply.as_bytes()).await;
+            let mut shared = state.lock().await;
+            shared.update_sent(written);
+            if config.tenancy() {
+                shared.update_tenant_sent(session.tenant(), written);
+            }
+            if let Err(e) = result {
+                shared.update_failed_write();
+                return Err(e);
+            }
+            session.update_sent(written);
+        }
+    }
 
-            // MessageTooLarge so, drop the rest so that we can create error response
-            // and free up the stream to read in subsequent messages
-            if bytes_read > message::MAX_MESSAGE {
-                let mut bytes = [0u8; message::MAX_MESSAGE_PADDED];
-                let num_bytes = stream.read(&mut bytes).await?;
-                state.update_read(num_bytes);
-                if num_bytes >= message::MAX_MESSAGE {
-                    return Err(Error::new(ErrorKind::Other, "Dropping client"));
+    /// Reads the next line into `line`, returning the bytes read. At most
+    /// `MAX_LINE` bytes and a "\r\n" are kept, the rest of a longer line is
+    /// read and discarded
+    async fn read_line<R>(reader: &mut R, line: &mut Vec<u8>) -> Result<usize>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        let limit = text::MAX_LINE as u64 + 2;
+        let mut read = (&mut *reader).take(limit).read_until(b'\n', line).await?;
+        if line.last() != Some(&b'\n') && read as u64 == limit {
+            let mut rest = Vec::new();
+            loop {
+                rest.clear();
+                let n = (&mut *reader)
+                    .take(limit)
+                    .read_until(b'\n', &mut rest)
+                    .await?;
+                read += n;
+                if n == 0 || rest.last() == Some(&b'\n') {
+                    break;
                 }
             }
-            state.update_read(bytes_read);
+        }
+        Ok(read)
+    }
+
+    /// Reads and throws away the next `len` bytes of `stream`, returning how
+    /// many there were. Stops early at the end of the stream or when nothing
+    /// arrives within `idle_timeout`, the next read finds out which
+    async fn drain<S>(
+        stream: &mut S,
+        len: usize,
+        idle_timeout: Option<Duration>,
+        zeroize: bool,
+    ) -> Result<usize>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut bytes = [0u8; message::MAX_MESSAGE_PADDED];
+        let mut drained = 0;
+        while drained < len {
+            let want = cmp::min(len - drained, bytes.len());
+            let read = match Server::read_request(stream, &mut bytes[..want], idle_timeout).await {
+                Ok((0, _)) => break,
+                Ok((read, _)) => read,
+                Err(e) if e.kind() == ErrorKind::TimedOut => break,
+                Err(e) => return Err(e),
+            };
+            if zeroize {
+                bytes[..read].zeroize();
+            }
+            drained += read;
+        }
+        Ok(drained)
+    }
 
-            // the request buffer (rx) must be atleast the size of the header
-            // otherwise parsing the buffer into a Message will return None
-            let sz = std::cmp::max(message::HEADER_SIZE, bytes_read);
+    /// Reads the next request into `rx`, also reporting whether the task had
+    /// to wait for it
+    async fn read_request<S>(
+        stream: &mut S,
+        rx: &mut [u8],
+        idle_timeout: Option<Duration>,
+    ) -> Result<(usize, bool)>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut waited = false;
+        let read = stream.read(rx);
+        tokio::pin!(read);
+        let read = future::poll_fn(|cx| {
+            let poll = read.as_mut().poll(cx);
+            waited |= poll.is_pending();
+            poll
+        });
+        let bytes_read = match idle_timeout {
+            Some(idle_timeout) => time::timeout(idle_timeout, read)
+                .await
+                .map_err(|_| Error::new(ErrorKind::TimedOut, "Idle timeout"))??,
+            None => read.await?,
+        };
+        Ok((bytes_read, waited))
+    }
 
-            let size = Connection::new_with(&rx[..sz], &mut tx[..], bytes_read)
-                .create_response(&mut state);
+    /// Closes a connection at its request cap or lifetime, its Goodbye tells
+    /// the client to reconnect
+    async fn rotate<S>(stream: &mut S, state: &Mutex<State>, session: &State, requests: u32)
+    where
+        S: AsyncWrite + Unpin,
+    {
+        state.lock().await.update_rotation();
+        let goodbye = Goodbye::new_with(session.snapshot().stats, requests, GoodbyeReason::Rotated);
+        Server::say_goodbye(stream, state, goodbye).await;
+    }
 
-            stream.write_all(&tx[..size]).await?;
-            state.update_sent(size);
+    /// Sends `goodbye` before the service closes the connection, the bytes
+    /// written count towards the service's stats. Only called while the
+    /// stream is otherwise idle, never after a failed write, and any error is
+    /// ignored as the connection is closed either way
+    async fn say_goodbye<S>(stream: &mut S, state: &Mutex<State>, goodbye: Goodbye)
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let mut tx = [0u8; message::HEADER_SIZE + mem::size_of::<Goodbye>()];
+        let mut message = message::Message::parse_mut(&mut tx[..]).unwrap();
+        message.set_all(
+            message::MAGIC,
+            goodbye.as_bytes().len() as u16,
+            Response::Goodbye as u16,
+            goodbye.as_bytes(),
+        );
+        let (written, _) = Server::write_response(stream, &tx).await;
+        // nothing is held back past the close, whatever the flush policy, a
+        // Goodbye held back only counts once written out
+        let sent = match stream.flush().await {
+            Ok(()) => written,
+            Err(_) => 0,
+        };
+        state.lock().await.update_sent(sent);
+    }
 
-            // Not strictly needed however, zero out buffers for data integrity
-            // Server::unset(&mut rx[..bytes_read]);
-            // Server::unset(&mut tx[..size]);
+    /// Greets a connection just accepted with a Hello of `limits`, the bytes
+    /// written count towards the service's stats
+    async fn say_hello<S>(stream: &mut S, state: &Mutex<State>, limits: &Limits) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let mut tx = [0u8; message::HEADER_SIZE + mem::size_of::<Limits>()];
+        let mut message = message::Message::parse_mut(&mut tx[..]).unwrap();
+        message.set_all(
+            message::MAGIC,
+            limits.as_bytes().len() as u16,
+            Response::Hello as u16,
+            limits.as_bytes(),
+        );
+        let (written, result) = Server::write_response(stream, &tx).await;
+        state.lock().await.update_sent(written);
+        // the client may wait for it before its first request
+        result?;
+        stream.flush().await
+    }
+
+    /// Writes all of `buf` like `write_all`, also reporting how many bytes
+    /// were written when the write fails partway
+    async fn write_response<S>(stream: &mut S, mut buf: &[u8]) -> (usize, Result<()>)
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let mut written = 0;
+        while !buf.is_empty() {
+            match stream.write(buf).await {
+                Ok(0) => return (written, Err(ErrorKind::WriteZero.into())),
+                Ok(n) => {
+                    written += n;
+                    buf = &buf[n..];
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return (written, Err(e)),
+            }
         }
+        (written, Ok(()))
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::message::{Header, HealthStatus, Request};
+    use crate::testing::replay::Script;
+    use crate::testing::wire::ping;
+    use std::{
+        io,
+        pin::Pin,
+        sync::atomic::{AtomicUsize, Ordering},
+        task::{Context, Poll},
+    };
+    use tokio::io::ReadBuf;
+    use zerocopy::AsBytes;
+
+    /// Reads back `reads` one per call, then accepts `accept` bytes of writes
+    /// before failing every write
+    struct MockStream {
+        reads: Vec<Vec<u8>>,
+        accept: usize,
+        written: Vec<u8>,
     }
 
-    #[allow(dead_code)]
-    fn unset(buf: &mut [u8]) {
-        buf.iter_mut().for_each(|x: &mut u8| *x = 0);
+    impl AsyncRead for MockStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if !self.reads.is_empty() {
+                let read = self.reads.remove(0);
+                buf.put_slice(&read);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for MockStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let n = std::cmp::min(self.accept - self.written.len(), buf.len());
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+            }
+            self.written.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    async fn process(reads: Vec<Vec<u8>>, accept: usize) -> (Result<()>, State) {
+        let stream = MockStream {
+            reads,
+            accept,
+            written: Vec::new(),
+        };
+        let state = Arc::new(Mutex::new(State::new()));
+        let result = Server::process(stream, state.clone(), Default::default()).await;
+        let state = state.lock().await.clone();
+        (result, state)
+    }
+
+    fn goodbye_reason(written: &[u8]) -> Option<GoodbyeReason> {
+        let goodbye = message::Message::parse(written)?;
+        assert_eq!(goodbye.header.code(), Response::Goodbye as u16);
+        Goodbye::parse(goodbye.payload_slice())?.reason()
+    }
+
+    fn read_sent(state: &State) -> (u32, u32) {
+        let stats = Stats::parse(state.stats_as_bytes()).unwrap();
+        (stats.read(), stats.sent())
+    }
+
+    #[tokio::test]
+    async fn test_write_response() {
+        let mut stream = MockStream {
+            reads: Vec::new(),
+            accept: 5,
+            written: Vec::new(),
+        };
+        let (written, result) = Server::write_response(&mut stream, b"abcdefgh").await;
+        assert_eq!(written, 5);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::BrokenPipe);
+        assert_eq!(stream.written, b"abcde");
+
+        stream.accept = 13;
+        let (written, result) = Server::write_response(&mut stream, b"ijklmnop").await;
+        assert_eq!(written, 8);
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_memory_budget() {
+        let config = ServerConfig {
+            memory_budget: 16,
+            ..Default::default()
+        };
+        let (_, configs) = watch::channel(Arc::new(config));
+        let state = Arc::new(Mutex::new(State::new()));
+        let in_flight = state.lock().await.in_flight();
+        let serve = |reads| {
+            let state = Arc::clone(&state);
+            let configs = configs.clone();
+            async move {
+                let mut stream = MockStream {
+                    reads,
+                    accept: 64,
+                    written: Vec::new(),
+                };
+                let (lanes, recorder) = (Lanes::default(), Recorder::default());
+                let rejects = RejectLog::default().connection();
+                Server::process_watched(&mut stream, state, configs, lanes, recorder, rejects)
+                    .await
+                    .unwrap();
+                stream.written
+            }
+        };
+        let busy = Header::response(Response::ServerBusy, 0).unwrap();
+
+        // another connection holds 10 bytes, a ping would take it to 18
+        let held = in_flight.reserve(10, 0).unwrap();
+        let written = serve(vec![ping(), ping()]).await;
+        assert_eq!(written, [busy.as_bytes(), busy.as_bytes()].concat());
+        assert_eq!(in_flight.bytes(), 10);
+        let snapshot = state.lock().await.snapshot();
+        assert_eq!(snapshot.errors, 2);
+        assert_eq!(snapshot.requests(&Request::Ping), 0);
+        assert_eq!((snapshot.stats.read(), snapshot.stats.sent()), (16, 16));
+
+        // once released the pings are answered
+        drop(held);
+        let written = serve(vec![ping(), ping()]).await;
+        let ok = Header::response(Response::Ok, 0).unwrap();
+        assert_eq!(written, [ok.as_bytes(), ok.as_bytes()].concat());
+        assert_eq!(in_flight.bytes(), 0);
+        assert_eq!(state.lock().await.requests(&Request::Ping), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reject_log_budget() {
+        // 1000 Compress of MAX_PAYLOAD uppercase letters, room to dump 10
+        let policy = RejectLogPolicy {
+            max_dump: 64,
+            sample_every: 1,
+            connection_rate: 640,
+            global_rate: 0,
+        };
+        let config = ServerConfig {
+            reject_log: Some(policy),
+            ..Default::default()
+        };
+        let payload = vec![b'A'; message::MAX_PAYLOAD as usize];
+        let header = Header::request(Request::Compress, message::MAX_PAYLOAD).unwrap();
+        let request = [header.as_bytes(), &payload[..]].concat();
+        let mut stream = MockStream {
+            reads: vec![request.clone(); 1000],
+            accept: usize::MAX,
+            written: Vec::new(),
+        };
+        let dumps = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&dumps);
+        let log = RejectLog::new_with(Arc::new(move |dump: &str| {
+            assert!(dump.starts_with("Rejected message (response code 40):\n"));
+            counted.fetch_add(1, Ordering::SeqCst);
+        }));
+        let (_, configs) = watch::channel(Arc::new(config));
+        let state = Arc::new(Mutex::new(State::new()));
+        let (lanes, recorder) = (Lanes::default(), Recorder::default());
+        Server::process_watched(
+            &mut stream,
+            state,
+            configs,
+            lanes,
+            recorder,
+            log.connection(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(dumps.load(Ordering::SeqCst), 10);
+        let stats = log.stats();
+        assert_eq!((stats.rejected, stats.dumped), (1000, 10));
+        assert_eq!((stats.throttled, stats.sampled_out), (990, 0));
+        assert_eq!(stats.bytes_dumped, 640);
+        assert_eq!(stats.bytes_truncated, 10 * (request.len() - 64));
+    }
+
+    #[tokio::test]
+    async fn test_partial_write_accounting() {
+        let (result, state) = process(vec![ping()], 5).await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::BrokenPipe);
+        assert_eq!(read_sent(&state), (8, 5));
+        assert_eq!(state.failed_writes(), 1);
+
+        // a request answered in full before the failing one
+        let (result, state) = process(vec![ping(), ping()], 13).await;
+        assert!(result.is_err());
+        assert_eq!(read_sent(&state), (16, 13));
+        assert_eq!(state.failed_writes(), 1);
+
+        let (result, state) = process(vec![ping()], 8).await;
+        assert!(result.is_ok());
+        assert_eq!(read_sent(&state), (8, 8));
+        assert_eq!(state.failed_writes(), 0);
+    }
+
+    /// Keeps a copy of the whole buffer each read is made into, as the
+    /// connection left it
+    struct SpyStream {
+        reads: Vec<Vec<u8>>,
+        buffers: Vec<Vec<u8>>,
+    }
+
+    impl AsyncRead for SpyStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let buffer = buf.initialized().to_vec();
+            self.buffers.push(buffer);
+            if !self.reads.is_empty() {
+                let read = self.reads.remove(0);
+                buf.put_slice(&read);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for SpyStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_zeroize_buffers() {
+        let len = message::MAX_PAYLOAD as usize;
+        let payload: Vec<u8> = (0..len).map(|i| b'a' + (i % 26) as u8).collect();
+        let mut compress = Header::request(Request::Compress, len as u16)
+            .unwrap()
+            .as_bytes()
+            .to_vec();
+        compress.extend_from_slice(&payload);
+
+        for zeroize_buffers in [false, true] {
+            let config = ServerConfig {
+                zeroize_buffers,
+                ..Default::default()
+            };
+            let mut stream = SpyStream {
+                reads: vec![compress.clone(), ping()],
+                buffers: Vec::new(),
+            };
+            let state = Arc::new(Mutex::new(State::new()));
+            Server::process(&mut stream, state, Arc::new(config))
+                .await
+                .unwrap();
+            // the buffer the ping is read into, after the compress used it
+            let reused = &stream.buffers[1];
+            let lingering = reused.windows(len).any(|window| window == payload);
+            assert_eq!(lingering, !zeroize_buffers);
+            if zeroize_buffers {
+                assert!(stream.buffers.iter().flatten().all(|&byte| byte == 0));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_discarded_accounting() {
+        // read whole, answered with MessageTooLarge without waiting on more
+        let over = vec![0u8; message::MAX_MESSAGE + 1];
+        let (result, state) = process(vec![over.clone()], 8).await;
+        assert!(result.is_ok());
+        assert_eq!(read_sent(&state), (over.len() as u32, 8));
+        assert_eq!(state.bytes_discarded(), over.len());
+
+        // filling the buffer, the rest its header declares is drained and
+        // answered with a single MessageTooLarge
+        let declared = message::total_response_len(message::MAX_PAYLOAD as usize * 2);
+        let mut oversized = vec![b'a'; declared];
+        let header = Header::raw(
+            message::MAGIC,
+            message::MAX_PAYLOAD * 2,
+            Request::Compress as u16,
+        );
+        oversized[..message::HEADER_SIZE].copy_from_slice(header.as_bytes());
+        let (first, rest) = oversized.split_at(message::MAX_MESSAGE_PADDED);
+        let (result, state) = process(vec![first.to_vec(), rest.to_vec()], 8).await;
+        assert!(result.is_ok());
+        assert_eq!(read_sent(&state), (declared as u32, 8));
+        assert_eq!(state.bytes_discarded(), declared);
+
+        // without a header its end isn't known, the rest is another request
+        // rather than a reason to drop the client
+        let unframed = vec![0u8; message::MAX_MESSAGE_PADDED];
+        let (result, state) = process(vec![unframed.clone(), vec![0u8; 10]], 16).await;
+        assert!(result.is_ok());
+        assert_eq!(read_sent(&state), (unframed.len() as u32 + 10, 16));
+        assert_eq!(state.bytes_discarded(), unframed.len());
+    }
+
+    #[tokio::test]
+    async fn test_oversize_drain_keeps_framing() {
+        // an oversized Compress written along with a Ping, the Ping isn't
+        // taken for more of the Compress
+        let declared = message::total_response_len(message::MAX_PAYLOAD as usize * 3 + 5);
+        let mut bytes = vec![b'a'; declared];
+        let header = Header::raw(
+            message::MAGIC,
+            message::MAX_PAYLOAD * 3 + 5,
+            Request::Compress as u16,
+        );
+        bytes[..message::HEADER_SIZE].copy_from_slice(header.as_bytes());
+        bytes.extend(ping());
+
+        let too_large = Header::response(Response::MessageTooLarge, 0).unwrap();
+        let replay = Script::new()
+            .write(bytes)
+            .expect_response(too_large.as_bytes())
+            .expect_response([83, 84, 82, 89, 0, 0, 0, 0])
+            .run()
+            .await
+            .unwrap();
+        replay.served.unwrap();
+        assert_eq!(replay.state.bytes_discarded(), declared);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_split_requests() {
+        // a request in pieces past its header is answered once whole, and so
+        // is the next request whole
+        let compress = [
+            Header::request(Request::Compress, 3).unwrap().as_bytes(),
+            b"aaa",
+        ]
+        .concat();
+        let compressed = [83, 84, 82, 89, 0, 2, 0, 0, b'3', b'a'];
+        let replay = Script::new()
+            .write_partial(compress.clone(), &[9])
+            .expect_response(compressed)
+            .write_partial(compress.clone(), &[8, 10])
+            .expect_response(compressed)
+            .write(compress)
+            .expect_response(compressed)
+            .run()
+            .await
+            .unwrap();
+        replay.served.unwrap();
+        assert_eq!(replay.state.requests(&Request::Compress), 3);
+        assert_eq!(replay.state.errors(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pipelined_large_requests() {
+        // messages written at once are more than a read holds, each is
+        // answered whole wherever the reads end
+        let payload = vec![b'a'; 5000];
+        let compress = [
+            Header::request(Request::Compress, payload.len() as u16)
+                .unwrap()
+                .as_bytes(),
+            &payload,
+        ]
+        .concat();
+        let compressed = [83, 84, 82, 89, 0, 5, 0, 0, b'5', b'0', b'0', b'0', b'a'];
+        let replay = Script::new()
+            .write([&compress[..], &compress, &compress].concat())
+            .expect_response(compressed)
+            .expect_response(compressed)
+            .expect_response(compressed)
+            .write(ping())
+            .expect_code(Response::Ok)
+            .run()
+            .await
+            .unwrap();
+        replay.served.unwrap();
+        assert_eq!(replay.state.requests(&Request::Compress), 3);
+        assert_eq!(replay.state.errors(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_undersized_answered_at_once() {
+        // a read shorter than a header is a request of its own, answered
+        // without waiting for the rest of the header, whether the client
+        // keeps the connection open or closes it
+        let config = ServerConfig {
+            idle_timeout: Some(Duration::from_secs(300)),
+            ..Default::default()
+        };
+        let started = time::Instant::now();
+        let replay = Script::new_with(config)
+            .write(&ping()[..5])
+            .expect_code(Response::MessageTooSmall)
+            .write(&ping()[..7])
+            .expect_code(Response::MessageTooSmall)
+            .run()
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        replay.served.unwrap();
+        assert_eq!(replay.state.bytes_discarded(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_trailing_bytes_framed() {
+        let compress = [
+            Header::request(Request::Compress, 3).unwrap().as_bytes(),
+            b"aaa",
+        ]
+        .concat();
+        let compressed = [83, 84, 82, 89, 0, 2, 0, 0, b'3', b'a'];
+        let bad_magic = Header::raw(message::MAGIC + 1, 0, Request::Ping as u16);
+
+        // permissive, junk past a message is skipped, a message cut short is
+        // answered once the rest of it is read and junk ahead of one is a
+        // bad magic
+        let replay = Script::new()
+            .write([&compress[..], b"junk"].concat())
+            .expect_response(compressed)
+            .write([&compress[..], &compress[..9]].concat())
+            .expect_response(compressed)
+            .write(&compress[9..])
+            .expect_response(compressed)
+            .write([bad_magic.as_bytes(), &compress].concat())
+            .expect_code(Response::MessageHeaderHasBadMagic)
+            .expect_response(compressed)
+            .write([&compress[..], b"xx", &ping(), b"yy"].concat())
+            .expect_response(compressed)
+            .expect_code(Response::Ok)
+            .write(ping())
+            .expect_code(Response::Ok)
+            .run()
+            .await
+            .unwrap();
+        replay.served.unwrap();
+        // nothing is sent for the junk, only counted
+        assert_eq!(replay.state.bytes_discarded(), 8);
+        assert_eq!(replay.state.requests(&Request::Ping), 2);
+
+        // strict, junk past a message is answered with the message whole
+        let config = ServerConfig {
+            enforcement: Enforcement::Strict,
+            ..Default::default()
+        };
+        let replay = Script::new_with(config)
+            .write([&compress[..], b"junk"].concat())
+            .expect_code(Response::TrailingBytes)
+            .write([&compress[..], &compress[..9]].concat())
+            .expect_response(compressed)
+            .write(&compress[9..])
+            .expect_response(compressed)
+            .write([bad_magic.as_bytes(), &compress].concat())
+            .expect_code(Response::MessageHeaderHasBadMagic)
+            .expect_response(compressed)
+            .run()
+            .await
+            .unwrap();
+        replay.served.unwrap();
+        assert_eq!(replay.state.bytes_discarded(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hello() {
+        let config = ServerConfig {
+            hello: true,
+            idle_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        let limits = config.limits();
+        let size = limits.as_bytes().len() as u16;
+        let hello = [
+            Header::response(Response::Hello, size).unwrap().as_bytes(),
+            limits.as_bytes(),
+        ]
+        .concat();
+        let ok = Header::response(Response::Ok, 0)
+            .unwrap()
+            .as_bytes()
+            .to_vec();
+
+        // greeted before any request, the bytes count as sent
+        let replay = Script::new_with(config.clone())
+            .expect_response(hello.clone())
+            .write(ping())
+            .expect_response(ok.clone())
+            .run()
+            .await
+            .unwrap();
+        replay.served.unwrap();
+        let stats = replay.state.snapshot().stats;
+        assert_eq!((stats.read(), stats.sent()), (8, 8 + hello.len() as u32));
+
+        // a client that doesn't wait for the greeting gets it all the same,
+        // ahead of the response to its first request
+        let replay = Script::new_with(config)
+            .write(ping())
+            .expect_response(hello)
+            .expect_response(ok.clone())
+            .run()
+            .await
+            .unwrap();
+        replay.served.unwrap();
+
+        // off by default, nothing is sent before the first response
+        let replay = Script::new()
+            .write(ping())
+            .expect_response(ok)
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(replay.transcript.len(), 1);
+        assert_eq!(replay.state.snapshot().stats.sent(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_request_cap() {
+        let config = ServerConfig {
+            max_requests_per_connection: 3,
+            ..Default::default()
+        };
+        let mut stream = MockStream {
+            reads: vec![ping(); 5],
+            accept: 64,
+            written: Vec::new(),
+        };
+        let state = Arc::new(Mutex::new(State::new()));
+        Server::process(&mut stream, Arc::clone(&state), Arc::new(config))
+            .await
+            .unwrap();
+        // three responses, then the Goodbye, the other pings are never read
+        let ok = Header::response(Response::Ok, 0).unwrap();
+        assert_eq!(stream.written[..24], [ok.as_bytes(); 3].concat());
+        assert_eq!(
+            goodbye_reason(&stream.written[24..]),
+            Some(GoodbyeReason::Rotated)
+        );
+        assert_eq!(stream.reads.len(), 2);
+        let snapshot = state.lock().await.snapshot();
+        assert_eq!((snapshot.rotations, snapshot.errors), (1, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connection_lifetime() {
+        let config = ServerConfig {
+            max_connection_lifetime: Some(Duration::from_secs(60)),
+            idle_timeout: Some(Duration::from_secs(300)),
+            ..Default::default()
+        };
+        let (mut client, server) = tokio::io::duplex(64);
+        let state = Arc::new(Mutex::new(State::new()));
+        let serving = tokio::spawn(Server::process(
+            server,
+            Arc::clone(&state),
+            Arc::new(config),
+        ));
+        let opened = time::Instant::now();
+        client.write_all(&ping()).await.unwrap();
+        let mut response = [0u8; message::HEADER_SIZE];
+        client.read_exact(&mut response).await.unwrap();
+
+        // idle past the lifetime, though not the idle timeout
+        let mut goodbye = Vec::new();
+        client.read_to_end(&mut goodbye).await.unwrap();
+        assert_eq!(opened.elapsed(), Duration::from_secs(60));
+        assert_eq!(goodbye_reason(&goodbye), Some(GoodbyeReason::Rotated));
+        serving.await.unwrap().unwrap();
+        assert_eq!(state.lock().await.rotations(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_half_close() {
+        let request = |request: Request, payload: &[u8]| {
+            let header = Header::request(request, payload.len() as u16).unwrap();
+            [header.as_bytes(), payload].concat()
+        };
+        let ok = |payload: &[u8]| {
+            let header = Header::response(Response::Ok, payload.len() as u16).unwrap();
+            [header.as_bytes(), payload].concat()
+        };
+        let state = Arc::new(Mutex::new(State::new()));
+        let config = Arc::new(ServerConfig::default());
+
+        // three pipelined requests, then the client is done sending
+        let (mut client, server) = tokio::io::duplex(1024);
+        let serving = tokio::spawn(Server::process(
+            server,
+            Arc::clone(&state),
+            Arc::clone(&config),
+        ));
+        let requests =
+            [&b"aaa"[..], b"bbbb", b"abc"].map(|payload| request(Request::Compress, payload));
+        client.write_all(&requests.concat()).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut responses = Vec::new();
+        client.read_to_end(&mut responses).await.unwrap();
+        assert_eq!(responses, [ok(b"3a"), ok(b"4b"), ok(b"abc")].concat());
+        serving.await.unwrap().unwrap();
+
+        // the end of the stream arrives while a response is partway written,
+        // the rest of it and the responses after it are still written
+        let (mut client, server) = tokio::io::duplex(256);
+        let serving = tokio::spawn(Server::process(
+            server,
+            Arc::clone(&state),
+            Arc::clone(&config),
+        ));
+        let decompress = request(Request::Decompress, b"1000a");
+        client
+            .write_all(&[&decompress[..]; 3].concat())
+            .await
+            .unwrap();
+        let mut responses = vec![0u8; 64];
+        client.read_exact(&mut responses).await.unwrap();
+        client.shutdown().await.unwrap();
+        client.read_to_end(&mut responses).await.unwrap();
+        assert_eq!(responses, ok(&[b'a'; 1000]).repeat(3));
+        serving.await.unwrap().unwrap();
+        let snapshot = state.lock().await.snapshot();
+        assert_eq!((snapshot.errors, snapshot.failed_writes), (0, 0));
+    }
+
+    /// `RlePrefix`, only sleeping for `0` before it compresses
+    struct Sleepy(Duration);
+
+    impl CompressionScheme for Sleepy {
+        fn compress(&self, rx: &[u8], tx: &mut [u8]) -> std::result::Result<usize, CompressError> {
+            std::thread::sleep(self.0);
+            RlePrefix::default().compress(rx, tx)
+        }
+
+        fn decompress(
+            &self,
+            rx: &[u8],
+            tx: &mut [u8],
+        ) -> std::result::Result<usize, DecompressError> {
+            RlePrefix::default().decompress(rx, tx)
+        }
+
+        fn bound(&self, input_len: usize) -> usize {
+            RlePrefix::default().bound(input_len)
+        }
+
+        fn validate_payload(&self, payload: &[u8]) -> Response {
+            RlePrefix::default().validate_payload(payload)
+        }
+
+        fn name(&self) -> &'static str {
+            "sleepy"
+        }
+    }
+
+    async fn exchange(
+        client: &mut tokio::io::DuplexStream,
+        request: Request,
+        payload: &[u8],
+    ) -> (u8, Vec<u8>) {
+        let header = Header::request(request, payload.len() as u16).unwrap();
+        client
+            .write_all(&[header.as_bytes(), payload].concat())
+            .await
+            .unwrap();
+        let mut header = [0u8; message::HEADER_SIZE];
+        client.read_exact(&mut header).await.unwrap();
+        let mut payload = vec![0u8; u16::from_be_bytes([header[4], header[5]]) as usize];
+        client.read_exact(&mut payload).await.unwrap();
+        (header[7], payload)
+    }
+
+    #[tokio::test]
+    async fn test_request_deadline() {
+        let serve = |config: ServerConfig| {
+            let (client, server) = tokio::io::duplex(1024);
+            let state = Arc::new(Mutex::new(State::new()));
+            let serving = tokio::spawn(Server::process(
+                server,
+                Arc::clone(&state),
+                Arc::new(config),
+            ));
+            (client, state, serving)
+        };
+
+        // a custom scheme that hangs is cut short, the connection goes on
+        let (mut client, state, serving) = serve(ServerConfig {
+            scheme: Some(Arc::new(Sleepy(Duration::from_millis(500)))),
+            request_deadline: Some(Duration::from_millis(50)),
+            ..Default::default()
+        });
+        let answered = exchange(&mut client, Request::Compress, b"aaa").await;
+        assert_eq!(answered, (Response::UnknownError as u8, vec![]));
+        let answered = exchange(&mut client, Request::Ping, b"").await;
+        assert_eq!(answered, (Response::Ok as u8, vec![]));
+        drop(client);
+        serving.await.unwrap().unwrap();
+        let snapshot = state.lock().await.snapshot();
+        assert_eq!((snapshot.slow_requests, snapshot.errors), (1, 1));
+        assert_eq!(snapshot.requests(&Request::Compress), 0);
+
+        // one done within the deadline is answered as usual
+        let (mut client, state, serving) = serve(ServerConfig {
+            scheme: Some(Arc::new(Sleepy(Duration::ZERO))),
+            ..Default::default()
+        });
+        let answered = exchange(&mut client, Request::Compress, b"aaa").await;
+        assert_eq!(answered, (Response::Ok as u8, b"3a".to_vec()));
+        drop(client);
+        serving.await.unwrap().unwrap();
+        let snapshot = state.lock().await.snapshot();
+        assert_eq!((snapshot.slow_requests, snapshot.errors), (0, 0));
+        assert_eq!(snapshot.requests(&Request::Compress), 1);
+
+        // the built-in handlers run to the end, past the deadline they are
+        // only counted
+        let (mut client, state, serving) = serve(ServerConfig {
+            request_deadline: Some(Duration::ZERO),
+            ..Default::default()
+        });
+        let answered = exchange(&mut client, Request::Compress, b"aaa").await;
+        assert_eq!(answered, (Response::Ok as u8, b"3a".to_vec()));
+        drop(client);
+        serving.await.unwrap().unwrap();
+        assert_eq!(state.lock().await.slow_requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_say_goodbye() {
+        let mut stream = MockStream {
+            reads: Vec::new(),
+            accept: 64,
+            written: Vec::new(),
+        };
+        let state = Mutex::new(State::new());
+        let goodbye = Goodbye::new_with(Stats::new_with(8, 8, 0), 1, GoodbyeReason::Shutdown);
+        Server::say_goodbye(&mut stream, &state, goodbye).await;
+        assert_eq!(
+            stream.written,
+            [
+                83u8, 84, 82, 89, 0, 14, 0, 48, //
+                0, 0, 0, 8, 0, 0, 0, 8, 0, 0, 0, 0, 1, 3
+            ]
+        );
+        assert_eq!(read_sent(&*state.lock().await), (0, 22));
+
+        // nothing is written after a failed write
+        let (result, state) = process(vec![ping()], 5).await;
+        assert!(result.is_err());
+        assert_eq!(read_sent(&state), (8, 5));
+    }
+
+    #[test]
+    fn test_serve_on_shutdown_timeout() {
+        use std::time::Instant;
+        use tokio::{net::TcpStream, sync::oneshot};
+
+        let grace = Duration::from_millis(200);
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let handle = runtime.handle().clone();
+        let started = runtime.block_on(async {
+            let mut server = Server::new_with_url("127.0.0.1:0").await.unwrap();
+            let addr = server.listener.local_addr().unwrap();
+            let (stop, stopped) = oneshot::channel();
+            let shutdown = async {
+                stopped.await.unwrap();
+            };
+            let serving = async { server.serve_on(&handle, shutdown, grace).await };
+            let client = async {
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                stream.write_all(&ping()).await.unwrap();
+                let mut response = [0u8; message::HEADER_SIZE];
+                stream.read_exact(&mut response).await.unwrap();
+                assert_eq!(response, [83, 84, 82, 89, 0, 0, 0, 0]);
+                // stays connected and idle past the shutdown
+                stop.send(()).unwrap();
+                (stream, Instant::now())
+            };
+            let (served, (_idle, started)) = tokio::join!(serving, client);
+            served.unwrap();
+            started
+        });
+        runtime.shutdown_timeout(grace);
+        assert!(started.elapsed() < 3 * grace, "{:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn test_serve_on_health_draining() {
+        use tokio::{net::TcpStream, sync::oneshot};
+
+        async fn health(stream: &mut TcpStream) -> Vec<u8> {
+            let request = Header::request(Request::Health, 0).unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut header = [0u8; message::HEADER_SIZE];
+            stream.read_exact(&mut header).await.unwrap();
+            let mut payload = vec![0u8; u16::from_be_bytes([header[4], header[5]]) as usize];
+            stream.read_exact(&mut payload).await.unwrap();
+            payload
+        }
+
+        let mut server = Server::new_with_url("127.0.0.1:0").await.unwrap();
+        let addr = server.listener.local_addr().unwrap();
+        let (stop, stopped) = oneshot::channel();
+        let shutdown = async {
+            stopped.await.unwrap();
+        };
+        let handle = Handle::current();
+        let serving = server.serve_on(&handle, shutdown, Duration::from_secs(5));
+        let client = async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            assert_eq!(health(&mut stream).await, [HealthStatus::Ready as u8]);
+            stop.send(()).unwrap();
+            // lets the accept loop see the shutdown
+            tokio::task::yield_now().await;
+            // the connection still open is served, as draining
+            let draining = health(&mut stream).await;
+            assert_eq!(draining[0], HealthStatus::Draining as u8);
+            assert_eq!(&draining[1..], b"shutting down");
+        };
+        let (served, ()) = tokio::join!(serving, client);
+        served.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_limit_waits() {
+        use tokio::net::TcpStream;
+
+        let config = ServerConfig {
+            max_connections: 1,
+            ..Default::default()
+        };
+        let server = Server::new_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap();
+        let handle = server.spawn();
+        let accepts = handle.stats().accepts().await;
+        let mut response = [0u8; message::HEADER_SIZE];
+
+        let mut first = TcpStream::connect(handle.local_addr()).await.unwrap();
+        first.write_all(&ping()).await.unwrap();
+        first.read_exact(&mut response).await.unwrap();
+        // the only connection allowed is open, the accept loop waits
+        let waited = time::timeout(Duration::from_secs(5), async {
+            while accepts.limit_waits() == 0 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        waited.await.unwrap();
+        let mut second = TcpStream::connect(handle.local_addr()).await.unwrap();
+        second.write_all(&ping()).await.unwrap();
+        let held = time::timeout(Duration::from_millis(200), second.read_exact(&mut response));
+        assert!(held.await.is_err());
+
+        // served once the first closes
+        drop(first);
+        second.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [83, 84, 82, 89, 0, 0, 0, 0]);
+        let snapshot = handle.stats().snapshot().await;
+        assert!(snapshot.limit_waits >= 1);
+        assert_eq!(snapshot.accept_errors, [0; ACCEPT_ERROR_CLASSES]);
+    }
+
+    /// Panics compressing anything
+    struct Panicky;
+
+    impl CompressionScheme for Panicky {
+        fn compress(&self, _: &[u8], _: &mut [u8]) -> std::result::Result<usize, CompressError> {
+            panic!("compressing")
+        }
+
+        fn decompress(
+            &self,
+            rx: &[u8],
+            tx: &mut [u8],
+        ) -> std::result::Result<usize, DecompressError> {
+            RlePrefix::default().decompress(rx, tx)
+        }
+
+        fn bound(&self, input_len: usize) -> usize {
+            RlePrefix::default().bound(input_len)
+        }
+
+        fn validate_payload(&self, payload: &[u8]) -> Response {
+            RlePrefix::default().validate_payload(payload)
+        }
+
+        fn name(&self) -> &'static str {
+            "panicky"
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_gauge_panics() {
+        use tokio::net::TcpStream;
+
+        let config = ServerConfig {
+            max_connections: 1,
+            scheme: Some(Arc::new(Panicky)),
+            ..Default::default()
+        };
+        let server = Server::new_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap();
+        let handle = server.spawn();
+        let gauge = handle.stats().connections().await;
+        let header = Header::request(Request::Compress, 3).unwrap();
+        let compress = [header.as_bytes(), b"aaa"].concat();
+
+        // each task panics, the next connection is only accepted once the
+        // permit of the one before is back
+        let panicking = async {
+            for _ in 0..3 {
+                let mut stream = TcpStream::connect(handle.local_addr()).await.unwrap();
+                stream.write_all(&compress).await.unwrap();
+                let mut response = Vec::new();
+                let _ = stream.read_to_end(&mut response).await;
+                assert!(response.is_empty());
+            }
+        };
+        time::timeout(Duration::from_secs(5), panicking)
+            .await
+            .unwrap();
+        let closed = time::timeout(Duration::from_secs(5), async {
+            while gauge.active() > 0 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        closed.await.unwrap();
+        assert_eq!(gauge.accepted(), 3);
+
+        // the limiter recovered its permit
+        let mut stream = TcpStream::connect(handle.local_addr()).await.unwrap();
+        stream.write_all(&ping()).await.unwrap();
+        let mut response = [0u8; message::HEADER_SIZE];
+        let read = stream.read_exact(&mut response);
+        time::timeout(Duration::from_secs(5), read)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response, [83, 84, 82, 89, 0, 0, 0, 0]);
+        let snapshot = handle.stats().snapshot().await;
+        assert_eq!(
+            (snapshot.connections, snapshot.accepted_connections),
+            (1, 4)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_peaks() {
+        use tokio::net::TcpStream;
+
+        let server = Server::new_with_config("127.0.0.1:0", ServerConfig::default())
+            .await
+            .unwrap();
+        let handle = server.spawn();
+        let mut response = [0u8; message::HEADER_SIZE];
+        let mut connections = Vec::new();
+        for _ in 0..3 {
+            let mut stream = TcpStream::connect(handle.local_addr()).await.unwrap();
+            stream.write_all(&ping()).await.unwrap();
+            stream.read_exact(&mut response).await.unwrap();
+            connections.push(stream);
+        }
+        // down to 1, the peak stays
+        connections.truncate(1);
+        let mut stream = connections.pop().unwrap();
+        let payload = vec![b'a'; 8192];
+        let header = Header::request(Request::Compress, payload.len() as u16).unwrap();
+        stream
+            .write_all(&[header.as_bytes(), &payload].concat())
+            .await
+            .unwrap();
+        stream.read_exact(&mut response).await.unwrap();
+        let compressed = u16::from_be_bytes([response[4], response[5]]) as usize;
+        let mut body = vec![0u8; compressed];
+        stream.read_exact(&mut body).await.unwrap();
+        let snapshot = handle.stats().snapshot().await;
+        assert_eq!(snapshot.peak_connections, 3);
+        assert_eq!(snapshot.peak_heavy, 1);
+        assert_eq!(snapshot.largest_request, 8192);
+        assert_eq!(snapshot.largest_response, compressed);
+    }
+
+    #[tokio::test]
+    async fn test_bind_with_retry() {
+        let held = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = held.local_addr().unwrap().to_string();
+
+        // exhausted, the address stays in use
+        let err = Server::bind_with_retry(&addr, 2, Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+
+        // released while retrying
+        let release = tokio::spawn(async move {
+            time::sleep(Duration::from_millis(100)).await;
+            drop(held);
+        });
+        let listener = Server::bind_with_retry(&addr, 10, Duration::from_millis(20))
+            .await
+            .unwrap();
+        assert_eq!(listener.local_addr().unwrap().to_string(), addr);
+        release.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bind_fails_fast() {
+        let started = std::time::Instant::now();
+        for url in ["256.0.0.1:4000", "127.0.0.1", "192.0.2.1:4000"] {
+            assert!(Server::bind_with_retry(url, 5, Duration::from_secs(1))
+                .await
+                .is_err());
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
     }
 }
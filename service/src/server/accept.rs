@@ -0,0 +1,231 @@
+use std::{
+    fmt, io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Classes of errors of `accept()` counted by `AcceptStats`
+pub const ACCEPT_ERROR_CLASSES: usize = 4;
+
+/// Why an `accept()` failed, by the class of its errno
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptError {
+    /// The peer gave up on the connection before it was accepted,
+    /// ECONNABORTED or ECONNRESET
+    Aborted = 0,
+    /// The process or the system ran out of file descriptors, EMFILE or
+    /// ENFILE, the service is accepting more than it is sized for
+    Descriptors = 1,
+    /// The kernel ran out of memory or buffers, ENOMEM or ENOBUFS
+    Memory = 2,
+    Other = 3,
+}
+
+impl AcceptError {
+    pub fn of(error: &io::Error) -> AcceptError {
+        match error.kind() {
+            io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset => {
+                return AcceptError::Aborted
+            }
+            io::ErrorKind::OutOfMemory => return AcceptError::Memory,
+            _ => (),
+        }
+        // the same numbers on Linux, the BSDs and macOS, which std has no
+        // stable kind for
+        match error.raw_os_error() {
+            Some(23) | Some(24) => AcceptError::Descriptors,
+            #[cfg(target_os = "linux")]
+            Some(105) => AcceptError::Memory,
+            _ => AcceptError::Other,
+        }
+    }
+
+    /// The key of the class in `StatsSnapshot::to_json`
+    pub fn name(&self) -> &'static str {
+        match self {
+            AcceptError::Aborted => "aborted",
+            AcceptError::Descriptors => "descriptors",
+            AcceptError::Memory => "memory",
+            AcceptError::Other => "other",
+        }
+    }
+
+    pub fn all() -> [AcceptError; ACCEPT_ERROR_CLASSES] {
+        [
+            AcceptError::Aborted,
+            AcceptError::Descriptors,
+            AcceptError::Memory,
+            AcceptError::Other,
+        ]
+    }
+}
+
+/// What happened in the accept loops of a `Server` short of a connection
+/// being handled, its listener's and its debug port's. Counted without
+/// taking the lock of the `State` it belongs to, since an accept loop failing
+/// to accept is the worst time to queue behind the connections
+///
+/// Shared by clones of the state, zeroed by `reset` like the other counters,
+/// and states equal but for it are equal
+#[derive(Default, Clone)]
+pub struct AcceptStats(Arc<Counters>);
+
+#[derive(Default)]
+struct Counters {
+    errors: [AtomicUsize; ACCEPT_ERROR_CLASSES],
+    limit_waits: AtomicUsize,
+    /// Connections accepted by each listener, by label in registration order
+    listeners: Mutex<Vec<(String, ListenerAccepts)>>,
+}
+
+/// The connections accepted by one listener of the servers sharing an
+/// `AcceptStats`, see `AcceptStats::listener`
+#[derive(Debug, Default, Clone)]
+pub struct ListenerAccepts(Arc<AtomicUsize>);
+
+impl ListenerAccepts {
+    pub fn update_accepted(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn accepted(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl AcceptStats {
+    /// Counts `error` by its class, returned
+    pub fn update_error(&self, error: &io::Error) -> AcceptError {
+        let class = AcceptError::of(error);
+        self.0.errors[class as usize].fetch_add(1, Ordering::Relaxed);
+        class
+    }
+
+    /// Counts the accept loop stopping at `ServerConfig::max_connections`,
+    /// the connections arriving until one closes wait in the listen backlog
+    pub fn update_limit_wait(&self) {
+        self.0.limit_waits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `accept()` errors, by `AcceptError` class
+    pub fn errors(&self) -> [usize; ACCEPT_ERROR_CLASSES] {
+        let mut errors = [0; ACCEPT_ERROR_CLASSES];
+        for (count, counter) in errors.iter_mut().zip(&self.0.errors) {
+            *count = counter.load(Ordering::Relaxed);
+        }
+        errors
+    }
+
+    pub fn limit_waits(&self) -> usize {
+        self.0.limit_waits.load(Ordering::Relaxed)
+    }
+
+    /// The counter of the listener labelled `label`, registered on first
+    /// use. The lock is only taken then, never to count a connection, so
+    /// the servers of a `ServerGroup` are told apart without contending
+    pub fn listener(&self, label: &str) -> ListenerAccepts {
+        let mut listeners = self.0.listeners.lock().unwrap();
+        match listeners.iter().find(|(name, _)| name == label) {
+            Some((_, accepts)) => accepts.clone(),
+            None => {
+                let accepts = ListenerAccepts::default();
+                listeners.push((label.to_string(), accepts.clone()));
+                accepts
+            }
+        }
+    }
+
+    /// Connections accepted by label of listener, in registration order
+    pub fn listeners(&self) -> Vec<(String, usize)> {
+        let listeners = self.0.listeners.lock().unwrap();
+        listeners
+            .iter()
+            .map(|(label, accepts)| (label.clone(), accepts.accepted()))
+            .collect()
+    }
+
+    pub fn reset(&self) {
+        for counter in &self.0.errors {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.0.limit_waits.store(0, Ordering::Relaxed);
+        for (_, accepts) in self.0.listeners.lock().unwrap().iter() {
+            accepts.0.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl fmt::Debug for AcceptStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AcceptStats")
+            .field("errors", &self.errors())
+            .field("limit_waits", &self.limit_waits())
+            .field("listeners", &self.listeners())
+            .finish()
+    }
+}
+
+impl PartialEq for AcceptStats {
+    fn eq(&self, _: &AcceptStats) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_error() {
+        let accepts = AcceptStats::default();
+        let shared = accepts.clone();
+        let errors = [
+            io::Error::from(io::ErrorKind::ConnectionAborted),
+            io::Error::from(io::ErrorKind::ConnectionReset),
+            io::Error::from_raw_os_error(24),
+            io::Error::from_raw_os_error(23),
+            io::Error::from(io::ErrorKind::OutOfMemory),
+            io::Error::from(io::ErrorKind::PermissionDenied),
+        ];
+        let classes: Vec<_> = errors.iter().map(|e| shared.update_error(e)).collect();
+        assert_eq!(
+            classes,
+            [
+                AcceptError::Aborted,
+                AcceptError::Aborted,
+                AcceptError::Descriptors,
+                AcceptError::Descriptors,
+                AcceptError::Memory,
+                AcceptError::Other,
+            ]
+        );
+        shared.update_limit_wait();
+        assert_eq!(accepts.errors(), [2, 2, 1, 1]);
+        assert_eq!(accepts.limit_waits(), 1);
+
+        accepts.reset();
+        assert_eq!((shared.errors(), shared.limit_waits()), ([0; 4], 0));
+    }
+
+    #[test]
+    fn test_listeners() {
+        let accepts = AcceptStats::default();
+        let first = accepts.listener("127.0.0.1:4000");
+        let second = accepts.clone().listener("127.0.0.1:4001");
+        first.update_accepted();
+        second.update_accepted();
+        accepts.listener("127.0.0.1:4000").update_accepted();
+        assert_eq!(
+            accepts.listeners(),
+            [
+                ("127.0.0.1:4000".to_string(), 2),
+                ("127.0.0.1:4001".to_string(), 1)
+            ]
+        );
+        accepts.reset();
+        assert_eq!(first.accepted() + second.accepted(), 0);
+        assert_eq!(accepts.listeners().len(), 2);
+    }
+}
@@ -0,0 +1,225 @@
+use super::compress::{run_length, DecompressError};
+use std::cmp;
+
+/// Marker byte introducing an encoded run: `[BINARY_MARKER, count_lo, count_hi, byte]`
+pub const BINARY_MARKER: u8 = 0xFF;
+
+/// Size of an encoded run
+pub const BINARY_RUN_LEN: usize = 4;
+
+/// Shortest run of a literal byte worth encoding, shorter runs are copied as is
+pub const MIN_BINARY_RUN: usize = BINARY_RUN_LEN + 1;
+
+/// Worst-case expansion of `compress_binary`, `(n * 5 + 3) / 2` for an input of
+/// `n` bytes
+///
+/// A literal `BINARY_MARKER` is escaped as a run of one (4 bytes), so the worst
+/// input alternates single markers and other bytes ("\xFFa\xFFa...") and grows
+/// by 2.5x, every other input is at most that size
+pub fn max_binary_len(len: usize) -> usize {
+    (len * 5 + 3) / 2
+}
+
+/// The exact length `compress_binary` produces for `rx`, without producing it
+///
+/// # Example
+/// ```
+/// # use service::{binary_len, compress_binary};
+/// let mut tx = [0u8; 16];
+/// let len = compress_binary(b"aaaaaabc\xFF", &mut tx).unwrap();
+/// assert_eq!(binary_len(b"aaaaaabc\xFF"), len);
+/// ```
+pub fn binary_len(rx: &[u8]) -> usize {
+    let mut len = 0;
+    let mut rest = rx;
+    while !rest.is_empty() {
+        let byte = rest[0];
+        let count = run_length(rest, byte);
+        len += encoded_run_len(byte, count);
+        rest = &rest[count..];
+    }
+    len
+}
+
+fn encoded_run_len(byte: u8, count: usize) -> usize {
+    match (byte, count) {
+        (BINARY_MARKER, n) => n.div_ceil(u16::MAX as usize) * BINARY_RUN_LEN,
+        (_, n) if n < MIN_BINARY_RUN => n,
+        (_, n) => {
+            let full = n / u16::MAX as usize;
+            match n % u16::MAX as usize {
+                0 => full * BINARY_RUN_LEN,
+                r if r < MIN_BINARY_RUN => full * BINARY_RUN_LEN + r,
+                _ => (full + 1) * BINARY_RUN_LEN,
+            }
+        }
+    }
+}
+
+/// Binary run-length encoding of arbitrary bytes
+///
+/// Runs of `MIN_BINARY_RUN` or more bytes are written as `BINARY_MARKER`, the
+/// little-endian u16 count and the byte. Literal `BINARY_MARKER` bytes are
+/// always written as runs, every other byte is copied as is.
+/// Returns None if the output does not fit in tx, see `max_binary_len`
+///
+/// # Example
+/// ```
+/// # use service::compress_binary;
+/// let mut tx = [0u8; 16];
+/// let len = compress_binary(b"aaaaaaab\xFF", &mut tx).unwrap();
+/// assert_eq!(tx[..len], [0xFF, 7, 0, b'a', b'b', 0xFF, 1, 0, 0xFF]);
+/// ```
+pub fn compress_binary(rx: &[u8], tx: &mut [u8]) -> Option<usize> {
+    let mut len = 0;
+    let mut rest = rx;
+    while !rest.is_empty() {
+        let byte = rest[0];
+        let count = cmp::min(run_length(rest, byte), u16::MAX as usize);
+        if byte != BINARY_MARKER && count < MIN_BINARY_RUN {
+            tx.get_mut(len..len + count)?.fill(byte);
+            len += count;
+        } else {
+            let [lo, hi] = (count as u16).to_le_bytes();
+            tx.get_mut(len..len + BINARY_RUN_LEN)?
+                .copy_from_slice(&[BINARY_MARKER, lo, hi, byte]);
+            len += BINARY_RUN_LEN;
+        }
+        rest = &rest[count..];
+    }
+    Some(len)
+}
+
+/// Expands the output of `compress_binary` into tx
+///
+/// Stops with `DecompressError::OutputTooLarge` as soon as a run would not fit
+/// in tx. A truncated run or a run with a count of zero is malformed
+///
+/// # Example
+/// ```
+/// # use service::{decompress_binary, DecompressError};
+/// let mut tx = [0u8; 8];
+/// let len = decompress_binary(&[0xFF, 7, 0, b'a', b'b'], &mut tx).unwrap();
+/// assert_eq!(tx[..len], *b"aaaaaaab");
+/// let bomb = [0xFF, 0xFF, 0xFF, b'a'];
+/// assert_eq!(decompress_binary(&bomb, &mut tx), Err(DecompressError::OutputTooLarge));
+/// ```
+pub fn decompress_binary(rx: &[u8], tx: &mut [u8]) -> Result<usize, DecompressError> {
+    if rx.is_empty() {
+        return Err(DecompressError::EmptyInput);
+    }
+    let mut len = 0;
+    let mut rest = rx;
+    while let Some((&first, tail)) = rest.split_first() {
+        let (byte, count, tail) = match (first, tail) {
+            (BINARY_MARKER, [lo, hi, byte, tail @ ..]) => {
+                (*byte, u16::from_le_bytes([*lo, *hi]) as usize, tail)
+            }
+            (BINARY_MARKER, _) => return Err(DecompressError::Malformed),
+            (byte, tail) => (byte, 1, tail),
+        };
+        if count == 0 {
+            return Err(DecompressError::Malformed);
+        }
+        tx.get_mut(len..len + count)
+            .ok_or(DecompressError::OutputTooLarge)?
+            .fill(byte);
+        len += count;
+        rest = tail;
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{binary_len, compress_binary, decompress_binary, max_binary_len};
+    use crate::message::MAX_PAYLOAD;
+    use crate::DecompressError;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_compress_binary() {
+        let mut tx = [0u8; 32];
+        let cases: Vec<(&[u8], &[u8])> = vec![
+            (b"a", b"a"),
+            (b"aaaa", b"aaaa"),
+            (b"aaaaa", &[0xFF, 5, 0, b'a']),
+            (b"\xFF", &[0xFF, 1, 0, 0xFF]),
+            (b"\xFF\xFF\xFF", &[0xFF, 3, 0, 0xFF]),
+            (b"\x00\x00\x00\x00\x00\x00a", &[0xFF, 6, 0, 0, b'a']),
+        ];
+        for (rx, expected) in cases {
+            let len = compress_binary(rx, &mut tx).unwrap();
+            assert_eq!(&tx[..len], expected);
+            assert_eq!(binary_len(rx), len);
+        }
+        assert_eq!(compress_binary(b"\xFF", &mut tx[..3]), None);
+    }
+
+    #[test]
+    fn test_compress_binary_long_runs() {
+        let rx = vec![b'a'; u16::MAX as usize + 2];
+        let mut tx = [0u8; 16];
+        let len = compress_binary(&rx, &mut tx).unwrap();
+        assert_eq!(&tx[..len], &[0xFF, 0xFF, 0xFF, b'a', b'a', b'a']);
+        assert_eq!(binary_len(&rx), len);
+
+        let mut out = vec![0u8; rx.len()];
+        assert_eq!(decompress_binary(&tx[..len], &mut out), Ok(rx.len()));
+        assert_eq!(out, rx);
+    }
+
+    #[test]
+    fn test_decompress_binary_malformed() {
+        let mut tx = [0u8; MAX_PAYLOAD as usize];
+        let cases: Vec<(&[u8], DecompressError)> = vec![
+            (b"", DecompressError::EmptyInput),
+            (&[0xFF], DecompressError::Malformed),
+            (&[0xFF, 1, 0], DecompressError::Malformed),
+            (&[0xFF, 0, 0, b'a'], DecompressError::Malformed),
+            (&[0xFF, 0x01, 0x20, b'a'], DecompressError::OutputTooLarge),
+        ];
+        for (rx, expected) in cases {
+            assert_eq!(decompress_binary(rx, &mut tx), Err(expected));
+        }
+        // exactly MAX_PAYLOAD
+        assert_eq!(
+            decompress_binary(&[0xFF, 0x00, 0x20, b'a'], &mut tx),
+            Ok(MAX_PAYLOAD as usize)
+        );
+    }
+
+    #[test]
+    fn test_worst_case_expansion() {
+        let rx: Vec<u8> = b"\xFFa".iter().cycle().take(101).copied().collect();
+        assert_eq!(binary_len(&rx), max_binary_len(rx.len()));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_binary_round_trip(rx in proptest::collection::vec(any::<u8>(), 0..2048)) {
+            let mut tx = vec![0u8; max_binary_len(rx.len())];
+            let len = compress_binary(&rx, &mut tx).unwrap();
+            prop_assert_eq!(len, binary_len(&rx));
+            let mut out = vec![0u8; rx.len()];
+            match decompress_binary(&tx[..len], &mut out) {
+                Ok(n) => prop_assert_eq!(&out[..n], &rx[..]),
+                Err(e) => prop_assert!(rx.is_empty() && e == DecompressError::EmptyInput),
+            }
+        }
+
+        #[test]
+        fn prop_binary_runs_round_trip(
+            runs in proptest::collection::vec((any::<u8>(), 1usize..20), 0..100)
+        ) {
+            let rx: Vec<u8> = runs.iter().flat_map(|&(b, n)| vec![b; n]).collect();
+            let mut tx = vec![0u8; max_binary_len(rx.len())];
+            let len = compress_binary(&rx, &mut tx).unwrap();
+            let mut out = vec![0u8; rx.len()];
+            if !rx.is_empty() {
+                prop_assert_eq!(decompress_binary(&tx[..len], &mut out), Ok(rx.len()));
+                prop_assert_eq!(out, rx);
+            }
+        }
+    }
+}
@@ -0,0 +1,483 @@
+use super::config::{FlushPolicy, ServerConfig};
+use super::state::State;
+use std::{
+    cmp,
+    collections::VecDeque,
+    future::{self, Future},
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::watch,
+    time::{self, Instant, Sleep},
+};
+
+/// A stream whose writes are held back and written out together as its
+/// `FlushPolicy` has it, so that the responses to pipelined requests take
+/// fewer syscalls than one each
+///
+/// A write is accepted whole and is one message, the messages held back are
+/// written in a single write once due. Whatever the policy they are written
+/// once `max_buffered` bytes are held back, before any more is accepted, and
+/// when flushed or shut down. Under `AfterMessages` they're also written as
+/// soon as a read would wait for the peer, which may well be waiting for them
+///
+/// A write held back is accepted before it's written, what it answered is
+/// only accounted for once `Held` sees it written out
+pub(crate) struct Coalesced<S> {
+    stream: S,
+    policy: FlushPolicy,
+    max_buffered: usize,
+    buf: Vec<u8>,
+    /// Bytes of `buf` written out already
+    written: usize,
+    /// Messages in `buf`
+    held: usize,
+    /// Messages ever held back
+    accepted: usize,
+    /// Messages ever held back and written out since
+    flushed: watch::Sender<usize>,
+    /// Fires once the messages held back under `AfterIdle` are due
+    idle: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Coalesced<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub(crate) fn new(stream: S, config: &ServerConfig) -> Coalesced<S> {
+        let mut coalesced = Coalesced {
+            stream,
+            policy: FlushPolicy::Immediate,
+            max_buffered: 0,
+            buf: Vec::new(),
+            written: 0,
+            held: 0,
+            accepted: 0,
+            flushed: watch::channel(0).0,
+            idle: None,
+        };
+        coalesced.configure(config);
+        coalesced
+    }
+
+    /// Holds back the messages written from now on as `config` has it
+    pub(crate) fn configure(&mut self, config: &ServerConfig) {
+        self.policy = config.flush_policy;
+        self.max_buffered = config.max_buffered;
+    }
+
+    /// Messages ever held back, a write that leaves it as it was went
+    /// straight through
+    pub(crate) fn accepted(&self) -> usize {
+        self.accepted
+    }
+
+    /// Bytes of the messages held back that made it out, of a write failing
+    /// partway
+    pub(crate) fn held_written(&self) -> usize {
+        self.written
+    }
+
+    /// Accounting for the messages held back from now on
+    pub(crate) fn held(&self) -> Held {
+        Held {
+            pending: VecDeque::new(),
+            seen: *self.flushed.borrow(),
+            flushed: self.flushed.subscribe(),
+        }
+    }
+
+    /// Whether the messages held back are due to be written, `waiting` when
+    /// a read would wait for the peer
+    fn due(&mut self, cx: &mut Context<'_>, waiting: bool) -> bool {
+        if self.held == 0 {
+            return false;
+        }
+        let due = match self.policy {
+            FlushPolicy::Immediate => true,
+            FlushPolicy::AfterMessages(n) => waiting || self.held >= n,
+            FlushPolicy::AfterIdle(_) => match self.idle.as_mut() {
+                Some(idle) => idle.as_mut().poll(cx).is_ready(),
+                None => true,
+            },
+        };
+        due || self.buf.len() >= self.max_buffered
+    }
+
+    /// Writes out the messages held back
+    fn poll_write_held(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.buf.len() {
+            let held = &self.buf[self.written..];
+            match ready!(Pin::new(&mut self.stream).poll_write(cx, held))? {
+                0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                n => self.written += n,
+            }
+        }
+        let held = self.held;
+        self.flushed.send_modify(|flushed| *flushed += held);
+        self.buf.clear();
+        self.written = 0;
+        self.held = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The pending states of the responses a `Coalesced` stream held back, one
+/// per message in the order written, until the stream writes them out
+pub(crate) struct Held {
+    pending: VecDeque<State>,
+    flushed: watch::Receiver<usize>,
+    /// Messages written out of those `flushed` counts, as last seen
+    seen: usize,
+}
+
+impl Held {
+    /// Keeps `pending` until the message the stream just held back for it is
+    /// written out
+    pub(crate) fn hold(&mut self, pending: State) {
+        self.pending.push_back(pending);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// The states of the messages written out since last asked, in order. A
+    /// message held back without a state, a Goodbye written last, has
+    /// nothing to account for
+    pub(crate) fn written_out(&mut self) -> Vec<State> {
+        let flushed = *self.flushed.borrow_and_update();
+        let written = cmp::min(flushed - self.seen, self.pending.len());
+        self.seen = flushed;
+        self.pending.drain(..written).collect()
+    }
+
+    /// Waits for the stream to write out messages held back, returning their
+    /// states
+    pub(crate) async fn flushed(&mut self) -> Vec<State> {
+        if self.flushed.changed().await.is_err() {
+            future::pending::<()>().await;
+        }
+        self.written_out()
+    }
+
+    /// The states of the messages never written out
+    pub(crate) fn unsent(&mut self) -> Vec<State> {
+        self.pending.drain(..).collect()
+    }
+}
+
+impl<S> AsyncRead for Coalesced<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let read = Pin::new(&mut this.stream).poll_read(cx, buf);
+        if this.due(cx, read.is_pending()) {
+            if let Poll::Ready(Err(e)) = this.poll_write_held(cx) {
+                return Poll::Ready(Err(e));
+            }
+        }
+        read
+    }
+}
+
+impl<S> AsyncWrite for Coalesced<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.due(cx, false) || this.buf.len() + data.len() > this.max_buffered {
+            ready!(this.poll_write_held(cx))?;
+        }
+        if this.held == 0
+            && (this.policy == FlushPolicy::Immediate || data.len() >= this.max_buffered)
+        {
+            return Pin::new(&mut this.stream).poll_write(cx, data);
+        }
+        if let (0, FlushPolicy::AfterIdle(idle)) = (this.held, this.policy) {
+            let deadline = Instant::now() + idle;
+            match this.idle.as_mut() {
+                Some(sleep) => sleep.as_mut().reset(deadline),
+                None => this.idle = Some(Box::pin(time::sleep_until(deadline))),
+            }
+        }
+        this.buf.extend_from_slice(data);
+        this.held += 1;
+        this.accepted += 1;
+        if this.due(cx, false) {
+            if let Poll::Ready(Err(e)) = this.poll_write_held(cx) {
+                return Poll::Ready(Err(e));
+            }
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_held(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_held(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Header, Request, Response, HEADER_SIZE, MAGIC};
+    use crate::{Server, State};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::Mutex;
+    use zerocopy::AsBytes;
+
+    /// Counts the writes to the stream it wraps, each a syscall on a socket
+    struct CountingStream<S> {
+        stream: S,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_read(cx, buf)
+        }
+    }
+
+    impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            Pin::new(&mut self.stream).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_shutdown(cx)
+        }
+    }
+
+    /// A stream held back under `policy` and `max_buffered`, its peer and its
+    /// count of writes
+    fn coalesced(
+        policy: FlushPolicy,
+        max_buffered: usize,
+    ) -> (
+        Coalesced<CountingStream<tokio::io::DuplexStream>>,
+        tokio::io::DuplexStream,
+        Arc<AtomicUsize>,
+    ) {
+        let (stream, peer) = tokio::io::duplex(64 * 1024);
+        let writes = Arc::new(AtomicUsize::new(0));
+        let stream = CountingStream {
+            stream,
+            writes: Arc::clone(&writes),
+        };
+        let config = ServerConfig {
+            flush_policy: policy,
+            max_buffered,
+            ..Default::default()
+        };
+        (Coalesced::new(stream, &config), peer, writes)
+    }
+
+    #[tokio::test]
+    async fn test_after_messages() {
+        let (mut stream, mut peer, writes) = coalesced(FlushPolicy::AfterMessages(4), 1024);
+        for _ in 0..10 {
+            stream.write_all(b"message!").await.unwrap();
+        }
+        assert_eq!(writes.load(Ordering::Relaxed), 2);
+        // the two left are written as soon as a read would wait for the peer
+        let mut byte = [0u8];
+        let read = time::timeout(Duration::from_millis(10), stream.read(&mut byte)).await;
+        assert!(read.is_err());
+        assert_eq!(writes.load(Ordering::Relaxed), 3);
+        let mut read = [0u8; 80];
+        peer.read_exact(&mut read).await.unwrap();
+        assert_eq!(&read[..], &b"message!".repeat(10)[..]);
+    }
+
+    #[tokio::test]
+    async fn test_max_buffered() {
+        let (mut stream, mut peer, writes) = coalesced(FlushPolicy::AfterMessages(100), 20);
+        for _ in 0..5 {
+            stream.write_all(b"message!").await.unwrap();
+        }
+        // no more than 20 bytes are held back at once
+        assert_eq!(writes.load(Ordering::Relaxed), 2);
+        // one larger than the cap goes straight through
+        stream.flush().await.unwrap();
+        stream.write_all(&[b'x'; 32]).await.unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 4);
+        let mut read = [0u8; 72];
+        peer.read_exact(&mut read).await.unwrap();
+        assert_eq!(&read[40..], &[b'x'; 32][..]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_after_idle() {
+        let idle = Duration::from_millis(10);
+        let (mut stream, mut peer, writes) = coalesced(FlushPolicy::AfterIdle(idle), 1024);
+        let start = Instant::now();
+        stream.write_all(b"first").await.unwrap();
+        time::sleep(idle / 2).await;
+        stream.write_all(b"second").await.unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 0);
+        // the connection waits for its peer, the messages are written once
+        // the first of them was held back for the idle bound
+        let reading = tokio::spawn(async move {
+            let mut byte = [0u8];
+            let _ = stream.read(&mut byte).await;
+        });
+        let mut read = [0u8; 11];
+        peer.read_exact(&mut read).await.unwrap();
+        assert_eq!(&read, b"firstsecond");
+        assert_eq!(start.elapsed(), idle);
+        assert_eq!(writes.load(Ordering::Relaxed), 1);
+        reading.abort();
+    }
+
+    /// Writes of the service answering `count` Pings sent at once, under
+    /// `policy`, along with how long the client waited for the responses
+    async fn serve_pings(count: usize, policy: FlushPolicy) -> (usize, Duration) {
+        let (mut client, stream) = tokio::io::duplex(64 * 1024);
+        let writes = Arc::new(AtomicUsize::new(0));
+        let stream = CountingStream {
+            stream,
+            writes: Arc::clone(&writes),
+        };
+        let config = ServerConfig {
+            flush_policy: policy,
+            ..Default::default()
+        };
+        let state = Arc::new(Mutex::new(State::new()));
+        let serving = tokio::spawn(Server::process(stream, state, Arc::new(config)));
+        let ping = Header::request(Request::Ping, 0).unwrap();
+        let pong = Header::raw(MAGIC, 0, Response::Ok as u16);
+        let start = Instant::now();
+        client
+            .write_all(&ping.as_bytes().repeat(count))
+            .await
+            .unwrap();
+        let mut responses = vec![0u8; count * HEADER_SIZE];
+        client.read_exact(&mut responses).await.unwrap();
+        let waited = start.elapsed();
+        assert_eq!(responses, pong.as_bytes().repeat(count));
+        client.shutdown().await.unwrap();
+        serving.await.unwrap().unwrap();
+        (writes.load(Ordering::Relaxed), waited)
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_pings() {
+        let (writes, _) = serve_pings(1000, FlushPolicy::Immediate).await;
+        assert_eq!(writes, 1000);
+        // the responses to the Pings read together go out 32 at a time
+        let (writes, _) = serve_pings(1000, FlushPolicy::AfterMessages(32)).await;
+        assert!(writes <= 1000 / 32 + 1, "{} writes", writes);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pings_idle_bound() {
+        // no response waits past the idle bound, however many there are
+        let idle = Duration::from_millis(10);
+        for count in [1, 10, 1000] {
+            let (writes, waited) = serve_pings(count, FlushPolicy::AfterIdle(idle)).await;
+            assert_eq!(waited, idle, "{} Pings", count);
+            assert_eq!(writes, 1, "{} Pings", count);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accounted_once_written() {
+        let config = Arc::new(ServerConfig {
+            flush_policy: FlushPolicy::AfterMessages(4),
+            ..Default::default()
+        });
+        let compress = [
+            Header::request(Request::Compress, 3).unwrap().as_bytes(),
+            b"aaa",
+        ]
+        .concat();
+        let requests = compress.repeat(2);
+        let sent = |state: &State| state.snapshot().stats.sent();
+
+        // the responses are held back until the read waits, only then are
+        // they accounted for, while the connection waits for more
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let state = Arc::new(Mutex::new(State::new()));
+        let serving = tokio::spawn(Server::process(stream, state.clone(), config.clone()));
+        client.write_all(&requests).await.unwrap();
+        let mut responses = [0u8; 2 * (HEADER_SIZE + 2)];
+        client.read_exact(&mut responses).await.unwrap();
+        time::timeout(Duration::from_secs(1), async {
+            while sent(&*state.lock().await) != 20 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            state.lock().await.snapshot().requests(&Request::Compress),
+            2
+        );
+        client.shutdown().await.unwrap();
+        serving.await.unwrap().unwrap();
+
+        // the peer is gone by the time they're written out, they never were:
+        // the requests count as read, not answered
+        let (mut client, stream) = tokio::io::duplex(1024);
+        client.write_all(&requests).await.unwrap();
+        drop(client);
+        let state = Arc::new(Mutex::new(State::new()));
+        Server::process(stream, state.clone(), config)
+            .await
+            .unwrap();
+        let state = state.lock().await;
+        let snapshot = state.snapshot();
+        assert_eq!((snapshot.stats.read(), snapshot.stats.sent()), (22, 0));
+        assert_eq!(snapshot.requests(&Request::Compress), 0);
+        assert_eq!(state.failed_writes(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_immediate() {
+        let (mut stream, _peer, writes) = coalesced(FlushPolicy::Immediate, 1024);
+        for _ in 0..3 {
+            stream.write_all(b"message!").await.unwrap();
+        }
+        assert_eq!(writes.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,90 @@
+use super::{Server, ServerConfig, State};
+use async_std::{net::TcpListener, task};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+/// The compression Server for applications running on async-std
+///
+/// Connections are accepted by async-std and handled by `Server::process`
+/// like those of the tokio `Server`, only the streams are adapted to tokio's
+/// IO traits. Timers (`idle_timeout`) run on the tokio runtime async-std
+/// starts alongside its own executor. The debug port is only served by the
+/// tokio `Server`
+///
+/// # Examples
+///
+/// ```ignore
+/// use service::AsyncStdServer;
+///
+/// #[async_std::main]
+/// async fn main() -> Result<(), std::io::Error> {
+///    AsyncStdServer::new_with_url("127.0.0.1:4000").await?.serve().await
+/// }
+/// ```
+pub struct AsyncStdServer {
+    pub listener: TcpListener,
+    the_state: Arc<Mutex<State>>,
+    config: Arc<ServerConfig>,
+}
+
+impl AsyncStdServer {
+    pub async fn new_with_url(url: &str) -> Result<AsyncStdServer> {
+        AsyncStdServer::new_with_config(url, Default::default()).await
+    }
+
+    /// Creates a server listening at `url` that behaves according to `config`
+    pub async fn new_with_config(url: &str, config: ServerConfig) -> Result<AsyncStdServer> {
+        let listener = TcpListener::bind(url).await?;
+        let the_state = Arc::new(Mutex::new(State::new()));
+        Ok(AsyncStdServer {
+            listener,
+            the_state,
+            config: Arc::new(config),
+        })
+    }
+
+    /// Asynchronous accept loop, each connection is processed on its own
+    /// async-std task
+    pub async fn serve(&mut self) -> Result<()> {
+        tracing::info!(
+            "Starting Compression Service @ {}",
+            self.listener.local_addr()?
+        );
+        let connections = Server::connection_limit(&self.config);
+        let (accepts, peaks, gauge) = {
+            let state = self.the_state.lock().await;
+            (state.accepts(), state.peaks(), state.connections())
+        };
+        loop {
+            let permit = match Arc::clone(&connections).try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    accepts.update_limit_wait();
+                    Arc::clone(&connections).acquire_owned().await.unwrap()
+                }
+            };
+            match self.listener.accept().await {
+                Ok((stream, peer_addr)) => {
+                    let counted = (gauge.accept(), peaks.open_connection(), permit);
+                    let state = Arc::clone(&self.the_state);
+                    let config = Arc::clone(&self.config);
+                    task::spawn(async move {
+                        let _counted = counted;
+                        if let Err(e) = Server::process(stream.compat(), state, config).await {
+                            tracing::warn!("{}", e)
+                        }
+
+                        tracing::info!("Client @ {:?} Complete", peer_addr);
+                    });
+                }
+                Err(e) => {
+                    let class = accepts.update_error(&e);
+                    tracing::error!("accept failed ({}): {:?}", class.name(), e)
+                }
+            }
+        }
+    }
+}
@@ -1,3 +1,19 @@
+#[cfg(feature = "std")]
+pub use owned::{compress, decompress, decompress_capped, validate_compressible, Uncompressible};
+#[cfg(feature = "server")]
+pub use writer::{
+    compress_to_async_writer, compress_to_async_writer_with, decompress_to_async_writer,
+};
+#[cfg(feature = "std")]
+pub use writer::{compress_to_writer, compress_to_writer_with, decompress_to_writer};
+
+#[cfg(feature = "std")]
+mod owned;
+#[cfg(feature = "std")]
+mod writer;
+
+use core::{cmp, convert::TryInto, error::Error, fmt, mem};
+
 /// A simplified prefix encoding compression scheme. Replace all consecutively
 /// repeated characters in the given string by a prefix denoting the number of
 /// characters replaced followed by the character itself.
@@ -21,36 +37,491 @@
 /// ```
 /// Must be validated already
 pub fn compress_message(rx: &[u8], tx: &mut [u8]) -> Option<usize> {
-    let len = rx.len();
-    let mut count = 1;
-    let mut compress = 0;
-    if len == 0 || (rx.len() > tx.len()) {
+    compress_message_with(rx, tx, &CompressOptions::default())
+}
+
+/// The longest output `compress_message_with` can produce for an input of
+/// `input_len` bytes, under any options
+///
+/// The output is never longer than the input: runs shorter than two bytes
+/// are copied and a run of n >= 2 bytes takes at most its digits and the
+/// character
+///
+/// # Example
+/// ```
+/// # use service::{compress_bound, compress_message};
+/// let rx = b"abcabc";
+/// let mut tx = vec![0u8; compress_bound(rx.len())];
+/// assert_eq!(compress_message(rx, &mut tx), Some(6));
+/// ```
+pub fn compress_bound(input_len: usize) -> usize {
+    input_len
+}
+
+/// Same as `compress_message` but uppercase ascii characters are folded to
+/// lowercase while compressing, so runs spanning both cases are merged
+///
+/// # Example
+/// ```
+/// # use service::compress_message_folded;
+/// let mut tx = [0u8; 5];
+/// let answer = compress_message_folded(b"aaAAa", &mut tx).unwrap();
+/// assert_eq!(tx[..answer], *b"5a");
+/// ```
+pub fn compress_message_folded(rx: &[u8], tx: &mut [u8]) -> Option<usize> {
+    let options = CompressOptions {
+        fold_case: true,
+        ..Default::default()
+    };
+    compress_message_with(rx, tx, &options)
+}
+
+/// The shortest run encoded with a count prefix by default, "2a" isn't
+/// shorter than "aa"
+pub const DEFAULT_MIN_RUN: usize = 3;
+
+/// Tunable parameters of the prefix encoding compression scheme
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressOptions {
+    /// Runs at least this long are encoded as count + character, shorter runs
+    /// are emitted literally. Values below 2 are treated as 2
+    pub min_run: usize,
+    /// Fold uppercase ascii characters to lowercase while compressing
+    pub fold_case: bool,
+}
+
+impl Default for CompressOptions {
+    fn default() -> CompressOptions {
+        CompressOptions {
+            min_run: DEFAULT_MIN_RUN,
+            fold_case: false,
+        }
+    }
+}
+
+/// Compresses `rx` into `tx` according to `options`
+/// Returns None if `rx` is empty or the output does not fit in `tx`
+///
+/// # Example
+/// ```
+/// # use service::{compress_message_with, CompressOptions};
+/// let options = CompressOptions { min_run: 2, ..Default::default() };
+/// let mut tx = [0u8; 6];
+/// let answer = compress_message_with(b"aabccc", &mut tx, &options).unwrap();
+/// assert_eq!(tx[..answer], *b"2ab3c");
+/// ```
+pub fn compress_message_with(rx: &[u8], tx: &mut [u8], options: &CompressOptions) -> Option<usize> {
+    compress_with_stats(rx, tx, options).map(|outcome| outcome.len)
+}
+
+/// Same as `compress_message_with` but also reports what the output is made
+/// of, see `CompressOutcome`
+///
+/// Inputs without a single repeated byte are copied rather than encoded, the
+/// output is identical but produced a lot faster
+///
+/// # Example
+/// ```
+/// # use service::{compress_with_stats, CompressOptions};
+/// let mut tx = [0u8; 16];
+/// let outcome = compress_with_stats(b"aaaaabbbbbbaaabb", &mut tx, &CompressOptions::default());
+/// let outcome = outcome.unwrap();
+/// assert_eq!(tx[..outcome.len], *b"5a6b3abb");
+/// assert_eq!((outcome.runs, outcome.longest_run, outcome.literals), (3, 6, 2));
+/// ```
+pub fn compress_with_stats(
+    rx: &[u8],
+    tx: &mut [u8],
+    options: &CompressOptions,
+) -> Option<CompressOutcome> {
+    if rx.is_empty() {
         return None;
     }
-    for i in 0..len {
-        if i == len - 1 || rx[i] != rx[i + 1] {
-            if count == 2 {
-                tx[compress] = rx[i];
-                compress += 1;
+    if !has_repeats(rx, options.fold_case) {
+        return store(rx, tx, options.fold_case);
+    }
+    let mut compressor = Compressor::new(rx, options);
+    compressor.fill(tx);
+    match compressor.is_done() {
+        true => Some(compressor.outcome()),
+        false => None,
+    }
+}
+
+/// False if no byte is followed by an equal byte, in which case there are no
+/// runs to encode and the output is the input
+fn has_repeats(rx: &[u8], fold_case: bool) -> bool {
+    let mut pairs = rx.iter().zip(&rx[1..]);
+    match fold_case {
+        false => pairs.any(|(a, b)| a == b),
+        true => pairs.any(|(a, b)| a.eq_ignore_ascii_case(b)),
+    }
+}
+
+/// Store mode: copies the input, which has no runs, instead of encoding it
+fn store(rx: &[u8], tx: &mut [u8], fold_case: bool) -> Option<CompressOutcome> {
+    let tx = tx.get_mut(..rx.len())?;
+    tx.copy_from_slice(rx);
+    if fold_case {
+        tx.make_ascii_lowercase();
+    }
+    Some(CompressOutcome {
+        len: rx.len(),
+        literals: rx.len(),
+        ..Default::default()
+    })
+}
+
+/// Summary of a compressed output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressOutcome {
+    /// Length of the output
+    pub len: usize,
+    /// Number of runs encoded as count + character
+    pub runs: usize,
+    /// Length of the longest run encoded as count + character
+    pub longest_run: usize,
+    /// Number of bytes copied to the output as is
+    pub literals: usize,
+}
+
+impl CompressOutcome {
+    /// An outcome of which only the output length is known
+    pub fn new_with_len(len: usize) -> CompressOutcome {
+        CompressOutcome {
+            len,
+            ..Default::default()
+        }
+    }
+}
+
+/// The streaming form of `compress_message_with`
+///
+/// The output is produced a buffer at a time, so it can be written to a sink
+/// of any size using a fixed amount of memory, see `compress_to_writer`
+///
+/// # Example
+/// ```
+/// # use service::{Compressor, CompressOptions};
+/// let mut compressor = Compressor::new(b"aaaaabbbbbbaaabb", &CompressOptions::default());
+/// let mut tx = [0u8; 3];
+/// assert_eq!(compressor.fill(&mut tx), 2);
+/// assert_eq!(tx[..2], *b"5a");
+/// assert_eq!(compressor.fill(&mut tx), 2);
+/// assert_eq!(tx[..2], *b"6b");
+/// assert_eq!(compressor.fill(&mut tx), 3);
+/// assert_eq!(tx, *b"3ab");
+/// assert_eq!(compressor.fill(&mut tx), 1);
+/// assert!(compressor.is_done());
+/// ```
+#[derive(Debug, Clone)]
+pub struct Compressor<'a> {
+    rest: &'a [u8],
+    min_run: usize,
+    fold_case: bool,
+    /// A run taken from `rest` that is not (completely) written yet
+    pending: Option<(u8, usize)>,
+    /// Summary of the output written so far
+    outcome: CompressOutcome,
+}
+
+impl<'a> Compressor<'a> {
+    pub fn new(rx: &'a [u8], options: &CompressOptions) -> Compressor<'a> {
+        Compressor {
+            rest: rx,
+            min_run: cmp::max(options.min_run, 2),
+            fold_case: options.fold_case,
+            pending: None,
+            outcome: CompressOutcome::default(),
+        }
+    }
+
+    /// True once all of the output has been produced
+    pub fn is_done(&self) -> bool {
+        self.rest.is_empty() && self.pending.is_none()
+    }
+
+    /// Summary of the output produced so far
+    pub fn outcome(&self) -> CompressOutcome {
+        self.outcome
+    }
+
+    /// Writes as much of the remaining output as fits at the start of `buf`,
+    /// returning the number of bytes written
+    ///
+    /// A count is never separated from its character, so 0 is returned when
+    /// `buf` can't hold the next encoded run (at most `MAX_COUNT_DIGITS` + 1
+    /// bytes) or when the output is complete
+    pub fn fill(&mut self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        loop {
+            let (current, run) = match self.pending.take() {
+                Some(pending) => pending,
+                None if self.rest.is_empty() => return len,
+                None => {
+                    let (current, run) = self.next_run();
+                    self.rest = &self.rest[run..];
+                    (current, run)
+                }
+            };
+            let free = buf.len() - len;
+            if run >= self.min_run {
+                if free <= count_digits(run) {
+                    self.pending = Some((current, run));
+                    return len;
+                }
+                let start = len;
+                len += write_count(run, &mut buf[len..]);
+                buf[len] = current;
+                len += 1;
+                self.outcome.len += len - start;
+                self.outcome.runs += 1;
+                self.outcome.longest_run = cmp::max(self.outcome.longest_run, run);
+            } else {
+                let written = cmp::min(run, free);
+                buf[len..len + written].fill(current);
+                len += written;
+                self.outcome.len += written;
+                self.outcome.literals += written;
+                if written < run {
+                    self.pending = Some((current, run - written));
+                    return len;
+                }
             }
-            if count > 2 {
-                for c in count.to_string().bytes() {
-                    tx[compress] = c;
-                    compress += 1;
+        }
+    }
+
+    /// The (output) character and the length of the run at the start of the
+    /// non-empty remaining input
+    fn next_run(&self) -> (u8, usize) {
+        if self.fold_case {
+            folded_run(self.rest)
+        } else {
+            (self.rest[0], run_length(self.rest, self.rest[0]))
+        }
+    }
+}
+
+/// Bytes compared at a time by `run_length`
+const SCAN_WIDTH: usize = mem::size_of::<u64>();
+
+/// Length of the run of `byte` at the start of `bytes`
+///
+/// Short runs, the common case, are found with a scalar scan of the first
+/// word. Longer runs are then compared a word at a time against `byte`
+/// splatted across a word, which the compiler can vectorize, falling back to a
+/// scalar scan for the word containing the end of the run and for the tail
+///
+/// # Example
+/// ```
+/// # use service::run_length;
+/// assert_eq!(run_length(b"aaaaaaaaaaab", b'a'), 11);
+/// ```
+#[inline]
+pub fn run_length(bytes: &[u8], byte: u8) -> usize {
+    let scalar = |bytes: &[u8]| bytes.iter().take_while(|&&x| x == byte).count();
+    let head = scalar(&bytes[..cmp::min(SCAN_WIDTH, bytes.len())]);
+    if head < SCAN_WIDTH {
+        return head;
+    }
+    let splat = u64::from_ne_bytes([byte; SCAN_WIDTH]);
+    let mut chunks = bytes[SCAN_WIDTH..].chunks_exact(SCAN_WIDTH);
+    let mut len = SCAN_WIDTH;
+    for chunk in &mut chunks {
+        let word = u64::from_ne_bytes(chunk.try_into().unwrap());
+        if word != splat {
+            return len + scalar(chunk);
+        }
+        len += SCAN_WIDTH;
+    }
+    len + scalar(chunks.remainder())
+}
+
+/// The case-insensitive run at the start of a non-empty slice
+fn folded_run(rest: &[u8]) -> (u8, usize) {
+    let current = rest[0].to_ascii_lowercase();
+    let run = rest
+        .iter()
+        .take_while(|x| x.to_ascii_lowercase() == current)
+        .count();
+    (current, run)
+}
+
+/// Enough digits for any usize
+const MAX_COUNT_DIGITS: usize = 20;
+
+/// Writes the decimal representation of `count` at the start of `tx` without
+/// allocating, returns the number of digits written
+fn write_count(mut count: usize, tx: &mut [u8]) -> usize {
+    let mut digits = [0u8; MAX_COUNT_DIGITS];
+    let mut start = MAX_COUNT_DIGITS;
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (count % 10) as u8;
+        count /= 10;
+        if count == 0 {
+            break;
+        }
+    }
+    let len = MAX_COUNT_DIGITS - start;
+    tx[..len].copy_from_slice(&digits[start..]);
+    len
+}
+
+/// Number of decimal digits in `count`
+fn count_digits(count: usize) -> usize {
+    count.checked_ilog10().unwrap_or(0) as usize + 1
+}
+
+/// The most digits a run count may have when decompressing, enough for any
+/// run of a MAX_PAYLOAD message. Longer counts are malformed rather than
+/// parsed into absurdly large runs
+pub const MAX_DECOMPRESS_COUNT_DIGITS: usize = 4;
+
+/// Reasons `decompress_message` rejects its input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecompressError {
+    /// There is nothing to decompress
+    EmptyInput,
+    /// A count of zero, a count with more than `MAX_DECOMPRESS_COUNT_DIGITS`
+    /// digits, a count that isn't followed by a character or a character
+    /// the scheme doesn't accept
+    Malformed,
+    /// The expanded output does not fit in the output buffer
+    OutputTooLarge,
+}
+
+impl fmt::Display for DecompressError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecompressError::EmptyInput => write!(fmt, "input is empty"),
+            DecompressError::Malformed => write!(fmt, "input is not a valid compressed payload"),
+            DecompressError::OutputTooLarge => write!(fmt, "expanded output is too large"),
+        }
+    }
+}
+
+impl Error for DecompressError {}
+
+/// Expands the output of `compress_message` back into the original bytes
+///
+/// Every count prefix is expanded regardless of the `min_run` used when
+/// compressing, so "2a" and "aa" both decompress to "aa"
+///
+/// The expanded size is computed incrementally and decompression stops with
+/// `DecompressError::OutputTooLarge` as soon as a run would not fit in `tx`,
+/// nothing is written beyond what fits, so a small request can't be used to
+/// make the service do an unbounded amount of work (decompression bombs)
+///
+/// # Example
+/// ```
+/// # use service::{decompress_message, DecompressError};
+/// let mut tx = [0u8; 16];
+/// let answer = decompress_message(b"5a6b3abb", &mut tx).unwrap();
+/// assert_eq!(tx[..answer], *b"aaaaabbbbbbaaabb");
+/// assert_eq!(decompress_message(b"9999a", &mut tx), Err(DecompressError::OutputTooLarge));
+/// ```
+pub fn decompress_message(rx: &[u8], tx: &mut [u8]) -> Result<usize, DecompressError> {
+    let len = expanded_len(rx, tx.len())?;
+    let mut start = 0;
+    for (byte, count) in Runs::new(rx).map_while(Result::ok) {
+        tx[start..start + count].fill(byte);
+        start += count;
+    }
+    Ok(len)
+}
+
+/// The exact length `decompress_message` expands `rx` to, only the counts
+/// are parsed and nothing is written
+///
+/// # Example
+/// ```
+/// # use service::{decompress_bound, decompress_message, DecompressError};
+/// let rx = b"5a6b3abb";
+/// let mut tx = vec![0u8; decompress_bound(rx).unwrap()];
+/// assert_eq!(decompress_message(rx, &mut tx), Ok(16));
+/// assert_eq!(decompress_bound(b"9999a"), Ok(9999));
+/// assert_eq!(decompress_bound(b"0a"), Err(DecompressError::Malformed));
+/// ```
+pub fn decompress_bound(rx: &[u8]) -> Result<usize, DecompressError> {
+    expanded_len(rx, usize::MAX)
+}
+
+/// The length `rx` expands to, stopping with `DecompressError::OutputTooLarge`
+/// as soon as it exceeds `limit`, before any later run is parsed. Errors come
+/// in the order of the runs, so a bomb is rejected without being expanded
+fn expanded_len(rx: &[u8], limit: usize) -> Result<usize, DecompressError> {
+    if rx.is_empty() {
+        return Err(DecompressError::EmptyInput);
+    }
+    let mut len: usize = 0;
+    for run in Runs::new(rx) {
+        let (_, count) = run?;
+        len = len
+            .checked_add(count)
+            .filter(|len| *len <= limit)
+            .ok_or(DecompressError::OutputTooLarge)?;
+    }
+    Ok(len)
+}
+
+/// The (character, count) runs of a compressed payload in order
+///
+/// Yields a single `DecompressError::Malformed` and stops at the first
+/// invalid count
+struct Runs<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Runs<'a> {
+    fn new(rx: &'a [u8]) -> Runs<'a> {
+        Runs { rest: rx }
+    }
+
+    fn malformed(&mut self) -> Option<Result<(u8, usize), DecompressError>> {
+        self.rest = &[];
+        Some(Err(DecompressError::Malformed))
+    }
+}
+
+impl<'a> Iterator for Runs<'a> {
+    type Item = Result<(u8, usize), DecompressError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut count: usize = 0;
+        let mut digits = 0;
+        while let Some((&byte, tail)) = self.rest.split_first() {
+            self.rest = tail;
+            if byte.is_ascii_digit() {
+                digits += 1;
+                if digits > MAX_DECOMPRESS_COUNT_DIGITS {
+                    return self.malformed();
                 }
+                count = count * 10 + (byte - b'0') as usize;
+                continue;
             }
-            tx[compress] = rx[i];
-            compress += 1;
-            count = 0;
+            return match (digits, count) {
+                (0, _) => Some(Ok((byte, 1))),
+                (_, 0) => self.malformed(),
+                (_, n) => Some(Ok((byte, n))),
+            };
+        }
+        match digits {
+            0 => None,
+            _ => self.malformed(),
         }
-        count += 1
     }
-    Some(compress)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::compress_message;
+    use super::{
+        compress_bound, compress_message, compress_message_folded, compress_message_with,
+        compress_with_stats, decompress_bound, decompress_message, run_length, write_count,
+        CompressOptions, DecompressError, MAX_COUNT_DIGITS,
+    };
+    use crate::message::MAX_PAYLOAD;
+    use proptest::prelude::*;
 
     #[test]
     fn test_none() {
@@ -62,7 +533,7 @@ mod tests {
     fn test_compress_message() {
         fn test_some(rx: &[u8], expect: &[u8]) {
             let mut tx = [0; 32];
-            let res = compress_message(&rx, &mut tx);
+            let res = compress_message(rx, &mut tx);
             assert_eq!(&tx[..res.unwrap()], expect);
         }
 
@@ -78,4 +549,284 @@ mod tests {
         let msg = [97u8, 97, 97, 97, 97, 97, 97, 97, 97, 97, 97];
         test_some(&msg, &[49, 49, 97]);
     }
+
+    #[test]
+    fn test_compress_with_stats() {
+        fn outcome(rx: &[u8]) -> (usize, usize, usize, usize) {
+            let mut tx = [0; 32];
+            let outcome = compress_with_stats(rx, &mut tx, &CompressOptions::default()).unwrap();
+            (
+                outcome.len,
+                outcome.runs,
+                outcome.longest_run,
+                outcome.literals,
+            )
+        }
+
+        assert_eq!(outcome(b"a"), (1, 0, 0, 1));
+        assert_eq!(outcome(b"aa"), (2, 0, 0, 2));
+        assert_eq!(outcome(b"aaa"), (2, 1, 3, 0));
+        assert_eq!(outcome(b"aaaaabbb"), (4, 2, 5, 0));
+        assert_eq!(outcome(b"aaaaabbbbbbaaabb"), (8, 3, 6, 2));
+        assert_eq!(outcome(b"abcdefg"), (7, 0, 0, 7));
+        assert_eq!(outcome(b"aaaccddddhhhhi"), (9, 3, 4, 3));
+        assert_eq!(outcome(&[b'a'; 12]), (3, 1, 12, 0));
+
+        let mut tx = [0; 32];
+        let options = CompressOptions::default();
+        assert_eq!(compress_with_stats(b"", &mut tx, &options), None);
+        assert_eq!(compress_with_stats(b"abc", &mut tx[..2], &options), None);
+        assert_eq!(compress_with_stats(b"aaaab", &mut tx[..2], &options), None);
+    }
+
+    #[test]
+    fn test_store() {
+        let mut tx = [0; 32];
+        let options = CompressOptions {
+            fold_case: true,
+            ..Default::default()
+        };
+        let outcome = compress_with_stats(b"AbCd", &mut tx, &options).unwrap();
+        assert_eq!(&tx[..outcome.len], b"abcd");
+        assert_eq!((outcome.runs, outcome.literals), (0, 4));
+
+        // a repeat means encoding, even if no run is long enough to be encoded
+        let outcome = compress_with_stats(b"abb", &mut tx, &Default::default()).unwrap();
+        assert_eq!(&tx[..outcome.len], b"abb");
+        assert_eq!((outcome.runs, outcome.literals), (0, 3));
+    }
+
+    #[test]
+    fn test_compress_message_folded() {
+        fn test_folded(rx: &[u8], expect: &[u8]) {
+            let mut tx = [0; 32];
+            let res = compress_message_folded(rx, &mut tx);
+            assert_eq!(&tx[..res.unwrap()], expect);
+        }
+
+        test_folded(b"aaAAa", b"5a");
+        test_folded(b"AAAbbb", b"3a3b");
+        test_folded(b"Ab", b"ab");
+        test_folded(b"aAbB", b"aabb");
+        test_folded(b"abc", b"abc");
+    }
+
+    #[test]
+    fn test_run_length() {
+        assert_eq!(run_length(b"", b'a'), 0);
+        assert_eq!(run_length(b"b", b'a'), 0);
+        assert_eq!(run_length(b"aaab", b'a'), 3);
+        assert_eq!(run_length(b"aaaaaaaab", b'a'), 8);
+        assert_eq!(run_length(b"aaaaaaaaaaab", b'a'), 11);
+        assert_eq!(run_length(&[b'a'; 8192], b'a'), 8192);
+        let mut rx = [b'a'; 100];
+        rx[64] = b'b';
+        assert_eq!(run_length(&rx, b'a'), 64);
+        assert_eq!(run_length(&rx[65..], b'a'), 35);
+    }
+
+    #[test]
+    fn test_write_count() {
+        let mut tx = [0u8; MAX_COUNT_DIGITS];
+        for (count, expect) in &[
+            (0, "0"),
+            (3, "3"),
+            (10, "10"),
+            (8192, "8192"),
+            (usize::MAX, "18446744073709551615"),
+        ] {
+            let len = write_count(*count, &mut tx);
+            assert_eq!(&tx[..len], expect.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_min_run() {
+        fn test_min(min_run: usize, rx: &[u8], expect: &[u8]) {
+            let mut tx = [0; 32];
+            let options = CompressOptions {
+                min_run,
+                ..Default::default()
+            };
+            let res = compress_message_with(rx, &mut tx, &options);
+            assert_eq!(&tx[..res.unwrap()], expect);
+        }
+
+        test_min(2, b"aabccc", b"2ab3c");
+        test_min(0, b"aabccc", b"2ab3c");
+        test_min(3, b"aabccc", b"aab3c");
+        test_min(4, b"aabcccdddd", b"aabccc4d");
+        test_min(5, b"aaaaabbbb", b"5abbbb");
+    }
+
+    #[test]
+    fn test_decompress_message() {
+        fn test_some(rx: &[u8], expect: &[u8]) {
+            let mut tx = [0; 32];
+            let res = decompress_message(rx, &mut tx);
+            assert_eq!(&tx[..res.unwrap()], expect);
+        }
+
+        test_some(b"a", b"a");
+        test_some(b"2a", b"aa");
+        test_some(b"5a3b", b"aaaaabbb");
+        test_some(b"3acc4d4hi", b"aaaccddddhhhhi");
+        test_some(b"10a", b"aaaaaaaaaa");
+        test_some(b"1a", b"a");
+
+        let mut tx = [0; 4];
+        assert_eq!(
+            decompress_message(b"", &mut tx),
+            Err(DecompressError::EmptyInput)
+        );
+        assert_eq!(
+            decompress_message(b"3", &mut tx),
+            Err(DecompressError::Malformed)
+        );
+        assert_eq!(
+            decompress_message(b"0a", &mut tx),
+            Err(DecompressError::Malformed)
+        );
+        assert_eq!(
+            decompress_message(b"5a", &mut tx),
+            Err(DecompressError::OutputTooLarge)
+        );
+        assert_eq!(decompress_message(b"4a", &mut tx), Ok(4));
+    }
+
+    #[test]
+    fn test_decompress_bombs() {
+        let limit = MAX_PAYLOAD as usize;
+        let mut tx = vec![0u8; limit];
+
+        // exactly at the limit
+        assert_eq!(decompress_message(b"8192a", &mut tx), Ok(limit));
+        assert!(tx.iter().all(|x| *x == b'a'));
+        assert_eq!(decompress_message(b"4096a4096b", &mut tx), Ok(limit));
+
+        // one over, nothing is written past the runs that fit
+        let mut tx = vec![0u8; limit + 1];
+        assert_eq!(
+            decompress_message(b"4096a4096bc", &mut tx[..limit]),
+            Err(DecompressError::OutputTooLarge)
+        );
+        assert_eq!(tx[limit], 0);
+        assert_eq!(
+            decompress_message(b"8193a", &mut tx[..limit]),
+            Err(DecompressError::OutputTooLarge)
+        );
+        assert!(tx.iter().all(|x| *x == 0 || *x == b'a' || *x == b'b'));
+
+        // absurd counts
+        assert_eq!(
+            decompress_message(b"9999a9999b", &mut tx[..limit]),
+            Err(DecompressError::OutputTooLarge)
+        );
+        assert_eq!(
+            decompress_message(b"99999999a", &mut tx[..limit]),
+            Err(DecompressError::Malformed)
+        );
+        assert_eq!(
+            decompress_message(b"00001a", &mut tx[..limit]),
+            Err(DecompressError::Malformed)
+        );
+    }
+
+    /// The byte-at-a-time implementation prior to making the threshold
+    /// configurable and the run detection word based
+    fn reference_compress(rx: &[u8], tx: &mut [u8]) -> Option<usize> {
+        let len = rx.len();
+        let mut count = 1;
+        let mut compress = 0;
+        if len == 0 || (rx.len() > tx.len()) {
+            return None;
+        }
+        for i in 0..len {
+            if i == len - 1 || rx[i] != rx[i + 1] {
+                if count == 2 {
+                    tx[compress] = rx[i];
+                    compress += 1;
+                }
+                if count > 2 {
+                    for c in count.to_string().bytes() {
+                        tx[compress] = c;
+                        compress += 1;
+                    }
+                }
+                tx[compress] = rx[i];
+                compress += 1;
+                count = 0;
+            }
+            count += 1
+        }
+        Some(compress)
+    }
+
+    /// Lowercase payloads made of runs of various lengths
+    fn payload() -> impl Strategy<Value = Vec<u8>> {
+        prop::collection::vec((b'a'..=b'd', 1usize..120), 1..40).prop_map(|runs| {
+            runs.into_iter()
+                .flat_map(|(byte, len)| std::iter::repeat_n(byte, len))
+                .collect()
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn prop_round_trip(rx in payload(), min_run in 2usize..=5) {
+            let mut tx = vec![0u8; rx.len()];
+            let options = CompressOptions { min_run, ..Default::default() };
+            let len = compress_message_with(&rx, &mut tx, &options).unwrap();
+            prop_assert!(len <= rx.len());
+
+            let mut out = vec![0u8; rx.len()];
+            let out_len = decompress_message(&tx[..len], &mut out).unwrap();
+            prop_assert_eq!(&out[..out_len], &rx[..]);
+        }
+
+        #[test]
+        fn prop_bounds(
+            rx in prop_oneof![payload(), prop::collection::vec(any::<u8>(), 1..300)],
+            min_run in 2usize..=5,
+            fold_case in any::<bool>(),
+        ) {
+            let mut tx = vec![0u8; compress_bound(rx.len())];
+            let options = CompressOptions { min_run, fold_case };
+            let len = compress_message_with(&rx, &mut tx, &options);
+            prop_assert!(len.is_some());
+            let len = len.unwrap();
+            // literal digits would read as counts
+            if !rx.iter().any(u8::is_ascii_digit) {
+                prop_assert_eq!(decompress_bound(&tx[..len]), Ok(rx.len()));
+            }
+
+            // arbitrary input, the bound is exact or an error decompression
+            // hits as well
+            let mut out = vec![0u8; MAX_PAYLOAD as usize];
+            let expected = decompress_message(&rx, &mut out);
+            match decompress_bound(&rx) {
+                Ok(bound) if bound <= out.len() => prop_assert_eq!(expected, Ok(bound)),
+                Ok(_) => prop_assert_eq!(expected, Err(DecompressError::OutputTooLarge)),
+                Err(_) => prop_assert!(expected.is_err()),
+            }
+        }
+
+        #[test]
+        fn prop_random_bytes_match_reference(rx in prop::collection::vec(b'a'..=b'b', 1..300)) {
+            let mut tx = vec![0u8; rx.len()];
+            let mut expected = vec![0u8; rx.len()];
+            let len = compress_message(&rx, &mut tx).unwrap();
+            let expected_len = reference_compress(&rx, &mut expected).unwrap();
+            prop_assert_eq!(&tx[..len], &expected[..expected_len]);
+        }
+
+        #[test]
+        fn prop_default_matches_reference(rx in payload()) {
+            let mut tx = vec![0u8; rx.len()];
+            let mut expected = vec![0u8; rx.len()];
+            let len = compress_message(&rx, &mut tx).unwrap();
+            let expected_len = reference_compress(&rx, &mut expected).unwrap();
+            prop_assert_eq!(&tx[..len], &expected[..expected_len]);
+        }
+    }
 }
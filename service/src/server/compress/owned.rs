@@ -0,0 +1,194 @@
+use super::{compress_bound, decompress_message, expanded_len, DecompressError};
+use crate::message::{InvalidPayload, Response, MAX_PAYLOAD};
+use crate::server::{CompressError, CompressionScheme, RlePrefix};
+use std::{error::Error, fmt};
+
+/// Compresses `input` the way the service answers a Compress under its
+/// default configuration, see `compress_message`
+///
+/// The buffer is allocated once, `compress_bound` bytes.
+/// The input isn't validated, only one that passes `validate_compressible`
+/// decompresses back to itself
+///
+/// # Example
+/// ```
+/// use service::{compress, CompressError};
+/// assert_eq!(compress(b"aaaaabbbbbbaaabb").unwrap(), b"5a6b3abb");
+/// assert_eq!(compress(b""), Err(CompressError::EmptyInput));
+/// ```
+pub fn compress(input: &[u8]) -> Result<Vec<u8>, CompressError> {
+    let mut output = vec![0u8; compress_bound(input.len())];
+    let len = RlePrefix::default().compress(input, &mut output)?;
+    output.truncate(len);
+    Ok(output)
+}
+
+/// Expands the output of `compress` back into the original bytes, up to
+/// MAX_PAYLOAD of them like the service's Decompress
+///
+/// # Example
+/// ```
+/// use service::{decompress, DecompressError};
+/// assert_eq!(decompress(b"5a6b3abb").unwrap(), b"aaaaabbbbbbaaabb");
+/// assert_eq!(decompress(b"9999a"), Err(DecompressError::OutputTooLarge));
+/// assert_eq!(decompress(b"0a"), Err(DecompressError::Malformed));
+/// ```
+pub fn decompress(input: &[u8]) -> Result<Vec<u8>, DecompressError> {
+    decompress_capped(input, MAX_PAYLOAD as usize)
+}
+
+/// Same as `decompress` but up to `cap` bytes of output
+///
+/// The counts are parsed first, like `decompress_bound`, and the output is
+/// allocated at its exact length. An input expanding past `cap` fails with
+/// `DecompressError::OutputTooLarge` without allocating anything
+///
+/// # Example
+/// ```
+/// use service::{decompress_capped, DecompressError};
+/// assert_eq!(decompress_capped(b"12a", 12).unwrap(), [b'a'; 12]);
+/// assert_eq!(decompress_capped(b"12a", 11), Err(DecompressError::OutputTooLarge));
+/// ```
+pub fn decompress_capped(input: &[u8], cap: usize) -> Result<Vec<u8>, DecompressError> {
+    RlePrefix::default().check_literals(input)?;
+    let mut output = vec![0u8; expanded_len(input, cap)?];
+    decompress_message(input, &mut output)?;
+    Ok(output)
+}
+
+/// Why `validate_compressible` rejects an input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Uncompressible {
+    /// There is nothing to compress
+    Empty,
+    /// The input holds a byte the service doesn't compress, the first one
+    Invalid(InvalidPayload),
+}
+
+impl Uncompressible {
+    /// The response a Compress of the input gets
+    pub fn response(&self) -> Response {
+        match self {
+            Uncompressible::Empty => Response::CompressionRequestRequiresNonZeroLength,
+            Uncompressible::Invalid(invalid) => invalid.response(),
+        }
+    }
+}
+
+impl fmt::Display for Uncompressible {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Uncompressible::Empty => write!(fmt, "input is empty"),
+            Uncompressible::Invalid(invalid) => write!(fmt, "{}", invalid),
+        }
+    }
+}
+
+impl Error for Uncompressible {}
+
+/// Whether the service would compress `input` under its default
+/// configuration, lowercase ASCII letters only. The error tells the first
+/// byte rejected and the response a Compress of it gets
+///
+/// # Example
+/// ```
+/// use service::{validate_compressible, Response, Uncompressible};
+/// assert_eq!(validate_compressible(b"abc"), Ok(()));
+/// let error = validate_compressible(b"abC").unwrap_err();
+/// assert_eq!(error.response(), Response::MessageContainsUppercaseCharacters);
+/// assert_eq!(error.to_string(), "invalid byte 0x43 at offset 2");
+/// assert_eq!(validate_compressible(b""), Err(Uncompressible::Empty));
+/// ```
+pub fn validate_compressible(input: &[u8]) -> Result<(), Uncompressible> {
+    if input.is_empty() {
+        return Err(Uncompressible::Empty);
+    }
+    RlePrefix::default()
+        .policy
+        .check(input)
+        .map_err(Uncompressible::Invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Header, Request, MAX_MESSAGE_PADDED};
+    use crate::server::{compress_message, decompress_message};
+    use crate::{Connection, State};
+    use proptest::prelude::*;
+    use zerocopy::AsBytes;
+
+    /// The response of the service to a Compress of `payload`
+    fn served(payload: &[u8]) -> (Response, Vec<u8>) {
+        let header = Header::request(Request::Compress, payload.len() as u16).unwrap();
+        let rx = [header.as_bytes(), payload].concat();
+        let mut tx = vec![0u8; MAX_MESSAGE_PADDED];
+        let len =
+            Connection::new_with(&rx[..], &mut tx[..], rx.len()).create_response(&mut State::new());
+        let response = crate::message::Message::parse(&tx[..len]).unwrap();
+        let code = Response::from_u16(response.header.code()).unwrap();
+        (code, response.payload_slice().to_vec())
+    }
+
+    #[test]
+    fn test_decompress_capped() {
+        // far longer than the input
+        let long = decompress(b"8192z").unwrap();
+        assert_eq!(long, vec![b'z'; MAX_PAYLOAD as usize]);
+        assert_eq!(decompress(b"8193z"), Err(DecompressError::OutputTooLarge));
+        assert_eq!(
+            decompress_capped(b"ab", 0),
+            Err(DecompressError::OutputTooLarge)
+        );
+        assert_eq!(decompress_capped(b"", 10), Err(DecompressError::EmptyInput));
+        // literals the service wouldn't compress are malformed
+        assert_eq!(decompress(b"3A"), Err(DecompressError::Malformed));
+    }
+
+    fn any_payload() -> impl Strategy<Value = Vec<u8>> {
+        prop_oneof![
+            prop::collection::vec((b'a'..=b'd', 1usize..40), 1..40).prop_map(|runs| {
+                runs.into_iter()
+                    .flat_map(|(byte, len)| std::iter::repeat_n(byte, len))
+                    .collect()
+            }),
+            prop::collection::vec(any::<u8>(), 0..64),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_compress_as_primitives(input in any_payload()) {
+            let mut tx = vec![0u8; input.len()];
+            let primitive = compress_message(&input, &mut tx).map(|len| tx[..len].to_vec());
+            prop_assert_eq!(compress(&input).ok(), primitive);
+        }
+
+        #[test]
+        fn prop_decompress_as_primitives(input in any_payload()) {
+            let mut tx = vec![0u8; MAX_PAYLOAD as usize];
+            let primitive = RlePrefix::default().decompress(&input, &mut tx);
+            let primitive = primitive.map(|len| tx[..len].to_vec());
+            prop_assert_eq!(decompress(&input), primitive);
+            if let Ok(output) = decompress(&input) {
+                let mut tx = vec![0u8; output.len()];
+                prop_assert_eq!(decompress_message(&input, &mut tx), Ok(output.len()));
+            }
+        }
+
+        #[test]
+        fn prop_as_served(input in any_payload()) {
+            // what the service answers a Compress of the input
+            let (response, payload) = served(&input);
+            match validate_compressible(&input) {
+                Ok(()) => {
+                    prop_assert_eq!(response, Response::Ok);
+                    let compressed = compress(&input).unwrap();
+                    prop_assert_eq!(&payload, &compressed);
+                    prop_assert_eq!(decompress(&compressed).unwrap(), input);
+                }
+                Err(error) => prop_assert_eq!(response, error.response()),
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use super::{CompressOptions, Compressor, Runs};
+use std::{
+    cmp,
+    io::{self, Write},
+};
+#[cfg(feature = "server")]
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Output is staged in a stack buffer of this size before being written to a
+/// sink, large enough for any encoded run
+const CHUNK_SIZE: usize = 256;
+
+/// Compresses `rx` into `w` (see `compress_message`), returning the length of
+/// the output. Memory use is constant however large the output is
+///
+/// # Example
+/// ```
+/// # use service::compress_to_writer;
+/// let mut out = Vec::new();
+/// assert_eq!(compress_to_writer(b"aaaaabbb", &mut out).unwrap(), 4);
+/// assert_eq!(out, b"5a3b");
+/// ```
+pub fn compress_to_writer<W: Write + ?Sized>(rx: &[u8], w: &mut W) -> io::Result<usize> {
+    compress_to_writer_with(rx, w, &CompressOptions::default())
+}
+
+/// Compresses `rx` into `w` according to `options`
+pub fn compress_to_writer_with<W: Write + ?Sized>(
+    rx: &[u8],
+    w: &mut W,
+    options: &CompressOptions,
+) -> io::Result<usize> {
+    let mut compressor = Compressor::new(rx, options);
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut len = 0;
+    loop {
+        match compressor.fill(&mut chunk) {
+            0 => return Ok(len),
+            n => {
+                w.write_all(&chunk[..n])?;
+                len += n;
+            }
+        }
+    }
+}
+
+/// The async counterpart of `compress_to_writer`
+#[cfg(feature = "server")]
+pub async fn compress_to_async_writer<W>(rx: &[u8], w: &mut W) -> io::Result<usize>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    compress_to_async_writer_with(rx, w, &CompressOptions::default()).await
+}
+
+/// The async counterpart of `compress_to_writer_with`
+#[cfg(feature = "server")]
+pub async fn compress_to_async_writer_with<W>(
+    rx: &[u8],
+    w: &mut W,
+    options: &CompressOptions,
+) -> io::Result<usize>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut compressor = Compressor::new(rx, options);
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut len = 0;
+    loop {
+        match compressor.fill(&mut chunk) {
+            0 => return Ok(len),
+            n => {
+                w.write_all(&chunk[..n]).await?;
+                len += n;
+            }
+        }
+    }
+}
+
+/// Expands `rx` into `w` (see `decompress_message`), returning the length of
+/// the output. A malformed `rx` is reported as `io::ErrorKind::InvalidData`,
+/// the runs before the error have already been written
+///
+/// # Example
+/// ```
+/// # use service::decompress_to_writer;
+/// let mut out = Vec::new();
+/// assert_eq!(decompress_to_writer(b"5a3b", &mut out).unwrap(), 8);
+/// assert_eq!(out, b"aaaaabbb");
+/// ```
+pub fn decompress_to_writer<W: Write + ?Sized>(rx: &[u8], w: &mut W) -> io::Result<usize> {
+    let mut chunk = Chunk::new();
+    for run in Runs::new(rx) {
+        let (byte, mut count) = run.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        while count > 0 {
+            count -= chunk.push(byte, count);
+            if chunk.is_full() {
+                w.write_all(chunk.take())?;
+            }
+        }
+    }
+    w.write_all(chunk.take())?;
+    Ok(chunk.total)
+}
+
+/// The async counterpart of `decompress_to_writer`
+#[cfg(feature = "server")]
+pub async fn decompress_to_async_writer<W>(rx: &[u8], w: &mut W) -> io::Result<usize>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut chunk = Chunk::new();
+    for run in Runs::new(rx) {
+        let (byte, mut count) = run.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        while count > 0 {
+            count -= chunk.push(byte, count);
+            if chunk.is_full() {
+                w.write_all(chunk.take()).await?;
+            }
+        }
+    }
+    w.write_all(chunk.take()).await?;
+    Ok(chunk.total)
+}
+
+/// Stages expanded runs before they are written
+struct Chunk {
+    buf: [u8; CHUNK_SIZE],
+    len: usize,
+    total: usize,
+}
+
+impl Chunk {
+    fn new() -> Chunk {
+        Chunk {
+            buf: [0u8; CHUNK_SIZE],
+            len: 0,
+            total: 0,
+        }
+    }
+
+    /// Appends up to `count` copies of `byte`, returning how many fit
+    fn push(&mut self, byte: u8, count: usize) -> usize {
+        let n = cmp::min(count, CHUNK_SIZE - self.len);
+        self.buf[self.len..self.len + n].fill(byte);
+        self.len += n;
+        self.total += n;
+        n
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == CHUNK_SIZE
+    }
+
+    /// The staged bytes, the chunk is empty afterwards
+    fn take(&mut self) -> &[u8] {
+        let len = self.len;
+        self.len = 0;
+        &self.buf[..len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "server")]
+    use super::{compress_to_async_writer, decompress_to_async_writer};
+    use super::{compress_to_writer, compress_to_writer_with, decompress_to_writer, CHUNK_SIZE};
+    use crate::{compress_message, CompressOptions};
+    use std::io::{Cursor, ErrorKind};
+    #[cfg(feature = "server")]
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_compress_to_vec() {
+        let mut out = Vec::new();
+        assert_eq!(
+            compress_to_writer(b"aaaaabbbbbbaaabb", &mut out).unwrap(),
+            8
+        );
+        assert_eq!(out, b"5a6b3abb");
+
+        // output larger than a chunk with runs and literals straddling chunks
+        let rx: Vec<u8> = (0..CHUNK_SIZE * 3)
+            .map(|i| {
+                if i % 7 < 4 {
+                    b'a'
+                } else {
+                    b'b' + (i % 3) as u8
+                }
+            })
+            .collect();
+        let mut expected = vec![0u8; rx.len()];
+        let len = compress_message(&rx, &mut expected).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(compress_to_writer(&rx, &mut out).unwrap(), len);
+        assert_eq!(out, &expected[..len]);
+
+        // a literal run longer than a chunk
+        let options = CompressOptions {
+            min_run: usize::MAX,
+            ..Default::default()
+        };
+        let rx = vec![b'z'; CHUNK_SIZE * 2 + 1];
+        let mut out = Vec::new();
+        assert_eq!(
+            compress_to_writer_with(&rx, &mut out, &options).unwrap(),
+            rx.len()
+        );
+        assert_eq!(out, rx);
+
+        let mut out = Vec::new();
+        assert_eq!(compress_to_writer(b"", &mut out).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_writer_full() {
+        let mut buf = [0u8; 3];
+        let mut cursor = Cursor::new(&mut buf[..]);
+        let err = compress_to_writer(b"aaaaabbb", &mut cursor).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WriteZero);
+
+        let mut buf = [0u8; 7];
+        let mut cursor = Cursor::new(&mut buf[..]);
+        let err = decompress_to_writer(b"5a3b", &mut cursor).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WriteZero);
+
+        let mut buf = [0u8; 8];
+        let mut cursor = Cursor::new(&mut buf[..]);
+        assert_eq!(decompress_to_writer(b"5a3b", &mut cursor).unwrap(), 8);
+        assert_eq!(&buf, b"aaaaabbb");
+    }
+
+    #[test]
+    fn test_decompress_to_vec() {
+        let mut out = Vec::new();
+        assert_eq!(decompress_to_writer(b"9999a1b", &mut out).unwrap(), 10000);
+        assert_eq!(out.len(), 10000);
+        assert!(out[..9999].iter().all(|x| *x == b'a'));
+        assert_eq!(out[9999], b'b');
+
+        for malformed in &[&b"0a"[..], b"3", b"99999a"] {
+            let err = decompress_to_writer(malformed, &mut Vec::new()).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_async_duplex() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let writer = tokio::spawn(async move {
+            let len = compress_to_async_writer(&[b'a'; 1000], &mut server).await?;
+            let len = len + decompress_to_async_writer(b"300b", &mut server).await?;
+            std::io::Result::Ok(len)
+        });
+        let mut out = Vec::new();
+        client.read_to_end(&mut out).await.unwrap();
+        assert_eq!(writer.await.unwrap().unwrap(), 5 + 300);
+        assert_eq!(&out[..5], b"1000a");
+        assert_eq!(&out[5..], &[b'b'; 300][..]);
+    }
+}
@@ -0,0 +1,552 @@
+use super::compress::{CompressOptions, DEFAULT_MIN_RUN};
+use super::limits::{Feature, Limits, ServedRequests};
+use super::ratio::RatioPolicy;
+use super::scheme::{CompressionScheme, RlePrefix};
+use super::state::ANONYMOUS_TENANT;
+use crate::message::{CharPolicy, Request, CODE_MASK, MAX_MESSAGE, MAX_PAYLOAD, PROTOCOL_VERSION};
+use std::{collections::HashMap, path::PathBuf, str::FromStr, sync::Arc, time::Duration};
+
+/// Requests a connection handles in a row before yielding by default
+pub const DEFAULT_REQUESTS_PER_YIELD: usize = 1;
+
+/// Messages with a bad magic a connection may send before it's closed by default
+pub const DEFAULT_BAD_MAGIC_STRIKES: usize = 3;
+
+/// Protocol violations a connection may commit under strict enforcement
+/// before it's closed by default, see `ServerConfig::violation_strikes`
+pub const DEFAULT_VIOLATION_STRIKES: usize = 3;
+
+/// How long the first retry of binding an address in use waits by default,
+/// each next one waits twice as long as the previous
+pub const DEFAULT_BIND_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Compress and Decompress requests handled at once across the connections
+/// by default, see `ServerConfig::heavy_lane`
+pub const DEFAULT_HEAVY_LANE: usize = 4;
+
+/// Tenants whose stats are kept at once by default, see
+/// `ServerConfig::max_tenants`
+pub const DEFAULT_MAX_TENANTS: usize = 64;
+
+/// Entries a Batch request may have by default, see `ServerConfig::max_batch`
+pub const DEFAULT_MAX_BATCH: usize = 256;
+
+/// ResetStats tokens each connection remembers by default, see
+/// `ServerConfig::reset_tokens`
+pub const DEFAULT_RESET_TOKENS: usize = 16;
+
+/// How long handling a single request may take by default, see
+/// `ServerConfig::request_deadline`
+pub const DEFAULT_REQUEST_DEADLINE: Duration = Duration::from_secs(1);
+
+/// How long the rest of a message cut short may take to arrive by default,
+/// see `ServerConfig::frame_timeout`
+pub const DEFAULT_FRAME_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long the connections being served get to finish on shutdown by default
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bytes of a rejected request dumped by default, see
+/// `RejectLogPolicy::max_dump`
+pub const DEFAULT_REJECT_DUMP: usize = 256;
+
+/// Bytes of rejected requests a connection may dump a second by default
+pub const DEFAULT_REJECT_CONNECTION_RATE: usize = 4 * 1024;
+
+/// Bytes of rejected requests all connections may dump a second by default
+pub const DEFAULT_REJECT_GLOBAL_RATE: usize = 64 * 1024;
+
+/// How much of the requests the service rejects is dumped to the log, see
+/// `RejectLog`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RejectLogPolicy {
+    /// Bytes of each request dumped, header included and rounded up to a
+    /// whole row of the dump, the rest is only summarized
+    pub max_dump: usize,
+    /// Every Nth rejection of a connection is dumped, the others are only
+    /// counted, 1 dumps them all
+    pub sample_every: u64,
+    /// Bytes a connection may dump a second, and at once, 0 is unlimited
+    pub connection_rate: usize,
+    /// Bytes the connections may dump a second across all of them, 0 is
+    /// unlimited
+    pub global_rate: usize,
+}
+
+impl Default for RejectLogPolicy {
+    fn default() -> RejectLogPolicy {
+        RejectLogPolicy {
+            max_dump: DEFAULT_REJECT_DUMP,
+            sample_every: 1,
+            connection_rate: DEFAULT_REJECT_CONNECTION_RATE,
+            global_rate: DEFAULT_REJECT_GLOBAL_RATE,
+        }
+    }
+}
+
+impl FromStr for RejectLogPolicy {
+    type Err = String;
+
+    /// Comma separated settings, e.g. "dump=64,every=10", each left out
+    /// keeps its default: dump=BYTES, every=N, connection=BYTES and
+    /// global=BYTES a second
+    fn from_str(s: &str) -> Result<RejectLogPolicy, String> {
+        let mut policy = RejectLogPolicy::default();
+        for setting in s.split(',') {
+            let invalid = || format!("{} is not dump=, every=, connection= or global=N", setting);
+            let (name, value) = setting.split_once('=').ok_or_else(invalid)?;
+            let value: usize = value.parse().map_err(|_| invalid())?;
+            match name {
+                "dump" => policy.max_dump = value,
+                "every" => policy.sample_every = value as u64,
+                "connection" => policy.connection_rate = value,
+                "global" => policy.global_rate = value,
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(policy)
+    }
+}
+
+/// Bytes of responses a connection holds back by default, whatever its
+/// `FlushPolicy`, see `ServerConfig::max_buffered`
+pub const DEFAULT_MAX_BUFFERED: usize = 4 * MAX_MESSAGE;
+
+/// When the messages held back on a connection are written to it, each
+/// write a syscall. They are written whatever the policy once
+/// `ServerConfig::max_buffered` bytes are held back
+///
+/// "immediate" => Immediate
+/// "messages:N" => AfterMessages(N)
+/// "idle:MICROS" => AfterIdle, in microseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// Every message is written as soon as it's built, as the service
+    /// always did
+    #[default]
+    Immediate,
+    /// Messages are held back until N of them go in a write, or until the
+    /// connection would wait for its peer, so that none waits on the others
+    AfterMessages(usize),
+    /// Messages are written together the duration after the first of them,
+    /// none is held back longer however many follow
+    AfterIdle(Duration),
+}
+
+impl FromStr for FlushPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FlushPolicy, String> {
+        let invalid = || format!("{} is not immediate, messages:N or idle:MICROS", s);
+        match s.split_once(':') {
+            None if s == "immediate" => Ok(FlushPolicy::Immediate),
+            Some(("messages", n)) => match n.parse() {
+                Ok(n) if n > 0 => Ok(FlushPolicy::AfterMessages(n)),
+                _ => Err(invalid()),
+            },
+            Some(("idle", micros)) => micros
+                .parse()
+                .map(|micros| FlushPolicy::AfterIdle(Duration::from_micros(micros)))
+                .map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// How closely the requests of clients are held to the protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Enforcement {
+    /// Messages are handled as they always were, the header flags are only
+    /// rejected if `ServerConfig::strict_flags` and a Decompress payload is
+    /// accepted as long as it can be expanded, e.g. "1a2a" for "aaa"
+    #[default]
+    Permissive,
+    /// Unsupported header flags are always rejected, trailing bytes past the
+    /// payload size get `TrailingBytes` rather than a size mismatch, and a
+    /// Decompress payload that isn't what Compress would send for its output
+    /// gets `NonCanonicalEncoding`. A connection committing
+    /// `ServerConfig::violation_strikes` such violations is closed
+    Strict,
+}
+
+/// Runtime configuration of the compression `Server`
+///
+/// The default configuration matches the behavior of the service before it
+/// was made configurable
+///
+/// # Example
+/// ```
+/// use service::{CharPolicy, ServerConfig};
+/// let config = ServerConfig {
+///     char_policy: CharPolicy::from_spec("a-z -").unwrap(),
+///     ..Default::default()
+/// };
+/// assert!(config.char_policy.allows(b' '));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// The characters accepted in the payload of compression requests
+    pub char_policy: CharPolicy,
+    /// Case-insensitive compression mode, uppercase ascii characters are
+    /// accepted (if their lowercase form is allowed by `char_policy`) and
+    /// folded to lowercase before compressing, i.e. "AAAbbb" => "3a3b"
+    pub fold_case: bool,
+    /// Runs at least this long are compressed as count + character
+    /// (default 3, minimum 2)
+    pub min_run: usize,
+    /// A custom compression algorithm, when `None` the `RlePrefix` scheme
+    /// described by the fields above is used
+    pub scheme: Option<Arc<dyn CompressionScheme + Send + Sync>>,
+    /// Connections that don't send a request for this long are closed
+    pub idle_timeout: Option<Duration>,
+    /// How long the rest of a message cut short may take to arrive once its
+    /// first bytes are read, it's then answered for what it has, e.g.
+    /// MessageTooSmall for fewer bytes than a header. Bounded by
+    /// `idle_timeout` alone when `None`, so that a client sending part of a
+    /// message can't hold its connection and the memory of the request for
+    /// longer than this by default
+    pub frame_timeout: Option<Duration>,
+    /// A connection yields to the others on its worker after handling this
+    /// many requests in a row without waiting for input, 0 never yields
+    pub requests_per_yield: usize,
+    /// Requests setting header flags the service doesn't support are
+    /// rejected with `UnsupportedFlags`, otherwise the flags are ignored
+    pub strict_flags: bool,
+    /// How closely requests are held to the protocol, permissive by default
+    /// like the service always was
+    pub enforcement: Enforcement,
+    /// ResetStats may clear the stats of the whole service, an empty
+    /// ResetStats then does so like it did before scopes existed. Otherwise
+    /// only the requesting connection's stats can be reset
+    pub allow_global_reset: bool,
+    /// A connection is closed once it has sent this many messages with a bad
+    /// magic, 0 never closes it. Its peer likely doesn't speak the protocol,
+    /// e.g. a port scanner or a TLS client
+    pub bad_magic_strikes: usize,
+    /// Messages with a bad magic get no response, they only count towards
+    /// `bad_magic_strikes`. A connection closed for them gets no Goodbye either
+    pub silent_bad_magic: bool,
+    /// Under strict `enforcement`, a connection is closed once it has sent
+    /// this many requests violating the protocol (see
+    /// `Response::is_violation`), 0 never closes it
+    pub violation_strikes: usize,
+    /// Address of the debug port, a second listener speaking a line based
+    /// text protocol (see `Server::process_text`), off when `None`
+    pub debug_addr: Option<String>,
+    /// Connections served at once, further ones wait to be accepted until
+    /// one closes, 0 is unlimited
+    pub max_connections: usize,
+    /// How long the connections being served get to finish once a server
+    /// started with `Server::spawn` is shut down, see `Server::serve_on`
+    pub shutdown_timeout: Duration,
+    /// A summary of the stats since the previous one is logged this often,
+    /// off when `None` or zero
+    pub stats_interval: Option<Duration>,
+    /// Times the listeners try to bind their address while it's in use, e.g.
+    /// by the process being replaced, before giving up. See
+    /// `Server::bind_with_retry`
+    pub bind_attempts: u32,
+    /// How long the first retry waits, doubled for each next one
+    pub bind_backoff: Duration,
+    /// Bytes of requests read but not answered yet, and of responses being
+    /// written, held across all connections at once, 0 is unlimited. A
+    /// request that would go over is answered ServerBusy, see `InFlight`
+    pub memory_budget: usize,
+    /// A connection is closed with a Goodbye once it has answered this many
+    /// requests, so that its client reconnects, 0 is unlimited
+    pub max_requests_per_connection: usize,
+    /// A connection open for this long is closed with a Goodbye once done
+    /// with the request at hand, unlimited when `None` or zero
+    pub max_connection_lifetime: Option<Duration>,
+    /// Compress and Decompress requests handled at once across the
+    /// connections, others wait for their turn while control requests like
+    /// Ping never do, 0 is unlimited. See `Lanes`
+    pub heavy_lane: usize,
+    /// Tenants by the token their clients send in an Authenticate request,
+    /// unknown tokens are answered Unauthorized. Once any is configured
+    /// GetStats and an empty ResetStats are scoped to the tenant of the
+    /// requesting connection, `ANONYMOUS_TENANT` if it never authenticated
+    pub tenants: HashMap<String, String>,
+    /// Tenants whose stats are kept at once, the anonymous one aside, a
+    /// token of a further tenant is answered Forbidden
+    pub max_tenants: usize,
+    /// Requests the tokio `Server` remembers, the latest ones, for the
+    /// debug port's RECENT and `Server::recent`, 0 remembers none. See
+    /// `Recent`
+    pub recent_requests: usize,
+    /// The recent requests also keep the first bytes of their payload, off
+    /// by default as those are the clients' data
+    pub capture_payload_prefix: bool,
+    /// The directory FlushStats requests write their dumps to, a file of it
+    /// named by the request. FlushStats is Forbidden when `None`
+    pub flush_dir: Option<PathBuf>,
+    /// The bytes of each request and of its response are wiped from the
+    /// connection's buffers once the response is written, so no client data
+    /// lingers in memory for longer than it's handled. Off by default, it
+    /// costs a write of both buffers per request
+    pub zeroize_buffers: bool,
+    /// How the compression ratio of the stats is computed, over every
+    /// request since the last reset by default
+    pub ratio_policy: RatioPolicy,
+    /// Entries a Batch request may have, one with more is answered
+    /// BatchTooLarge without handling any. 0 turns batches off, they are
+    /// then Forbidden
+    pub max_batch: usize,
+    /// Error responses to malformed requests carry why in their payload,
+    /// e.g. the size the header declared against the bytes that followed
+    /// it. Off by default as clients comparing responses byte for byte
+    /// expect bare headers, see `Feature::ERROR_DETAILS`
+    pub error_details: bool,
+    /// Rejected requests are dumped to the log within the budgets of the
+    /// policy, off when `None`. On by default in debug builds only
+    pub reject_log: Option<RejectLogPolicy>,
+    /// Each connection accepted is greeted with a Hello message holding the
+    /// `limits`, before any request. Off by default, the protocol is
+    /// otherwise only ever started by the client
+    pub hello: bool,
+    /// ResetStats tokens each connection remembers along with what they were
+    /// answered, a ResetStats repeating one of them gets the same response
+    /// without resetting again, so that a client retrying one whose response
+    /// was lost doesn't reset twice. 0 turns tokens off, a ResetStats
+    /// carrying one is then answered UnsupportedResetScope, see
+    /// `Feature::IDEMPOTENT_RESET`
+    pub reset_tokens: usize,
+    /// An UnsupportedRequestType carries the protocol version and the
+    /// requests served as its payload, see `ServedRequests`, in place of any
+    /// error detail. Off by default, clients of the bare header keep it
+    pub describe_unsupported: bool,
+    /// How long handling a single request may take. A request taking longer
+    /// is logged and counted as slow, and one handled by a custom `scheme`
+    /// is cut short and answered UnknownError, as the scheme can hang where
+    /// the built-in one can't. Off when `None`
+    pub request_deadline: Option<Duration>,
+    /// Bytes the payload of a response may have, at most MAX_PAYLOAD, the
+    /// default. A response over it is answered ResponseTooLarge, an error
+    /// detail is truncated to fit. One the service builds itself, e.g. the
+    /// GetStats payload, is also counted as an internal error
+    pub max_response: usize,
+    /// When the responses of a connection are written, each as soon as it's
+    /// built by default. Holding them back lets a pipelining client get
+    /// several in a single write. A response held back counts as sent, a
+    /// write of it failing later fails the connection at its next read
+    pub flush_policy: FlushPolicy,
+    /// Bytes of responses a connection holds back at most under its
+    /// `flush_policy`, past them it writes them out before building more,
+    /// so a client that reads slowly is answered at its pace rather than
+    /// buffered for
+    pub max_buffered: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            char_policy: Default::default(),
+            fold_case: false,
+            min_run: DEFAULT_MIN_RUN,
+            scheme: None,
+            idle_timeout: None,
+            frame_timeout: Some(DEFAULT_FRAME_TIMEOUT),
+            requests_per_yield: DEFAULT_REQUESTS_PER_YIELD,
+            strict_flags: true,
+            enforcement: Enforcement::Permissive,
+            allow_global_reset: false,
+            bad_magic_strikes: DEFAULT_BAD_MAGIC_STRIKES,
+            silent_bad_magic: false,
+            violation_strikes: DEFAULT_VIOLATION_STRIKES,
+            debug_addr: None,
+            max_connections: 0,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            stats_interval: None,
+            bind_attempts: 1,
+            bind_backoff: DEFAULT_BIND_BACKOFF,
+            memory_budget: 0,
+            max_requests_per_connection: 0,
+            max_connection_lifetime: None,
+            heavy_lane: DEFAULT_HEAVY_LANE,
+            tenants: HashMap::new(),
+            max_tenants: DEFAULT_MAX_TENANTS,
+            recent_requests: 0,
+            capture_payload_prefix: false,
+            flush_dir: None,
+            zeroize_buffers: false,
+            ratio_policy: RatioPolicy::Cumulative,
+            max_batch: DEFAULT_MAX_BATCH,
+            error_details: false,
+            reject_log: cfg!(debug_assertions).then(RejectLogPolicy::default),
+            hello: false,
+            reset_tokens: DEFAULT_RESET_TOKENS,
+            describe_unsupported: false,
+            request_deadline: Some(DEFAULT_REQUEST_DEADLINE),
+            max_response: MAX_PAYLOAD as usize,
+            flush_policy: FlushPolicy::Immediate,
+            max_buffered: DEFAULT_MAX_BUFFERED,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Checks the settings can be served with, e.g. before reloading them
+    /// into a running server (see `ConfigHandle::reload`)
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_run < 2 {
+            return Err(format!("min_run {} is below 2", self.min_run));
+        }
+        if self
+            .tenants
+            .values()
+            .any(|tenant| tenant == ANONYMOUS_TENANT)
+        {
+            return Err(format!("no tenant may be named {}", ANONYMOUS_TENANT));
+        }
+        self.ratio_policy.validate()
+    }
+
+    /// Names of the settings differing from `other`'s that a running server
+    /// only applies once restarted, they are sized or bound at startup
+    pub fn restart_only_changes(&self, other: &ServerConfig) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+        if self.debug_addr != other.debug_addr {
+            changes.push("debug_addr");
+        }
+        if self.max_connections != other.max_connections {
+            changes.push("max_connections");
+        }
+        if self.stats_interval != other.stats_interval {
+            changes.push("stats_interval");
+        }
+        if self.shutdown_timeout != other.shutdown_timeout {
+            changes.push("shutdown_timeout");
+        }
+        if self.heavy_lane != other.heavy_lane {
+            changes.push("heavy_lane");
+        }
+        if self.recent_requests != other.recent_requests {
+            changes.push("recent_requests");
+        }
+        changes
+    }
+
+    /// Whether stats are scoped to tenants, i.e. any is configured
+    pub fn tenancy(&self) -> bool {
+        !self.tenants.is_empty()
+    }
+
+    /// The tenant of `token`, `None` if it's not one of `tenants`
+    pub fn tenant_of(&self, token: &[u8]) -> Option<&str> {
+        let token = std::str::from_utf8(token).ok()?;
+        self.tenants.get(token).map(String::as_str)
+    }
+
+    /// The `memory_budget` the request in `bytes` is held to, a Health
+    /// request is never answered ServerBusy so it reports the overload
+    /// instead
+    pub fn memory_budget_of(&self, bytes: &[u8]) -> usize {
+        match Request::of(bytes) {
+            Some(Request::Health) => 0,
+            _ => self.memory_budget,
+        }
+    }
+
+    /// Requests setting header flags the service doesn't support are rejected
+    pub fn rejects_flags(&self) -> bool {
+        self.strict_flags || self.enforcement == Enforcement::Strict
+    }
+
+    /// Whether a connection that sent `violations` requests violating the
+    /// protocol is due to be closed
+    pub fn violations_due(&self, violations: usize) -> bool {
+        self.enforcement == Enforcement::Strict
+            && self.violation_strikes > 0
+            && violations >= self.violation_strikes
+    }
+
+    /// Whether a connection that answered `requests` and has been open for
+    /// `age` is due to be closed for its client to reconnect
+    pub fn rotation_due(&self, requests: usize, age: Duration) -> bool {
+        let capped =
+            self.max_requests_per_connection > 0 && requests >= self.max_requests_per_connection;
+        capped || self.lifetime_left(age) == Some(Duration::ZERO)
+    }
+
+    /// How much longer than `age` a connection may stay open, `None` if its
+    /// lifetime is unlimited
+    pub fn lifetime_left(&self, age: Duration) -> Option<Duration> {
+        self.max_connection_lifetime
+            .filter(|lifetime| !lifetime.is_zero())
+            .map(|lifetime| lifetime.saturating_sub(age))
+    }
+
+    /// The compression parameters derived from this configuration
+    pub fn compress_options(&self) -> CompressOptions {
+        CompressOptions {
+            min_run: self.min_run,
+            fold_case: self.fold_case,
+        }
+    }
+
+    /// The default compression scheme described by this configuration
+    pub fn rle_prefix(&self) -> RlePrefix {
+        RlePrefix::new_with(self.payload_policy(), self.compress_options())
+    }
+
+    /// The character policy compression payloads are validated against,
+    /// accounting for case folding
+    pub fn payload_policy(&self) -> CharPolicy {
+        if self.fold_case {
+            self.char_policy.case_insensitive()
+        } else {
+            self.char_policy
+        }
+    }
+
+    /// Whether `request` is served under this configuration, the one place
+    /// deciding it for both GetConfig's features and `served_requests`
+    pub fn serves(&self, request: &Request) -> bool {
+        match request {
+            Request::Batch => self.max_batch > 0,
+            _ => true,
+        }
+    }
+
+    /// The requests served under this configuration, reported to clients by
+    /// UnsupportedRequestType under `describe_unsupported`
+    pub fn served_requests(&self) -> ServedRequests {
+        let requests = (0..=CODE_MASK)
+            .filter_map(Request::from_u16)
+            .filter(|request| self.serves(request));
+        ServedRequests::new_with(PROTOCOL_VERSION, requests)
+    }
+
+    /// The limits reported to clients by GetConfig
+    pub fn limits(&self) -> Limits {
+        let idle_timeout = self.idle_timeout.map_or(0, |t| t.as_secs() as u32);
+        let decompress = if self.serves(&Request::Decompress) {
+            Feature::DECOMPRESS
+        } else {
+            0
+        };
+        let batch = if self.serves(&Request::Batch) {
+            Feature::BATCH
+        } else {
+            0
+        };
+        let details = if self.error_details {
+            Feature::ERROR_DETAILS
+        } else {
+            0
+        };
+        let reset = if self.reset_tokens > 0 {
+            Feature::IDEMPOTENT_RESET
+        } else {
+            0
+        };
+        Limits::new_with(
+            PROTOCOL_VERSION,
+            decompress | Feature::SEQUENCE | batch | details | reset,
+            MAX_PAYLOAD,
+            MAX_MESSAGE as u16,
+            idle_timeout,
+            0,
+        )
+    }
+}
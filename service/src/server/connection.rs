@@ -1,9 +1,14 @@
-use super::compress::compress_message;
+use super::compress::DecompressError;
+use super::config::{Enforcement, ServerConfig};
+use super::flush;
+use super::scheme::{CompressError, CompressionScheme, RleBinary};
 use super::state::State;
+use super::stats::{Stats, StatsV2, STATS_LEN, STATS_VERSION};
 use crate::message;
 use crate::message::*;
 
-use zerocopy::{ByteSlice, ByteSliceMut};
+use std::{cmp, error::Error, fmt, mem};
+use zerocopy::{AsBytes, ByteSlice, ByteSliceMut};
 
 /// A facade of the underlying receive and transmit slices in the form of
 /// `Message`s
@@ -38,7 +43,12 @@ where
     Rx: ByteSlice,
     Tx: ByteSliceMut,
 {
+    /// The connection answering the `message_len` bytes read into `rx`.
+    /// Whatever `rx` holds past them, left over from an earlier and longer
+    /// message, is never read, and `message_len` is never taken to go past
+    /// the end of `rx`
     pub fn new_with(rx: Rx, tx: Tx, message_len: usize) -> Connection<Rx, Tx> {
+        let message_len = cmp::min(message_len, rx.len());
         let rx = Message::parse(rx).unwrap();
         let tx = Message::parse_mut(tx).unwrap();
         Connection {
@@ -48,81 +58,866 @@ where
         }
     }
 
+    /// Bytes of payload read, whatever size the header declares
     pub fn read_payload_len(&self) -> usize {
-        message::payload_len(self.message_len) // self.message_len - HEADER_SIZE
+        // self.message_len - HEADER_SIZE
+        cmp::min(
+            message::payload_len(self.message_len),
+            self.rx.payload.len(),
+        )
+    }
+
+    /// Bytes of the payload the request is answered from: those both read
+    /// and declared by the header. Every payload is sliced by it, so that no
+    /// byte past either ends up in a response
+    fn payload_len(&self) -> usize {
+        cmp::min(self.read_payload_len(), self.rx.header.size() as usize)
     }
 
     /// Handles the client's query (rx) and constructs response (tx)
+    /// under the default `ServerConfig`
     pub fn create_response(&mut self, state: &mut State) -> usize {
-        let response_code = self.rx.validate(self.message_len);
-        let tx_body_len = match response_code {
-            Response::Ok => self.process_response(state),
-            _ => 0,
+        self.create_response_with(state, &ServerConfig::default())
+    }
+
+    /// Handles the client's query (rx) and constructs response (tx)
+    /// compression requests are handled by the configured `CompressionScheme`
+    pub fn create_response_with(&mut self, state: &mut State, config: &ServerConfig) -> usize {
+        self.create_response_scoped(state, &mut State::new(), config)
+    }
+
+    /// Handles the client's query (rx) and constructs response (tx), `state`
+    /// is the stats of the whole service and `connection` those of the
+    /// connection the request was received on
+    ///
+    /// Returns the length of the response, always its header and the size
+    /// the header declares. tx is reused from one request to the next and
+    /// only that many bytes of it are written, whatever it holds past them is
+    /// left over from an earlier response, so the response is `tx[..len]`
+    pub fn create_response_scoped(
+        &mut self,
+        state: &mut State,
+        connection: &mut State,
+        config: &ServerConfig,
+    ) -> usize {
+        // a bad magic is answered as such, unnumbered, so it still counts
+        // towards the strikes
+        let sequenced = self.rx.header.flags() & Flag::SEQUENCED != 0;
+        let in_bounds = (HEADER_SIZE..=MAX_MESSAGE).contains(&self.message_len);
+        let len = if sequenced && in_bounds && self.rx.header.sign() == MAGIC {
+            self.respond_sequenced(state, connection, config)
+        } else {
+            match &config.scheme {
+                Some(scheme) => self.respond(state, connection, scheme.as_ref(), config),
+                None => self.respond(state, connection, &config.rle_prefix(), config),
+            }
         };
+        debug_assert_eq!(
+            len,
+            HEADER_SIZE + self.tx.header.size() as usize,
+            "the length of the response disagrees with its header"
+        );
+        len
+    }
+
+    /// Handles a request numbered by the client as the same request without
+    /// the number, the response echoes it back in front of its payload.
+    /// The number counts towards MAX_PAYLOAD like the rest of the payload
+    ///
+    /// The unnumbered response is built within tx's own payload, then its
+    /// payload is moved down to make room for the number. Its header takes
+    /// up more of tx than the number does, which `MAX_MESSAGE_PADDED` leaves
+    /// room for
+    fn respond_sequenced(
+        &mut self,
+        state: &mut State,
+        connection: &mut State,
+        config: &ServerConfig,
+    ) -> usize {
+        let read = &self.rx.payload[..self.read_payload_len()];
+        let capacity = self.max_payload(config);
+        let room = cmp::min(
+            (HEADER_SIZE + capacity).saturating_sub(SEQUENCE_LEN),
+            self.tx.payload.len(),
+        );
+        let header = self.rx.header.as_bytes();
+        let (sequence, rx) = match message::without_sequence(&[header, read].concat()) {
+            Some(unsequenced) if capacity >= SEQUENCE_LEN && room >= HEADER_SIZE => unsequenced,
+            _ => {
+                state.update_error();
+                let code = Response::MessageTooSmall as u16;
+                self.tx.set_header(MAGIC, 0, code);
+                return HEADER_SIZE;
+            }
+        };
+        let len = Connection::new_with(&rx[..], &mut self.tx.payload[..room], rx.len())
+            .create_response_scoped(state, connection, config);
+        let response = Message::parse(&self.tx.payload[..len]).unwrap();
+        let code = response.header.code() | (Flag::SEQUENCED as u16) << 8;
+        let size = SEQUENCE_LEN + response.header.size() as usize;
+        self.tx.payload.copy_within(HEADER_SIZE..len, SEQUENCE_LEN);
+        self.tx.payload[..SEQUENCE_LEN].copy_from_slice(&sequence.to_be_bytes());
+        self.tx.set_header(MAGIC, size as u16, code);
+        message::total_response_len(size)
+    }
+
+    /// Handles the client's query (rx) and constructs response (tx),
+    /// delegating the validation and compression of payloads to `scheme`
+    ///
+    /// `CompressBinary` and `DecompressBinary` requests always use `RleBinary`
+    pub fn create_response_using(
+        &mut self,
+        state: &mut State,
+        scheme: &dyn CompressionScheme,
+    ) -> usize {
+        self.respond(state, &mut State::new(), scheme, &ServerConfig::default())
+    }
+
+    /// `create_response_using` with everything but the scheme taken from
+    /// `config`
+    fn respond(
+        &mut self,
+        state: &mut State,
+        connection: &mut State,
+        scheme: &dyn CompressionScheme,
+        config: &ServerConfig,
+    ) -> usize {
+        let scheme = match Request::from_u16(self.rx.header.code()) {
+            Some(Request::CompressBinary) | Some(Request::DecompressBinary) => &RleBinary,
+            _ => scheme,
+        };
+        let response_code =
+            self.rx
+                .validate_flags_using(self.message_len, config.rejects_flags(), |payload| {
+                    scheme.validate_payload(payload)
+                });
+        let response_code = self.enforce(response_code, config);
+        let (response_code, tx_body_len) = match response_code {
+            Response::Ok => {
+                state.peaks().update_request(self.payload_len());
+                self.process_response(state, connection, scheme, config)
+            }
+            _ => (response_code, 0),
+        };
+        // whatever built it, a response over the max is never sent. Only the
+        // service's own payloads aren't checked before they're built
+        let (response_code, tx_body_len) = match response_code {
+            _ if response_code.is_success() && tx_body_len as usize > self.max_payload(config) => {
+                tracing::error!(
+                    "A {:?} response of {} bytes is over the max of {}",
+                    response_code,
+                    tx_body_len,
+                    self.max_payload(config)
+                );
+                state.update_internal_error();
+                (Response::ResponseTooLarge, 0)
+            }
+            _ => (response_code, tx_body_len),
+        };
+        let tx_body_len = if response_code.is_success() {
+            tx_body_len
+        } else {
+            state.update_error();
+            match response_code {
+                Response::UnsupportedRequestType if config.describe_unsupported => {
+                    self.write_served_requests(config)
+                }
+                _ => self.write_error_detail(response_code, scheme, config),
+            }
+        };
+        state.peaks().update_response(tx_body_len as usize);
         self.tx
             .set_header(message::MAGIC, tx_body_len, response_code as u16);
         message::total_response_len(tx_body_len as usize) // HEADER_SIZE + tx_body_len
     }
 
-    fn process_response(&mut self, state: &mut State) -> u16 {
-        match Request::from_u16(self.rx.header.code()).unwrap() {
+    /// Under strict enforcement, tells trailing bytes apart from a payload
+    /// cut short
+    fn enforce(&self, response: Response, config: &ServerConfig) -> Response {
+        match (config.enforcement, response) {
+            (Enforcement::Strict, Response::MessageHeaderSizeMismatch)
+                if self.read_payload_len() > self.rx.header.size() as usize =>
+            {
+                Response::TrailingBytes
+            }
+            _ => response,
+        }
+    }
+
+    /// Writes why the request was rejected with `response`, if it can be
+    /// told, then the code of the request as the payload if the service
+    /// sends error details, returning its length
+    fn write_error_detail(
+        &mut self,
+        response: Response,
+        scheme: &dyn CompressionScheme,
+        config: &ServerConfig,
+    ) -> u16 {
+        if !config.error_details || self.tx.payload.is_empty() {
+            return 0;
+        }
+        let max = self.max_payload(config);
+        if max == 0 {
+            return 0;
+        }
+        let detail = self.error_detail(response, scheme).unwrap_or_default();
+        let len = cmp::min(detail.len(), MAX_ERROR_DETAIL);
+        let len = cmp::min(len, max - 1);
+        self.tx.payload[..len].copy_from_slice(&detail.as_bytes()[..len]);
+        self.tx.payload[len] = self.echoed_request();
+        len as u16 + 1
+    }
+
+    /// Writes the requests the service serves as the payload of an
+    /// UnsupportedRequestType, returning its length
+    fn write_served_requests(&mut self, config: &ServerConfig) -> u16 {
+        let served = config.served_requests();
+        let payload = served.as_bytes();
+        if payload.len() > self.max_payload(config) {
+            return 0;
+        }
+        match self.tx.payload.get_mut(..payload.len()) {
+            Some(tx) => {
+                tx.copy_from_slice(payload);
+                payload.len() as u16
+            }
+            None => 0,
+        }
+    }
+
+    /// The code of the request answered, echoed by its error response so
+    /// that a client with several requests in flight can tell which one
+    /// failed. 0 if it can't be told: fewer bytes than a header, a bad magic
+    /// or an unknown code
+    fn echoed_request(&self) -> u8 {
+        let framed = self.message_len >= HEADER_SIZE && self.rx.header.sign() == MAGIC;
+        match Request::from_u16(self.rx.header.code()) {
+            Some(request) if framed => request as u8,
+            _ => 0,
+        }
+    }
+
+    /// Why the request was rejected with `response`, from its header and the
+    /// bytes read. Of the payload's contents only the first byte `scheme`
+    /// rejects is ever told, and where it is
+    fn error_detail(&self, response: Response, scheme: &dyn CompressionScheme) -> Option<String> {
+        let header = &self.rx.header;
+        let request = Request::from_u16(header.code());
+        let detail = match (response, request) {
+            (Response::MessageTooSmall, _) => format!(
+                "{} bytes read of a {} byte header",
+                self.message_len, HEADER_SIZE
+            ),
+            (Response::MessageTooLarge, _) if self.message_len > MAX_MESSAGE => format!(
+                "{} bytes read over the max message of {}",
+                self.message_len, MAX_MESSAGE
+            ),
+            (Response::MessageTooLarge, _) => format!(
+                "header.size={} over the max payload of {}",
+                header.size(),
+                MAX_PAYLOAD
+            ),
+            (Response::MessageHeaderHasBadMagic, _) => format!(
+                "header.sign={:#010x} but magic={:#010x}",
+                header.sign(),
+                MAGIC
+            ),
+            (Response::MessageHeaderSizeMismatch, _) | (Response::TrailingBytes, _) => format!(
+                "header.size={} but payload={} bytes",
+                header.size(),
+                self.read_payload_len()
+            ),
+            (Response::UnsupportedFlags, _) => format!(
+                "header.flags={:#04x} but supported={:#04x}",
+                header.flags(),
+                Flag::SUPPORTED
+            ),
+            (Response::UnsupportedRequestType, _) => {
+                format!("request code {} is unknown", header.code() & CODE_MASK)
+            }
+            (Response::RequestKindRequiresZeroLength, Some(request)) => {
+                format!("header.size={} too large for {:?}", header.size(), request)
+            }
+            (Response::CompressionRequestRequiresNonZeroLength, Some(request)) => {
+                format!("header.size=0 but {:?} requires a payload", request)
+            }
+            (Response::MessagePayloadContainsInvalidCharacters, _)
+            | (Response::MessageContainsUppercaseCharacters, _)
+            | (Response::MessageContainsDigits, _)
+            | (Response::MessageContainsNonAscii, _) => {
+                let payload = &self.rx.payload[..self.payload_len()];
+                scheme.invalid_byte(payload)?.to_string()
+            }
+            _ => return None,
+        };
+        Some(detail)
+    }
+
+    /// The response code and payload length of a validated request
+    fn process_response(
+        &mut self,
+        state: &mut State,
+        connection: &mut State,
+        scheme: &dyn CompressionScheme,
+        config: &ServerConfig,
+    ) -> (Response, u16) {
+        let request = Request::from_u16(self.rx.header.code()).unwrap();
+        state.update_request(&request);
+        match request {
             Request::Ping => self.process_ping(state),
-            Request::GetStats => self.process_getstats(state),
-            Request::ResetStats => self.process_resetstats(state),
-            Request::Compress => self.process_compress(state),
+            Request::GetStats | Request::GetStatsV2 => {
+                self.process_getstats(&request, state, connection, config)
+            }
+            Request::ResetStats => self.process_resetstats(state, connection, config),
+            Request::Compress | Request::CompressBinary => {
+                self.process_compress(state, connection, scheme, config)
+            }
+            Request::CompressWithStats => {
+                self.process_compress_with_stats(state, connection, scheme, config)
+            }
+            Request::Decompress | Request::DecompressBinary => {
+                self.process_decompress(scheme, config)
+            }
+            Request::GetConfig => self.process_getconfig(state, config),
+            Request::Authenticate => self.process_authenticate(state, connection, config),
+            Request::FlushStats => self.process_flushstats(state, connection, config),
+            Request::Batch => self.process_batch(state, connection, scheme, config),
+            Request::Health => self.process_health(state, config),
         }
     }
 
-    fn process_ping(&mut self, state: &mut State) -> u16 {
-        self.tx.set_code(state.internal_error()); // report errors?
-        0
+    /// Ping reports any internal error of the service
+    fn process_ping(&mut self, state: &mut State) -> (Response, u16) {
+        match state.internal_error() {
+            0 => (Response::Ok, 0),
+            _ => (Response::UnknownError, 0),
+        }
     }
 
-    fn process_getstats(&mut self, state: &mut State) -> u16 {
-        let stats_bytes = state.stats_as_bytes();
-        self.tx.set_payload(stats_bytes).unwrap();
-        stats_bytes.len() as u16
+    /// Health is answered Ok whatever the status, the payload is the status
+    /// byte and the detail of any status but ready, truncated to fit
+    fn process_health(&mut self, state: &mut State, config: &ServerConfig) -> (Response, u16) {
+        let (status, detail) = health(state, config, self.message_len);
+        let room = self.max_payload(config).saturating_sub(1);
+        let detail = &detail.as_bytes()[..cmp::min(detail.len(), room)];
+        let payload = [&[status as u8][..], detail].concat();
+        self.write_payload(&payload, state, config)
     }
 
-    fn process_resetstats(&mut self, state: &mut State) -> u16 {
-        state.reset();
-        0
+    /// Serializes from a snapshot, never from the state directly, so read,
+    /// sent and ratio always come from the same moment. Under tenancy only
+    /// the stats of the connection's tenant are reported. GetStatsV2 prefixes
+    /// them with `STATS_VERSION` in its layout
+    fn process_getstats(
+        &mut self,
+        request: &Request,
+        state: &mut State,
+        connection: &mut State,
+        config: &ServerConfig,
+    ) -> (Response, u16) {
+        let stats = reported_stats(state, connection, config);
+        if *request == Request::GetStats {
+            return self.write_payload(stats.as_bytes(), state, config);
+        }
+        let mut payload = [0u8; 1 + mem::size_of::<StatsV2>()];
+        payload[0] = STATS_VERSION;
+        payload[1..].copy_from_slice(StatsV2::from(&stats).as_bytes());
+        self.write_payload(&payload, state, config)
+    }
+
+    fn process_getconfig(&mut self, state: &mut State, config: &ServerConfig) -> (Response, u16) {
+        let limits = config.limits();
+        self.write_payload(limits.as_bytes(), state, config)
     }
 
-    fn process_compress(&mut self, state: &mut State) -> u16 {
+    /// Bytes the payload of the response may have, see
+    /// `ServerConfig::max_response`
+    fn max_payload(&self, config: &ServerConfig) -> usize {
+        let max = cmp::min(config.max_response, MAX_PAYLOAD as usize);
+        cmp::min(self.tx.payload.len(), max)
+    }
+
+    /// Writes a payload the service builds itself as the response's. One
+    /// over the max is no fault of the request, it's answered
+    /// ResponseTooLarge and counted as an internal error
+    fn write_payload(
+        &mut self,
+        payload: &[u8],
+        state: &mut State,
+        config: &ServerConfig,
+    ) -> (Response, u16) {
+        let max = self.max_payload(config);
+        if payload.len() > max {
+            tracing::error!(
+                "A response payload of {} bytes is over the max of {}",
+                payload.len(),
+                max
+            );
+            state.update_internal_error();
+            return (Response::ResponseTooLarge, 0);
+        }
+        self.tx.set_payload(payload).expect("within the max");
+        (Response::Ok, payload.len() as u16)
+    }
+
+    /// Associates the connection with the tenant of the token in the payload
+    fn process_authenticate(
+        &mut self,
+        state: &mut State,
+        connection: &mut State,
+        config: &ServerConfig,
+    ) -> (Response, u16) {
+        let tenant = match config.tenant_of(&self.rx.payload[..self.payload_len()]) {
+            Some(tenant) => tenant,
+            None => return (Response::Unauthorized, 0),
+        };
+        if !state.admit_tenant(tenant, config.max_tenants) {
+            return (Response::Forbidden, 0);
+        }
+        connection.set_tenant(tenant);
+        (Response::Ok, 0)
+    }
+
+    /// Writes the stats of the whole service to the file of `flush_dir` the
+    /// payload names, for authenticated connections only. The response
+    /// payload is the u32 count of bytes written
+    fn process_flushstats(
+        &mut self,
+        state: &mut State,
+        connection: &mut State,
+        config: &ServerConfig,
+    ) -> (Response, u16) {
+        if !connection.authenticated() {
+            return (Response::Unauthorized, 0);
+        }
+        let payload = &self.rx.payload[..self.payload_len()];
+        let path = match config.flush_dir.as_deref() {
+            Some(dir) => flush::flush_path(dir, payload),
+            None => None,
+        };
+        let path = match path {
+            Some(path) => path,
+            None => return (Response::Forbidden, 0),
+        };
+        match flush::flush_stats(&path, &state.snapshot()) {
+            Ok(written) => {
+                let written = (written as u32).to_be_bytes();
+                self.write_payload(&written, state, config)
+            }
+            Err(e) => {
+                tracing::error!("Failed to flush the stats to {}: {}", path.display(), e);
+                state.update_internal_error();
+                (Response::IoError, 0)
+            }
+        }
+    }
+
+    /// Resets the stats of the `ResetScope` in the payload, without one those
+    /// of the connection's tenant under tenancy, otherwise only the
+    /// connection's unless the service allows global resets
+    /// A token following the scope is looked up among those the connection
+    /// remembers first, a repeated one is answered as it was the first time
+    /// without resetting again
+    fn process_resetstats(
+        &mut self,
+        state: &mut State,
+        connection: &mut State,
+        config: &ServerConfig,
+    ) -> (Response, u16) {
+        let payload = &self.rx.payload[..self.payload_len()];
+        let token = match payload.len() {
+            0 | 1 => None,
+            len if len == 1 + RESET_TOKEN_LEN && config.reset_tokens > 0 => {
+                let mut token = [0; RESET_TOKEN_LEN];
+                token.copy_from_slice(&payload[1..]);
+                Some(token)
+            }
+            _ => return (Response::UnsupportedResetScope, 0),
+        };
+        if let Some(response) = token.and_then(|token| connection.reset_response(&token)) {
+            return (response, 0);
+        }
+        let response = reset_scope(payload.first(), state, connection, config);
+        if let Some(token) = token {
+            connection.remember_reset(token, response, config.reset_tokens);
+        }
+        (response, 0)
+    }
+
+    /// Handles the entries of the batch in order, each as a request of its
+    /// own, into the Batch of their responses. An entry that isn't a valid
+    /// Compress or Ping gets its error response and the next ones are still
+    /// handled, an entry cut short by the end of the payload is the last
+    fn process_batch(
+        &mut self,
+        state: &mut State,
+        connection: &mut State,
+        scheme: &dyn CompressionScheme,
+        config: &ServerConfig,
+    ) -> (Response, u16) {
+        if config.max_batch == 0 {
+            return (Response::Forbidden, 0);
+        }
+        let payload = &self.rx.payload[..self.payload_len()];
+        if BatchEntries::new(payload).count() > config.max_batch {
+            return (Response::BatchTooLarge, 0);
+        }
+        let limit = self.max_payload(config);
+        let mut batch = BatchWriter::new(&mut self.tx.payload[..limit]);
+        let mut tx = vec![0u8; MAX_MESSAGE_PADDED];
+        for entry in BatchEntries::new(payload) {
+            let len = match entry {
+                Ok(entry) => respond_entry(entry, &mut tx, state, connection, scheme, config),
+                Err(_) => {
+                    let mismatch = Response::MessageHeaderSizeMismatch;
+                    reject_entry(mismatch, 0, &mut tx, state, config)
+                }
+            };
+            if batch.push(&tx[..len]).is_err() {
+                return (Response::ResponseTooLarge, 0);
+            }
+        }
+        (Response::Batch, batch.len() as u16)
+    }
+
+    fn process_compress(
+        &mut self,
+        state: &mut State,
+        connection: &mut State,
+        scheme: &dyn CompressionScheme,
+        config: &ServerConfig,
+    ) -> (Response, u16) {
+        let limit = self.max_payload(config);
+        match self.compress_within(state, connection, scheme, config, limit) {
+            Ok(len) => (Response::Ok, len),
+            Err(_) => (Response::ResponseTooLarge, 0),
+        }
+    }
+
+    /// The compressed payload followed by the stats GetStats would report
+    /// right after, they account for this compression. An output leaving no
+    /// room for them within MAX_PAYLOAD is answered ResponseTooLarge
+    fn process_compress_with_stats(
+        &mut self,
+        state: &mut State,
+        connection: &mut State,
+        scheme: &dyn CompressionScheme,
+        config: &ServerConfig,
+    ) -> (Response, u16) {
+        let limit = self.max_payload(config).saturating_sub(STATS_LEN);
+        let len = match self.compress_within(state, connection, scheme, config, limit) {
+            Ok(len) => len as usize,
+            Err(_) => return (Response::ResponseTooLarge, 0),
+        };
+        let stats = reported_stats(state, connection, config);
+        self.tx.payload[len..len + STATS_LEN].copy_from_slice(stats.as_bytes());
+        (Response::Ok, (len + STATS_LEN) as u16)
+    }
+
+    /// Compresses the payload into the first `limit` bytes of tx, returning
+    /// the length of the output
+    fn compress_within(
+        &mut self,
+        state: &mut State,
+        connection: &mut State,
+        scheme: &dyn CompressionScheme,
+        config: &ServerConfig,
+        limit: usize,
+    ) -> Result<u16, CompressError> {
         // stats are not updated if the message is invalid
-        let payload_len = self.read_payload_len();
+        let payload_len = self.payload_len();
         let the_rx = &self.rx.payload[..payload_len];
-        let the_tx = &mut self.tx.payload;
-        match compress_message(the_rx, the_tx) {
-            None => 0,
-            Some(compressed_len) => {
-                state.update_ratio(payload_len, compressed_len);
-                compressed_len as u16
+        let the_tx = &mut self.tx.payload[..limit];
+        match scheme.compress_outcome(the_rx, the_tx) {
+            Err(e) => Err(e),
+            Ok(outcome) => {
+                let policy = &config.ratio_policy;
+                state.update_ratio_with(policy, payload_len, outcome.len);
+                state.update_outcome(&outcome);
+                if config.tenancy() {
+                    let tenant = connection.tenant();
+                    state.update_tenant_ratio(tenant, policy, payload_len, outcome.len);
+                }
+                connection.update_ratio_with(policy, payload_len, outcome.len);
+                connection.update_outcome(&outcome);
+                Ok(outcome.len as u16)
             }
         }
     }
 }
 
+impl<Rx: ByteSlice, Tx: ByteSliceMut> Connection<Rx, Tx> {
+    /// The output is limited to MAX_PAYLOAD however large tx is, decompression
+    /// stops as soon as that limit would be exceeded
+    /// Under strict enforcement, only the payload Compress would send for
+    /// the output is accepted
+    fn process_decompress(
+        &mut self,
+        scheme: &dyn CompressionScheme,
+        config: &ServerConfig,
+    ) -> (Response, u16) {
+        let payload_len = self.payload_len();
+        let the_rx = &self.rx.payload[..payload_len];
+        let limit = self.max_payload(config);
+        let the_tx = &mut self.tx.payload[..limit];
+        match scheme.decompress(the_rx, the_tx) {
+            Ok(len)
+                if config.enforcement == Enforcement::Strict
+                    && !is_canonical(scheme, &the_tx[..len], the_rx) =>
+            {
+                (Response::NonCanonicalEncoding, 0)
+            }
+            Ok(len) => (Response::Ok, len as u16),
+            Err(DecompressError::OutputTooLarge) => (Response::ResponseTooLarge, 0),
+            Err(_) => (Response::MalformedCompressedPayload, 0),
+        }
+    }
+}
+
+/// The response to an `entry` of a batch, written into `tx`, returning its
+/// length. Only Compress and Ping are handled, the others are rejected
+/// however valid, a Batch within a batch included
+fn respond_entry(
+    entry: &[u8],
+    tx: &mut [u8],
+    state: &mut State,
+    connection: &mut State,
+    scheme: &dyn CompressionScheme,
+    config: &ServerConfig,
+) -> usize {
+    let (request, sign) = match Message::parse(entry) {
+        Some(message) => (
+            Request::from_u16(message.header.code()),
+            message.header.sign(),
+        ),
+        None => return reject_entry(Response::MessageTooSmall, 0, tx, state, config),
+    };
+    match request {
+        Some(Request::Compress) | Some(Request::Ping) => {
+            Connection::new_with(entry, tx, entry.len()).respond(state, connection, scheme, config)
+        }
+        Some(request) if sign == MAGIC => {
+            let code = request as u8;
+            reject_entry(Response::UnsupportedRequestType, code, tx, state, config)
+        }
+        _ => reject_entry(Response::UnsupportedRequestType, 0, tx, state, config),
+    }
+}
+
+/// Writes `response` into `tx` for an entry of a batch that isn't handled,
+/// returning its length. Its payload is the code of the entry's request,
+/// `request`, if the service sends error details
+fn reject_entry(
+    response: Response,
+    request: u8,
+    tx: &mut [u8],
+    state: &mut State,
+    config: &ServerConfig,
+) -> usize {
+    state.update_error();
+    let mut message = Message::parse_mut(tx).unwrap();
+    let size = match message.payload.first_mut() {
+        Some(echo) if config.error_details => {
+            *echo = request;
+            1
+        }
+        _ => 0,
+    };
+    message.set_header(MAGIC, size, response as u16);
+    HEADER_SIZE + size as usize
+}
+
+/// Resets the stats of the ResetStats `scope`, the default one when the
+/// request has none
+fn reset_scope(
+    scope: Option<&u8>,
+    state: &mut State,
+    connection: &mut State,
+    config: &ServerConfig,
+) -> Response {
+    let scope = match scope {
+        None if config.tenancy() => ResetScope::Tenant,
+        None if config.allow_global_reset => ResetScope::Global,
+        None => ResetScope::Connection,
+        Some(&scope) => match ResetScope::from_u8(scope) {
+            Some(scope) => scope,
+            None => return Response::UnsupportedResetScope,
+        },
+    };
+    match scope {
+        ResetScope::Connection => connection.reset(),
+        ResetScope::Global if config.allow_global_reset => state.reset(),
+        ResetScope::Global => return Response::Forbidden,
+        ResetScope::Tenant => state.reset_tenant(connection.tenant()),
+    }
+    Response::Ok
+}
+
+/// The stats a GetStats on `connection` reports, from a snapshot so read,
+/// sent and ratio always come from the same moment. Under tenancy only those
+/// of the connection's tenant
+fn reported_stats(state: &mut State, connection: &State, config: &ServerConfig) -> Stats {
+    if config.tenancy() {
+        state.tenant_stats(connection.tenant())
+    } else {
+        state.snapshot().stats
+    }
+}
+
+/// The status a Health request reports and its detail, the bytes of the
+/// request itself, `own`, left out of those in flight
+fn health(state: &State, config: &ServerConfig, own: usize) -> (HealthStatus, String) {
+    let held = state.in_flight().bytes().saturating_sub(own);
+    let budget = config.memory_budget;
+    let (open, limit) = (state.connections().active(), config.max_connections);
+    if state.draining().is_draining() {
+        (HealthStatus::Draining, String::from("shutting down"))
+    } else if budget > 0 && held > 0 && held + MAX_MESSAGE > budget {
+        let detail = format!("{} bytes in flight of a budget of {}", held, budget);
+        (HealthStatus::Overloaded, detail)
+    } else if limit > 0 && open >= limit {
+        let detail = format!("{} connections open of a limit of {}", open, limit);
+        (HealthStatus::Overloaded, detail)
+    } else if state.internal_error() > 0 {
+        let detail = format!("{} internal errors", state.internal_error());
+        (HealthStatus::Degraded, detail)
+    } else {
+        (HealthStatus::Ready, String::new())
+    }
+}
+
+/// Whether `compressed` is what `scheme` compresses `output` to
+fn is_canonical(scheme: &dyn CompressionScheme, output: &[u8], compressed: &[u8]) -> bool {
+    let mut canonical = vec![0u8; scheme.bound(output.len())];
+    matches!(scheme.compress(output, &mut canonical), Ok(len) if canonical[..len] == *compressed)
+}
+
+/// Why `handle_request` couldn't answer a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandleError {
+    /// The response buffer can't hold even a header
+    ResponseBufferTooSmall { len: usize },
+}
+
+impl fmt::Display for HandleError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HandleError::ResponseBufferTooSmall { len } => write!(
+                fmt,
+                "a response buffer of {} bytes is smaller than a {} byte header",
+                len, HEADER_SIZE
+            ),
+        }
+    }
+}
+
+impl Error for HandleError {}
+
+/// Answers `request`, the bytes of one message as read, into `response_buf`
+/// and returns the length of the response, validating, dispatching,
+/// compressing and updating `state` exactly as the `Server` does
+///
+/// This is the stable extension point for embedders doing their own IO, and
+/// the one the `Server` itself goes through: whatever frames the messages,
+/// each is answered by this function alone. A `response_buf` of
+/// `MAX_MESSAGE_PADDED` bytes holds any response, a smaller one gets the
+/// error responses a smaller `Connection` tx would
+///
+/// # Example
+/// ```
+/// use service::{handle_request, ServerConfig, State};
+///
+/// let mut state = State::new();
+/// let mut response = [0u8; 64];
+/// let request = [83u8, 84, 82, 89, 0, 4, 0, 4, b'a', b'a', b'a', b'b'];
+/// let len = handle_request(&request, &mut response, &mut state, &ServerConfig::default())
+///     .unwrap();
+/// assert_eq!(&response[8..len], b"3ab");
+/// ```
+pub fn handle_request(
+    request: &[u8],
+    response_buf: &mut [u8],
+    state: &mut State,
+    config: &ServerConfig,
+) -> Result<usize, HandleError> {
+    handle_request_scoped(request, response_buf, state, &mut State::new(), config)
+}
+
+/// `handle_request`, with `connection` the stats of the connection the
+/// request was received on, see `Connection::create_response_scoped`
+pub fn handle_request_scoped(
+    request: &[u8],
+    response_buf: &mut [u8],
+    state: &mut State,
+    connection: &mut State,
+    config: &ServerConfig,
+) -> Result<usize, HandleError> {
+    if response_buf.len() < HEADER_SIZE {
+        let len = response_buf.len();
+        return Err(HandleError::ResponseBufferTooSmall { len });
+    }
+    // a message must be at least the size of a header to be parsed, fewer
+    // bytes read are answered from a zeroed one, never looked past
+    let mut padded = [0u8; HEADER_SIZE];
+    let rx = if request.len() < HEADER_SIZE {
+        padded[..request.len()].copy_from_slice(request);
+        &padded[..]
+    } else {
+        request
+    };
+    let len = Connection::new_with(rx, response_buf, request.len())
+        .create_response_scoped(state, connection, config);
+    Ok(len)
+}
+
+/// Writes the stats a CompressWithStats response of `len` bytes ends with
+/// afresh from `state`, any other response is left as it is. One handled
+/// against a `State::pending` reports the stats of the pending state,
+/// restated from a `State::view` of the shared state it reports those
+/// GetStats would right after it as usual
+pub fn restate_stats(
+    request: &[u8],
+    response_buf: &mut [u8],
+    len: usize,
+    state: &mut State,
+    connection: &State,
+    config: &ServerConfig,
+) {
+    // the code without its flags, a sequenced response is answered all the same
+    let answered = Message::parse(&response_buf[..len])
+        .is_some_and(|response| Response::from_u16(response.header.code()) == Some(Response::Ok));
+    if Request::of(request) != Some(Request::CompressWithStats) || !answered {
+        return;
+    }
+    let stats = reported_stats(state, connection, config);
+    response_buf[len - STATS_LEN..len].copy_from_slice(stats.as_bytes());
+}
+
 impl<Rx: ByteSlice, Tx: ByteSliceMut> Connection<Rx, Tx> {
     #[allow(dead_code)]
     // Used in illustration example above
     pub fn parse_slices(rx: Rx, tx: Tx, len: usize) -> Connection<Rx, Tx> {
-        let rx = Message::parse(rx).unwrap();
-        let tx = Message::parse_mut(tx).unwrap();
-        let message_len = len;
-        Connection {
-            rx,
-            tx,
-            message_len,
-        }
+        Connection::new_with(rx, tx, len)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Connection, Request, Response, State};
-    use crate::stats::Stats;
+    use super::{
+        handle_request, CompressionScheme, Connection, Enforcement, HandleError, Request, Response,
+        ServerConfig, State,
+    };
+    use crate::message::{
+        encode_batch, with_sequence, Flag, Header, Message, HEADER_SIZE, MAGIC, SEQUENCE_LEN,
+    };
+    use crate::message::{CharPolicy, HealthStatus, MAX_MESSAGE, MAX_MESSAGE_PADDED, MAX_PAYLOAD};
+    use crate::stats::{Stats, STATS_LEN};
+    use crate::testing::wire::{self, message};
+    use crate::testing::{arbitrary_strategy, Validity, WireMessage};
+    use crate::{CompressError, CompressOutcome, DecompressError, Feature};
+    use proptest::prelude::*;
+    use std::{sync::Arc, time::Duration};
+    use zerocopy::AsBytes;
 
     fn test_response(bytes_read: usize, rx: &mut [u8], tx: &mut [u8]) -> usize {
         let mut state: State = Default::default();
@@ -131,7 +926,7 @@ mod tests {
 
     #[test]
     fn test_compress_invalid_characters() {
-        let mut rx = [83u8, 84, 82, 89, 0, 1, 0, 4, 65];
+        let mut rx = [83u8, 84, 82, 89, 0, 1, 0, 4, 33];
         let mut tx = [0u8; 9];
         let bytes_read = rx.len();
         let response_size = test_response(bytes_read, &mut rx, &mut tx);
@@ -140,6 +935,154 @@ mod tests {
         assert_eq!(tx[..response_size], result);
     }
 
+    #[test]
+    fn test_compress_invalid_character_categories() {
+        fn response_code(payload: &[u8]) -> u8 {
+            let mut rx = [83u8, 84, 82, 89, 0, 0, 0, Request::Compress as u8, 0, 0, 0];
+            rx[5] = payload.len() as u8;
+            rx[8..8 + payload.len()].copy_from_slice(payload);
+            let mut tx = [0u8; 11];
+            let bytes_read = 8 + payload.len();
+            let size = test_response(bytes_read, &mut rx[..bytes_read], &mut tx);
+            assert_eq!(size, 8);
+            tx[7]
+        }
+
+        let uppercase = Response::MessageContainsUppercaseCharacters as u8;
+        let digits = Response::MessageContainsDigits as u8;
+        let non_ascii = Response::MessageContainsNonAscii as u8;
+        let generic = Response::MessagePayloadContainsInvalidCharacters as u8;
+        assert_eq!(response_code(b"abC"), uppercase);
+        assert_eq!(response_code(b"ab3"), digits);
+        assert_eq!(response_code(b"ab\xFF"), non_ascii);
+        assert_eq!(response_code(b"aB3"), generic);
+        assert_eq!(response_code(b"a-b"), generic);
+    }
+
+    #[test]
+    fn test_compress_with_char_policy() {
+        let config = ServerConfig {
+            char_policy: CharPolicy::from_spec("a-z -").unwrap(),
+            ..Default::default()
+        };
+        let payload = b"well-known   words";
+        let mut rx = vec![83u8, 84, 82, 89, 0, payload.len() as u8, 0, 4];
+        rx.extend_from_slice(payload);
+        let mut tx = [0u8; 32];
+        let mut state = State::new();
+        let size = Connection::new_with(&rx[..], &mut tx[..], rx.len())
+            .create_response_with(&mut state, &config);
+        assert_eq!(&tx[..8], &[83u8, 84, 82, 89, 0, 17, 0, 0]);
+        assert_eq!(&tx[8..size], b"well-known3 words");
+
+        // the default policy still rejects the same payload
+        let size = Connection::new_with(&rx[..], &mut tx[..], rx.len()).create_response(&mut state);
+        let n = Response::MessagePayloadContainsInvalidCharacters as u8;
+        assert_eq!(&tx[..size], &[83u8, 84, 82, 89, 0, 0, 0, n]);
+
+        // uppercase is still reported precisely under a custom policy
+        let rx = [83u8, 84, 82, 89, 0, 3, 0, 4, 97, 32, 65];
+        let size = Connection::new_with(&rx[..], &mut tx[..], rx.len())
+            .create_response_with(&mut state, &config);
+        let n = Response::MessageContainsUppercaseCharacters as u8;
+        assert_eq!(&tx[..size], &[83u8, 84, 82, 89, 0, 0, 0, n]);
+    }
+
+    #[test]
+    fn test_compress_fold_case() {
+        let config = ServerConfig {
+            fold_case: true,
+            ..Default::default()
+        };
+        let mut tx = [0u8; 16];
+        let mut state = State::new();
+
+        let rx = [83u8, 84, 82, 89, 0, 5, 0, 4, 97, 97, 65, 65, 97];
+        let size = Connection::new_with(&rx[..], &mut tx[..], rx.len())
+            .create_response_with(&mut state, &config);
+        assert_eq!(&tx[..size], &[83u8, 84, 82, 89, 0, 2, 0, 0, 53, 97]);
+
+        let rx = [83u8, 84, 82, 89, 0, 6, 0, 4, 65, 65, 65, 98, 98, 98];
+        let size = Connection::new_with(&rx[..], &mut tx[..], rx.len())
+            .create_response_with(&mut state, &config);
+        assert_eq!(&tx[..size], &[83u8, 84, 82, 89, 0, 4, 0, 0, 51, 97, 51, 98]);
+
+        // digits are still rejected in case-insensitive mode
+        let rx = [83u8, 84, 82, 89, 0, 2, 0, 4, 65, 49];
+        let size = Connection::new_with(&rx[..], &mut tx[..], rx.len())
+            .create_response_with(&mut state, &config);
+        let n = Response::MessageContainsDigits as u8;
+        assert_eq!(&tx[..size], &[83u8, 84, 82, 89, 0, 0, 0, n]);
+
+        // uppercase is rejected by default
+        let rx = [83u8, 84, 82, 89, 0, 5, 0, 4, 97, 97, 65, 65, 97];
+        let size = Connection::new_with(&rx[..], &mut tx[..], rx.len()).create_response(&mut state);
+        let n = Response::MessageContainsUppercaseCharacters as u8;
+        assert_eq!(&tx[..size], &[83u8, 84, 82, 89, 0, 0, 0, n]);
+    }
+
+    #[test]
+    fn test_compress_min_run() {
+        let config = ServerConfig {
+            min_run: 2,
+            ..Default::default()
+        };
+        let rx = [83u8, 84, 82, 89, 0, 6, 0, 4, 97, 97, 98, 99, 99, 99];
+        let mut tx = [0u8; 16];
+        let mut state = State::new();
+        let size = Connection::new_with(&rx[..], &mut tx[..], rx.len())
+            .create_response_with(&mut state, &config);
+        assert_eq!(
+            &tx[..size],
+            &[83u8, 84, 82, 89, 0, 5, 0, 0, 50, 97, 98, 51, 99]
+        );
+    }
+
+    #[test]
+    fn test_compress_custom_scheme() {
+        /// Accepts anything and stores it reversed
+        struct Reverse;
+
+        impl CompressionScheme for Reverse {
+            fn compress(&self, rx: &[u8], tx: &mut [u8]) -> Result<usize, CompressError> {
+                let tx = tx
+                    .get_mut(..rx.len())
+                    .ok_or(CompressError::OutputTooSmall)?;
+                tx.iter_mut()
+                    .zip(rx.iter().rev())
+                    .for_each(|(t, r)| *t = *r);
+                Ok(rx.len())
+            }
+
+            fn decompress(&self, _rx: &[u8], _tx: &mut [u8]) -> Result<usize, DecompressError> {
+                Err(DecompressError::Malformed)
+            }
+
+            fn bound(&self, input_len: usize) -> usize {
+                input_len
+            }
+
+            fn validate_payload(&self, _payload: &[u8]) -> Response {
+                Response::Ok
+            }
+
+            fn name(&self) -> &'static str {
+                "reverse"
+            }
+        }
+
+        let config = ServerConfig {
+            scheme: Some(Arc::new(Reverse)),
+            ..Default::default()
+        };
+        let rx = [83u8, 84, 82, 89, 0, 3, 0, 4, 65, 49, 50];
+        let mut tx = [0u8; 16];
+        let mut state = State::new();
+        let size = Connection::new_with(&rx[..], &mut tx[..], rx.len())
+            .create_response_with(&mut state, &config);
+        assert_eq!(&tx[..size], &[83u8, 84, 82, 89, 0, 3, 0, 0, 50, 49, 65]);
+    }
+
     #[test]
     fn test_compress() {
         let request = Request::Compress as u8;
@@ -163,7 +1106,454 @@ mod tests {
         //     internal_error: 0,
         // };
         let stats = Stats::new_with(11, 0, 33);
-        let expected_state = State::new_with(stats, 3, 2, 0);
+        let mut expected_state = State::new_with(stats, 3, 2, 0);
+        expected_state.update_outcome(&CompressOutcome {
+            len: 2,
+            runs: 1,
+            longest_run: 3,
+            literals: 0,
+        });
+        expected_state.update_request(&Request::Compress);
+        assert_eq!(state, expected_state);
+    }
+
+    #[test]
+    fn test_leftover_rx() {
+        // rx still holds a longer message read before the one answered
+        fn respond(request: &[u8], bytes_read: usize, config: &ServerConfig) -> Vec<u8> {
+            let previous = message(Request::Compress as u16, &[b'z'; 64]);
+            let mut rx = vec![0u8; MAX_MESSAGE_PADDED];
+            rx[..previous.len()].copy_from_slice(&previous);
+            rx[..request.len()].copy_from_slice(request);
+            let mut tx = vec![0u8; MAX_MESSAGE_PADDED];
+            let mut state = State::new();
+            let len = Connection::new_with(&rx[..], &mut tx[..], bytes_read)
+                .create_response_with(&mut state, config);
+            tx.truncate(len);
+            tx
+        }
+        let ok = |payload: &[u8]| message(Response::Ok as u16, payload);
+        let config = ServerConfig::default();
+
+        let compress = message(Request::Compress as u16, b"aaab");
+        assert_eq!(respond(&compress, compress.len(), &config), ok(b"3ab"));
+        let decompress = message(Request::Decompress as u16, b"3a");
+        assert_eq!(respond(&decompress, decompress.len(), &config), ok(b"aaa"));
+        let sequenced = with_sequence(&compress, 7);
+        assert_eq!(
+            respond(&sequenced, sequenced.len(), &config),
+            with_sequence(&ok(b"3ab"), 7)
+        );
+        let batch = message(Request::Batch as u16, &encode_batch([&compress[..]]));
+        assert_eq!(
+            respond(&batch, batch.len(), &config),
+            message(Response::Batch as u16, &encode_batch([&ok(b"3ab")[..]]))
+        );
+
+        // a header declaring more than was read isn't made up for by the
+        // bytes left over, nor is their count
+        let mut short = compress.clone();
+        short[5] = 10;
+        let details = ServerConfig {
+            error_details: true,
+            ..ServerConfig::default()
+        };
+        let mismatch = message(
+            Response::MessageHeaderSizeMismatch as u16,
+            b"header.size=10 but payload=4 bytes\x04",
+        );
+        assert_eq!(respond(&short, short.len(), &details), mismatch);
+
+        // nor is a count of bytes read past the end of rx
+        let mut rx = compress.clone();
+        let mut tx = vec![0u8; MAX_MESSAGE_PADDED];
+        let mut connection = Connection::new_with(&mut rx[..], &mut tx[..], 100);
+        assert_eq!(connection.read_payload_len(), 4);
+        let len = connection.create_response(&mut State::new());
+        assert_eq!(tx[..len], ok(b"3ab")[..]);
+    }
+
+    #[test]
+    fn test_stale_tx() {
+        // the buffers of a connection, reused from one request to the next
+        let mut rx = vec![0u8; MAX_MESSAGE_PADDED];
+        let mut tx = vec![0u8; MAX_MESSAGE_PADDED];
+        let mut state = State::new();
+        let mut respond = |request: &[u8], rx: &mut [u8], tx: &mut [u8]| {
+            rx[..request.len()].copy_from_slice(request);
+            Connection::new_with(&rx[..], tx, request.len()).create_response(&mut state)
+        };
+        let payload: Vec<u8> = b"xy".iter().copied().cycle().take(8000).collect();
+        let compress = Header::request(Request::Compress, payload.len() as u16).unwrap();
+        let compress = [compress.as_bytes(), &payload].concat();
+        let len = respond(&compress, &mut rx, &mut tx);
+        assert_eq!(tx[HEADER_SIZE..len], payload[..]);
+
+        // the Ping response is its header alone, the payload left in tx
+        // past it is neither declared nor returned
+        let ping = Header::request(Request::Ping, 0).unwrap();
+        let len = respond(ping.as_bytes(), &mut rx, &mut tx);
+        assert_eq!(len, HEADER_SIZE);
+        let pong = Header::response(Response::Ok, 0).unwrap();
+        assert_eq!(tx[..len], *pong.as_bytes());
+        let reply = Message::parse(&tx[..]).unwrap();
+        assert_eq!(reply.header.size(), 0);
+        assert!(reply.payload_slice().is_empty());
+        assert_eq!(
+            format!("{}", reply),
+            format!("{}", Message::parse(&tx[..len]).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_compress_outcome_counters() {
+        let mut state = State::new();
+        for payload in &[&b"aaaaabbbbbbaaabb"[..], b"aaaaaaaaaaxy"] {
+            let mut rx = vec![83u8, 84, 82, 89, 0, 0, 0, Request::Compress as u8];
+            rx[4..6].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+            rx.extend_from_slice(payload);
+            let mut tx = [0u8; 32];
+            Connection::new_with(&rx[..], &mut tx[..], rx.len()).create_response(&mut state);
+        }
+        assert_eq!(state.runs(), 4);
+        assert_eq!(state.longest_run(), 10);
+        assert_eq!(state.literals(), 4);
+        assert_eq!(state.stored_responses(), 0);
+
+        let rx = [
+            83u8,
+            84,
+            82,
+            89,
+            0,
+            3,
+            0,
+            Request::Compress as u8,
+            97,
+            98,
+            99,
+        ];
+        let mut tx = [0u8; 32];
+        let size = Connection::new_with(&rx[..], &mut tx[..], rx.len()).create_response(&mut state);
+        // a payload without repeats is stored, only the stats tell, the
+        // response is the plain Ok an old client expects
+        assert_eq!(&tx[..size], &[83u8, 84, 82, 89, 0, 3, 0, 0, 97, 98, 99]);
+        assert_eq!(state.stored_responses(), 1);
+        assert_eq!(state.literals(), 7);
+
+        state.reset();
+        assert_eq!(
+            (state.runs(), state.longest_run(), state.literals()),
+            (0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn test_decompress() {
+        fn decompress(payload: &[u8], tx: &mut [u8]) -> usize {
+            let mut rx = vec![83u8, 84, 82, 89, 0, 0, 0, Request::Decompress as u8];
+            rx[4..6].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+            rx.extend_from_slice(payload);
+            let mut state = State::new();
+            Connection::new_with(&rx[..], tx, rx.len()).create_response(&mut state)
+        }
+
+        let mut tx = vec![0u8; MAX_MESSAGE_PADDED];
+        let size = decompress(b"5a3b", &mut tx);
+        assert_eq!(&tx[..size], b"STRY\x00\x08\x00\x00aaaaabbb");
+
+        // exactly MAX_PAYLOAD
+        let size = decompress(b"8192a", &mut tx);
+        assert_eq!(size, MAX_MESSAGE);
+        assert_eq!(&tx[..8], b"STRY\x20\x00\x00\x00");
+
+        let too_large = Response::ResponseTooLarge as u8;
+        for payload in &[&b"8193a"[..], b"4096a4097b", b"9999a9999b"] {
+            let size = decompress(payload, &mut tx);
+            assert_eq!(&tx[..size], &[83u8, 84, 82, 89, 0, 0, 0, too_large]);
+        }
+
+        let malformed = Response::MalformedCompressedPayload as u8;
+        for payload in &[&b"99999999a"[..], b"0a", b"3", b"3A"] {
+            let size = decompress(payload, &mut tx);
+            assert_eq!(&tx[..size], &[83u8, 84, 82, 89, 0, 0, 0, malformed]);
+        }
+
+        let size = decompress(b"", &mut tx);
+        let n = Response::CompressionRequestRequiresNonZeroLength as u8;
+        assert_eq!(&tx[..size], &[83u8, 84, 82, 89, 0, 0, 0, n]);
+    }
+
+    #[test]
+    fn test_binary() {
+        fn request(code: Request, payload: &[u8], tx: &mut [u8]) -> usize {
+            let rx = wire::request(code, payload);
+            let mut state = State::new();
+            Connection::new_with(&rx[..], tx, rx.len()).create_response(&mut state)
+        }
+
+        let mut tx = vec![0u8; MAX_MESSAGE_PADDED];
+        // uppercase, digits and non-ascii bytes are fine
+        let size = request(Request::CompressBinary, b"AAAAA3\xFF", &mut tx);
+        assert_eq!(
+            &tx[..size],
+            b"STRY\x00\x09\x00\x00\xFF\x05\x00A3\xFF\x01\x00\xFF"
+        );
+        let size = request(Request::DecompressBinary, b"\xFF\x05\x00A3", &mut tx);
+        assert_eq!(&tx[..size], b"STRY\x00\x06\x00\x00AAAAA3");
+
+        // the request fits but its worst-case expanded response would not
+        let payload = [0xFFu8, 0].repeat(MAX_PAYLOAD as usize / 4);
+        let size = request(Request::CompressBinary, &payload, &mut tx);
+        let too_large = Response::ResponseTooLarge as u8;
+        assert_eq!(&tx[..size], &[83u8, 84, 82, 89, 0, 0, 0, too_large]);
+
+        let size = request(Request::DecompressBinary, b"\xFF\x00", &mut tx);
+        let malformed = Response::MalformedCompressedPayload as u8;
+        assert_eq!(&tx[..size], &[83u8, 84, 82, 89, 0, 0, 0, malformed]);
+    }
+
+    #[test]
+    fn test_enforcement() {
+        fn message(code: Request, size: u16, flags: u8, payload: &[u8]) -> Vec<u8> {
+            wire::raw(MAGIC, size, (flags as u16) << 8 | code as u16, payload)
+        }
+
+        let permissive = ServerConfig {
+            strict_flags: false,
+            ..Default::default()
+        };
+        let strict = ServerConfig {
+            enforcement: Enforcement::Strict,
+            ..permissive.clone()
+        };
+        let ok = Response::Ok;
+        // the same messages under both modes
+        let cases = [
+            (message(Request::Ping, 0, 0, b""), ok, ok),
+            (
+                message(Request::Ping, 0, 0x80, b""),
+                ok,
+                Response::UnsupportedFlags,
+            ),
+            (
+                message(Request::Ping, 0, 0, b"\0\0"),
+                Response::MessageHeaderSizeMismatch,
+                Response::TrailingBytes,
+            ),
+            (
+                message(Request::Compress, 4, 0, b"aaaaa"),
+                Response::MessageHeaderSizeMismatch,
+                Response::TrailingBytes,
+            ),
+            // cut short is a mismatch either way
+            (
+                message(Request::Compress, 5, 0, b"aaa"),
+                Response::MessageHeaderSizeMismatch,
+                Response::MessageHeaderSizeMismatch,
+            ),
+            (message(Request::Decompress, 4, 0, b"5a3b"), ok, ok),
+            (message(Request::Decompress, 2, 0, b"aa"), ok, ok),
+            (
+                message(Request::Decompress, 4, 0, b"1a2a"),
+                ok,
+                Response::NonCanonicalEncoding,
+            ),
+            (
+                message(Request::Decompress, 3, 0, b"03a"),
+                ok,
+                Response::NonCanonicalEncoding,
+            ),
+            (
+                message(Request::Decompress, 4, 0, b"3a3a"),
+                ok,
+                Response::NonCanonicalEncoding,
+            ),
+            (
+                message(Request::Decompress, 3, 0, b"aaa"),
+                ok,
+                Response::NonCanonicalEncoding,
+            ),
+            (
+                message(Request::DecompressBinary, 5, 0, b"\xFF\x05\x00A3"),
+                ok,
+                ok,
+            ),
+            (
+                message(Request::DecompressBinary, 6, 0, b"AAAAA3"),
+                ok,
+                Response::NonCanonicalEncoding,
+            ),
+            // not a matter of enforcement
+            (
+                message(Request::Decompress, 2, 0, b"0a"),
+                Response::MalformedCompressedPayload,
+                Response::MalformedCompressedPayload,
+            ),
+        ];
+        for (rx, expected_permissive, expected_strict) in cases {
+            for (config, expected) in [
+                (&permissive, expected_permissive),
+                (&strict, expected_strict),
+            ] {
+                let mut tx = vec![0u8; MAX_MESSAGE_PADDED];
+                let mut state = State::new();
+                let size = Connection::new_with(&rx[..], &mut tx[..], rx.len())
+                    .create_response_with(&mut state, config);
+                assert_eq!(
+                    tx[7], expected as u8,
+                    "{:?} under {:?}",
+                    rx, config.enforcement
+                );
+                if expected != ok {
+                    assert_eq!(size, 8);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_details() {
+        fn respond(rx: &[u8], config: &ServerConfig) -> (u16, Vec<u8>) {
+            let mut rx = rx.to_vec();
+            let len = rx.len();
+            rx.resize(std::cmp::max(len, HEADER_SIZE), 0);
+            let mut tx = vec![0u8; MAX_MESSAGE_PADDED];
+            let size = Connection::new_with(&rx[..], &mut tx[..], len)
+                .create_response_with(&mut State::new(), config);
+            let message = Message::parse(&tx[..size]).unwrap();
+            (message.header.code(), message.payload_slice().to_vec())
+        }
+        let detailed = ServerConfig {
+            error_details: true,
+            ..Default::default()
+        };
+        let strict = ServerConfig {
+            enforcement: Enforcement::Strict,
+            ..detailed.clone()
+        };
+        let code = |response: Response| response as u16;
+
+        let short = Header::raw(MAGIC, 5, Request::Compress as u16);
+        let short = [short.as_bytes(), b"abc"].concat();
+        let bad_magic = Header::raw(MAGIC + 1, 0, Request::Ping as u16);
+        let unknown = Header::raw(MAGIC, 0, 99);
+        let empty = Header::raw(MAGIC, 0, Request::Compress as u16);
+        let long = [
+            Header::raw(MAGIC, 4, Request::Compress as u16).as_bytes(),
+            b"aaaaa",
+        ]
+        .concat();
+        let cases = [
+            (
+                &short[..],
+                &detailed,
+                Response::MessageHeaderSizeMismatch,
+                &b"header.size=5 but payload=3 bytes\x04"[..],
+            ),
+            (
+                &short,
+                &strict,
+                Response::MessageHeaderSizeMismatch,
+                b"header.size=5 but payload=3 bytes\x04",
+            ),
+            (
+                &long,
+                &strict,
+                Response::TrailingBytes,
+                b"header.size=4 but payload=5 bytes\x04",
+            ),
+            (
+                bad_magic.as_bytes(),
+                &detailed,
+                Response::MessageHeaderHasBadMagic,
+                b"header.sign=0x5354525a but magic=0x53545259\x00",
+            ),
+            (
+                bad_magic.as_bytes(),
+                &strict,
+                Response::MessageHeaderHasBadMagic,
+                b"header.sign=0x5354525a but magic=0x53545259\x00",
+            ),
+            (
+                &MAGIC.to_be_bytes(),
+                &detailed,
+                Response::MessageTooSmall,
+                b"4 bytes read of a 8 byte header\x00",
+            ),
+            (
+                unknown.as_bytes(),
+                &detailed,
+                Response::UnsupportedRequestType,
+                b"request code 99 is unknown\x00",
+            ),
+            (
+                empty.as_bytes(),
+                &detailed,
+                Response::CompressionRequestRequiresNonZeroLength,
+                b"header.size=0 but Compress requires a payload\x04",
+            ),
+        ];
+        for (rx, config, response, detail) in cases {
+            // the detail then the request's code, 0 if it can't be told
+            assert_eq!(respond(rx, config), (code(response), detail.to_vec()));
+            // bare headers unless the service sends details
+            let plain = ServerConfig {
+                error_details: false,
+                ..config.clone()
+            };
+            assert_eq!(respond(rx, &plain), (code(response), vec![]));
+        }
+
+        // of the payload only the first byte rejected is told, and where
+        let compress = [
+            Header::request(Request::Compress, 3).unwrap().as_bytes(),
+            b"aB3",
+        ]
+        .concat();
+        let invalid = code(Response::MessagePayloadContainsInvalidCharacters);
+        let detail = b"invalid byte 0x42 at offset 1\x04".to_vec();
+        assert_eq!(respond(&compress, &detailed), (invalid, detail));
+        let compress = [
+            Header::request(Request::Compress, 4).unwrap().as_bytes(),
+            b"abc\xFF",
+        ]
+        .concat();
+        let non_ascii = code(Response::MessageContainsNonAscii);
+        let detail = b"invalid byte 0xff at offset 3\x04".to_vec();
+        assert_eq!(respond(&compress, &detailed), (non_ascii, detail));
+        // nor are the successes
+        let ping = Header::request(Request::Ping, 0).unwrap();
+        assert_eq!(respond(ping.as_bytes(), &detailed), (0, vec![]));
+        // and a detail never outgrows the response buffer
+        let mut tx = [0u8; HEADER_SIZE + 4];
+        let size = Connection::new_with(&short[..], &mut tx[..], short.len())
+            .create_response_with(&mut State::new(), &detailed);
+        assert_eq!(&tx[HEADER_SIZE..size], b"hea\x04");
+    }
+
+    #[test]
+    fn test_get_config() {
+        let rx = [83u8, 84, 82, 89, 0, 0, 0, Request::GetConfig as u8];
+        let mut tx = [0u8; 22];
+        let mut state = State::new();
+        let config = ServerConfig {
+            idle_timeout: Some(Duration::from_secs(300)),
+            ..Default::default()
+        };
+        let size = Connection::new_with(&rx[..], &mut tx[..], rx.len())
+            .create_response_with(&mut state, &config);
+        assert_eq!(size, 22);
+        assert_eq!(
+            &tx[..size],
+            &[
+                83u8, 84, 82, 89, 0, 14, 0, 0, //
+                1, 178, 32, 0, 32, 8, 0, 0, 1, 44, 0, 0, 0, 0
+            ]
+        );
+        // nothing is accounted for besides the request
+        let mut expected_state = State::new();
+        expected_state.update_request(&Request::GetConfig);
         assert_eq!(state, expected_state);
     }
 
@@ -191,7 +1581,8 @@ mod tests {
         //     internal_error: 0,
         // };
         let stats = Stats::new_with(bytes_read as u32, 0, 0);
-        let expected_state = State::new_with(stats, 0, 0, 0);
+        let mut expected_state = State::new_with(stats, 0, 0, 0);
+        expected_state.update_request(&Request::Ping);
         assert_eq!(state, expected_state);
     }
 
@@ -220,6 +1611,385 @@ mod tests {
                 0, 0, 0, 11, 0, 0, 0, 10, 33
             ]
         );
+
+        // the same stats, prefixed by their layout version and widened
+        let rx = [83u8, 84, 82, 89, 0, 0, 0, Request::GetStatsV2 as u8];
+        let mut tx = [0u8; 26];
+        let size = Connection::new_with(&rx[..], &mut tx[..], rx.len()).create_response(&mut state);
+        assert_eq!(size, 26);
+        assert_eq!(
+            &tx[..size],
+            &[
+                83u8, 84, 82, 89, 0, 18, 0, 0, 2, //
+                0, 0, 0, 0, 0, 0, 0, 11, 0, 0, 0, 0, 0, 0, 0, 10, 33
+            ]
+        );
+        let stats = Stats::parse_versioned(&tx[8..size]).unwrap();
+        assert_eq!((stats.version(), stats.read(), stats.sent()), (2, 11, 10));
+    }
+
+    #[test]
+    fn test_compress_with_stats() {
+        fn respond(request: Request, payload: &[u8], state: &mut State) -> Vec<u8> {
+            let rx = wire::request(request, payload);
+            let mut tx = [0u8; MAX_MESSAGE_PADDED];
+            state.update_read(rx.len());
+            let size = Connection::new_with(&rx[..], &mut tx[..], rx.len()).create_response(state);
+            state.update_sent(size);
+            tx[..size].to_vec()
+        }
+        let mut state = State::new();
+        let response = respond(Request::CompressWithStats, b"aaaaabbb", &mut state);
+        let message = Message::parse(&response[..]).unwrap();
+        assert_eq!(message.header.code(), Response::Ok as u16);
+        // the compressed bytes then the stats, at a fixed offset from the end
+        let payload = message.payload_slice();
+        let (compressed, stats) = payload.split_at(payload.len() - STATS_LEN);
+        assert_eq!(compressed, b"5a3b");
+        // including the compression, not the response carrying them
+        assert_eq!(*Stats::parse(stats).unwrap(), Stats::new_with(16, 0, 50));
+        assert_eq!(state.snapshot().requests(&Request::CompressWithStats), 1);
+
+        // the same stats as GetStats but for the bytes exchanged since
+        let response = respond(Request::GetStats, b"", &mut state);
+        let read = 16 + HEADER_SIZE as u32;
+        let sent = (HEADER_SIZE + 4 + STATS_LEN) as u32;
+        assert_eq!(
+            &response[HEADER_SIZE..],
+            Stats::new_with(read, sent, 50).as_bytes()
+        );
+
+        // validated as Compress
+        let response = respond(Request::CompressWithStats, b"aB3", &mut state);
+        let code = Response::MessagePayloadContainsInvalidCharacters as u16;
+        assert_eq!(response, Header::raw(MAGIC, 0, code).as_bytes());
+        let response = respond(Request::CompressWithStats, b"", &mut state);
+        let code = Response::CompressionRequestRequiresNonZeroLength as u16;
+        assert_eq!(response, Header::raw(MAGIC, 0, code).as_bytes());
+        // an output without room for the stats isn't sent
+        let alternating: Vec<u8> = b"ab"
+            .iter()
+            .cycle()
+            .take(MAX_PAYLOAD as usize)
+            .copied()
+            .collect();
+        let response = respond(Request::CompressWithStats, &alternating, &mut state);
+        let code = Response::ResponseTooLarge as u16;
+        assert_eq!(response, Header::raw(MAGIC, 0, code).as_bytes());
+        let response = respond(Request::CompressWithStats, &alternating[..8000], &mut state);
+        assert_eq!(response.len(), HEADER_SIZE + 8000 + STATS_LEN);
+    }
+
+    #[test]
+    fn test_health() {
+        fn health(state: &mut State, config: &ServerConfig) -> (HealthStatus, String) {
+            let rx = Header::request(Request::Health, 0).unwrap();
+            let mut tx = [0u8; MAX_MESSAGE_PADDED];
+            let size = Connection::new_with(rx.as_bytes(), &mut tx[..], HEADER_SIZE)
+                .create_response_with(state, config);
+            let message = Message::parse(&tx[..size]).unwrap();
+            assert_eq!(message.header.code(), Response::Ok as u16);
+            let (status, detail) = message.payload_slice().split_first().unwrap();
+            let detail = String::from_utf8(detail.to_vec()).unwrap();
+            (HealthStatus::from_u8(*status).unwrap(), detail)
+        }
+        let config = ServerConfig {
+            memory_budget: MAX_MESSAGE + 100,
+            ..Default::default()
+        };
+        let mut state = State::new();
+        assert_eq!(
+            health(&mut state, &config),
+            (HealthStatus::Ready, "".into())
+        );
+        assert_eq!(state.snapshot().requests(&Request::Health), 1);
+
+        // a failed flush degrades the service, for good
+        state.update_internal_error();
+        let degraded = (HealthStatus::Degraded, "1 internal errors".into());
+        assert_eq!(health(&mut state, &config), degraded);
+
+        // overloaded once a message of MAX_MESSAGE would be shed, the bytes of
+        // the Health request itself left out
+        let in_flight = state.in_flight();
+        let own = in_flight.reserve(HEADER_SIZE, 0).unwrap();
+        let held = in_flight.reserve(100, 0).unwrap();
+        assert_eq!(health(&mut state, &config), degraded);
+        let more = in_flight.reserve(1, 0).unwrap();
+        let overloaded = health(&mut state, &config);
+        assert_eq!(overloaded.0, HealthStatus::Overloaded);
+        assert_eq!(
+            overloaded.1,
+            format!("101 bytes in flight of a budget of {}", MAX_MESSAGE + 100)
+        );
+        // never without a budget
+        assert_eq!(health(&mut state, &ServerConfig::default()), degraded);
+        drop(more);
+
+        // or once the connections open reach the limit, the next one would
+        // wait to be accepted
+        let limited = ServerConfig {
+            max_connections: 2,
+            ..config.clone()
+        };
+        let gauge = state.connections();
+        let first = gauge.accept();
+        assert_eq!(health(&mut state, &limited), degraded);
+        let second = gauge.accept();
+        let overloaded = (
+            HealthStatus::Overloaded,
+            "2 connections open of a limit of 2".into(),
+        );
+        assert_eq!(health(&mut state, &limited), overloaded);
+        drop((first, second));
+        assert_eq!(health(&mut state, &limited), degraded);
+
+        // draining is reported over anything else, by clones of the state too
+        state.clone().draining().start();
+        let draining = (HealthStatus::Draining, "shutting down".into());
+        assert_eq!(health(&mut state, &config), draining);
+        drop((own, held));
+        state.reset();
+        assert_eq!(health(&mut state, &config), draining);
+    }
+
+    #[test]
+    fn test_max_response() {
+        /// Accepts anything and stores each byte four times over
+        struct Quadruple;
+
+        impl CompressionScheme for Quadruple {
+            fn compress(&self, rx: &[u8], tx: &mut [u8]) -> Result<usize, CompressError> {
+                let tx = tx
+                    .get_mut(..rx.len() * 4)
+                    .ok_or(CompressError::OutputTooSmall)?;
+                tx.iter_mut()
+                    .zip(rx.iter().flat_map(|r| [*r; 4]))
+                    .for_each(|(t, r)| *t = r);
+                Ok(rx.len() * 4)
+            }
+
+            fn decompress(&self, _rx: &[u8], _tx: &mut [u8]) -> Result<usize, DecompressError> {
+                Err(DecompressError::Malformed)
+            }
+
+            fn bound(&self, input_len: usize) -> usize {
+                input_len * 4
+            }
+
+            fn validate_payload(&self, _payload: &[u8]) -> Response {
+                Response::Ok
+            }
+
+            fn name(&self) -> &'static str {
+                "quadruple"
+            }
+        }
+
+        fn respond(rx: &[u8], state: &mut State, config: &ServerConfig) -> Vec<u8> {
+            let mut tx = [0u8; MAX_MESSAGE_PADDED];
+            let size =
+                Connection::new_with(rx, &mut tx[..], rx.len()).create_response_with(state, config);
+            tx[..size].to_vec()
+        }
+        let request = wire::request;
+        let too_large = Header::raw(MAGIC, 0, Response::ResponseTooLarge as u16);
+
+        // the stats outgrowing the max are the service's fault
+        let config = ServerConfig {
+            max_response: STATS_LEN,
+            scheme: Some(Arc::new(Quadruple)),
+            ..Default::default()
+        };
+        let mut state = State::new();
+        let stats = respond(&request(Request::GetStats, b""), &mut state, &config);
+        assert_eq!(stats.len(), HEADER_SIZE + STATS_LEN);
+        assert_eq!(state.internal_error(), 0);
+        let stats = respond(&request(Request::GetStatsV2, b""), &mut state, &config);
+        assert_eq!(stats, too_large.as_bytes());
+        assert_eq!(state.internal_error(), 1);
+        let ping = respond(&request(Request::Ping, b""), &mut state, &config);
+        assert_eq!(ping[7], Response::UnknownError as u8);
+
+        // an output outgrowing it is the request's, compressed within the max
+        // or not at all
+        let mut state = State::new();
+        let compressed = respond(&request(Request::Compress, b"ab"), &mut state, &config);
+        assert_eq!(&compressed[HEADER_SIZE..], b"aaaabbbb");
+        let compressed = respond(&request(Request::Compress, b"abc"), &mut state, &config);
+        assert_eq!(compressed, too_large.as_bytes());
+        assert_eq!(state.internal_error(), 0);
+        let config = ServerConfig {
+            max_response: 0,
+            ..config
+        };
+        let compressed = respond(&request(Request::Compress, b"a"), &mut state, &config);
+        assert_eq!(compressed, too_large.as_bytes());
+        assert_eq!(state.internal_error(), 0);
+
+        // details are truncated to fit
+        let config = ServerConfig {
+            max_response: 5,
+            error_details: true,
+            ..Default::default()
+        };
+        let mut state = State::new();
+        let rejected = respond(&request(Request::Ping, b"x"), &mut state, &config);
+        assert_eq!(&rejected[HEADER_SIZE..], b"head\x01");
+        state.draining().start();
+        let health = respond(&request(Request::Health, b""), &mut state, &config);
+        assert_eq!(health[HEADER_SIZE], HealthStatus::Draining as u8);
+        assert_eq!(&health[HEADER_SIZE + 1..], b"shut");
+        assert_eq!(state.internal_error(), 0);
+    }
+
+    #[test]
+    fn test_batch() {
+        fn respond(payload: &[u8], state: &mut State, config: &ServerConfig) -> Vec<u8> {
+            let rx = message(Request::Batch as u16, payload);
+            let mut tx = [0u8; MAX_MESSAGE_PADDED];
+            let size = Connection::new_with(&rx[..], &mut tx[..], rx.len())
+                .create_response_with(state, config);
+            tx[..size].to_vec()
+        }
+        let reply = |response: Response, payload: &[u8]| message(response as u16, payload);
+        let compress = |payload: &[u8]| message(Request::Compress as u16, payload);
+        let ping = message(Request::Ping as u16, b"");
+        let config = ServerConfig::default();
+
+        let mut state = State::new();
+        let entries = [
+            compress(b"aaaaabbb"),
+            ping.clone(),
+            // a malformed entry doesn't abort the ones after it
+            compress(b"aB3"),
+            message(Request::GetStats as u16, b""),
+            message(Request::Batch as u16, &encode_batch([&ping[..]])),
+            ping[..5].to_vec(),
+            [&ping[..], b"a"].concat(),
+            compress(b"abc"),
+        ];
+        let response = respond(
+            &encode_batch(entries.iter().map(|e| &e[..])),
+            &mut state,
+            &config,
+        );
+        let expected = [
+            reply(Response::Ok, b"5a3b"),
+            reply(Response::Ok, b""),
+            reply(Response::MessagePayloadContainsInvalidCharacters, b""),
+            reply(Response::UnsupportedRequestType, b""),
+            reply(Response::UnsupportedRequestType, b""),
+            reply(Response::MessageTooSmall, b""),
+            reply(Response::MessageHeaderSizeMismatch, b""),
+            reply(Response::Ok, b"abc"),
+        ];
+        let expected = encode_batch(expected.iter().map(|e| &e[..]));
+        assert_eq!(response, reply(Response::Batch, &expected));
+        // every entry is accounted for as a request of its own, only the
+        // valid ones are counted by kind
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.requests(&Request::Batch), 1);
+        assert_eq!(snapshot.requests(&Request::Compress), 2);
+        assert_eq!(snapshot.requests(&Request::Ping), 1);
+        assert_eq!(snapshot.errors, 5);
+        // 11 bytes compressed to 7
+        assert_eq!(snapshot.stats.ratio(), 36);
+
+        // an entry cut short by the end of the payload is the last
+        let mut payload = encode_batch([&ping[..], &ping[..]]);
+        payload.truncate(payload.len() - 3);
+        let expected = [
+            reply(Response::Ok, b""),
+            reply(Response::MessageHeaderSizeMismatch, b""),
+        ];
+        let expected = encode_batch(expected.iter().map(|e| &e[..]));
+        let response = respond(&payload, &mut state, &config);
+        assert_eq!(response, reply(Response::Batch, &expected));
+        // an empty batch is answered by an empty one
+        let response = respond(b"", &mut state, &config);
+        assert_eq!(response, reply(Response::Batch, b""));
+
+        // too many entries, none is handled
+        let pings = encode_batch([&ping[..], &ping[..], &ping[..]]);
+        let limited = ServerConfig {
+            max_batch: 2,
+            ..Default::default()
+        };
+        let before = state.snapshot().requests(&Request::Ping);
+        let response = respond(&pings, &mut state, &limited);
+        assert_eq!(response, reply(Response::BatchTooLarge, b""));
+        assert_eq!(state.snapshot().requests(&Request::Ping), before);
+        let off = ServerConfig {
+            max_batch: 0,
+            ..Default::default()
+        };
+        let response = respond(&pings, &mut state, &off);
+        assert_eq!(response, reply(Response::Forbidden, b""));
+        assert_eq!(off.limits().features() & Feature::BATCH, 0);
+    }
+
+    #[test]
+    fn test_sequenced() {
+        fn respond(rx: &[u8], tx_len: usize, state: &mut State) -> Vec<u8> {
+            let mut tx = vec![0u8; tx_len];
+            let size = Connection::new_with(rx, &mut tx[..], rx.len()).create_response(state);
+            tx[..size].to_vec()
+        }
+        let sequenced = |response: Response| (Flag::SEQUENCED as u16) << 8 | response as u16;
+        let compress = message(Request::Compress as u16, b"aaaaabbb");
+        let mut state = State::new();
+
+        // the response echoes the number before its payload
+        let response = respond(&with_sequence(&compress, 0xBEEF), 64, &mut state);
+        assert_eq!(response, message(sequenced(Response::Ok), b"\xBE\xEF5a3b"));
+        // and so do the errors
+        let invalid = message(Request::Compress as u16, b"aB3");
+        let response = respond(&with_sequence(&invalid, 1), 64, &mut state);
+        let code = sequenced(Response::MessagePayloadContainsInvalidCharacters);
+        assert_eq!(response, message(code, b"\x00\x01"));
+        // the number is part of the payload its size has to cover
+        let mut lying = with_sequence(&compress, 2);
+        lying[5] -= 1;
+        let response = respond(&lying, 64, &mut state);
+        let code = sequenced(Response::MessageHeaderSizeMismatch);
+        assert_eq!(response, message(code, b"\x00\x02"));
+
+        // a payload too short to hold a number has none to echo
+        let ping = message(Request::Ping as u16, b"");
+        let mut short = with_sequence(&ping, 4);
+        short.truncate(HEADER_SIZE + 1);
+        let response = respond(&short, 64, &mut state);
+        assert_eq!(response, message(Response::MessageTooSmall as u16, b""));
+        // nor does a bad magic
+        let mut bad_magic = with_sequence(&ping, 5);
+        bad_magic[0] = b'X';
+        let response = respond(&bad_magic, 64, &mut state);
+        let code = Response::MessageHeaderHasBadMagic as u16;
+        assert_eq!(response, message(code, b""));
+
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.requests(&Request::Compress), 1);
+        assert_eq!(snapshot.errors, 4);
+
+        // the largest sequenced response is built in place in a padded tx
+        let payload: Vec<u8> = b"ab"
+            .iter()
+            .cycle()
+            .take(MAX_PAYLOAD as usize - 2)
+            .copied()
+            .collect();
+        let largest = with_sequence(&message(Request::Compress as u16, &payload), 0xCAFE);
+        let response = respond(&largest, MAX_MESSAGE_PADDED, &mut state);
+        assert_eq!(response.len(), MAX_MESSAGE);
+        let response = Message::parse(&response[..]).unwrap();
+        assert_eq!(response.header.flags() & Flag::SEQUENCED, Flag::SEQUENCED);
+        assert_eq!(
+            Response::from_u16(response.header.code()),
+            Some(Response::Ok)
+        );
+        assert_eq!(&response.payload[..SEQUENCE_LEN], b"\xCA\xFE");
+        assert_eq!(&response.payload[SEQUENCE_LEN..], &payload[..]);
     }
 
     #[test]
@@ -248,4 +2018,328 @@ mod tests {
         assert_eq!(size, 8);
         assert_eq!(&tx[..size], &[83u8, 84, 82, 89, 0, 0, 0, 0]);
     }
+
+    #[test]
+    fn test_reset_stats_scope() {
+        fn reset(payload: &[u8], state: &mut State, config: &ServerConfig) -> (u8, State) {
+            let mut rx = vec![83u8, 84, 82, 89, 0, payload.len() as u8, 0];
+            rx.push(Request::ResetStats as u8);
+            rx.extend_from_slice(payload);
+            let mut tx = [0u8; 8];
+            let mut connection = State::new_with(Stats::new_with(8, 8, 0), 0, 0, 0);
+            Connection::new_with(&rx[..], &mut tx[..], rx.len()).create_response_scoped(
+                state,
+                &mut connection,
+                config,
+            );
+            (tx[7], connection)
+        }
+
+        let global = State::new_with(Stats::new_with(1000, 2000, 43), 0, 0, 0);
+        // a ResetStats leaving the global stats be is still counted by them
+        let mut counted = global.clone();
+        counted.update_request(&Request::ResetStats);
+        let allowed = ServerConfig {
+            allow_global_reset: true,
+            ..Default::default()
+        };
+        let default = ServerConfig::default();
+
+        // only the connection's stats by default
+        for payload in [&b""[..], b"\x00"] {
+            let mut state = global.clone();
+            let (code, connection) = reset(payload, &mut state, &default);
+            assert_eq!(code, Response::Ok as u8);
+            assert_eq!((state.clone(), connection), (counted.clone(), State::new()));
+        }
+
+        let mut state = global.clone();
+        let (code, connection) = reset(b"\x01", &mut state, &default);
+        assert_eq!(code, Response::Forbidden as u8);
+        let mut rejected = counted.clone();
+        rejected.update_error();
+        assert_eq!(state, rejected);
+        assert_ne!(connection, State::new());
+
+        // an empty ResetStats is global when allowed
+        for payload in [&b""[..], b"\x01"] {
+            let mut state = global.clone();
+            let (code, connection) = reset(payload, &mut state, &allowed);
+            assert_eq!(code, Response::Ok as u8);
+            assert_eq!(state, State::new());
+            assert_ne!(connection, State::new());
+        }
+        let mut state = global.clone();
+        let (code, connection) = reset(b"\x00", &mut state, &allowed);
+        assert_eq!(code, Response::Ok as u8);
+        assert_eq!((state, connection), (counted.clone(), State::new()));
+
+        let mut state = global.clone();
+        let (code, _) = reset(b"\x03", &mut state, &allowed);
+        assert_eq!(code, Response::UnsupportedResetScope as u8);
+        let (code, _) = reset(b"\x01\x00", &mut state, &allowed);
+        assert_eq!(code, Response::UnsupportedResetScope as u8);
+        // a scope followed by less than a token passes the header's checks
+        rejected.update_request(&Request::ResetStats);
+        rejected.update_error();
+        assert_eq!(state, rejected);
+    }
+
+    #[test]
+    fn test_reset_stats_token() {
+        fn reset(
+            payload: &[u8],
+            state: &mut State,
+            connection: &mut State,
+            config: &ServerConfig,
+        ) -> Vec<u8> {
+            let mut rx = vec![83u8, 84, 82, 89, 0, payload.len() as u8, 0];
+            rx.push(Request::ResetStats as u8);
+            rx.extend_from_slice(payload);
+            let mut tx = [0u8; 8];
+            let size = Connection::new_with(&rx[..], &mut tx[..], rx.len())
+                .create_response_scoped(state, connection, config);
+            tx[..size].to_vec()
+        }
+
+        let allowed = ServerConfig {
+            allow_global_reset: true,
+            ..Default::default()
+        };
+        let mut state = State::new_with(Stats::new_with(1000, 2000, 43), 0, 0, 0);
+        let mut connection = State::new();
+        let first = reset(b"\x01retry-me", &mut state, &mut connection, &allowed);
+        assert_eq!(first, [83u8, 84, 82, 89, 0, 0, 0, 0]);
+        assert_eq!(state, State::new());
+
+        // the response was lost, the client sends the same token again once
+        // more was accounted: it is answered alike and nothing is reset
+        state.update_read(100);
+        let mut expected = state.clone();
+        expected.update_request(&Request::ResetStats);
+        let second = reset(b"\x01retry-me", &mut state, &mut connection, &allowed);
+        assert_eq!(second, first);
+        assert_eq!(state, expected);
+
+        // so is one answered with an error, a new token resets again
+        let default = ServerConfig::default();
+        let forbidden = reset(b"\x01forbid!!", &mut state, &mut connection, &default);
+        assert_eq!(forbidden[7], Response::Forbidden as u8);
+        let retried = reset(b"\x01forbid!!", &mut state, &mut connection, &allowed);
+        assert_eq!(retried, forbidden);
+        assert_ne!(state, State::new());
+        reset(b"\x01new-one!", &mut state, &mut connection, &allowed);
+        assert_eq!(state, State::new());
+
+        // the tokens outlive a reset of the connection's stats
+        let mut connection = State::new_with(Stats::new_with(8, 8, 0), 0, 0, 0);
+        reset(b"\x00conn-one", &mut state, &mut connection, &allowed);
+        assert_eq!(connection.snapshot(), State::new().snapshot());
+        connection.update_read(100);
+        let expected = connection.snapshot().stats;
+        reset(b"\x00conn-one", &mut state, &mut connection, &allowed);
+        assert_ne!(connection.snapshot().stats, Stats::default());
+        assert_eq!(connection.snapshot().stats, expected);
+
+        // only the last `reset_tokens` are remembered
+        let config = ServerConfig {
+            reset_tokens: 2,
+            ..allowed.clone()
+        };
+        let mut connection = State::new();
+        for token in [b"\x01token-01", b"\x01token-02", b"\x01token-03"] {
+            reset(token, &mut state, &mut connection, &config);
+        }
+        state.update_read(100);
+        reset(b"\x01token-03", &mut state, &mut connection, &config);
+        assert_ne!(state, State::new());
+        reset(b"\x01token-01", &mut state, &mut connection, &config);
+        assert_eq!(state, State::new());
+
+        // a token is unsupported without any remembered
+        let off = ServerConfig {
+            reset_tokens: 0,
+            ..allowed
+        };
+        let code = reset(b"\x01token-04", &mut state, &mut connection, &off)[7];
+        assert_eq!(code, Response::UnsupportedResetScope as u8);
+    }
+
+    #[test]
+    fn test_authenticate() {
+        fn send(
+            code: Request,
+            payload: &[u8],
+            state: &mut State,
+            connection: &mut State,
+            config: &ServerConfig,
+        ) -> Vec<u8> {
+            let mut rx = vec![83u8, 84, 82, 89, 0, payload.len() as u8, 0, code as u8];
+            rx.extend_from_slice(payload);
+            let mut tx = [0u8; 32];
+            let size = Connection::new_with(&rx[..], &mut tx[..], rx.len())
+                .create_response_scoped(state, connection, config);
+            tx[..size].to_vec()
+        }
+
+        let config = ServerConfig {
+            tenants: [("tok-a", "acme"), ("tok-b", "globex")]
+                .iter()
+                .map(|(token, tenant)| (token.to_string(), tenant.to_string()))
+                .collect(),
+            max_tenants: 1,
+            ..Default::default()
+        };
+        let mut state = State::new();
+        let mut connection = State::new();
+        let auth = |token: &[u8], state: &mut State, connection: &mut State| {
+            send(Request::Authenticate, token, state, connection, &config)[7]
+        };
+
+        assert_eq!(
+            auth(b"nope", &mut state, &mut connection),
+            Response::Unauthorized as u8
+        );
+        assert_eq!(connection.tenant(), crate::ANONYMOUS_TENANT);
+        assert_eq!(auth(b"tok-a", &mut state, &mut connection), 0);
+        assert_eq!(connection.tenant(), "acme");
+        // acme takes the only room
+        let mut other = State::new();
+        assert_eq!(
+            auth(b"tok-b", &mut state, &mut other),
+            Response::Forbidden as u8
+        );
+        assert_eq!(other.tenant(), crate::ANONYMOUS_TENANT);
+
+        // GetStats reports the tenant's stats, the global ones are kept
+        state.update_read(100);
+        state.update_tenant_read("acme", 40);
+        state.update_tenant_sent("acme", 20);
+        let stats = send(Request::GetStats, b"", &mut state, &mut connection, &config);
+        assert_eq!(&stats[8..], [0, 0, 0, 40, 0, 0, 0, 20, 0]);
+        // an empty ResetStats resets them alone
+        send(
+            Request::ResetStats,
+            b"",
+            &mut state,
+            &mut connection,
+            &config,
+        );
+        assert_eq!(state.tenant_stats("acme"), Stats::new());
+        assert_eq!(state.snapshot().stats.read(), 100);
+        assert_eq!(connection.tenant(), "acme");
+    }
+
+    /// Answers `request` through a `Connection` the way the server used to,
+    /// over a buffer as large as any message with whatever it held before
+    fn through_connection(request: &[u8], state: &mut State, config: &ServerConfig) -> Vec<u8> {
+        let mut rx = [0xa5u8; MAX_MESSAGE_PADDED];
+        rx[..request.len()].copy_from_slice(request);
+        let sz = std::cmp::max(HEADER_SIZE, request.len());
+        let mut tx = [0u8; MAX_MESSAGE_PADDED];
+        let len = Connection::new_with(&rx[..sz], &mut tx[..], request.len())
+            .create_response_with(state, config);
+        tx[..len].to_vec()
+    }
+
+    #[test]
+    fn test_handle_request() {
+        let batch = encode_batch([&b"aaab"[..], b"xyz"]);
+        let requests = [
+            Vec::new(),
+            b"STR".to_vec(),
+            message(Request::Ping as u16, b""),
+            message(Request::Compress as u16, b"aaaabbbcd"),
+            message(Request::Compress as u16, b"aAa"),
+            message(Request::Compress as u16, b""),
+            message(Request::CompressBinary as u16, &[0, 0, 0, 7]),
+            message(Request::CompressWithStats as u16, b"zzzz"),
+            message(Request::Decompress as u16, b"4a"),
+            message(Request::GetStats as u16, b""),
+            message(Request::GetStatsV2 as u16, b""),
+            message(Request::GetConfig as u16, b""),
+            message(Request::Health as u16, b""),
+            message(Request::Batch as u16, &batch),
+            message(Request::ResetStats as u16, b""),
+            message(Request::Ping as u16, b"x"),
+            message(99, b""),
+            message(0x8000 | Request::Compress as u16, b"aa"),
+            message(Request::Compress as u16, b"aa")[..9].to_vec(),
+            [message(Request::Compress as u16, b"aa"), b"zz".to_vec()].concat(),
+            with_sequence(&message(Request::Compress as u16, b"ccc"), 7),
+            [
+                &[0u8, 0, 0, 0][..],
+                &message(Request::Ping as u16, b"")[4..],
+            ]
+            .concat(),
+            vec![b'a'; MAX_MESSAGE + 1],
+        ];
+        let configs = [
+            ServerConfig::default(),
+            ServerConfig {
+                enforcement: Enforcement::Strict,
+                error_details: true,
+                fold_case: true,
+                ..Default::default()
+            },
+        ];
+        for config in &configs {
+            let (mut direct, mut framed) = (State::new(), State::new());
+            for request in &requests {
+                let mut response = [0u8; MAX_MESSAGE_PADDED];
+                let len = handle_request(request, &mut response, &mut direct, config).unwrap();
+                let expected = through_connection(request, &mut framed, config);
+                assert_eq!(&response[..len], &expected[..], "{:?}", request);
+                assert_eq!(direct, framed, "{:?}", request);
+            }
+        }
+
+        let ping = message(Request::Ping as u16, b"");
+        let mut state = State::new();
+        let mut short = [0u8; HEADER_SIZE - 1];
+        assert_eq!(
+            handle_request(&ping, &mut short, &mut state, &configs[0]),
+            Err(HandleError::ResponseBufferTooSmall { len: 7 })
+        );
+        assert_eq!(state, State::new());
+        let mut header = [0u8; HEADER_SIZE];
+        assert_eq!(
+            handle_request(&ping, &mut header, &mut state, &configs[0]),
+            Ok(HEADER_SIZE)
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_handled_alike(message in arbitrary_strategy::<WireMessage>()) {
+            let config = ServerConfig::default();
+            let (mut direct, mut framed) = (State::new(), State::new());
+            let mut response = [0u8; MAX_MESSAGE_PADDED];
+            let len = handle_request(&message.bytes, &mut response, &mut direct, &config).unwrap();
+            let expected = through_connection(&message.bytes, &mut framed, &config);
+            prop_assert_eq!(&response[..len], &expected[..]);
+            prop_assert_eq!(direct, framed);
+        }
+
+        #[test]
+        fn prop_any_message_is_answered(message in arbitrary_strategy::<WireMessage>()) {
+            // read into the buffer of a connection like the server does, its
+            // tx holding an earlier response
+            let mut rx = [0u8; MAX_MESSAGE_PADDED];
+            rx[..message.bytes.len()].copy_from_slice(&message.bytes);
+            let mut tx = [0xa5u8; MAX_MESSAGE_PADDED];
+            let mut state = State::new();
+            let size = Connection::new_with(&rx[..], &mut tx[..], message.bytes.len())
+                .create_response(&mut state);
+
+            let reply = Message::parse(&tx[..size]).unwrap();
+            prop_assert_eq!(reply.header.sign(), MAGIC);
+            prop_assert_eq!(reply.header.size() as usize, size - HEADER_SIZE);
+            let response = Response::from_u16(reply.header.code());
+            prop_assert!(response.is_some(), "{:?}", reply);
+            if message.validity == Validity::StructurallyValidButSemanticallyWrong {
+                prop_assert_ne!(response, Some(Response::Ok), "{:?}", message);
+            }
+        }
+    }
 }
@@ -0,0 +1,234 @@
+use super::state::StatsSnapshot;
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The file a FlushStats without a payload writes within
+/// `ServerConfig::flush_dir`
+pub const DEFAULT_FLUSH_FILE: &str = "stats.json";
+
+/// Numbers the temporary files of the flushes of this process
+static FLUSHES: AtomicU64 = AtomicU64::new(0);
+
+/// The file of `dir` a FlushStats with `payload` writes, `None` if the
+/// payload isn't the name of a file right within `dir`, e.g. "../stats.json",
+/// "jobs/stats.json" or "/tmp/stats.json"
+pub fn flush_path(dir: &Path, payload: &[u8]) -> Option<PathBuf> {
+    if payload.is_empty() {
+        return Some(dir.join(DEFAULT_FLUSH_FILE));
+    }
+    let name = Path::new(std::str::from_utf8(payload).ok()?);
+    let mut components = name.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(file)), None) => Some(dir.join(file)),
+        _ => None,
+    }
+}
+
+/// Writes `snapshot` as JSON to `path`, returning the bytes written
+///
+/// The JSON goes to a temporary file of the same directory, renamed over
+/// `path` once complete, so readers and concurrent flushes only ever see a
+/// whole dump, the last one renamed wins
+pub fn flush_stats(path: &Path, snapshot: &StatsSnapshot) -> io::Result<usize> {
+    let json = snapshot.to_json();
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+    let temporary = path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        name.to_string_lossy(),
+        process::id(),
+        FLUSHES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let flushed = fs::write(&temporary, &json).and_then(|_| fs::rename(&temporary, path));
+    if flushed.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    flushed.map(|_| json.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Request, Response};
+    use crate::{Connection, ServerConfig, State, Stats, StatsSnapshot};
+    use std::{env, sync::Arc, thread};
+
+    /// A directory of its own under the system's temporary one
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("service-flush-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn send(
+        code: Request,
+        payload: &[u8],
+        state: &mut State,
+        connection: &mut State,
+        config: &ServerConfig,
+    ) -> Vec<u8> {
+        let mut rx = vec![83u8, 84, 82, 89, 0, payload.len() as u8, 0, code as u8];
+        rx.extend_from_slice(payload);
+        let mut tx = [0u8; 32];
+        let size = Connection::new_with(&rx[..], &mut tx[..], rx.len())
+            .create_response_scoped(state, connection, config);
+        tx[..size].to_vec()
+    }
+
+    #[test]
+    fn test_flush_path() {
+        let dir = Path::new("/var/lib/service");
+        assert_eq!(flush_path(dir, b""), Some(dir.join(DEFAULT_FLUSH_FILE)));
+        assert_eq!(
+            flush_path(dir, b"nightly.json"),
+            Some(dir.join("nightly.json"))
+        );
+        for outside in [
+            &b"../stats.json"[..],
+            b"jobs/stats.json",
+            b"/tmp/stats.json",
+            b"..",
+            b".",
+            b"\xFF",
+        ] {
+            assert_eq!(flush_path(dir, outside), None, "{:?}", outside);
+        }
+    }
+
+    #[test]
+    fn test_flush_stats() {
+        let dir = temp_dir("request");
+        let config = ServerConfig {
+            tenants: [("tok-ops".to_string(), "ops".to_string())].into(),
+            flush_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let mut state = State::new_with(Stats::new_with(1000, 2000, 43), 0, 0, 0);
+        state.update_request(&Request::Compress);
+        let mut connection = State::new();
+
+        // only authenticated connections may flush
+        let reply = send(
+            Request::FlushStats,
+            b"",
+            &mut state,
+            &mut connection,
+            &config,
+        );
+        assert_eq!(reply[7], Response::Unauthorized as u8);
+        send(
+            Request::Authenticate,
+            b"tok-ops",
+            &mut state,
+            &mut connection,
+            &config,
+        );
+
+        let reply = send(
+            Request::FlushStats,
+            b"",
+            &mut state,
+            &mut connection,
+            &config,
+        );
+        assert_eq!(reply[7], Response::Ok as u8);
+        let json = fs::read_to_string(dir.join(DEFAULT_FLUSH_FILE)).unwrap();
+        let written = u32::from_be_bytes([reply[8], reply[9], reply[10], reply[11]]);
+        assert_eq!(written as usize, json.len());
+        // the dump is of the stats as they were at the time, the largest
+        // response only grew by the flush's own once it was written
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.largest_response, 4);
+        let snapshot = StatsSnapshot {
+            largest_response: 0,
+            ..snapshot
+        };
+        let dump: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(dump["read"], snapshot.stats.read());
+        assert_eq!(dump["sent"], snapshot.stats.sent());
+        assert_eq!(dump["ratio"], snapshot.stats.ratio());
+        assert_eq!(dump["errors"], snapshot.errors);
+        assert_eq!(dump["requests"]["Compress"], 1);
+        // the rejected one included
+        assert_eq!(dump["requests"]["FlushStats"], 2);
+        assert_eq!(dump["requests"]["Authenticate"], 1);
+        assert_eq!(json, snapshot.to_json());
+
+        // outside the directory is rejected, nothing is written
+        let reply = send(
+            Request::FlushStats,
+            b"../escaped.json",
+            &mut state,
+            &mut connection,
+            &config,
+        );
+        assert_eq!(reply[7], Response::Forbidden as u8);
+        assert!(!dir.join("../escaped.json").exists());
+        let unconfigured = ServerConfig {
+            flush_dir: None,
+            ..config.clone()
+        };
+        let reply = send(
+            Request::FlushStats,
+            b"",
+            &mut state,
+            &mut connection,
+            &unconfigured,
+        );
+        assert_eq!(reply[7], Response::Forbidden as u8);
+        assert_eq!(state.internal_error(), 0);
+
+        // a failed write is an internal error
+        let missing = ServerConfig {
+            flush_dir: Some(dir.join("missing")),
+            ..config
+        };
+        let reply = send(
+            Request::FlushStats,
+            b"",
+            &mut state,
+            &mut connection,
+            &missing,
+        );
+        assert_eq!(reply[7], Response::IoError as u8);
+        assert_eq!(state.internal_error(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_flushes() {
+        let dir = temp_dir("concurrent");
+        let path = Arc::new(dir.join(DEFAULT_FLUSH_FILE));
+        let writers: Vec<_> = (0..8u32)
+            .map(|i| {
+                let path = Arc::clone(&path);
+                thread::spawn(move || {
+                    let mut state = State::new_with(Stats::new_with(i, i, 0), 0, 0, 0);
+                    for _ in 0..50 {
+                        state.update_read(1);
+                        flush_stats(&path, &state.snapshot()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        // whenever it's read the file holds one whole dump
+        for _ in 0..200 {
+            if let Ok(json) = fs::read_to_string(&*path) {
+                serde_json::from_str::<serde_json::Value>(&json).unwrap();
+            }
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        serde_json::from_str::<serde_json::Value>(&fs::read_to_string(&*path).unwrap()).unwrap();
+        // no temporary file is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
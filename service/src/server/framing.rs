@@ -0,0 +1,391 @@
+use super::config::{Enforcement, ServerConfig};
+use super::state::State;
+use crate::message::{self, GoodbyeReason, Response};
+use std::{
+    cmp,
+    io::{Error, ErrorKind},
+    ops::Range,
+    time::Duration,
+};
+use zeroize::Zeroize;
+
+/// How the peer of a connection keeps to the protocol over the messages it
+/// sends, the same for `Server::process` and the blocking server, which only
+/// do the I/O. A peer that keeps failing the magic check, or under strict
+/// enforcement keeps breaking the protocol in other ways, is closed
+pub(crate) struct Conduct {
+    /// Messages received with a bad magic, at the start of a message
+    strikes: usize,
+    /// Requests violating the protocol, see `ServerConfig::enforcement`
+    violations: usize,
+    /// Whether the next read starts a message, not the rest of one cut
+    /// short, see `cut_short`
+    at_boundary: bool,
+}
+
+/// What follows the response to a message, see `Conduct::judge`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Verdict {
+    /// Whether the response is written, one to a bad magic isn't under
+    /// `ServerConfig::silent_bad_magic`
+    pub(crate) respond: bool,
+    /// Why the connection is closed once the message is answered
+    pub(crate) close: Option<GoodbyeReason>,
+}
+
+impl Conduct {
+    pub(crate) fn new() -> Conduct {
+        Conduct {
+            strikes: 0,
+            violations: 0,
+            at_boundary: true,
+        }
+    }
+
+    /// Judges the message `rx`, answered with `code`, `discarded` the bytes
+    /// read for it that aren't handled as a request
+    pub(crate) fn judge(
+        &mut self,
+        rx: &[u8],
+        code: u16,
+        discarded: usize,
+        config: &ServerConfig,
+    ) -> Verdict {
+        // a peer that keeps failing the magic check doesn't speak the
+        // protocol, it isn't kept around for however long it likes. An
+        // oversized read is answered for its size, its magic counts all
+        // the same
+        let bad_magic = code == Response::MessageHeaderHasBadMagic as u16
+            || (discarded > 0 && message::declared_len(rx).is_none());
+        // the rest of a message cut short is answered with a bad magic
+        // like any other, but it's no strike: the peer may well speak
+        // the protocol and have been split up by the network
+        let struck = bad_magic && self.at_boundary;
+        if struck {
+            self.strikes += 1;
+        }
+        let struck_out =
+            struck && config.bad_magic_strikes > 0 && self.strikes >= config.bad_magic_strikes;
+        self.at_boundary = !cut_short(rx);
+        // under strict enforcement, so is one that keeps breaking the
+        // protocol in other ways
+        if Response::from_u16(code).is_some_and(|response| response.is_violation()) {
+            self.violations += 1;
+        }
+        let close = if struck_out {
+            Some(GoodbyeReason::BadMagic)
+        } else if config.violations_due(self.violations) {
+            Some(GoodbyeReason::Violations)
+        } else {
+            None
+        };
+        Verdict {
+            respond: !(bad_magic && config.silent_bad_magic),
+            close,
+        }
+    }
+}
+
+impl Verdict {
+    /// The reason the connection is told it's closed for, none for one
+    /// closed for a bad magic under `ServerConfig::silent_bad_magic`
+    pub(crate) fn goodbye(&self, config: &ServerConfig) -> Option<GoodbyeReason> {
+        match self.close {
+            Some(GoodbyeReason::BadMagic) if config.silent_bad_magic => None,
+            close => close,
+        }
+    }
+
+    /// Accounts for the connection being closed in the pending state of
+    /// the message's request, and returns the error it's closed with
+    pub(crate) fn closed(&self, pending: &mut State) -> Option<Error> {
+        let reason = match self.close? {
+            GoodbyeReason::BadMagic => {
+                pending.update_bad_magic_drop();
+                "Dropping client sending bad magic"
+            }
+            _ => "Dropping client violating the protocol",
+        };
+        Some(Error::new(ErrorKind::InvalidData, reason))
+    }
+}
+
+/// Bytes read for a message that aren't handled as a request: all of an
+/// oversized one, `drained` past the `len` read of it, otherwise those
+/// `skipped` past it
+pub(crate) fn discarded(len: usize, skipped: usize, drained: usize) -> usize {
+    if len > message::MAX_MESSAGE || drained > 0 {
+        drained + len
+    } else {
+        skipped
+    }
+}
+
+/// Accounts for the `read` bytes of a message, `discarded` of them, in the
+/// pending state of its request and the stats of the connection
+pub(crate) fn account_read(
+    pending: &mut State,
+    session: &mut State,
+    read: usize,
+    discarded: usize,
+    config: &ServerConfig,
+) {
+    pending.update_read(read);
+    pending.update_discarded(discarded);
+    session.update_read(read);
+    if config.tenancy() {
+        pending.update_tenant_read(session.tenant(), read);
+    }
+}
+
+/// Accounts for a request handled in `took`, past `ServerConfig::request_deadline`
+/// it's slow. Returns the deadline it's past, for the server to tell about
+pub(crate) fn account_handled(
+    pending: &mut State,
+    session: &mut State,
+    took: Duration,
+    config: &ServerConfig,
+) -> Option<Duration> {
+    let deadline = config
+        .request_deadline
+        .filter(|deadline| took >= *deadline)?;
+    pending.update_slow_request();
+    session.update_slow_request();
+    Some(deadline)
+}
+
+/// Accounts for the `written` bytes of a response, in the pending state of
+/// its request and the stats of the connection
+pub(crate) fn account_sent(
+    pending: &mut State,
+    session: &mut State,
+    written: usize,
+    config: &ServerConfig,
+) {
+    pending.update_sent(written);
+    session.update_sent(written);
+    if config.tenancy() {
+        pending.update_tenant_sent(session.tenant(), written);
+    }
+}
+
+/// Accounts for a response whose write failed to `shared`: the bytes that
+/// made it out, `written`, and its request as read though not answered
+pub(crate) fn account_failed(
+    shared: &mut State,
+    pending: State,
+    session: &State,
+    written: usize,
+    config: &ServerConfig,
+) {
+    shared.apply_unanswered(pending);
+    shared.update_sent(written);
+    shared.update_failed_write();
+    if config.tenancy() {
+        shared.update_tenant_sent(session.tenant(), written);
+    }
+}
+
+/// Answers a request with ServerBusy in `tx` without handling it,
+/// returning the size and code of the response
+pub(crate) fn set_busy(tx: &mut [u8], state: &mut State) -> (usize, u16) {
+    let code = Response::ServerBusy as u16;
+    message::Message::parse_mut(&mut tx[..message::HEADER_SIZE])
+        .unwrap()
+        .set_header_with_default_magic(0, code);
+    state.update_error();
+    (message::HEADER_SIZE, code)
+}
+
+/// Frames a read holding more than the message its header declares: the
+/// message is answered alone and the bytes past it are the next message,
+/// answered without waiting for another read. Returns the length of the
+/// message and how many bytes past it are skipped before the next one,
+/// `None` for a read answered whole
+///
+/// Bytes past a message that don't start with the magic aren't a message.
+/// Under permissive enforcement they're skipped up to the next magic, or
+/// all of them if there's none, and counted as discarded. Under strict
+/// enforcement the read is answered whole, with TrailingBytes, so a client
+/// that keeps sending them is closed by `violation_strikes`. A read that
+/// doesn't start with the magic is answered with a bad magic as far as
+/// the next magic, wherever that is, and counts as one strike
+///
+/// `rx` is expected to hold the message whole, its reads joined as
+/// `missing` tells. One still cut short, at the end of the stream or an
+/// idle timeout, is answered for the bytes it has
+pub(crate) fn frame(rx: &[u8], enforcement: Enforcement) -> Option<(usize, usize)> {
+    let magic = message::MAGIC.to_be_bytes();
+    let magic_at = |bytes: &[u8]| bytes.windows(magic.len()).position(|bytes| bytes == magic);
+    if rx.len() < message::HEADER_SIZE {
+        return None;
+    }
+    let len = match message::declared_len(rx) {
+        Some(len) if len < rx.len() && len <= message::MAX_MESSAGE => len,
+        Some(_) => return None,
+        None => return magic_at(&rx[1..]).map(|at| (1 + at, 0)),
+    };
+    let rest = &rx[len..];
+    // fewer bytes than the magic are a message if they may begin one
+    if rest.len() < magic.len() && magic.starts_with(rest) {
+        return Some((len, 0));
+    }
+    match magic_at(rest) {
+        Some(0) => Some((len, 0)),
+        _ if enforcement == Enforcement::Strict => None,
+        Some(at) => Some((len, at)),
+        None => Some((len, rest.len())),
+    }
+}
+
+/// Bytes the message at the start of `rx` is missing as its header declares,
+/// its reads are joined until it's whole before it's framed. Zero for one
+/// read whole, one without a valid header and one over MAX_MESSAGE, whose
+/// rest is drained. Fewer bytes than a header are answered at once, unless
+/// they're `continued` from past the previous message, a header cut short
+pub(crate) fn missing(rx: &[u8], continued: bool) -> usize {
+    if rx.len() < message::HEADER_SIZE {
+        return match continued {
+            true => message::HEADER_SIZE - rx.len(),
+            false => 0,
+        };
+    }
+    match message::declared_len(rx) {
+        Some(len) if len <= message::MAX_MESSAGE => len.saturating_sub(rx.len()),
+        _ => 0,
+    }
+}
+
+/// Whether `rx`, a message answered, was cut short of what its header
+/// declares or of a header beginning with the magic. The read after it is
+/// then the rest of it rather than the start of a message
+pub(crate) fn cut_short(rx: &[u8]) -> bool {
+    let magic = message::MAGIC.to_be_bytes();
+    if rx.len() < message::HEADER_SIZE {
+        let len = cmp::min(rx.len(), magic.len());
+        return len > 0 && magic.starts_with(&rx[..len]);
+    }
+    missing(rx, false) > 0
+}
+
+/// Moves the `next` bytes of `rx`, read past the message answered, to its
+/// start and returns how many there are
+pub(crate) fn keep_pending(rx: &mut [u8], next: Range<usize>, config: &ServerConfig) -> usize {
+    let (pending, end) = (next.len(), next.end);
+    rx.copy_within(next, 0);
+    if config.zeroize_buffers {
+        rx[pending..end].zeroize();
+    }
+    pending
+}
+
+/// The bytes of an oversized message left to read after the `rx` read of
+/// it, as its header declares. Zero for any other message, its end is
+/// only known from the read
+pub(crate) fn oversize_left(rx: &[u8]) -> usize {
+    match message::declared_len(rx) {
+        Some(len) if len > message::MAX_MESSAGE => len.saturating_sub(rx.len()),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{frame, Conduct, Verdict};
+    use crate::message::{GoodbyeReason, Response};
+    use crate::server::config::{Enforcement, ServerConfig};
+    use crate::server::state::State;
+    use crate::testing::wire::ping;
+
+    #[test]
+    fn test_frame() {
+        let ping = ping();
+        let permissive = |rx: &[u8]| frame(rx, Enforcement::Permissive);
+        let strict = |rx: &[u8]| frame(rx, Enforcement::Strict);
+        // a message alone, or cut short, is answered whole
+        for rx in [&ping[..], &ping[..5], &ping[..8]] {
+            assert_eq!((permissive(rx), strict(rx)), (None, None));
+        }
+        let pipelined = [&ping[..], &ping].concat();
+        assert_eq!(
+            (permissive(&pipelined), strict(&pipelined)),
+            (Some((8, 0)), Some((8, 0)))
+        );
+        let cut_short = [&ping[..], b"ST"].concat();
+        assert_eq!(strict(&cut_short), Some((8, 0)));
+        // bytes that aren't a message are skipped, up to the next one
+        let junk = [&ping[..], b"xyz", &ping].concat();
+        assert_eq!((permissive(&junk), strict(&junk)), (Some((8, 3)), None));
+        let junk = [&ping[..], b"xyz"].concat();
+        assert_eq!((permissive(&junk), strict(&junk)), (Some((8, 3)), None));
+        let junk = [&b"junk"[..], &ping, &ping].concat();
+        assert_eq!(
+            (permissive(&junk), strict(&junk)),
+            (Some((4, 0)), Some((4, 0)))
+        );
+        assert_eq!(permissive(b"junk and more junk"), None);
+    }
+
+    #[test]
+    fn test_conduct() {
+        let ping = ping();
+        let bad_magic = Response::MessageHeaderHasBadMagic as u16;
+        let config = ServerConfig {
+            bad_magic_strikes: 2,
+            ..Default::default()
+        };
+        let answered = Verdict {
+            respond: true,
+            close: None,
+        };
+
+        // the rest of a message cut short is answered with a bad magic, but
+        // it's no strike, the second one at the start of a message closes
+        let mut conduct = Conduct::new();
+        let size_mismatch = Response::MessageHeaderSizeMismatch as u16;
+        assert_eq!(
+            conduct.judge(&ping[..5], size_mismatch, 0, &config),
+            answered
+        );
+        assert_eq!(conduct.judge(b"NG", bad_magic, 0, &config), answered);
+        assert_eq!(conduct.judge(b"junk", bad_magic, 0, &config), answered);
+        let verdict = conduct.judge(b"junk", bad_magic, 0, &config);
+        assert_eq!(verdict.close, Some(GoodbyeReason::BadMagic));
+        assert_eq!(verdict.goodbye(&config), Some(GoodbyeReason::BadMagic));
+        let mut pending = State::new().pending();
+        let closed = verdict.closed(&mut pending).unwrap();
+        assert_eq!(closed.to_string(), "Dropping client sending bad magic");
+        assert_eq!(pending.snapshot().bad_magic_drops, 1);
+
+        // under silent_bad_magic neither the responses nor a goodbye are sent
+        let silent = ServerConfig {
+            bad_magic_strikes: 2,
+            silent_bad_magic: true,
+            ..Default::default()
+        };
+        let mut conduct = Conduct::new();
+        let verdict = conduct.judge(b"junk", bad_magic, 0, &silent);
+        assert!(!verdict.respond && verdict.close.is_none());
+        let verdict = conduct.judge(b"junk", bad_magic, 0, &silent);
+        assert_eq!(verdict.close, Some(GoodbyeReason::BadMagic));
+        assert_eq!(verdict.goodbye(&silent), None);
+
+        // violations only close under strict enforcement
+        let trailing = Response::TrailingBytes as u16;
+        let mut conduct = Conduct::new();
+        for _ in 0..3 {
+            assert_eq!(conduct.judge(&ping, trailing, 0, &config), answered);
+        }
+        let strict = ServerConfig {
+            enforcement: Enforcement::Strict,
+            violation_strikes: 2,
+            ..Default::default()
+        };
+        let mut conduct = Conduct::new();
+        assert_eq!(conduct.judge(&ping, trailing, 0, &strict), answered);
+        let verdict = conduct.judge(&ping, trailing, 0, &strict);
+        assert_eq!(verdict.close, Some(GoodbyeReason::Violations));
+        let closed = verdict.closed(&mut State::new().pending()).unwrap();
+        assert_eq!(closed.to_string(), "Dropping client violating the protocol");
+    }
+}
@@ -0,0 +1,77 @@
+use super::stats::Stats;
+use crate::message::GoodbyeReason;
+use byteorder::NetworkEndian;
+use zerocopy::{byteorder::U32, AsBytes, ByteSlice, FromBytes, LayoutVerified, Unaligned};
+
+/// The Goodbye payload, sent before the service closes a connection
+/// stats: The `Stats` of the connection alone, the Goodbye itself excluded
+/// requests: Requests answered on the connection
+/// reason: The `GoodbyeReason` for closing
+#[derive(Default, Debug, Clone, PartialEq, AsBytes, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct Goodbye {
+    stats: Stats,
+    requests: U32<NetworkEndian>,
+    reason: u8,
+}
+
+impl Goodbye {
+    pub fn new_with(stats: Stats, requests: u32, reason: GoodbyeReason) -> Goodbye {
+        Goodbye {
+            stats,
+            requests: U32::new(requests),
+            reason: reason as u8,
+        }
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn requests(&self) -> u32 {
+        self.requests.get()
+    }
+
+    /// `None` if the reason is unknown to this version of the protocol
+    pub fn reason(&self) -> Option<GoodbyeReason> {
+        GoodbyeReason::from_u8(self.reason)
+    }
+
+    pub fn parse<B: ByteSlice>(bytes: B) -> Option<LayoutVerified<B, Goodbye>> {
+        LayoutVerified::new(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Goodbye, GoodbyeReason, Stats};
+    use std::mem;
+    use zerocopy::AsBytes;
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(mem::size_of::<Goodbye>(), 14);
+        assert_eq!(mem::align_of::<Goodbye>(), 1);
+    }
+
+    #[test]
+    fn test_as_bytes() {
+        let goodbye =
+            Goodbye::new_with(Stats::new_with(300, 22, 10), 3, GoodbyeReason::IdleTimeout);
+        assert_eq!(
+            goodbye.as_bytes(),
+            [0, 0, 1, 44, 0, 0, 0, 22, 10, 0, 0, 0, 3, 1]
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let msg = [0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0, 1, 4];
+        let goodbye = Goodbye::parse(&msg[..]).unwrap();
+        assert_eq!((goodbye.stats().read(), goodbye.requests()), (8, 1));
+        assert_eq!(goodbye.reason(), Some(GoodbyeReason::Abuse));
+
+        let msg = [0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0, 1, 99];
+        assert_eq!(Goodbye::parse(&msg[..]).unwrap().reason(), None);
+    }
+}
@@ -0,0 +1,216 @@
+use super::handle::StateHandle;
+use super::lanes::Lanes;
+use super::spawn::spawn_named_in;
+use super::state::{State, StatsSnapshot};
+use super::{Result, Server, ServerConfig};
+use std::{future::Future, net::SocketAddr, sync::Arc};
+use tokio::{
+    runtime::Handle,
+    sync::{watch, Mutex},
+    task::JoinSet,
+};
+
+/// Servers on listeners of their own, each with its own configuration, that
+/// account for their requests in one `State`: a GetStats on any of them, or
+/// the group's `state_handle`, reports the traffic of all. Each listener's
+/// accepted connections stay apart in `StatsSnapshot::listeners`, by address
+///
+/// The requests of all of the servers are scheduled in the same `Lanes`,
+/// sized by the `heavy_lane` of the first one bound
+///
+/// # Example
+/// ```no_run
+/// use service::{ServerConfig, ServerGroup};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), std::io::Error> {
+///     let mut group = ServerGroup::new();
+///     group.bind("127.0.0.1:4000", ServerConfig::default()).await?;
+///     let small = ServerConfig {
+///         max_batch: 4,
+///         ..Default::default()
+///     };
+///     group.bind("127.0.0.1:4001", small).await?;
+///     let shutdown = async { tokio::signal::ctrl_c().await.unwrap() };
+///     group.serve_all(shutdown).await
+/// }
+/// ```
+pub struct ServerGroup {
+    servers: Vec<Server>,
+    the_state: Arc<Mutex<State>>,
+    changes: watch::Receiver<StatsSnapshot>,
+    /// Shared by the servers, created along with the first one
+    lanes: Option<Lanes>,
+}
+
+impl Default for ServerGroup {
+    fn default() -> ServerGroup {
+        ServerGroup::new()
+    }
+}
+
+impl ServerGroup {
+    /// A group without any server yet
+    pub fn new() -> ServerGroup {
+        let (publisher, changes) = watch::channel(StatsSnapshot::default());
+        let mut state = State::new();
+        state.observe(Arc::new(move |snapshot: &StatsSnapshot| {
+            publisher.send_replace(snapshot.clone());
+        }));
+        ServerGroup {
+            servers: Vec::new(),
+            the_state: Arc::new(Mutex::new(state)),
+            changes,
+            lanes: None,
+        }
+    }
+
+    /// Adds a server listening at `url` that behaves according to `config`,
+    /// returning the address it is bound to
+    pub async fn bind(&mut self, url: &str, config: ServerConfig) -> Result<SocketAddr> {
+        let lanes = self
+            .lanes
+            .get_or_insert_with(|| Lanes::new_with(config.heavy_lane))
+            .clone();
+        let state = Arc::clone(&self.the_state);
+        let server =
+            Server::new_with_state(url, config, state, self.changes.clone(), lanes).await?;
+        let local_addr = server.listener.local_addr()?;
+        self.servers.push(server);
+        Ok(local_addr)
+    }
+
+    /// The servers of the group, in the order they were bound
+    pub fn servers(&self) -> &[Server] {
+        &self.servers
+    }
+
+    /// A read-only handle on the stats the servers share
+    pub fn state_handle(&self) -> StateHandle {
+        StateHandle::new_with(
+            Arc::clone(&self.the_state),
+            self.changes.clone(),
+            self.lanes.clone().unwrap_or_default(),
+        )
+    }
+
+    /// Serves on every listener of the group until `shutdown` completes, or
+    /// one of the servers fails, and stops them all. Each then gives its
+    /// connections its own `ServerConfig::shutdown_timeout` to finish.
+    /// Returns the first error any of them failed with
+    pub async fn serve_all<F>(self, shutdown: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let handle = Handle::current();
+        let (stop, stopped) = watch::channel(false);
+        let mut serving = JoinSet::new();
+        for mut server in self.servers {
+            let mut stopped = stopped.clone();
+            let grace = server.config.borrow().shutdown_timeout;
+            let name = format!("server {}", server.listener.local_addr()?);
+            let task = {
+                let handle = handle.clone();
+                async move {
+                    let shutdown = async move {
+                        let _ = stopped.wait_for(|stop| *stop).await;
+                    };
+                    server.serve_on(&handle, shutdown, grace).await
+                }
+            };
+            spawn_named_in(&mut serving, &name, task, &handle);
+        }
+
+        tokio::pin!(shutdown);
+        let mut result = Ok(());
+        loop {
+            let served = tokio::select! {
+                _ = &mut shutdown, if !*stop.borrow() => {
+                    stop.send_replace(true);
+                    continue;
+                }
+                served = serving.join_next() => served,
+            };
+            let served = match served {
+                Some(served) => served.unwrap_or_else(|e| Err(e.into())),
+                None => return result,
+            };
+            if let Err(e) = served {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+                stop.send_replace(true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{self, Request, HEADER_SIZE};
+    use crate::stats::Stats;
+    use crate::testing::wire;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::oneshot,
+    };
+
+    async fn send(stream: &mut TcpStream, request: Request, payload: &[u8]) -> Vec<u8> {
+        stream
+            .write_all(&wire::request(request, payload))
+            .await
+            .unwrap();
+        let mut response = vec![0u8; HEADER_SIZE];
+        stream.read_exact(&mut response).await.unwrap();
+        let size = message::Message::parse(&response[..])
+            .unwrap()
+            .header
+            .size();
+        let mut payload = vec![0u8; size as usize];
+        stream.read_exact(&mut payload).await.unwrap();
+        payload
+    }
+
+    #[tokio::test]
+    async fn test_shared_state() {
+        let mut group = ServerGroup::new();
+        let first = group.bind("127.0.0.1:0", Default::default()).await.unwrap();
+        let folding = ServerConfig {
+            fold_case: true,
+            ..Default::default()
+        };
+        let second = group.bind("127.0.0.1:0", folding).await.unwrap();
+        let handle = group.state_handle();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let serving = tokio::spawn(group.serve_all(async {
+            let _ = stopped.await;
+        }));
+
+        // each keeps its own configuration
+        let mut to_first = TcpStream::connect(first).await.unwrap();
+        let mut to_second = TcpStream::connect(second).await.unwrap();
+        assert_eq!(send(&mut to_first, Request::Compress, b"aaa").await, b"3a");
+        assert_eq!(send(&mut to_second, Request::Compress, b"aAa").await, b"3a");
+        let (read, sent) = (2 * (HEADER_SIZE as u32 + 3), 2 * (HEADER_SIZE as u32 + 2));
+
+        // either reports the traffic of both
+        let stats = send(&mut to_second, Request::GetStats, b"").await;
+        let stats = Stats::parse(&stats[..]).unwrap();
+        assert_eq!(
+            (stats.read(), stats.sent()),
+            (read + HEADER_SIZE as u32, sent)
+        );
+        let snapshot = handle.snapshot().await;
+        assert_eq!(
+            snapshot.listeners,
+            [(first.to_string(), 1), (second.to_string(), 1)]
+        );
+
+        drop((to_first, to_second));
+        stop.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+        assert!(TcpStream::connect(first).await.is_err());
+    }
+}
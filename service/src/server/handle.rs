@@ -0,0 +1,571 @@
+use super::accept::AcceptStats;
+use super::config::ServerConfig;
+use super::lanes::{Lane, Lanes};
+use super::metrics::Metrics;
+use super::state::{ConnectionGauge, State, StatsSnapshot};
+use crate::message::Request;
+use crate::stats::Stats;
+use std::{
+    future::Future,
+    io::{Error, ErrorKind},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    sync::{oneshot, watch, Mutex},
+    task::{JoinError, JoinHandle},
+};
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+/// Read-only access to the stats of a running `Server`, see
+/// `Server::state_handle`
+///
+/// Handles are cheap to clone, `Send` and `Sync`, and can be used from any
+/// task or runtime. Every accessor briefly locks the state the connections
+/// update, so two calls may see different requests accounted for, a
+/// `snapshot` has all of the counters from the same moment
+#[derive(Debug, Clone)]
+pub struct StateHandle {
+    state: Arc<Mutex<State>>,
+    changes: watch::Receiver<StatsSnapshot>,
+    lanes: Lanes,
+}
+
+impl StateHandle {
+    pub fn new_with(
+        state: Arc<Mutex<State>>,
+        changes: watch::Receiver<StatsSnapshot>,
+        lanes: Lanes,
+    ) -> StateHandle {
+        StateHandle {
+            state,
+            changes,
+            lanes,
+        }
+    }
+
+    pub async fn snapshot(&self) -> StatsSnapshot {
+        self.state.lock().await.snapshot()
+    }
+
+    /// Bytes received by the service, headers included
+    pub async fn read_bytes(&self) -> u32 {
+        self.state.lock().await.snapshot().stats.read()
+    }
+
+    /// Bytes sent by the service, headers included
+    pub async fn sent_bytes(&self) -> u32 {
+        self.state.lock().await.snapshot().stats.sent()
+    }
+
+    /// The compression ratio reported by GetStats, 0-100
+    pub async fn ratio(&self) -> u8 {
+        self.state.lock().await.snapshot().stats.ratio()
+    }
+
+    /// Valid requests of the kind of `request` handled
+    pub async fn requests(&self, request: &Request) -> usize {
+        self.state.lock().await.requests(request)
+    }
+
+    /// The stats of `tenant`, `ANONYMOUS_TENANT` for the connections that
+    /// never authenticated, see `ServerConfig::tenants`
+    pub async fn tenant_stats(&self, tenant: &str) -> Stats {
+        self.state.lock().await.tenant_stats(tenant)
+    }
+
+    /// Bytes of requests and responses the connections hold at the moment,
+    /// see `ServerConfig::memory_budget`
+    pub async fn in_flight_bytes(&self) -> usize {
+        self.state.lock().await.in_flight().bytes()
+    }
+
+    /// The counters of the accept loops, errors and waits at
+    /// `ServerConfig::max_connections`, see `AcceptStats`
+    pub async fn accepts(&self) -> AcceptStats {
+        self.state.lock().await.accepts()
+    }
+
+    /// The connections open and accepted, read without locking the state
+    /// again, see `ConnectionGauge`
+    pub async fn connections(&self) -> ConnectionGauge {
+        self.state.lock().await.connections()
+    }
+
+    /// The counters exported as metrics, see `Metrics`
+    pub async fn metrics(&self) -> Metrics {
+        self.state.lock().await.metrics()
+    }
+
+    /// Requests waiting in or going through `lane`, see `Lanes`
+    pub fn lane_depth(&self, lane: Lane) -> usize {
+        self.lanes.depth(lane)
+    }
+
+    /// A receiver of a snapshot each time the bytes read and sent move by
+    /// `MATERIAL_CHANGE` or the stats are reset, so that a dashboard can wait
+    /// on `changed` instead of polling. The initial value is all zeros
+    pub fn subscribe(&self) -> watch::Receiver<StatsSnapshot> {
+        self.changes.clone()
+    }
+}
+
+/// A `StateHandle` that can also reset the stats, see `Server::reset_handle`
+#[derive(Debug, Clone)]
+pub struct ResetHandle {
+    handle: StateHandle,
+}
+
+impl ResetHandle {
+    pub fn new_with(handle: StateHandle) -> ResetHandle {
+        ResetHandle { handle }
+    }
+
+    pub fn state(&self) -> &StateHandle {
+        &self.handle
+    }
+
+    /// Resets the stats of the whole service, like a global ResetStats
+    pub async fn reset(&self) {
+        let mut state = self.handle.state.lock().await;
+        state.reset();
+        state.publish();
+    }
+}
+
+/// Replaces the configuration of a running `Server`, see
+/// `Server::config_handle`
+#[derive(Debug, Clone)]
+pub struct ConfigHandle {
+    config: Arc<watch::Sender<Arc<ServerConfig>>>,
+}
+
+impl ConfigHandle {
+    pub fn new_with(config: Arc<watch::Sender<Arc<ServerConfig>>>) -> ConfigHandle {
+        ConfigHandle { config }
+    }
+
+    /// The configuration being served with
+    pub fn current(&self) -> Arc<ServerConfig> {
+        Arc::clone(&self.config.borrow())
+    }
+
+    /// Serves with `config` from the next request of every connection on,
+    /// and from their next connection on for those of the debug port. The
+    /// settings only applied on restart keep their current values, the names
+    /// of those `config` would have changed are returned. A `config` failing
+    /// validation leaves the current one in place
+    pub fn reload(&self, mut config: ServerConfig) -> Result<Vec<&'static str>> {
+        config
+            .validate()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let current = self.current();
+        let ignored = config.restart_only_changes(&current);
+        config.debug_addr = current.debug_addr.clone();
+        config.max_connections = current.max_connections;
+        config.stats_interval = current.stats_interval;
+        config.shutdown_timeout = current.shutdown_timeout;
+        config.heavy_lane = current.heavy_lane;
+        config.recent_requests = current.recent_requests;
+        self.config.send_replace(Arc::new(config));
+        Ok(ignored)
+    }
+}
+
+/// A `Server` serving on a task of its own, see `Server::spawn`
+///
+/// Awaiting the handle waits for the server to stop, with the error that
+/// stopped it if any. Dropping it leaves the server running
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    state: StateHandle,
+    stop: Option<oneshot::Sender<()>>,
+    /// `None` once the server is known to have stopped
+    task: Option<JoinHandle<Result<()>>>,
+}
+
+impl ServerHandle {
+    pub fn new_with(
+        local_addr: SocketAddr,
+        state: StateHandle,
+        stop: oneshot::Sender<()>,
+        task: JoinHandle<Result<()>>,
+    ) -> ServerHandle {
+        ServerHandle {
+            local_addr,
+            state,
+            stop: Some(stop),
+            task: Some(task),
+        }
+    }
+
+    /// The address the server accepts connections at
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn stats(&self) -> &StateHandle {
+        &self.state
+    }
+
+    /// Stops accepting connections and waits for the server to stop, the
+    /// connections being served are given `ServerConfig::shutdown_timeout` to
+    /// finish. Shutting down a server that already stopped does nothing
+    pub async fn shutdown(&mut self) -> Result<()> {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        match self.task.take() {
+            Some(task) => ServerHandle::joined(task.await),
+            None => Ok(()),
+        }
+    }
+
+    /// Stops the server at once, dropping every connection
+    pub fn abort(&self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+
+    fn joined(result: std::result::Result<Result<()>, JoinError>) -> Result<()> {
+        match result {
+            Ok(result) => result,
+            Err(e) if e.is_cancelled() => Err(Error::new(ErrorKind::Interrupted, "server aborted")),
+            Err(e) => Err(Error::other(e)),
+        }
+    }
+}
+
+impl Future for ServerHandle {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let result = match self.task.as_mut() {
+            Some(task) => ready!(Pin::new(task).poll(cx)),
+            None => return Poll::Ready(Ok(())),
+        };
+        self.task = None;
+        Poll::Ready(ServerHandle::joined(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{self, Response, MAX_PAYLOAD};
+    use crate::testing::wire;
+    use crate::{CompressError, CompressionScheme, DecompressError, RlePrefix, Server};
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        time,
+    };
+
+    fn compress_request(payload: &[u8]) -> Vec<u8> {
+        wire::request(Request::Compress, payload)
+    }
+
+    #[tokio::test]
+    async fn test_state_handle_with_traffic() {
+        let server = Server::new_with_url("127.0.0.1:0").await.unwrap();
+        let reset = server.reset_handle();
+        let server = server.spawn();
+        let addr = server.local_addr();
+        let handle = server.stats();
+        let mut changes = handle.subscribe();
+
+        let payload = vec![b'a'; MAX_PAYLOAD as usize];
+        let request = compress_request(&payload);
+        let clients: Vec<_> = (0..4)
+            .map(|_| {
+                let request = request.clone();
+                tokio::spawn(async move {
+                    let mut stream = TcpStream::connect(addr).await.unwrap();
+                    let mut response = [0u8; message::HEADER_SIZE + 5];
+                    for _ in 0..25 {
+                        stream.write_all(&request).await.unwrap();
+                        stream.read_exact(&mut response).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        // read concurrently with the traffic, the counters never go back
+        let (mut read, mut sent) = (0, 0);
+        while !clients.iter().all(|client| client.is_finished()) {
+            let snapshot = handle.snapshot().await;
+            assert!(snapshot.stats.read() >= read && snapshot.stats.sent() >= sent);
+            (read, sent) = (snapshot.stats.read(), snapshot.stats.sent());
+            tokio::task::yield_now().await;
+        }
+        for client in clients {
+            client.await.unwrap();
+        }
+        let total = 100 * request.len() as u32;
+        assert_eq!(handle.read_bytes().await, total);
+        assert_eq!(handle.sent_bytes().await, 100 * 13);
+        // 99.99% to the nearest percent
+        assert_eq!(handle.ratio().await, 100);
+        assert_eq!(handle.requests(&Request::Compress).await, 100);
+        assert_eq!(handle.requests(&Request::Ping).await, 0);
+
+        // every request is material, the last one was published
+        assert!(changes.has_changed().unwrap());
+        assert_eq!(changes.borrow_and_update().stats.read(), total);
+        reset.reset().await;
+        changes.changed().await.unwrap();
+        // zeroed, the listener is still known, the connections accepted are
+        // never reset and the peak is lowered to the connections that may
+        // still be closing
+        let zeroed = StatsSnapshot {
+            listeners: vec![(addr.to_string(), 0)],
+            connections: changes.borrow().connections,
+            accepted_connections: 4,
+            peak_connections: changes.borrow().peak_connections,
+            ..Default::default()
+        };
+        assert!(zeroed.peak_connections <= 4);
+        assert_eq!(*changes.borrow(), zeroed);
+        assert_eq!(reset.state().read_bytes().await, 0);
+    }
+
+    /// `RlePrefix`, only each compression waits for the gate to open first.
+    /// `entered` counts those that got to it
+    #[derive(Default)]
+    struct Gated {
+        open: std::sync::Mutex<bool>,
+        opened: std::sync::Condvar,
+        entered: AtomicUsize,
+    }
+
+    impl Gated {
+        fn open(&self) {
+            *self.open.lock().unwrap() = true;
+            self.opened.notify_all();
+        }
+    }
+
+    impl CompressionScheme for Gated {
+        fn compress(&self, rx: &[u8], tx: &mut [u8]) -> std::result::Result<usize, CompressError> {
+            self.entered.fetch_add(1, Ordering::SeqCst);
+            let open = self.open.lock().unwrap();
+            drop(self.opened.wait_while(open, |open| !*open).unwrap());
+            RlePrefix::default().compress(rx, tx)
+        }
+
+        fn decompress(
+            &self,
+            rx: &[u8],
+            tx: &mut [u8],
+        ) -> std::result::Result<usize, DecompressError> {
+            RlePrefix::default().decompress(rx, tx)
+        }
+
+        fn bound(&self, input_len: usize) -> usize {
+            RlePrefix::default().bound(input_len)
+        }
+
+        fn validate_payload(&self, payload: &[u8]) -> Response {
+            RlePrefix::default().validate_payload(payload)
+        }
+
+        fn name(&self) -> &'static str {
+            "gated"
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_control_lane_under_load() {
+        // compressions held at the gate keep the heavy lane full for as long
+        // as the test needs, on the blocking pool under the deadline
+        let gate = Arc::new(Gated::default());
+        let config = ServerConfig {
+            heavy_lane: 2,
+            scheme: Some(gate.clone()),
+            request_deadline: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let server = Server::new_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap()
+            .spawn();
+        let addr = server.local_addr();
+        let handle = server.stats().clone();
+
+        // two compressions in the lane, two more waiting for a turn
+        let flooding: Vec<_> = (0..4)
+            .map(|_| {
+                tokio::spawn(async move {
+                    let mut stream = TcpStream::connect(addr).await.unwrap();
+                    compress(&mut stream, b"aaaabb").await
+                })
+            })
+            .collect();
+        while gate.entered.load(Ordering::SeqCst) < 2 || handle.lane_depth(Lane::Heavy) < 4 {
+            tokio::task::yield_now().await;
+        }
+
+        // a Ping is answered before any of the backlog drains
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let ping = message::Header::request(Request::Ping, 0).unwrap();
+        let mut response = [0u8; message::HEADER_SIZE];
+        for _ in 0..20 {
+            stream
+                .write_all(zerocopy::AsBytes::as_bytes(&ping))
+                .await
+                .unwrap();
+            stream.read_exact(&mut response).await.unwrap();
+            assert_eq!(response[7], Response::Ok as u8);
+        }
+        // the gate is opened before asserting, a failure never leaves the
+        // runtime waiting on the blocking pool
+        let backlog = (
+            gate.entered.load(Ordering::SeqCst),
+            handle.lane_depth(Lane::Heavy),
+            flooding.iter().any(|client| client.is_finished()),
+        );
+        gate.open();
+        assert_eq!(backlog, (2, 4, false));
+        for client in flooding {
+            assert_eq!(client.await.unwrap(), (0, b"4abb".to_vec()));
+        }
+        // a turn ends before its response is written
+        while handle.lane_depth(Lane::Heavy) + handle.lane_depth(Lane::Control) > 0 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_heavy_lane_with_stalled_peers() {
+        let config = ServerConfig {
+            heavy_lane: 2,
+            ..Default::default()
+        };
+        let server = Server::new_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap()
+            .spawn();
+        let addr = server.local_addr();
+
+        // peers that never read compressions that don't compress, their
+        // responses fill the socket buffers and the server's writes to them
+        // wait, as do their writes to the server
+        let payload: Vec<u8> = b"ab".repeat(MAX_PAYLOAD as usize / 2);
+        let request = compress_request(&payload);
+        let sent: Vec<_> = (0..4).map(|_| Arc::new(AtomicUsize::new(0))).collect();
+        let stalled: Vec<_> = sent
+            .iter()
+            .map(|sent| {
+                let (request, sent) = (request.clone(), sent.clone());
+                tokio::spawn(async move {
+                    let mut stream = TcpStream::connect(addr).await.unwrap();
+                    loop {
+                        stream.write_all(&request).await.unwrap();
+                        sent.fetch_add(1, Ordering::Relaxed);
+                    }
+                })
+            })
+            .collect();
+        let progress = || {
+            sent.iter()
+                .map(|sent| sent.load(Ordering::Relaxed))
+                .collect()
+        };
+        let mut last: Vec<usize> = progress();
+        loop {
+            time::sleep(Duration::from_millis(200)).await;
+            let now = progress();
+            if now.iter().all(|&n| n > 0) && now == last {
+                break;
+            }
+            last = now;
+        }
+
+        // a fresh connection's Compress is answered all the same
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let answered = time::timeout(Duration::from_secs(5), compress(&mut stream, b"aaaabb"));
+        assert_eq!(answered.await.unwrap(), (0, b"4abb".to_vec()));
+        for peer in stalled {
+            peer.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let mut server = Server::new_with_url("127.0.0.1:0").await.unwrap().spawn();
+        // nothing connects, the server is waiting to accept
+        tokio::task::yield_now().await;
+        let shutdown = time::timeout(Duration::from_secs(1), server.shutdown()).await;
+        shutdown.unwrap().unwrap();
+        // again, and awaiting the stopped server, do nothing
+        server.shutdown().await.unwrap();
+        server.abort();
+        (&mut server).await.unwrap();
+        assert!(TcpStream::connect(server.local_addr()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_abort() {
+        let server = Server::new_with_url("127.0.0.1:0").await.unwrap().spawn();
+        server.abort();
+        let err = server.await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Interrupted);
+    }
+
+    /// Sends a Compress request, returning the response code and payload
+    async fn compress(stream: &mut TcpStream, payload: &[u8]) -> (u8, Vec<u8>) {
+        stream.write_all(&compress_request(payload)).await.unwrap();
+        let mut header = [0u8; message::HEADER_SIZE];
+        stream.read_exact(&mut header).await.unwrap();
+        let mut payload = vec![0u8; header[5] as usize];
+        stream.read_exact(&mut payload).await.unwrap();
+        (header[7], payload)
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let server = Server::new_with_url("127.0.0.1:0").await.unwrap();
+        let config = server.config_handle();
+        let server = server.spawn();
+        let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+        assert_eq!(
+            compress(&mut stream, b"aaaabb").await,
+            (0, b"4abb".to_vec())
+        );
+
+        // a tighter min_run, seen by the same connection
+        let reloaded = ServerConfig {
+            min_run: 2,
+            max_connections: 8,
+            ..Default::default()
+        };
+        assert_eq!(config.reload(reloaded).unwrap(), ["max_connections"]);
+        assert_eq!(config.current().max_connections, 0);
+        assert_eq!(
+            compress(&mut stream, b"aaaabb").await,
+            (0, b"4a2b".to_vec())
+        );
+
+        // an invalid config is rejected, the current one is kept
+        let invalid = ServerConfig {
+            min_run: 1,
+            ..Default::default()
+        };
+        let err = config.reload(invalid).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(config.current().min_run, 2);
+        assert_eq!(
+            compress(&mut stream, b"aaaabb").await,
+            (0, b"4a2b".to_vec())
+        );
+    }
+}
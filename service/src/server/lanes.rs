@@ -0,0 +1,167 @@
+use crate::message::Request;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Which of the `Lanes` a request goes through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+    /// Requests whose handling is bounded and small, Ping, GetStats,
+    /// GetConfig and ResetStats, along with those failing to parse
+    Control = 0,
+    /// Compress and Decompress, binary or not, CompressWithStats and Batch,
+    /// whose handling grows with their payload
+    Heavy = 1,
+}
+
+impl Lane {
+    /// The lane of the request in `bytes`, by its header alone
+    pub fn of(bytes: &[u8]) -> Lane {
+        match Request::of(bytes) {
+            Some(Request::Compress)
+            | Some(Request::Decompress)
+            | Some(Request::CompressBinary)
+            | Some(Request::DecompressBinary)
+            | Some(Request::CompressWithStats)
+            | Some(Request::Batch) => Lane::Heavy,
+            _ => Lane::Control,
+        }
+    }
+}
+
+/// Schedules the requests of every connection of a `Server` across two
+/// lanes. Control requests go straight to being handled, at most `heavy`
+/// requests of the heavy lane are let through at once and the others wait
+/// for their turn, so a monitor's Ping never queues behind more than those
+/// whatever the number of connections flooding the service with Compress
+/// requests. A connection still handles its own requests in order, only one
+/// of them is ever in a lane
+///
+/// Cheap to clone, clones share the lanes
+#[derive(Clone)]
+pub struct Lanes {
+    heavy: Arc<Semaphore>,
+    /// Requests waiting in or going through each lane
+    depths: Arc<[AtomicUsize; 2]>,
+}
+
+impl Lanes {
+    /// Lanes letting `heavy` requests of the heavy lane through at once, 0 is
+    /// unlimited
+    pub fn new_with(heavy: usize) -> Lanes {
+        let permits = match heavy {
+            0 => Semaphore::MAX_PERMITS,
+            n => n,
+        };
+        Lanes {
+            heavy: Arc::new(Semaphore::new(permits)),
+            depths: Default::default(),
+        }
+    }
+
+    /// Waits for the turn of a request of `lane`, which lasts until the
+    /// returned `Turn` is dropped
+    pub async fn enter(&self, lane: Lane) -> Turn {
+        self.depths[lane as usize].fetch_add(1, Ordering::SeqCst);
+        let mut turn = Turn {
+            lanes: self.clone(),
+            lane,
+            _permit: None,
+        };
+        if lane == Lane::Heavy {
+            // the semaphore is never closed
+            turn._permit = Some(Arc::clone(&self.heavy).acquire_owned().await.unwrap());
+        }
+        turn
+    }
+
+    /// Requests waiting in or going through `lane`
+    pub fn depth(&self, lane: Lane) -> usize {
+        self.depths[lane as usize].load(Ordering::SeqCst)
+    }
+}
+
+impl Default for Lanes {
+    fn default() -> Lanes {
+        Lanes::new_with(0)
+    }
+}
+
+impl fmt::Debug for Lanes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Lanes")
+            .field("control", &self.depth(Lane::Control))
+            .field("heavy", &self.depth(Lane::Heavy))
+            .finish()
+    }
+}
+
+/// A request's turn in its lane, see `Lanes::enter`
+#[derive(Debug)]
+pub struct Turn {
+    lanes: Lanes,
+    lane: Lane,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        self.lanes.depths[self.lane as usize].fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::wire;
+    use std::time::Duration;
+    use tokio::time;
+
+    fn request(code: Request) -> Vec<u8> {
+        wire::request(code, b"")
+    }
+
+    #[test]
+    fn test_lane_of() {
+        assert_eq!(Lane::of(&request(Request::Compress)), Lane::Heavy);
+        assert_eq!(Lane::of(&request(Request::Decompress)), Lane::Heavy);
+        assert_eq!(Lane::of(&request(Request::CompressBinary)), Lane::Heavy);
+        assert_eq!(Lane::of(&request(Request::DecompressBinary)), Lane::Heavy);
+        assert_eq!(Lane::of(&request(Request::Ping)), Lane::Control);
+        assert_eq!(Lane::of(&request(Request::GetStats)), Lane::Control);
+        assert_eq!(Lane::of(&[0u8; 3]), Lane::Control);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_enter() {
+        let lanes = Lanes::new_with(1);
+        let heavy = lanes.enter(Lane::Heavy).await;
+        // the heavy lane is full, control requests don't wait on it
+        let waiting = time::timeout(Duration::from_secs(1), lanes.enter(Lane::Heavy));
+        assert!(waiting.await.is_err());
+        let control = lanes.enter(Lane::Control).await;
+        assert_eq!(
+            (lanes.depth(Lane::Control), lanes.depth(Lane::Heavy)),
+            (1, 1)
+        );
+
+        let next = {
+            let lanes = lanes.clone();
+            tokio::spawn(async move { lanes.enter(Lane::Heavy).await })
+        };
+        tokio::task::yield_now().await;
+        assert_eq!(lanes.depth(Lane::Heavy), 2);
+        drop(heavy);
+        let next = next.await.unwrap();
+        drop((control, next));
+        assert_eq!(
+            (lanes.depth(Lane::Control), lanes.depth(Lane::Heavy)),
+            (0, 0)
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use crate::message::{Request, CODE_MASK};
+use byteorder::NetworkEndian;
+use zerocopy::{
+    byteorder::{U16, U32},
+    AsBytes, ByteSlice, FromBytes, LayoutVerified, Unaligned,
+};
+
+/// Bits of the `features` field of `Limits`
+pub struct Feature;
+
+impl Feature {
+    /// Connections are encrypted with TLS
+    pub const TLS: u8 = 1 << 0;
+    /// Decompress requests are served
+    pub const DECOMPRESS: u8 = 1 << 1;
+    /// Payloads larger than `max_payload` can be sent in chunks
+    pub const CHUNKING: u8 = 1 << 2;
+    /// Payloads can carry checksums
+    pub const CHECKSUMS: u8 = 1 << 3;
+    /// Compress and Ping requests can be sent together in a Batch
+    pub const BATCH: u8 = 1 << 4;
+    /// Requests can be numbered, the responses echo their numbers
+    pub const SEQUENCE: u8 = 1 << 5;
+    /// Error responses to malformed requests carry why in ASCII, see
+    /// `MAX_ERROR_DETAIL`
+    pub const ERROR_DETAILS: u8 = 1 << 6;
+    /// A ResetStats can carry a token after its scope, one repeating a recent
+    /// token of the connection is answered as before without resetting again,
+    /// see `RESET_TOKEN_LEN`
+    pub const IDEMPOTENT_RESET: u8 = 1 << 7;
+}
+
+/// The GetConfig payload, the limits a client has to respect
+/// version: The protocol version spoken by the service
+/// features: `Feature` bits of the service
+/// max_payload: Largest payload accepted in a request
+/// max_message: Largest message, header included, accepted in a request
+/// idle_timeout: Seconds a connection may stay idle, 0 if it never times out
+/// rate_limit: Requests accepted per second, 0 if unlimited
+///
+/// Like `Stats`, every field is unaligned so `repr(C)` lays out the 14 bytes
+/// without padding, all integers in network order
+#[derive(Default, Debug, Clone, PartialEq, AsBytes, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct Limits {
+    version: u8,
+    features: u8,
+    max_payload: U16<NetworkEndian>,
+    max_message: U16<NetworkEndian>,
+    idle_timeout: U32<NetworkEndian>,
+    rate_limit: U32<NetworkEndian>,
+}
+
+impl Limits {
+    pub fn new_with(
+        version: u8,
+        features: u8,
+        max_payload: u16,
+        max_message: u16,
+        idle_timeout: u32,
+        rate_limit: u32,
+    ) -> Limits {
+        Limits {
+            version,
+            features,
+            max_payload: U16::new(max_payload),
+            max_message: U16::new(max_message),
+            idle_timeout: U32::new(idle_timeout),
+            rate_limit: U32::new(rate_limit),
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn features(&self) -> u8 {
+        self.features
+    }
+
+    pub fn max_payload(&self) -> u16 {
+        self.max_payload.get()
+    }
+
+    pub fn max_message(&self) -> u16 {
+        self.max_message.get()
+    }
+
+    pub fn idle_timeout(&self) -> u32 {
+        self.idle_timeout.get()
+    }
+
+    pub fn rate_limit(&self) -> u32 {
+        self.rate_limit.get()
+    }
+
+    pub fn parse<B: ByteSlice>(bytes: B) -> Option<LayoutVerified<B, Limits>> {
+        LayoutVerified::new(bytes)
+    }
+}
+
+/// The payload of an UnsupportedRequestType under
+/// `ServerConfig::describe_unsupported`, so that a client can tell a service
+/// too old for a request from a request built wrong
+/// version: The protocol version spoken by the service
+/// requests: The request codes served, a bit each, code `n` being bit
+/// `n % 8` of byte `n / 8`
+///
+/// Like `Limits` every field is unaligned, the 33 bytes are laid out without
+/// padding
+#[derive(Default, Debug, Clone, PartialEq, AsBytes, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct ServedRequests {
+    version: u8,
+    requests: [u8; 32],
+}
+
+impl ServedRequests {
+    pub fn new_with<I: IntoIterator<Item = Request>>(version: u8, requests: I) -> ServedRequests {
+        let mut served = ServedRequests {
+            version,
+            ..Default::default()
+        };
+        for request in requests {
+            let code = request as usize;
+            served.requests[code / 8] |= 1 << (code % 8);
+        }
+        served
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Whether the request of `code` is served, any flags set on it aside
+    pub fn serves(&self, code: u16) -> bool {
+        let code = (code & CODE_MASK) as usize;
+        self.requests[code / 8] & 1 << (code % 8) != 0
+    }
+
+    /// The requests served that are known to this build, in order of their
+    /// codes
+    pub fn requests(&self) -> impl Iterator<Item = Request> + '_ {
+        (0..=CODE_MASK)
+            .filter(move |&code| self.serves(code))
+            .filter_map(Request::from_u16)
+    }
+
+    pub fn parse<B: ByteSlice>(bytes: B) -> Option<LayoutVerified<B, ServedRequests>> {
+        LayoutVerified::new(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Feature, Limits, ServedRequests};
+    use crate::message::Request;
+    use std::mem;
+    use zerocopy::AsBytes;
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(mem::size_of::<Limits>(), 14);
+        assert_eq!(mem::align_of::<Limits>(), 1);
+    }
+
+    #[test]
+    fn test_as_bytes() {
+        let limits = Limits::new_with(1, Feature::DECOMPRESS, 8192, 8200, 30, 0);
+        assert_eq!(
+            limits.as_bytes(),
+            [1, 2, 32, 0, 32, 8, 0, 0, 0, 30, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let msg = [1, 10, 16, 0, 16, 8, 0, 0, 1, 44, 0, 0, 3, 232];
+        let limits = Limits::parse(&msg[..]).unwrap();
+        assert_eq!(
+            (limits.version(), limits.features()),
+            (1, Feature::DECOMPRESS | Feature::CHECKSUMS)
+        );
+        assert_eq!((limits.max_payload(), limits.max_message()), (4096, 4104));
+        assert_eq!((limits.idle_timeout(), limits.rate_limit()), (300, 1000));
+        assert!(Limits::parse(&msg[..13]).is_none());
+    }
+
+    #[test]
+    fn test_served_requests() {
+        assert_eq!(mem::size_of::<ServedRequests>(), 33);
+        let served =
+            ServedRequests::new_with(1, [Request::Ping, Request::Compress, Request::Health]);
+        let mut expected = [0u8; 33];
+        expected[..3].copy_from_slice(&[1, 0b0001_0010, 0b0100_0000]);
+        assert_eq!(served.as_bytes(), expected);
+        assert_eq!(
+            served.requests().collect::<Vec<_>>(),
+            [Request::Ping, Request::Compress, Request::Health]
+        );
+        assert!(served.serves(0x0100 | Request::Ping as u16));
+        assert!(!served.serves(200));
+
+        // a code unknown to this build is served all the same
+        expected[1 + 200 / 8] = 1;
+        let parsed = ServedRequests::parse(&expected[..]).unwrap();
+        assert!(parsed.serves(200));
+        assert_eq!(parsed.requests().count(), 3);
+        assert!(ServedRequests::parse(&expected[..32]).is_none());
+    }
+}
@@ -0,0 +1,108 @@
+use super::stats::Stats;
+use std::fmt::Write;
+
+/// The counters of a `State` exported as metrics, apart from the stats of the
+/// protocol. GetStats reports a ratio blended over the requests since the
+/// stats were last reset, which goes back to 0 with every ResetStats. These
+/// only ever go up instead: the bytes in and out of Compress requests, from
+/// which a collector derives the ratio over any window, and the resets
+/// themselves, so that it can tell when the stats of the protocol went back
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metrics {
+    /// Bytes of the payloads of Compress requests
+    pub compress_input_bytes: u64,
+    /// Bytes they were compressed to
+    pub compress_output_bytes: u64,
+    /// Times the stats of the protocol were reset, see `State::reset`
+    pub resets: u64,
+}
+
+impl Metrics {
+    /// Accounts for a Compress request of `input` bytes compressed to
+    /// `output`, saturating rather than wrapping
+    pub fn update_compress(&mut self, input: usize, output: usize) {
+        self.compress_input_bytes = self.compress_input_bytes.saturating_add(input as u64);
+        self.compress_output_bytes = self.compress_output_bytes.saturating_add(output as u64);
+    }
+
+    pub fn update_reset(&mut self) {
+        self.resets = self.resets.saturating_add(1);
+    }
+
+    /// The percentage saved over every Compress request since the service
+    /// started, 0 before the first
+    pub fn ratio_lifetime(&self) -> u8 {
+        Stats::ratio_of(self.compress_output_bytes, self.compress_input_bytes).unwrap_or(0)
+    }
+
+    /// The metrics in the Prometheus text format, each with its help and
+    /// type, e.g. "...\n# TYPE compress_input_bytes_total counter\n
+    /// compress_input_bytes_total 120\n..."
+    pub fn to_prometheus(&self) -> String {
+        let metrics = [
+            (
+                "compress_input_bytes_total",
+                "counter",
+                "Bytes of the payloads of Compress requests, never reset",
+                self.compress_input_bytes,
+            ),
+            (
+                "compress_output_bytes_total",
+                "counter",
+                "Bytes the payloads of Compress requests were compressed to, never reset",
+                self.compress_output_bytes,
+            ),
+            (
+                "compress_ratio_lifetime",
+                "gauge",
+                "Percentage saved over every Compress request since the service started",
+                self.ratio_lifetime() as u64,
+            ),
+            (
+                "resets_total",
+                "counter",
+                "Resets of the stats GetStats reports, which the totals ignore",
+                self.resets,
+            ),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = write!(
+                text,
+                "# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+                name, help, name, kind, name, value
+            );
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_prometheus() {
+        let mut metrics = Metrics::default();
+        assert_eq!(metrics.ratio_lifetime(), 0);
+        metrics.update_compress(16, 8);
+        metrics.update_compress(4, 4);
+        metrics.update_reset();
+        assert_eq!(metrics.ratio_lifetime(), 40);
+
+        let text = metrics.to_prometheus();
+        let samples: Vec<&str> = text.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            samples,
+            [
+                "compress_input_bytes_total 20",
+                "compress_output_bytes_total 12",
+                "compress_ratio_lifetime 40",
+                "resets_total 1"
+            ]
+        );
+        assert!(text.contains("# TYPE compress_input_bytes_total counter\n"));
+        assert!(text.contains("# TYPE compress_ratio_lifetime gauge\n"));
+        assert!(text.ends_with('\n'));
+    }
+}
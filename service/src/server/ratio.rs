@@ -0,0 +1,242 @@
+use crate::stats::Stats;
+use std::{collections::VecDeque, fmt, str::FromStr};
+
+/// The largest window of a `RatioPolicy::LastN`
+pub const MAX_RATIO_WINDOW: usize = 1024;
+
+/// How the compression ratio of the stats is computed from the Compress
+/// requests handled since they were last reset
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RatioPolicy {
+    /// Over the bytes of every request, so one early large incompressible
+    /// payload weighs on the ratio until the stats are reset
+    #[default]
+    Cumulative,
+    /// Over the ratios of the requests, each weighted by the alpha (0 to 1)
+    /// against the average of those before it, a request's weight decays
+    /// with each one after it
+    ExponentialMovingAverage(f64),
+    /// Over the bytes of the last requests of the window, at most
+    /// `MAX_RATIO_WINDOW` of them
+    LastN(usize),
+}
+
+impl RatioPolicy {
+    /// Checks the alpha or the window is in range
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            RatioPolicy::ExponentialMovingAverage(alpha) if !(alpha > 0.0 && alpha <= 1.0) => {
+                Err(format!("the alpha of ema:{} is not within (0, 1]", alpha))
+            }
+            RatioPolicy::LastN(window) if window == 0 || window > MAX_RATIO_WINDOW => Err(format!(
+                "the window of last:{} is not within 1-{}",
+                window, MAX_RATIO_WINDOW
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for RatioPolicy {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RatioPolicy::Cumulative => write!(fmt, "cumulative"),
+            RatioPolicy::ExponentialMovingAverage(alpha) => write!(fmt, "ema:{}", alpha),
+            RatioPolicy::LastN(window) => write!(fmt, "last:{}", window),
+        }
+    }
+}
+
+/// Parses the `Display` form of a policy, "cumulative", "ema:ALPHA" or
+/// "last:N"
+impl FromStr for RatioPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RatioPolicy, String> {
+        let invalid = || format!("{} is not cumulative, ema:ALPHA or last:N", s);
+        let policy = match s.split_once(':') {
+            None if s == "cumulative" => RatioPolicy::Cumulative,
+            Some(("ema", alpha)) => {
+                RatioPolicy::ExponentialMovingAverage(alpha.parse().map_err(|_| invalid())?)
+            }
+            Some(("last", window)) => RatioPolicy::LastN(window.parse().map_err(|_| invalid())?),
+            _ => return Err(invalid()),
+        };
+        policy.validate().map(|_| policy)
+    }
+}
+
+/// What the ratio under any `RatioPolicy` is computed from, the totals of
+/// the Compress requests, the moving average of their ratios and the sizes
+/// of the last ones
+///
+/// Shared by the service's `State` and the mirror of it the test client
+/// keeps, so the two always agree on the ratio GetStats reports
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RatioTracker {
+    total: u64,      // Total bytes received from compression requests
+    compressed: u64, // Total bytes sent after compressing them
+    expanded: u64,   // Requests compressed to more bytes than they had, see `update`
+    /// The moving average of the compressed size over the total, `None`
+    /// until a request is accounted for under the policy
+    average: Option<f64>,
+    /// The (total, compressed) bytes of the last requests, only kept under
+    /// `RatioPolicy::LastN`
+    recent: VecDeque<(u64, u64)>,
+}
+
+impl RatioTracker {
+    /// A tracker that already accounted for requests of `total` bytes in all
+    /// compressed to `compressed`
+    pub fn new_with(total: u64, compressed: u64) -> RatioTracker {
+        RatioTracker {
+            total,
+            compressed,
+            ..Default::default()
+        }
+    }
+
+    /// Accounts for a request of `total` bytes compressed to `compressed`,
+    /// returning the ratio under `policy`, `None` if it is left as it was
+    ///
+    /// The totals saturate rather than wrap. An output larger than its input,
+    /// which `RleBinary` produces for short runs, is counted, the ratio it
+    /// makes is 0 rather than negative
+    pub fn update(&mut self, policy: &RatioPolicy, total: usize, compressed: usize) -> Option<u8> {
+        if compressed > total {
+            self.expanded += 1;
+        }
+        let (total, compressed) = (total as u64, compressed as u64);
+        self.total = self.total.saturating_add(total);
+        self.compressed = self.compressed.saturating_add(compressed);
+        match *policy {
+            RatioPolicy::Cumulative => Stats::ratio_of(self.compressed, self.total),
+            RatioPolicy::ExponentialMovingAverage(alpha) => {
+                Stats::ratio_of(compressed, total)?;
+                let fraction = compressed as f64 / total as f64;
+                let average = match self.average {
+                    Some(average) => alpha * fraction + (1.0 - alpha) * average,
+                    None => fraction,
+                };
+                self.average = Some(average);
+                Some(Stats::ratio_from(average))
+            }
+            RatioPolicy::LastN(window) => {
+                if self.recent.len() >= window {
+                    self.recent.drain(..=self.recent.len() - window);
+                }
+                self.recent.push_back((total, compressed));
+                let (total, compressed) = self
+                    .recent
+                    .iter()
+                    .fold((0, 0), |(t, c), (total, compressed)| {
+                        (t + total, c + compressed)
+                    });
+                Stats::ratio_of(compressed, total)
+            }
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn compressed(&self) -> u64 {
+        self.compressed
+    }
+
+    /// Requests compressed to more bytes than they had, see `update`
+    pub fn expanded(&self) -> u64 {
+        self.expanded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The ratio after each of `requests` under `policy`
+    fn ratios(policy: RatioPolicy, requests: &[(usize, usize)]) -> Vec<Option<u8>> {
+        let mut tracker = RatioTracker::default();
+        requests
+            .iter()
+            .map(|&(total, compressed)| tracker.update(&policy, total, compressed))
+            .collect()
+    }
+
+    /// An early large incompressible request, then small ones halved
+    const REQUESTS: [(usize, usize); 5] =
+        [(10_000, 10_000), (100, 50), (100, 50), (100, 50), (100, 50)];
+
+    #[test]
+    fn test_cumulative() {
+        assert_eq!(
+            ratios(RatioPolicy::Cumulative, &REQUESTS),
+            [Some(0), Some(0), Some(1), Some(1), Some(2)]
+        );
+    }
+
+    #[test]
+    fn test_exponential_moving_average() {
+        // 1.0, then 0.75, 0.625, 0.5625 and 0.53125 of the sizes
+        assert_eq!(
+            ratios(RatioPolicy::ExponentialMovingAverage(0.5), &REQUESTS),
+            [Some(0), Some(25), Some(38), Some(44), Some(47)]
+        );
+        // only the request at hand
+        assert_eq!(
+            ratios(RatioPolicy::ExponentialMovingAverage(1.0), &REQUESTS),
+            [Some(0), Some(50), Some(50), Some(50), Some(50)]
+        );
+    }
+
+    #[test]
+    fn test_last_n() {
+        assert_eq!(
+            ratios(RatioPolicy::LastN(2), &REQUESTS),
+            [Some(0), Some(0), Some(50), Some(50), Some(50)]
+        );
+        assert_eq!(
+            ratios(RatioPolicy::LastN(3), &REQUESTS),
+            [Some(0), Some(0), Some(1), Some(50), Some(50)]
+        );
+    }
+
+    #[test]
+    fn test_expanded() {
+        let mut tracker = RatioTracker::default();
+        let policy = RatioPolicy::Cumulative;
+        assert_eq!(tracker.update(&policy, 7, 9), Some(0));
+        assert_eq!(tracker.update(&policy, 100, 50), Some(45));
+        assert_eq!((tracker.total(), tracker.compressed()), (107, 59));
+        assert_eq!(tracker.expanded(), 1);
+        // under the moving average too, never below 0
+        let mut tracker = RatioTracker::default();
+        let policy = RatioPolicy::ExponentialMovingAverage(0.5);
+        assert_eq!(tracker.update(&policy, 1, 100), Some(0));
+        assert_eq!(tracker.expanded(), 1);
+
+        // the totals saturate
+        let mut tracker = RatioTracker::new_with(u64::MAX - 1, u64::MAX / 2);
+        assert_eq!(tracker.update(&RatioPolicy::Cumulative, 10, 5), Some(50));
+        assert_eq!(tracker.total(), u64::MAX);
+    }
+
+    #[test]
+    fn test_parse() {
+        for policy in ["cumulative", "ema:0.25", "last:32"] {
+            assert_eq!(policy.parse::<RatioPolicy>().unwrap().to_string(), policy);
+        }
+        for invalid in [
+            "",
+            "ema",
+            "ema:0",
+            "ema:1.5",
+            "last:0",
+            "last:1025",
+            "mean:3",
+        ] {
+            assert!(invalid.parse::<RatioPolicy>().is_err(), "{}", invalid);
+        }
+    }
+}
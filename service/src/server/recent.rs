@@ -0,0 +1,308 @@
+use crate::message::HEADER_SIZE;
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Payload bytes a `RequestSummary` keeps when they are captured, see
+/// `ServerConfig::capture_payload_prefix`
+pub const PAYLOAD_PREFIX: usize = 32;
+
+/// What `Recent` remembers of a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestSummary {
+    /// When the request was read
+    pub at: SystemTime,
+    /// The connection it was read on, numbered from 1 in the order the
+    /// connections were accepted
+    pub connection: u64,
+    pub peer: Option<SocketAddr>,
+    /// The code field of the header as received, flags included, 0 if the
+    /// request was shorter than a header
+    pub code: u16,
+    /// Bytes read past the header, whatever its size field says
+    pub payload_len: usize,
+    /// The code of the response sent back
+    pub response: u16,
+    /// From reading the request to writing its response
+    pub duration: Duration,
+    /// The first `PAYLOAD_PREFIX` bytes of the payload, only captured with
+    /// `ServerConfig::capture_payload_prefix`
+    pub payload_prefix: Option<Vec<u8>>,
+}
+
+impl RequestSummary {
+    /// Appends the summary as a JSON object to `out`, e.g.
+    /// {"at_ms":1700000000000,"connection":1,"peer":"127.0.0.1:50000","code":4,
+    /// "payload_len":16,"response":0,"duration_us":120,"payload_prefix":null}
+    pub fn write_json(&self, out: &mut String) {
+        let at = self.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        // a SocketAddr has nothing that needs escaping
+        let peer = match self.peer {
+            Some(peer) => format!("\"{}\"", peer),
+            None => "null".to_string(),
+        };
+        let prefix = match &self.payload_prefix {
+            Some(prefix) => format!("\"{}\"", hex(prefix)),
+            None => "null".to_string(),
+        };
+        // writing to a String never fails
+        let _ = write!(
+            out,
+            "{{\"at_ms\":{},\"connection\":{},\"peer\":{},\"code\":{},\"payload_len\":{},\
+             \"response\":{},\"duration_us\":{},\"payload_prefix\":{}}}",
+            at.as_millis(),
+            self.connection,
+            peer,
+            self.code,
+            self.payload_len,
+            self.response,
+            self.duration.as_micros(),
+            prefix
+        );
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+/// The last requests the connections of a `Server` handled, oldest first, so
+/// that what the service saw before it started failing can be looked at
+/// after the fact. Holds at most `capacity` of them, the oldest is evicted
+/// for each one recorded past that, a capacity of 0 records nothing
+///
+/// Guarded by a mutex of its own, held only to push or copy out summaries,
+/// never along with the lock of the `State`
+///
+/// Cheap to clone, clones share the requests
+#[derive(Debug, Clone, Default)]
+pub struct Recent {
+    capacity: usize,
+    summaries: Arc<Mutex<VecDeque<RequestSummary>>>,
+}
+
+impl Recent {
+    pub fn new_with(capacity: usize) -> Recent {
+        Recent {
+            capacity,
+            summaries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Where the requests of connection number `connection` to `peer` are
+    /// recorded
+    pub fn connection(&self, connection: u64, peer: Option<SocketAddr>) -> Recorder {
+        Recorder {
+            recent: self.clone(),
+            connection,
+            peer,
+        }
+    }
+
+    pub fn record(&self, summary: RequestSummary) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut summaries = self.summaries.lock().unwrap();
+        if summaries.len() == self.capacity {
+            summaries.pop_front();
+        }
+        summaries.push_back(summary);
+    }
+
+    /// A copy of the requests recorded, oldest first
+    pub fn summaries(&self) -> Vec<RequestSummary> {
+        self.summaries.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// The requests recorded as a JSON array, oldest first, on a single line
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("[");
+        for (i, summary) in self.summaries().iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            summary.write_json(&mut json);
+        }
+        json.push(']');
+        json
+    }
+}
+
+/// Records the requests of one connection into a `Recent`, see
+/// `Recent::connection`. The default records nothing
+#[derive(Debug, Clone, Default)]
+pub struct Recorder {
+    recent: Recent,
+    connection: u64,
+    peer: Option<SocketAddr>,
+}
+
+impl Recorder {
+    /// Records a request read at `at` and answered with `response` after
+    /// `duration`. `request` is the start of it as read, header included,
+    /// out of `read` bytes in all. The payload prefix is only kept if
+    /// `capture`
+    pub fn record(
+        &self,
+        request: &[u8],
+        read: usize,
+        response: u16,
+        at: SystemTime,
+        duration: Duration,
+        capture: bool,
+    ) {
+        if self.recent.capacity == 0 {
+            return;
+        }
+        let summary = self.summarize(request, read, response, at, duration, capture);
+        self.recent.record(summary);
+    }
+
+    /// The summary `record` keeps of a request, whether or not any are kept
+    pub fn summarize(
+        &self,
+        request: &[u8],
+        read: usize,
+        response: u16,
+        at: SystemTime,
+        duration: Duration,
+        capture: bool,
+    ) -> RequestSummary {
+        let code = match request {
+            [_, _, _, _, _, _, high, low, ..] => u16::from_be_bytes([*high, *low]),
+            _ => 0,
+        };
+        let payload = request.get(HEADER_SIZE..).unwrap_or_default();
+        let payload_prefix = if capture {
+            Some(payload[..payload.len().min(PAYLOAD_PREFIX)].to_vec())
+        } else {
+            None
+        };
+        RequestSummary {
+            at,
+            connection: self.connection,
+            peer: self.peer,
+            code,
+            payload_len: read.saturating_sub(HEADER_SIZE),
+            response,
+            duration,
+            payload_prefix,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Request, Response};
+    use crate::testing::wire::request;
+
+    #[test]
+    fn test_eviction() {
+        let recent = Recent::new_with(3);
+        let peer = "127.0.0.1:50000".parse().unwrap();
+        let recorder = recent.connection(7, Some(peer));
+        for i in 0..5u64 {
+            let at = UNIX_EPOCH + Duration::from_secs(1_700_000_000 + i);
+            let ping = request(Request::Ping, b"");
+            recorder.record(&ping, ping.len(), 0, at, Duration::from_micros(i), false);
+        }
+        let summaries = recent.summaries();
+        assert_eq!(summaries.len(), 3);
+        // the two oldest are gone
+        let durations: Vec<_> = summaries.iter().map(|s| s.duration.as_micros()).collect();
+        assert_eq!(durations, [2, 3, 4]);
+        assert_eq!(
+            (summaries[0].connection, summaries[0].peer),
+            (7, Some(peer))
+        );
+        assert_eq!(summaries[0].code, Request::Ping as u16);
+    }
+
+    #[test]
+    fn test_payload_prefix() {
+        let recent = Recent::new_with(4);
+        let recorder = recent.connection(1, None);
+        let payload = [b'a'; 100];
+        let compress = request(Request::Compress, &payload);
+        let at = UNIX_EPOCH;
+        recorder.record(&compress, compress.len(), 0, at, Duration::ZERO, false);
+        recorder.record(&compress, compress.len(), 0, at, Duration::ZERO, true);
+        // too short for a header
+        recorder.record(
+            b"STR",
+            3,
+            Response::MessageTooSmall as u16,
+            at,
+            Duration::ZERO,
+            true,
+        );
+
+        let summaries = recent.summaries();
+        assert_eq!(summaries[0].payload_prefix, None);
+        assert_eq!(
+            summaries[1].payload_prefix,
+            Some(vec![b'a'; PAYLOAD_PREFIX])
+        );
+        assert_eq!(summaries[1].payload_len, 100);
+        assert_eq!((summaries[2].code, summaries[2].payload_len), (0, 0));
+        assert_eq!(summaries[2].payload_prefix, Some(vec![]));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let recent = Recent::new_with(0);
+        let ping = request(Request::Ping, b"");
+        let recorder = recent.connection(1, None);
+        recorder.record(&ping, ping.len(), 0, UNIX_EPOCH, Duration::ZERO, true);
+        Recorder::default().record(&ping, ping.len(), 0, UNIX_EPOCH, Duration::ZERO, true);
+        assert!(recent.summaries().is_empty());
+        assert_eq!(recent.to_json(), "[]");
+    }
+
+    #[test]
+    fn test_to_json() {
+        let recent = Recent::new_with(2);
+        let at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let compress = request(Request::Compress, b"ab");
+        recent
+            .connection(1, Some("127.0.0.1:50000".parse().unwrap()))
+            .record(
+                &compress,
+                compress.len(),
+                0,
+                at,
+                Duration::from_micros(120),
+                true,
+            );
+        let ping = request(Request::Ping, b"");
+        recent.connection(2, None).record(
+            &ping,
+            ping.len(),
+            1,
+            at,
+            Duration::from_micros(5),
+            false,
+        );
+        assert_eq!(
+            recent.to_json(),
+            "[{\"at_ms\":1700000000123,\"connection\":1,\"peer\":\"127.0.0.1:50000\",\
+             \"code\":4,\"payload_len\":2,\"response\":0,\"duration_us\":120,\
+             \"payload_prefix\":\"6162\"},\
+             {\"at_ms\":1700000000123,\"connection\":2,\"peer\":null,\"code\":1,\
+             \"payload_len\":0,\"response\":1,\"duration_us\":5,\"payload_prefix\":null}]"
+        );
+    }
+}
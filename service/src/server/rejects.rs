@@ -0,0 +1,305 @@
+use super::config::RejectLogPolicy;
+use crate::message::{hexdump, HEXDUMP_ROW_WIDTH};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::time::Instant;
+
+/// Where the dumps of a `RejectLog` are written, stderr by default
+pub type RejectSink = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// A budget of bytes refilled at `rate` a second, holding at most a
+/// second's worth. A rate of 0 is unlimited
+#[derive(Debug)]
+struct Bucket {
+    rate: usize,
+    tokens: usize,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new_with(rate: usize) -> Bucket {
+        Bucket {
+            rate,
+            tokens: rate,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Refills the bucket for the time since it last was, in whole bytes
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let earned = (now - self.refilled).as_secs_f64() * self.rate as f64;
+        if earned >= 1.0 {
+            self.tokens = self.rate.min(self.tokens.saturating_add(earned as usize));
+            self.refilled = now;
+        }
+    }
+
+    fn allows(&mut self, bytes: usize) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+        self.refill();
+        self.tokens >= bytes
+    }
+
+    fn take(&mut self, bytes: usize) {
+        if self.rate > 0 {
+            self.tokens -= bytes;
+        }
+    }
+}
+
+/// What a `RejectLog` did with the rejected requests it was given
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RejectStats {
+    pub rejected: usize,
+    /// Dumped, in full or truncated to `RejectLogPolicy::max_dump`
+    pub dumped: usize,
+    /// Only counted, between the rejections sampled
+    pub sampled_out: usize,
+    /// Only counted, the connection's or the global budget was spent
+    pub throttled: usize,
+    /// Bytes of requests dumped
+    pub bytes_dumped: usize,
+    /// Bytes of the requests dumped left out by the truncation
+    pub bytes_truncated: usize,
+}
+
+#[derive(Default)]
+struct Counts {
+    rejected: AtomicUsize,
+    dumped: AtomicUsize,
+    sampled_out: AtomicUsize,
+    throttled: AtomicUsize,
+    bytes_dumped: AtomicUsize,
+    bytes_truncated: AtomicUsize,
+}
+
+/// Dumps the requests the connections of a `Server` reject, within the
+/// budgets of `ServerConfig::reject_log`, so that a flood of garbage can't
+/// make the log the bottleneck or fill the disk. What isn't dumped is
+/// counted, see `stats`
+///
+/// The global budget is shared by the connections, each also has a budget
+/// of its own, see `connection`
+///
+/// Cheap to clone, clones share the budget and the counts
+#[derive(Clone)]
+pub struct RejectLog {
+    sink: RejectSink,
+    /// Created with the rate of the policy of the first dump
+    global: Arc<Mutex<Option<Bucket>>>,
+    counts: Arc<Counts>,
+}
+
+impl Default for RejectLog {
+    fn default() -> RejectLog {
+        RejectLog::new_with(Arc::new(|dump: &str| eprintln!("{}", dump)))
+    }
+}
+
+impl fmt::Debug for RejectLog {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("RejectLog")
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl RejectLog {
+    /// A log writing its dumps to `sink`
+    pub fn new_with(sink: RejectSink) -> RejectLog {
+        RejectLog {
+            sink,
+            global: Arc::new(Mutex::new(None)),
+            counts: Arc::new(Counts::default()),
+        }
+    }
+
+    /// Logs the rejections of one connection, within a budget of its own
+    pub fn connection(&self) -> ConnectionRejects {
+        ConnectionRejects {
+            log: self.clone(),
+            budget: None,
+            seen: 0,
+        }
+    }
+
+    pub fn stats(&self) -> RejectStats {
+        let counts = &self.counts;
+        RejectStats {
+            rejected: counts.rejected.load(Ordering::Relaxed),
+            dumped: counts.dumped.load(Ordering::Relaxed),
+            sampled_out: counts.sampled_out.load(Ordering::Relaxed),
+            throttled: counts.throttled.load(Ordering::Relaxed),
+            bytes_dumped: counts.bytes_dumped.load(Ordering::Relaxed),
+            bytes_truncated: counts.bytes_truncated.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The rejections of one connection, see `RejectLog::connection`
+#[derive(Debug)]
+pub struct ConnectionRejects {
+    log: RejectLog,
+    /// Created with the rate of the policy of the first dump
+    budget: Option<Bucket>,
+    /// Rejections so far
+    seen: u64,
+}
+
+impl ConnectionRejects {
+    /// Logs `request` as read, answered with `response`. It's dumped if it's
+    /// one sampled by `policy` and both budgets have room for its dump,
+    /// otherwise it's only counted
+    pub fn reject(&mut self, policy: &RejectLogPolicy, request: &[u8], response: u16) {
+        let counts = &self.log.counts;
+        counts.rejected.fetch_add(1, Ordering::Relaxed);
+        self.seen += 1;
+        if !(self.seen - 1).is_multiple_of(policy.sample_every.max(1)) {
+            counts.sampled_out.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let rows = policy.max_dump.div_ceil(HEXDUMP_ROW_WIDTH);
+        let len = request.len().min(rows * HEXDUMP_ROW_WIDTH);
+        let budget = self
+            .budget
+            .get_or_insert_with(|| Bucket::new_with(policy.connection_rate));
+        {
+            let mut global = self.log.global.lock().unwrap();
+            let global = global.get_or_insert_with(|| Bucket::new_with(policy.global_rate));
+            if !(budget.allows(len) && global.allows(len)) {
+                counts.throttled.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            budget.take(len);
+            global.take(len);
+        }
+        counts.dumped.fetch_add(1, Ordering::Relaxed);
+        counts.bytes_dumped.fetch_add(len, Ordering::Relaxed);
+        counts
+            .bytes_truncated
+            .fetch_add(request.len() - len, Ordering::Relaxed);
+        let dump = format!(
+            "Rejected message (response code {}):\n{}",
+            response,
+            hexdump(request, rows)
+        );
+        (self.log.sink)(&dump);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time;
+
+    /// A log counting its dumps, and the dumps themselves
+    fn counted() -> (RejectLog, Arc<Mutex<Vec<String>>>) {
+        let dumps = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&dumps);
+        let log = RejectLog::new_with(Arc::new(move |dump: &str| {
+            sink.lock().unwrap().push(dump.to_string())
+        }));
+        (log, dumps)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_budgets() {
+        let policy = RejectLogPolicy {
+            max_dump: 64,
+            sample_every: 1,
+            connection_rate: 640,
+            global_rate: 1280,
+        };
+        let (log, dumps) = counted();
+        let request = [0u8; 100];
+        let mut first = log.connection();
+        for _ in 0..20 {
+            first.reject(&policy, &request, 35);
+        }
+        // 10 dumps of 64 bytes spend the connection's budget
+        let stats = log.stats();
+        assert_eq!(
+            (stats.rejected, stats.dumped, stats.throttled),
+            (20, 10, 10)
+        );
+        assert_eq!((stats.bytes_dumped, stats.bytes_truncated), (640, 360));
+        let dump = dumps.lock().unwrap()[0].clone();
+        assert!(dump.starts_with("Rejected message (response code 35):\n00000000  00"));
+        assert_eq!(dump.lines().count(), 1 + 4 + 1);
+
+        // another connection has a budget of its own, not the global one
+        let mut second = log.connection();
+        let mut third = log.connection();
+        for _ in 0..20 {
+            second.reject(&policy, &request, 35);
+            third.reject(&policy, &request, 35);
+        }
+        assert_eq!(log.stats().dumped, 20);
+
+        // refilled over time, a second's worth at most
+        time::sleep(Duration::from_secs(10)).await;
+        for _ in 0..30 {
+            first.reject(&policy, &request, 35);
+        }
+        assert_eq!(log.stats().dumped, 30);
+        time::sleep(Duration::from_millis(100)).await;
+        first.reject(&policy, &request, 35);
+        first.reject(&policy, &request, 35);
+        assert_eq!(log.stats().dumped, 31);
+        assert_eq!(dumps.lock().unwrap().len(), 31);
+    }
+
+    #[test]
+    fn test_policy_spec() {
+        let policy: RejectLogPolicy = "dump=64,every=10".parse().unwrap();
+        assert_eq!(
+            policy,
+            RejectLogPolicy {
+                max_dump: 64,
+                sample_every: 10,
+                ..Default::default()
+            }
+        );
+        let policy: RejectLogPolicy = "connection=0,global=100".parse().unwrap();
+        assert_eq!((policy.connection_rate, policy.global_rate), (0, 100));
+        assert!("dump".parse::<RejectLogPolicy>().is_err());
+        assert!("every=x".parse::<RejectLogPolicy>().is_err());
+        assert!("rate=1".parse::<RejectLogPolicy>().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sampled() {
+        let policy = RejectLogPolicy {
+            max_dump: 1024,
+            sample_every: 100,
+            connection_rate: 0,
+            global_rate: 0,
+        };
+        let (log, dumps) = counted();
+        let mut rejects = log.connection();
+        for _ in 0..1000 {
+            rejects.reject(&policy, b"STRY", 34);
+        }
+        assert_eq!(
+            log.stats(),
+            RejectStats {
+                rejected: 1000,
+                dumped: 10,
+                sampled_out: 990,
+                throttled: 0,
+                bytes_dumped: 40,
+                bytes_truncated: 0,
+            }
+        );
+        assert_eq!(dumps.lock().unwrap().len(), 10);
+    }
+}
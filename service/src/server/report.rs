@@ -0,0 +1,158 @@
+use super::state::{ConnectionGauge, State, StatsSnapshot, REQUEST_KINDS};
+use super::stats::Stats;
+use crate::message::Request;
+use std::{fmt, sync::Arc, time::Duration};
+use tokio::{sync::Mutex, time};
+
+/// What happened between two snapshots of the stats, logged every
+/// `ServerConfig::stats_interval`. All but `connections` and `ratio` are
+/// counted since the previous report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub read: u32,
+    pub sent: u32,
+    /// Valid requests handled, by request code less one
+    pub requests: [usize; REQUEST_KINDS],
+    /// Requests answered with an error response
+    pub errors: usize,
+    /// Connections open at the time of the report
+    pub connections: usize,
+    pub ratio: u8,
+}
+
+impl Report {
+    /// The report of the stats going from `previous` to `current`, if they
+    /// were reset in between the counts since the reset are reported
+    pub fn between(
+        previous: &StatsSnapshot,
+        current: &StatsSnapshot,
+        connections: usize,
+    ) -> Report {
+        let delta = current.since(previous);
+        Report {
+            read: delta.read,
+            sent: delta.sent,
+            requests: delta.requests,
+            errors: delta.errors,
+            connections,
+            ratio: current.stats.ratio(),
+        }
+    }
+}
+
+/// e.g. "Stats: read=120B sent=40B ratio=40% requests Ping 1 Compress 4
+/// errors 0 connections 2", only the kinds of requests handled are listed
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stats = Stats::new_with(self.read, self.sent, self.ratio);
+        write!(f, "Stats: {} requests", stats)?;
+        for (i, requests) in self.requests.iter().enumerate() {
+            if *requests > 0 {
+                let request = Request::from_u16(i as u16 + 1).unwrap();
+                write!(f, " {:?} {}", request, requests)?;
+            }
+        }
+        write!(
+            f,
+            " errors {} connections {}",
+            self.errors, self.connections
+        )
+    }
+}
+
+/// Passes a `Report` to `emit` every `period`, the state is only locked for
+/// as long as taking a snapshot of it. Runs until the task is dropped
+pub async fn report_stats<F>(
+    state: Arc<Mutex<State>>,
+    connections: ConnectionGauge,
+    period: Duration,
+    mut emit: F,
+) where
+    F: FnMut(Report),
+{
+    let mut interval = time::interval(period);
+    // the first tick completes at once
+    interval.tick().await;
+    let mut previous = state.lock().await.snapshot();
+    loop {
+        interval.tick().await;
+        let current = state.lock().await.snapshot();
+        emit(Report::between(&previous, &current, connections.active()));
+        previous = current;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let mut requests = [0; REQUEST_KINDS];
+        requests[Request::Ping as usize - 1] = 1;
+        requests[Request::Compress as usize - 1] = 4;
+        let report = Report {
+            read: 120,
+            sent: 40,
+            requests,
+            errors: 0,
+            connections: 2,
+            ratio: 40,
+        };
+        assert_eq!(
+            report.to_string(),
+            "Stats: read=120B sent=40B ratio=40% requests Ping 1 Compress 4 errors 0 connections 2"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_report_stats() {
+        let state = Arc::new(Mutex::new(State::new()));
+        let connections = ConnectionGauge::default();
+        let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let emitted = Arc::clone(&reports);
+        let reporter = tokio::spawn(report_stats(
+            Arc::clone(&state),
+            connections.clone(),
+            Duration::from_secs(10),
+            move |report| emitted.lock().unwrap().push(report),
+        ));
+        // updates fall halfway between the reports, at 10s, 20s and 30s
+        time::sleep(Duration::from_secs(5)).await;
+
+        {
+            let mut state = state.lock().await;
+            state.update_read(100);
+            state.update_sent(30);
+            state.update_request(&Request::Compress);
+            state.update_error();
+        }
+        let mut open: Vec<_> = (0..3).map(|_| connections.accept()).collect();
+        time::sleep(Duration::from_secs(10)).await;
+        {
+            let mut state = state.lock().await;
+            state.update_read(8);
+            state.update_request(&Request::Ping);
+        }
+        time::sleep(Duration::from_secs(10)).await;
+        // reset since the last report, the counts since are reported
+        {
+            let mut state = state.lock().await;
+            state.reset();
+            state.update_read(5);
+        }
+        open.truncate(1);
+        time::sleep(Duration::from_secs(10)).await;
+        reporter.abort();
+
+        let reports = reports.lock().unwrap().clone();
+        let summary: Vec<_> = reports
+            .iter()
+            .map(|r| (r.read, r.sent, r.errors, r.connections))
+            .collect();
+        assert_eq!(summary, [(100, 30, 1, 3), (8, 0, 0, 3), (5, 0, 0, 1)]);
+        assert_eq!(reports[0].requests[Request::Compress as usize - 1], 1);
+        assert_eq!(reports[1].requests[Request::Compress as usize - 1], 0);
+        assert_eq!(reports[1].requests[Request::Ping as usize - 1], 1);
+    }
+}
@@ -0,0 +1,491 @@
+use super::config::ServerConfig;
+use super::framing;
+use super::Server;
+use crate::message::{self, Header, Request, Response};
+use futures_core::Stream;
+use std::{
+    convert::TryFrom,
+    error::Error,
+    fmt,
+    future::{self, Future},
+    io,
+    ops::Range,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::{sync::Mutex, time};
+use zerocopy::AsBytes;
+use zeroize::Zeroize;
+
+/// The read side of a connection's framing, shared by `Server::process` and
+/// `RequestStream`: reads into a buffer of its own, answers a read a message
+/// at a time and keeps what's read past the message for the next one, see
+/// `framing::frame`
+pub(crate) struct Framer {
+    rx: Vec<u8>,
+    /// Bytes read past the last message framed, the start of the next
+    pending: usize,
+    framed: Framed,
+}
+
+/// Where a read was split by `Framer::frame`
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Framed {
+    /// Bytes in the buffer, the message and what follows it
+    pub(crate) read: usize,
+    /// Bytes of the message
+    pub(crate) len: usize,
+    /// Bytes past the message skipped before the next one
+    pub(crate) skipped: usize,
+    /// Bytes of an oversized message read past the buffer and thrown away
+    pub(crate) drained: usize,
+}
+
+impl Framer {
+    pub(crate) fn new() -> Framer {
+        Framer {
+            rx: vec![0u8; message::MAX_MESSAGE_PADDED],
+            pending: 0,
+            framed: Framed::default(),
+        }
+    }
+
+    /// Reads the next request, unless part of one is pending already, then
+    /// reads on until it's whole, see `framing::missing`. Also reports whether
+    /// the task had to wait for it, 0 bytes is the end of the stream. The end
+    /// of the stream, an idle timeout or `frame_timeout` from its first bytes
+    /// while it's cut short leave it so, it's answered for what it has
+    pub(crate) async fn read<S>(
+        &mut self,
+        stream: &mut S,
+        idle_timeout: Option<Duration>,
+        frame_timeout: Option<Duration>,
+    ) -> io::Result<(usize, bool)>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let pending = std::mem::take(&mut self.pending);
+        let (mut read, mut waited) = match pending {
+            0 => Server::read_request(stream, &mut self.rx, idle_timeout).await?,
+            pending => (pending, false),
+        };
+        let started = time::Instant::now();
+        while read > 0 && framing::missing(&self.rx[..read], pending > 0) > 0 {
+            let left = frame_timeout.map(|timeout| timeout.saturating_sub(started.elapsed()));
+            let timeout = match (idle_timeout, left) {
+                (Some(idle), Some(left)) => Some(idle.min(left)),
+                (idle, left) => idle.or(left),
+            };
+            match Server::read_request(stream, &mut self.rx[read..], timeout).await {
+                Ok((0, _)) => break,
+                Ok((more, waited_more)) => {
+                    read += more;
+                    waited |= waited_more;
+                }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((read, waited))
+    }
+
+    /// Frames the `read` bytes in the buffer, the rest of an oversized
+    /// message is drained from `stream`
+    pub(crate) async fn frame<S>(
+        &mut self,
+        stream: &mut S,
+        read: usize,
+        idle_timeout: Option<Duration>,
+        config: &ServerConfig,
+    ) -> io::Result<Framed>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let (len, skipped) =
+            framing::frame(&self.rx[..read], config.enforcement).unwrap_or((read, 0));
+        // MessageTooLarge so, drain the rest of the message its header
+        // declares before answering, the next read starts at the message
+        // after it. Without a valid header the end isn't known, what's
+        // left is read as more requests with a bad magic
+        let drained = match framing::oversize_left(&self.rx[..len]) {
+            0 => 0,
+            left => Server::drain(stream, left, idle_timeout, config.zeroize_buffers).await?,
+        };
+        self.framed = Framed {
+            read,
+            len,
+            skipped,
+            drained,
+        };
+        Ok(self.framed)
+    }
+
+    /// The message last framed
+    pub(crate) fn message(&self) -> &[u8] {
+        &self.rx[..self.framed.len]
+    }
+
+    /// Zeroes the message last framed
+    pub(crate) fn zeroize_message(&mut self) {
+        self.rx[..self.framed.len].zeroize();
+    }
+
+    /// Done with the message last framed, the bytes read past it are the
+    /// next read
+    pub(crate) fn advance(&mut self, config: &ServerConfig) {
+        let Framed {
+            read, len, skipped, ..
+        } = self.framed;
+        let next: Range<usize> = len + skipped..read;
+        self.pending = framing::keep_pending(&mut self.rx, next, config);
+    }
+}
+
+/// Where the responses to the requests of a `RequestStream` are written
+type Writer = Arc<Mutex<Pin<Box<dyn AsyncWrite + Send>>>>;
+
+/// The requests of a connection, framed, validated and each with the
+/// `Responder` answering it, for servers dispatching requests their own way.
+/// Reads are framed like `Server::process` frames them, under the
+/// `ServerConfig` given: its `enforcement`, `idle_timeout`, `frame_timeout`
+/// and `zeroize_buffers`. Nothing is accounted for, there's no `State`
+///
+/// The stream ends once the connection is closed, or after the error a read
+/// failing or timing out is yielded as. A message that isn't a valid request
+/// is yielded as `ServiceError::Invalid`, with the response the built-in
+/// dispatch answers it with, and the stream goes on
+///
+/// # Example
+/// A server answering Compress requests as the service does, and logging
+/// and rejecting every other request
+/// ```
+/// use service::{compress, validate_compressible, RequestStream, ServerConfig, ServiceError};
+/// use service::message::{Header, Request, Response};
+/// use std::sync::Arc;
+/// use tokio::io::{AsyncReadExt, AsyncWriteExt};
+/// use zerocopy::AsBytes;
+///
+/// async fn serve<S>(stream: S) -> std::io::Result<()>
+/// where
+///     S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static,
+/// {
+///     let mut requests = RequestStream::new_with(stream, Arc::new(ServerConfig::default()));
+///     while let Some(request) = requests.next_request().await {
+///         match request {
+///             Ok(request) if request.request == Request::Compress => {
+///                 let compressed = validate_compressible(&request.payload)
+///                     .map_err(|error| error.response())
+///                     .and_then(|()| compress(&request.payload).map_err(|_| Response::UnknownError));
+///                 match compressed {
+///                     Ok(compressed) => request.responder.respond_ok(&compressed).await?,
+///                     Err(response) => request.responder.respond_error(response).await?,
+///                 }
+///             }
+///             Ok(request) => {
+///                 eprintln!("rejected {:?}", request.request);
+///                 request.responder.respond_error(Response::UnsupportedRequestType).await?
+///             }
+///             Err(ServiceError::Invalid { response, responder }) => {
+///                 responder.respond_error(response).await?
+///             }
+///             Err(ServiceError::Io(e)) => return Err(e),
+///         }
+///     }
+///     Ok(())
+/// }
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let (mut client, server) = tokio::io::duplex(1024);
+/// let serving = tokio::spawn(serve(server));
+/// let header = Header::request(Request::Compress, 4).unwrap();
+/// client.write_all(&[header.as_bytes(), b"aaab"].concat()).await.unwrap();
+/// let mut response = [0u8; 11];
+/// client.read_exact(&mut response).await.unwrap();
+/// assert_eq!(response, [83, 84, 82, 89, 0, 3, 0, 0, b'3', b'a', b'b']);
+///
+/// let ping = Header::request(Request::Ping, 0).unwrap();
+/// client.write_all(ping.as_bytes()).await.unwrap();
+/// let mut response = [0u8; 8];
+/// client.read_exact(&mut response).await.unwrap();
+/// assert_eq!(response[7], Response::UnsupportedRequestType as u8);
+/// drop(client);
+/// serving.await.unwrap().unwrap();
+/// # }
+/// ```
+pub struct RequestStream {
+    reading: Option<Reading>,
+    next: Option<Pin<Box<dyn Future<Output = Next> + Send>>>,
+}
+
+/// The item of a `RequestStream` and what's left to read once it's yielded,
+/// nothing once the stream has ended
+type Next = (Option<Reading>, Option<Result<OwnedRequest, ServiceError>>);
+
+struct Reading {
+    framer: Framer,
+    reader: Pin<Box<dyn AsyncRead + Send>>,
+    writer: Writer,
+    config: Arc<ServerConfig>,
+}
+
+impl Reading {
+    async fn next(mut self) -> Next {
+        let timeout = self.config.idle_timeout;
+        let frame_timeout = self.config.frame_timeout;
+        let read = match self
+            .framer
+            .read(&mut self.reader, timeout, frame_timeout)
+            .await
+        {
+            Ok((0, _)) => return (None, None),
+            Ok((read, _)) => read,
+            Err(e) => return (None, Some(Err(ServiceError::Io(e)))),
+        };
+        let framed = self
+            .framer
+            .frame(&mut self.reader, read, timeout, &self.config);
+        if let Err(e) = framed.await {
+            return (None, Some(Err(ServiceError::Io(e))));
+        }
+        let responder = Responder {
+            writer: Arc::clone(&self.writer),
+        };
+        let item = match message::validate_wire(self.framer.message()) {
+            Ok((request, payload)) => Ok(OwnedRequest {
+                request,
+                payload: payload.to_vec(),
+                responder,
+            }),
+            Err(response) => Err(ServiceError::Invalid {
+                response,
+                responder,
+            }),
+        };
+        if self.config.zeroize_buffers {
+            self.framer.zeroize_message();
+        }
+        self.framer.advance(&self.config);
+        (Some(self), Some(item))
+    }
+}
+
+impl RequestStream {
+    /// The requests read from `stream`, framed under `config`
+    pub fn new_with<S>(stream: S, config: Arc<ServerConfig>) -> RequestStream
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(stream);
+        RequestStream {
+            reading: Some(Reading {
+                framer: Framer::new(),
+                reader: Box::pin(reader),
+                writer: Arc::new(Mutex::new(Box::pin(writer))),
+                config,
+            }),
+            next: None,
+        }
+    }
+
+    /// The next request, `None` once the stream has ended
+    pub async fn next_request(&mut self) -> Option<Result<OwnedRequest, ServiceError>> {
+        future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl Stream for RequestStream {
+    type Item = Result<OwnedRequest, ServiceError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.next.is_none() {
+            match this.reading.take() {
+                Some(reading) => this.next = Some(Box::pin(reading.next())),
+                None => return Poll::Ready(None),
+            }
+        }
+        let (reading, item) = match this.next.as_mut().unwrap().as_mut().poll(cx) {
+            Poll::Ready(next) => next,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.next = None;
+        this.reading = reading;
+        Poll::Ready(item)
+    }
+}
+
+impl fmt::Debug for RequestStream {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("RequestStream")
+            .field("ended", &(self.reading.is_none() && self.next.is_none()))
+            .finish()
+    }
+}
+
+/// A valid request read by a `RequestStream`
+#[derive(Debug)]
+pub struct OwnedRequest {
+    pub request: Request,
+    /// The payload as read, the sequence number of a sequenced request
+    /// included
+    pub payload: Vec<u8>,
+    /// Answers the request
+    pub responder: Responder,
+}
+
+/// Writes the response to one request of a `RequestStream` to the
+/// connection it was read from
+pub struct Responder {
+    writer: Writer,
+}
+
+impl Responder {
+    /// Answers Ok with `payload`, at most MAX_PAYLOAD bytes
+    pub async fn respond_ok(self, payload: &[u8]) -> io::Result<()> {
+        self.respond(Response::Ok, payload).await
+    }
+
+    /// Answers with `response` and no payload
+    pub async fn respond_error(self, response: Response) -> io::Result<()> {
+        self.respond(response, &[]).await
+    }
+
+    /// Answers with `response` and `payload`, at most MAX_PAYLOAD bytes
+    pub async fn respond(self, response: Response, payload: &[u8]) -> io::Result<()> {
+        let size = u16::try_from(payload.len()).unwrap_or(u16::MAX);
+        let header = Header::response(response, size)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut writer = self.writer.lock().await;
+        writer
+            .write_all(&[header.as_bytes(), payload].concat())
+            .await?;
+        writer.flush().await
+    }
+}
+
+impl fmt::Debug for Responder {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Responder").finish_non_exhaustive()
+    }
+}
+
+/// Why a `RequestStream` yielded no request
+#[derive(Debug)]
+pub enum ServiceError {
+    /// Reading the connection failed or timed out, the stream ends with it
+    Io(io::Error),
+    /// The message read isn't a valid request, `response` is what the
+    /// service answers it with
+    Invalid {
+        response: Response,
+        responder: Responder,
+    },
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServiceError::Io(e) => write!(fmt, "reading the request failed: {}", e),
+            ServiceError::Invalid { response, .. } => {
+                write!(fmt, "invalid request, answered {:?}", response)
+            }
+        }
+    }
+}
+
+impl Error for ServiceError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::MAX_PAYLOAD;
+    use tokio::io::AsyncReadExt;
+
+    async fn response<S: AsyncRead + Unpin>(client: &mut S) -> (u16, Vec<u8>) {
+        let mut header = [0u8; message::HEADER_SIZE];
+        client.read_exact(&mut header).await.unwrap();
+        let mut payload = vec![0u8; u16::from_be_bytes([header[4], header[5]]) as usize];
+        client.read_exact(&mut payload).await.unwrap();
+        (u16::from_be_bytes([header[6], header[7]]), payload)
+    }
+
+    #[tokio::test]
+    async fn test_request_stream() {
+        let (mut client, server) = tokio::io::duplex(4 * message::MAX_MESSAGE);
+        let config = ServerConfig::default();
+        let mut requests = RequestStream::new_with(server, Arc::new(config));
+
+        // two messages in one write, then a bad magic and an oversized one
+        let compress = Header::request(Request::Compress, 4).unwrap();
+        let ping = Header::request(Request::Ping, 0).unwrap();
+        let written = [compress.as_bytes(), b"aaab", ping.as_bytes()].concat();
+        client.write_all(&written).await.unwrap();
+        let compress = requests.next_request().await.unwrap().unwrap();
+        assert_eq!(
+            (compress.request, &compress.payload[..]),
+            (Request::Compress, &b"aaab"[..])
+        );
+        let ping = requests.next_request().await.unwrap().unwrap();
+        assert_eq!((ping.request, ping.payload.len()), (Request::Ping, 0));
+        // answered in any order
+        ping.responder.respond_ok(b"").await.unwrap();
+        compress.responder.respond_ok(b"3ab").await.unwrap();
+        assert_eq!(response(&mut client).await, (0, vec![]));
+        assert_eq!(response(&mut client).await, (0, b"3ab".to_vec()));
+
+        let bad_magic = Header::raw(message::MAGIC + 1, 0, Request::Ping as u16);
+        client.write_all(bad_magic.as_bytes()).await.unwrap();
+        match requests.next_request().await.unwrap() {
+            Err(ServiceError::Invalid { response, .. }) => {
+                assert_eq!(response, Response::MessageHeaderHasBadMagic)
+            }
+            other => panic!("{:?}", other),
+        }
+        let size = MAX_PAYLOAD as usize + 1;
+        let oversized = Header::raw(message::MAGIC, size as u16, Request::Compress as u16);
+        client.write_all(oversized.as_bytes()).await.unwrap();
+        client.write_all(&vec![b'a'; size]).await.unwrap();
+        match requests.next_request().await.unwrap() {
+            Err(ServiceError::Invalid {
+                response,
+                responder,
+            }) => {
+                assert_eq!(response, Response::MessageTooLarge);
+                responder.respond_error(response).await.unwrap();
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(response(&mut client).await, (2, vec![]));
+        // drained, the next message is read as such
+        client
+            .write_all(Header::request(Request::Ping, 0).unwrap().as_bytes())
+            .await
+            .unwrap();
+        let ping = requests.next_request().await.unwrap().unwrap();
+        assert_eq!(ping.request, Request::Ping);
+
+        // ends with the connection
+        drop(client);
+        assert!(requests.next_request().await.is_none());
+        assert!(requests.next_request().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_request_stream_idle_timeout() {
+        let (_client, server) = tokio::io::duplex(64);
+        let config = ServerConfig {
+            idle_timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+        let mut requests = RequestStream::new_with(server, Arc::new(config));
+        match requests.next_request().await.unwrap() {
+            Err(ServiceError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            other => panic!("{:?}", other),
+        }
+        assert!(requests.next_request().await.is_none());
+    }
+}
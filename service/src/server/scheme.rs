@@ -0,0 +1,251 @@
+use super::binary::{binary_len, compress_binary, decompress_binary, max_binary_len};
+use super::compress::{
+    compress_bound, compress_with_stats, decompress_message, CompressOptions, CompressOutcome,
+    DecompressError,
+};
+use crate::message::{CharPolicy, InvalidPayload, Response, MAX_PAYLOAD};
+use std::{error::Error, fmt};
+
+/// A compression algorithm the service can be configured with
+///
+/// The scheme decides which payloads it accepts (`validate_payload`) and how
+/// they are compressed into the response payload (`compress`)
+///
+/// # Example
+/// ```
+/// use service::{CompressionScheme, RlePrefix};
+/// let scheme = RlePrefix::default();
+/// let mut tx = [0u8; 8];
+/// let len = scheme.compress(b"aaaaabbb", &mut tx).unwrap();
+/// assert_eq!(&tx[..len], b"5a3b");
+/// assert_eq!(scheme.name(), "rle-prefix");
+/// ```
+pub trait CompressionScheme {
+    /// Compresses `rx` into `tx`, returning the length of the output
+    fn compress(&self, rx: &[u8], tx: &mut [u8]) -> Result<usize, CompressError>;
+
+    /// Same as `compress` but also describes the output, schemes that don't
+    /// track runs only report its length
+    fn compress_outcome(&self, rx: &[u8], tx: &mut [u8]) -> Result<CompressOutcome, CompressError> {
+        self.compress(rx, tx).map(CompressOutcome::new_with_len)
+    }
+
+    /// Expands the output of `compress` in `rx` into `tx`, returning the
+    /// length of the output. Must never write more than `tx.len()` bytes
+    fn decompress(&self, rx: &[u8], tx: &mut [u8]) -> Result<usize, DecompressError>;
+
+    /// The longest output `compress` can produce for an input of `input_len`
+    /// bytes, a `tx` that long never fails with `CompressError::OutputTooSmall`
+    fn bound(&self, input_len: usize) -> usize;
+
+    /// Validates the payload of a compression request
+    /// `Response::Ok` if the payload can be compressed by this scheme
+    fn validate_payload(&self, payload: &[u8]) -> Response;
+
+    /// The first byte of `payload` that `validate_payload` rejects, for the
+    /// error detail of its response. `None` if the scheme can't tell
+    fn invalid_byte(&self, _payload: &[u8]) -> Option<InvalidPayload> {
+        None
+    }
+
+    /// A short identifier of the scheme
+    fn name(&self) -> &'static str;
+}
+
+impl fmt::Debug for dyn CompressionScheme + Send + Sync {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "CompressionScheme({})", self.name())
+    }
+}
+
+/// Reasons a `CompressionScheme` fails to compress a payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressError {
+    /// There is nothing to compress
+    EmptyInput,
+    /// The output does not fit in the provided buffer
+    OutputTooSmall,
+}
+
+impl fmt::Display for CompressError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompressError::EmptyInput => write!(fmt, "input is empty"),
+            CompressError::OutputTooSmall => write!(fmt, "output buffer is too small"),
+        }
+    }
+}
+
+impl Error for CompressError {}
+
+/// The simplified prefix encoding scheme of `compress_message`
+/// Payloads are validated against a `CharPolicy`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RlePrefix {
+    pub policy: CharPolicy,
+    pub options: CompressOptions,
+}
+
+impl RlePrefix {
+    pub fn new_with(policy: CharPolicy, options: CompressOptions) -> RlePrefix {
+        RlePrefix { policy, options }
+    }
+
+    /// Literal characters of the compressed payload must be allowed by the policy
+    pub(crate) fn check_literals(&self, rx: &[u8]) -> Result<(), DecompressError> {
+        match rx
+            .iter()
+            .any(|x| !x.is_ascii_digit() && !self.policy.allows(*x))
+        {
+            true => Err(DecompressError::Malformed),
+            false => Ok(()),
+        }
+    }
+}
+
+impl CompressionScheme for RlePrefix {
+    fn compress(&self, rx: &[u8], tx: &mut [u8]) -> Result<usize, CompressError> {
+        self.compress_outcome(rx, tx).map(|outcome| outcome.len)
+    }
+
+    fn compress_outcome(&self, rx: &[u8], tx: &mut [u8]) -> Result<CompressOutcome, CompressError> {
+        if rx.is_empty() {
+            return Err(CompressError::EmptyInput);
+        }
+        compress_with_stats(rx, tx, &self.options).ok_or(CompressError::OutputTooSmall)
+    }
+
+    fn decompress(&self, rx: &[u8], tx: &mut [u8]) -> Result<usize, DecompressError> {
+        self.check_literals(rx)?;
+        decompress_message(rx, tx)
+    }
+
+    fn bound(&self, input_len: usize) -> usize {
+        compress_bound(input_len)
+    }
+
+    fn validate_payload(&self, payload: &[u8]) -> Response {
+        self.policy.validate(payload)
+    }
+
+    fn invalid_byte(&self, payload: &[u8]) -> Option<InvalidPayload> {
+        self.policy.check(payload).err()
+    }
+
+    fn name(&self) -> &'static str {
+        "rle-prefix"
+    }
+}
+
+/// The binary run-length encoding of `compress_binary`
+///
+/// Any byte can be compressed, a payload is only rejected when its encoding
+/// would not fit in a response (`max_binary_len` bounds the expansion)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RleBinary;
+
+impl CompressionScheme for RleBinary {
+    fn compress(&self, rx: &[u8], tx: &mut [u8]) -> Result<usize, CompressError> {
+        if rx.is_empty() {
+            return Err(CompressError::EmptyInput);
+        }
+        compress_binary(rx, tx).ok_or(CompressError::OutputTooSmall)
+    }
+
+    fn decompress(&self, rx: &[u8], tx: &mut [u8]) -> Result<usize, DecompressError> {
+        decompress_binary(rx, tx)
+    }
+
+    fn bound(&self, input_len: usize) -> usize {
+        max_binary_len(input_len)
+    }
+
+    fn validate_payload(&self, payload: &[u8]) -> Response {
+        match binary_len(payload) {
+            n if n > MAX_PAYLOAD as usize => Response::ResponseTooLarge,
+            _ => Response::Ok,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "rle-binary"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompressError, CompressionScheme, DecompressError, RleBinary, RlePrefix};
+    use crate::message::Response;
+    use crate::message::MAX_PAYLOAD;
+
+    #[test]
+    fn test_rle_prefix() {
+        let scheme = RlePrefix::default();
+        let mut tx = [0u8; 16];
+        let len = scheme.compress(b"aaaaabbbbbbaaabb", &mut tx);
+        assert_eq!(&tx[..len.unwrap()], b"5a6b3abb");
+        assert_eq!(
+            scheme.compress(b"", &mut tx),
+            Err(CompressError::EmptyInput)
+        );
+        assert_eq!(
+            scheme.compress(b"abc", &mut tx[..2]),
+            Err(CompressError::OutputTooSmall)
+        );
+        let outcome = scheme
+            .compress_outcome(b"aaaaabbbbbbaaabb", &mut tx)
+            .unwrap();
+        assert_eq!(
+            (outcome.runs, outcome.longest_run, outcome.literals),
+            (3, 6, 2)
+        );
+        assert_eq!(scheme.bound(16), 16);
+        assert_eq!(scheme.decompress(b"5a6b3abb", &mut tx), Ok(16));
+        assert_eq!(&tx[..], b"aaaaabbbbbbaaabb");
+        assert_eq!(
+            scheme.decompress(b"5A", &mut tx),
+            Err(DecompressError::Malformed)
+        );
+        assert_eq!(scheme.validate_payload(b"abc"), Response::Ok);
+        assert_eq!(
+            scheme.validate_payload(b"abC"),
+            Response::MessageContainsUppercaseCharacters
+        );
+    }
+
+    #[test]
+    fn test_rle_binary() {
+        let scheme = RleBinary;
+        let mut tx = [0u8; 16];
+        let len = scheme.compress(b"aaaaabC\xFF", &mut tx).unwrap();
+        assert_eq!(
+            &tx[..len],
+            &[0xFF, 5, 0, b'a', b'b', b'C', 0xFF, 1, 0, 0xFF]
+        );
+        let mut out = [0u8; 8];
+        assert_eq!(scheme.decompress(&tx[..len], &mut out), Ok(8));
+        assert_eq!(&out, b"aaaaabC\xFF");
+        assert_eq!(
+            scheme.compress(b"", &mut tx),
+            Err(CompressError::EmptyInput)
+        );
+        assert_eq!(scheme.validate_payload(b"abC3\xFF"), Response::Ok);
+
+        // the worst case fills the bound exactly
+        let worst = b"\xFFa\xFFa\xFF";
+        let mut tx = vec![0u8; scheme.bound(worst.len())];
+        assert_eq!(scheme.compress(worst, &mut tx), Ok(tx.len()));
+
+        // isolated markers expand 2.5x, so this payload fits in a request but
+        // its response would not
+        let payload = [0xFFu8, 0].repeat(MAX_PAYLOAD as usize / 4);
+        assert_eq!(
+            scheme.validate_payload(&payload),
+            Response::ResponseTooLarge
+        );
+        assert_eq!(
+            scheme.validate_payload(&payload[..payload.len() / 2]),
+            Response::Ok
+        );
+    }
+}
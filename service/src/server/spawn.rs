@@ -0,0 +1,70 @@
+use std::future::Future;
+use tokio::{
+    runtime::Handle,
+    task::{AbortHandle, JoinHandle, JoinSet},
+};
+
+/// Spawns `task` on the runtime of `handle` under `name`, which is how it
+/// shows in tokio-console (see the `console` feature). Tasks are only named
+/// when built with `--cfg tokio_unstable`, otherwise this is `handle.spawn`
+pub fn spawn_named<F>(name: &str, task: F, handle: &Handle) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(tokio_unstable)]
+    return tokio::task::Builder::new()
+        .name(name)
+        .spawn_on(task, handle)
+        .expect("spawning a task");
+    #[cfg(not(tokio_unstable))]
+    {
+        let _ = name;
+        handle.spawn(task)
+    }
+}
+
+/// Spawns `task` into `tasks` like `spawn_named`
+pub fn spawn_named_in<F>(
+    tasks: &mut JoinSet<F::Output>,
+    name: &str,
+    task: F,
+    handle: &Handle,
+) -> AbortHandle
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(tokio_unstable)]
+    return tasks
+        .build_task()
+        .name(name)
+        .spawn_on(task, handle)
+        .expect("spawning a task");
+    #[cfg(not(tokio_unstable))]
+    {
+        let _ = name;
+        tasks.spawn_on(task, handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_spawn_named() {
+        let handle = Handle::current();
+        assert_eq!(
+            spawn_named("answer", async { 42 }, &handle).await.unwrap(),
+            42
+        );
+
+        let mut tasks = JoinSet::new();
+        spawn_named_in(&mut tasks, "first", async { 1 }, &handle);
+        spawn_named_in(&mut tasks, "second", async { 2 }, &handle);
+        let mut results = tasks.join_all().await;
+        results.sort();
+        assert_eq!(results, [1, 2]);
+    }
+}
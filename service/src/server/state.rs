@@ -1,13 +1,621 @@
+use super::accept::{AcceptError, AcceptStats, ACCEPT_ERROR_CLASSES};
+use super::metrics::Metrics;
+use super::ratio::{RatioPolicy, RatioTracker};
+use crate::message::{Request, Response, RESET_TOKEN_LEN};
 use crate::stats::Stats;
+use crate::CompressOutcome;
+use std::{
+    cmp,
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
 use zerocopy::AsBytes;
 
+/// Kinds of requests counted by a `State`, one per request code
+pub const REQUEST_KINDS: usize = 14;
+
+/// Bytes read and sent since the last published snapshot for the change to
+/// be material, see `State::observe`
+pub const MATERIAL_CHANGE: usize = 4096;
+
+/// The tenant of connections that never authenticated, see
+/// `ServerConfig::tenants`
+pub const ANONYMOUS_TENANT: &str = "anonymous";
+
+/// Called with a snapshot of a `State` whenever it changes materially
+pub type Observer = Arc<dyn Fn(&StatsSnapshot) + Send + Sync>;
+
+/// An owned copy of the counters of a `State`, all taken at the same point in
+/// time so the response built from it is never torn
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct StatsSnapshot {
+    pub stats: Stats,
+    pub runs: usize,
+    pub longest_run: usize,
+    pub literals: usize,
+    pub stored_responses: usize,
+    pub max_requests_per_wake: usize,
+    pub failed_writes: usize,
+    pub bytes_discarded: usize,
+    pub bad_magic_drops: usize,
+    /// Valid requests handled, by request code less one
+    pub requests: [usize; REQUEST_KINDS],
+    pub errors: usize,
+    pub rotations: usize,
+    /// Requests that took longer than `ServerConfig::request_deadline`
+    pub slow_requests: usize,
+    /// Compress requests whose output was larger than their payload, see
+    /// `RatioTracker::update`
+    pub expanded_outputs: usize,
+    /// Bytes of requests and responses held in memory at the time, see
+    /// `InFlight`
+    pub in_flight_bytes: usize,
+    /// `accept()` errors by `AcceptError` class, see `AcceptStats`
+    pub accept_errors: [usize; ACCEPT_ERROR_CLASSES],
+    /// Times the accept loop stopped at `ServerConfig::max_connections`
+    pub limit_waits: usize,
+    /// Connections accepted by label of listener, see `AcceptStats::listener`
+    pub listeners: Vec<(String, usize)>,
+    /// Connections open at the time, see `ConnectionGauge`
+    pub connections: usize,
+    /// Connections accepted since the service started, never reset
+    pub accepted_connections: u64,
+    /// Most connections open at once, see `Peaks`
+    pub peak_connections: usize,
+    /// Most requests of the heavy lane handled at once
+    pub peak_heavy: usize,
+    /// Largest payload of a request that passed validation
+    pub largest_request: usize,
+    /// Largest payload of a response
+    pub largest_response: usize,
+}
+
+impl StatsSnapshot {
+    /// The GetStats payload
+    pub fn as_bytes(&self) -> &[u8] {
+        self.stats.as_bytes()
+    }
+
+    /// Valid requests of the kind of `request` handled
+    pub fn requests(&self, request: &Request) -> usize {
+        self.requests[request.clone() as usize - 1]
+    }
+
+    /// The counters grown since `previous`, an earlier snapshot of the same
+    /// state. A counter that went back, i.e. was reset in between, counts
+    /// all of its current value, so no delta is ever negative
+    pub fn since(&self, previous: &StatsSnapshot) -> StatsDelta {
+        let mut requests = [0; REQUEST_KINDS];
+        for (i, requests) in requests.iter_mut().enumerate() {
+            *requests = delta(previous.requests[i], self.requests[i]);
+        }
+        let mut accept_errors = [0; ACCEPT_ERROR_CLASSES];
+        for (i, errors) in accept_errors.iter_mut().enumerate() {
+            *errors = delta(previous.accept_errors[i], self.accept_errors[i]);
+        }
+        StatsDelta {
+            read: delta(previous.stats.read(), self.stats.read()),
+            sent: delta(previous.stats.sent(), self.stats.sent()),
+            runs: delta(previous.runs, self.runs),
+            literals: delta(previous.literals, self.literals),
+            stored_responses: delta(previous.stored_responses, self.stored_responses),
+            failed_writes: delta(previous.failed_writes, self.failed_writes),
+            bytes_discarded: delta(previous.bytes_discarded, self.bytes_discarded),
+            bad_magic_drops: delta(previous.bad_magic_drops, self.bad_magic_drops),
+            requests,
+            errors: delta(previous.errors, self.errors),
+            rotations: delta(previous.rotations, self.rotations),
+            slow_requests: delta(previous.slow_requests, self.slow_requests),
+            expanded_outputs: delta(previous.expanded_outputs, self.expanded_outputs),
+            accept_errors,
+            limit_waits: delta(previous.limit_waits, self.limit_waits),
+        }
+    }
+
+    /// Every counter as a JSON object on one line, the requests by the name
+    /// of their kind, e.g. {"read":24,"sent":16,"ratio":50,...,
+    /// "requests":{"Ping":0,"GetStats":1,...},...}
+    pub fn to_json(&self) -> String {
+        let requests: Vec<String> = self
+            .requests
+            .iter()
+            .enumerate()
+            .map(|(i, count)| {
+                let request = Request::from_u16(i as u16 + 1).unwrap();
+                format!("\"{:?}\":{}", request, count)
+            })
+            .collect();
+        let accept_errors: Vec<String> = AcceptError::all()
+            .iter()
+            .map(|class| {
+                format!(
+                    "\"{}\":{}",
+                    class.name(),
+                    self.accept_errors[*class as usize]
+                )
+            })
+            .collect();
+        let listeners: Vec<String> = self
+            .listeners
+            .iter()
+            .map(|(label, accepted)| format!("\"{}\":{}", label, accepted))
+            .collect();
+        format!(
+            "{{\"read\":{},\"sent\":{},\"ratio\":{},\"runs\":{},\"longest_run\":{},\
+             \"literals\":{},\"stored_responses\":{},\"max_requests_per_wake\":{},\
+             \"failed_writes\":{},\"bytes_discarded\":{},\"bad_magic_drops\":{},\
+             \"requests\":{{{}}},\"errors\":{},\"rotations\":{},\"slow_requests\":{},\
+             \"expanded_outputs\":{},\"in_flight_bytes\":{},\
+             \"accept_errors\":{{{}}},\"limit_waits\":{},\"listeners\":{{{}}},\
+             \"connections\":{},\"accepted_connections\":{},\"peak_connections\":{},\"peak_heavy\":{},\"largest_request\":{},\
+             \"largest_response\":{}}}",
+            self.stats.read(),
+            self.stats.sent(),
+            self.stats.ratio(),
+            self.runs,
+            self.longest_run,
+            self.literals,
+            self.stored_responses,
+            self.max_requests_per_wake,
+            self.failed_writes,
+            self.bytes_discarded,
+            self.bad_magic_drops,
+            requests.join(","),
+            self.errors,
+            self.rotations,
+            self.slow_requests,
+            self.expanded_outputs,
+            self.in_flight_bytes,
+            accept_errors.join(","),
+            self.limit_waits,
+            listeners.join(","),
+            self.connections,
+            self.accepted_connections,
+            self.peak_connections,
+            self.peak_heavy,
+            self.largest_request,
+            self.largest_response
+        )
+    }
+}
+
+/// How much the counters of a `StatsSnapshot` grew since an earlier one, see
+/// `StatsSnapshot::since`. The gauges, e.g. the ratio or the bytes in flight,
+/// are left out as they are only meaningful at a point in time
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct StatsDelta {
+    pub read: u32,
+    pub sent: u32,
+    pub runs: usize,
+    pub literals: usize,
+    pub stored_responses: usize,
+    pub failed_writes: usize,
+    pub bytes_discarded: usize,
+    pub bad_magic_drops: usize,
+    /// Valid requests handled, by request code less one
+    pub requests: [usize; REQUEST_KINDS],
+    pub errors: usize,
+    pub rotations: usize,
+    pub slow_requests: usize,
+    pub expanded_outputs: usize,
+    pub accept_errors: [usize; ACCEPT_ERROR_CLASSES],
+    pub limit_waits: usize,
+}
+
+/// How much `current` grew since `previous`, all of it if it went back
+/// i.e. was reset in between
+fn delta<T: Copy + PartialOrd + std::ops::Sub<Output = T>>(previous: T, current: T) -> T {
+    if current >= previous {
+        current - previous
+    } else {
+        current
+    }
+}
+
+/// The observer of a `State` along with the bytes it was last published at,
+/// states equal but for their observers are equal
+#[derive(Default, Clone)]
+struct Observed {
+    observer: Option<Observer>,
+    published: usize,
+}
+
+impl fmt::Debug for Observed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Observed")
+            .field("observed", &self.observer.is_some())
+            .field("published", &self.published)
+            .finish()
+    }
+}
+
+impl PartialEq for Observed {
+    fn eq(&self, _: &Observed) -> bool {
+        true
+    }
+}
+
+/// Bytes of the requests read but not answered yet, and of the responses
+/// being written, across every connection sharing a `State`. Unlike the
+/// counters it is a gauge, shared by clones of the state and kept by `reset`,
+/// and states equal but for it are equal
+#[derive(Default, Clone)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    /// Holds `bytes` until the `Reservation` is dropped, unless that would
+    /// take the total over `budget` (0 is unlimited). A request is always let
+    /// through while nothing else is in flight, so a budget smaller than a
+    /// message slows the service down without stalling it
+    pub fn reserve(&self, bytes: usize, budget: usize) -> Option<Reservation> {
+        let mut current = self.0.load(Ordering::SeqCst);
+        loop {
+            if budget > 0 && current > 0 && current + bytes > budget {
+                return None;
+            }
+            match self.0.compare_exchange_weak(
+                current,
+                current + bytes,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => {
+                    return Some(Reservation {
+                        in_flight: self.clone(),
+                        bytes,
+                    })
+                }
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl fmt::Debug for InFlight {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("InFlight").field(&self.bytes()).finish()
+    }
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, _: &InFlight) -> bool {
+        true
+    }
+}
+
+/// Bytes counted as in flight until dropped, see `InFlight::reserve`
+#[derive(Debug)]
+pub struct Reservation {
+    in_flight: InFlight,
+    bytes: usize,
+}
+
+impl Reservation {
+    /// Also holds `bytes` more, whatever the budget, e.g. for the response to
+    /// a request already let through
+    pub fn grow(&mut self, bytes: usize) {
+        self.in_flight.0.fetch_add(bytes, Ordering::SeqCst);
+        self.bytes += bytes;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.in_flight.0.fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
+
+/// The connections of the service sharing a `State`, those open now and
+/// those accepted since it started, read on every accept, Health request and
+/// snapshot without taking the state's lock. Like `InFlight` a gauge, shared
+/// by clones of the state and kept by `reset`
+#[derive(Default, Clone)]
+pub struct ConnectionGauge(Arc<Connections>);
+
+#[derive(Default)]
+struct Connections {
+    active: AtomicUsize,
+    accepted: AtomicU64,
+}
+
+impl ConnectionGauge {
+    /// Counts a connection accepted, and open until the returned `Connected`
+    /// is dropped. Held by the task serving the connection, it is dropped
+    /// however the task ends, by panicking or being cancelled included
+    pub fn accept(&self) -> Connected {
+        self.0.active.fetch_add(1, Ordering::SeqCst);
+        self.0.accepted.fetch_add(1, Ordering::SeqCst);
+        Connected {
+            gauge: self.clone(),
+        }
+    }
+
+    /// Connections open now
+    pub fn active(&self) -> usize {
+        self.0.active.load(Ordering::SeqCst)
+    }
+
+    /// Connections ever accepted, never lowered
+    pub fn accepted(&self) -> u64 {
+        self.0.accepted.load(Ordering::SeqCst)
+    }
+}
+
+impl fmt::Debug for ConnectionGauge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConnectionGauge")
+            .field("active", &self.active())
+            .field("accepted", &self.accepted())
+            .finish()
+    }
+}
+
+impl PartialEq for ConnectionGauge {
+    fn eq(&self, _: &ConnectionGauge) -> bool {
+        true
+    }
+}
+
+/// A connection counted as open until dropped, see `ConnectionGauge::accept`
+#[derive(Debug)]
+pub struct Connected {
+    gauge: ConnectionGauge,
+}
+
+impl Drop for Connected {
+    fn drop(&mut self) {
+        self.gauge.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Whether the service sharing a `State` is shutting down, reported by Health
+/// requests. Like `InFlight` a gauge, shared by clones of the state and kept
+/// by `reset`
+#[derive(Default, Clone)]
+pub struct Draining(Arc<AtomicBool>);
+
+impl Draining {
+    /// Marks the service as shutting down, for good
+    pub fn start(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl fmt::Debug for Draining {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Draining")
+            .field(&self.is_draining())
+            .finish()
+    }
+}
+
+impl PartialEq for Draining {
+    fn eq(&self, _: &Draining) -> bool {
+        true
+    }
+}
+
+/// The high-water marks of the service sharing a `State`, for capacity
+/// planning: the most connections open and heavy requests handled at once,
+/// and the largest request and response payloads. Like `InFlight` shared by
+/// clones of the state and equal whatever their marks, they survive the
+/// reset of a connection's or a tenant's stats and only the global `reset`
+/// lowers them
+#[derive(Default, Clone)]
+pub struct Peaks(Arc<Marks>);
+
+#[derive(Default)]
+struct Marks {
+    connections: HighWater,
+    heavy: HighWater,
+    largest_request: AtomicUsize,
+    largest_response: AtomicUsize,
+}
+
+/// How many of something are open now, and the most that ever were
+#[derive(Default)]
+struct HighWater {
+    open: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl HighWater {
+    fn open(&self) {
+        let open = self.open.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(open, Ordering::SeqCst);
+    }
+
+    fn close(&self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Back to those open now
+    fn reset(&self) {
+        self.peak
+            .store(self.open.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+}
+
+impl Peaks {
+    /// Counts a connection as open until the returned `Open` is dropped
+    pub fn open_connection(&self) -> Open {
+        self.0.connections.open();
+        Open {
+            peaks: self.clone(),
+            heavy: false,
+        }
+    }
+
+    /// Counts a request of the heavy lane as handled until the returned
+    /// `Open` is dropped
+    pub fn open_heavy(&self) -> Open {
+        self.0.heavy.open();
+        Open {
+            peaks: self.clone(),
+            heavy: true,
+        }
+    }
+
+    /// Raises the largest request to `len`, the payload of a request which
+    /// passed validation, never the size a rejected header claims
+    pub fn update_request(&self, len: usize) {
+        self.0.largest_request.fetch_max(len, Ordering::SeqCst);
+    }
+
+    /// Raises the largest response to `len`, the payload of a response
+    pub fn update_response(&self, len: usize) {
+        self.0.largest_response.fetch_max(len, Ordering::SeqCst);
+    }
+
+    pub fn connections(&self) -> usize {
+        self.0.connections.open.load(Ordering::SeqCst)
+    }
+
+    pub fn peak_connections(&self) -> usize {
+        self.0.connections.peak.load(Ordering::SeqCst)
+    }
+
+    pub fn peak_heavy(&self) -> usize {
+        self.0.heavy.peak.load(Ordering::SeqCst)
+    }
+
+    pub fn largest_request(&self) -> usize {
+        self.0.largest_request.load(Ordering::SeqCst)
+    }
+
+    pub fn largest_response(&self) -> usize {
+        self.0.largest_response.load(Ordering::SeqCst)
+    }
+
+    /// Lowers the peaks to what is open now and forgets the largest
+    /// payloads
+    pub fn reset(&self) {
+        self.0.connections.reset();
+        self.0.heavy.reset();
+        self.0.largest_request.store(0, Ordering::SeqCst);
+        self.0.largest_response.store(0, Ordering::SeqCst);
+    }
+}
+
+impl fmt::Debug for Peaks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Peaks")
+            .field("connections", &self.peak_connections())
+            .field("heavy", &self.peak_heavy())
+            .field("request", &self.largest_request())
+            .field("response", &self.largest_response())
+            .finish()
+    }
+}
+
+impl PartialEq for Peaks {
+    fn eq(&self, _: &Peaks) -> bool {
+        true
+    }
+}
+
+/// A connection or a heavy request counted as open until dropped, see
+/// `Peaks::open_connection` and `Peaks::open_heavy`
+#[derive(Debug)]
+pub struct Open {
+    peaks: Peaks,
+    heavy: bool,
+}
+
+impl Drop for Open {
+    fn drop(&mut self) {
+        match self.heavy {
+            true => self.peaks.0.heavy.close(),
+            false => self.peaks.0.connections.close(),
+        }
+    }
+}
+
+/// The metrics of a `State`, which only ever go up. Like the gauges, states
+/// equal but for them are equal
+#[derive(Default, Debug, Clone, Copy)]
+struct Exported(Metrics);
+
+impl PartialEq for Exported {
+    fn eq(&self, _: &Exported) -> bool {
+        true
+    }
+}
+
+/// The stats of a tenant, like those of the whole service
+#[derive(Default, Debug, Clone, PartialEq)]
+struct TenantStats {
+    stats: Stats,
+    ratio: RatioTracker,
+}
+
+/// What a pending state doesn't account for itself, see `State::pending`
+#[derive(Default, Debug, Clone, PartialEq)]
+struct Pending {
+    /// The internal errors of the state it was made from, it reports them
+    /// as its own but only applies those since
+    internal_error: u16,
+    /// The compress requests as (tenant, policy, total, compressed), the
+    /// tenant `None` for those of the whole service
+    ratios: Vec<(Option<String>, RatioPolicy, usize, usize)>,
+    /// The counters of the state a view was made from, see `State::view`,
+    /// reported as its own but only those since are applied. None for any
+    /// other pending state, which reports its own alone
+    base: Option<Base>,
+    /// Whether it was reset, and the tenants whose stats were, the state it
+    /// is applied to is reset alike first
+    reset: bool,
+    reset_tenants: Vec<String>,
+}
+
+/// The counters a pending state starts from, none but for a view
+#[derive(Default, Debug, Clone, PartialEq)]
+struct Base {
+    snapshot: StatsSnapshot,
+    tenants: BTreeMap<String, Stats>,
+}
+
 /// Contains state information about the running service
-#[derive(Default, Debug, PartialEq)]
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct State {
     stats: Stats,
-    total: usize,      // Total bytes received from compression requests
-    compressed: usize, // Total bytes sent after compressing valid compress requests
+    ratio: RatioTracker, // The compress requests the ratio is computed from
     internal_error: u16,
+    runs: usize,                      // Runs encoded as count + character
+    longest_run: usize,               // Longest run encoded as count + character
+    literals: usize,                  // Bytes copied to compressed outputs as is
+    stored: usize,                    // Compress responses without any encoded run
+    requests_per_wake: usize,         // Most requests a connection handled without yielding
+    failed_writes: usize,             // Responses whose write failed, possibly partway
+    discarded: usize,                 // Bytes read but not handled as a request, i.e. oversized
+    bad_magic_drops: usize,           // Connections closed for repeatedly sending bad magic
+    requests: [usize; REQUEST_KINDS], // Valid requests handled, by code less one
+    errors: usize,                    // Requests answered with an error response
+    rotations: usize,                 // Connections closed at their request cap or lifetime
+    slow_requests: usize,             // Requests handled past the request deadline
+    metrics: Exported,                // Counters exported as metrics, never reset
+    tenant: Option<String>,           // Tenant the connection authenticated as
+    reset_tokens: VecDeque<([u8; RESET_TOKEN_LEN], Response)>, // Last ResetStats tokens of the connection
+    tenants: BTreeMap<String, TenantStats>, // Stats of each tenant, the anonymous one included
+    in_flight: InFlight,
+    draining: Draining,
+    connections: ConnectionGauge,
+    accepts: AcceptStats,
+    peaks: Peaks,
+    observed: Observed,
+    pending: Option<Pending>,
 }
 
 impl State {
@@ -19,10 +627,238 @@ impl State {
         self.stats.as_bytes()
     }
 
+    /// Copies out the counters, only field reads happen while `self` is
+    /// borrowed so any serializing can happen after a lock is released
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            stats: self.stats.clone(),
+            runs: self.runs,
+            longest_run: self.longest_run,
+            literals: self.literals,
+            stored_responses: self.stored,
+            max_requests_per_wake: self.requests_per_wake,
+            failed_writes: self.failed_writes,
+            bytes_discarded: self.discarded,
+            bad_magic_drops: self.bad_magic_drops,
+            requests: self.requests,
+            errors: self.errors,
+            rotations: self.rotations,
+            slow_requests: self.slow_requests,
+            expanded_outputs: self.ratio.expanded() as usize,
+            in_flight_bytes: self.in_flight.bytes(),
+            accept_errors: self.accepts.errors(),
+            limit_waits: self.accepts.limit_waits(),
+            listeners: self.accepts.listeners(),
+            connections: self.connections.active(),
+            accepted_connections: self.connections.accepted(),
+            peak_connections: self.peaks.peak_connections(),
+            peak_heavy: self.peaks.peak_heavy(),
+            largest_request: self.peaks.largest_request(),
+            largest_response: self.peaks.largest_response(),
+        }
+    }
+
+    /// The gauge of the bytes in flight, shared with the state
+    pub fn in_flight(&self) -> InFlight {
+        self.in_flight.clone()
+    }
+
+    /// Whether the service is shutting down, shared with the state
+    pub fn draining(&self) -> Draining {
+        self.draining.clone()
+    }
+
+    /// The connections open and accepted, shared with the state
+    pub fn connections(&self) -> ConnectionGauge {
+        self.connections.clone()
+    }
+
+    /// The counters of the accept loops, shared with the state
+    pub fn accepts(&self) -> AcceptStats {
+        self.accepts.clone()
+    }
+
+    /// The high-water marks, shared with the state
+    pub fn peaks(&self) -> Peaks {
+        self.peaks.clone()
+    }
+
+    /// Has `observer` called by `publish` with the counters whenever they
+    /// change materially, it is kept by clones of the state and by `reset`
+    pub fn observe(&mut self, observer: Observer) {
+        self.observed = Observed {
+            observer: Some(observer),
+            published: 0,
+        };
+    }
+
+    /// Passes a snapshot to the observer if the bytes read and sent moved by
+    /// `MATERIAL_CHANGE` since it was last given one or the state was reset,
+    /// meant to be called once updates are committed
+    pub fn publish(&mut self) {
+        let observer = match &self.observed.observer {
+            Some(observer) => Arc::clone(observer),
+            None => return,
+        };
+        let bytes = self.stats.read() as usize + self.stats.sent() as usize;
+        let published = self.observed.published;
+        if bytes < published || bytes - published >= MATERIAL_CHANGE {
+            self.observed.published = bytes;
+            observer(&self.snapshot());
+        }
+    }
+
     pub fn internal_error(&self) -> u16 {
         self.internal_error
     }
 
+    /// A state with none of the counters of this one but its internal errors,
+    /// sharing its gauges, to handle a request against without holding the
+    /// lock of this one. Its updates are made to this one by `apply`, the
+    /// ratios of its compress requests, which depend on the requests before
+    /// them, are then computed over those this one accounted for
+    pub fn pending(&self) -> State {
+        State {
+            internal_error: self.internal_error,
+            in_flight: self.in_flight.clone(),
+            draining: self.draining.clone(),
+            connections: self.connections.clone(),
+            accepts: self.accepts.clone(),
+            peaks: self.peaks.clone(),
+            pending: Some(Pending {
+                internal_error: self.internal_error,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// A pending state reporting the counters of this one with those of
+    /// `pending` applied, to handle a request reading them against, e.g.
+    /// GetStats, in a critical section. Like `pending` only its updates
+    /// since are made to this one by `apply`, a reset included: it resets
+    /// this one once applied, not as it's handled
+    pub fn view(&self, pending: State) -> State {
+        let Pending {
+            ratios,
+            reset,
+            reset_tenants,
+            ..
+        } = pending.pending.clone().expect("a pending state");
+        let mut view = self.clone();
+        view.apply(pending);
+        let tenants = self.tenants.iter();
+        view.pending = Some(Pending {
+            internal_error: self.internal_error,
+            ratios,
+            base: Some(Base {
+                snapshot: self.snapshot(),
+                tenants: tenants
+                    .map(|(tenant, entry)| (tenant.clone(), entry.stats.clone()))
+                    .collect(),
+            }),
+            reset,
+            reset_tenants,
+        });
+        view
+    }
+
+    /// Admits the tenants `view` admitted as it was handled ahead of
+    /// applying it, so connections authenticating at once never admit more
+    /// than the max between them
+    pub fn admit_from(&mut self, view: &State) {
+        for tenant in view.tenants.keys() {
+            if tenant != ANONYMOUS_TENANT && !self.tenants.contains_key(tenant) {
+                self.tenants.insert(tenant.clone(), Default::default());
+            }
+        }
+    }
+
+    /// Makes the updates of `pending`, a state from `State::pending` or
+    /// `State::view`, to this one, as though they had been made to it
+    pub fn apply(&mut self, pending: State) {
+        let Pending {
+            internal_error,
+            ratios,
+            base,
+            reset,
+            reset_tenants,
+        } = pending.pending.expect("a pending state");
+        if reset {
+            self.reset();
+        }
+        for tenant in reset_tenants {
+            self.reset_tenant(&tenant);
+        }
+        let Base {
+            snapshot: base,
+            tenants: base_tenants,
+        } = base.unwrap_or_default();
+        let since = |count: usize, base: usize| count.saturating_sub(base);
+        let (read, sent) = (pending.stats.read(), pending.stats.sent());
+        let read = read.saturating_sub(base.stats.read()) as usize;
+        let sent = sent.saturating_sub(base.stats.sent()) as usize;
+        self.stats.update_read(read);
+        self.stats.update_sent(sent);
+        let errors = pending.internal_error.saturating_sub(internal_error);
+        self.internal_error = self.internal_error.saturating_add(errors);
+        self.runs += since(pending.runs, base.runs);
+        self.longest_run = cmp::max(self.longest_run, pending.longest_run);
+        self.literals += since(pending.literals, base.literals);
+        self.stored += since(pending.stored, base.stored_responses);
+        self.requests_per_wake = cmp::max(self.requests_per_wake, pending.requests_per_wake);
+        self.failed_writes += since(pending.failed_writes, base.failed_writes);
+        self.discarded += since(pending.discarded, base.bytes_discarded);
+        self.bad_magic_drops += since(pending.bad_magic_drops, base.bad_magic_drops);
+        let handled = pending.requests.iter().zip(base.requests);
+        for (requests, (handled, base)) in self.requests.iter_mut().zip(handled) {
+            *requests += since(*handled, base);
+        }
+        self.errors += since(pending.errors, base.errors);
+        self.rotations += since(pending.rotations, base.rotations);
+        self.slow_requests += since(pending.slow_requests, base.slow_requests);
+        for (tenant, entry) in pending.tenants {
+            let base = base_tenants.get(&tenant).cloned().unwrap_or_default();
+            let stats = &mut self.tenant_entry(&tenant).stats;
+            stats.update_read(entry.stats.read().saturating_sub(base.read()) as usize);
+            stats.update_sent(entry.stats.sent().saturating_sub(base.sent()) as usize);
+        }
+        for (tenant, policy, total, compressed) in ratios {
+            match tenant {
+                Some(tenant) => self.update_tenant_ratio(&tenant, &policy, total, compressed),
+                None => self.update_ratio_with(&policy, total, compressed),
+            }
+        }
+    }
+
+    /// Makes the bytes `pending` read and discarded to this one, as `apply`
+    /// would, but none of the requests it answered nor the bytes of their
+    /// responses, for responses that never made it out
+    pub fn apply_unanswered(&mut self, pending: State) {
+        let Base {
+            snapshot: base,
+            tenants: base_tenants,
+        } = pending
+            .pending
+            .expect("a pending state")
+            .base
+            .unwrap_or_default();
+        let read = pending.stats.read().saturating_sub(base.stats.read());
+        self.stats.update_read(read as usize);
+        self.discarded += pending.discarded.saturating_sub(base.bytes_discarded);
+        for (tenant, entry) in pending.tenants {
+            let base = base_tenants.get(&tenant).map_or(0, Stats::read);
+            let stats = &mut self.tenant_entry(&tenant).stats;
+            stats.update_read(entry.stats.read().saturating_sub(base) as usize);
+        }
+    }
+
+    /// Records an internal error of the service, e.g. a failed FlushStats,
+    /// Ping reports UnknownError from then on
+    pub fn update_internal_error(&mut self) {
+        self.internal_error = self.internal_error.saturating_add(1);
+    }
+
     pub fn update_read(&mut self, size: usize) {
         self.stats.update_read(size)
     }
@@ -32,24 +868,555 @@ impl State {
     }
 
     pub fn update_ratio(&mut self, total: usize, compressed: usize) {
-        self.total += total;
-        self.compressed += compressed;
-        self.stats.set_ratio(self.compressed, self.total);
+        self.update_ratio_with(&RatioPolicy::Cumulative, total, compressed)
+    }
+
+    /// Accounts for a compress request of `total` bytes compressed to
+    /// `compressed`, the ratio is then computed under `policy`
+    pub fn update_ratio_with(&mut self, policy: &RatioPolicy, total: usize, compressed: usize) {
+        match self.pending.as_mut() {
+            Some(pending) => {
+                pending.ratios.push((None, *policy, total, compressed));
+                // only a view reports them, as its state will once it's applied
+                if pending.base.is_none() {
+                    return;
+                }
+            }
+            None => self.metrics.0.update_compress(total, compressed),
+        }
+        if let Some(ratio) = self.ratio.update(policy, total, compressed) {
+            self.stats.set_ratio_to(ratio);
+        }
+    }
+
+    /// Accumulates the runs and literals of a compressed payload
+    pub fn update_outcome(&mut self, outcome: &CompressOutcome) {
+        if outcome.runs == 0 {
+            self.stored += 1;
+        }
+        self.runs += outcome.runs;
+        self.longest_run = cmp::max(self.longest_run, outcome.longest_run);
+        self.literals += outcome.literals;
+    }
+
+    pub fn runs(&self) -> usize {
+        self.runs
+    }
+
+    pub fn longest_run(&self) -> usize {
+        self.longest_run
+    }
+
+    pub fn literals(&self) -> usize {
+        self.literals
+    }
+
+    /// Number of compress responses identical to their request payload
+    pub fn stored_responses(&self) -> usize {
+        self.stored
+    }
+
+    /// Records that a connection handled `handled` requests in a row without
+    /// waiting for input or yielding
+    pub fn update_requests_per_wake(&mut self, handled: usize) {
+        self.requests_per_wake = cmp::max(self.requests_per_wake, handled);
+    }
+
+    /// The most requests a connection handled in a row without yielding
+    pub fn max_requests_per_wake(&self) -> usize {
+        self.requests_per_wake
+    }
+
+    /// Records a response whose write failed, the bytes written before the
+    /// failure are accounted for with `update_sent`
+    pub fn update_failed_write(&mut self) {
+        self.failed_writes += 1;
+    }
+
+    pub fn failed_writes(&self) -> usize {
+        self.failed_writes
+    }
+
+    /// Records `size` bytes read but not handled as a request, they are still
+    /// part of the read total
+    pub fn update_discarded(&mut self, size: usize) {
+        self.discarded += size;
+    }
+
+    pub fn bytes_discarded(&self) -> usize {
+        self.discarded
+    }
+
+    /// Records a connection closed for sending too many messages with a bad
+    /// magic, i.e. from a peer that doesn't speak the protocol
+    pub fn update_bad_magic_drop(&mut self) {
+        self.bad_magic_drops += 1;
+    }
+
+    pub fn bad_magic_drops(&self) -> usize {
+        self.bad_magic_drops
+    }
+
+    /// Records a valid request handled
+    pub fn update_request(&mut self, request: &Request) {
+        self.requests[request.clone() as usize - 1] += 1;
+    }
+
+    /// Valid requests of the kind of `request` handled
+    pub fn requests(&self, request: &Request) -> usize {
+        self.requests[request.clone() as usize - 1]
+    }
+
+    /// Records a request answered with an error response
+    pub fn update_error(&mut self) {
+        self.errors += 1;
     }
 
+    pub fn errors(&self) -> usize {
+        self.errors
+    }
+
+    /// Records a connection closed at its request cap or lifetime, for its
+    /// client to reconnect rather than because anything went wrong
+    pub fn update_rotation(&mut self) {
+        self.rotations += 1;
+    }
+
+    pub fn rotations(&self) -> usize {
+        self.rotations
+    }
+
+    /// Records a request that took longer than `ServerConfig::request_deadline`
+    /// to handle, whether or not it was cut short
+    pub fn update_slow_request(&mut self) {
+        self.slow_requests += 1;
+    }
+
+    pub fn slow_requests(&self) -> usize {
+        self.slow_requests
+    }
+
+    /// Compress requests whose output was larger than their payload
+    pub fn expanded_outputs(&self) -> usize {
+        self.ratio.expanded() as usize
+    }
+
+    /// The counters exported as metrics, which `reset` keeps, see `Metrics`
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.0
+    }
+
+    /// Whether the connection of this state authenticated as a tenant
+    pub fn authenticated(&self) -> bool {
+        self.tenant.is_some()
+    }
+
+    /// The tenant the connection of this state authenticated as,
+    /// `ANONYMOUS_TENANT` if it never did
+    pub fn tenant(&self) -> &str {
+        self.tenant.as_deref().unwrap_or(ANONYMOUS_TENANT)
+    }
+
+    /// Records the tenant the connection of this state authenticated as, it
+    /// is kept by `reset`
+    pub fn set_tenant(&mut self, tenant: &str) {
+        self.tenant = Some(tenant.to_string());
+    }
+
+    /// The response of the ResetStats which carried `token`, if it is among
+    /// the last ones the connection of this state remembers
+    pub fn reset_response(&self, token: &[u8; RESET_TOKEN_LEN]) -> Option<Response> {
+        self.reset_tokens
+            .iter()
+            .find(|(seen, _)| seen == token)
+            .map(|&(_, response)| response)
+    }
+
+    /// Remembers the response of the ResetStats which carried `token`,
+    /// forgetting the oldest tokens past `max`. The tokens are kept by
+    /// `reset`, they go with the connection
+    pub fn remember_reset(&mut self, token: [u8; RESET_TOKEN_LEN], response: Response, max: usize) {
+        self.reset_tokens.push_back((token, response));
+        while self.reset_tokens.len() > max {
+            self.reset_tokens.pop_front();
+        }
+    }
+
+    /// Whether the stats of `tenant` are kept, making room for them unless
+    /// `max_tenants` others already are. The anonymous tenant is always kept
+    /// and doesn't count
+    pub fn admit_tenant(&mut self, tenant: &str, max_tenants: usize) -> bool {
+        if tenant == ANONYMOUS_TENANT || self.tenants.contains_key(tenant) {
+            return true;
+        }
+        let admitted = self.tenants.len() - self.tenants.contains_key(ANONYMOUS_TENANT) as usize;
+        if admitted >= max_tenants {
+            return false;
+        }
+        self.tenants.insert(tenant.to_string(), Default::default());
+        true
+    }
+
+    fn tenant_entry(&mut self, tenant: &str) -> &mut TenantStats {
+        if !self.tenants.contains_key(tenant) {
+            self.tenants.insert(tenant.to_string(), Default::default());
+        }
+        self.tenants.get_mut(tenant).unwrap()
+    }
+
+    pub fn update_tenant_read(&mut self, tenant: &str, size: usize) {
+        self.tenant_entry(tenant).stats.update_read(size)
+    }
+
+    pub fn update_tenant_sent(&mut self, tenant: &str, size: usize) {
+        self.tenant_entry(tenant).stats.update_sent(size)
+    }
+
+    pub fn update_tenant_ratio(
+        &mut self,
+        tenant: &str,
+        policy: &RatioPolicy,
+        total: usize,
+        compressed: usize,
+    ) {
+        if let Some(pending) = self.pending.as_mut() {
+            let of = Some(tenant.to_string());
+            pending.ratios.push((of, *policy, total, compressed));
+            if pending.base.is_none() {
+                return;
+            }
+        }
+        let entry = self.tenant_entry(tenant);
+        if let Some(ratio) = entry.ratio.update(policy, total, compressed) {
+            entry.stats.set_ratio_to(ratio);
+        }
+    }
+
+    /// The stats of `tenant`, zeroes if nothing was accounted to it
+    pub fn tenant_stats(&self, tenant: &str) -> Stats {
+        self.tenants
+            .get(tenant)
+            .map(|entry| entry.stats.clone())
+            .unwrap_or_default()
+    }
+
+    /// Resets the stats of `tenant` alone, those of the whole service are
+    /// kept
+    pub fn reset_tenant(&mut self, tenant: &str) {
+        if let Some(pending) = self.pending.as_mut() {
+            if let Some(base) = pending.base.as_mut() {
+                base.tenants.remove(tenant);
+            }
+            pending
+                .ratios
+                .retain(|(of, ..)| of.as_deref() != Some(tenant));
+            pending.reset_tenants.push(tenant.to_string());
+        }
+        if let Some(entry) = self.tenants.get_mut(tenant) {
+            *entry = Default::default();
+        }
+    }
+
+    /// Tenants whose stats are kept, the anonymous one included once
+    /// anything was accounted to it
+    pub fn tenants(&self) -> impl Iterator<Item = &str> {
+        self.tenants.keys().map(String::as_str)
+    }
+
+    /// Resets every counter, those of each tenant included, the tenants
+    /// admitted stay so. Unlike the resets of a connection or a tenant, the
+    /// peaks are lowered too. The metrics are kept, only counting the reset
+    ///
+    /// A pending state only resets its own counters, the state it's applied
+    /// to is reset then
     pub fn reset(&mut self) {
+        match self.pending.as_mut() {
+            Some(pending) => {
+                if let Some(base) = pending.base.as_mut() {
+                    *base = Default::default();
+                }
+                pending.ratios.clear();
+                pending.reset_tenants.clear();
+                pending.reset = true;
+            }
+            None => {
+                self.metrics.0.update_reset();
+                self.accepts.reset();
+                self.peaks.reset();
+            }
+        }
         self.stats.reset();
-        self.total = 0;
-        self.compressed = 0;
+        self.ratio = Default::default();
+        self.runs = 0;
+        self.longest_run = 0;
+        self.literals = 0;
+        self.stored = 0;
+        self.requests_per_wake = 0;
+        self.failed_writes = 0;
+        self.discarded = 0;
+        self.bad_magic_drops = 0;
+        self.requests = [0; REQUEST_KINDS];
+        self.errors = 0;
+        self.rotations = 0;
+        self.slow_requests = 0;
+        for entry in self.tenants.values_mut() {
+            *entry = Default::default();
+        }
     }
 
     // used in testing
     pub fn new_with(stats: Stats, total: usize, compressed: usize, internal_error: u16) -> State {
         State {
             stats,
-            total,
-            compressed,
+            ratio: RatioTracker::new_with(total as u64, compressed as u64),
             internal_error,
+            ..Default::default()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let mut state = State::new_with(Stats::new_with(24, 16, 0), 0, 0, 0);
+        state.update_ratio(16, 8);
+        state.update_outcome(&CompressOutcome {
+            len: 8,
+            runs: 3,
+            longest_run: 6,
+            literals: 2,
+        });
+        state.update_failed_write();
+        state.update_discarded(9000);
+        state.update_bad_magic_drop();
+        state.update_request(&Request::Compress);
+        state.update_request(&Request::Compress);
+        state.update_error();
+        state.update_rotation();
+        state.update_slow_request();
+
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.as_bytes(), state.stats_as_bytes());
+        assert_eq!(
+            (snapshot.runs, snapshot.longest_run, snapshot.literals),
+            (3, 6, 2)
+        );
+        assert_eq!(snapshot.stored_responses, 0);
+        assert_eq!(
+            (snapshot.failed_writes, snapshot.bytes_discarded),
+            (1, 9000)
+        );
+        assert_eq!(snapshot.bad_magic_drops, 1);
+        assert_eq!(snapshot.requests(&Request::Compress), 2);
+        assert_eq!(snapshot.requests(&Request::Ping), 0);
+        assert_eq!(snapshot.errors, 1);
+        assert_eq!((snapshot.rotations, snapshot.slow_requests), (1, 1));
+        assert!(snapshot
+            .to_json()
+            .contains("\"slow_requests\":1,\"expanded_outputs\":0,"));
+
+        // owned, later updates don't show through
+        state.reset();
+        assert_eq!(snapshot.as_bytes(), [0, 0, 0, 24, 0, 0, 0, 16, 50]);
+        assert_eq!(state.snapshot(), StatsSnapshot::default());
+    }
+
+    #[test]
+    fn test_since() {
+        let mut state = State::new();
+        state.update_read(100);
+        state.update_request(&Request::Compress);
+        let before = state.snapshot();
+        // a clone snapshots the same, and a snapshot equals its copies
+        assert_eq!(state.clone().snapshot(), before);
+        assert_eq!(before.clone(), before);
+        assert_eq!(before.since(&before), StatsDelta::default());
+
+        state.update_read(8);
+        state.update_sent(30);
+        state.update_request(&Request::Ping);
+        state.update_error();
+        state.update_ratio(3, 4);
+        let delta = state.snapshot().since(&before);
+        assert_eq!((delta.read, delta.sent, delta.errors), (8, 30, 1));
+        assert_eq!(delta.expanded_outputs, 1);
+        assert_eq!(delta.requests[Request::Compress as usize - 1], 0);
+        assert_eq!(delta.requests[Request::Ping as usize - 1], 1);
+
+        // reset in between, the counts since the reset and never less than 0
+        state.reset();
+        state.update_read(5);
+        let delta = state.snapshot().since(&before);
+        assert_eq!((delta.read, delta.sent, delta.errors), (5, 0, 0));
+        assert_eq!(delta.requests, [0; REQUEST_KINDS]);
+    }
+
+    #[test]
+    fn test_apply() {
+        let policy = RatioPolicy::LastN(2);
+        let update = |state: &mut State| {
+            state.update_read(24);
+            state.update_sent(16);
+            state.update_ratio_with(&policy, 16, 8);
+            state.update_ratio_with(&policy, 4, 6);
+            state.update_tenant_ratio("tenant", &policy, 16, 4);
+            state.update_tenant_read("tenant", 24);
+            state.update_outcome(&CompressOutcome {
+                len: 8,
+                runs: 3,
+                longest_run: 6,
+                literals: 2,
+            });
+            state.update_request(&Request::Compress);
+            state.update_internal_error();
+            state.update_requests_per_wake(3);
+        };
+        let mut direct = State::new_with(Stats::new_with(8, 8, 0), 0, 0, 1);
+        direct.update_ratio_with(&policy, 10, 10);
+        direct.update_requests_per_wake(5);
+        let mut applied = direct.clone();
+        update(&mut direct);
+
+        // reported as the state it was made from has it, only what changed
+        // since is applied
+        let mut pending = applied.pending();
+        assert_eq!(pending.internal_error(), 1);
+        update(&mut pending);
+        assert_eq!(pending.snapshot().stats.ratio(), 0);
+        applied.apply(pending);
+        assert_eq!(applied, direct);
+        assert_eq!(applied.snapshot(), direct.snapshot());
+        assert_eq!(
+            applied.tenant_stats("tenant"),
+            direct.tenant_stats("tenant")
+        );
+        assert_eq!(applied.metrics(), direct.metrics());
+        assert_eq!(applied.internal_error(), 2);
+        assert_eq!(applied.max_requests_per_wake(), 5);
+
+        // unanswered, only the bytes read count
+        let mut unanswered = State::new();
+        let mut pending = unanswered.pending();
+        update(&mut pending);
+        pending.update_discarded(4);
+        unanswered.apply_unanswered(pending);
+        let snapshot = unanswered.snapshot();
+        assert_eq!((snapshot.stats.read(), snapshot.stats.sent()), (24, 0));
+        assert_eq!(snapshot.requests(&Request::Compress), 0);
+        assert_eq!(unanswered.bytes_discarded(), 4);
+        assert_eq!(unanswered.runs(), 0);
+        assert_eq!(unanswered.tenant_stats("tenant").read(), 24);
+
+        // a view reports the counters of the state it was made from with the
+        // pending ones, only what changed since it was made is applied
+        let mut shared = State::new_with(Stats::new_with(8, 8, 0), 0, 0, 0);
+        shared.update_request(&Request::Ping);
+        let mut pending = shared.pending();
+        pending.update_read(8);
+        let mut view = shared.view(pending);
+        view.update_request(&Request::GetStats);
+        view.update_ratio(16, 8);
+        assert_eq!(view.snapshot().stats.read(), 16);
+        assert_eq!(view.snapshot().stats.ratio(), 50);
+        assert_eq!(shared.snapshot().stats.read(), 8);
+        shared.apply(view);
+        let snapshot = shared.snapshot();
+        assert_eq!((snapshot.stats.read(), snapshot.stats.sent()), (16, 8));
+        assert_eq!(snapshot.stats.ratio(), 50);
+        assert_eq!(shared.metrics().compress_input_bytes, 16);
+        assert_eq!(snapshot.requests(&Request::Ping), 1);
+        assert_eq!(snapshot.requests(&Request::GetStats), 1);
+
+        // one reset resets the state it's applied to, then
+        let mut pending = shared.pending();
+        pending.update_read(8);
+        let mut view = shared.view(pending);
+        view.reset();
+        view.update_sent(8);
+        assert_eq!(view.snapshot().stats.read(), 0);
+        assert_eq!(shared.snapshot().stats.read(), 16);
+        shared.apply(view);
+        let snapshot = shared.snapshot();
+        assert_eq!((snapshot.stats.read(), snapshot.stats.sent()), (0, 8));
+        assert_eq!(snapshot.requests(&Request::GetStats), 0);
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let state = State::new();
+        let in_flight = state.clone().in_flight();
+        // alone, a request over the budget is let through
+        let mut first = in_flight.reserve(30, 16).unwrap();
+        assert!(in_flight.reserve(8, 16).is_none());
+        first.grow(10);
+        assert_eq!(state.snapshot().in_flight_bytes, 40);
+        assert!(in_flight.reserve(8, 0).is_some());
+        drop(first);
+        assert_eq!(in_flight.bytes(), 0);
+
+        let second = in_flight.reserve(8, 16).unwrap();
+        let third = in_flight.reserve(8, 16).unwrap();
+        assert!(in_flight.reserve(1, 16).is_none());
+        drop((second, third));
+        assert_eq!(state.snapshot(), StatsSnapshot::default());
+    }
+
+    #[test]
+    fn test_peaks() {
+        let mut state = State::new();
+        let peaks = state.clone().peaks();
+        let mut open: Vec<_> = (0..3).map(|_| peaks.open_connection()).collect();
+        let heavy = peaks.open_heavy();
+        peaks.update_request(8192);
+        peaks.update_request(100);
+        peaks.update_response(5);
+        open.truncate(1);
+        assert_eq!(peaks.connections(), 1);
+        let snapshot = state.snapshot();
+        assert_eq!((snapshot.peak_connections, snapshot.peak_heavy), (3, 1));
+        assert_eq!(
+            (snapshot.largest_request, snapshot.largest_response),
+            (8192, 5)
+        );
+        assert!(snapshot.to_json().ends_with(
+            "\"peak_connections\":3,\"peak_heavy\":1,\"largest_request\":8192,\
+             \"largest_response\":5}"
+        ));
+
+        // kept by the resets of a tenant, lowered to what is open by the
+        // global one
+        state.reset_tenant(ANONYMOUS_TENANT);
+        assert_eq!(state.snapshot().peak_connections, 3);
+        drop(heavy);
+        state.reset();
+        let snapshot = state.snapshot();
+        assert_eq!((snapshot.peak_connections, snapshot.peak_heavy), (1, 0));
+        assert_eq!(snapshot.largest_request, 0);
+    }
+
+    #[test]
+    fn test_publish() {
+        let published = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut state = State::new();
+        let observed = Arc::clone(&published);
+        state.observe(Arc::new(move |snapshot: &StatsSnapshot| {
+            observed.lock().unwrap().push(snapshot.stats.read())
+        }));
+        state.update_read(MATERIAL_CHANGE - 1);
+        state.publish();
+        assert!(published.lock().unwrap().is_empty());
+        // kept by clones
+        let mut state = state.clone();
+        state.update_sent(1);
+        state.publish();
+        state.update_read(1);
+        state.publish();
+        state.reset();
+        state.publish();
+        let reads = published.lock().unwrap().clone();
+        assert_eq!(reads, [MATERIAL_CHANGE as u32 - 1, 0]);
+    }
+}
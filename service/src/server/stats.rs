@@ -1,12 +1,28 @@
 use byteorder::NetworkEndian;
-use zerocopy::{byteorder::U32, AsBytes, ByteSlice, FromBytes, LayoutVerified, Unaligned};
+use core::{error::Error, fmt};
+use zerocopy::{
+    byteorder::{U32, U64},
+    AsBytes, ByteSlice, FromBytes, LayoutVerified, Unaligned,
+};
+
+/// The layout version GetStatsV2 prefixes its payload with, the unversioned
+/// GetStats payload is retroactively version 1
+pub const STATS_VERSION: u8 = 2;
+
+/// Bytes of a `Stats` on the wire, the GetStats payload and the tail of a
+/// CompressWithStats one
+pub const STATS_LEN: usize = core::mem::size_of::<Stats>();
 
 /// Useful for keeping track of client server communication
 /// Count of all bytes received by the service, including headers
 /// sent: Count of all bytes sent by the service, including headers
 /// ratio: From 0-100 representing the performance of the compression service
-#[derive(Default, Debug, PartialEq, AsBytes, FromBytes, Unaligned)]
-#[repr(packed)]
+///
+/// Every field is unaligned (byte-array backed), so `repr(C)` already lays out
+/// the 9 bytes without padding and no reference to a field is ever unaligned,
+/// which `repr(packed)` could not guarantee
+#[derive(Default, Debug, Clone, PartialEq, AsBytes, FromBytes, Unaligned)]
+#[repr(C)]
 pub struct Stats {
     read: U32<NetworkEndian>,
     sent: U32<NetworkEndian>,
@@ -47,11 +63,41 @@ impl Stats {
     }
 
     pub fn set_ratio(&mut self, compressed: usize, msg_total: usize) {
-        if msg_total > 0 && compressed > 0 {
-            let new_ratio = compressed as f64 / msg_total as f64;
-            let ratio = (1f64 - new_ratio) * 100f64;
-            self.ratio = ratio as u8;
+        if let Some(ratio) = Stats::ratio_of(compressed as u64, msg_total as u64) {
+            self.ratio = ratio;
+        }
+    }
+
+    /// Sets the ratio as computed by a `RatioPolicy`
+    pub fn set_ratio_to(&mut self, ratio: u8) {
+        self.ratio = ratio;
+    }
+
+    /// The ratio of `msg_total` bytes compressed down to `compressed`, to the
+    /// nearest percent, `None` if either is 0, the ratio is then left as it
+    /// was. 0 for outputs larger than their input
+    ///
+    /// Computed in integers, exact whatever the totals grew to, the service
+    /// and the test client's mirror of it both compute it here
+    pub fn ratio_of(compressed: u64, msg_total: u64) -> Option<u8> {
+        if msg_total == 0 || compressed == 0 {
+            return None;
         }
+        // widened, the bytes saved times 100 overflow a u64 long before the
+        // totals do
+        let (saved, total) = (
+            msg_total.saturating_sub(compressed) as u128,
+            msg_total as u128,
+        );
+        let ratio = (saved * 100 + total / 2) / total;
+        Some(ratio.min(100) as u8)
+    }
+
+    /// The ratio of outputs `fraction` the size of their inputs, to the
+    /// nearest percent like `ratio_of`, 0 for outputs larger than their input
+    pub fn ratio_from(fraction: f64) -> u8 {
+        // rounded by hand, `f64::round` needs std
+        (((1f64 - fraction) * 100f64).clamp(0f64, 100f64) + 0.5) as u8
     }
 
     pub fn reset(&mut self) {
@@ -63,23 +109,477 @@ impl Stats {
 
 // used in test-client package
 impl Stats {
+    /// `parse_exact`, for callers that don't need to know why it failed
     pub fn parse<B: ByteSlice>(bytes: B) -> Option<LayoutVerified<B, Stats>> {
-        let stats = LayoutVerified::new(bytes)?;
-        Some(stats)
+        Stats::parse_exact(bytes).ok()
+    }
+
+    /// Parses `bytes` of exactly `STATS_LEN`, as in a GetStats payload
+    pub fn parse_exact<B: ByteSlice>(
+        bytes: B,
+    ) -> Result<LayoutVerified<B, Stats>, StatsParseError> {
+        let actual = bytes.len();
+        LayoutVerified::new(bytes).ok_or(StatsParseError {
+            expected: STATS_LEN,
+            actual,
+        })
+    }
+
+    /// Parses the stats at the start of `bytes`, returning them along with
+    /// the bytes past them, as when stats are followed by more of a response
+    pub fn parse_prefix<B: ByteSlice>(
+        bytes: B,
+    ) -> Result<(LayoutVerified<B, Stats>, B), StatsParseError> {
+        let actual = bytes.len();
+        LayoutVerified::new_from_prefix(bytes).ok_or(StatsParseError {
+            expected: STATS_LEN,
+            actual,
+        })
+    }
+
+    /// Parses a payload prefixed by its layout version, as sent in response
+    /// to GetStatsV2, into whichever layout it is of
+    pub fn parse_versioned(bytes: &[u8]) -> Result<VersionedStats, StatsError> {
+        let (&version, layout) = bytes.split_first().ok_or(StatsError::Empty)?;
+        let bad_length = StatsError::BadLength {
+            version,
+            len: layout.len(),
+        };
+        match version {
+            1 => LayoutVerified::<_, Stats>::new(layout)
+                .map(|stats| VersionedStats::V1(stats.clone()))
+                .ok_or(bad_length),
+            2 => LayoutVerified::<_, StatsV2>::new(layout)
+                .map(|stats| VersionedStats::V2(stats.clone()))
+                .ok_or(bad_length),
+            _ => Err(StatsError::UnknownVersion(version)),
+        }
     }
 }
 
+/// e.g. "read=1.2KiB sent=980B ratio=33%", or with "{:#}" one field per
+/// line along with the exact byte counts
+impl fmt::Display for Stats {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        display(fmt, self.read() as u64, self.sent() as u64, self.ratio())
+    }
+}
+
+/// Version 2 of the stats layout, the counters of `Stats` widened so they
+/// can grow past 4GiB
+/// read: u64 count of all bytes received by the service, including headers
+/// sent: u64 count of all bytes sent by the service, including headers
+/// ratio: From 0-100 representing the performance of the compression service
+///
+/// Unaligned like `Stats`, `repr(C)` lays out the 17 bytes without padding
+#[derive(Default, Debug, Clone, PartialEq, AsBytes, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct StatsV2 {
+    read: U64<NetworkEndian>,
+    sent: U64<NetworkEndian>,
+    ratio: u8,
+}
+
+impl StatsV2 {
+    pub fn new_with(read: u64, sent: u64, ratio: u8) -> StatsV2 {
+        StatsV2 {
+            read: U64::new(read),
+            sent: U64::new(sent),
+            ratio,
+        }
+    }
+
+    pub fn read(&self) -> u64 {
+        self.read.get()
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.get()
+    }
+
+    pub fn ratio(&self) -> u8 {
+        self.ratio
+    }
+}
+
+/// Like `Stats`
+impl fmt::Display for StatsV2 {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        display(fmt, self.read(), self.sent(), self.ratio())
+    }
+}
+
+impl From<&Stats> for StatsV2 {
+    fn from(stats: &Stats) -> StatsV2 {
+        StatsV2::new_with(stats.read() as u64, stats.sent() as u64, stats.ratio())
+    }
+}
+
+/// The stats of a payload of any layout version this library knows, see
+/// `Stats::parse_versioned`. The getters widen to the largest layout so
+/// that clients handle every version alike
+#[derive(Debug, Clone, PartialEq)]
+pub enum VersionedStats {
+    V1(Stats),
+    V2(StatsV2),
+}
+
+impl VersionedStats {
+    pub fn version(&self) -> u8 {
+        match self {
+            VersionedStats::V1(_) => 1,
+            VersionedStats::V2(_) => 2,
+        }
+    }
+
+    pub fn read(&self) -> u64 {
+        match self {
+            VersionedStats::V1(stats) => stats.read() as u64,
+            VersionedStats::V2(stats) => stats.read(),
+        }
+    }
+
+    pub fn sent(&self) -> u64 {
+        match self {
+            VersionedStats::V1(stats) => stats.sent() as u64,
+            VersionedStats::V2(stats) => stats.sent(),
+        }
+    }
+
+    pub fn ratio(&self) -> u8 {
+        match self {
+            VersionedStats::V1(stats) => stats.ratio(),
+            VersionedStats::V2(stats) => stats.ratio(),
+        }
+    }
+}
+
+/// Like `Stats`, whatever the version
+impl fmt::Display for VersionedStats {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        display(fmt, self.read(), self.sent(), self.ratio())
+    }
+}
+
+fn display(fmt: &mut fmt::Formatter, read: u64, sent: u64, ratio: u8) -> fmt::Result {
+    if fmt.alternate() {
+        writeln!(fmt, "read:  {} ({} bytes)", HumanBytes(read), read)?;
+        writeln!(fmt, "sent:  {} ({} bytes)", HumanBytes(sent), sent)?;
+        write!(fmt, "ratio: {}%", ratio)
+    } else {
+        write!(
+            fmt,
+            "read={} sent={} ratio={}%",
+            HumanBytes(read),
+            HumanBytes(sent),
+            ratio
+        )
+    }
+}
+
+/// A count of bytes displayed in the largest binary unit it makes at least
+/// one of, to a tenth rounded to nearest, e.g. "980B", "1.2KiB", "4.0GiB"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HumanBytes(pub u64);
+
+impl fmt::Display for HumanBytes {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+        let bytes = self.0 as u128;
+        if bytes < 1024 {
+            return write!(fmt, "{}B", bytes);
+        }
+        // in tenths of the unit, moving up a unit when the rounding would
+        // display 1024.0 of the one below
+        let mut unit = 0;
+        let tenths = loop {
+            let size = 1u128 << (10 * (unit + 1));
+            let tenths = (bytes * 10 + size / 2) / size;
+            if tenths < 10240 || unit == UNITS.len() - 1 {
+                break tenths;
+            }
+            unit += 1;
+        };
+        write!(fmt, "{}.{}{}", tenths / 10, tenths % 10, UNITS[unit])
+    }
+}
+
+/// Why a versioned stats payload couldn't be parsed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsError {
+    /// The payload has no version byte
+    Empty,
+    /// The version is of a layout newer than this library
+    UnknownVersion(u8),
+    /// The payload past the version byte is not of the version's size
+    BadLength { version: u8, len: usize },
+}
+
+impl fmt::Display for StatsError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StatsError::Empty => write!(fmt, "stats payload without a version"),
+            StatsError::UnknownVersion(version) => {
+                write!(fmt, "unknown stats layout version {}", version)
+            }
+            StatsError::BadLength { version, len } => write!(
+                fmt,
+                "{} bytes are not a version {} stats layout",
+                len, version
+            ),
+        }
+    }
+}
+
+impl Error for StatsError {}
+
+/// Why bytes couldn't be parsed as a `Stats`, see `Stats::parse_exact` and
+/// `Stats::parse_prefix`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatsParseError {
+    /// Bytes of a `Stats`, exactly or at least
+    pub expected: usize,
+    /// Bytes given
+    pub actual: usize,
+}
+
+impl fmt::Display for StatsParseError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{} bytes are not a stats layout of {} bytes",
+            self.actual, self.expected
+        )
+    }
+}
+
+impl Error for StatsParseError {}
+
 #[cfg(test)]
 mod tests {
+    use std::mem;
     use zerocopy::AsBytes;
 
     #[test]
+    fn test_layout() {
+        assert_eq!(mem::size_of::<super::Stats>(), 9);
+        assert_eq!(mem::align_of::<super::Stats>(), 1);
+    }
+
+    #[test]
+    fn test_update() {
+        let mut stats = super::Stats::new();
+        stats.update_read(300);
+        stats.update_sent(70000);
+        stats.set_ratio(43, 100);
+        assert_eq!(
+            (stats.read(), stats.sent(), stats.ratio()),
+            (300, 70000, 57)
+        );
+        assert_eq!(stats.as_bytes(), [0, 0, 1, 44, 0, 1, 17, 112, 57]);
+        stats.reset();
+        assert_eq!(stats.as_bytes(), [0; 9]);
+    }
+
+    #[test]
+    fn test_ratio_of() {
+        use super::Stats;
+        // nothing to compute it from
+        assert_eq!(Stats::ratio_of(0, 0), None);
+        assert_eq!(Stats::ratio_of(5, 0), None);
+        assert_eq!(Stats::ratio_of(0, 5), None);
+        // to the nearest percent, halves up
+        assert_eq!(Stats::ratio_of(43, 100), Some(57));
+        assert_eq!(Stats::ratio_of(1, 3), Some(67));
+        assert_eq!(Stats::ratio_of(2, 3), Some(33));
+        assert_eq!(Stats::ratio_of(1, 200), Some(100));
+        assert_eq!(Stats::ratio_of(3, 200), Some(99));
+        assert_eq!(Stats::ratio_of(199, 200), Some(1));
+        assert_eq!(Stats::ratio_of(1, 1), Some(0));
+        assert_eq!(Stats::ratio_of(1, 2), Some(50));
+        // larger outputs are 0, never wrapped
+        assert_eq!(Stats::ratio_of(9, 7), Some(0));
+        assert_eq!(Stats::ratio_of(u64::MAX, 1), Some(0));
+        // at huge magnitudes
+        for total in [1 << 32, u64::MAX / 100, u64::MAX / 2, u64::MAX] {
+            assert_eq!(Stats::ratio_of(total, total), Some(0), "{}", total);
+            assert_eq!(Stats::ratio_of(total - 1, total), Some(0), "{}", total);
+            assert_eq!(Stats::ratio_of(total / 2, total), Some(50), "{}", total);
+            assert_eq!(Stats::ratio_of(1, total), Some(100), "{}", total);
+            assert_eq!(Stats::ratio_of(total, total - 1), Some(0), "{}", total);
+        }
+        // exact at the largest totals, where the bytes saved times 100
+        // overflow a u64
+        assert_eq!(Stats::ratio_of(u64::MAX / 4, u64::MAX), Some(75));
+        assert_eq!(Stats::ratio_of(u64::MAX / 100 * 43, u64::MAX), Some(57));
+        assert_eq!(Stats::ratio_of(u64::MAX - 1, u64::MAX), Some(0));
+
+        assert_eq!(Stats::ratio_from(0.53125), 47);
+        assert_eq!(Stats::ratio_from(1.0), 0);
+        assert_eq!(Stats::ratio_from(2.5), 0);
+        assert_eq!(Stats::ratio_from(0.0), 100);
+    }
+
+    #[test]
+    #[allow(clippy::nonminimal_bool)]
     fn test_parse() {
         let msg = [0, 0, 0, 22, 0, 0, 0, 22, 10];
         let stats = super::Stats::parse(&msg[..]);
         assert!(!stats.is_none())
     }
 
+    #[test]
+    fn test_parse_lengths() {
+        use super::{Stats, StatsParseError};
+
+        let bytes: Vec<u8> = (1..=17).collect();
+        let short = StatsParseError {
+            expected: 9,
+            actual: 8,
+        };
+        assert_eq!(Stats::parse_exact(&bytes[..8]).err(), Some(short));
+        assert_eq!(Stats::parse_prefix(&bytes[..8]).err(), Some(short));
+        assert_eq!(
+            short.to_string(),
+            "8 bytes are not a stats layout of 9 bytes"
+        );
+
+        let stats = Stats::parse_exact(&bytes[..9]).unwrap();
+        assert_eq!(stats.as_bytes(), &bytes[..9]);
+        let (stats, rest) = Stats::parse_prefix(&bytes[..9]).unwrap();
+        assert_eq!((stats.as_bytes(), rest), (&bytes[..9], &[][..]));
+
+        for len in [10, 17] {
+            assert_eq!(
+                Stats::parse_exact(&bytes[..len]).err(),
+                Some(StatsParseError {
+                    expected: 9,
+                    actual: len
+                })
+            );
+            assert!(Stats::parse(&bytes[..len]).is_none());
+            let (stats, rest) = Stats::parse_prefix(&bytes[..len]).unwrap();
+            assert_eq!((stats.as_bytes(), rest), (&bytes[..9], &bytes[9..len]));
+        }
+    }
+
+    #[test]
+    fn test_network_order() {
+        use super::{Stats, StatsV2};
+        use zerocopy::LayoutVerified;
+
+        // most significant byte first, whatever the host's order
+        let bytes = [0x01, 0x02, 0x03, 0x04, 0xa1, 0xa2, 0xa3, 0xa4, 99];
+        let stats = Stats::parse_exact(&bytes[..]).unwrap();
+        assert_eq!(
+            (stats.read(), stats.sent(), stats.ratio()),
+            (0x0102_0304, 0xa1a2_a3a4, 99)
+        );
+        let built = Stats::new_with(0x0102_0304, 0xa1a2_a3a4, 99);
+        assert_eq!(built.as_bytes(), bytes);
+
+        let mut bytes: Vec<u8> = (1..=16).collect();
+        bytes.push(42);
+        let stats = LayoutVerified::<_, StatsV2>::new(&bytes[..]).unwrap();
+        assert_eq!(
+            (stats.read(), stats.sent(), stats.ratio()),
+            (0x0102_0304_0506_0708, 0x090a_0b0c_0d0e_0f10, 42)
+        );
+        let built = StatsV2::new_with(0x0102_0304_0506_0708, 0x090a_0b0c_0d0e_0f10, 42);
+        assert_eq!(built.as_bytes(), &bytes[..]);
+    }
+
+    #[test]
+    fn test_parse_versioned() {
+        use super::{Stats, StatsError, StatsV2, VersionedStats};
+
+        let v1 = [1, 0, 0, 0, 22, 0, 0, 0, 23, 10];
+        let stats = Stats::parse_versioned(&v1).unwrap();
+        assert_eq!(stats, VersionedStats::V1(Stats::new_with(22, 23, 10)));
+        assert_eq!((stats.version(), stats.read(), stats.sent()), (1, 22, 23));
+
+        let mut v2 = vec![2];
+        v2.extend_from_slice(&(5u64 << 32).to_be_bytes());
+        v2.extend_from_slice(&23u64.to_be_bytes());
+        v2.push(10);
+        let stats = Stats::parse_versioned(&v2).unwrap();
+        assert_eq!(
+            stats,
+            VersionedStats::V2(StatsV2::new_with(5 << 32, 23, 10))
+        );
+        assert_eq!(
+            (stats.version(), stats.read(), stats.sent(), stats.ratio()),
+            (2, 5 << 32, 23, 10)
+        );
+        assert_eq!(
+            StatsV2::from(&Stats::new_with(22, 23, 10)).as_bytes()[..],
+            [0, 0, 0, 0, 0, 0, 0, 22, 0, 0, 0, 0, 0, 0, 0, 23, 10]
+        );
+
+        // a newer layout is told apart from a malformed one
+        let mut v9 = vec![9];
+        v9.extend_from_slice(&[0; 32]);
+        assert_eq!(
+            Stats::parse_versioned(&v9),
+            Err(StatsError::UnknownVersion(9))
+        );
+        assert_eq!(
+            Stats::parse_versioned(&v2[..10]),
+            Err(StatsError::BadLength { version: 2, len: 9 })
+        );
+        // the unversioned GetStats payload isn't mistaken for either layout
+        assert_eq!(
+            Stats::parse_versioned(&[0, 0, 0, 22, 0, 0, 0, 22, 10]),
+            Err(StatsError::UnknownVersion(0))
+        );
+        assert_eq!(Stats::parse_versioned(&[]), Err(StatsError::Empty));
+    }
+
+    #[test]
+    fn test_human_bytes() {
+        use super::HumanBytes;
+
+        let displayed: Vec<_> = [
+            0,
+            980,
+            1023,
+            1024,
+            1228,
+            // rounds up to the next unit rather than to "1024.0KiB"
+            1024 * 1024 - 1,
+            5 * 1024 * 1024 + 512 * 1024,
+            u32::MAX as u64,
+            u64::MAX,
+        ]
+        .iter()
+        .map(|&bytes| HumanBytes(bytes).to_string())
+        .collect();
+        assert_eq!(
+            displayed,
+            ["0B", "980B", "1023B", "1.0KiB", "1.2KiB", "1.0MiB", "5.5MiB", "4.0GiB", "16.0EiB"]
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        use super::{Stats, StatsV2, VersionedStats};
+
+        let stats = Stats::new_with(1228, 980, 33);
+        assert_eq!(stats.to_string(), "read=1.2KiB sent=980B ratio=33%");
+        assert_eq!(
+            format!("{:#}", stats),
+            "read:  1.2KiB (1228 bytes)\nsent:  980B (980 bytes)\nratio: 33%"
+        );
+        assert_eq!(Stats::new().to_string(), "read=0B sent=0B ratio=0%");
+        let max = Stats::new_with(u32::MAX, u32::MAX, 100);
+        assert_eq!(max.to_string(), "read=4.0GiB sent=4.0GiB ratio=100%");
+        assert_eq!(
+            StatsV2::new_with(5 << 40, 0, 50).to_string(),
+            "read=5.0TiB sent=0B ratio=50%"
+        );
+        assert_eq!(VersionedStats::V1(max.clone()).to_string(), max.to_string());
+    }
+
     #[test]
     fn test_as_bytes() {
         let stats = super::Stats::new_with(22, 22, 10);
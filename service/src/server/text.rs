@@ -0,0 +1,216 @@
+use super::stats::Stats;
+use crate::message::{Header, HealthStatus, Request, Response, HEADER_SIZE, MAX_PAYLOAD};
+use zerocopy::AsBytes;
+
+/// The longest line the debug port accepts, its line terminator excluded
+pub const MAX_LINE: usize = MAX_PAYLOAD as usize;
+
+/// The debug port's line for the recent requests, answered by the server
+/// from its `Recent` rather than translated into a binary request
+pub const RECENT: &[u8] = b"RECENT";
+
+/// The debug port's line for the metrics, answered by the server from its
+/// `State` in the Prometheus text format, then a line of "# EOF"
+pub const METRICS: &[u8] = b"METRICS";
+
+/// A line of the debug port's text protocol, each one is translated into the
+/// binary request it stands for
+/// "PING" => Ping
+/// "STATS" => GetStats
+/// "RESET" => ResetStats, of the scope an empty ResetStats resets
+/// "COMPRESS <text>" => Compress of text
+/// "HEALTH" => Health
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command<'a> {
+    Ping,
+    Stats,
+    Reset,
+    Compress(&'a [u8]),
+    Health,
+}
+
+impl<'a> Command<'a> {
+    /// Parses a line, without its terminator, or returns the name of the
+    /// error it's answered with. Command names are case-insensitive
+    pub fn parse(line: &'a [u8]) -> Result<Command<'a>, &'static str> {
+        if line.len() > MAX_LINE {
+            return Err("line-too-long");
+        }
+        if !line.is_ascii() {
+            return Err("non-ascii");
+        }
+        let (name, argument) = match line.iter().position(|&b| b == b' ') {
+            Some(i) => (&line[..i], Some(&line[i + 1..])),
+            None => (line, None),
+        };
+        let command = match name.to_ascii_uppercase().as_slice() {
+            b"PING" => Command::Ping,
+            b"STATS" => Command::Stats,
+            b"RESET" => Command::Reset,
+            b"HEALTH" => Command::Health,
+            b"COMPRESS" => return Ok(Command::Compress(argument.unwrap_or_default())),
+            _ => return Err("unknown-command"),
+        };
+        match argument {
+            Some(_) => Err("unexpected-argument"),
+            None => Ok(command),
+        }
+    }
+
+    pub fn request(&self) -> Request {
+        match self {
+            Command::Ping => Request::Ping,
+            Command::Stats => Request::GetStats,
+            Command::Reset => Request::ResetStats,
+            Command::Compress(_) => Request::Compress,
+            Command::Health => Request::Health,
+        }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        match self {
+            Command::Compress(text) => text,
+            _ => b"",
+        }
+    }
+
+    /// Writes the binary request into `rx`, returning its length
+    pub fn encode(&self, rx: &mut [u8]) -> usize {
+        let payload = self.payload();
+        // a line is never longer than MAX_PAYLOAD
+        let header = Header::request(self.request(), payload.len() as u16).unwrap();
+        rx[..HEADER_SIZE].copy_from_slice(header.as_bytes());
+        rx[HEADER_SIZE..HEADER_SIZE + payload.len()].copy_from_slice(payload);
+        HEADER_SIZE + payload.len()
+    }
+
+    /// The line answering this command given the binary response to it,
+    /// "OK" followed by what the payload holds if anything, otherwise the
+    /// "ERR" of the response code. Health is "OK ready", or "ERR" followed by
+    /// the status and its detail
+    pub fn reply(&self, response: Option<Response>, payload: &[u8]) -> String {
+        match (response, self) {
+            (Some(Response::Ok), Command::Compress(_)) => {
+                format!("OK {}", String::from_utf8_lossy(payload))
+            }
+            (Some(Response::Ok), Command::Stats) => match Stats::parse(payload) {
+                Some(stats) => format!(
+                    "OK read={} sent={} ratio={}",
+                    stats.read(),
+                    stats.sent(),
+                    stats.ratio()
+                ),
+                None => error_line(Response::UnknownError),
+            },
+            (Some(Response::Ok), Command::Health) => {
+                match payload
+                    .split_first()
+                    .map(|(s, d)| (HealthStatus::from_u8(*s), d))
+                {
+                    Some((Some(HealthStatus::Ready), _)) => "OK ready".to_string(),
+                    Some((Some(status), detail)) => {
+                        format!("ERR {} {}", status.name(), String::from_utf8_lossy(detail))
+                    }
+                    _ => error_line(Response::UnknownError),
+                }
+            }
+            (Some(Response::Ok), _) => "OK".to_string(),
+            (Some(response), _) => error_line(response),
+            (None, _) => error_line(Response::UnknownError),
+        }
+    }
+}
+
+fn error_line(response: Response) -> String {
+    format!("ERR {}", error_name(response))
+}
+
+/// The name of a response code on the debug port
+pub fn error_name(response: Response) -> &'static str {
+    match response {
+        Response::Ok => "ok",
+        Response::UnknownError => "unknown-error",
+        Response::MessageTooLarge => "too-large",
+        Response::UnsupportedRequestType => "unsupported-request",
+        Response::MessageTooSmall => "too-small",
+        Response::MessageHeaderHasBadMagic => "bad-magic",
+        Response::MessageHeaderSizeMismatch => "size-mismatch",
+        Response::RequestKindRequiresZeroLength => "unexpected-argument",
+        Response::CompressionRequestRequiresNonZeroLength => "empty-payload",
+        Response::MessagePayloadContainsInvalidCharacters => "invalid-characters",
+        Response::MessageContainsUppercaseCharacters => "uppercase-characters",
+        Response::MessageContainsDigits => "digits",
+        Response::MessageContainsNonAscii => "non-ascii",
+        Response::MalformedCompressedPayload => "malformed-payload",
+        Response::ResponseTooLarge => "response-too-large",
+        Response::UnsupportedFlags => "unsupported-flags",
+        Response::Forbidden => "forbidden",
+        Response::UnsupportedResetScope => "unsupported-scope",
+        Response::Goodbye => "goodbye",
+        Response::ServerBusy => "server-busy",
+        Response::Unauthorized => "unauthorized",
+        Response::IoError => "io-error",
+        Response::TrailingBytes => "trailing-bytes",
+        Response::NonCanonicalEncoding => "non-canonical",
+        Response::Batch => "batch",
+        Response::BatchTooLarge => "batch-too-large",
+        Response::Hello => "hello",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Message;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Command::parse(b"PING"), Ok(Command::Ping));
+        assert_eq!(Command::parse(b"stats"), Ok(Command::Stats));
+        assert_eq!(Command::parse(b"RESET"), Ok(Command::Reset));
+        assert_eq!(Command::parse(b"health"), Ok(Command::Health));
+        assert_eq!(
+            Command::parse(b"COMPRESS aaaaabbb"),
+            Ok(Command::Compress(b"aaaaabbb"))
+        );
+        assert_eq!(Command::parse(b"COMPRESS"), Ok(Command::Compress(b"")));
+        assert_eq!(Command::parse(b"PING now"), Err("unexpected-argument"));
+        assert_eq!(Command::parse(b"HELP"), Err("unknown-command"));
+        assert_eq!(Command::parse(b""), Err("unknown-command"));
+        assert_eq!(Command::parse("COMPRESS é".as_bytes()), Err("non-ascii"));
+        let line = vec![b'a'; MAX_LINE + 1];
+        assert_eq!(Command::parse(&line), Err("line-too-long"));
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut rx = [0u8; 32];
+        let len = Command::Compress(b"aab").encode(&mut rx);
+        let message = Message::parse(&rx[..len]).unwrap();
+        assert_eq!(message.header.code(), Request::Compress as u16);
+        assert_eq!(message.payload_slice(), b"aab");
+        assert_eq!(Command::Stats.encode(&mut rx), HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_reply() {
+        let compress = Command::Compress(b"aaaaabbb");
+        assert_eq!(compress.reply(Some(Response::Ok), b"5a3b"), "OK 5a3b");
+        assert_eq!(
+            compress.reply(Some(Response::MessageContainsDigits), b""),
+            "ERR digits"
+        );
+        let stats = Stats::new_with(24, 16, 50);
+        assert_eq!(
+            Command::Stats.reply(Some(Response::Ok), stats.as_bytes()),
+            "OK read=24 sent=16 ratio=50"
+        );
+        assert_eq!(Command::Ping.reply(Some(Response::Ok), b""), "OK");
+        assert_eq!(Command::Reset.reply(None, b""), "ERR unknown-error");
+        let health = |payload: &[u8]| Command::Health.reply(Some(Response::Ok), payload);
+        assert_eq!(health(&[0]), "OK ready");
+        assert_eq!(health(b"\x01shutting down"), "ERR draining shutting down");
+        assert_eq!(health(&[9]), "ERR unknown-error");
+        assert_eq!(health(b""), "ERR unknown-error");
+    }
+}
@@ -0,0 +1,392 @@
+//! Generators of the protocol's types for fuzz targets and property tests,
+//! built with the `testing` feature
+//!
+//! `Arbitrary` builds a `Header`, `Request`, `Response`, `Stats` or a whole
+//! `WireMessage` from the bytes of a fuzzer. The proptest strategies run the
+//! same `Arbitrary` implementations over random bytes, so that fuzz targets,
+//! property tests and the test client's fuzzing all draw from one generator
+use crate::message::{
+    Flag, Header, PayloadPolicy, Request, ResetScope, Response, CODE_MASK, HEADER_SIZE, MAGIC,
+};
+use crate::message::{MAX_MESSAGE, MAX_PAYLOAD};
+use crate::Stats;
+use arbitrary::Result;
+use proptest::{collection, prelude::*};
+use std::{fmt, ops::RangeInclusive};
+use wire::message;
+use zerocopy::AsBytes;
+
+/// For the generators to be used without depending on the same arbitrary
+pub use arbitrary::{Arbitrary, Unstructured};
+
+#[cfg(feature = "server")]
+pub mod replay;
+pub mod wire;
+
+/// Every request of the protocol, in the order of their codes
+pub fn requests() -> Vec<Request> {
+    (1..=CODE_MASK).map_while(Request::from_u16).collect()
+}
+
+/// The requests of `policy`, in the order of their codes
+pub fn requests_of(policy: PayloadPolicy) -> Vec<Request> {
+    requests()
+        .into_iter()
+        .filter(|request| request.payload_policy() == policy)
+        .collect()
+}
+
+/// Every response of the protocol, in the order of their codes
+pub fn responses() -> Vec<Response> {
+    (0..=CODE_MASK).filter_map(Response::from_u16).collect()
+}
+
+impl<'a> Arbitrary<'a> for Request {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Request> {
+        Ok(u.choose(&requests())?.clone())
+    }
+}
+
+impl<'a> Arbitrary<'a> for Response {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Response> {
+        Ok(*u.choose(&responses())?)
+    }
+}
+
+/// Any size and code, mostly under the magic and without flags so that the
+/// service looks past them
+impl<'a> Arbitrary<'a> for Header {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Header> {
+        let sign = if u.ratio(1, 8)? {
+            u.arbitrary()?
+        } else {
+            MAGIC
+        };
+        let flags: u8 = if u.ratio(1, 8)? { u.arbitrary()? } else { 0 };
+        let code = u16::from_be_bytes([flags, u.arbitrary()?]);
+        Ok(Header::raw(sign, u.arbitrary()?, code))
+    }
+}
+
+/// Any byte counts, a ratio within 0-100
+impl<'a> Arbitrary<'a> for Stats {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Stats> {
+        Ok(Stats::new_with(
+            u.arbitrary()?,
+            u.arbitrary()?,
+            u.int_in_range(0..=100)?,
+        ))
+    }
+}
+
+/// How far a generated `WireMessage` is from what the service accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Validity {
+    /// Always passes `Message::validate`, the request may still be refused
+    /// for what it asks, e.g. an Authenticate of an unknown token
+    AlwaysValid,
+    /// Framed right, the magic and a size matching the payload, but wrong
+    /// for the request: a payload where none is allowed, invalid characters,
+    /// unknown codes or flags, a malformed compressed payload
+    StructurallyValidButSemanticallyWrong,
+    /// Random bytes, short or torn headers, sizes that don't match, any
+    /// sign and code
+    Garbage,
+}
+
+impl<'a> Arbitrary<'a> for Validity {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Validity> {
+        Ok(*u.choose(&[
+            Validity::AlwaysValid,
+            Validity::StructurallyValidButSemanticallyWrong,
+            Validity::Garbage,
+        ])?)
+    }
+}
+
+/// The bytes of a request as sent on the wire, of the `validity` it was
+/// generated for. Never longer than MAX_MESSAGE, how an oversized message is
+/// split up is up to the transport rather than the service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WireMessage {
+    pub validity: Validity,
+    pub bytes: Vec<u8>,
+}
+
+impl WireMessage {
+    pub fn arbitrary_of(u: &mut Unstructured, validity: Validity) -> Result<WireMessage> {
+        let bytes = match validity {
+            Validity::AlwaysValid => valid(u)?,
+            Validity::StructurallyValidButSemanticallyWrong => wrong(u)?,
+            Validity::Garbage => garbage(u)?,
+        };
+        Ok(WireMessage { validity, bytes })
+    }
+}
+
+impl<'a> Arbitrary<'a> for WireMessage {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<WireMessage> {
+        let validity = u.arbitrary()?;
+        WireMessage::arbitrary_of(u, validity)
+    }
+}
+
+/// 1 to MAX_PAYLOAD bytes of `alphabet`, mostly short ones, in runs so that
+/// there is something to compress
+fn payload(u: &mut Unstructured, alphabet: RangeInclusive<u8>) -> Result<Vec<u8>> {
+    let len = match u.int_in_range(0..=3)? {
+        0 => u.int_in_range(1..=3)?,
+        1 | 2 => u.int_in_range(1..=64)?,
+        _ => u.int_in_range(1..=MAX_PAYLOAD as usize)?,
+    };
+    let mut payload = Vec::with_capacity(len);
+    while payload.len() < len {
+        let byte = u.int_in_range(alphabet.clone())?;
+        let run = u.int_in_range(1..=16)?.min(len - payload.len());
+        payload.resize(payload.len() + run, byte);
+    }
+    Ok(payload)
+}
+
+fn valid(u: &mut Unstructured) -> Result<Vec<u8>> {
+    let request: Request = u.arbitrary()?;
+    let payload = match request {
+        // what validate checks of a CompressBinary is up to the caller, the
+        // default policy's lowercase passes them all
+        Request::Compress
+        | Request::Decompress
+        | Request::CompressBinary
+        | Request::CompressWithStats => payload(u, b'a'..=b'd')?,
+        Request::DecompressBinary => payload(u, 0..=255)?,
+        Request::Authenticate | Request::FlushStats => payload(u, b'a'..=b'z')?,
+        Request::ResetStats if u.arbitrary()? => vec![ResetScope::Connection as u8],
+        _ => Vec::new(),
+    };
+    Ok(message(request as u16, &payload))
+}
+
+fn wrong(u: &mut Unstructured) -> Result<Vec<u8>> {
+    let bytes = match u.int_in_range(0..=6)? {
+        // a payload where none is allowed
+        0 => {
+            let request = u.choose(&requests_of(PayloadPolicy::MustBeZero))?.clone();
+            let payload = payload(u, b'a'..=b'z')?;
+            message(request as u16, &payload[..payload.len().min(8)])
+        }
+        // a compression request without a payload
+        1 => {
+            let request = u
+                .choose(&requests_of(PayloadPolicy::MustBeNonZero))?
+                .clone();
+            message(request as u16, b"")
+        }
+        // a character the default policy rejects
+        2 => {
+            let mut payload = payload(u, b'a'..=b'd')?;
+            let i = u.choose_index(payload.len())?;
+            payload[i] = *u.choose(b"AZ09 \xFF")?;
+            message(Request::Compress as u16, &payload)
+        }
+        // a code past the last request
+        3 => {
+            let code = u.int_in_range(requests().len() as u16 + 1..=CODE_MASK)?;
+            message(code, b"")
+        }
+        // flags the service doesn't support
+        4 => {
+            let mut bytes = valid(u)?;
+            let flags = u.int_in_range(1..=255u8)? & !Flag::SUPPORTED;
+            bytes[6] |= flags.max(1);
+            bytes
+        }
+        // a scope that isn't one
+        5 => message(
+            Request::ResetStats as u16,
+            &[u.int_in_range(ResetScope::Tenant as u8 + 1..=255)?],
+        ),
+        // counts without the character they repeat
+        _ => message(Request::Decompress as u16, &payload(u, b'0'..=b'9')?),
+    };
+    Ok(bytes)
+}
+
+fn garbage(u: &mut Unstructured) -> Result<Vec<u8>> {
+    let bytes = match u.int_in_range(0..=3)? {
+        // anything at all
+        0 => {
+            let len = u.int_in_range(0..=64)?.min(u.len());
+            u.bytes(len)?.to_vec()
+        }
+        // cut short of a header
+        1 => {
+            let mut bytes = valid(u)?;
+            bytes.truncate(u.int_in_range(0..=HEADER_SIZE - 1)?);
+            bytes
+        }
+        // a size that doesn't match the payload
+        2 => {
+            let mut bytes = valid(u)?;
+            let size: u16 = u.arbitrary()?;
+            if (size as usize) == bytes.len() - HEADER_SIZE {
+                bytes.push(b'a');
+            }
+            bytes[4..6].copy_from_slice(&size.to_be_bytes());
+            bytes
+        }
+        // anything under any sign and code, of the size it declares
+        _ => {
+            let header: Header = u.arbitrary()?;
+            let len = u.int_in_range(0..=64)?.min(u.len());
+            let header = Header::raw(header.sign(), len as u16, header.code());
+            [header.as_bytes(), u.bytes(len)?].concat()
+        }
+    };
+    Ok(bytes[..bytes.len().min(MAX_MESSAGE)].to_vec())
+}
+
+/// Whatever `T::arbitrary` builds out of random bytes, shrinking the bytes
+pub fn arbitrary_strategy<T>() -> impl Strategy<Value = T>
+where
+    T: for<'a> Arbitrary<'a> + fmt::Debug,
+{
+    collection::vec(any::<u8>(), 0..1024).prop_filter_map("the bytes ran out", |bytes| {
+        T::arbitrary(&mut Unstructured::new(&bytes)).ok()
+    })
+}
+
+pub fn request_strategy() -> impl Strategy<Value = Request> {
+    arbitrary_strategy()
+}
+
+pub fn response_strategy() -> impl Strategy<Value = Response> {
+    arbitrary_strategy()
+}
+
+pub fn header_strategy() -> impl Strategy<Value = Header> {
+    arbitrary_strategy()
+}
+
+pub fn stats_strategy() -> impl Strategy<Value = Stats> {
+    arbitrary_strategy()
+}
+
+/// The bytes of `WireMessage`s of `validity`
+pub fn wire_message_strategy(validity: Validity) -> impl Strategy<Value = Vec<u8>> {
+    collection::vec(any::<u8>(), 0..1024).prop_filter_map("the bytes ran out", move |bytes| {
+        let message = WireMessage::arbitrary_of(&mut Unstructured::new(&bytes), validity);
+        message.ok().map(|message| message.bytes)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Message;
+    use std::collections::BTreeMap;
+
+    /// `len` bytes of a xorshift seeded with `seed`
+    fn entropy(seed: u64, len: usize) -> Vec<u8> {
+        let mut x = seed | 1;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    /// The `validate` of `count` messages of `validity`, counted by response
+    fn validated(validity: Validity, count: u64) -> BTreeMap<u16, usize> {
+        let mut responses = BTreeMap::new();
+        for seed in 0..count {
+            let bytes = entropy(seed, 512);
+            let message = WireMessage::arbitrary_of(&mut Unstructured::new(&bytes), validity);
+            let bytes = message.unwrap().bytes;
+            assert!(bytes.len() <= MAX_MESSAGE);
+            let response = match Message::parse(&bytes[..]) {
+                Some(message) => message.validate(bytes.len()),
+                None => Response::MessageTooSmall,
+            };
+            *responses.entry(response as u16).or_default() += 1;
+        }
+        responses
+    }
+
+    #[test]
+    fn test_enumerations() {
+        #[cfg(feature = "std")]
+        assert_eq!(requests().len(), crate::REQUEST_KINDS);
+        assert_eq!(requests()[0], Request::Ping);
+        assert_eq!(responses()[0], Response::Ok);
+        assert!(responses().contains(&Response::NonCanonicalEncoding));
+    }
+
+    #[test]
+    fn test_garbage_spread() {
+        let responses = validated(Validity::Garbage, 2000);
+        for rejected in [
+            Response::MessageTooSmall,
+            Response::MessageHeaderHasBadMagic,
+            Response::MessageHeaderSizeMismatch,
+            Response::UnsupportedRequestType,
+        ] {
+            assert!(
+                responses.get(&(rejected as u16)) > Some(&10),
+                "{:?}: {:?}",
+                rejected,
+                responses
+            );
+        }
+        // and then some, mostly rejected
+        assert!(responses.len() >= 6, "{:?}", responses);
+        assert!(responses[&(Response::Ok as u16)] < 200, "{:?}", responses);
+    }
+
+    #[test]
+    fn test_semantically_wrong_spread() {
+        let responses = validated(Validity::StructurallyValidButSemanticallyWrong, 2000);
+        // never a framing error
+        for framing in [
+            Response::MessageTooSmall,
+            Response::MessageHeaderHasBadMagic,
+            Response::MessageHeaderSizeMismatch,
+        ] {
+            assert_eq!(responses.get(&(framing as u16)), None, "{:?}", responses);
+        }
+        for rejected in [
+            Response::RequestKindRequiresZeroLength,
+            Response::CompressionRequestRequiresNonZeroLength,
+            Response::UnsupportedRequestType,
+            Response::UnsupportedFlags,
+        ] {
+            assert!(responses.contains_key(&(rejected as u16)), "{:?}", rejected);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_always_valid(bytes in wire_message_strategy(Validity::AlwaysValid)) {
+            let message = Message::parse(&bytes[..]).unwrap();
+            prop_assert_eq!(message.validate(bytes.len()), Response::Ok);
+        }
+
+        #[test]
+        fn prop_stats_ratio(stats in stats_strategy()) {
+            prop_assert!(stats.ratio() <= 100);
+        }
+
+        #[test]
+        fn prop_request_codes(request in request_strategy(), response in response_strategy()) {
+            prop_assert_eq!(Request::from_u16(request.clone() as u16), Some(request));
+            prop_assert_eq!(Response::from_u16(response as u16), Some(response));
+        }
+
+        #[test]
+        fn prop_header_round_trip(header in header_strategy()) {
+            let parsed = Message::parse(header.as_bytes()).unwrap();
+            prop_assert_eq!(&*parsed.header, &header);
+        }
+    }
+}
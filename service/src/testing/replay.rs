@@ -0,0 +1,471 @@
+//! Scripted connections, for regression tests of what the service does with
+//! a sequence of writes rather than with one request
+//!
+//! A `Script` is a timeline of client actions and expectations, replayed by
+//! `Script::run` against `Server::process` over a duplex stream. Run it on a
+//! paused runtime (`#[tokio::test(start_paused = true)]`) so that sleeps and
+//! timeouts take no time and the replay is the same on every run
+//!
+//! # Example
+//! ```
+//! use service::message::{Header, Request, Response};
+//! use service::testing::replay::Script;
+//! use zerocopy::AsBytes;
+//!
+//! # tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+//! let compress = [Header::request(Request::Compress, 3).unwrap().as_bytes(), b"aaa"].concat();
+//! Script::new()
+//!     .write(compress)
+//!     .expect_response([Header::response(Response::Ok, 2).unwrap().as_bytes(), b"3a"].concat())
+//!     .write(Header::request(Request::Compress, 0).unwrap().as_bytes())
+//!     .expect_code(Response::CompressionRequestRequiresNonZeroLength)
+//!     .run()
+//!     .await
+//!     .unwrap();
+//! # });
+//! ```
+use crate::message::{hexdump, Message, Response, HEADER_SIZE, MAX_MESSAGE_PADDED};
+use crate::{Server, ServerConfig, State};
+use std::{error::Error, fmt, io, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    sync::Mutex,
+    time,
+};
+
+/// How long an expectation waits for the service before it fails, only ever
+/// elapsed on a paused runtime
+pub const EXPECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time left between the pieces of a `Script::write_partial`, for the
+/// service to read each before the next is written
+pub const PIECE_GAP: Duration = Duration::from_millis(1);
+
+/// Rows of the hex dumps of a failure
+const DIFF_ROWS: usize = 64;
+
+/// A step of a `Script`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    Write(Vec<u8>),
+    /// The bytes written in pieces, split at the offsets
+    WritePartial(Vec<u8>, Vec<usize>),
+    Sleep(Duration),
+    /// A whole response, header and payload, byte for byte
+    ExpectResponse(Vec<u8>),
+    /// A whole response with this code, whatever its payload
+    ExpectCode(Response),
+    /// The end of the stream, the service closed the connection
+    ExpectClose,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Step::Write(bytes) => write!(fmt, "write {} bytes", bytes.len()),
+            Step::WritePartial(bytes, offsets) => {
+                write!(fmt, "write {} bytes split at {:?}", bytes.len(), offsets)
+            }
+            Step::Sleep(duration) => write!(fmt, "sleep {:?}", duration),
+            Step::ExpectResponse(bytes) => write!(fmt, "expect a {} byte response", bytes.len()),
+            Step::ExpectCode(code) => write!(fmt, "expect a response of {:?}", code),
+            Step::ExpectClose => write!(fmt, "expect the connection to close"),
+        }
+    }
+}
+
+/// A timeline of client actions and expectations on one connection
+#[derive(Debug, Clone, Default)]
+pub struct Script {
+    steps: Vec<Step>,
+    config: ServerConfig,
+}
+
+impl Script {
+    /// A script against the default configuration
+    pub fn new() -> Script {
+        Default::default()
+    }
+
+    /// A script against a service configured with `config`
+    pub fn new_with(config: ServerConfig) -> Script {
+        Script {
+            steps: Vec::new(),
+            config,
+        }
+    }
+
+    pub fn write(mut self, bytes: impl Into<Vec<u8>>) -> Script {
+        self.steps.push(Step::Write(bytes.into()));
+        self
+    }
+
+    /// Writes `bytes` in pieces split at `at_offsets`, leaving `PIECE_GAP`
+    /// in between so that the service reads the pieces apart
+    pub fn write_partial(mut self, bytes: impl Into<Vec<u8>>, at_offsets: &[usize]) -> Script {
+        self.steps
+            .push(Step::WritePartial(bytes.into(), at_offsets.to_vec()));
+        self
+    }
+
+    pub fn sleep(mut self, duration: Duration) -> Script {
+        self.steps.push(Step::Sleep(duration));
+        self
+    }
+
+    pub fn expect_response(mut self, bytes: impl Into<Vec<u8>>) -> Script {
+        self.steps.push(Step::ExpectResponse(bytes.into()));
+        self
+    }
+
+    pub fn expect_code(mut self, code: Response) -> Script {
+        self.steps.push(Step::ExpectCode(code));
+        self
+    }
+
+    pub fn expect_close(mut self) -> Script {
+        self.steps.push(Step::ExpectClose);
+        self
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// Replays the script on a new connection, stopping at the first
+    /// expectation that isn't met. Once every step passed, the client closes
+    /// its end and the service is waited for
+    pub async fn run(self) -> Result<Replay, ReplayError> {
+        let (mut client, server) = tokio::io::duplex(MAX_MESSAGE_PADDED * 4);
+        let state = Arc::new(Mutex::new(State::new()));
+        let serving = tokio::spawn(Server::process(
+            server,
+            Arc::clone(&state),
+            Arc::new(self.config),
+        ));
+        let mut transcript = Vec::new();
+        for (index, step) in self.steps.iter().enumerate() {
+            let failed = |problem| ReplayError {
+                index,
+                step: step.clone(),
+                problem,
+                transcript: transcript.clone(),
+            };
+            match play(&mut client, step).await {
+                Ok(Some(received)) => transcript.push(received),
+                Ok(None) => (),
+                Err(problem) => return Err(failed(problem)),
+            }
+        }
+        drop(client);
+        let served = serving.await.unwrap_or_else(|e| Err(e.into()));
+        let state = state.lock().await.clone();
+        Ok(Replay {
+            served,
+            state,
+            transcript,
+        })
+    }
+}
+
+/// Plays `step`, returning the response it received if any
+async fn play(client: &mut DuplexStream, step: &Step) -> Result<Option<Vec<u8>>, Problem> {
+    match step {
+        Step::Write(bytes) => client.write_all(bytes).await.map_err(Problem::Io)?,
+        Step::WritePartial(bytes, offsets) => {
+            let mut start = 0;
+            for end in offsets.iter().copied().chain([bytes.len()]) {
+                let end = end.clamp(start, bytes.len());
+                client
+                    .write_all(&bytes[start..end])
+                    .await
+                    .map_err(Problem::Io)?;
+                time::sleep(PIECE_GAP).await;
+                start = end;
+            }
+        }
+        Step::Sleep(duration) => time::sleep(*duration).await,
+        Step::ExpectResponse(expected) => {
+            let response = read_response(client).await?;
+            if response != *expected {
+                return Err(Problem::Mismatch {
+                    expected: expected.clone(),
+                    actual: response,
+                });
+            }
+            return Ok(Some(response));
+        }
+        Step::ExpectCode(code) => {
+            let response = read_response(client).await?;
+            let actual = Message::parse(&response[..]).unwrap().header.code();
+            if actual != *code as u16 {
+                return Err(Problem::WrongCode {
+                    expected: *code,
+                    actual: response,
+                });
+            }
+            return Ok(Some(response));
+        }
+        Step::ExpectClose => {
+            let mut rest = Vec::new();
+            let read = time::timeout(EXPECT_TIMEOUT, client.read_to_end(&mut rest)).await;
+            match read {
+                Err(_) => return Err(Problem::Timeout(rest)),
+                Ok(Err(e)) => return Err(Problem::Io(e)),
+                Ok(Ok(0)) => (),
+                Ok(Ok(_)) => return Err(Problem::Unexpected(rest)),
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Reads a whole response, by the size its header declares
+async fn read_response(client: &mut DuplexStream) -> Result<Vec<u8>, Problem> {
+    let mut response = vec![0u8; HEADER_SIZE];
+    let mut len = 0;
+    let read = time::timeout(EXPECT_TIMEOUT, async {
+        while len < response.len() {
+            match client.read(&mut response[len..]).await? {
+                0 => return Ok(false),
+                n => len += n,
+            }
+            if len == HEADER_SIZE {
+                let size = Message::parse(&response[..]).unwrap().header.size();
+                response.resize(HEADER_SIZE + size as usize, 0);
+            }
+        }
+        Ok(true)
+    })
+    .await;
+    response.truncate(len);
+    match read {
+        Err(_) => Err(Problem::Timeout(response)),
+        Ok(Err(e)) => Err(Problem::Io(e)),
+        Ok(Ok(false)) => Err(Problem::Closed(response)),
+        Ok(Ok(true)) => Ok(response),
+    }
+}
+
+/// What the service did once every step of a `Script` passed
+#[derive(Debug)]
+pub struct Replay {
+    /// What `Server::process` returned
+    pub served: io::Result<()>,
+    /// The state the connection accounted for its requests in
+    pub state: State,
+    /// The responses the expectations received, in order
+    pub transcript: Vec<Vec<u8>>,
+}
+
+/// Why a step failed
+#[derive(Debug)]
+pub enum Problem {
+    Io(io::Error),
+    /// Not the response expected
+    Mismatch {
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+    /// A response with another code
+    WrongCode {
+        expected: Response,
+        actual: Vec<u8>,
+    },
+    /// Nothing more within `EXPECT_TIMEOUT`, along with what was read
+    Timeout(Vec<u8>),
+    /// The connection closed partway through a response
+    Closed(Vec<u8>),
+    /// Bytes where the end of the stream was expected
+    Unexpected(Vec<u8>),
+}
+
+/// The first step of a `Script` that failed, its `Display` is the report of
+/// a failed test: the step, what was expected and what was received
+#[derive(Debug)]
+pub struct ReplayError {
+    /// Position of the step in the script, from 0
+    pub index: usize,
+    pub step: Step,
+    pub problem: Problem,
+    /// The responses received by the steps before it
+    pub transcript: Vec<Vec<u8>>,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(fmt, "step {} ({}) failed", self.index, self.step)?;
+        match &self.problem {
+            Problem::Io(e) => writeln!(fmt, "i/o error: {}", e),
+            Problem::Mismatch { expected, actual } => {
+                writeln!(fmt, "response differs (- expected, + actual):")?;
+                write!(fmt, "{}", diff(expected, actual))
+            }
+            Problem::WrongCode { expected, actual } => {
+                let code = Message::parse(&actual[..]).unwrap().header.code();
+                let name = Response::from_u16(code).map(|response| format!("{:?}", response));
+                writeln!(
+                    fmt,
+                    "expected {:?} ({}), got {} ({}):",
+                    expected,
+                    *expected as u16,
+                    name.unwrap_or_else(|| "unknown".to_string()),
+                    code
+                )?;
+                write!(fmt, "{}", hexdump(actual, DIFF_ROWS))
+            }
+            Problem::Timeout(read) => {
+                writeln!(fmt, "nothing more within {:?}", EXPECT_TIMEOUT)?;
+                dump_read(fmt, read)
+            }
+            Problem::Closed(read) => {
+                writeln!(fmt, "the service closed the connection")?;
+                dump_read(fmt, read)
+            }
+            Problem::Unexpected(read) => {
+                writeln!(fmt, "{} bytes before the connection closed:", read.len())?;
+                write!(fmt, "{}", hexdump(read, DIFF_ROWS))
+            }
+        }
+    }
+}
+
+impl Error for ReplayError {}
+
+fn dump_read(fmt: &mut fmt::Formatter, read: &[u8]) -> fmt::Result {
+    match read.is_empty() {
+        true => Ok(()),
+        false => write!(
+            fmt,
+            "after {} bytes:\n{}",
+            read.len(),
+            hexdump(read, DIFF_ROWS)
+        ),
+    }
+}
+
+/// The rows of the hex dumps of `expected` and `actual` that differ, as a
+/// unified diff
+fn diff(expected: &[u8], actual: &[u8]) -> String {
+    let expected = hexdump(expected, DIFF_ROWS);
+    let actual = hexdump(actual, DIFF_ROWS);
+    let (expected, actual): (Vec<_>, Vec<_>) =
+        (expected.lines().collect(), actual.lines().collect());
+    let mut out = String::new();
+    for row in 0..expected.len().max(actual.len()) {
+        match (expected.get(row), actual.get(row)) {
+            (Some(e), Some(a)) if e == a => out.push_str(&format!("  {}\n", e)),
+            (e, a) => {
+                if let Some(e) = e {
+                    out.push_str(&format!("- {}\n", e));
+                }
+                if let Some(a) = a {
+                    out.push_str(&format!("+ {}\n", a));
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Request;
+    use crate::testing::wire::{ping, response};
+
+    fn ok() -> Vec<u8> {
+        response(Response::Ok, b"")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_passing_script() {
+        let replay = Script::new()
+            .write(ping())
+            .expect_response(ok())
+            .sleep(Duration::from_secs(60))
+            .write(ping())
+            .expect_code(Response::Ok)
+            .run()
+            .await
+            .unwrap();
+        assert!(replay.served.is_ok());
+        assert_eq!(replay.transcript, [ok(), ok()]);
+        assert_eq!(replay.state.requests(&Request::Ping), 2);
+
+        // each piece is read, and answered, apart
+        let replay = Script::new()
+            .write_partial(ping(), &[3])
+            .expect_code(Response::MessageTooSmall)
+            .expect_code(Response::MessageTooSmall)
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(replay.state.requests(&Request::Ping), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failures_are_reported() {
+        // the wrong bytes, the differing row is shown
+        let error = Script::new()
+            .write(ping())
+            .expect_response(b"STRY\0\0\0\x01".to_vec())
+            .run()
+            .await
+            .unwrap_err();
+        assert_eq!(error.index, 1);
+        let report = error.to_string();
+        assert!(report.starts_with("step 1 (expect a 8 byte response) failed\n"));
+        assert!(
+            report.contains("- 00000000  53 54 52 59 00 00 00 01"),
+            "{}",
+            report
+        );
+        assert!(
+            report.contains("+ 00000000  53 54 52 59 00 00 00 00"),
+            "{}",
+            report
+        );
+
+        // the wrong code, named
+        let error = Script::new()
+            .write(ping())
+            .expect_code(Response::MessageTooLarge)
+            .run()
+            .await
+            .unwrap_err();
+        let report = error.to_string();
+        assert!(
+            report.contains("expected MessageTooLarge (2), got Ok (0)"),
+            "{}",
+            report
+        );
+
+        // nothing comes, the wait is bounded
+        let started = time::Instant::now();
+        let error = Script::new()
+            .sleep(Duration::from_secs(1))
+            .expect_code(Response::Ok)
+            .run()
+            .await
+            .unwrap_err();
+        assert!(matches!(error.problem, Problem::Timeout(ref read) if read.is_empty()));
+        assert_eq!(started.elapsed(), Duration::from_secs(1) + EXPECT_TIMEOUT);
+        assert!(error.to_string().contains("nothing more within 5s"));
+
+        // a response where the close was expected, the connection stays open
+        let error = Script::new()
+            .write(ping())
+            .expect_close()
+            .run()
+            .await
+            .unwrap_err();
+        assert!(matches!(error.problem, Problem::Timeout(ref read) if *read == ok()));
+        let report = error.to_string();
+        assert!(
+            report.contains("after 8 bytes:\n00000000  53 54 52 59"),
+            "{}",
+            report
+        );
+    }
+}
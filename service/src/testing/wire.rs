@@ -0,0 +1,89 @@
+//! Builders of wire messages, shared by the unit tests and the integration
+//! tests (through the `testing` feature the crate's dev-dependency on itself
+//! enables) rather than copied into each of them
+
+use crate::message::{
+    GoodbyeReason, Header, Request, Response, MAGIC, MAX_MESSAGE, MAX_PAYLOAD, PROTOCOL_VERSION,
+};
+use crate::server::limits::Feature;
+use zerocopy::AsBytes;
+
+/// A message of `payload` under a header with an explicit `sign` and `size`
+pub fn raw(sign: u32, size: u16, code: u16, payload: &[u8]) -> Vec<u8> {
+    [Header::raw(sign, size, code).as_bytes(), payload].concat()
+}
+
+/// A message of `payload` under the magic and its size, `code` may carry flags
+/// or be unknown
+pub fn message(code: u16, payload: &[u8]) -> Vec<u8> {
+    raw(MAGIC, payload.len() as u16, code, payload)
+}
+
+pub fn request(code: Request, payload: &[u8]) -> Vec<u8> {
+    message(code as u16, payload)
+}
+
+pub fn response(code: Response, payload: &[u8]) -> Vec<u8> {
+    message(code as u16, payload)
+}
+
+pub fn ping() -> Vec<u8> {
+    request(Request::Ping, b"")
+}
+
+/// The GetStats payload: u32 read, u32 sent (network order), u8 ratio
+pub fn stats(read: u32, sent: u32, ratio: u8) -> Vec<u8> {
+    let mut bytes = read.to_be_bytes().to_vec();
+    bytes.extend_from_slice(&sent.to_be_bytes());
+    bytes.push(ratio);
+    bytes
+}
+
+/// The GetStatsV2 payload: u8 layout version 2, u64 read, u64 sent (network
+/// order), u8 ratio
+pub fn stats_v2(read: u64, sent: u64, ratio: u8) -> Vec<u8> {
+    let mut bytes = vec![2];
+    bytes.extend_from_slice(&read.to_be_bytes());
+    bytes.extend_from_slice(&sent.to_be_bytes());
+    bytes.push(ratio);
+    bytes
+}
+
+/// The GetConfig payload: u8 version, u8 features, u16 max payload, u16 max
+/// message, u32 idle timeout seconds, u32 rate limit (network order)
+pub fn limits(
+    version: u8,
+    features: u8,
+    max_payload: u16,
+    max_message: u16,
+    idle_timeout: u32,
+    rate_limit: u32,
+) -> Vec<u8> {
+    let mut bytes = vec![version, features];
+    bytes.extend_from_slice(&max_payload.to_be_bytes());
+    bytes.extend_from_slice(&max_message.to_be_bytes());
+    bytes.extend_from_slice(&idle_timeout.to_be_bytes());
+    bytes.extend_from_slice(&rate_limit.to_be_bytes());
+    bytes
+}
+
+/// The GetConfig payload of a service with the default `ServerConfig`
+pub fn default_limits() -> Vec<u8> {
+    limits(
+        PROTOCOL_VERSION,
+        Feature::DECOMPRESS | Feature::BATCH | Feature::SEQUENCE | Feature::IDEMPOTENT_RESET,
+        MAX_PAYLOAD,
+        MAX_MESSAGE as u16,
+        0,
+        0,
+    )
+}
+
+/// A whole Goodbye message: the connection's `stats` payload, u32 requests
+/// (network order), u8 reason
+pub fn goodbye(stats: Vec<u8>, requests: u32, reason: GoodbyeReason) -> Vec<u8> {
+    let mut payload = stats;
+    payload.extend_from_slice(&requests.to_be_bytes());
+    payload.push(reason as u8);
+    response(Response::Goodbye, &payload)
+}
@@ -0,0 +1,57 @@
+//! Sessions against an `AsyncStdServer`, driven entirely by async-std
+
+#![cfg(feature = "async-std")]
+
+use async_std::{net::TcpStream, prelude::*, task};
+use service::message::{Request, Response, HEADER_SIZE};
+use service::testing::wire::{request, response, stats};
+use service::{AsyncStdServer, ServerConfig};
+use std::time::Duration;
+
+/// Sends `request` and reads back one whole response
+async fn send(stream: &mut TcpStream, request: &[u8]) -> Vec<u8> {
+    stream.write_all(request).await.unwrap();
+    let mut response = vec![0u8; HEADER_SIZE];
+    stream.read_exact(&mut response).await.unwrap();
+    let size = u16::from_be_bytes([response[4], response[5]]) as usize;
+    response.resize(HEADER_SIZE + size, 0);
+    stream
+        .read_exact(&mut response[HEADER_SIZE..])
+        .await
+        .unwrap();
+    response
+}
+
+#[test]
+fn test_async_std_session() {
+    task::block_on(async {
+        // the idle timeout needs the tokio timers to be running
+        let config = ServerConfig {
+            idle_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        let mut server = AsyncStdServer::new_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap();
+        let addr = server.listener.local_addr().unwrap();
+        task::spawn(async move { server.serve().await });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(
+            send(&mut stream, &request(Request::Ping, b"")).await,
+            response(Response::Ok, b"")
+        );
+        assert_eq!(
+            send(&mut stream, &request(Request::Compress, b"aaaaabbb")).await,
+            response(Response::Ok, b"5a3b")
+        );
+        assert_eq!(
+            send(&mut stream, &request(Request::Compress, b"aB3")).await,
+            response(Response::MessagePayloadContainsInvalidCharacters, b"")
+        );
+        assert_eq!(
+            send(&mut stream, &request(Request::GetStats, b"")).await,
+            response(Response::Ok, &stats(43, 28, 50))
+        );
+    });
+}
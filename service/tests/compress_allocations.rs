@@ -0,0 +1,44 @@
+//! Compression must not allocate, counted with a global allocator wrapper
+//! (in its own test binary so other tests don't disturb the count)
+
+use service::{compress_message, MAX_PAYLOAD};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn test_compress_does_not_allocate() {
+    // worst case for the number of encoded runs, alternating runs of 3
+    let mut rx = [0u8; MAX_PAYLOAD as usize];
+    rx.chunks_mut(3)
+        .enumerate()
+        .for_each(|(i, run)| run.fill(b'a' + (i % 2) as u8));
+    let mut tx = [0u8; MAX_PAYLOAD as usize];
+
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    let len = compress_message(&rx, &mut tx).unwrap();
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst) - before;
+
+    assert_eq!(&tx[..4], b"3a3b");
+    assert_eq!(len, (MAX_PAYLOAD as usize / 3) * 2 + 2);
+    assert_eq!(allocations, 0);
+}
@@ -0,0 +1,253 @@
+//! Sessions against the debug port of a running `Server`, over plain sockets
+//! like `nc` would use
+
+#![cfg(feature = "server")]
+
+use service::message::{Request, Response, HEADER_SIZE, MAX_PAYLOAD};
+use service::testing::wire::{request, response, stats};
+use service::{Server, ServerConfig};
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time,
+};
+
+/// Starts a server with a debug port, returning the addresses of both
+async fn start() -> (SocketAddr, SocketAddr) {
+    start_with(Default::default()).await
+}
+
+/// `start` with `config`, but for its debug port
+async fn start_with(config: ServerConfig) -> (SocketAddr, SocketAddr) {
+    let config = ServerConfig {
+        debug_addr: Some("127.0.0.1:0".to_string()),
+        ..config
+    };
+    let server = Server::new_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let debug_addr = server
+        .debug_listener
+        .as_ref()
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    (server.spawn().local_addr(), debug_addr)
+}
+
+/// A debug port client, keeping count of the bytes it sent and received
+struct Client {
+    stream: BufReader<TcpStream>,
+    sent: usize,
+    received: usize,
+}
+
+impl Client {
+    async fn connect(addr: SocketAddr) -> Client {
+        let stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        Client {
+            stream,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    /// Sends `line` and reads back the line answering it, without its "\n"
+    async fn send(&mut self, line: &[u8]) -> String {
+        self.stream.get_mut().write_all(line).await.unwrap();
+        self.sent += line.len();
+        let mut reply = String::new();
+        self.received += self.stream.read_line(&mut reply).await.unwrap();
+        assert_eq!(reply.pop(), Some('\n'));
+        reply
+    }
+
+    /// Sends METRICS and reads back the samples up to its "# EOF", by name
+    async fn scrape(&mut self) -> Vec<(String, u64)> {
+        self.stream.get_mut().write_all(b"METRICS\n").await.unwrap();
+        let mut samples = Vec::new();
+        loop {
+            let mut line = String::new();
+            self.stream.read_line(&mut line).await.unwrap();
+            match line.trim_end() {
+                "# EOF" => return samples,
+                comment if comment.starts_with('#') => (),
+                sample => {
+                    let (name, value) = sample.split_once(' ').unwrap();
+                    samples.push((name.to_string(), value.parse().unwrap()));
+                }
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_debug_port() {
+    let (addr, debug_addr) = start().await;
+    let mut client = Client::connect(debug_addr).await;
+
+    assert_eq!(client.send(b"PING\n").await, "OK");
+    assert_eq!(client.send(b"ping\r\n").await, "OK");
+    assert_eq!(client.send(b"COMPRESS aaaaabbb\n").await, "OK 5a3b");
+    assert_eq!(
+        client.send(b"COMPRESS aB3\n").await,
+        "ERR invalid-characters"
+    );
+    assert_eq!(client.send(b"COMPRESS 123\n").await, "ERR digits");
+    assert_eq!(client.send(b"COMPRESS\n").await, "ERR empty-payload");
+    assert_eq!(client.send(b"HELLO\n").await, "ERR unknown-command");
+    assert_eq!(client.send(b"STATS now\n").await, "ERR unexpected-argument");
+    assert_eq!(
+        client.send("COMPRESS \u{e9}\n".as_bytes()).await,
+        "ERR non-ascii"
+    );
+
+    // the whole of a long line is skipped, the next one is answered as usual
+    let mut long = b"COMPRESS ".to_vec();
+    long.extend(vec![b'a'; 2 * MAX_PAYLOAD as usize]);
+    long.push(b'\n');
+    assert_eq!(client.send(&long).await, "ERR line-too-long");
+    assert_eq!(client.send(b"PING\n").await, "OK");
+
+    let (read, sent) = (client.sent + 6, client.received);
+    assert_eq!(
+        client.send(b"STATS\n").await,
+        format!("OK read={} sent={} ratio=50", read, sent)
+    );
+
+    // the binary port reports the same stats, the text traffic included
+    let mut binary = TcpStream::connect(addr).await.unwrap();
+    binary
+        .write_all(&request(Request::GetStats, b""))
+        .await
+        .unwrap();
+    let mut reply = vec![0u8; HEADER_SIZE + 9];
+    binary.read_exact(&mut reply).await.unwrap();
+    let expected = stats(
+        (client.sent + HEADER_SIZE) as u32,
+        client.received as u32,
+        50,
+    );
+    assert_eq!(reply, response(Response::Ok, &expected));
+
+    // RESET only resets the stats of its own connection by default
+    assert_eq!(client.send(b"RESET\n").await, "OK");
+    let reply = client.send(b"STATS\n").await;
+    assert!(reply.starts_with("OK read="), "{}", reply);
+    assert!(!reply.ends_with("ratio=0"), "{}", reply);
+}
+
+#[tokio::test]
+async fn test_recent() {
+    let config = ServerConfig {
+        recent_requests: 2,
+        capture_payload_prefix: true,
+        ..Default::default()
+    };
+    let (addr, debug_addr) = start_with(config).await;
+    let mut client = Client::connect(debug_addr).await;
+    assert_eq!(client.send(b"RECENT\n").await, "OK []");
+
+    let mut binary = TcpStream::connect(addr).await.unwrap();
+    let requests = [
+        (request(Request::Ping, b""), 8),
+        (request(Request::Compress, b"aaaaabbb"), 12),
+        (request(Request::Compress, b"aB"), 8),
+    ];
+    for (request, len) in requests {
+        binary.write_all(&request).await.unwrap();
+        let mut reply = vec![0u8; len];
+        binary.read_exact(&mut reply).await.unwrap();
+    }
+
+    // a request is recorded once its response is written, the client may
+    // see the response first
+    let recorded = async {
+        loop {
+            let reply = client.send(b"RECENT\n").await;
+            if reply.contains("\"payload_prefix\":\"6142\"") {
+                return reply;
+            }
+            tokio::task::yield_now().await;
+        }
+    };
+    let reply = time::timeout(Duration::from_secs(5), recorded)
+        .await
+        .unwrap();
+    // the Ping was evicted
+    let json = reply
+        .strip_prefix("OK [")
+        .unwrap()
+        .strip_suffix(']')
+        .unwrap();
+    let entries: Vec<&str> = json.split("},{").collect();
+    assert_eq!(entries.len(), 2, "{}", reply);
+    let peer = binary.local_addr().unwrap();
+    for entry in &entries {
+        assert!(entry.contains("\"connection\":1,"), "{}", entry);
+        assert!(
+            entry.contains(&format!("\"peer\":\"{}\"", peer)),
+            "{}",
+            entry
+        );
+        assert!(entry.contains("\"code\":4,"), "{}", entry);
+    }
+    assert!(entries[0].contains("\"payload_len\":8,\"response\":0,"));
+    assert!(entries[0].contains("\"payload_prefix\":\"6161616161626262\""));
+    let uppercase = Response::MessageContainsUppercaseCharacters as u16;
+    assert!(entries[1].contains(&format!("\"payload_len\":2,\"response\":{},", uppercase)));
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let config = ServerConfig {
+        allow_global_reset: true,
+        ..Default::default()
+    };
+    let (_, debug_addr) = start_with(config).await;
+    let mut client = Client::connect(debug_addr).await;
+    let value = |samples: &[(String, u64)], name: &str| {
+        let sample = samples.iter().find(|(n, _)| n == name);
+        sample.map(|(_, value)| *value).unwrap()
+    };
+    let names: Vec<String> = client.scrape().await.into_iter().map(|(n, _)| n).collect();
+    assert_eq!(
+        names,
+        [
+            "compress_input_bytes_total",
+            "compress_output_bytes_total",
+            "compress_ratio_lifetime",
+            "resets_total"
+        ]
+    );
+
+    assert_eq!(client.send(b"COMPRESS aaaaabbb\n").await, "OK 5a3b");
+    assert_eq!(client.send(b"COMPRESS abcd\n").await, "OK abcd");
+    let before = client.scrape().await;
+    assert_eq!(value(&before, "compress_input_bytes_total"), 12);
+    assert_eq!(value(&before, "compress_output_bytes_total"), 8);
+    assert_eq!(value(&before, "compress_ratio_lifetime"), 33);
+    assert_eq!(value(&before, "resets_total"), 0);
+
+    // a reset takes the ratio of STATS back to 0 but none of the totals
+    assert_eq!(client.send(b"RESET\n").await, "OK");
+    assert!(client.send(b"STATS\n").await.ends_with("ratio=0"));
+    assert_eq!(client.send(b"COMPRESS aaaa\n").await, "OK 4a");
+    let after = client.scrape().await;
+    for ((name, before), (_, after)) in before.iter().zip(&after) {
+        if name.ends_with("_total") {
+            assert!(
+                after >= before,
+                "{} went from {} to {}",
+                name,
+                before,
+                after
+            );
+        }
+    }
+    assert_eq!(value(&after, "compress_input_bytes_total"), 16);
+    assert_eq!(value(&after, "compress_output_bytes_total"), 10);
+    assert_eq!(value(&after, "compress_ratio_lifetime"), 38);
+    assert_eq!(value(&after, "resets_total"), 1);
+}
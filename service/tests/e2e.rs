@@ -0,0 +1,1252 @@
+//! End-to-end sessions against `Server::process` over in-memory duplex pipes,
+//! no sockets involved
+//!
+//! The tests of `shared_tests!` also run against the blocking server's
+//! `process` (with the `blocking` feature) so the two behave identically
+
+#![cfg(feature = "server")]
+
+use service::message::{
+    with_sequence, GoodbyeReason, Request, ResetScope, Response, HEADER_SIZE, MAGIC, MAX_MESSAGE,
+    MAX_PAYLOAD, PROTOCOL_VERSION,
+};
+use service::testing::wire::{goodbye, limits, raw, request, response, stats};
+use service::{
+    Enforcement, ServedRequests, Server, ServerConfig, State, Stats, ANONYMOUS_TENANT, STATS_LEN,
+};
+use std::{
+    cmp, io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf},
+    sync::Mutex,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+/// Enough room to queue several maximum sized messages
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// A server implementation the shared tests run against
+#[derive(Debug, Clone, Copy)]
+enum Backend {
+    Tokio,
+    #[cfg(all(feature = "blocking", unix))]
+    Blocking,
+}
+
+/// The state of the service, shared by the sessions of one backend
+#[derive(Clone)]
+enum Shared {
+    Tokio(Arc<Mutex<State>>),
+    #[cfg(all(feature = "blocking", unix))]
+    Blocking(Arc<std::sync::Mutex<State>>),
+}
+
+impl Shared {
+    fn new(backend: Backend) -> Shared {
+        match backend {
+            Backend::Tokio => Shared::Tokio(Arc::new(Mutex::new(State::new()))),
+            #[cfg(all(feature = "blocking", unix))]
+            Backend::Blocking => Shared::Blocking(Arc::new(std::sync::Mutex::new(State::new()))),
+        }
+    }
+
+    /// A copy of the state as it is now
+    async fn get(&self) -> State {
+        match self {
+            Shared::Tokio(state) => state.lock().await.clone(),
+            #[cfg(all(feature = "blocking", unix))]
+            Shared::Blocking(state) => state.lock().unwrap().clone(),
+        }
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+struct Session {
+    client: Box<dyn Stream>,
+    server: JoinHandle<io::Result<()>>,
+}
+
+impl Session {
+    fn start_with(config: ServerConfig) -> Session {
+        Session::start_shared(Arc::new(Mutex::new(State::new())), Arc::new(config))
+    }
+
+    /// A session sharing `state` with others, like connections to one server
+    fn start_shared(state: Arc<Mutex<State>>, config: Arc<ServerConfig>) -> Session {
+        let (client, stream) = tokio::io::duplex(PIPE_CAPACITY);
+        let server = tokio::spawn(Server::process(stream, state, config));
+        Session {
+            client: Box::new(client),
+            server,
+        }
+    }
+
+    fn start_on(backend: Backend, config: ServerConfig) -> Session {
+        Session::start_on_shared(&Shared::new(backend), Arc::new(config))
+    }
+
+    /// A session sharing `state` with others of the same backend
+    fn start_on_shared(state: &Shared, config: Arc<ServerConfig>) -> Session {
+        match state {
+            Shared::Tokio(state) => Session::start_shared(state.clone(), config),
+            #[cfg(all(feature = "blocking", unix))]
+            Shared::Blocking(state) => {
+                use std::{io::Read, net::Shutdown, os::unix::net::UnixStream};
+
+                let (client, mut stream) = UnixStream::pair().unwrap();
+                client.set_nonblocking(true).unwrap();
+                stream.set_read_timeout(config.idle_timeout).unwrap();
+                let state = state.clone();
+                let server = tokio::task::spawn_blocking(move || {
+                    let result = service::blocking::Server::process(&stream, &state, &config);
+                    // like the duplex pipes, input left unread is discarded
+                    // rather than failing the client's reads once closed
+                    let _ = stream.shutdown(Shutdown::Write);
+                    stream.set_nonblocking(true).unwrap();
+                    let mut rest = [0u8; 1024];
+                    while let Ok(1..) = stream.read(&mut rest) {}
+                    result
+                });
+                Session {
+                    client: Box::new(tokio::net::UnixStream::from_std(client).unwrap()),
+                    server,
+                }
+            }
+        }
+    }
+
+    /// Sends `request` and reads back one whole response
+    async fn send(&mut self, request: &[u8]) -> Vec<u8> {
+        self.client.write_all(request).await.unwrap();
+        self.receive().await
+    }
+
+    /// Reads one whole response
+    async fn receive(&mut self) -> Vec<u8> {
+        let mut response = vec![0u8; HEADER_SIZE];
+        self.client.read_exact(&mut response).await.unwrap();
+        let size = u16::from_be_bytes([response[4], response[5]]) as usize;
+        response.resize(HEADER_SIZE + size, 0);
+        self.client
+            .read_exact(&mut response[HEADER_SIZE..])
+            .await
+            .unwrap();
+        response
+    }
+
+    /// Closes the client side and waits for the server to finish
+    async fn finish(self) -> io::Result<()> {
+        drop(self.client);
+        self.server.await.unwrap()
+    }
+}
+
+/// Runs each of the tests against every backend, as a test of its own
+macro_rules! shared_tests {
+    ($($test:ident),* $(,)?) => {
+        mod tokio_backend {
+            $(
+                #[tokio::test]
+                async fn $test() {
+                    super::$test(super::Backend::Tokio).await
+                }
+            )*
+        }
+
+        #[cfg(all(feature = "blocking", unix))]
+        mod blocking_backend {
+            $(
+                #[tokio::test]
+                async fn $test() {
+                    super::$test(super::Backend::Blocking).await
+                }
+            )*
+        }
+    };
+}
+
+shared_tests!(
+    test_requests,
+    test_global_reset,
+    test_error_responses,
+    test_error_details,
+    test_describe_unsupported,
+    test_permissive_flags,
+    test_stats_accumulate,
+    test_tenant_stats,
+    test_flood_struck_out,
+    test_bad_magic_strikes,
+    test_split_writes_never_strike,
+    test_silent_bad_magic,
+    test_violation_strikes,
+    test_get_config,
+    test_hello,
+    test_no_goodbye_on_client_close,
+    test_payload_boundaries,
+    test_reads_joined,
+    test_frame_timeout,
+    test_compress_with_stats,
+);
+
+async fn test_requests(backend: Backend) {
+    let mut session = Session::start_on(backend, Default::default());
+    let cases = vec![
+        (request(Request::Ping, b""), response(Response::Ok, b"")),
+        (
+            request(Request::Compress, b"aaaaabbbbbbaaabb"),
+            response(Response::Ok, b"5a6b3abb"),
+        ),
+        (
+            request(Request::Decompress, b"5a6b3abb"),
+            response(Response::Ok, b"aaaaabbbbbbaaabb"),
+        ),
+        (
+            request(Request::CompressBinary, b"AAAAA"),
+            response(Response::Ok, b"\xFF\x05\x00A"),
+        ),
+        (
+            request(Request::DecompressBinary, b"\xFF\x05\x00A"),
+            response(Response::Ok, b"AAAAA"),
+        ),
+        (
+            request(Request::ResetStats, b""),
+            response(Response::Ok, b""),
+        ),
+        // the stats of the whole service are untouched,
+        // read: 8 + 24 + 16 + 13 + 12 + 8 + 8, sent: 8 + 16 + 24 + 12 + 13 + 8
+        // ratio: 12 bytes compressed out of 21, 42.86% saved
+        (
+            request(Request::GetStats, b""),
+            response(Response::Ok, &stats(89, 81, 43)),
+        ),
+    ];
+    for (request, expected) in cases {
+        assert_eq!(session.send(&request).await, expected);
+    }
+    session.finish().await.unwrap();
+}
+
+async fn test_global_reset(backend: Backend) {
+    let mut session = Session::start_on(backend, Default::default());
+    let reset = request(Request::ResetStats, &[ResetScope::Global as u8]);
+    assert_eq!(
+        session.send(&reset).await,
+        response(Response::Forbidden, b"")
+    );
+    session.finish().await.unwrap();
+
+    let config = ServerConfig {
+        allow_global_reset: true,
+        ..Default::default()
+    };
+    let mut session = Session::start_on(backend, config);
+    session.send(&request(Request::Ping, b"")).await;
+    for reset in [request(Request::ResetStats, b""), reset] {
+        assert_eq!(session.send(&reset).await, response(Response::Ok, b""));
+        // the ResetStats response is sent after resetting
+        assert_eq!(
+            session.send(&request(Request::GetStats, b"")).await,
+            response(Response::Ok, &stats(8, 8, 0))
+        );
+    }
+    // a reset retried with the same token, its response having been lost,
+    // is answered alike without resetting the stats again
+    let reset = request(Request::ResetStats, b"\x01retry-me");
+    for expected in [stats(8, 8, 0), stats(33, 33, 0)] {
+        assert_eq!(session.send(&reset).await, response(Response::Ok, b""));
+        assert_eq!(
+            session.send(&request(Request::GetStats, b"")).await,
+            response(Response::Ok, &expected)
+        );
+    }
+    session.finish().await.unwrap();
+}
+
+async fn test_error_responses(backend: Backend) {
+    let mut session = Session::start_on(backend, Default::default());
+    let cases = vec![
+        (
+            raw(MAGIC + 1, 0, Request::Ping as u16, b""),
+            Response::MessageHeaderHasBadMagic,
+        ),
+        (
+            raw(MAGIC, 5, Request::Compress as u16, b"abc"),
+            Response::MessageHeaderSizeMismatch,
+        ),
+        (raw(MAGIC, 0, 99, b""), Response::UnsupportedRequestType),
+        (
+            raw(MAGIC, 0, 0x0800 | Request::Ping as u16, b""),
+            Response::UnsupportedFlags,
+        ),
+        (
+            request(Request::GetStats, b"a"),
+            Response::RequestKindRequiresZeroLength,
+        ),
+        (
+            request(Request::Compress, b""),
+            Response::CompressionRequestRequiresNonZeroLength,
+        ),
+        (
+            request(Request::Compress, b"aB3"),
+            Response::MessagePayloadContainsInvalidCharacters,
+        ),
+        (
+            request(Request::Compress, b"abC"),
+            Response::MessageContainsUppercaseCharacters,
+        ),
+        (
+            request(Request::Compress, b"ab3"),
+            Response::MessageContainsDigits,
+        ),
+        (
+            request(Request::Compress, b"ab\xFF"),
+            Response::MessageContainsNonAscii,
+        ),
+        (
+            request(Request::Decompress, b"0a"),
+            Response::MalformedCompressedPayload,
+        ),
+        (request(Request::ResetStats, &[1]), Response::Forbidden),
+        (
+            request(Request::ResetStats, &[3]),
+            Response::UnsupportedResetScope,
+        ),
+        (
+            request(Request::Decompress, b"9999a"),
+            Response::ResponseTooLarge,
+        ),
+        (MAGIC.to_be_bytes().to_vec(), Response::MessageTooSmall),
+    ];
+    for (request, code) in cases {
+        assert_eq!(session.send(&request).await, response(code, b""));
+    }
+    // the session is still usable after every error
+    let ping = request(Request::Ping, b"");
+    assert_eq!(session.send(&ping).await, response(Response::Ok, b""));
+    session.finish().await.unwrap();
+}
+
+async fn test_error_details(backend: Backend) {
+    let config = ServerConfig {
+        enforcement: Enforcement::Strict,
+        error_details: true,
+        ..Default::default()
+    };
+    let mut session = Session::start_on(backend, config);
+    let cases = vec![
+        (
+            raw(MAGIC + 1, 0, Request::Ping as u16, b""),
+            response(
+                Response::MessageHeaderHasBadMagic,
+                b"header.sign=0x5354525a but magic=0x53545259\x00",
+            ),
+        ),
+        (
+            raw(MAGIC, 5, Request::Compress as u16, b"abc"),
+            response(
+                Response::MessageHeaderSizeMismatch,
+                b"header.size=5 but payload=3 bytes\x04",
+            ),
+        ),
+        (
+            raw(MAGIC, 1, Request::Compress as u16, b"abc"),
+            response(
+                Response::TrailingBytes,
+                b"header.size=1 but payload=3 bytes\x04",
+            ),
+        ),
+        // errors of the payload's contents tell the first byte rejected
+        (
+            request(Request::Compress, b"aB3"),
+            response(
+                Response::MessagePayloadContainsInvalidCharacters,
+                b"invalid byte 0x42 at offset 1\x04",
+            ),
+        ),
+    ];
+    for (request, expected) in cases {
+        assert_eq!(session.send(&request).await, expected);
+    }
+    let ping = request(Request::Ping, b"");
+    assert_eq!(session.send(&ping).await, response(Response::Ok, b""));
+    session.finish().await.unwrap();
+}
+
+async fn test_describe_unsupported(backend: Backend) {
+    // clients of the bare header keep it by default
+    let unknown = raw(MAGIC, 0, 200, b"");
+    let mut session = Session::start_on(backend, Default::default());
+    assert_eq!(
+        session.send(&unknown).await,
+        response(Response::UnsupportedRequestType, b"")
+    );
+    session.finish().await.unwrap();
+
+    let config = ServerConfig {
+        describe_unsupported: true,
+        max_batch: 0,
+        ..Default::default()
+    };
+    let mut session = Session::start_on(backend, config);
+    let unsupported = session.send(&unknown).await;
+    assert_eq!(unsupported[7], Response::UnsupportedRequestType as u8);
+    let served = ServedRequests::parse(&unsupported[HEADER_SIZE..]).unwrap();
+    assert_eq!(served.version(), PROTOCOL_VERSION);
+    // every request but Batch, turned off
+    let codes: Vec<u16> = (0..256).filter(|&code| served.serves(code)).collect();
+    let expected: Vec<u16> = (1..=14)
+        .filter(|&code| code != Request::Batch as u16)
+        .collect();
+    assert_eq!(codes, expected);
+    session.finish().await.unwrap();
+}
+
+async fn test_permissive_flags(backend: Backend) {
+    let config = ServerConfig {
+        strict_flags: false,
+        ..Default::default()
+    };
+    let mut session = Session::start_on(backend, config);
+    let compress = raw(
+        MAGIC,
+        16,
+        0x0200 | Request::Compress as u16,
+        b"aaaaabbbbbbaaabb",
+    );
+    assert_eq!(
+        session.send(&compress).await,
+        response(Response::Ok, b"5a6b3abb")
+    );
+    session.finish().await.unwrap();
+}
+
+async fn test_compress_with_stats(backend: Backend) {
+    let mut session = Session::start_on(backend, Default::default());
+    session.send(&request(Request::Compress, b"aaaaabbb")).await;
+
+    let compress = request(Request::CompressWithStats, b"aaaaabbbbbbaaabb");
+    let combined = session.send(&compress).await;
+    let payload = &combined[HEADER_SIZE..];
+    let (compressed, with_stats) = payload.split_at(payload.len() - STATS_LEN);
+    assert_eq!(compressed, b"5a6b3abb");
+    // read: 16 + 24, sent: 12, ratio: 12 bytes compressed out of 24
+    assert_eq!(with_stats, &stats(40, 12, 50)[..]);
+
+    // a GetStats right after reports the same, besides its own request and
+    // the response carrying them
+    let get_stats = session.send(&request(Request::GetStats, b"")).await;
+    let read = 40 + HEADER_SIZE as u32;
+    let sent = 12 + combined.len() as u32;
+    assert_eq!(get_stats, response(Response::Ok, &stats(read, sent, 50)));
+
+    // so do those of a payload stored as is and of a sequenced request, the
+    // flags of their response are no reason to report pending stats
+    let stored = request(Request::CompressWithStats, b"abc");
+    let sequenced = with_sequence(&compress, 7);
+    for compress in [stored, sequenced] {
+        let combined = session.send(&compress).await;
+        let with_stats = &combined[combined.len() - STATS_LEN..];
+        let get_stats = session.send(&request(Request::GetStats, b"")).await;
+        let reported = Stats::parse(&get_stats[HEADER_SIZE..]).unwrap();
+        let read = reported.read() - HEADER_SIZE as u32;
+        let sent = reported.sent() - combined.len() as u32;
+        assert_eq!(with_stats, &stats(read, sent, reported.ratio())[..]);
+    }
+    session.finish().await.unwrap();
+}
+
+async fn test_stats_accumulate(backend: Backend) {
+    let mut session = Session::start_on(backend, Default::default());
+    let get_stats = request(Request::GetStats, b"");
+
+    assert_eq!(
+        session.send(&get_stats).await,
+        response(Response::Ok, &stats(8, 0, 0))
+    );
+    session.send(&request(Request::Ping, b"")).await;
+    session
+        .send(&request(Request::Compress, b"aaaaabbbbbbaaabb"))
+        .await;
+    // invalid requests count towards the bytes, not the ratio
+    session.send(&request(Request::Compress, b"A")).await;
+
+    // read: 8 + 8 + 24 + 9 + 8, sent: 17 + 8 + 16 + 8
+    assert_eq!(
+        session.send(&get_stats).await,
+        response(Response::Ok, &stats(57, 49, 50))
+    );
+
+    // only this connection's stats are reset, read: 57 + 8 + 8, sent: 49 + 17 + 8
+    session.send(&request(Request::ResetStats, b"")).await;
+    assert_eq!(
+        session.send(&get_stats).await,
+        response(Response::Ok, &stats(73, 74, 50))
+    );
+    session.finish().await.unwrap();
+}
+
+async fn test_tenant_stats(backend: Backend) {
+    let state = Shared::new(backend);
+    let tenants = [("tok-a", "acme"), ("tok-b", "globex"), ("tok-c", "initech")];
+    let config = Arc::new(ServerConfig {
+        tenants: tenants
+            .iter()
+            .map(|(token, tenant)| (token.to_string(), tenant.to_string()))
+            .collect(),
+        max_tenants: 2,
+        ..Default::default()
+    });
+    let get_stats = request(Request::GetStats, b"");
+    let ok = response(Response::Ok, b"");
+
+    // the Authenticate request counts towards the connection's tenant before
+    // it, its response towards the new one
+    let mut acme = Session::start_on_shared(&state, config.clone());
+    let mut globex = Session::start_on_shared(&state, config.clone());
+    let mut anonymous = Session::start_on_shared(&state, config.clone());
+    assert_eq!(
+        acme.send(&request(Request::Authenticate, b"tok-a")).await,
+        ok
+    );
+    assert_eq!(
+        globex.send(&request(Request::Authenticate, b"nope")).await,
+        response(Response::Unauthorized, b"")
+    );
+    assert_eq!(
+        globex.send(&request(Request::Authenticate, b"tok-b")).await,
+        ok
+    );
+    acme.send(&request(Request::Compress, b"aaaaabbbbbbaaabb"))
+        .await;
+    anonymous.send(&request(Request::Ping, b"")).await;
+
+    // acme read: 24 + 8, sent: 8 + 16, globex read: 8, sent: 8
+    // anonymous read: 13 + 12 + 13 + 8 + 8, sent: 8 + 8
+    assert_eq!(
+        acme.send(&get_stats).await,
+        response(Response::Ok, &stats(32, 24, 50))
+    );
+    assert_eq!(
+        globex.send(&get_stats).await,
+        response(Response::Ok, &stats(8, 8, 0))
+    );
+    assert_eq!(
+        anonymous.send(&get_stats).await,
+        response(Response::Ok, &stats(54, 16, 0))
+    );
+    for session in [acme, globex, anonymous] {
+        session.finish().await.unwrap();
+    }
+
+    // the tenants add up to the whole service
+    let shared = state.get().await;
+    let scopes: Vec<Stats> = ["acme", "globex", ANONYMOUS_TENANT]
+        .iter()
+        .map(|tenant| shared.tenant_stats(tenant))
+        .collect();
+    let global = shared.snapshot().stats;
+    assert_eq!(scopes.iter().map(Stats::read).sum::<u32>(), global.read());
+    assert_eq!(scopes.iter().map(Stats::sent).sum::<u32>(), global.sent());
+    assert_eq!((global.read(), global.sent()), (94, 99));
+
+    // an empty ResetStats only resets the tenant's stats
+    let mut acme = Session::start_on_shared(&state, config.clone());
+    let mut globex = Session::start_on_shared(&state, config.clone());
+    acme.send(&request(Request::Authenticate, b"tok-a")).await;
+    assert_eq!(acme.send(&request(Request::ResetStats, b"")).await, ok);
+    assert_eq!(
+        acme.send(&get_stats).await,
+        response(Response::Ok, &stats(8, 8, 0))
+    );
+    globex.send(&request(Request::Authenticate, b"tok-b")).await;
+    assert_eq!(
+        globex.send(&get_stats).await,
+        response(Response::Ok, &stats(16, 33, 0))
+    );
+    assert_eq!(
+        globex
+            .send(&request(Request::ResetStats, &[ResetScope::Global as u8]))
+            .await,
+        response(Response::Forbidden, b"")
+    );
+
+    // no room is left for a third tenant
+    assert_eq!(
+        globex.send(&request(Request::Authenticate, b"tok-c")).await,
+        response(Response::Forbidden, b"")
+    );
+    for session in [acme, globex] {
+        session.finish().await.unwrap();
+    }
+}
+
+async fn test_flood_struck_out(backend: Backend) {
+    let mut session = Session::start_on(backend, Default::default());
+    let flood = vec![0u8; MAX_MESSAGE * 4];
+    session.client.write_all(&flood).await.unwrap();
+
+    // answered read by read until it strikes out for its magic
+    let result = session.server.await.unwrap();
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "Dropping client sending bad magic"
+    );
+    let mut rest = Vec::new();
+    session.client.read_to_end(&mut rest).await.unwrap();
+    let too_large = response(Response::MessageTooLarge, b"");
+    assert!(rest.starts_with(&too_large));
+    assert_eq!(rest.last(), Some(&(GoodbyeReason::BadMagic as u8)));
+}
+
+/// A blob from a peer that doesn't speak the protocol, its size field happens
+/// to match so it's rejected for its magic
+const GARBAGE: &[u8] = b"\x16\x03\x01\x02\x00\x00\x00\x01";
+
+async fn test_bad_magic_strikes(backend: Backend) {
+    let state = Shared::new(backend);
+    let config = Arc::new(ServerConfig::default());
+    let bad_magic = response(Response::MessageHeaderHasBadMagic, b"");
+    let ping = request(Request::Ping, b"");
+
+    // valid requests in between don't keep the connection from closing
+    let mut session = Session::start_on_shared(&state, config.clone());
+    assert_eq!(session.send(GARBAGE).await, bad_magic);
+    assert_eq!(session.send(&ping).await, response(Response::Ok, b""));
+    assert_eq!(session.send(GARBAGE).await, bad_magic);
+    assert_eq!(session.send(&ping).await, response(Response::Ok, b""));
+    assert_eq!(state.get().await.bad_magic_drops(), 0);
+    session.finish().await.unwrap();
+
+    let mut session = Session::start_on_shared(&state, config);
+    for _ in 0..2 {
+        assert_eq!(session.send(GARBAGE).await, bad_magic);
+    }
+    session.client.write_all(GARBAGE).await.unwrap();
+    let result = session.server.await.unwrap();
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    let mut rest = Vec::new();
+    session.client.read_to_end(&mut rest).await.unwrap();
+    let mut expected = bad_magic.clone();
+    expected.extend(goodbye(stats(24, 24, 0), 3, GoodbyeReason::BadMagic));
+    assert_eq!(rest, expected);
+    assert_eq!(state.get().await.bad_magic_drops(), 1);
+}
+
+async fn test_split_writes_never_strike(backend: Backend) {
+    let state = Shared::new(backend);
+    let config = ServerConfig {
+        bad_magic_strikes: 1,
+        idle_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let mut session = Session::start_on_shared(&state, Arc::new(config));
+    let compress = request(Request::Compress, b"aaaa");
+    let ok = response(Response::Ok, b"");
+    // wherever a request is split, the rest of it is no bad magic of its
+    // own, even when it's answered with one
+    for at in 1..compress.len() {
+        session.client.write_all(&compress[..at]).await.unwrap();
+        time::sleep(Duration::from_millis(20)).await;
+        session.client.write_all(&compress[at..]).await.unwrap();
+        let mut answered = session.send(&request(Request::Ping, b"")).await;
+        while answered != ok {
+            answered = session.receive().await;
+        }
+    }
+
+    // the rest of a payload arriving past the idle timeout is no longer
+    // joined, it's answered on its own. Read as a header, this one declares
+    // the length it has, so it's answered with a bad magic
+    let compress = request(Request::Compress, b"aaaaaaaa\0\x04aaaaaa");
+    session.client.write_all(&compress[..12]).await.unwrap();
+    let mismatch = response(Response::MessageHeaderSizeMismatch, b"");
+    assert_eq!(session.receive().await, mismatch);
+    time::sleep(Duration::from_millis(50)).await;
+    let bad_magic = response(Response::MessageHeaderHasBadMagic, b"");
+    assert_eq!(session.send(&compress[12..]).await, bad_magic);
+    assert_eq!(session.send(&request(Request::Ping, b"")).await, ok);
+    assert_eq!(state.get().await.bad_magic_drops(), 0);
+    session.finish().await.unwrap();
+}
+
+async fn test_violation_strikes(backend: Backend) {
+    // bytes past the payload the size field declares
+    let trailing = raw(MAGIC, 0, Request::Ping as u16, b"\0\0");
+    let non_canonical = request(Request::Decompress, b"2a");
+    let ping = request(Request::Ping, b"");
+
+    // permissive, the violations are answered and never close the connection,
+    // the bytes past a message that aren't another are skipped
+    let mut session = Session::start_on(backend, Default::default());
+    for _ in 0..4 {
+        assert_eq!(session.send(&trailing).await, response(Response::Ok, b""));
+    }
+    assert_eq!(
+        session.send(&non_canonical).await,
+        response(Response::Ok, b"aa")
+    );
+    assert_eq!(session.send(&ping).await, response(Response::Ok, b""));
+    session.finish().await.unwrap();
+
+    let config = ServerConfig {
+        enforcement: Enforcement::Strict,
+        ..Default::default()
+    };
+    let mut session = Session::start_on(backend, config);
+    assert_eq!(
+        session.send(&trailing).await,
+        response(Response::TrailingBytes, b"")
+    );
+    assert_eq!(
+        session.send(&non_canonical).await,
+        response(Response::NonCanonicalEncoding, b"")
+    );
+    assert_eq!(session.send(&ping).await, response(Response::Ok, b""));
+    session.client.write_all(&trailing).await.unwrap();
+    let result = session.server.await.unwrap();
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    let mut rest = Vec::new();
+    session.client.read_to_end(&mut rest).await.unwrap();
+    let mut expected = response(Response::TrailingBytes, b"");
+    expected.extend(goodbye(stats(38, 32, 0), 4, GoodbyeReason::Violations));
+    assert_eq!(rest, expected);
+}
+
+async fn test_silent_bad_magic(backend: Backend) {
+    let state = Shared::new(backend);
+    let config = ServerConfig {
+        silent_bad_magic: true,
+        ..Default::default()
+    };
+    let mut session = Session::start_on_shared(&state, Arc::new(config));
+    let read = || async {
+        let state = state.get().await;
+        service::Stats::parse(state.stats_as_bytes())
+            .unwrap()
+            .read() as usize
+    };
+
+    // with no response to wait on, wait for each blob to be read on its own
+    for strike in 1..=3 {
+        session.client.write_all(GARBAGE).await.unwrap();
+        while read().await < strike * GARBAGE.len() {
+            tokio::task::yield_now().await;
+        }
+    }
+    let result = session.server.await.unwrap();
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    // closed without a word
+    let mut rest = Vec::new();
+    session.client.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(state.get().await.bad_magic_drops(), 1);
+}
+
+async fn test_payload_boundaries(backend: Backend) {
+    let mut session = Session::start_on(backend, Default::default());
+    let max = MAX_PAYLOAD as usize;
+    for len in [max - 1, max] {
+        let run = vec![b'a'; len];
+        let expected = format!("{}a", len);
+        assert_eq!(
+            session.send(&request(Request::Compress, &run)).await,
+            response(Response::Ok, expected.as_bytes())
+        );
+        let alternating: Vec<u8> = b"ab".iter().copied().cycle().take(len).collect();
+        assert_eq!(
+            session
+                .send(&request(Request::Compress, &alternating))
+                .await,
+            response(Response::Ok, &alternating)
+        );
+    }
+    // one byte over is rejected as soon as it's read
+    let over = vec![b'a'; max + 1];
+    assert_eq!(
+        session.send(&request(Request::Compress, &over)).await,
+        response(Response::MessageTooLarge, b"")
+    );
+    let ping = request(Request::Ping, b"");
+    assert_eq!(session.send(&ping).await, response(Response::Ok, b""));
+    session.finish().await.unwrap();
+}
+
+async fn test_reads_joined(backend: Backend) {
+    let mut session = Session::start_on(backend, Default::default());
+    let compress = request(Request::Compress, &[b'a'; 5000]);
+    let compressed = response(Response::Ok, b"5000a");
+    // a request split across writes is answered once whole
+    session
+        .client
+        .write_all(&compress[..HEADER_SIZE + 1])
+        .await
+        .unwrap();
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(session.send(&compress[HEADER_SIZE + 1..]).await, compressed);
+    // and so is each of several written at once, more than a read holds
+    assert_eq!(session.send(&compress.repeat(3)).await, compressed);
+    for _ in 0..2 {
+        assert_eq!(session.receive().await, compressed);
+    }
+    let ping = request(Request::Ping, b"");
+    assert_eq!(session.send(&ping).await, response(Response::Ok, b""));
+    session.finish().await.unwrap();
+}
+
+async fn test_frame_timeout(backend: Backend) {
+    let config = ServerConfig {
+        frame_timeout: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let mut session = Session::start_on(backend, config);
+    let ping = request(Request::Ping, b"");
+    let ok = response(Response::Ok, b"");
+    let compress = request(Request::Compress, b"aaaaaa");
+    let compressed = response(Response::Ok, b"6a");
+    // the rest of a split request arriving within the deadline is joined
+    session
+        .client
+        .write_all(&compress[..HEADER_SIZE + 1])
+        .await
+        .unwrap();
+    time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(session.send(&compress[HEADER_SIZE + 1..]).await, compressed);
+
+    // a header cut short, left after a whole message, is answered for what
+    // it has once the deadline passes
+    let started = Instant::now();
+    let mut written = ping.clone();
+    written.extend_from_slice(&compress[..5]);
+    assert_eq!(session.send(&written).await, ok);
+    let too_small = response(Response::MessageTooSmall, b"");
+    assert_eq!(session.receive().await, too_small);
+    assert!(started.elapsed() >= Duration::from_millis(100));
+
+    // and so is a payload cut short
+    let started = Instant::now();
+    let mismatch = response(Response::MessageHeaderSizeMismatch, b"");
+    assert_eq!(session.send(&compress[..HEADER_SIZE + 1]).await, mismatch);
+    assert!(started.elapsed() >= Duration::from_millis(100));
+
+    // the connection is still served
+    assert_eq!(session.send(&ping).await, ok);
+    session.finish().await.unwrap();
+}
+
+async fn test_get_config(backend: Backend) {
+    let config = ServerConfig {
+        idle_timeout: Some(Duration::from_secs(30)),
+        ..Default::default()
+    };
+    let mut session = Session::start_on(backend, config);
+    let get_config = request(Request::GetConfig, b"");
+    let features = service::Feature::DECOMPRESS
+        | service::Feature::BATCH
+        | service::Feature::SEQUENCE
+        | service::Feature::IDEMPOTENT_RESET;
+    let expected = limits(1, features, 8192, 8200, 30, 0);
+    assert_eq!(
+        session.send(&get_config).await,
+        response(Response::Ok, &expected)
+    );
+    assert_eq!(
+        session.send(&request(Request::GetConfig, b"a")).await,
+        response(Response::RequestKindRequiresZeroLength, b"")
+    );
+    session.finish().await.unwrap();
+}
+
+async fn test_hello(backend: Backend) {
+    let config = ServerConfig {
+        hello: true,
+        idle_timeout: Some(Duration::from_secs(30)),
+        ..Default::default()
+    };
+    let mut session = Session::start_on(backend, config);
+    // a client unaware of the greeting reads it for the response to its
+    // first request, the one to its second for the first's, and so on
+    let features = service::Feature::DECOMPRESS
+        | service::Feature::BATCH
+        | service::Feature::SEQUENCE
+        | service::Feature::IDEMPOTENT_RESET;
+    let hello = response(Response::Hello, &limits(1, features, 8192, 8200, 30, 0));
+    let ping = request(Request::Ping, b"");
+    assert_eq!(session.send(&ping).await, hello);
+    assert_eq!(session.send(&ping).await, response(Response::Ok, b""));
+    let mut last = vec![0u8; HEADER_SIZE];
+    session.client.read_exact(&mut last).await.unwrap();
+    assert_eq!(last, response(Response::Ok, b""));
+    session.finish().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_idle_timeout() {
+    let config = ServerConfig {
+        idle_timeout: Some(Duration::from_secs(30)),
+        ..Default::default()
+    };
+    let mut session = Session::start_with(config);
+    let ping = request(Request::Ping, b"");
+
+    // each request restarts the timeout
+    for _ in 0..3 {
+        time::sleep(Duration::from_secs(29)).await;
+        assert_eq!(session.send(&ping).await, response(Response::Ok, b""));
+    }
+
+    let idle = Instant::now();
+    let result = session.server.await.unwrap();
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    assert_eq!(idle.elapsed(), Duration::from_secs(30));
+
+    let mut rest = Vec::new();
+    session.client.read_to_end(&mut rest).await.unwrap();
+    let expected = goodbye(stats(24, 24, 0), 3, GoodbyeReason::IdleTimeout);
+    assert_eq!(rest, expected);
+}
+
+/// The blocking server times out on its socket's read timeout, real time
+/// that can't be paused like tokio's
+#[cfg(all(feature = "blocking", unix))]
+#[tokio::test]
+async fn test_blocking_idle_timeout() {
+    let config = ServerConfig {
+        idle_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let mut session = Session::start_on(Backend::Blocking, config);
+    let ping = request(Request::Ping, b"");
+    assert_eq!(session.send(&ping).await, response(Response::Ok, b""));
+
+    let result = session.server.await.unwrap();
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    let mut rest = Vec::new();
+    session.client.read_to_end(&mut rest).await.unwrap();
+    let expected = goodbye(stats(8, 8, 0), 1, GoodbyeReason::IdleTimeout);
+    assert_eq!(rest, expected);
+}
+
+async fn test_no_goodbye_on_client_close(backend: Backend) {
+    let mut session = Session::start_on(backend, Default::default());
+    session.send(&request(Request::Ping, b"")).await;
+    session.client.shutdown().await.unwrap();
+    let mut rest = Vec::new();
+    session.client.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+    session.finish().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_getstats_never_torn() {
+    const COMPRESSES: usize = 500;
+    let state = Arc::new(Mutex::new(State::new()));
+    let config = Arc::new(ServerConfig::default());
+
+    // alternates a request compressing by half with one stored as is, so the
+    // ratio depends on how many of each were handled
+    let mut compressor = Session::start_shared(state.clone(), config.clone());
+    let compressing = tokio::spawn(async move {
+        for i in 0..COMPRESSES {
+            let (payload, expected): (&[u8], &[u8]) = match i % 2 {
+                0 => (b"aaaaabbbbbbaaabb", b"5a6b3abb"),
+                _ => (b"abcd", b"abcd"),
+            };
+            let compress = request(Request::Compress, payload);
+            assert_eq!(
+                compressor.send(&compress).await,
+                response(Response::Ok, expected)
+            );
+        }
+        compressor.finish().await.unwrap();
+    });
+
+    let mut session = Session::start_shared(state, config);
+    let get_stats = request(Request::GetStats, b"");
+    let mut seen = 0;
+    for k in 0.. {
+        let done = compressing.is_finished();
+        let response = session.send(&get_stats).await;
+        let stats = service::Stats::parse(&response[HEADER_SIZE..]).unwrap();
+
+        // this GetStats is read but not yet sent, the earlier ones are both
+        let read = stats.read() as usize - 8 * (k + 1);
+        let sent = stats.sent() as usize - 17 * k;
+        // 24 + 12 read and 16 + 12 sent per pair of compress requests
+        let (halved, stored) = match (read / 36, read % 36) {
+            (pairs, 0) => (pairs, pairs),
+            (pairs, 24) => (pairs + 1, pairs),
+            _ => panic!("read {} is not a number of compress requests", read),
+        };
+        assert_eq!(sent, 16 * halved + 12 * stored, "torn read/sent");
+
+        let mut expected = State::new();
+        if halved > 0 {
+            expected.update_ratio(16 * halved + 4 * stored, 8 * halved + 4 * stored);
+        }
+        let expected = service::Stats::parse(expected.stats_as_bytes()).unwrap();
+        assert_eq!(stats.ratio(), expected.ratio(), "torn ratio");
+
+        assert!(halved + stored >= seen);
+        seen = halved + stored;
+        if done {
+            break;
+        }
+    }
+    assert_eq!(seen, COMPRESSES);
+    compressing.await.unwrap();
+    session.finish().await.unwrap();
+}
+
+/// A stream whose writes stay pending while `blocked` is set, so a test can
+/// cancel the server at the point it is writing a response
+struct GatedStream {
+    inner: DuplexStream,
+    blocked: Arc<AtomicBool>,
+    writing: Arc<AtomicBool>,
+}
+
+impl AsyncRead for GatedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for GatedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.writing.store(true, Ordering::SeqCst);
+        if self.blocked.load(Ordering::SeqCst) {
+            return Poll::Pending;
+        }
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn test_cancellation_keeps_stats_consistent() {
+    let state = Arc::new(Mutex::new(State::new()));
+    let config = Arc::new(ServerConfig::default());
+
+    // cancelled while waiting for a request
+    let (_client, stream) = tokio::io::duplex(PIPE_CAPACITY);
+    let server = tokio::spawn(Server::process(stream, state.clone(), config.clone()));
+    tokio::task::yield_now().await;
+    server.abort();
+    assert!(server.await.unwrap_err().is_cancelled());
+    assert_eq!(*state.lock().await, State::new());
+
+    // cancelled while writing the response, after the request was handled,
+    // whichever lane handled it and however it reads the shared state
+    let blocked = Arc::new(AtomicBool::new(true));
+    let writing = Arc::new(AtomicBool::new(false));
+    let compress = request(Request::Compress, b"aaaaabbbbbbaaabb");
+    let cases = [
+        compress.clone(),
+        request(Request::GetStats, b""),
+        request(Request::CompressWithStats, b"aaaaabbbbbbaaabb"),
+    ];
+    for sent in cases {
+        let (mut client, inner) = tokio::io::duplex(PIPE_CAPACITY);
+        writing.store(false, Ordering::SeqCst);
+        let stream = GatedStream {
+            inner,
+            blocked: blocked.clone(),
+            writing: writing.clone(),
+        };
+        let server = tokio::spawn(Server::process(stream, state.clone(), config.clone()));
+        client.write_all(&sent).await.unwrap();
+        while !writing.load(Ordering::SeqCst) {
+            tokio::task::yield_now().await;
+        }
+        server.abort();
+        assert!(server.await.unwrap_err().is_cancelled());
+        assert_eq!(*state.lock().await, State::new());
+    }
+
+    // the same request completing is accounted for as a whole
+    let (mut client, inner) = tokio::io::duplex(PIPE_CAPACITY);
+    blocked.store(false, Ordering::SeqCst);
+    let stream = GatedStream {
+        inner,
+        blocked,
+        writing,
+    };
+    let server = tokio::spawn(Server::process(stream, state.clone(), config));
+    client.write_all(&compress).await.unwrap();
+    let mut response = [0u8; HEADER_SIZE + 8];
+    client.read_exact(&mut response).await.unwrap();
+    drop(client);
+    server.await.unwrap().unwrap();
+
+    let state = state.lock().await;
+    let stats = service::Stats::parse(state.stats_as_bytes()).unwrap();
+    assert_eq!((stats.read(), stats.sent(), stats.ratio()), (24, 16, 50));
+    assert_eq!(state.runs(), 3);
+}
+
+#[tokio::test]
+async fn test_stalled_write_holds_no_lock() {
+    let state = Arc::new(Mutex::new(State::new()));
+    let config = Arc::new(ServerConfig::default());
+
+    // a peer whose response can't be written, for however long
+    let (mut stalled, inner) = tokio::io::duplex(PIPE_CAPACITY);
+    let writing = Arc::new(AtomicBool::new(false));
+    let stream = GatedStream {
+        inner,
+        blocked: Arc::new(AtomicBool::new(true)),
+        writing: writing.clone(),
+    };
+    let server = tokio::spawn(Server::process(stream, state.clone(), config.clone()));
+    let compress = request(Request::Compress, b"aaaaabbbbbbaaabb");
+    stalled.write_all(&compress).await.unwrap();
+    while !writing.load(Ordering::SeqCst) {
+        tokio::task::yield_now().await;
+    }
+
+    // the other connections are served meanwhile, and the stats they get
+    // leave out the request whose response is still to be written
+    let (mut client, stream) = tokio::io::duplex(PIPE_CAPACITY);
+    let other = tokio::spawn(Server::process(stream, state.clone(), config));
+    let exchange = async {
+        client
+            .write_all(&request(Request::GetStats, b""))
+            .await
+            .unwrap();
+        let mut answer = [0u8; HEADER_SIZE + 9];
+        client.read_exact(&mut answer).await.unwrap();
+        answer
+    };
+    let answer = time::timeout(Duration::from_secs(5), exchange)
+        .await
+        .expect("answered while the other write stalls");
+    assert_eq!(answer[..], response(Response::Ok, &stats(8, 0, 0))[..]);
+    drop(client);
+    other.await.unwrap().unwrap();
+
+    server.abort();
+    assert!(server.await.unwrap_err().is_cancelled());
+    let state = state.lock().await;
+    let stats = service::Stats::parse(state.stats_as_bytes()).unwrap();
+    assert_eq!((stats.read(), stats.sent()), (8, 17));
+    assert_eq!(state.runs(), 0);
+}
+
+/// A client pipelining `remaining` copies of `request`, each read returns a
+/// whole request without ever waiting and responses are discarded
+struct FloodStream {
+    request: Vec<u8>,
+    remaining: usize,
+}
+
+impl AsyncRead for FloodStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            buf.put_slice(&self.request);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for FloodStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Pings over one session while a `FloodStream` keeps another busy on the
+/// same single-threaded runtime, returning the most flood requests handled
+/// during one ping round trip and the most requests handled per wake
+async fn flood_and_ping(requests_per_yield: usize) -> (u32, usize) {
+    let config = ServerConfig {
+        requests_per_yield,
+        ..Default::default()
+    };
+    let ping = request(Request::Ping, b"");
+    let flood_state = Arc::new(Mutex::new(State::new()));
+    let stream = FloodStream {
+        request: ping.clone(),
+        remaining: 10_000,
+    };
+    let flood = tokio::spawn(Server::process(
+        stream,
+        flood_state.clone(),
+        Arc::new(config.clone()),
+    ));
+    let mut session = Session::start_with(config);
+
+    let flooded = || async {
+        let state = flood_state.lock().await;
+        service::Stats::parse(state.stats_as_bytes())
+            .unwrap()
+            .read()
+            / HEADER_SIZE as u32
+    };
+    let mut worst = 0;
+    while !flood.is_finished() {
+        let before = flooded().await;
+        assert_eq!(session.send(&ping).await, response(Response::Ok, b""));
+        worst = cmp::max(worst, flooded().await - before);
+    }
+    flood.await.unwrap().unwrap();
+    session.finish().await.unwrap();
+    let per_wake = flood_state.lock().await.max_requests_per_wake();
+    (worst, per_wake)
+}
+
+#[tokio::test]
+async fn test_flood_does_not_starve_others() {
+    // yielding after every request interleaves the connections one to one
+    let (latency, per_wake) = flood_and_ping(1).await;
+    assert!(latency <= 2, "{} flood requests per ping", latency);
+    assert_eq!(per_wake, 1);
+
+    // otherwise the ping waits on the runtime's own budget to run out
+    let (latency, per_wake) = flood_and_ping(0).await;
+    assert!(latency > 16, "{} flood requests per ping", latency);
+    assert_eq!(per_wake, 10_000);
+}
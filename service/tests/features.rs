@@ -0,0 +1,113 @@
+//! Keeps the crate usable without the default `server` feature: the wire
+//! format builds as #![no_std] without an allocator for clients on embedded
+//! targets, and with `std` alone requests are handled without tokio
+
+use std::{env, path::Path, process::Command};
+
+/// An embedded target without std, checked against when it's installed
+const EMBEDDED_TARGET: &str = "thumbv7em-none-eabihf";
+
+/// Runs a cargo subcommand on this crate, with its own target directory so
+/// the build of the tests isn't invalidated. Arguments after a `--` are
+/// passed on as they are, e.g. to clippy
+fn cargo(args: &[&str]) -> String {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let split = args
+        .iter()
+        .position(|arg| *arg == "--")
+        .unwrap_or(args.len());
+    let (args, passed) = args.split_at(split);
+    let output = Command::new(cargo)
+        .args(args)
+        .args(["--offline", "--manifest-path"])
+        .arg(Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"))
+        .args(passed)
+        .env("CARGO_TARGET_DIR", env!("CARGO_TARGET_TMPDIR"))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn check_no_default_features(target: Option<&str>) {
+    let mut args = vec!["check", "--lib", "--no-default-features"];
+    if let Some(target) = target {
+        args.extend(["--target", target]);
+    }
+    cargo(&args);
+}
+
+fn is_installed(target: &str) -> bool {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let sysroot = Command::new(rustc)
+        .args(["--print", "sysroot"])
+        .output()
+        .unwrap();
+    let sysroot = String::from_utf8_lossy(&sysroot.stdout);
+    Path::new(sysroot.trim())
+        .join("lib/rustlib")
+        .join(target)
+        .exists()
+}
+
+#[test]
+fn test_no_default_features() {
+    check_no_default_features(None);
+    if is_installed(EMBEDDED_TARGET) {
+        check_no_default_features(Some(EMBEDDED_TARGET));
+    }
+}
+
+#[test]
+fn test_no_tokio_without_server() {
+    for features in ["", "std", "blocking"] {
+        let tree = cargo(&[
+            "tree",
+            "--no-default-features",
+            "--features",
+            features,
+            "--edges",
+            "normal",
+            "--prefix",
+            "none",
+        ]);
+        assert!(!tree.contains("tokio"), "{}", tree);
+    }
+    // the tests, benches and binaries too, each leaving out what needs
+    // features it's built without, and without any warning, e.g. of code
+    // only the features left out use
+    for features in ["", "std", "blocking", "testing"] {
+        cargo(&[
+            "clippy",
+            "--all-targets",
+            "--no-default-features",
+            "--features",
+            features,
+            "--",
+            "-D",
+            "warnings",
+        ]);
+    }
+
+    let tree = cargo(&["tree", "--edges", "normal", "--prefix", "none"]);
+    assert!(tree.contains("tokio"), "{}", tree);
+}
+
+/// The unit and integration tests build without tokio too, those needing it
+/// are left out along with the `server` feature
+#[test]
+fn test_tests_without_server() {
+    for features in ["std", "blocking"] {
+        cargo(&[
+            "test",
+            "--no-run",
+            "--no-default-features",
+            "--features",
+            features,
+        ]);
+    }
+}
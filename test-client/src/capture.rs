@@ -0,0 +1,336 @@
+//! Captures of the traffic between clients and the service
+//!
+//! A capture file is the MAGIC and VERSION (u16) followed by records, each
+//! prefixed by the length (u32) of what follows it:
+//! u8 direction | u64 timestamp, microseconds since the epoch
+//! | u32 connection | data
+//! All integers are big-endian, like the protocol's
+
+use crate::target::Target;
+use byteorder::{BigEndian, ByteOrder};
+use futures::StreamExt;
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufReader, Error, ErrorKind, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{io::AsyncWriteExt, time};
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+/// Starts every capture file, followed by its version
+pub const MAGIC: &[u8; 4] = b"SCAP";
+pub const VERSION: u16 = 1;
+pub const FILE_HEADER_SIZE: usize = 6;
+/// direction (1) + timestamp (8) + connection (4), the data follows
+pub const RECORD_HEADER_SIZE: usize = 13;
+/// How long a replayed request waits for the whole of its recorded response
+pub const REPLAY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Whether a record was sent to the service or received from it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Request = 0,
+    Response = 1,
+}
+
+impl Direction {
+    pub fn from_u8(n: u8) -> Option<Direction> {
+        match n {
+            0 => Some(Direction::Request),
+            1 => Some(Direction::Response),
+            _ => None,
+        }
+    }
+}
+
+/// The bytes sent to or received from the service at once on a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub direction: Direction,
+    pub timestamp: u64,
+    pub connection: u32,
+    pub data: Vec<u8>,
+}
+
+/// Appends records to a capture file, shared by all the clients recording
+#[derive(Debug, Clone)]
+pub struct CaptureWriter {
+    file: Arc<Mutex<File>>,
+}
+
+impl CaptureWriter {
+    /// Opens `path` for appending, a new or empty file is given the header
+    /// while an existing capture must be of this VERSION
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<CaptureWriter> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        if file.metadata()?.len() == 0 {
+            let mut header = [0u8; FILE_HEADER_SIZE];
+            header[..4].copy_from_slice(MAGIC);
+            BigEndian::write_u16(&mut header[4..], VERSION);
+            file.write_all(&header)?;
+        } else {
+            read_header(&mut file)?;
+        }
+        Ok(CaptureWriter {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Appends a record of `data`, timestamped now
+    pub fn append(&self, direction: Direction, connection: u32, data: &[u8]) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_micros() as u64);
+        let mut record = vec![0u8; 4 + RECORD_HEADER_SIZE];
+        BigEndian::write_u32(&mut record[..4], (RECORD_HEADER_SIZE + data.len()) as u32);
+        record[4] = direction as u8;
+        BigEndian::write_u64(&mut record[5..13], timestamp);
+        BigEndian::write_u32(&mut record[13..17], connection);
+        record.extend_from_slice(data);
+        // written at once so that concurrent connections never interleave
+        self.file.lock().unwrap().write_all(&record)
+    }
+}
+
+/// Reads back the records of a capture file
+pub struct CaptureReader<R> {
+    reader: R,
+    /// Set once a record cut short, e.g. by a client killed mid-write, ended
+    /// the capture
+    pub truncated: bool,
+}
+
+impl CaptureReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<CaptureReader<BufReader<File>>> {
+        CaptureReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut reader: R) -> Result<CaptureReader<R>> {
+        read_header(&mut reader)?;
+        Ok(CaptureReader {
+            reader,
+            truncated: false,
+        })
+    }
+
+    /// The next record, None at the end of the capture or of its last whole
+    /// record if the one after it is truncated
+    pub fn next_record(&mut self) -> Result<Option<Record>> {
+        let mut len = [0u8; 4];
+        match self.read_fully(&mut len)? {
+            0 => return Ok(None),
+            4 => (),
+            _ => return self.truncate(),
+        }
+        let len = BigEndian::read_u32(&len) as usize;
+        if len < RECORD_HEADER_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, "malformed record"));
+        }
+        let mut record = vec![0u8; len];
+        if self.read_fully(&mut record)? < len {
+            return self.truncate();
+        }
+        let direction = Direction::from_u8(record[0])
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "unknown record direction"))?;
+        Ok(Some(Record {
+            direction,
+            timestamp: BigEndian::read_u64(&record[1..9]),
+            connection: BigEndian::read_u32(&record[9..13]),
+            data: record.split_off(RECORD_HEADER_SIZE),
+        }))
+    }
+
+    /// Every whole record of the capture
+    pub fn read_all(&mut self) -> Result<Vec<Record>> {
+        let mut records = Vec::new();
+        while let Some(record) = self.next_record()? {
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    fn truncate(&mut self) -> Result<Option<Record>> {
+        self.truncated = true;
+        Ok(None)
+    }
+
+    /// Like read_exact, but returns how much was read when the end is reached
+    fn read_fully(&mut self, mut buf: &mut [u8]) -> Result<usize> {
+        let mut read = 0;
+        while !buf.is_empty() {
+            match self.reader.read(buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    read += n;
+                    buf = &mut buf[n..];
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(read)
+    }
+}
+
+/// Checks the file header, leaving `reader` at the first record
+fn read_header<S: Read>(reader: &mut S) -> Result<()> {
+    let mut header = [0u8; FILE_HEADER_SIZE];
+    reader
+        .read_exact(&mut header)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "not a capture file"))?;
+    if &header[..4] != MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "not a capture file"));
+    }
+    let version = BigEndian::read_u16(&header[4..]);
+    if version != VERSION {
+        let msg = format!("unsupported capture version {}", version);
+        return Err(Error::new(ErrorKind::InvalidData, msg));
+    }
+    Ok(())
+}
+
+/// A request along with the response recorded for it
+type Exchange = (Vec<u8>, Vec<u8>);
+
+/// A replayed request whose response differs from the recorded one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub connection: u32,
+    /// Position of the request among those of its connection
+    pub index: usize,
+    pub request: Vec<u8>,
+    pub expected: Vec<u8>,
+    pub received: Vec<u8>,
+}
+
+/// Replays the requests of `records` against `target`, one connection at a
+/// time, each on a connection of its own, and diffs the responses with the
+/// recorded ones. A request's response is whatever was recorded after it
+/// until the next request of its connection
+pub async fn replay(target: &Target, records: &[Record]) -> Result<Vec<Mismatch>> {
+    let mut connections: Vec<u32> = Vec::new();
+    let mut exchanges: HashMap<u32, Vec<Exchange>> = HashMap::new();
+    for record in records {
+        let exchanges = exchanges.entry(record.connection).or_insert_with(|| {
+            connections.push(record.connection);
+            Vec::new()
+        });
+        match (record.direction, exchanges.last_mut()) {
+            (Direction::Request, _) => exchanges.push((record.data.clone(), Vec::new())),
+            (Direction::Response, Some((_, response))) => response.extend(&record.data),
+            // a response before any request, nothing replayed would get it
+            (Direction::Response, None) => (),
+        }
+    }
+
+    let mut mismatches = Vec::new();
+    for connection in connections {
+        let (stream, _) = target.connect().await?;
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut frames = FramedRead::new(reader, BytesCodec::new());
+        for (index, (request, expected)) in exchanges[&connection].iter().enumerate() {
+            writer.write_all(request).await?;
+            let mut received = Vec::new();
+            while received.len() < expected.len() {
+                match time::timeout(REPLAY_TIMEOUT, frames.next()).await {
+                    Ok(Some(Ok(frame))) => received.extend(&frame[..]),
+                    _ => break,
+                }
+            }
+            if &received != expected {
+                mismatches.push(Mismatch {
+                    connection,
+                    index,
+                    request: request.clone(),
+                    expected: expected.clone(),
+                    received,
+                });
+            }
+        }
+    }
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("{}-{}.cap", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_write_read() {
+        let path = temp_path("test-write-read");
+        let _ = std::fs::remove_file(&path);
+        let writer = CaptureWriter::open(&path).unwrap();
+        writer.append(Direction::Request, 7, b"ping").unwrap();
+        writer.append(Direction::Response, 7, b"").unwrap();
+        drop(writer);
+        // reopening appends to the capture
+        let writer = CaptureWriter::open(&path).unwrap();
+        writer.append(Direction::Request, 8, b"stats").unwrap();
+
+        let mut reader = CaptureReader::open(&path).unwrap();
+        let records = reader.read_all().unwrap();
+        assert!(!reader.truncated);
+        let summary: Vec<_> = records
+            .iter()
+            .map(|r| (r.direction, r.connection, r.data.as_slice()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Direction::Request, 7, &b"ping"[..]),
+                (Direction::Response, 7, &b""[..]),
+                (Direction::Request, 8, &b"stats"[..]),
+            ]
+        );
+        assert!(records[0].timestamp > 0);
+        assert!(records[0].timestamp <= records[2].timestamp);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_truncated_record() {
+        let path = temp_path("test-truncated-record");
+        let _ = std::fs::remove_file(&path);
+        let writer = CaptureWriter::open(&path).unwrap();
+        writer.append(Direction::Request, 1, b"ping").unwrap();
+        writer.append(Direction::Response, 1, b"pong").unwrap();
+        let capture = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // cut anywhere in the last record, the first one is still read
+        let last = capture.len() - (4 + RECORD_HEADER_SIZE + 4);
+        for end in last + 1..capture.len() {
+            let mut reader = CaptureReader::new(Cursor::new(&capture[..end])).unwrap();
+            let records = reader.read_all().unwrap();
+            assert_eq!(records.len(), 1, "{}", end);
+            assert_eq!(records[0].data, b"ping");
+            assert!(reader.truncated);
+        }
+    }
+
+    #[test]
+    fn test_bad_header() {
+        assert!(CaptureReader::new(Cursor::new(b"SCAP")).is_err());
+        assert!(CaptureReader::new(Cursor::new(b"PCAP\x00\x01")).is_err());
+        let err = CaptureReader::new(Cursor::new(b"SCAP\x00\x02"))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "unsupported capture version 2");
+    }
+}
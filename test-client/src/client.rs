@@ -1,24 +1,286 @@
-use message::{Header, Message, Request, Response};
-use service::{message, State};
-
-use bytes::{Bytes, BytesMut};
-use futures::SinkExt;
-use std::{
-    io::{Error, ErrorKind},
-    net::SocketAddr,
-};
-use tokio::{net::TcpStream, stream::StreamExt};
+use message::{BatchEntries, Flag, GoodbyeReason, Header, Message, Request, ResetScope, Response};
+use service::{message, Feature, Goodbye, Limits, RatioPolicy, ServedRequests, ServerConfig};
+use service::{FlushPolicy, State, Stats};
+use service::{VersionedStats, STATS_LEN};
+
+use crate::capture::{CaptureWriter, Direction};
+use crate::pipeline::{Pipeline, DEFAULT_WINDOW};
+use crate::target::{Stream, Target};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::BytesMut;
+use futures::StreamExt;
+use std::{fmt, io::Error, time::Duration};
+use tokio::{io::AsyncWriteExt, time};
 use tokio_util::codec::{BytesCodec, Framed};
-use zerocopy::AsBytes;
+use zerocopy::{AsBytes, ByteSlice};
 
 type Result<T> = std::result::Result<T, std::io::Error>;
-type BytesFramed = Framed<TcpStream, BytesCodec>;
 
 /// For conducting dynamic testing of the service
 pub struct Client {
-    url: String,
+    target: Target,
     state: State,
     results: TestResults,
+    limits: Limits, // fetched on connect
+    capture: Option<CaptureWriter>,
+    ratio_policy: RatioPolicy, // the service's, for the expected GetStats
+    sequenced: bool,           // numbers requests if the service supports it
+    window: usize,             // requests in flight at once on a pipeline
+    flush_policy: FlushPolicy, // when a pipeline writes its requests
+    strict: bool,              // checks every response against the protocol
+    connection_per_test: bool, // runs each test on a new connection
+    reconnect_on_error: bool,  // reconnects after a test that ended one
+}
+
+/// A request the service answered with an error `Response`, along with why
+/// and the request it answered if the service sends error details
+/// (`Feature::ERROR_DETAILS`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceError {
+    pub response: Response,
+    request: Option<Request>,
+    detail: Option<String>,
+}
+
+impl ServiceError {
+    pub fn new(response: Response) -> ServiceError {
+        ServiceError {
+            response,
+            request: None,
+            detail: None,
+        }
+    }
+
+    /// The error of the response `message`. Its payload, if any, is the
+    /// detail in ASCII then the code of the request answered. An unknown
+    /// response code is an UnknownError
+    pub fn of<B: ByteSlice>(message: &Message<B>) -> ServiceError {
+        let response = Response::from_u16(message.header.code()).unwrap_or(Response::UnknownError);
+        let (request, detail) = match message.payload_slice().split_last() {
+            Some((&code, detail)) => (Request::from_u16(code as u16), detail),
+            None => (None, &[][..]),
+        };
+        let detail = (!detail.is_empty() && detail.is_ascii())
+            .then(|| String::from_utf8_lossy(detail).into_owned());
+        ServiceError {
+            request,
+            detail,
+            ..ServiceError::new(response)
+        }
+    }
+
+    /// The request the service was answering, `None` if it doesn't send
+    /// error details or couldn't tell, e.g. of an unknown request code
+    pub fn request(&self) -> Option<&Request> {
+        self.request.as_ref()
+    }
+
+    /// Why the service rejected the request, e.g. "header.size=5 but
+    /// payload=3 bytes"
+    pub fn detail(&self) -> Option<&str> {
+        self.detail.as_deref()
+    }
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "the service answered {:?}", self.response)?;
+        if let Some(request) = self.request() {
+            write!(fmt, " to {:?}", request)?;
+        }
+        match self.detail() {
+            Some(detail) => write!(fmt, " ({})", detail),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+/// A response that doesn't echo the sequence number of the request it
+/// answers, it can't be told which request it belongs to so the connection
+/// is broken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SequenceMismatch {
+    pub expected: u16,
+    /// `None` if the response isn't numbered
+    pub received: Option<u16>,
+}
+
+impl fmt::Display for SequenceMismatch {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.received {
+            Some(received) => write!(
+                fmt,
+                "response numbered {} to request {}",
+                received, self.expected
+            ),
+            None => write!(fmt, "unnumbered response to request {}", self.expected),
+        }
+    }
+}
+
+impl std::error::Error for SequenceMismatch {}
+
+/// A rule of the protocol a response breaks, see `Client::set_strict`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// Shorter than a header
+    TooSmall,
+    BadMagic,
+    /// Its payload, sequence number aside, is over the limit the service
+    /// advertised with GetConfig
+    TooLarge {
+        limit: u16,
+    },
+    /// Its payload isn't the size its header declares
+    SizeMismatch {
+        received: usize,
+    },
+    UnknownCode,
+    /// An error response with a payload from a service that doesn't send
+    /// error details (`Feature::ERROR_DETAILS`)
+    ErrorPayload,
+}
+
+/// A response that breaks the rules of the protocol, along with the fields
+/// of its header as received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolViolation {
+    pub violation: Violation,
+    /// Magic, size and code, `None` if the response is shorter than a header
+    pub header: Option<(u32, u16, u16)>,
+}
+
+impl fmt::Display for ProtocolViolation {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.header {
+            Some((sign, size, code)) => write!(
+                fmt,
+                "response (magic {:#010x}, size {}, code {}) ",
+                sign, size, code
+            )?,
+            None => write!(fmt, "response ")?,
+        }
+        match self.violation {
+            Violation::TooSmall => write!(fmt, "shorter than a header"),
+            Violation::BadMagic => write!(fmt, "has a bad magic"),
+            Violation::TooLarge { limit } => write!(fmt, "over the limit of {} bytes", limit),
+            Violation::SizeMismatch { received } => {
+                write!(fmt, "with a payload of {} bytes", received)
+            }
+            Violation::UnknownCode => write!(fmt, "of an unknown code"),
+            Violation::ErrorPayload => write!(fmt, "is an error with a payload"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolViolation {}
+
+/// The framed connection to the service, every frame sent or received is
+/// also appended to the capture when recording
+struct Tee<S> {
+    frames: Framed<S, BytesCodec>,
+    capture: Option<(CaptureWriter, u32)>,
+    /// Bytes read past the end of the last message, e.g. a Goodbye sent
+    /// right after a response
+    pending: Option<BytesMut>,
+    /// The number of the next request, `None` if requests aren't numbered
+    /// on this connection
+    sequence: Option<u16>,
+    /// The service closed the connection, with a Goodbye or without
+    closed: bool,
+    /// The send buffer, holding the request last sent
+    out: Vec<u8>,
+}
+
+impl<S: Stream> Tee<S> {
+    /// Sends `bytes` on the connection, see `send_numbered`
+    async fn send(&mut self, bytes: &[u8]) -> Result<()> {
+        self.send_numbered(bytes, None).await.map(|_| ())
+    }
+
+    /// Sends `query` numbered `sequence` if any, like `with_sequence` numbers
+    /// it, returning the bytes sent. The request is built in the send buffer
+    /// of the connection, reused from one request to the next, so a request
+    /// costs no allocation of its own once the buffer holds the largest
+    async fn send_numbered(&mut self, query: &[u8], sequence: Option<u16>) -> Result<usize> {
+        self.out.clear();
+        match (sequence, Message::parse(query)) {
+            (Some(sequence), Some(message)) => {
+                let header = &message.header;
+                let size = header.size().wrapping_add(message::SEQUENCE_LEN as u16);
+                let code = header.code() | (Flag::SEQUENCED as u16) << 8;
+                self.out
+                    .extend_from_slice(Header::raw(header.sign(), size, code).as_bytes());
+                self.out.extend_from_slice(&sequence.to_be_bytes());
+                self.out.extend_from_slice(message.payload_slice());
+            }
+            _ => self.out.extend_from_slice(query),
+        }
+        if let Some((capture, connection)) = &self.capture {
+            capture.append(Direction::Request, *connection, &self.out)?;
+        }
+        // nothing is ever left in the framed write buffer, the request is
+        // written to the stream as is
+        let stream = self.frames.get_mut();
+        stream.write_all(&self.out).await?;
+        stream.flush().await?;
+        Ok(self.out.len())
+    }
+
+    async fn next(&mut self) -> Option<Result<BytesMut>> {
+        if let Some(pending) = self.pending.take() {
+            return Some(Ok(pending));
+        }
+        let frame = self.frames.next().await;
+        if let (Some((capture, connection)), Some(Ok(frame))) = (&self.capture, &frame) {
+            if let Err(e) = capture.append(Direction::Response, *connection, frame) {
+                return Some(Err(e));
+            }
+        }
+        frame
+    }
+
+    /// The number of the next request, counting up from 1 and skipping 0
+    /// when it wraps around
+    fn next_sequence(&mut self) -> Option<u16> {
+        let sequence = self.sequence?;
+        self.sequence = Some(sequence.checked_add(1).unwrap_or(1));
+        Some(sequence)
+    }
+}
+
+/// The connections a client runs its test cases over, one after the other,
+/// numbered from 1 for the results
+struct Connections {
+    frames: Tee<Box<dyn Stream>>,
+    number: usize,
+    used: bool,  // a test ran on it
+    ended: bool, // the next test needs a new one
+}
+
+impl Connections {
+    fn new(frames: Tee<Box<dyn Stream>>) -> Connections {
+        Connections {
+            frames,
+            number: 1,
+            used: false,
+            ended: false,
+        }
+    }
+
+    /// Whether a test, on a connection of its own if `own`, needs a new one
+    fn needs_new(&self, own: bool) -> bool {
+        self.ended || (own && self.used)
+    }
+
+    fn replace(&mut self, frames: Tee<Box<dyn Stream>>) {
+        *self = Connections {
+            number: self.number + 1,
+            ..Connections::new(frames)
+        };
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -29,10 +291,61 @@ pub enum TestKind {
 
 #[derive(Debug, Clone)]
 pub struct Test {
+    /// Shown in the output instead of the test's position
+    pub name: String,
+    /// Families the test belongs to, e.g. "compress", "invalid", "boundary"
+    pub tags: Vec<&'static str>,
     pub query_kind: Request,
     pub query: Vec<u8>,
-    pub expected: Vec<u8>,
+    pub expected: Expectation,
     pub validity: TestKind,
+    /// Whether the test runs on a connection of its own, `None` as the
+    /// client has it, see `Client::set_connection_per_test`
+    pub own_connection: Option<bool>,
+}
+
+/// How long an `Expectation::Disconnect` or `Goodbye` waits for the service
+/// to close the connection
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a `Test` expects the service to do with its query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expectation {
+    /// The response, byte for byte. That of GetStats is checked against the
+    /// stats the client kept instead, and left empty
+    ResponseBytes(Vec<u8>),
+    /// A response of this code, whatever its payload
+    ResponseCode(Response),
+    /// The connection closed within `CLOSE_TIMEOUT`, with a Goodbye or
+    /// without, whatever was answered first
+    Disconnect,
+    /// A Goodbye of this reason within `CLOSE_TIMEOUT`, whatever was
+    /// answered first
+    Goodbye(GoodbyeReason),
+    /// Nothing at all for this long, the connection left open
+    NoResponseWithin(Duration),
+}
+
+impl From<Vec<u8>> for Expectation {
+    fn from(bytes: Vec<u8>) -> Expectation {
+        Expectation::ResponseBytes(bytes)
+    }
+}
+
+impl Expectation {
+    /// The response expected byte for byte, empty if not expected as bytes
+    pub fn bytes(&self) -> &[u8] {
+        match self {
+            Expectation::ResponseBytes(bytes) => bytes,
+            _ => &[],
+        }
+    }
+
+    /// Whether the service is expected to close the connection, the next
+    /// test is then run over a new one
+    pub fn closes(&self) -> bool {
+        matches!(self, Expectation::Disconnect | Expectation::Goodbye(_))
+    }
 }
 
 #[derive(Debug, Default)]
@@ -40,12 +353,49 @@ pub struct TestResults {
     count: usize,
     failed: usize,
     passed: usize,
+    goodbye: Option<GoodbyeReason>, // set if the service closed the connection
+    sequence_mismatch: Option<SequenceMismatch>, // set if it broke the connection
+    violations: usize,              // responses that broke the protocol, if strict
+    failures: Vec<String>,          // the name of each test failed, with why
+    connections: usize,             // opened to run the tests
+    ran_on: Vec<usize>,             // the connection of each test run, from 1
+}
+
+/// What the service sent back for a request
+#[derive(Debug)]
+pub enum Event {
+    Response(BytesMut),
+    /// The service closed the connection instead of answering
+    Goodbye(Goodbye),
+    Disconnected,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Response(frame) => match Message::parse(&frame[..]) {
+                Some(message) => write!(fmt, "a response of code {}", message.header.code()),
+                None => write!(fmt, "{} bytes", frame.len()),
+            },
+            Event::Goodbye(goodbye) => write!(fmt, "a Goodbye {:?}", goodbye.reason()),
+            Event::Disconnected => write!(fmt, "the connection closed"),
+        }
+    }
 }
 
 impl TestResults {
     pub fn inc_failed(&mut self) {
         self.failed += 1;
     }
+
+    /// Fails `test` for `reason`, which is logged and kept for the report
+    pub fn fail(&mut self, test: &Test, reason: &str) {
+        eprintln!("{}: {}", test.name, reason);
+        let summary = reason.lines().next().unwrap_or_default();
+        self.failures.push(format!("{}: {}", test.name, summary));
+        self.inc_failed();
+    }
+
     pub fn inc_passed(&mut self) {
         self.passed += 1;
     }
@@ -55,21 +405,91 @@ impl TestResults {
 }
 
 impl Client {
-    pub async fn new_with_url(url: String) -> Result<Client> {
+    pub async fn new_with_target(target: Target) -> Result<Client> {
         let state: State = Default::default();
         let results: TestResults = Default::default();
         Ok(Client {
-            url,
+            target,
             state,
             results,
+            limits: ServerConfig::default().limits(),
+            capture: None,
+            ratio_policy: Default::default(),
+            sequenced: true,
+            window: DEFAULT_WINDOW,
+            flush_policy: FlushPolicy::default(),
+            strict: false,
+            connection_per_test: false,
+            reconnect_on_error: false,
         })
     }
 
+    /// Runs each test case on a new connection rather than all of them on
+    /// one, so that a test ending its connection doesn't fail the rest. A
+    /// test may have it otherwise, see `Test::on_own_connection`. Off by
+    /// default
+    pub fn set_connection_per_test(&mut self, per_test: bool) {
+        self.connection_per_test = per_test;
+    }
+
+    /// Opens a new connection after a test the service ended the connection
+    /// of, unexpectedly or with a Goodbye, rather than failing the tests
+    /// after it on the closed one. Off by default
+    pub fn set_reconnect_on_error(&mut self, reconnect: bool) {
+        self.reconnect_on_error = reconnect;
+    }
+
+    /// Checks every response to a test case with the rules the service
+    /// applies to requests: its magic, its size against its header and the
+    /// `max_payload` advertised with GetConfig, and a known code. An error
+    /// response may only have a payload if the service sends error details.
+    /// A response breaking them fails its test with a `ProtocolViolation`,
+    /// counted in the results. Off by default
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Numbers the requests of each connection, if the service supports it,
+    /// and checks that every response echoes the number of its request. On
+    /// by default
+    pub fn set_sequenced(&mut self, sequenced: bool) {
+        self.sequenced = sequenced;
+    }
+
+    /// Lets the pipelines of this client have up to `window` requests in
+    /// flight at once, 1 by default waits for each response before sending
+    /// the next request
+    pub fn set_window(&mut self, window: usize) {
+        self.window = window;
+    }
+
+    /// Writes the requests of the pipelines of this client as `policy` has
+    /// it, each as soon as it's queued by default
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.flush_policy = policy;
+    }
+
+    /// A connection of its own on which requests are pipelined, up to the
+    /// client's window of them in flight at once
+    pub async fn pipeline(&self) -> Result<Pipeline> {
+        Pipeline::connect(&self.target, self.window, self.flush_policy).await
+    }
+
+    /// Expects the service to compute its ratio under `policy`
+    pub fn set_ratio_policy(&mut self, policy: RatioPolicy) {
+        self.ratio_policy = policy;
+    }
+
+    /// Records the traffic of this client's connection into `capture`
+    pub fn record_to(&mut self, capture: CaptureWriter) {
+        self.capture = Some(capture);
+    }
+
     pub async fn run_with(&mut self, i: usize, cases: Vec<Test>) -> Result<()> {
-        match TcpStream::connect(&self.url).await {
-            Ok(stream) => {
-                // println!("Client({}) @ {}", i, stream.local_addr()?);
-                if let Err(e) = self.process(i, stream, cases).await {
+        match self.target.connect().await {
+            Ok((stream, local)) => {
+                // println!("Client({}) @ {}", i, local);
+                if let Err(e) = self.process(i, stream, &local, cases).await {
                     eprintln!("{}", e)
                 }
                 Ok(())
@@ -81,60 +501,550 @@ impl Client {
         }
     }
 
-    fn update_ratio(state: &mut State, test: &Test) {
-        let message = Message::parse(&test.query[..]).unwrap();
-        if let Request::Compress = Request::from_u16(message.header.code()).unwrap() {
-            let compressed = Message::parse(&test.expected[..]).unwrap();
-            let total_len = message.payload.len();
-            let compressed_len = compressed.payload.len();
-            state.update_ratio(total_len, compressed_len);
+    /// Accounts for the compression of a Compress test the way the service
+    /// does, only a query that validates whole is trusted for its payload
+    fn update_ratio(state: &mut State, policy: &RatioPolicy, test: &Test) {
+        if let Ok((Request::Compress, payload)) = message::validate_wire(&test.query) {
+            let compressed = Message::parse(test.expected.bytes())
+                .map_or(0, |compressed| compressed.payload_slice().len());
+            state.update_ratio_with(policy, payload.len(), compressed);
         }
     }
 
-    fn show_overview(&self, i: usize, addr: SocketAddr) {
-        println!("Client({}) @ {:?} : {:?}", i, addr, self.results);
+    fn show_overview(&self, i: usize, local: &str) {
+        let stats = self.state.snapshot().stats;
+        println!("Client({}) @ {} : {:?} {}", i, local, self.results, stats);
         // for displaying client's state also
         // println!("Client({}) @ {:?} : {:?}\n{:?}", i, addr, self.results, self.state);
     }
 
-    async fn process(&mut self, i: usize, stream: TcpStream, cases: Vec<Test>) -> Result<()> {
-        let client_addr = stream.local_addr()?;
-        let mut frames = Framed::new(stream, BytesCodec::new());
-        for test in cases.iter() {
-            println!("({}) count({:?})", i, self.results.count);
-            if let Err(e) = self.process_test_case(&mut frames, test).await {
+    /// Runs the test cases over `stream`, whichever transport it uses, `local`
+    /// describing its end for the overview. A new connection is opened for a
+    /// test when the last one was closed as expected, ended with
+    /// `reconnect_on_error`, or the test runs on a connection of its own. A
+    /// connection the service rotates is evicted rather than failed, the test
+    /// it cut short is run again over a new one
+    async fn process(
+        &mut self,
+        i: usize,
+        stream: Box<dyn Stream>,
+        local: &str,
+        cases: Vec<Test>,
+    ) -> Result<()> {
+        let mut frames = self.frames(i, stream);
+        self.fetch_limits(&mut frames).await?;
+        let mut connections = Connections::new(frames);
+        let mut cases = cases.iter();
+        let mut next = cases.next();
+        while let Some(test) = next {
+            let own = test.own_connection.unwrap_or(self.connection_per_test);
+            if connections.needs_new(own) {
+                let frames = self.open(i).await?;
+                connections.replace(frames);
+            }
+            println!("({}) {} on connection {}", i, test.name, connections.number);
+            let before = self.state.clone();
+            connections.used = true;
+            let outcome = self.process_test_case(&mut connections.frames, test).await;
+            if let Ok(Some(goodbye)) = &outcome {
+                if goodbye.reason() == Some(GoodbyeReason::Rotated) {
+                    println!("({}) Rotated, reconnecting", i);
+                    self.state = before;
+                    connections.ended = true;
+                    continue;
+                }
+            }
+            self.results.ran_on.push(connections.number);
+            match outcome {
+                Ok(_) if test.expected.closes() => connections.ended = true,
+                Ok(Some(goodbye)) => {
+                    println!("({}) Goodbye {:?}", i, goodbye);
+                    self.results.goodbye = goodbye.reason();
+                    if !self.reconnect_on_error {
+                        break;
+                    }
+                    self.results.inc_count();
+                    let reason = format!("the service said Goodbye {:?}", goodbye.reason());
+                    self.results.fail(test, &reason);
+                }
+                Ok(None) => (),
                 // return error here to propogate forward otherwise just display test failure
-                eprintln!("{:?}", e);
+                Err(e) => {
+                    eprintln!("({}) {}: {:?}", i, test.name, e);
+                    let inner = e
+                        .get_ref()
+                        .and_then(|e| e.downcast_ref::<SequenceMismatch>());
+                    if let Some(mismatch) = inner {
+                        self.results.sequence_mismatch = Some(*mismatch);
+                        break;
+                    }
+                }
             }
+            if connections.frames.closed && self.reconnect_on_error {
+                connections.ended = true;
+            }
+            next = cases.next();
         }
-        self.show_overview(i, client_addr);
+        self.results.connections = connections.number;
+        self.show_overview(i, local);
         Ok(())
     }
 
-    async fn process_test_case(&mut self, frames: &mut BytesFramed, test: &Test) -> Result<()> {
+    /// A new connection to the service, its limits fetched
+    async fn open(&mut self, i: usize) -> Result<Tee<Box<dyn Stream>>> {
+        let (stream, _) = self.target.connect().await?;
+        let mut frames = self.frames(i, stream);
+        self.fetch_limits(&mut frames).await?;
+        Ok(frames)
+    }
+
+    fn frames(&self, i: usize, stream: Box<dyn Stream>) -> Tee<Box<dyn Stream>> {
+        Tee {
+            frames: Framed::new(stream, BytesCodec::new()),
+            capture: self.capture.clone().map(|capture| (capture, i as u32)),
+            pending: None,
+            sequence: None,
+            closed: false,
+            out: Vec::new(),
+        }
+    }
+
+    /// The limits of the service, over a connection of its own, so that the
+    /// test cases can be fitted to them before running
+    pub async fn query_limits(&mut self) -> Result<Limits> {
+        let (stream, _) = self.target.connect().await?;
+        let mut frames = Tee {
+            frames: Framed::new(stream, BytesCodec::new()),
+            capture: None,
+            pending: None,
+            sequence: None,
+            closed: false,
+            out: Vec::new(),
+        };
+        self.fetch_limits(&mut frames).await?;
+        Ok(self.limits.clone())
+    }
+
+    /// Compresses each of `payloads` with a single Batch request, over a
+    /// connection of its own, the result of each in the same order. The
+    /// stats expected of the service account for every one compressed
+    pub async fn compress_batch(
+        &mut self,
+        payloads: &[&[u8]],
+    ) -> Result<Vec<std::result::Result<Vec<u8>, ServiceError>>> {
+        let (stream, _) = self.target.connect().await?;
+        let mut frames = Tee {
+            frames: Framed::new(stream, BytesCodec::new()),
+            capture: None,
+            pending: None,
+            sequence: None,
+            closed: false,
+            out: Vec::new(),
+        };
+        let entries: Vec<_> = payloads.iter().map(|p| Test::request_compress(p)).collect();
+        let query = Test::request_batch(&entries);
+        frames.send(&query).await?;
+        self.state.update_read(query.len());
+        let frame = match Client::next_event(&mut frames).await {
+            Event::Response(frame) => frame,
+            _ => return Err(Error::other("Server Disconnected")),
+        };
+        self.state.update_sent(frame.len());
+
+        let response = Message::parse(&frame[..])
+            .ok_or_else(|| Error::other("response shorter than a header"))?;
+        match Response::from_u16(response.header.code()) {
+            Some(Response::Batch) => (),
+            Some(_) => return Err(Error::other(ServiceError::of(&response))),
+            None => return Err(Error::other("unknown response code")),
+        }
+        let mut results = Vec::with_capacity(payloads.len());
+        for (entry, payload) in BatchEntries::new(response.payload_slice()).zip(payloads) {
+            let entry = Message::parse(entry.map_err(Error::other)?)
+                .ok_or_else(|| Error::other("batch entry shorter than a header"))?;
+            let result = match Response::from_u16(entry.header.code()) {
+                Some(Response::Ok) => {
+                    let compressed = entry.payload_slice();
+                    let policy = &self.ratio_policy;
+                    self.state
+                        .update_ratio_with(policy, payload.len(), compressed.len());
+                    Ok(compressed.to_vec())
+                }
+                _ => Err(ServiceError::of(&entry)),
+            };
+            results.push(result);
+        }
+        if results.len() != payloads.len() {
+            let msg = format!("{} responses to {} entries", results.len(), payloads.len());
+            return Err(Error::other(msg));
+        }
+        Ok(results)
+    }
+
+    /// Compresses `payload` with a CompressWithStats request, over a
+    /// connection of its own, along with the stats the service reported
+    /// right after compressing it. A service telling it doesn't serve
+    /// CompressWithStats (`ServedRequests`) is sent a Compress then a
+    /// GetStats instead
+    pub async fn compress_with_stats(&mut self, payload: &[u8]) -> Result<(Vec<u8>, Stats)> {
+        let (stream, _) = self.target.connect().await?;
+        let mut frames = Tee {
+            frames: Framed::new(stream, BytesCodec::new()),
+            capture: None,
+            pending: None,
+            sequence: None,
+            closed: false,
+            out: Vec::new(),
+        };
+        let query = Test::request_bytes(Request::CompressWithStats, payload);
+        frames.send(&query).await?;
+        self.state.update_read(query.len());
+        let frame = match Client::next_event(&mut frames).await {
+            Event::Response(frame) => frame,
+            _ => return Err(Error::other("Server Disconnected")),
+        };
+        self.state.update_sent(frame.len());
+
+        let response = Message::parse(&frame[..])
+            .ok_or_else(|| Error::other("response shorter than a header"))?;
+        match Response::from_u16(response.header.code()) {
+            Some(Response::Ok) => (),
+            _ if Client::unserved(&response, Request::CompressWithStats) => {
+                return self.compress_then_get_stats(&mut frames, payload).await;
+            }
+            Some(_) => return Err(Error::other(ServiceError::of(&response))),
+            None => return Err(Error::other("unknown response code")),
+        }
+        let payload_len = response.payload_slice().len();
+        let (compressed, stats) = match payload_len.checked_sub(STATS_LEN) {
+            Some(offset) => response.payload_slice().split_at(offset),
+            None => return Err(Error::other("response shorter than its stats")),
+        };
+        let stats = Stats::parse_exact(stats).map_err(Error::other)?.clone();
+        self.state
+            .update_ratio_with(&self.ratio_policy, payload.len(), compressed.len());
+        Ok((compressed.to_vec(), stats))
+    }
+
+    /// `compress_with_stats` in two round trips on `frames`, for a service
+    /// without CompressWithStats. The stats it reports then account for the
+    /// Compress response too
+    async fn compress_then_get_stats<S: Stream>(
+        &mut self,
+        frames: &mut Tee<S>,
+        payload: &[u8],
+    ) -> Result<(Vec<u8>, Stats)> {
+        let compressed = self
+            .exchange(frames, &Test::request_compress(payload))
+            .await?;
+        let stats = self.exchange(frames, &Test::request_get_stats()).await?;
+        let stats = Stats::parse_exact(&stats[..])
+            .map_err(Error::other)?
+            .clone();
+        self.state
+            .update_ratio_with(&self.ratio_policy, payload.len(), compressed.len());
+        Ok((compressed, stats))
+    }
+
+    /// Sends `query` on `frames`, returning the payload of its Ok response
+    async fn exchange<S: Stream>(&mut self, frames: &mut Tee<S>, query: &[u8]) -> Result<Vec<u8>> {
+        frames.send(query).await?;
+        self.state.update_read(query.len());
+        let frame = match Client::next_event(frames).await {
+            Event::Response(frame) => frame,
+            _ => return Err(Error::other("Server Disconnected")),
+        };
+        self.state.update_sent(frame.len());
+        let response = Message::parse(&frame[..])
+            .ok_or_else(|| Error::other("response shorter than a header"))?;
+        match Response::from_u16(response.header.code()) {
+            Some(Response::Ok) => Ok(response.payload_slice().to_vec()),
+            Some(_) => Err(Error::other(ServiceError::of(&response))),
+            None => Err(Error::other("unknown response code")),
+        }
+    }
+
+    /// Whether `response` is an UnsupportedRequestType telling the service
+    /// doesn't serve `request`, see `ServerConfig::describe_unsupported`. A
+    /// bare one can't tell an old service from a request built wrong
+    fn unserved<B: ByteSlice>(response: &Message<B>, request: Request) -> bool {
+        response.header.code() == Response::UnsupportedRequestType as u16
+            && ServedRequests::parse(response.payload_slice())
+                .is_some_and(|served| !served.serves(request as u16))
+    }
+
+    /// Asks the service for its limits with GetConfig and sizes the read
+    /// buffer for its largest message, a service without GetConfig keeps the
+    /// default limits, or those it greeted the connection with. The requests
+    /// after it are numbered if the service supports it
+    async fn fetch_limits<S: Stream>(&mut self, frames: &mut Tee<S>) -> Result<()> {
+        let query = Test::request_get_config();
+        frames.send(&query[..]).await?;
+        self.state.update_read(query.len());
+        let mut greeted = false;
+        let frame = loop {
+            let frame = match Client::next_event(frames).await {
+                Event::Response(frame) => frame,
+                _ => return Err(Error::other("Server Disconnected")),
+            };
+            self.state.update_sent(frame.len());
+            // a Hello is only ever sent before any response
+            let hello = Message::parse(&frame[..])
+                .filter(|message| message.header.code() == Response::Hello as u16);
+            match hello {
+                Some(hello) if !greeted => {
+                    if let Some(limits) = Limits::parse(hello.payload_slice()) {
+                        self.limits = limits.clone();
+                    }
+                    greeted = true;
+                }
+                _ => break frame,
+            }
+        };
+        let response = Message::parse(&frame[..]).unwrap();
+        if response.header.code() == Response::Ok as u16 {
+            if let Some(limits) = Limits::parse(response.payload_slice()) {
+                self.limits = limits.clone();
+            }
+        }
+        let capacity = self.limits.max_message() as usize;
+        frames.frames.read_buffer_mut().reserve(capacity);
+        frames.out.reserve(capacity);
+        let numbered = self.sequenced && self.limits.features() & Feature::SEQUENCE != 0;
+        frames.sequence = if numbered { Some(1) } else { None };
+        Ok(())
+    }
+
+    /// Runs `test`, returning the service's Goodbye if it closed the
+    /// connection instead of answering. A response that doesn't echo the
+    /// number of a numbered request is a `SequenceMismatch`
+    async fn process_test_case<S: Stream>(
+        &mut self,
+        frames: &mut Tee<S>,
+        test: &Test,
+    ) -> Result<Option<Goodbye>> {
         if let TestKind::Valid = test.validity {
-            if test.query.len() >= message::HEADER_SIZE {
-                Client::update_ratio(&mut self.state, test);
+            Client::update_ratio(&mut self.state, &self.ratio_policy, test);
+        }
+        let sequence = if self.numbers(&test.query) {
+            frames.next_sequence()
+        } else {
+            None
+        };
+        let sent = frames.send_numbered(&test.query, sequence).await?;
+        self.state.update_read(sent);
+        match test.expected {
+            Expectation::Disconnect
+            | Expectation::Goodbye(_)
+            | Expectation::NoResponseWithin(_) => {
+                let verdict = self.await_outcome(frames, &test.expected).await;
+                self.results.inc_count();
+                match verdict {
+                    Ok(()) => self.results.inc_passed(),
+                    Err(reason) => self.results.fail(test, &reason),
+                }
+                return Ok(None);
             }
+            Expectation::ResponseBytes(_) | Expectation::ResponseCode(_) => (),
+        }
+        match Client::next_event(frames).await {
+            Event::Response(frame) => {
+                let len = frame.len();
+                if self.strict {
+                    if let Err(violation) = self.check_response(&frame[..]) {
+                        self.state.update_sent(len);
+                        self.results.inc_count();
+                        self.results.inc_failed();
+                        self.results.violations += 1;
+                        return Err(Error::other(violation));
+                    }
+                }
+                let frame = match sequence {
+                    Some(expected) => Client::unnumber(frame, expected).map_err(Error::other)?,
+                    None => frame,
+                };
+                let frame = self.undetailed(frame);
+                let stripped = frame.len();
+                self.handle_server_response(frame, test)?;
+                self.state.update_sent(len - stripped);
+                Ok(None)
+            }
+            Event::Goodbye(goodbye) => Ok(Some(goodbye)),
+            Event::Disconnected => Err(Error::other("Server Disconnected")),
+        }
+    }
+
+    /// Whether the service did what `expected` has it do rather than answer,
+    /// close the connection or leave it be. The responses sent before
+    /// closing it are accounted for but not checked
+    async fn await_outcome<S: Stream>(
+        &mut self,
+        frames: &mut Tee<S>,
+        expected: &Expectation,
+    ) -> std::result::Result<(), String> {
+        let wait = match expected {
+            Expectation::NoResponseWithin(wait) => *wait,
+            _ => CLOSE_TIMEOUT,
+        };
+        let deadline = time::Instant::now() + wait;
+        loop {
+            let event = time::timeout_at(deadline, Client::next_event(frames)).await;
+            let outcome = match (expected, event) {
+                (Expectation::NoResponseWithin(_), Err(_)) => return Ok(()),
+                (Expectation::NoResponseWithin(wait), Ok(event)) => {
+                    return Err(format!("expected nothing for {:?}, got {}", wait, event))
+                }
+                (_, Err(_)) => return Err(format!("still connected after {:?}", wait)),
+                (_, Ok(Event::Response(frame))) => {
+                    self.state.update_sent(frame.len());
+                    continue;
+                }
+                (_, Ok(event)) => event,
+            };
+            return match (expected, outcome) {
+                (Expectation::Goodbye(reason), Event::Goodbye(goodbye)) => match goodbye.reason() {
+                    Some(received) if received == *reason => Ok(()),
+                    received => Err(format!(
+                        "expected a Goodbye {:?}, got one {:?}",
+                        reason, received
+                    )),
+                },
+                (Expectation::Goodbye(reason), outcome) => {
+                    Err(format!("expected a Goodbye {:?}, got {}", reason, outcome))
+                }
+                _ => Ok(()),
+            };
+        }
+    }
+
+    /// Whether `frame`, as received, keeps to the protocol, see `set_strict`
+    fn check_response(&self, frame: &[u8]) -> std::result::Result<(), ProtocolViolation> {
+        let message = Message::parse(frame).ok_or(ProtocolViolation {
+            violation: Violation::TooSmall,
+            header: None,
+        })?;
+        let header = &message.header;
+        let violated = |violation| ProtocolViolation {
+            violation,
+            header: Some((header.sign(), header.size(), header.code())),
+        };
+        let received = frame.len() - message::HEADER_SIZE;
+        let numbered = message::sequence(frame).map_or(0, |_| message::SEQUENCE_LEN);
+        if header.sign() != message::MAGIC {
+            return Err(violated(Violation::BadMagic));
+        }
+        if received != header.size() as usize {
+            return Err(violated(Violation::SizeMismatch { received }));
         }
-        match frames.send(Bytes::copy_from_slice(&test.query[..])).await {
-            Ok(()) => {
-                self.state.update_read(test.query.len());
-                // // read next incomming message from socket
+        let limit = self.limits.max_payload();
+        if received - numbered > limit as usize {
+            return Err(violated(Violation::TooLarge { limit }));
+        }
+        let details = self.limits.features() & Feature::ERROR_DETAILS != 0;
+        match Response::from_u16(header.code() & message::CODE_MASK) {
+            None => Err(violated(Violation::UnknownCode)),
+            Some(response)
+                if !response.is_success()
+                    && response != Response::Goodbye
+                    && received > numbered
+                    && !details =>
+            {
+                Err(violated(Violation::ErrorPayload))
+            }
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Whether `query` can be numbered: the service only echoes the number
+    /// of a request it reads whole and whose magic it recognizes
+    fn numbers(&self, query: &[u8]) -> bool {
+        let max_message = self.limits.max_message() as usize;
+        let fits = (message::HEADER_SIZE..=max_message.saturating_sub(message::SEQUENCE_LEN))
+            .contains(&query.len());
+        fits && Message::parse(query).is_some_and(|query| query.header.sign() == message::MAGIC)
+    }
+
+    /// `frame` without the detail of an error response if the service sends
+    /// them, the bare header the tests expect
+    fn undetailed(&self, frame: BytesMut) -> BytesMut {
+        if self.limits.features() & Feature::ERROR_DETAILS == 0 {
+            return frame;
+        }
+        let (sign, code) = match Message::parse(&frame[..]) {
+            Some(message) => (message.header.sign(), message.header.code()),
+            None => return frame,
+        };
+        match Response::from_u16(code) {
+            Some(response) if !response.is_success() && response != Response::Goodbye => {
+                let bare = Header::raw(sign, 0, code);
+                BytesMut::from(bare.as_bytes())
+            }
+            _ => frame,
+        }
+    }
+
+    /// `frame` without the number it echoes, which must be `expected`. Only
+    /// ServerBusy is sent before the request is read, it's never numbered
+    fn unnumber(frame: BytesMut, expected: u16) -> std::result::Result<BytesMut, SequenceMismatch> {
+        let received = match message::without_sequence(&frame[..]) {
+            Some((received, plain)) if received == expected => {
+                return Ok(BytesMut::from(&plain[..]))
+            }
+            Some((received, _)) => Some(received),
+            None => None,
+        };
+        let response = Message::parse(&frame[..]).and_then(|m| Response::from_u16(m.header.code()));
+        match (received, response) {
+            (None, Some(Response::ServerBusy)) => Ok(frame),
+            _ => Err(SequenceMismatch { expected, received }),
+        }
+    }
+
+    /// Reads the next message from the service, noting on `frames` if it
+    /// closed the connection
+    async fn next_event<S: Stream>(frames: &mut Tee<S>) -> Event {
+        let event = Client::read_event(frames).await;
+        if let Event::Goodbye(_) | Event::Disconnected = event {
+            frames.closed = true;
+        }
+        event
+    }
+
+    async fn read_event<S: Stream>(frames: &mut Tee<S>) -> Event {
+        let mut frame = match frames.next().await {
+            Some(Ok(frame)) if !frame.is_empty() => frame,
+            _ => return Event::Disconnected,
+        };
+        // a large response may take several reads to arrive, and a read may
+        // hold the start of the next message
+        if frame.len() >= message::HEADER_SIZE {
+            let len = message::total_response_len(BigEndian::read_u16(&frame[4..6]) as usize);
+            while frame.len() < len {
                 match frames.next().await {
-                    Some(Ok(frame)) if frame.is_empty() => Ok(()), // disconnected
-                    Some(Ok(frame)) => self.handle_server_response(frame, test),
-                    _ => Err(Error::new(ErrorKind::Other, "Server Disconnected")),
+                    Some(Ok(more)) if !more.is_empty() => frame.extend_from_slice(&more),
+                    _ => return Event::Disconnected,
                 }
             }
-            Err(e) => Err(e),
+            if frame.len() > len {
+                frames.pending = Some(frame.split_off(len));
+            }
         }
+        let message = match Message::parse(&frame[..]) {
+            Some(message) => message,
+            None => return Event::Response(frame),
+        };
+        if Response::from_u16(message.header.code()) == Some(Response::Goodbye) {
+            if let Some(goodbye) = Goodbye::parse(message.payload_slice()) {
+                return Event::Goodbye(goodbye.clone());
+            }
+        }
+        Event::Response(frame)
     }
 
     fn handle_server_response(&mut self, response: BytesMut, test: &Test) -> Result<()> {
         let bytes_read = response.len();
         match test.query_kind {
-            Request::GetStats => self.handle_get_stats(response, test),
+            Request::GetStats | Request::GetStatsV2 => self.handle_get_stats(response, test),
             Request::ResetStats => self.handle_reset_stats(response, test),
             _ => self.handle_other_requests(response, test),
         }
@@ -148,134 +1058,205 @@ impl Client {
         let stats = self.state.stats_as_bytes();
         match Client::validate_getstats(&test.query[..], &response[..], stats) {
             Ok(()) => self.results.inc_passed(),
-            Err(e) => {
-                eprintln!("{}", e);
-                self.results.inc_failed();
-            }
+            Err(e) => self.results.fail(test, &e.to_string()),
         }
     }
 
+    /// Only a global reset clears the stats GetStats reports, an empty
+    /// ResetStats resets them only if the service allows global resets, so
+    /// the expected stats are kept as is
     fn handle_reset_stats(&mut self, response: BytesMut, test: &Test) {
-        self.state.reset();
+        let query = Message::parse(&test.query[..]).unwrap();
+        let global = query.payload_slice() == [ResetScope::Global as u8];
+        if global && response[..] == Test::response_reset_stats()[..] {
+            self.state.reset();
+        }
         self.handle_other_requests(response, test)
     }
 
     fn handle_other_requests(&mut self, response: BytesMut, test: &Test) {
-        match Client::validate_messages(&response[..], &test.expected[..]) {
+        let verdict = match &test.expected {
+            Expectation::ResponseCode(code) => Client::validate_code(&response[..], *code),
+            expected => Client::validate_messages(&response[..], expected.bytes()),
+        };
+        match verdict {
             Ok(()) => self.results.inc_passed(),
-            Err(e) => {
-                eprintln!("{}", e);
-                self.results.inc_failed();
-            }
+            Err(e) => self.results.fail(test, &e.to_string()),
+        }
+    }
+
+    /// The stats of a response to `request`, GetStats or GetStatsV2, of
+    /// whichever layout version the response is in
+    pub fn parse_stats(request: &Request, payload: &[u8]) -> Result<VersionedStats> {
+        match request {
+            Request::GetStats => Stats::parse_exact(payload)
+                .map(|stats| VersionedStats::V1(stats.clone()))
+                .map_err(Error::other),
+            Request::GetStatsV2 => Stats::parse_versioned(payload).map_err(Error::other),
+            _ => Err(Error::other("Client Error: Request is not GetStats")),
         }
     }
 
     fn validate_getstats(query: &[u8], response: &[u8], stats: &[u8]) -> Result<()> {
-        let query = Message::parse(&query[..]).unwrap();
-        let response = Message::parse(&response[..]).unwrap();
+        let query = Message::parse(query).unwrap();
+        let response = Message::parse(response).unwrap();
         // println!("{:?}", response);
-        if Request::from_u16(query.header.code()).unwrap() != Request::GetStats {
-            return Err(Error::new(
-                ErrorKind::Other,
-                "Client Error: Request is not GetStats",
-            ));
+        let request = Request::from_u16(query.header.code()).unwrap();
+        let expected = Stats::parse(stats).unwrap();
+        let received = Client::parse_stats(&request, response.payload_slice());
+        let matches = received.is_ok_and(|received| {
+            (received.read(), received.sent(), received.ratio())
+                == (
+                    expected.read() as u64,
+                    expected.sent() as u64,
+                    expected.ratio(),
+                )
+        });
+        if !matches {
+            let msg: String = format!(
+                "Error: Validating GetStats Request:\nreceived\n{}expected\n{}",
+                response.hexdump(),
+                message::hexdump(stats, message::HEXDUMP_DEFAULT_ROWS)
+            );
+            return Err(Error::other(msg));
         }
-        if response.payload != stats {
+        Ok(())
+    }
+
+    fn validate_code(pack: &[u8], code: Response) -> Result<()> {
+        let received = Message::parse(pack).map(|message| message.header.code());
+        if received != Some(code as u16) {
             let msg: String = format!(
-                "Error: Validating GetStats Request:\nreceived {:?}\nexpected {:?}\n",
-                response.payload, stats
+                "Error: Expected a response of {:?} ({})\nreceived:\n{}",
+                code,
+                code as u16,
+                message::hexdump(pack, message::HEXDUMP_DEFAULT_ROWS)
             );
-            return Err(Error::new(ErrorKind::Other, msg));
+            return Err(Error::other(msg));
         }
         Ok(())
     }
 
     fn validate_messages(pack: &[u8], test: &[u8]) -> Result<()> {
-        let pack_message = Message::parse(&pack[..]).unwrap();
-        let test_message = Message::parse(&test[..]).unwrap();
+        let (pack_message, test_message) = match (Message::parse(pack), Message::parse(test)) {
+            (Some(pack_message), Some(test_message)) => (pack_message, test_message),
+            _ => {
+                let msg: String = format!(
+                    "Error: Message shorter than a header\nreceived:\n{}expected:\n{}",
+                    message::hexdump(pack, message::HEXDUMP_DEFAULT_ROWS),
+                    message::hexdump(test, message::HEXDUMP_DEFAULT_ROWS)
+                );
+                return Err(Error::other(msg));
+            }
+        };
         if pack_message.header.as_bytes() != test_message.header.as_bytes() {
             let msg: String = format!(
-                "Error: Headers not equal\nreceived: {:?}\nexpected: {:?}\n",
-                pack_message.header.as_bytes(),
-                test_message.header.as_bytes()
+                "Error: Headers not equal\nreceived:\n{}expected:\n{}",
+                pack_message.hexdump(),
+                test_message.hexdump()
             );
-            return Err(Error::new(ErrorKind::Other, msg));
+            return Err(Error::other(msg));
         }
         if pack[..] != test[..] {
             let msg: String = format!(
-                "Error: Payloads not equal\nreceived: {:?}\nexpected: {:?}",
-                pack, test
+                "Error: Payloads not equal\nreceived:\n{}expected:\n{}",
+                message::hexdump(pack, message::HEXDUMP_DEFAULT_ROWS),
+                message::hexdump(test, message::HEXDUMP_DEFAULT_ROWS)
             );
-            return Err(Error::new(ErrorKind::Other, msg));
+            return Err(Error::other(msg));
         }
         Ok(())
     }
 }
 
 impl Test {
-    // arbitrarily large to allow testing total message size larger than MAX_MESSAGE
-    const FULL_BUFF: usize = (message::MAX_MESSAGE_PADDED * 2) + 12;
-
-    pub fn message_bytes(sign: u32, size: u16, code: u16, msg: &[u8]) -> Result<Vec<u8>> {
-        let mut buf = [0u8; Test::FULL_BUFF];
-        match msg.len() {
-            n if n > Test::FULL_BUFF => Err(Error::new(ErrorKind::Other, "payload is too large")),
-            n => {
-                Message::parse_mut(&mut buf[..])
-                    .unwrap()
-                    .set_all(sign, size, code, msg);
-                Ok(buf[..message::total_response_len(n)].to_vec())
-            }
-        }
+    pub fn tagged(mut self, tag: &'static str) -> Test {
+        self.tags.push(tag);
+        self
     }
 
-    pub fn message_default(code: u16, bytes: &[u8]) -> Vec<u8> {
-        Test::message_bytes(message::MAGIC, bytes.len() as u16, code, bytes).unwrap()
+    /// Runs the test on a connection of its own if `own`, on the one the
+    /// test before it ran on if not, whatever the client has it
+    pub fn on_own_connection(mut self, own: bool) -> Test {
+        self.own_connection = Some(own);
+        self
     }
 
+    /// Any header, including those the service rejects, see `Header::raw`
     pub fn header_bytes(sign: u32, size: u16, code: u16) -> Vec<u8> {
-        Header::new_with(sign, size, code).as_bytes().to_vec()
+        Header::raw(sign, size, code).as_bytes().to_vec()
     }
 
-    pub fn header_default(code: u16) -> Vec<u8> {
-        Test::header_bytes(message::MAGIC, 0, code)
+    /// `request` with `payload`. A payload over MAX_PAYLOAD, only ever sent
+    /// for the service to reject it, goes under a raw header of the same
+    /// fields
+    pub fn request_bytes(request: Request, payload: &[u8]) -> Vec<u8> {
+        let size = payload.len() as u16;
+        let header = Header::request(request.clone(), size)
+            .unwrap_or_else(|_| Header::raw(message::MAGIC, size, request as u16));
+        [header.as_bytes(), payload].concat()
+    }
+
+    /// `response` with `payload`, the service never sends one over
+    /// MAX_PAYLOAD
+    pub fn response_bytes(response: Response, payload: &[u8]) -> Vec<u8> {
+        let header = Header::response(response, payload.len() as u16).unwrap();
+        [header.as_bytes(), payload].concat()
     }
 
     pub fn response_fail(response: Response) -> Vec<u8> {
-        Test::header_default(response as u16)
+        Test::response_bytes(response, b"")
     }
 
     pub fn request_ping() -> Vec<u8> {
-        Test::header_default(Request::Ping as u16)
+        Test::request_bytes(Request::Ping, b"")
     }
 
     pub fn response_ping() -> Vec<u8> {
-        Test::header_default(Response::Ok as u16)
+        Test::response_bytes(Response::Ok, b"")
     }
 
     pub fn request_reset_stats() -> Vec<u8> {
-        Test::header_default(Request::ResetStats as u16)
+        Test::request_bytes(Request::ResetStats, b"")
+    }
+
+    pub fn request_reset_stats_scope(scope: ResetScope) -> Vec<u8> {
+        Test::request_bytes(Request::ResetStats, &[scope as u8])
     }
 
     pub fn response_reset_stats() -> Vec<u8> {
-        Test::header_default(Response::Ok as u16)
+        Test::response_bytes(Response::Ok, b"")
     }
 
     pub fn request_get_stats() -> Vec<u8> {
-        Test::header_default(Request::GetStats as u16)
+        Test::request_bytes(Request::GetStats, b"")
+    }
+
+    pub fn request_get_stats_v2() -> Vec<u8> {
+        Test::request_bytes(Request::GetStatsV2, b"")
     }
 
     #[allow(unused)]
     pub fn response_get_stats(stats: &[u8]) -> Vec<u8> {
-        Test::message_default(Response::Ok as u16, stats)
+        Test::response_bytes(Response::Ok, stats)
+    }
+
+    pub fn request_get_config() -> Vec<u8> {
+        Test::request_bytes(Request::GetConfig, b"")
+    }
+
+    /// A Batch of the whole messages of `entries`
+    pub fn request_batch(entries: &[Vec<u8>]) -> Vec<u8> {
+        let payload = message::encode_batch(entries.iter().map(|e| &e[..]));
+        Test::request_bytes(Request::Batch, &payload)
     }
 
     pub fn request_compress(payload: &[u8]) -> Vec<u8> {
-        Test::message_default(Request::Compress as u16, payload)
+        Test::request_bytes(Request::Compress, payload)
     }
 
     pub fn response_compress(bytes: &[u8]) -> Vec<u8> {
-        Test::message_default(Response::Ok as u16, bytes)
+        Test::response_bytes(Response::Ok, bytes)
     }
 }
 
@@ -288,31 +1269,947 @@ impl Test {
 // aaaccddddhhhhi => 3acc4d4hi
 pub fn test_compress_ok(request: &[u8], response: &[u8]) -> Test {
     Test {
+        name: format!("compress {}", payload_name(request)),
+        tags: vec!["compress", "valid"],
         query_kind: Request::Compress,
         query: Test::request_compress(request),
-        expected: Test::response_compress(response),
+        expected: Test::response_compress(response).into(),
         validity: TestKind::Valid,
+        own_connection: None,
     }
 }
 
-/// TODO:
-/// add response argument if other unique character types error messages added
-/// i.e. Resopnse::MessageContainsNumbers,
-/// or, Resopnse::MessageContainsUppercaseCharacters
+/// A compress request expected to be rejected with the specific `response`
+/// i.e. Response::MessageContainsDigits,
+/// or, Response::MessageContainsUppercaseCharacters
 pub fn test_compress_fail(request: &[u8], response: Response) -> Test {
     Test {
+        name: format!("compress {} fails {:?}", payload_name(request), response),
+        tags: vec!["compress", "invalid"],
         query_kind: Request::Compress,
         query: Test::request_compress(request),
-        expected: Test::response_fail(response),
+        expected: Test::response_fail(response).into(),
         validity: TestKind::Invalid,
+        own_connection: None,
     }
 }
 
+/// A compress request expected to be rejected with the generic
+/// `MessagePayloadContainsInvalidCharacters` (e.g. mixed kinds of invalid characters)
 pub fn test_compress_fail_default(request: &[u8]) -> Test {
     Test {
+        name: format!("compress {} fails", payload_name(request)),
+        tags: vec!["compress", "invalid"],
         query_kind: Request::Compress,
         query: Test::request_compress(request),
-        expected: Test::response_fail(Response::MessagePayloadContainsInvalidCharacters),
+        expected: Test::response_fail(Response::MessagePayloadContainsInvalidCharacters).into(),
         validity: TestKind::Invalid,
+        own_connection: None,
+    }
+}
+
+/// Compress requests at the edges of the payload limit, one byte under it,
+/// at it and one byte over it (MessageTooLarge). Each of the payloads that fit
+/// is tried as a single run, compressed to e.g. "8192a", and alternating
+/// characters, which don't compress at all and so make for the largest
+/// response
+pub fn boundary_cases(limits: &Limits) -> Vec<Test> {
+    let max = limits.max_payload() as usize;
+    let mut cases = Vec::new();
+    for len in [max - 1, max] {
+        let run = vec![b'a'; len];
+        cases.push(Test {
+            name: format!("compress run of {}", len),
+            ..test_compress_ok(&run, format!("{}a", len).as_bytes()).tagged("boundary")
+        });
+        let alternating: Vec<u8> = b"ab".iter().copied().cycle().take(len).collect();
+        cases.push(Test {
+            name: format!("compress {} alternating", len),
+            ..test_compress_ok(&alternating, &alternating).tagged("boundary")
+        });
+    }
+    let over = vec![b'a'; max + 1];
+    cases.push(Test {
+        name: format!("compress run of {} fails", max + 1),
+        ..test_compress_fail(&over, Response::MessageTooLarge).tagged("boundary")
+    });
+    cases
+}
+
+/// A payload as it appears in test names, long ones are cut short
+fn payload_name(payload: &[u8]) -> String {
+    const SHOWN: usize = 16;
+    let shown = payload[..payload.len().min(SHOWN)].escape_ascii();
+    match payload.len() {
+        n if n > SHOWN => format!("\"{}\"... ({} bytes)", shown, n),
+        _ => format!("\"{}\"", shown),
+    }
+}
+
+/// Selects tests by name and tags
+#[derive(Debug, Default, Clone)]
+pub struct Filter {
+    /// Part of the names of the tests selected
+    pub name: Option<String>,
+    /// Tags the tests selected all have
+    pub tags: Vec<String>,
+}
+
+impl Filter {
+    pub fn matches(&self, test: &Test) -> bool {
+        let named = match &self.name {
+            Some(name) => test.name.contains(name.as_str()),
+            None => true,
+        };
+        named
+            && self
+                .tags
+                .iter()
+                .all(|tag| test.tags.contains(&tag.as_str()))
+    }
+
+    /// The tests matching the filter, in order
+    pub fn select(&self, tests: Vec<Test>) -> Vec<Test> {
+        tests
+            .into_iter()
+            .filter(|test| self.matches(test))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{self, CaptureReader};
+    use service::Server;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::{net::TcpListener, sync::Mutex};
+
+    fn ping_test() -> Test {
+        Test {
+            name: "ping".to_string(),
+            tags: vec!["ping"],
+            query_kind: Request::Ping,
+            query: Test::request_ping(),
+            expected: Test::response_ping().into(),
+            validity: TestKind::Valid,
+            own_connection: None,
+        }
+    }
+
+    /// Runs a Ping over `target`, returning the client's results
+    async fn ping(target: Target) -> TestResults {
+        let mut client = Client::new_with_target(target).await.unwrap();
+        client.run_with(0, vec![ping_test()]).await.unwrap();
+        client.results
+    }
+
+    #[test]
+    fn test_select() {
+        let tests = vec![
+            test_compress_ok(b"aaa", b"3a"),
+            test_compress_fail(b"abC", Response::MessageContainsUppercaseCharacters)
+                .tagged("boundary"),
+            test_compress_fail_default(b"a b").tagged("boundary"),
+        ];
+        let names = |filter: Filter| -> Vec<String> {
+            filter
+                .select(tests.clone())
+                .into_iter()
+                .map(|test| test.name)
+                .collect()
+        };
+        assert_eq!(names(Filter::default()).len(), 3);
+        assert_eq!(
+            names(Filter {
+                name: Some("abC".to_string()),
+                ..Default::default()
+            }),
+            vec!["compress \"abC\" fails MessageContainsUppercaseCharacters"]
+        );
+        assert_eq!(
+            names(Filter {
+                tags: vec!["invalid".to_string()],
+                ..Default::default()
+            })
+            .len(),
+            2
+        );
+        // every tag must match, as must the name
+        let filter = Filter {
+            name: Some("fails".to_string()),
+            tags: vec!["boundary".to_string(), "compress".to_string()],
+        };
+        assert_eq!(names(filter).len(), 2);
+        let filter = Filter {
+            name: Some("3a".to_string()),
+            tags: vec!["boundary".to_string()],
+        };
+        assert!(names(filter).is_empty());
+        let filter = Filter {
+            tags: vec!["slow".to_string()],
+            ..Default::default()
+        };
+        assert!(names(filter).is_empty());
+    }
+
+    #[test]
+    fn test_boundary_cases() {
+        let limits = Limits::new_with(1, 0, 16, 24, 0, 0);
+        let cases = boundary_cases(&limits);
+        let sizes: Vec<usize> = cases
+            .iter()
+            .map(|test| test.query.len() - message::HEADER_SIZE)
+            .collect();
+        assert_eq!(sizes, vec![15, 15, 16, 16, 17]);
+        assert_eq!(cases[2].name, "compress run of 16");
+        assert_eq!(cases[2].expected.bytes(), Test::response_compress(b"16a"));
+        assert_eq!(
+            cases[3].expected.bytes(),
+            Test::response_compress(b"abababababababab")
+        );
+        assert_eq!(
+            cases[4].expected.bytes(),
+            Test::response_fail(Response::MessageTooLarge)
+        );
+        assert!(cases.iter().all(|test| test.tags.contains(&"boundary")));
+    }
+
+    #[tokio::test]
+    async fn test_send_buffer_reused() {
+        let (stream, mut service) = tokio::io::duplex(64 * 1024);
+        let client = Client::new_with_target(Target::Tcp(([127, 0, 0, 1], 0).into()))
+            .await
+            .unwrap();
+        let mut frames = client.frames(0, Box::new(stream));
+        frames.out.reserve(message::MAX_MESSAGE_PADDED);
+        let capacity = frames.out.capacity();
+        let compress = Test::request_compress(b"aaabbb");
+        for sequence in 1..=100 {
+            let numbered = (sequence % 2 == 0).then_some(sequence);
+            let sent = frames.send_numbered(&compress, numbered).await.unwrap();
+            assert_eq!(frames.out.capacity(), capacity);
+            let mut received = vec![0u8; sent];
+            service.read_exact(&mut received).await.unwrap();
+            let expected = match numbered {
+                Some(sequence) => message::with_sequence(&compress, sequence),
+                None => compress.clone(),
+            };
+            assert_eq!(received, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_boundary_cases_against_server() {
+        let target = serve(ServerConfig::default()).await;
+        let mut client = Client::new_with_target(target).await.unwrap();
+        let limits = client.query_limits().await.unwrap();
+        assert_eq!(limits.max_payload(), message::MAX_PAYLOAD);
+        client.run_with(1, boundary_cases(&limits)).await.unwrap();
+        let results = &client.results;
+        assert_eq!((results.count, results.passed, results.failed), (5, 5, 0));
+    }
+
+    #[tokio::test]
+    async fn test_error_details() {
+        let config = ServerConfig {
+            error_details: true,
+            ..Default::default()
+        };
+        let target = serve(config).await;
+        let mut client = Client::new_with_target(target).await.unwrap();
+        let limits = client.query_limits().await.unwrap();
+        assert_ne!(limits.features() & Feature::ERROR_DETAILS, 0);
+        // the errors expected as bare headers still pass, and the stats
+        // expected account for the details sent
+        let get_stats = Test {
+            name: "get stats".to_string(),
+            tags: vec!["stats"],
+            query_kind: Request::GetStats,
+            query: Test::request_get_stats(),
+            expected: Vec::new().into(),
+            validity: TestKind::Valid,
+            own_connection: None,
+        };
+        let mut tests = boundary_cases(&limits);
+        tests.push(get_stats);
+        client.run_with(1, tests).await.unwrap();
+        let results = &client.results;
+        assert_eq!((results.count, results.passed, results.failed), (6, 6, 0));
+
+        let empty = client.compress_with_stats(b"").await.unwrap_err();
+        let service_error = empty.get_ref().unwrap().downcast_ref::<ServiceError>();
+        let service_error = service_error.unwrap();
+        assert_eq!(
+            service_error.response,
+            Response::CompressionRequestRequiresNonZeroLength
+        );
+        let detail = "header.size=0 but CompressWithStats requires a payload";
+        assert_eq!(service_error.detail(), Some(detail));
+        assert_eq!(service_error.request(), Some(&Request::CompressWithStats));
+        assert_eq!(
+            service_error.to_string(),
+            format!(
+                "the service answered CompressionRequestRequiresNonZeroLength to \
+                 CompressWithStats ({})",
+                detail
+            )
+        );
+
+        // the request can't be told of an unknown code, nor without details
+        let unknown = Test::response_bytes(
+            Response::UnsupportedRequestType,
+            b"request code 99 is unknown\x00",
+        );
+        let unknown = ServiceError::of(&Message::parse(&unknown[..]).unwrap());
+        assert_eq!(unknown.request(), None);
+        assert_eq!(unknown.detail(), Some("request code 99 is unknown"));
+        let bare = Test::response_fail(Response::UnsupportedRequestType);
+        let bare = ServiceError::of(&Message::parse(&bare[..]).unwrap());
+        assert_eq!(bare, ServiceError::new(Response::UnsupportedRequestType));
+    }
+
+    #[test]
+    fn test_malformed_messages() {
+        // a response shorter than a header fails the test rather than the
+        // client
+        let expected = Test::response_fail(Response::MessageTooSmall);
+        assert!(Client::validate_messages(b"STRY", &expected).is_err());
+        assert!(Client::validate_messages(&expected, &expected).is_ok());
+
+        // only a Compress that validates whole counts towards the ratio
+        let mut state = State::new();
+        let policy = RatioPolicy::Cumulative;
+        let mut lying = test_compress_ok(b"aaaa", b"4a");
+        lying.query[5] = 9;
+        Client::update_ratio(&mut state, &policy, &lying);
+        assert_eq!(state.snapshot().stats.ratio(), 0);
+        Client::update_ratio(&mut state, &policy, &test_compress_ok(b"aaaa", b"4a"));
+        assert_eq!(state.snapshot().stats.ratio(), 50);
+    }
+
+    #[test]
+    fn test_payload_name() {
+        assert_eq!(payload_name(b"ab\xFF"), "\"ab\\xff\"");
+        assert_eq!(
+            payload_name(&[b'a'; 20]),
+            "\"aaaaaaaaaaaaaaaa\"... (20 bytes)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ping_over_tcp() {
+        let server = Server::new_with_url("127.0.0.1:0").await.unwrap().spawn();
+        let addr = server.local_addr();
+
+        let results = ping(Target::Tcp(addr)).await;
+        assert_eq!((results.count, results.passed, results.failed), (1, 1, 0));
+        let results = ping(format!("localhost:{}", addr.port()).parse().unwrap()).await;
+        assert_eq!((results.count, results.passed, results.failed), (1, 1, 0));
+    }
+
+    /// Starts a fake service answering GetConfig with the default limits and
+    /// every other request with `reply` of it, returning its target
+    async fn fake(reply: fn(&[u8]) -> Vec<u8>) -> Target {
+        fake_with(reply, false).await
+    }
+
+    /// `fake`, closing each connection after its reply if `closes`
+    async fn fake_with(reply: fn(&[u8]) -> Vec<u8>, closes: bool) -> Target {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 64];
+                while let Ok(len @ 1..) = stream.read(&mut buf).await {
+                    let request = &buf[..len];
+                    let (response, close) =
+                        match Request::from_u16(BigEndian::read_u16(&request[6..8])) {
+                            Some(Request::GetConfig) => {
+                                let limits = ServerConfig::default().limits();
+                                (Test::response_bytes(Response::Ok, limits.as_bytes()), false)
+                            }
+                            _ => (reply(request), closes),
+                        };
+                    stream.write_all(&response).await.unwrap();
+                    if close {
+                        break;
+                    }
+                }
+            }
+        });
+        Target::Tcp(addr)
+    }
+
+    #[tokio::test]
+    async fn test_sequence_mismatch() {
+        // off by one
+        let target = fake(|request| {
+            let sequence = message::sequence(request).unwrap();
+            message::with_sequence(&Test::response_ping(), sequence + 1)
+        })
+        .await;
+        let results = ping(target).await;
+        let mismatch = SequenceMismatch {
+            expected: 1,
+            received: Some(2),
+        };
+        assert_eq!(results.sequence_mismatch, Some(mismatch));
+        assert_eq!(results.count, 0);
+
+        // not numbered at all, as by a service that ignores the flag
+        let target = fake(|_| Test::response_ping()).await;
+        let results = ping(target.clone()).await;
+        let mismatch = SequenceMismatch {
+            expected: 1,
+            received: None,
+        };
+        assert_eq!(results.sequence_mismatch, Some(mismatch));
+        // which is fine if the requests aren't numbered either
+        let mut client = Client::new_with_target(target).await.unwrap();
+        client.set_sequenced(false);
+        client.run_with(0, vec![ping_test()]).await.unwrap();
+        let results = client.results;
+        assert_eq!((results.count, results.passed), (1, 1));
+        assert_eq!(results.sequence_mismatch, None);
+
+        // echoed, the response is checked without the number
+        let target = fake(|request| {
+            let sequence = message::sequence(request).unwrap();
+            message::with_sequence(&Test::response_ping(), sequence)
+        })
+        .await;
+        let results = ping(target).await;
+        assert_eq!((results.count, results.passed), (1, 1));
+        assert_eq!(results.sequence_mismatch, None);
+    }
+
+    #[tokio::test]
+    async fn test_strict() {
+        let strict = |target: Target| async move {
+            let mut client = Client::new_with_target(target).await.unwrap();
+            client.set_sequenced(false);
+            client.set_strict(true);
+            client.run_with(0, vec![ping_test()]).await.unwrap();
+            client.results
+        };
+        let header = |sign: u32, size: u16, code: u16| Some((sign, size, code));
+        let oversized = |_: &[u8]| {
+            let header = Header::raw(message::MAGIC, 9216, Response::Ok as u16);
+            [header.as_bytes(), &[0u8; 9216][..]].concat()
+        };
+        type Reply = fn(&[u8]) -> Vec<u8>;
+        let cases: [(Reply, ProtocolViolation); 5] = [
+            (
+                oversized,
+                ProtocolViolation {
+                    violation: Violation::TooLarge { limit: 8192 },
+                    header: header(message::MAGIC, 9216, 0),
+                },
+            ),
+            (
+                |_| Test::header_bytes(0, 0, 0),
+                ProtocolViolation {
+                    violation: Violation::BadMagic,
+                    header: header(0, 0, 0),
+                },
+            ),
+            (
+                |_| b"STRY".to_vec(),
+                ProtocolViolation {
+                    violation: Violation::TooSmall,
+                    header: None,
+                },
+            ),
+            (
+                |_| Test::header_bytes(message::MAGIC, 0, 200),
+                ProtocolViolation {
+                    violation: Violation::UnknownCode,
+                    header: header(message::MAGIC, 0, 200),
+                },
+            ),
+            (
+                |_| Test::response_bytes(Response::MessageTooSmall, b"x"),
+                ProtocolViolation {
+                    violation: Violation::ErrorPayload,
+                    header: header(message::MAGIC, 1, 34),
+                },
+            ),
+        ];
+        for (reply, violation) in cases {
+            let target = fake(reply).await;
+            let results = strict(target.clone()).await;
+            assert_eq!(
+                (results.count, results.failed, results.violations),
+                (1, 1, 1),
+                "{}",
+                violation
+            );
+            // the check itself, against the default limits
+            let client = Client::new_with_target(target).await.unwrap();
+            assert_eq!(client.check_response(&reply(b"")), Err(violation));
+        }
+        let client = Client::new_with_target(fake(|_| Vec::new()).await)
+            .await
+            .unwrap();
+        let short = [Test::header_bytes(message::MAGIC, 3, 0), b"a".to_vec()].concat();
+        let mismatch = client.check_response(&short).unwrap_err();
+        assert_eq!(mismatch.violation, Violation::SizeMismatch { received: 1 });
+        assert_eq!(
+            mismatch.to_string(),
+            "response (magic 0x53545259, size 3, code 0) with a payload of 1 bytes"
+        );
+
+        // a numbered response may be over the limit by its number
+        let numbered = message::with_sequence(&Test::response_bytes(Response::Ok, &[0; 8192]), 1);
+        assert_eq!(client.check_response(&numbered), Ok(()));
+
+        // not counted unless strict
+        let results = ping(fake(oversized).await).await;
+        assert_eq!(results.violations, 0);
+        // details are a payload the service may send
+        let mut client = client;
+        client.limits = ServerConfig {
+            error_details: true,
+            ..Default::default()
+        }
+        .limits();
+        let detailed = Test::response_bytes(Response::MessageTooSmall, b"x");
+        assert_eq!(client.check_response(&detailed), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_expectations() {
+        let expect = |expected: Expectation| Test {
+            name: format!("{:?}", expected),
+            expected,
+            ..ping_test()
+        };
+        let run = |target: Target, tests: Vec<Test>| async move {
+            let mut client = Client::new_with_target(target).await.unwrap();
+            client.set_sequenced(false);
+            client.run_with(0, tests).await.unwrap();
+            client.results
+        };
+        let goodbye = |_: &[u8]| {
+            let goodbye = Goodbye::new_with(Stats::new(), 0, GoodbyeReason::BadMagic);
+            Test::response_bytes(Response::Goodbye, goodbye.as_bytes())
+        };
+        let bad_magic = |_: &[u8]| Test::response_fail(Response::MessageHeaderHasBadMagic);
+        let short = Duration::from_millis(100);
+
+        // closed, answered first or not, the next test gets a new connection
+        let closing = fake_with(bad_magic, true).await;
+        let tests = vec![
+            expect(Expectation::Disconnect),
+            expect(Expectation::ResponseCode(
+                Response::MessageHeaderHasBadMagic,
+            )),
+            expect(Expectation::Goodbye(GoodbyeReason::BadMagic)),
+        ];
+        let results = run(closing, tests).await;
+        assert_eq!((results.count, results.passed), (3, 2));
+        assert_eq!(
+            results.failures,
+            ["Goodbye(BadMagic): expected a Goodbye BadMagic, got the connection closed"]
+        );
+
+        let saying_goodbye = fake_with(goodbye, true).await;
+        let tests = vec![
+            expect(Expectation::Goodbye(GoodbyeReason::BadMagic)),
+            expect(Expectation::Goodbye(GoodbyeReason::IdleTimeout)),
+            expect(Expectation::Disconnect),
+        ];
+        let results = run(saying_goodbye, tests).await;
+        assert_eq!((results.count, results.passed), (3, 2));
+        assert_eq!(
+            results.failures,
+            ["Goodbye(IdleTimeout): expected a Goodbye IdleTimeout, got one Some(BadMagic)"]
+        );
+
+        // silence, whether expected or not
+        let silent = fake(|_| Vec::new()).await;
+        let results = run(silent, vec![expect(Expectation::NoResponseWithin(short))]).await;
+        assert_eq!((results.count, results.passed), (1, 1));
+        let answering = fake(|_| Test::response_ping()).await;
+        let tests = vec![
+            expect(Expectation::NoResponseWithin(short)),
+            expect(Expectation::ResponseCode(Response::MessageTooSmall)),
+        ];
+        let results = run(answering, tests).await;
+        assert_eq!((results.count, results.passed), (2, 0));
+        assert_eq!(
+            results.failures,
+            [
+                "NoResponseWithin(100ms): expected nothing for 100ms, got a response of code 0",
+                "ResponseCode(MessageTooSmall): Error: Expected a response of MessageTooSmall (34)"
+            ]
+        );
+    }
+
+    /// Starts a server with `config`, returning its target
+    async fn serve(config: ServerConfig) -> Target {
+        let server = Server::new_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap();
+        Target::Tcp(server.spawn().local_addr())
+    }
+
+    #[tokio::test]
+    async fn test_hello() {
+        let get_stats = || Test {
+            name: "get stats".to_string(),
+            tags: vec!["stats"],
+            query_kind: Request::GetStats,
+            query: Test::request_get_stats(),
+            expected: Vec::new().into(),
+            validity: TestKind::Valid,
+            own_connection: None,
+        };
+        // the greeting is taken in before the limits, and accounted for in
+        // the stats expected, whether the service sends one or not
+        for hello in [true, false] {
+            let config = ServerConfig {
+                hello,
+                idle_timeout: Some(Duration::from_secs(30)),
+                ..Default::default()
+            };
+            let limits = config.limits();
+            let mut client = Client::new_with_target(serve(config).await).await.unwrap();
+            client.set_sequenced(false);
+            client
+                .run_with(0, vec![ping_test(), get_stats()])
+                .await
+                .unwrap();
+            assert_eq!(client.limits, limits);
+            let results = &client.results;
+            assert_eq!((results.count, results.passed), (2, 2), "{:?}", results);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ratio_policy() {
+        let policy = RatioPolicy::LastN(1);
+        let config = ServerConfig {
+            ratio_policy: policy,
+            ..Default::default()
+        };
+        let get_stats = Test {
+            name: "get stats".to_string(),
+            tags: vec!["stats"],
+            query_kind: Request::GetStats,
+            query: Test::request_get_stats(),
+            expected: Vec::new().into(),
+            validity: TestKind::Valid,
+            own_connection: None,
+        };
+        // incompressible, then halved, the ratio is the last request's alone
+        let tests = vec![
+            test_compress_ok(b"abcdefghijklmnop", b"abcdefghijklmnop"),
+            test_compress_ok(b"aaaaaaaa", b"8a"),
+            get_stats,
+        ];
+
+        for (expected, passed) in [(policy, 3), (RatioPolicy::Cumulative, 2)] {
+            let target = serve(config.clone()).await;
+            let mut client = Client::new_with_target(target).await.unwrap();
+            client.set_ratio_policy(expected);
+            client.run_with(0, tests.clone()).await.unwrap();
+            let results = client.results;
+            assert_eq!((results.count, results.passed), (3, passed));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_stats_versions() {
+        let target = serve(ServerConfig::default()).await;
+        let get_stats = |query_kind: Request, query: Vec<u8>| Test {
+            name: format!("{:?}", query_kind),
+            tags: vec!["stats"],
+            query_kind,
+            query,
+            expected: Vec::new().into(),
+            validity: TestKind::Valid,
+            own_connection: None,
+        };
+        // either layout is checked against the same expected stats
+        let tests = vec![
+            test_compress_ok(b"aaaaaaaa", b"8a"),
+            get_stats(Request::GetStats, Test::request_get_stats()),
+            get_stats(Request::GetStatsV2, Test::request_get_stats_v2()),
+        ];
+        let mut client = Client::new_with_target(target).await.unwrap();
+        client.run_with(0, tests).await.unwrap();
+        let results = client.results;
+        assert_eq!((results.count, results.passed), (3, 3));
+
+        let v1 = Stats::new_with(24, 16, 75);
+        let parsed = Client::parse_stats(&Request::GetStats, v1.as_bytes()).unwrap();
+        assert_eq!(parsed, VersionedStats::V1(v1));
+        let mut v9 = vec![9];
+        v9.extend_from_slice(&[0; 17]);
+        assert!(Client::parse_stats(&Request::GetStatsV2, &v9).is_err());
+        assert!(Client::parse_stats(&Request::Ping, b"").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compress_batch() {
+        let target = serve(ServerConfig::default()).await;
+        let mut client = Client::new_with_target(target).await.unwrap();
+        let results = client
+            .compress_batch(&[b"aaaaabbb", b"aB3", b"abc"])
+            .await
+            .unwrap();
+        assert_eq!(
+            results,
+            [
+                Ok(b"5a3b".to_vec()),
+                Err(ServiceError::new(
+                    Response::MessagePayloadContainsInvalidCharacters
+                )),
+                Ok(b"abc".to_vec()),
+            ]
+        );
+        // the stats expected of the service account for the batch
+        let get_stats = Test {
+            name: "get stats".to_string(),
+            tags: vec!["stats"],
+            query_kind: Request::GetStats,
+            query: Test::request_get_stats(),
+            expected: Vec::new().into(),
+            validity: TestKind::Valid,
+            own_connection: None,
+        };
+        client.run_with(0, vec![get_stats]).await.unwrap();
+        assert_eq!(client.results.passed, 1);
+
+        let limited = ServerConfig {
+            max_batch: 1,
+            ..Default::default()
+        };
+        let mut client = Client::new_with_target(serve(limited).await).await.unwrap();
+        let error = client.compress_batch(&[b"a", b"b"]).await.unwrap_err();
+        assert_eq!(error.to_string(), "the service answered BatchTooLarge");
+    }
+
+    #[tokio::test]
+    async fn test_compress_with_stats() {
+        let target = serve(ServerConfig::default()).await;
+        let mut client = Client::new_with_target(target).await.unwrap();
+        let (compressed, stats) = client.compress_with_stats(b"aaaaabbb").await.unwrap();
+        assert_eq!(compressed, b"5a3b");
+        // the request read, its response not yet sent
+        assert_eq!(stats, Stats::new_with(16, 0, 50));
+        let invalid = client.compress_with_stats(b"aB3").await.unwrap_err();
+        let service_error = invalid.get_ref().unwrap().downcast_ref::<ServiceError>();
+        let expected = ServiceError::new(Response::MessagePayloadContainsInvalidCharacters);
+        assert_eq!(service_error, Some(&expected));
+
+        // the stats expected of the service account for both
+        let get_stats = Test {
+            name: "get stats".to_string(),
+            tags: vec!["stats"],
+            query_kind: Request::GetStats,
+            query: Test::request_get_stats(),
+            expected: Vec::new().into(),
+            validity: TestKind::Valid,
+            own_connection: None,
+        };
+        client.run_with(0, vec![get_stats]).await.unwrap();
+        assert_eq!(client.results.passed, 1);
+    }
+
+    #[tokio::test]
+    async fn test_compress_with_stats_fallback() {
+        // a service too old for CompressWithStats, telling what it serves
+        let target = fake(
+            |request| match Request::from_u16(BigEndian::read_u16(&request[6..8])) {
+                Some(Request::CompressWithStats) => {
+                    let served = (1..=14)
+                        .filter_map(Request::from_u16)
+                        .filter(|request| *request != Request::CompressWithStats);
+                    let served = ServedRequests::new_with(1, served);
+                    Test::response_bytes(Response::UnsupportedRequestType, served.as_bytes())
+                }
+                Some(Request::Compress) => Test::response_compress(b"5a3b"),
+                _ => Test::response_get_stats(Stats::new_with(16, 12, 50).as_bytes()),
+            },
+        )
+        .await;
+        let mut client = Client::new_with_target(target).await.unwrap();
+        let (compressed, stats) = client.compress_with_stats(b"aaaaabbb").await.unwrap();
+        assert_eq!(compressed, b"5a3b");
+        assert_eq!(stats, Stats::new_with(16, 12, 50));
+
+        // a bare UnsupportedRequestType doesn't tell, it's an error
+        let target = fake(|_| Test::response_fail(Response::UnsupportedRequestType)).await;
+        let mut client = Client::new_with_target(target).await.unwrap();
+        let error = client.compress_with_stats(b"aaaaabbb").await.unwrap_err();
+        let service_error = error.get_ref().unwrap().downcast_ref::<ServiceError>();
+        let expected = ServiceError::new(Response::UnsupportedRequestType);
+        assert_eq!(service_error, Some(&expected));
+    }
+
+    #[tokio::test]
+    async fn test_rotated_connections() {
+        // GetConfig and a ping fill each connection
+        let config = ServerConfig {
+            max_requests_per_connection: 2,
+            ..Default::default()
+        };
+        let target = serve(config).await;
+        let pings = (0..3)
+            .map(|_| Test {
+                name: "ping".to_string(),
+                tags: vec!["ping"],
+                query_kind: Request::Ping,
+                query: Test::request_ping(),
+                expected: Test::response_ping().into(),
+                validity: TestKind::Valid,
+                own_connection: None,
+            })
+            .collect();
+        let mut client = Client::new_with_target(target).await.unwrap();
+        client.run_with(0, pings).await.unwrap();
+        let results = client.results;
+        assert_eq!((results.count, results.passed, results.failed), (3, 3, 0));
+        assert_eq!(results.goodbye, None);
+    }
+
+    #[tokio::test]
+    async fn test_connection_per_test() {
+        let config = ServerConfig {
+            bad_magic_strikes: 1,
+            ..Default::default()
+        };
+        let target = serve(config).await;
+        let bad_magic = |expected: Expectation| Test {
+            name: "bad magic".to_string(),
+            query: Test::header_bytes(0, 0, 1),
+            expected,
+            validity: TestKind::Invalid,
+            ..ping_test()
+        };
+        let answered = || Expectation::ResponseCode(Response::MessageHeaderHasBadMagic);
+        let run = |tests: Vec<Test>, per_test: bool, reconnect: bool| {
+            let target = target.clone();
+            async move {
+                let mut client = Client::new_with_target(target).await.unwrap();
+                client.set_connection_per_test(per_test);
+                client.set_reconnect_on_error(reconnect);
+                client.run_with(0, tests).await.unwrap();
+                client.results
+            }
+        };
+
+        // closed as expected or not, the ping after it runs on a new
+        // connection and passes
+        for expected in [Expectation::Disconnect, answered()] {
+            let results = run(vec![bad_magic(expected), ping_test()], true, false).await;
+            assert_eq!((results.count, results.passed, results.failed), (2, 2, 0));
+            assert_eq!((results.connections, &results.ran_on[..]), (2, &[1, 2][..]));
+        }
+
+        // on the same connection the ping gets the Goodbye instead
+        let results = run(vec![bad_magic(answered()), ping_test()], false, false).await;
+        assert_eq!((results.count, results.passed), (1, 1));
+        assert_eq!(results.goodbye, Some(GoodbyeReason::BadMagic));
+        // unless it has a connection of its own
+        let tests = vec![bad_magic(answered()), ping_test().on_own_connection(true)];
+        let results = run(tests, false, false).await;
+        assert_eq!((results.count, results.passed), (2, 2));
+        assert_eq!(results.ran_on, [1, 2]);
+        // with a connection each, a test that opts out shares the last one
+        let tests = vec![ping_test(), ping_test().on_own_connection(false)];
+        let results = run(tests, true, false).await;
+        assert_eq!((results.count, results.passed), (2, 2));
+        assert_eq!(results.ran_on, [1, 1]);
+
+        // reconnecting on error, only the ping that found it closed fails
+        let tests = vec![bad_magic(answered()), ping_test(), ping_test()];
+        let results = run(tests, false, true).await;
+        assert_eq!((results.count, results.passed, results.failed), (3, 2, 1));
+        assert_eq!(results.ran_on, [1, 1, 2]);
+        assert_eq!(
+            results.failures,
+            ["ping: the service said Goodbye Some(BadMagic)"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("test-replay-{}.cap", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let target = serve(ServerConfig::default()).await;
+        let mut client = Client::new_with_target(target).await.unwrap();
+        client.record_to(CaptureWriter::open(&path).unwrap());
+        client
+            .run_with(1, crate::cases(&ServerConfig::default().limits()))
+            .await
+            .unwrap();
+        assert_eq!(client.results.failed, 0);
+
+        let mut reader = CaptureReader::open(&path).unwrap();
+        let records = reader.read_all().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!reader.truncated);
+        // GetConfig and every case, each answered
+        let requests = records
+            .iter()
+            .filter(|r| r.direction == Direction::Request)
+            .count();
+        assert_eq!(
+            requests,
+            crate::cases(&ServerConfig::default().limits()).len() + 1
+        );
+
+        // a fresh server answers exactly the same
+        let fresh = serve(ServerConfig::default()).await;
+        assert_eq!(capture::replay(&fresh, &records).await.unwrap(), vec![]);
+
+        // one accepting uppercase doesn't
+        let config = ServerConfig {
+            fold_case: true,
+            ..Default::default()
+        };
+        let folding = serve(config).await;
+        let mismatches = capture::replay(&folding, &records).await.unwrap();
+        // the requests were numbered, so are the responses
+        let uppercase = Test::response_fail(Response::MessageContainsUppercaseCharacters);
+        assert_eq!(
+            mismatches
+                .iter()
+                .filter_map(|m| message::without_sequence(&m.expected))
+                .filter(|(_, expected)| *expected == uppercase)
+                .count(),
+            2
+        );
+        // and aB3 is left with only its digit to reject
+        assert_eq!(mismatches.len(), 3);
+        for mismatch in mismatches {
+            assert_eq!(mismatch.connection, 1);
+            assert_ne!(mismatch.received, mismatch.expected);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_ping_over_unix_socket() {
+        let path = std::env::temp_dir().join(format!("test-client-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let state = Arc::new(Mutex::new(State::new()));
+            let config = Arc::new(ServerConfig::default());
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(Server::process(stream, state.clone(), config.clone()));
+            }
+        });
+
+        let target = format!("unix:{}", path.display()).parse().unwrap();
+        let results = ping(target).await;
+        assert_eq!((results.count, results.passed, results.failed), (1, 1, 0));
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,273 @@
+use crate::capture::REPLAY_TIMEOUT;
+use crate::target::{Stream, Target};
+use message::{Request, Response, HEADER_SIZE};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use service::testing::{Unstructured, Validity, WireMessage};
+use service::{message, Stats, StatsV2};
+use std::{mem, ops::Range};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time,
+};
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+/// The random bytes each fuzzed request is built out of
+const FUZZ_ENTROPY: usize = 1024;
+
+/// Bytes of the responses to a kind of request allowed to differ between the
+/// two servers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Allowance {
+    pub request: Request,
+    pub bytes: Range<usize>,
+}
+
+/// The stats counters, which depend on what else each server has served,
+/// past the version byte of GetStatsV2
+pub fn default_allowances() -> Vec<Allowance> {
+    vec![
+        Allowance {
+            request: Request::GetStats,
+            bytes: HEADER_SIZE..HEADER_SIZE + mem::size_of::<Stats>(),
+        },
+        Allowance {
+            request: Request::GetStatsV2,
+            bytes: HEADER_SIZE + 1..HEADER_SIZE + 1 + mem::size_of::<StatsV2>(),
+        },
+    ]
+}
+
+/// What a server did with a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Message(Vec<u8>),
+    /// Nothing came back within `REPLAY_TIMEOUT`
+    Timeout,
+    /// The connection was closed without a response
+    Closed,
+}
+
+impl Reply {
+    /// Whether the server is done with the connection
+    fn closes(&self) -> bool {
+        match self {
+            Reply::Message(bytes) => {
+                message::Message::parse(&bytes[..])
+                    .map(|message| message.header.code() == Response::Goodbye as u16)
+                    == Some(true)
+            }
+            _ => true,
+        }
+    }
+}
+
+/// A request the two servers answered differently
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Position of the request among those sent
+    pub index: usize,
+    pub request: Vec<u8>,
+    pub a: Reply,
+    pub b: Reply,
+}
+
+/// Sends the same requests to two servers and compares their responses
+pub struct Differ {
+    a: Target,
+    b: Target,
+    allowances: Vec<Allowance>,
+}
+
+impl Differ {
+    pub fn new_with(a: Target, b: Target) -> Differ {
+        Differ::new_with_allowances(a, b, default_allowances())
+    }
+
+    pub fn new_with_allowances(a: Target, b: Target, allowances: Vec<Allowance>) -> Differ {
+        Differ { a, b, allowances }
+    }
+
+    /// Sends each request to both servers in turn, over a pair of
+    /// connections that is opened again whenever either server closes its
+    /// one. A message following a response, like a Goodbye, is taken for the
+    /// reply to the next request
+    pub async fn run(&self, requests: &[Vec<u8>]) -> Result<Vec<Divergence>> {
+        let mut divergences = Vec::new();
+        let mut pair: Option<(Box<dyn Stream>, Box<dyn Stream>)> = None;
+        for (index, request) in requests.iter().enumerate() {
+            let (a, b) = match &mut pair {
+                Some(pair) => pair,
+                None => {
+                    let (a, _) = self.a.connect().await?;
+                    let (b, _) = self.b.connect().await?;
+                    pair.insert((a, b))
+                }
+            };
+            let (a, b) = tokio::join!(exchange(a, request), exchange(b, request));
+            if a.closes() || b.closes() {
+                pair = None;
+            }
+            if !self.equivalent(request, &a, &b) {
+                divergences.push(Divergence {
+                    index,
+                    request: request.clone(),
+                    a,
+                    b,
+                });
+            }
+        }
+        Ok(divergences)
+    }
+
+    /// Whether the replies are identical but for the allowed bytes
+    fn equivalent(&self, request: &[u8], a: &Reply, b: &Reply) -> bool {
+        let (a, b) = match (a, b) {
+            (Reply::Message(a), Reply::Message(b)) => (a, b),
+            (a, b) => return a == b,
+        };
+        if a.len() != b.len() {
+            return false;
+        }
+        let request = message::Message::parse(request)
+            .and_then(|message| Request::from_u16(message.header.code()));
+        let allowed: Vec<&Range<usize>> = self
+            .allowances
+            .iter()
+            .filter(|allowance| Some(&allowance.request) == request.as_ref())
+            .map(|allowance| &allowance.bytes)
+            .collect();
+        a.iter()
+            .zip(b.iter())
+            .enumerate()
+            .all(|(i, (a, b))| a == b || allowed.iter().any(|bytes| bytes.contains(&i)))
+    }
+}
+
+/// Sends `request` and reads back one whole message
+async fn exchange<S: Stream + ?Sized>(stream: &mut S, request: &[u8]) -> Reply {
+    if stream.write_all(request).await.is_err() {
+        return Reply::Closed;
+    }
+    let mut response = vec![0u8; HEADER_SIZE];
+    match time::timeout(REPLAY_TIMEOUT, stream.read_exact(&mut response)).await {
+        Ok(Ok(_)) => (),
+        Ok(Err(_)) => return Reply::Closed,
+        Err(_) => return Reply::Timeout,
+    }
+    let size = u16::from_be_bytes([response[4], response[5]]) as usize;
+    response.resize(HEADER_SIZE + size, 0);
+    match time::timeout(
+        REPLAY_TIMEOUT,
+        stream.read_exact(&mut response[HEADER_SIZE..]),
+    )
+    .await
+    {
+        Ok(Ok(_)) => Reply::Message(response),
+        Ok(Err(_)) => Reply::Closed,
+        Err(_) => Reply::Timeout,
+    }
+}
+
+/// Random requests, the same ones for a given `seed`, the `WireMessage`s
+/// of `service::testing`. Mostly valid requests of every kind, mixed with
+/// ones framed right but wrong for the request (invalid characters, unknown
+/// codes or flags, payloads where none is allowed) and garbage (bad magics,
+/// size mismatches and messages too small). None is larger than the service
+/// accepts, how an oversized message is split by the transport is up to it
+/// rather than the server
+pub fn fuzz_requests(seed: u64, count: usize) -> Vec<Vec<u8>> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut entropy = vec![0u8; FUZZ_ENTROPY];
+    (0..count)
+        .map(|_| {
+            let validity = match rng.gen_range(0, 20) {
+                0 => Validity::Garbage,
+                1 | 2 => Validity::StructurallyValidButSemanticallyWrong,
+                _ => Validity::AlwaysValid,
+            };
+            rng.fill(&mut entropy[..]);
+            // short of entropy the rest of the fields are zeros, never an error
+            WireMessage::arbitrary_of(&mut Unstructured::new(&entropy), validity)
+                .unwrap()
+                .bytes
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Test;
+    use service::{Server, ServerConfig};
+    use zerocopy::AsBytes;
+
+    /// Starts a server with `config`, returning its target
+    async fn serve(config: ServerConfig) -> Target {
+        let server = Server::new_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap();
+        Target::Tcp(server.spawn().local_addr())
+    }
+
+    fn requests() -> Vec<Vec<u8>> {
+        let mut requests: Vec<_> = crate::cases(&ServerConfig::default().limits())
+            .into_iter()
+            .map(|test| test.query)
+            .collect();
+        requests.extend(fuzz_requests(7, 300));
+        requests
+    }
+
+    #[test]
+    fn test_fuzz_requests_are_seeded() {
+        assert_eq!(fuzz_requests(1, 50), fuzz_requests(1, 50));
+        assert_ne!(fuzz_requests(1, 50), fuzz_requests(2, 50));
+    }
+
+    #[test]
+    fn test_equivalent() {
+        let target = Target::Host("localhost:4000".to_string());
+        let differ = Differ::new_with(target.clone(), target);
+        let get_stats = Test::request_get_stats();
+        let stats = |read: u32| {
+            Reply::Message(Test::response_get_stats(
+                Stats::new_with(read, 16, 50).as_bytes(),
+            ))
+        };
+        assert!(differ.equivalent(&get_stats, &stats(24), &stats(32)));
+        // only the stats of GetStats responses are allowed to differ
+        let ping = Test::request_ping();
+        assert!(!differ.equivalent(&ping, &stats(24), &stats(32)));
+        let ok = Reply::Message(Test::response_ping());
+        let forbidden = Reply::Message(Test::response_fail(Response::Forbidden));
+        assert!(!differ.equivalent(&get_stats, &ok, &forbidden));
+        assert!(!differ.equivalent(&ping, &ok, &Reply::Closed));
+        assert!(differ.equivalent(&ping, &Reply::Timeout, &Reply::Timeout));
+    }
+
+    #[tokio::test]
+    async fn test_identical_servers() {
+        let a = serve(ServerConfig::default()).await;
+        let b = serve(ServerConfig::default()).await;
+        let divergences = Differ::new_with(a, b).run(&requests()).await.unwrap();
+        assert_eq!(divergences, vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_diverging_servers() {
+        let a = serve(ServerConfig::default()).await;
+        let config = ServerConfig {
+            fold_case: true,
+            ..Default::default()
+        };
+        let b = serve(config).await;
+        let requests = requests();
+        let divergences = Differ::new_with(a, b).run(&requests).await.unwrap();
+        assert!(!divergences.is_empty());
+        for divergence in divergences {
+            assert_eq!(divergence.request, requests[divergence.index]);
+            assert_ne!(divergence.a, divergence.b);
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use crate::client::{test_compress_ok, Test};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use service::{compress_bound, compress_message, Limits};
+use std::{
+    f64::consts::PI,
+    io::{Error, ErrorKind},
+    str::FromStr,
+    time::Duration,
+};
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+/// How the sizes of generated payloads are distributed, sizes are always
+/// kept within 1 and the service's payload limit
+/// "fixed:N" => Fixed(N)
+/// "uniform:MIN-MAX" => Uniform(MIN, MAX), both included
+/// "lognormal:MEDIAN,SIGMA" => LogNormal, most payloads small with a long
+/// tail of large ones, like real traffic
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PayloadSize {
+    Fixed(usize),
+    Uniform(usize, usize),
+    LogNormal { median: f64, sigma: f64 },
+}
+
+impl PayloadSize {
+    pub fn sample<R: Rng>(&self, rng: &mut R, max: usize) -> usize {
+        let size = match *self {
+            PayloadSize::Fixed(size) => size,
+            PayloadSize::Uniform(min, max) => rng.gen_range(min, max + 1),
+            PayloadSize::LogNormal { median, sigma } => {
+                (median * (sigma * standard_normal(rng)).exp()).round() as usize
+            }
+        };
+        size.clamp(1, max)
+    }
+}
+
+impl Default for PayloadSize {
+    fn default() -> PayloadSize {
+        PayloadSize::Uniform(1, 512)
+    }
+}
+
+impl FromStr for PayloadSize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<PayloadSize> {
+        let invalid = || invalid("expected fixed:N, uniform:MIN-MAX or lognormal:MEDIAN,SIGMA");
+        let (kind, args) = s.split_once(':').ok_or_else(invalid)?;
+        match kind {
+            "fixed" => args.parse().map(PayloadSize::Fixed).map_err(|_| invalid()),
+            "uniform" => {
+                let (min, max) = args.split_once('-').ok_or_else(invalid)?;
+                match (min.parse(), max.parse()) {
+                    (Ok(min), Ok(max)) if min <= max => Ok(PayloadSize::Uniform(min, max)),
+                    _ => Err(invalid()),
+                }
+            }
+            "lognormal" => {
+                let (median, sigma) = args.split_once(',').ok_or_else(invalid)?;
+                match (median.parse(), sigma.parse()) {
+                    (Ok(median), Ok(sigma)) if median > 0.0 && sigma >= 0.0 => {
+                        Ok(PayloadSize::LogNormal { median, sigma })
+                    }
+                    _ => Err(invalid()),
+                }
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// How compressible generated payloads are, by the length of their runs
+/// "none" => runs of 1, the payload is sent back as is
+/// "runs:N" => runs averaging N characters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunProfile {
+    pub mean_run: usize,
+}
+
+impl RunProfile {
+    /// Run lengths are uniform between 1 and twice the mean, less one
+    fn sample<R: Rng>(&self, rng: &mut R) -> usize {
+        rng.gen_range(1, 2 * self.mean_run)
+    }
+}
+
+impl Default for RunProfile {
+    fn default() -> RunProfile {
+        RunProfile { mean_run: 4 }
+    }
+}
+
+impl FromStr for RunProfile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<RunProfile> {
+        let invalid = || invalid("expected none or runs:N");
+        match s.split_once(':') {
+            None if s == "none" => Ok(RunProfile { mean_run: 1 }),
+            Some(("runs", n)) => match n.parse() {
+                Ok(mean_run) if mean_run > 0 => Ok(RunProfile { mean_run }),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Produces valid lowercase Compress payloads, the same ones for a given seed,
+/// along with the response the service is expected to send for them
+pub struct Generator {
+    rng: StdRng,
+    size: PayloadSize,
+    profile: RunProfile,
+    max_payload: usize,
+}
+
+impl Generator {
+    pub fn new_with(
+        seed: u64,
+        size: PayloadSize,
+        profile: RunProfile,
+        limits: &Limits,
+    ) -> Generator {
+        Generator {
+            rng: StdRng::seed_from_u64(seed),
+            size,
+            profile,
+            max_payload: limits.max_payload() as usize,
+        }
+    }
+
+    pub fn payload(&mut self) -> Vec<u8> {
+        let len = self.size.sample(&mut self.rng, self.max_payload);
+        let mut payload = Vec::with_capacity(len);
+        let mut previous = 0;
+        while payload.len() < len {
+            // a character of its own so that neighbouring runs never merge
+            let mut byte = self.rng.gen_range(b'a', b'z');
+            if byte >= previous && previous != 0 {
+                byte += 1;
+            }
+            let run = self.profile.sample(&mut self.rng).min(len - payload.len());
+            payload.extend(std::iter::repeat_n(byte, run));
+            previous = byte;
+        }
+        payload
+    }
+
+    /// A Compress test of the next payload, its expected response compressed
+    /// locally like the service does by default
+    pub fn test(&mut self) -> Test {
+        let payload = self.payload();
+        let mut compressed = vec![0u8; compress_bound(payload.len())];
+        let len = compress_message(&payload, &mut compressed).unwrap();
+        Test {
+            name: format!("generated compress of {} bytes", payload.len()),
+            ..test_compress_ok(&payload, &compressed[..len]).tagged("generated")
+        }
+    }
+}
+
+/// The random numbers of client `client_num` of a run seeded with `seed`, the
+/// same every run. The clients of a seed under 2^32 each have a stream of
+/// their own
+pub fn client_rng(seed: u64, client_num: usize) -> StdRng {
+    StdRng::seed_from_u64(seed.wrapping_add((client_num as u64) << 32))
+}
+
+/// How a client of a concurrent run goes about its test cases, drawn from
+/// its `client_rng` so that it can be replayed alone
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientPlan {
+    /// How long the client waits before connecting
+    pub delay: Duration,
+    /// The positions of the test cases, in the order they're run
+    pub order: Vec<usize>,
+}
+
+impl ClientPlan {
+    /// The plan of client `client_num` for `cases` test cases, shuffled if
+    /// `shuffle`, starting up to `jitter` late. The order doesn't depend on
+    /// the jitter
+    pub fn new_with(
+        seed: u64,
+        client_num: usize,
+        cases: usize,
+        jitter: Duration,
+        shuffle: bool,
+    ) -> ClientPlan {
+        let mut rng = client_rng(seed, client_num);
+        let mut order: Vec<usize> = (0..cases).collect();
+        if shuffle {
+            order.shuffle(&mut rng);
+        }
+        let jitter = jitter.as_millis() as u64;
+        let delay = Duration::from_millis(rng.gen_range(0, jitter + 1));
+        ClientPlan { delay, order }
+    }
+
+    /// `tests` in the order of the plan
+    pub fn arrange(&self, tests: &[Test]) -> Vec<Test> {
+        self.order.iter().map(|&i| tests[i].clone()).collect()
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg.to_string())
+}
+
+/// A sample of the standard normal distribution, by the Box-Muller transform
+fn standard_normal<R: Rng>(rng: &mut R) -> f64 {
+    let u1: f64 = 1.0 - rng.gen::<f64>(); // (0, 1], ln(0) is undefined
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use service::ServerConfig;
+
+    fn new_generator(seed: u64, size: &str, profile: &str) -> Generator {
+        let limits = ServerConfig::default().limits();
+        Generator::new_with(
+            seed,
+            size.parse().unwrap(),
+            profile.parse().unwrap(),
+            &limits,
+        )
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "fixed:64".parse::<PayloadSize>().unwrap(),
+            PayloadSize::Fixed(64)
+        );
+        assert_eq!(
+            "uniform:1-512".parse::<PayloadSize>().unwrap(),
+            PayloadSize::Uniform(1, 512)
+        );
+        assert_eq!(
+            "lognormal:64,1.5".parse::<PayloadSize>().unwrap(),
+            PayloadSize::LogNormal {
+                median: 64.0,
+                sigma: 1.5
+            }
+        );
+        for bad in ["64", "fixed:", "uniform:9-3", "lognormal:0,1", "normal:1,2"] {
+            assert!(bad.parse::<PayloadSize>().is_err(), "{}", bad);
+        }
+        assert_eq!("none".parse::<RunProfile>().unwrap().mean_run, 1);
+        assert_eq!("runs:8".parse::<RunProfile>().unwrap().mean_run, 8);
+        for bad in ["runs:0", "runs", "many"] {
+            assert!(bad.parse::<RunProfile>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_size_bounds() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let max = 8192;
+        let uniform = PayloadSize::Uniform(10, 20);
+        assert!((0..1000)
+            .map(|_| uniform.sample(&mut rng, max))
+            .all(|size| (10..=20).contains(&size)));
+        // clamped to the limit, and never empty
+        assert_eq!(PayloadSize::Fixed(9000).sample(&mut rng, max), max);
+        assert_eq!(PayloadSize::Fixed(0).sample(&mut rng, max), 1);
+        let lognormal = PayloadSize::LogNormal {
+            median: 64.0,
+            sigma: 2.0,
+        };
+        let mut sizes: Vec<usize> = (0..1001).map(|_| lognormal.sample(&mut rng, max)).collect();
+        assert!(sizes.iter().all(|size| (1..=max).contains(size)));
+        sizes.sort_unstable();
+        assert!((40..100).contains(&sizes[500]), "median {}", sizes[500]);
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let payloads = |seed| {
+            let mut generator = new_generator(seed, "lognormal:64,1", "runs:4");
+            (0..20).map(|_| generator.payload()).collect::<Vec<_>>()
+        };
+        assert_eq!(payloads(3), payloads(3));
+        assert_ne!(payloads(3), payloads(4));
+    }
+
+    #[test]
+    fn test_client_plan() {
+        let plan = |seed, client_num| {
+            ClientPlan::new_with(seed, client_num, 30, Duration::from_millis(50), true)
+        };
+        assert_eq!(plan(7, 3), plan(7, 3));
+        assert_ne!(plan(7, 3).order, plan(7, 4).order);
+        assert_ne!(plan(7, 3).order, plan(8, 3).order);
+        for client_num in 0..100 {
+            let plan = plan(7, client_num);
+            assert!(plan.delay <= Duration::from_millis(50));
+            let mut order = plan.order.clone();
+            order.sort_unstable();
+            assert_eq!(order, (0..30).collect::<Vec<_>>());
+        }
+
+        // in order unless shuffled, and whatever the jitter
+        let unshuffled = ClientPlan::new_with(7, 3, 30, Duration::from_millis(50), false);
+        assert_eq!(unshuffled.order, (0..30).collect::<Vec<_>>());
+        let still = ClientPlan::new_with(7, 3, 30, Duration::ZERO, true);
+        assert_eq!(
+            (still.delay, &still.order),
+            (Duration::ZERO, &plan(7, 3).order)
+        );
+    }
+
+    #[test]
+    fn test_seeded_runs() {
+        // a run's cases, generated then arranged for a client
+        let run = |seed| {
+            let mut generator = new_generator(seed, "uniform:1-64", "runs:4");
+            let tests: Vec<Test> = (0..20).map(|_| generator.test()).collect();
+            let plan = ClientPlan::new_with(seed, 12, tests.len(), Duration::ZERO, true);
+            let queries: Vec<Vec<u8>> = plan.arrange(&tests).into_iter().map(|t| t.query).collect();
+            (plan.order, queries)
+        };
+        assert_eq!(run(11), run(11));
+        assert_ne!(run(11), run(12));
+    }
+
+    #[test]
+    fn test_valid_payloads() {
+        let mut generator = new_generator(5, "uniform:1-8192", "runs:6");
+        for _ in 0..200 {
+            let test = generator.test();
+            let payload = &test.query[service::HEADER_SIZE..];
+            assert!(!payload.is_empty() && payload.len() <= 8192);
+            assert!(payload.iter().all(u8::is_ascii_lowercase));
+            assert!(test.expected.bytes().len() <= test.query.len());
+        }
+
+        // runs of one never repeat a character, nothing compresses
+        let mut generator = new_generator(5, "fixed:100", "none");
+        let payload = generator.payload();
+        assert!(payload.windows(2).all(|pair| pair[0] != pair[1]));
+        let test = generator.test();
+        assert_eq!(
+            test.expected.bytes()[service::HEADER_SIZE..],
+            test.query[service::HEADER_SIZE..]
+        );
+    }
+}
@@ -1,133 +1,614 @@
-use std::env;
+use std::{env, io::Error, time::Duration};
 
+mod capture;
+use capture::{CaptureReader, CaptureWriter};
 mod client;
 use client::*;
+mod diff;
+use diff::{Differ, Reply};
+mod generate;
+use generate::{ClientPlan, Generator, PayloadSize, RunProfile};
+mod pipeline;
+mod target;
+use target::Target;
 
-use message::{Request, Response};
-use service::message;
+use message::{Request, ResetScope, Response};
+use service::{message, Limits, RatioPolicy};
 
 /// Currently can only verify GetStats responses with single client
 const IS_CONCURRENT: bool = true;
 const OVERLOAD_SERVER: bool = false;
 
+/// Run the test clients against the service at the given target, host:port,
+/// [ipv6]:port or unix:/path/to/socket (default 127.0.0.1:4000)
+///
+/// Options:
+///   --record <file>   append every request and response to a capture file
+///   --replay <file>   instead of the test cases, replay the requests of a
+///                     capture and diff the responses with the recorded ones
+///   --filter <text>   only run the tests whose name contains text
+///   --tag <tag>       only run the tests tagged tag, given more than once
+///                     the tests must have all of the tags
+///   --generate <n>    also run n generated Compress tests, checked against
+///                     the payloads compressed locally
+///   --payload-size <dist>  sizes of the generated payloads, fixed:N,
+///                     uniform:MIN-MAX or lognormal:MEDIAN,SIGMA
+///                     (default uniform:1-512)
+///   --run-profile <profile>  how compressible they are, none or runs:N for
+///                     runs averaging N characters (default runs:4)
+///   --seed <n>        seeds the generated payloads and each client's
+///                     jitter and order of the tests (default 0)
+///   --jitter <ms>     start each client up to ms milliseconds late rather
+///                     than all at once
+///   --shuffle-cases   run the tests in an order of each client's own
+///   --replay-client <id>  run client id alone, as it ran for the seed
+///   --ratio-policy <policy>  the service's --ratio-policy, for the ratio
+///                     GetStats is expected to report (default cumulative)
+///   --no-sequence     don't number the requests, even if the service
+///                     supports sequence numbers
+///   --strict          fail any response that breaks the protocol, e.g. over
+///                     the service's max payload, and count them
+///   --connection-per-test  run each test on a new connection
+///   --reconnect-on-error  open a new connection after a test the service
+///                     ended the connection of, instead of failing the rest
+///
+/// `test-client batch <target> <payload>...` compresses the payloads with a
+/// single Batch request, printing the result of each
+///
+/// `test-client pipeline <target> [--window <n>] [--flush <policy>]
+/// [--half-close] <payload>...` compresses each payload with a Compress request of its own,
+/// up to n of them in flight at once (default 1), printing the result of
+/// each. The requests are written as they are queued (immediate), those
+/// queued together up to N per write (messages:N), or MICROS microseconds
+/// after the first of them (idle:MICROS). With --half-close the connection
+/// is half-closed once the last of them is written, not once every response
+/// is read
+///
+/// `test-client probe <target> <payload>` compresses the payload with a
+/// CompressWithStats request, printing the result and the stats of the
+/// service in a single round trip
+///
+/// `test-client diff --a <target> --b <target> [--seed <n>] [--fuzz <n>]`
+/// sends the test cases and `n` fuzzed requests (default 1000, seeded by
+/// `seed`, default 0) to two servers, reporting every request they answer
+/// differently
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "127.0.0.1:4000".to_string());
+    let mut target = "127.0.0.1:4000".to_string();
+    let mut record = None;
+    let mut replay = None;
+    let mut filter = Filter::default();
+    let mut generate = 0;
+    let (mut size, mut profile) = (PayloadSize::default(), RunProfile::default());
+    let mut options = ClientOptions::default();
+    let mut replay_client = None;
+    let mut args = env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("diff") {
+        args.next();
+        return diff_servers(args).await;
+    }
+    if args.peek().map(String::as_str) == Some("batch") {
+        args.next();
+        return compress_batch(args).await;
+    }
+    if args.peek().map(String::as_str) == Some("pipeline") {
+        args.next();
+        return compress_pipelined(args).await;
+    }
+    if args.peek().map(String::as_str) == Some("probe") {
+        args.next();
+        return probe(args).await;
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--record" => record = Some(args.next().ok_or_else(|| file_expected(&arg))?),
+            "--replay" => replay = Some(args.next().ok_or_else(|| file_expected(&arg))?),
+            "--filter" => filter.name = Some(args.next().ok_or_else(|| value_expected(&arg))?),
+            "--tag" => filter
+                .tags
+                .push(args.next().ok_or_else(|| value_expected(&arg))?),
+            "--generate" => generate = number(&arg, args.next())? as usize,
+            "--seed" => options.seed = number(&arg, args.next())?,
+            "--jitter" => options.jitter = Duration::from_millis(number(&arg, args.next())?),
+            "--shuffle-cases" => options.shuffle_cases = true,
+            "--replay-client" => replay_client = Some(number(&arg, args.next())? as usize),
+            "--payload-size" => size = args.next().ok_or_else(|| value_expected(&arg))?.parse()?,
+            "--run-profile" => {
+                profile = args.next().ok_or_else(|| value_expected(&arg))?.parse()?
+            }
+            "--ratio-policy" => {
+                let policy = args.next().ok_or_else(|| value_expected(&arg))?;
+                options.ratio_policy = policy.parse().map_err(invalid_input)?
+            }
+            "--no-sequence" => options.sequenced = false,
+            "--strict" => options.strict = true,
+            "--connection-per-test" => options.connection_per_test = true,
+            "--reconnect-on-error" => options.reconnect_on_error = true,
+            _ => target = arg,
+        }
+    }
+    let target: Target = target.parse()?;
 
-    run_clients(addr, 1000).await?;
+    if let Some(path) = replay {
+        return replay_capture(&target, &path).await;
+    }
+    let capture = record.map(CaptureWriter::open).transpose()?;
+    let limits = Client::new_with_target(target.clone())
+        .await?
+        .query_limits()
+        .await?;
+    let mut tests = test_cases(&limits);
+    let mut generator = Generator::new_with(options.seed, size, profile, &limits);
+    tests.extend((0..generate).map(|_| generator.test()));
+    let total = tests.len();
+    let tests = filter.select(tests);
+    let selected = format!("{} of {} tests selected", tests.len(), total);
+    if tests.is_empty() {
+        eprintln!("Warning: {}, check --filter and --tag", selected);
+    }
+    println!("{}", selected);
+    println!("Seed {}", options.seed);
+    match replay_client {
+        Some(client_num) => {
+            let options = ClientOptions {
+                jitter: Duration::ZERO,
+                ..options
+            };
+            create_client(target, capture, options, tests, client_num).await?
+        }
+        None => run_clients(target, capture, options, tests, 1000).await?,
+    }
 
-    println!("Tests Complete");
+    println!("Tests Complete, {}", selected);
     Ok(())
 }
 
-async fn run_clients(addr: String, num_clients: usize) -> Result<(), std::io::Error> {
-    futures::future::join_all(
-        (1..num_clients).map(|client_num| {
-	    let the_addr = addr.clone();
-	    tokio::spawn(async move { create_client(the_addr, client_num).await })
-	}),
-    )
+fn file_expected(option: &str) -> Error {
+    invalid_input(format!("{} expects a file", option))
+}
+
+fn value_expected(option: &str) -> Error {
+    invalid_input(format!("{} expects a value", option))
+}
+
+fn number(option: &str, value: Option<String>) -> Result<u64, std::io::Error> {
+    value
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| invalid_input(format!("{} expects a number", option)))
+}
+
+fn invalid_input(msg: String) -> Error {
+    Error::new(std::io::ErrorKind::InvalidInput, msg)
+}
+
+/// How each of the clients runs its tests
+#[derive(Debug, Clone, Copy)]
+struct ClientOptions {
+    /// The service's, for the ratio GetStats is expected to report
+    ratio_policy: RatioPolicy,
+    /// Numbers the requests, if the service supports it
+    sequenced: bool,
+    /// Checks every response against the protocol
+    strict: bool,
+    /// Runs each test on a new connection
+    connection_per_test: bool,
+    /// Reconnects after a test the service ended the connection of
+    reconnect_on_error: bool,
+    /// Seeds the random numbers of each client, see `generate::client_rng`
+    seed: u64,
+    /// The most each client starts late by
+    jitter: Duration,
+    /// Runs the tests in an order of each client's own
+    shuffle_cases: bool,
+}
+
+impl Default for ClientOptions {
+    fn default() -> ClientOptions {
+        ClientOptions {
+            ratio_policy: RatioPolicy::default(),
+            sequenced: true,
+            strict: false,
+            connection_per_test: false,
+            reconnect_on_error: false,
+            seed: 0,
+            jitter: Duration::ZERO,
+            shuffle_cases: false,
+        }
+    }
+}
+
+async fn run_clients(
+    target: Target,
+    capture: Option<CaptureWriter>,
+    options: ClientOptions,
+    tests: Vec<Test>,
+    num_clients: usize,
+) -> Result<(), std::io::Error> {
+    futures::future::join_all((1..num_clients).map(|client_num| {
+        let the_target = target.clone();
+        let the_capture = capture.clone();
+        let the_tests = tests.clone();
+        tokio::spawn(async move {
+            create_client(the_target, the_capture, options, the_tests, client_num).await
+        })
+    }))
     .await;
     Ok(())
 }
 
-/// Create a single client at the given address `addr`
+/// Create a single client at the given `target` running `tests`, recording
+/// into `capture` and running them as `options` has it. Its delay and order
+/// of the tests, drawn from the seed and `client_num`, are printed so that
+/// it can be replayed alone
 /// For multiple clients,
-async fn create_client(addr: String, client_num: usize) -> Result<(), std::io::Error> {
-    println!("Starting Client {}", client_num);
-    Client::new_with_url(addr)
+async fn create_client(
+    target: Target,
+    capture: Option<CaptureWriter>,
+    options: ClientOptions,
+    tests: Vec<Test>,
+    client_num: usize,
+) -> Result<(), std::io::Error> {
+    let plan = ClientPlan::new_with(
+        options.seed,
+        client_num,
+        tests.len(),
+        options.jitter,
+        options.shuffle_cases,
+    );
+    println!(
+        "Starting Client {} after {:?}, order {:?}",
+        client_num, plan.delay, plan.order
+    );
+    tokio::time::sleep(plan.delay).await;
+    let tests = plan.arrange(&tests);
+    let mut client = Client::new_with_target(target).await?;
+    client.set_ratio_policy(options.ratio_policy);
+    client.set_sequenced(options.sequenced);
+    client.set_strict(options.strict);
+    client.set_connection_per_test(options.connection_per_test);
+    client.set_reconnect_on_error(options.reconnect_on_error);
+    if let Some(capture) = capture {
+        client.record_to(capture);
+    }
+    client.run_with(client_num, tests).await
+}
+
+/// Replays the capture at `path`, reporting each response that differs from
+/// the recorded one
+async fn replay_capture(target: &Target, path: &str) -> Result<(), std::io::Error> {
+    let mut reader = CaptureReader::open(path)?;
+    let records = reader.read_all()?;
+    if reader.truncated {
+        eprintln!("Warning: the last record of {} is truncated", path);
+    }
+    let mismatches = capture::replay(target, &records).await?;
+    for mismatch in mismatches.iter() {
+        let heading = format!(
+            "Mismatch: connection {} request {}",
+            mismatch.connection, mismatch.index
+        );
+        show_responses(
+            &heading,
+            &mismatch.request,
+            &[
+                ("expected", hexdump(&mismatch.expected)),
+                ("received", hexdump(&mismatch.received)),
+            ],
+        );
+    }
+    println!(
+        "Replayed {} records, {} mismatches",
+        records.len(),
+        mismatches.len()
+    );
+    match mismatches.len() {
+        0 => Ok(()),
+        n => Err(Error::other(format!("{} responses differ", n))),
+    }
+}
+
+/// Compresses the payloads of `test-client batch` in a single request
+async fn compress_batch<I: Iterator<Item = String>>(mut args: I) -> Result<(), std::io::Error> {
+    let target: Target = args
+        .next()
+        .ok_or_else(|| invalid_input("batch expects a target".to_string()))?
+        .parse()?;
+    let payloads: Vec<String> = args.collect();
+    let payloads: Vec<&[u8]> = payloads.iter().map(|p| p.as_bytes()).collect();
+    let results = Client::new_with_target(target)
         .await?
-        .run_with(client_num, test_cases())
+        .compress_batch(&payloads)
+        .await?;
+    for (payload, result) in payloads.iter().zip(results) {
+        match result {
+            Ok(compressed) => println!(
+                "{} => {}",
+                String::from_utf8_lossy(payload),
+                String::from_utf8_lossy(&compressed)
+            ),
+            Err(e) => println!("{} => {}", String::from_utf8_lossy(payload), e),
+        }
+    }
+    Ok(())
+}
+
+/// Compresses the payloads of `test-client pipeline`, each in a request of
+/// its own all on one connection
+async fn compress_pipelined<I: Iterator<Item = String>>(mut args: I) -> Result<(), std::io::Error> {
+    let target: Target = args
+        .next()
+        .ok_or_else(|| invalid_input("pipeline expects a target".to_string()))?
+        .parse()?;
+    let mut client = Client::new_with_target(target).await?;
+    let mut payloads = Vec::new();
+    let mut half_close = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--window" => client.set_window(number(&arg, args.next())? as usize),
+            "--half-close" => half_close = true,
+            "--flush" => {
+                let policy = args.next().ok_or_else(|| value_expected(&arg))?;
+                client.set_flush_policy(policy.parse().map_err(invalid_input)?)
+            }
+            _ => payloads.push(arg),
+        }
+    }
+    let queries: Vec<Vec<u8>> = payloads
+        .iter()
+        .map(|payload| Test::request_compress(payload.as_bytes()))
+        .collect();
+    let pipeline = client.pipeline().await?;
+    let responses = if half_close {
+        pipeline.send_and_close(&queries).await
+    } else {
+        futures::future::join_all(queries.iter().map(|query| {
+            let pipeline = pipeline.clone();
+            async move { pipeline.send(query).await }
+        }))
         .await
+    };
+    for (payload, response) in payloads.iter().zip(responses) {
+        let response = response?;
+        let message = message::Message::parse(&response[..]).unwrap();
+        match Response::from_u16(message.header.code()) {
+            Some(Response::Ok) => println!(
+                "{} => {}",
+                payload,
+                String::from_utf8_lossy(message.payload_slice())
+            ),
+            _ => println!("{} => {}", payload, ServiceError::of(&message)),
+        }
+    }
+    Ok(())
+}
+
+/// Compresses the payload of `test-client probe` along with the stats
+async fn probe<I: Iterator<Item = String>>(mut args: I) -> Result<(), std::io::Error> {
+    let target: Target = args
+        .next()
+        .ok_or_else(|| invalid_input("probe expects a target".to_string()))?
+        .parse()?;
+    let payload = args
+        .next()
+        .ok_or_else(|| invalid_input("probe expects a payload".to_string()))?;
+    let (compressed, stats) = Client::new_with_target(target)
+        .await?
+        .compress_with_stats(payload.as_bytes())
+        .await?;
+    println!("{} => {}", payload, String::from_utf8_lossy(&compressed));
+    println!("{}", stats);
+    Ok(())
+}
+
+/// Runs the differential test of `test-client diff`
+async fn diff_servers<I: Iterator<Item = String>>(mut args: I) -> Result<(), std::io::Error> {
+    let (mut a, mut b, mut seed, mut fuzz) = (None, None, 0, 1000);
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| invalid_input(format!("{} expects a value", arg)))?;
+        let number = || {
+            value
+                .parse()
+                .map_err(|_| invalid_input(format!("{} expects a number", arg)))
+        };
+        match arg.as_str() {
+            "--a" => a = Some(value.parse::<Target>()?),
+            "--b" => b = Some(value.parse::<Target>()?),
+            "--seed" => seed = number()?,
+            "--fuzz" => fuzz = number()? as usize,
+            _ => return Err(invalid_input(format!("unknown option {}", arg))),
+        }
+    }
+    let (a, b) = match (a, b) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Err(invalid_input("diff expects --a and --b".to_string())),
+    };
+
+    let limits = Client::new_with_target(a.clone())
+        .await?
+        .query_limits()
+        .await?;
+    let mut requests: Vec<Vec<u8>> = cases(&limits).into_iter().map(|test| test.query).collect();
+    requests.extend(diff::fuzz_requests(seed, fuzz));
+    let divergences = Differ::new_with(a, b).run(&requests).await?;
+    for divergence in divergences.iter() {
+        let heading = format!("Divergence: request {}", divergence.index);
+        show_responses(
+            &heading,
+            &divergence.request,
+            &[
+                ("a", describe(&divergence.a)),
+                ("b", describe(&divergence.b)),
+            ],
+        );
+    }
+    println!(
+        "Sent {} requests, {} divergences",
+        requests.len(),
+        divergences.len()
+    );
+    match divergences.len() {
+        0 => Ok(()),
+        n => Err(Error::other(format!("{} responses differ", n))),
+    }
+}
+
+/// Prints a request along with the responses it got, each under its label
+fn show_responses(heading: &str, request: &[u8], responses: &[(&str, String)]) {
+    let mut report = format!("{}\nrequest:\n{}", heading, hexdump(request));
+    for (label, response) in responses {
+        report.push_str(&format!("{}:\n{}", label, response));
+    }
+    println!("{}", report);
+}
+
+fn hexdump(bytes: &[u8]) -> String {
+    message::hexdump(bytes, message::HEXDUMP_DEFAULT_ROWS)
+}
+
+fn describe(reply: &Reply) -> String {
+    match reply {
+        Reply::Message(bytes) => hexdump(bytes),
+        Reply::Timeout => "no response\n".to_string(),
+        Reply::Closed => "connection closed\n".to_string(),
+    }
 }
 
-pub fn test_cases() -> Vec<Test> {
+/// The test cases, fitted to the `limits` of the service
+pub fn test_cases(limits: &Limits) -> Vec<Test> {
     if OVERLOAD_SERVER {
         flood_server()
     } else {
-        cases()
+        cases(limits)
     }
 }
 
-fn cases() -> Vec<Test> {
-    let mut res = Vec::new();
-
-    res.push(test_compress_ok(b"a", b"a"));
-    res.push(test_compress_ok(b"aa", b"aa"));
-    res.push(test_compress_ok(b"aa", b"aa"));
-    res.push(test_compress_ok(b"aaa", b"3a"));
-    res.push(test_compress_ok(b"aaaaabbb", b"5a3b"));
-    res.push(test_compress_ok(b"aaaaabbbbbbaaabb", b"5a6b3abb"));
-    res.push(test_compress_ok(b"abcdefg", b"abcdefg"));
-    res.push(test_compress_ok(b"aaaccddddhhhhi", b"3acc4d4hi"));
+fn cases(limits: &Limits) -> Vec<Test> {
+    let mut res = vec![
+        test_compress_ok(b"a", b"a"),
+        test_compress_ok(b"aa", b"aa"),
+        test_compress_ok(b"aa", b"aa"),
+        test_compress_ok(b"aaa", b"3a"),
+        test_compress_ok(b"aaaaabbb", b"5a3b"),
+        test_compress_ok(b"aaaaabbbbbbaaabb", b"5a6b3abb"),
+        test_compress_ok(b"abcdefg", b"abcdefg"),
+        test_compress_ok(b"aaaccddddhhhhi", b"3acc4d4hi"),
+        test_compress_fail(b"123", Response::MessageContainsDigits),
+        test_compress_fail(b"abCD", Response::MessageContainsUppercaseCharacters),
+        test_compress_fail(
+            b"aaaaaaaaaaaaaaaaaaaaaaaaaB",
+            Response::MessageContainsUppercaseCharacters,
+        ),
+        test_compress_fail(b"ab\xFF", Response::MessageContainsNonAscii),
+        test_compress_fail_default(b"aB3"),
+        test_compress_fail_default(b"a b"),
+    ];
 
-    res.push(test_compress_fail_default(b"123"));
-    res.push(test_compress_fail_default(b"abCD"));
-    res.push(test_compress_fail_default(b"aaaaaaaaaaaaaaaaaaaaaaaaaB"));
+    res.extend(boundary_cases(limits));
 
     {
         if !OVERLOAD_SERVER {
             let msg = [97u8; ((message::MAX_PAYLOAD as usize) + 12)];
-            res.push(test_compress_fail(&msg, Response::MessageTooLarge));
+            res.push(test_compress_fail(&msg, Response::MessageTooLarge).tagged("boundary"));
         }
     }
 
     res.push(Test {
+        name: "message too small".to_string(),
+        tags: vec!["ping", "invalid", "boundary"],
         query_kind: Request::Ping,
         query: [97u8; 7].to_vec(),
-        expected: Test::response_fail(Response::MessageTooSmall),
+        expected: Test::response_fail(Response::MessageTooSmall).into(),
         validity: TestKind::Invalid,
+        own_connection: None,
     });
 
     res.push(Test {
+        name: "bad magic".to_string(),
+        tags: vec!["ping", "invalid"],
         query_kind: Request::Ping,
         query: Test::header_bytes(0, 0, 1),
-        expected: Test::response_fail(Response::MessageHeaderHasBadMagic),
+        expected: Test::response_fail(Response::MessageHeaderHasBadMagic).into(),
         validity: TestKind::Invalid,
+        own_connection: None,
     });
 
     res.push(Test {
+        name: "compress empty payload".to_string(),
+        tags: vec!["compress", "invalid", "boundary"],
         query_kind: Request::Compress,
         query: Test::header_bytes(message::MAGIC, 0, Request::Compress as u16),
-        expected: Test::response_fail(Response::CompressionRequestRequiresNonZeroLength),
+        expected: Test::response_fail(Response::CompressionRequestRequiresNonZeroLength).into(),
         validity: TestKind::Invalid,
+        own_connection: None,
     });
 
     {
         if !IS_CONCURRENT {
             res.push(Test {
+                name: "get stats".to_string(),
+                tags: vec!["stats", "valid"],
                 query_kind: Request::GetStats,
                 query: Test::request_get_stats(),
-                expected: vec![],
+                expected: vec![].into(),
                 validity: TestKind::Valid,
+                own_connection: None,
             });
         }
     }
 
     // Note: will fail if resopnse is not Response::Ok
     res.push(Test {
+        name: "ping".to_string(),
+        tags: vec!["ping", "valid"],
         query_kind: Request::Ping,
         query: Test::request_ping(),
-        expected: Test::response_ping(),
+        expected: Test::response_ping().into(),
         validity: TestKind::Valid,
+        own_connection: None,
     });
 
     res.push(Test {
+        name: "reset stats".to_string(),
+        tags: vec!["stats", "valid"],
         query_kind: Request::ResetStats,
         query: Test::request_reset_stats(),
-        expected: Test::response_reset_stats(),
+        expected: Test::response_reset_stats().into(),
         validity: TestKind::Valid,
+        own_connection: None,
+    });
+
+    // the service's stats can't be reset unless it allows global resets
+    res.push(Test {
+        name: "global reset forbidden".to_string(),
+        tags: vec!["stats", "invalid"],
+        query_kind: Request::ResetStats,
+        query: Test::request_reset_stats_scope(ResetScope::Global),
+        expected: Test::response_fail(Response::Forbidden).into(),
+        validity: TestKind::Invalid,
+        own_connection: None,
     });
 
     {
         if !IS_CONCURRENT {
             res.push(Test {
+                name: "get stats".to_string(),
+                tags: vec!["stats", "valid"],
                 query_kind: Request::GetStats,
                 query: Test::request_get_stats(),
-                expected: vec![],
+                expected: vec![].into(),
                 validity: TestKind::Valid,
+                own_connection: None,
+            });
+            res.push(Test {
+                name: "get stats v2".to_string(),
+                tags: vec!["stats", "valid"],
+                query_kind: Request::GetStatsV2,
+                query: Test::request_get_stats_v2(),
+                expected: vec![].into(),
+                validity: TestKind::Valid,
+                own_connection: None,
             });
         }
     }
@@ -138,7 +619,17 @@ fn cases() -> Vec<Test> {
 // The following should result in the server from dropping this client
 // as conncurrent requests of this kind could lead to DOS due to overuse
 // of server resources
+/// A flood without a header, answered read by read until the service closes
+/// the connection for its magic
 fn flood_server() -> Vec<Test> {
-    let msg = [97u8; ((((message::MAX_PAYLOAD) * 2) as usize) + 20)];
-    vec![test_compress_fail(&msg, Response::MessageTooLarge)]
+    vec![Test {
+        name: "flood".to_string(),
+        tags: vec!["invalid", "slow"],
+        query_kind: Request::Compress,
+        query: vec![0u8; message::MAX_MESSAGE * 4],
+        expected: Expectation::Disconnect,
+        validity: TestKind::Invalid,
+        own_connection: None,
+    }
+    .on_own_connection(true)]
 }
@@ -0,0 +1,649 @@
+use crate::client::ServiceError;
+use crate::target::{Stream, Target};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::BytesMut;
+use futures::future::join_all;
+use message::{Message, Request, Response, HEADER_SIZE};
+use service::{message, FlushPolicy, Goodbye};
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    sync::{mpsc, oneshot},
+    time,
+};
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+/// The window of a client that waits for each response before sending the
+/// next request
+pub const DEFAULT_WINDOW: usize = 1;
+
+/// Most bytes of requests held back before they are written whatever the
+/// `FlushPolicy`, as many as the service holds back of its responses
+pub const MAX_BUFFERED: usize = service::DEFAULT_MAX_BUFFERED;
+
+/// A request waiting to be sent, along with where its response goes
+struct Pending {
+    query: Vec<u8>,
+    responder: oneshot::Sender<Result<BytesMut>>,
+}
+
+/// A request sent whose response hasn't come back yet
+struct InFlight {
+    request: Option<Request>,
+    responder: oneshot::Sender<Result<BytesMut>>,
+}
+
+/// A connection to the service with up to `window` requests in flight at
+/// once, written without waiting for the responses to those before them
+///
+/// The service answers the requests of a connection in the order it reads
+/// them, so each response goes to the oldest request still in flight. An
+/// error response echoing a request code (`Feature::ERROR_DETAILS`) other
+/// than that request's means they went out of step, which fails every
+/// request in flight as does a Goodbye or the connection dropping. The
+/// connection is driven by a task of its own, cloning the pipeline shares it
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    requests: mpsc::Sender<Pending>,
+}
+
+impl Pipeline {
+    /// Connects to `target` with at most `window` requests in flight, at
+    /// least 1, written as `policy` has it
+    pub async fn connect(target: &Target, window: usize, policy: FlushPolicy) -> Result<Pipeline> {
+        let (stream, _) = target.connect().await?;
+        Ok(Pipeline::over(stream, window, policy))
+    }
+
+    /// The pipeline of a connected `stream`
+    pub fn over(stream: Box<dyn Stream>, window: usize, policy: FlushPolicy) -> Pipeline {
+        let window = window.max(1);
+        let (requests, queued) = mpsc::channel(window);
+        let writer = Writer {
+            policy,
+            buf: Vec::new(),
+            held: 0,
+            since: time::Instant::now(),
+        };
+        tokio::spawn(drive(stream, queued, window, writer));
+        Pipeline { requests }
+    }
+
+    /// Sends `query` as soon as the window allows, resolving to its whole
+    /// response
+    pub async fn send(&self, query: &[u8]) -> Result<BytesMut> {
+        let (responder, response) = oneshot::channel();
+        let pending = Pending {
+            query: query.to_vec(),
+            responder,
+        };
+        if self.requests.send(pending).await.is_err() {
+            return Err(closed());
+        }
+        response.await.unwrap_or_else(|_| Err(closed()))
+    }
+
+    /// Sends every query as the window allows, then half-closes the
+    /// connection: the service still answers every request it read before
+    /// the end of the stream. Resolves to their responses in order. The
+    /// connection is only half-closed once the clones of the pipeline are
+    /// dropped too
+    pub async fn send_and_close(self, queries: &[Vec<u8>]) -> Vec<Result<BytesMut>> {
+        let mut responses = Vec::with_capacity(queries.len());
+        for query in queries {
+            let (responder, response) = oneshot::channel();
+            let pending = Pending {
+                query: query.clone(),
+                responder,
+            };
+            // one the queue no longer takes drops its responder, failing it
+            let _ = self.requests.send(pending).await;
+            responses.push(response);
+        }
+        drop(self);
+        let responses = responses
+            .into_iter()
+            .map(|response| async { response.await.unwrap_or_else(|_| Err(closed())) });
+        join_all(responses).await
+    }
+}
+
+fn closed() -> Error {
+    Error::new(
+        ErrorKind::NotConnected,
+        "the connection to the service is closed",
+    )
+}
+
+/// The requests held back from the connection under a `FlushPolicy`
+struct Writer {
+    policy: FlushPolicy,
+    buf: Vec<u8>,
+    /// Requests in `buf`
+    held: usize,
+    /// When the first request in `buf` was queued
+    since: time::Instant,
+}
+
+impl Writer {
+    /// Holds back `pending`, it's in flight from then on
+    fn hold(&mut self, pending: Pending, in_flight: &mut VecDeque<InFlight>) {
+        let request = Message::parse(&pending.query[..])
+            .and_then(|query| Request::from_u16(query.header.code()));
+        in_flight.push_back(InFlight {
+            request,
+            responder: pending.responder,
+        });
+        if self.held == 0 {
+            self.since = time::Instant::now();
+        }
+        self.buf.extend_from_slice(&pending.query);
+        self.held += 1;
+    }
+
+    /// Whether the requests held back are written before holding back
+    /// another
+    fn due_before_more(&self) -> bool {
+        let due = match self.policy {
+            FlushPolicy::Immediate => true,
+            FlushPolicy::AfterMessages(n) => self.held >= n,
+            FlushPolicy::AfterIdle(_) => false,
+        };
+        self.held > 0 && (due || self.buf.len() >= MAX_BUFFERED)
+    }
+
+    /// Whether the requests held back are written once nothing more is
+    /// queued, or the window is `full`
+    fn due_when_drained(&self, full: bool) -> bool {
+        let idle = matches!(self.policy, FlushPolicy::AfterIdle(_));
+        self.held > 0 && (full || !idle || self.buf.len() >= MAX_BUFFERED)
+    }
+
+    /// When the requests held back are written at the latest
+    fn deadline(&self) -> Option<time::Instant> {
+        match self.policy {
+            FlushPolicy::AfterIdle(idle) if self.held > 0 => Some(self.since + idle),
+            _ => None,
+        }
+    }
+
+    /// Writes the requests held back in a single write
+    async fn flush(&mut self, writer: &mut WriteHalf<Box<dyn Stream>>) -> Result<()> {
+        writer.write_all(&self.buf).await?;
+        self.buf.clear();
+        self.held = 0;
+        Ok(())
+    }
+
+    /// Holds back `pending` and the requests queued along with it that fit
+    /// the window, writing them as the policy has it
+    async fn write(
+        &mut self,
+        writer: &mut WriteHalf<Box<dyn Stream>>,
+        pending: Pending,
+        queued: &mut mpsc::Receiver<Pending>,
+        in_flight: &mut VecDeque<InFlight>,
+        window: usize,
+    ) -> Result<()> {
+        self.hold(pending, in_flight);
+        while in_flight.len() < window {
+            let pending = match queued.try_recv() {
+                Ok(pending) => pending,
+                Err(_) => break,
+            };
+            if self.due_before_more() {
+                self.flush(writer).await?;
+            }
+            self.hold(pending, in_flight);
+        }
+        if self.due_when_drained(in_flight.len() >= window) {
+            self.flush(writer).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the requests queued while fewer than `window` are in flight and
+/// hands each response read to the oldest of them, until every pipeline is
+/// dropped with nothing left in flight or the connection fails. Once every
+/// pipeline is dropped the requests held back are written and the
+/// connection half-closed, the responses in flight are still read
+async fn drive(
+    stream: Box<dyn Stream>,
+    mut queued: mpsc::Receiver<Pending>,
+    window: usize,
+    mut held: Writer,
+) {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut in_flight = VecDeque::with_capacity(window);
+    let mut buf = BytesMut::with_capacity(message::MAX_MESSAGE_PADDED);
+    let mut open = true;
+    // a request queued while the window is full, taken off the queue all the
+    // same so that the pipelines being dropped is seen
+    let mut next = None;
+    let error = loop {
+        if in_flight.len() < window {
+            if let Some(pending) = next.take() {
+                let written = held.write(&mut writer, pending, &mut queued, &mut in_flight, window);
+                if let Err(e) = written.await {
+                    break e;
+                }
+            }
+        }
+        let deadline = held.deadline();
+        let idle = time::sleep_until(deadline.unwrap_or_else(time::Instant::now));
+        tokio::select! {
+            pending = queued.recv(), if open && next.is_none() => match pending {
+                Some(pending) => next = Some(pending),
+                None => {
+                    open = false;
+                    let closed = async {
+                        held.flush(&mut writer).await?;
+                        writer.shutdown().await
+                    };
+                    if let Err(e) = closed.await {
+                        break e;
+                    }
+                }
+            },
+            _ = idle, if deadline.is_some() => {
+                if let Err(e) = held.flush(&mut writer).await {
+                    break e;
+                }
+            },
+            read = reader.read_buf(&mut buf), if !in_flight.is_empty() => match read {
+                Ok(0) => break Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("the service disconnected with {} requests in flight", in_flight.len()),
+                ),
+                Ok(_) => {
+                    if let Err(e) = answer(&mut buf, &mut in_flight) {
+                        break e;
+                    }
+                }
+                Err(e) => break e,
+            },
+            else => return,
+        }
+    };
+    fail(error, in_flight, next, queued, reader, writer);
+}
+
+/// Hands every whole response in `buf` to the oldest request in flight
+fn answer(buf: &mut BytesMut, in_flight: &mut VecDeque<InFlight>) -> Result<()> {
+    while buf.len() >= HEADER_SIZE {
+        let len = message::total_response_len(BigEndian::read_u16(&buf[4..6]) as usize);
+        if buf.len() < len {
+            break;
+        }
+        let frame = buf.split_to(len);
+        let response = Message::parse(&frame[..]).unwrap();
+        if Response::from_u16(response.header.code()) == Some(Response::Goodbye) {
+            let reason = Goodbye::parse(response.payload_slice()).and_then(|g| g.reason());
+            let msg = format!("the service said Goodbye ({:?})", reason);
+            return Err(Error::new(ErrorKind::ConnectionAborted, msg));
+        }
+        let pending = match in_flight.pop_front() {
+            Some(pending) => pending,
+            None => return Err(Error::other("a response to no request in flight")),
+        };
+        let failed = Response::from_u16(response.header.code()).is_some_and(|r| !r.is_success());
+        let echoed = match failed {
+            true => ServiceError::of(&response).request().cloned(),
+            false => None,
+        };
+        match echoed {
+            Some(echoed) if Some(&echoed) != pending.request.as_ref() => {
+                let msg = format!(
+                    "a response to {:?} in place of one to {:?}",
+                    echoed, pending.request
+                );
+                in_flight.push_front(pending);
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+            _ => {
+                let _ = pending.responder.send(Ok(frame));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fails every request in flight or still queued with `error`, the
+/// connection is closed along with the queue
+fn fail(
+    error: Error,
+    in_flight: VecDeque<InFlight>,
+    next: Option<Pending>,
+    mut queued: mpsc::Receiver<Pending>,
+    reader: ReadHalf<Box<dyn Stream>>,
+    writer: WriteHalf<Box<dyn Stream>>,
+) {
+    drop(reader.unsplit(writer));
+    queued.close();
+    let fails = in_flight.into_iter().map(|failed| failed.responder);
+    let queued = next
+        .into_iter()
+        .chain(std::iter::from_fn(|| queued.try_recv().ok()))
+        .map(|queued| queued.responder);
+    for responder in fails.chain(queued) {
+        let _ = responder.send(Err(Error::new(error.kind(), error.to_string())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Test;
+    use service::{Server, ServerConfig};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::{
+        io,
+        pin::Pin,
+        time::{Duration, Instant},
+    };
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Reads one whole message off `stream`, `None` once it's closed
+    async fn read_message<S: AsyncReadExt + Unpin>(stream: &mut S) -> Option<Vec<u8>> {
+        let mut message = vec![0u8; HEADER_SIZE];
+        stream.read_exact(&mut message).await.ok()?;
+        let len = message::total_response_len(BigEndian::read_u16(&message[4..6]) as usize);
+        message.resize(len, 0);
+        stream.read_exact(&mut message[HEADER_SIZE..]).await.ok()?;
+        Some(message)
+    }
+
+    /// Starts a server behind a proxy holding back each of its responses by
+    /// `delay`, as a slow link would, returning the proxy's target. The
+    /// server reads a request per read, so the proxy hands it the requests
+    /// one at a time however many the client has in flight
+    async fn serve_behind(delay: Duration, config: ServerConfig) -> Target {
+        let server = Server::new_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap()
+            .spawn();
+        let upstream = server.local_addr();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let _server = server;
+            while let Ok((client, _)) = listener.accept().await {
+                let mut service = TcpStream::connect(upstream).await.unwrap();
+                let (mut client_rx, mut client_tx) = client.into_split();
+                let (delayed, mut late) = mpsc::unbounded_channel();
+                tokio::spawn(async move {
+                    while let Some(request) = read_message(&mut client_rx).await {
+                        service.write_all(&request).await.unwrap();
+                        let response = read_message(&mut service).await.unwrap();
+                        let due = time::Instant::now() + delay;
+                        if delayed.send((due, response)).is_err() {
+                            break;
+                        }
+                    }
+                });
+                tokio::spawn(async move {
+                    while let Some((due, response)) = late.recv().await {
+                        time::sleep_until(due).await;
+                        if client_tx.write_all(&response).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        Target::Tcp(addr)
+    }
+
+    /// Compresses a distinct payload per request through a pipeline of
+    /// `window`, checking every caller gets its own, returning how long
+    /// they all took
+    async fn compress_all(target: &Target, window: usize, count: usize) -> Duration {
+        let pipeline = Pipeline::connect(target, window, FlushPolicy::Immediate)
+            .await
+            .unwrap();
+        let payloads: Vec<Vec<u8>> = (0..count)
+            .map(|i| vec![b'a' + (i % 26) as u8; 3 + i])
+            .collect();
+        let start = Instant::now();
+        let responses = join_all(payloads.iter().map(|payload| {
+            let pipeline = pipeline.clone();
+            async move { pipeline.send(&Test::request_compress(payload)).await }
+        }))
+        .await;
+        let elapsed = start.elapsed();
+        for (payload, response) in payloads.iter().zip(responses) {
+            let expected = format!("{}{}", payload.len(), payload[0] as char);
+            assert_eq!(
+                response.unwrap()[..],
+                Test::response_compress(expected.as_bytes())[..]
+            );
+        }
+        elapsed
+    }
+
+    #[tokio::test]
+    async fn test_window_scaling() {
+        let delay = Duration::from_millis(20);
+        let target = serve_behind(delay, ServerConfig::default()).await;
+        let count = 16;
+        let one = compress_all(&target, DEFAULT_WINDOW, count).await;
+        let eight = compress_all(&target, 8, count).await;
+        // one at a time waits out the delay of every response, eight at a
+        // time about a handful of them
+        assert!(one >= delay * count as u32, "{:?}", one);
+        assert!(eight * 3 < one, "{:?} of 8 against {:?} of 1", eight, one);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_fails_in_flight() {
+        // answers nothing, then hangs up once both requests are in
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 2 * HEADER_SIZE];
+            stream.read_exact(&mut buf).await.unwrap();
+        });
+        let pipeline = Pipeline::connect(&Target::Tcp(addr), 2, FlushPolicy::Immediate)
+            .await
+            .unwrap();
+        let ping = Test::request_ping();
+        let (a, b) = tokio::join!(pipeline.send(&ping), pipeline.send(&ping));
+        for result in [a, b] {
+            let error = result.unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+            assert_eq!(
+                error.to_string(),
+                "the service disconnected with 2 requests in flight"
+            );
+        }
+        // and so does every request after
+        let error = pipeline.send(&ping).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotConnected);
+    }
+
+    #[tokio::test]
+    async fn test_echo_out_of_step() {
+        // answers a Compress with the error of a Ping
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            while let Ok(1..) = stream.read(&mut buf).await {
+                let echo = [Request::Ping as u8];
+                let response = Test::response_bytes(Response::UnsupportedFlags, &echo);
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+        let pipeline = Pipeline::connect(&Target::Tcp(addr), 1, FlushPolicy::Immediate)
+            .await
+            .unwrap();
+        let error = pipeline.send(&Test::request_compress(b"a")).await;
+        let error = error.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            "a response to Ping in place of one to Some(Compress)"
+        );
+    }
+
+    /// Counts the writes to the stream it wraps, each a syscall on a socket
+    struct CountingStream<S> {
+        stream: S,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_read(cx, buf)
+        }
+    }
+
+    impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            Pin::new(&mut self.stream).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_shutdown(cx)
+        }
+    }
+
+    /// A pipeline of `window` and `policy` to a fake service answering
+    /// every request with a Ping response, along with its count of writes
+    fn pinged(window: usize, policy: FlushPolicy) -> (Pipeline, Arc<AtomicUsize>) {
+        let (client, mut service) = tokio::io::duplex(2 * MAX_BUFFERED);
+        tokio::spawn(async move {
+            while read_message(&mut service).await.is_some() {
+                service.write_all(&Test::response_ping()).await.unwrap();
+            }
+        });
+        let writes = Arc::new(AtomicUsize::new(0));
+        let stream = CountingStream {
+            stream: client,
+            writes: Arc::clone(&writes),
+        };
+        (Pipeline::over(Box::new(stream), window, policy), writes)
+    }
+
+    /// Sends `count` Pings at once through `pipeline`, checking each is
+    /// answered
+    async fn ping_all(pipeline: &Pipeline, count: usize) {
+        let ping = Test::request_ping();
+        let responses = join_all((0..count).map(|_| pipeline.send(&ping))).await;
+        for response in responses {
+            assert_eq!(response.unwrap()[..], Test::response_ping()[..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_policy_writes() {
+        let (immediate, immediate_writes) = pinged(64, FlushPolicy::Immediate);
+        ping_all(&immediate, 1000).await;
+        assert_eq!(immediate_writes.load(Ordering::Relaxed), 1000);
+        // the Pings queued while the window is full go out together
+        let (batched, batched_writes) = pinged(64, FlushPolicy::AfterMessages(32));
+        ping_all(&batched, 1000).await;
+        let writes = batched_writes.load(Ordering::Relaxed);
+        assert!(writes <= 1000 / 8, "{} writes", writes);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_flush_bound() {
+        let idle = Duration::from_millis(10);
+        let (pipeline, writes) = pinged(8, FlushPolicy::AfterIdle(idle));
+        for count in [1, 4, 7] {
+            let start = time::Instant::now();
+            ping_all(&pipeline, count).await;
+            assert_eq!(start.elapsed(), idle, "{} Pings", count);
+        }
+        assert_eq!(writes.load(Ordering::Relaxed), 3);
+        // a request queued after the first goes along with it, held back
+        // for less
+        let (ping, start) = (Test::request_ping(), time::Instant::now());
+        let later = async {
+            time::sleep(idle / 2).await;
+            pipeline.send(&ping).await
+        };
+        let (first, later) = tokio::join!(pipeline.send(&ping), later);
+        assert_eq!((first.is_ok(), later.is_ok()), (true, true));
+        assert_eq!(start.elapsed(), idle);
+        assert_eq!(writes.load(Ordering::Relaxed), 4);
+        // a full window is written without waiting
+        let start = time::Instant::now();
+        ping_all(&pipeline, 8).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_send_and_close() {
+        // answers nothing before the end of the stream, then a Ping response
+        // per request read
+        let (client, mut service) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let mut requests = Vec::new();
+            service.read_to_end(&mut requests).await.unwrap();
+            let responses = Test::response_ping().repeat(requests.len() / HEADER_SIZE);
+            service.write_all(&responses).await.unwrap();
+        });
+        let pipeline = Pipeline::over(Box::new(client), 2, FlushPolicy::Immediate);
+        let responses = pipeline
+            .send_and_close(&vec![Test::request_ping(); 2])
+            .await;
+        for response in responses {
+            assert_eq!(response.unwrap()[..], Test::response_ping()[..]);
+        }
+
+        // the service answers the pipelined requests before closing its side
+        let server = Server::new_with_config("127.0.0.1:0", ServerConfig::default())
+            .await
+            .unwrap()
+            .spawn();
+        let target = Target::Tcp(server.local_addr());
+        let pipeline = Pipeline::connect(&target, 3, FlushPolicy::AfterMessages(3))
+            .await
+            .unwrap();
+        let payloads = [&b"aaa"[..], b"bbbb", b"abc"];
+        let queries: Vec<Vec<u8>> = payloads.iter().map(|p| Test::request_compress(p)).collect();
+        let responses = pipeline.send_and_close(&queries).await;
+        let expected = [&b"3a"[..], b"4b", b"abc"];
+        for (response, expected) in responses.into_iter().zip(expected) {
+            assert_eq!(response.unwrap()[..], Test::response_compress(expected)[..]);
+        }
+    }
+
+    #[test]
+    fn test_flush_policy_from_str() {
+        let parse = |s: &str| s.parse::<FlushPolicy>().ok();
+        assert_eq!(parse("immediate"), Some(FlushPolicy::Immediate));
+        assert_eq!(parse("messages:16"), Some(FlushPolicy::AfterMessages(16)));
+        let idle = FlushPolicy::AfterIdle(Duration::from_micros(250));
+        assert_eq!(parse("idle:250"), Some(idle));
+        for invalid in ["", "messages:0", "messages", "idle:soon", "never:1"] {
+            assert_eq!(parse(invalid), None, "{}", invalid);
+        }
+        assert_eq!(FlushPolicy::default(), FlushPolicy::Immediate);
+    }
+}
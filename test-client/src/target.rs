@@ -0,0 +1,188 @@
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    net::{Ipv6Addr, SocketAddr, SocketAddrV6},
+    path::PathBuf,
+    str::FromStr,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+/// Where the service is reached
+/// "unix:/path/to/socket" => Unix
+/// "127.0.0.1:4000", "[::1]:4000", "[fe80::1%eth0]:4000" => Tcp
+/// "localhost:4000" => Host, resolved on connect
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Tcp(SocketAddr),
+    Host(String),
+    Unix(PathBuf),
+}
+
+/// A connected stream of any of the transports
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+impl Target {
+    /// Connects to the target, returning the stream along with a description
+    /// of its local end
+    pub async fn connect(&self) -> Result<(Box<dyn Stream>, String)> {
+        match self {
+            Target::Tcp(addr) => Target::connect_tcp(TcpStream::connect(addr).await?),
+            Target::Host(host) => Target::connect_tcp(TcpStream::connect(host).await?),
+            #[cfg(unix)]
+            Target::Unix(path) => {
+                let stream = tokio::net::UnixStream::connect(path).await?;
+                Ok((Box::new(stream), self.to_string()))
+            }
+            #[cfg(not(unix))]
+            Target::Unix(_) => Err(Error::new(
+                ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    fn connect_tcp(stream: TcpStream) -> Result<(Box<dyn Stream>, String)> {
+        let local = stream.local_addr()?.to_string();
+        Ok((Box::new(stream), local))
+    }
+}
+
+impl FromStr for Target {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Target> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(invalid("unix: expects a socket path"));
+            }
+            return Ok(Target::Unix(PathBuf::from(path)));
+        }
+        if s.starts_with('[') {
+            return parse_ipv6(s).map(|addr| Target::Tcp(SocketAddr::V6(addr)));
+        }
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Target::Tcp(addr));
+        }
+        match s.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                Ok(Target::Host(s.to_string()))
+            }
+            _ => Err(invalid("expected host:port, [ipv6]:port or unix:path")),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Tcp(addr) => write!(f, "{}", addr),
+            Target::Host(host) => write!(f, "{}", host),
+            Target::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg.to_string())
+}
+
+/// Parses "[addr%scope]:port", the scope being an interface index or name
+fn parse_ipv6(s: &str) -> Result<SocketAddrV6> {
+    let (host, port) = s[1..]
+        .split_once("]:")
+        .ok_or_else(|| invalid("expected [ipv6]:port"))?;
+    let port = port.parse().map_err(|_| invalid("invalid port"))?;
+    let (ip, scope) = match host.split_once('%') {
+        Some((ip, scope)) => (ip, scope_id(scope)?),
+        None => (host, 0),
+    };
+    let ip: Ipv6Addr = ip.parse().map_err(|_| invalid("invalid IPv6 address"))?;
+    Ok(SocketAddrV6::new(ip, port, 0, scope))
+}
+
+/// The index of the interface a scope ID names
+fn scope_id(scope: &str) -> Result<u32> {
+    if let Ok(index) = scope.parse() {
+        return Ok(index);
+    }
+    let unknown = || invalid("unknown interface in IPv6 scope ID");
+    if scope.is_empty() || scope.contains('/') {
+        return Err(unknown());
+    }
+    std::fs::read_to_string(format!("/sys/class/net/{}/ifindex", scope))
+        .ok()
+        .and_then(|index| index.trim().parse().ok())
+        .ok_or_else(unknown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "127.0.0.1:4000".parse::<Target>().unwrap(),
+            Target::Tcp(SocketAddr::from((Ipv4Addr::LOCALHOST, 4000)))
+        );
+        assert_eq!(
+            "[::1]:4000".parse::<Target>().unwrap(),
+            Target::Tcp(SocketAddr::from((Ipv6Addr::LOCALHOST, 4000)))
+        );
+        let scoped = SocketAddrV6::new("fe80::1".parse().unwrap(), 4000, 0, 2);
+        assert_eq!(
+            "[fe80::1%2]:4000".parse::<Target>().unwrap(),
+            Target::Tcp(SocketAddr::V6(scoped))
+        );
+        assert_eq!(
+            "localhost:4000".parse::<Target>().unwrap(),
+            Target::Host("localhost:4000".to_string())
+        );
+        assert_eq!(
+            "unix:/tmp/service.sock".parse::<Target>().unwrap(),
+            Target::Unix(PathBuf::from("/tmp/service.sock"))
+        );
+        for bad in [
+            "",
+            "localhost",
+            ":4000",
+            "host:port",
+            "[::1]",
+            "[::g]:4000",
+            "unix:",
+        ] {
+            assert!(bad.parse::<Target>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_parse_scope_name() {
+        let lo = "[fe80::1%lo]:4000".parse::<Target>().unwrap();
+        match lo {
+            Target::Tcp(SocketAddr::V6(addr)) => assert_ne!(addr.scope_id(), 0),
+            other => panic!("{:?}", other),
+        }
+        assert!("[fe80::1%nosuchif0]:4000".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        for s in [
+            "127.0.0.1:4000",
+            "[::1]:4000",
+            "localhost:4000",
+            "unix:/tmp/s",
+        ] {
+            assert_eq!(s.parse::<Target>().unwrap().to_string(), s);
+        }
+    }
+}
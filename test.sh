@@ -4,6 +4,16 @@ unit_test() {
     cargo test --release
 }
 
+features_test() {
+    for features in "" std blocking testing; do
+        cargo clippy -p service --all-targets --no-default-features --features "$features" \
+            -- -D warnings || exit 1
+    done
+    for features in std blocking; do
+        cargo test -p service --no-default-features --features "$features" || exit 1
+    done
+}
+
 client_test() {
     cargo -q run --release --bin test-client
 }
@@ -12,11 +22,13 @@ show_help() {
     echo "./test.sh COMMAND"
     echo "COMMANDS"
     echo "      unit    run unit tests"
+    echo "  features    build without the server feature"
     echo "    client    run test-client"
 }
 
 case "$1" in
      unit) unit_test ;;
+     features) features_test ;;
      client) client_test ;;
      *) show_help ;;
 esac